@@ -6,33 +6,58 @@ mod netmap;
 #[cfg(feature = "netmap")]
 mod nm;
 
+mod memory;
 mod raw_socket;
 mod raw_socket_sys;
+mod rss;
 mod tap_interface;
 mod tap_interface_sys;
 mod uds;
 mod unixdomainsocket;
+mod uring;
+mod vnet;
+mod xdp;
+mod xsk;
 
 #[cfg(feature = "netmap")]
 pub use self::netmap::{nmreq, Netmap, RxToken as NetmapRxToken, TxToken as NetmapTxToken};
 
+pub use self::memory::{
+    link_conditions, set_link_conditions, InMemory, LinkConditions, RxToken as InMemoryRxToken,
+    TxToken as InMemoryTxToken,
+};
 pub use self::raw_socket::{RawSocket, RxToken as RawSocketRxToken, TxToken as RawSocketTxToken};
+pub use self::rss::Rss;
 pub use self::tap_interface::{
     RxToken as TapInterfaceRxToken, TapInterface, TxToken as TapInterfaceTxToken,
 };
 pub use self::unixdomainsocket::{
     RxToken as UnixDomainSocketRxToken, TxToken as UnixDomainSocketTxToken, UnixDomainSocket,
 };
+pub use self::xdp::{RxToken as XdpRxToken, TxToken as XdpTxToken, Xdp};
 use std::io;
 
 pub const SMOLTCP_ETHERNET_HEADER: usize = 14;
 
+/// Occupied slots of the RX or TX rings of a device
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct RingOccupancy {
+    /// Received frames which were not read yet, or sent frames which were not transmitted yet
+    pub used: usize,
+    /// Slots which can hold a frame
+    pub size: usize,
+}
+
 const SIOCGIFMTU: libc::c_ulong = 0x8921;
 const SIOCGIFINDEX: libc::c_ulong = 0x8933;
 const ETH_P_ALL: libc::c_short = 0x0003;
 const IFF_TAP: libc::c_int = 0x0002;
 const IFF_NO_PI: libc::c_int = 0x1000;
 const TUNSETIFF: libc::c_ulong = 0x400454CA;
+const IFF_VNET_HDR: libc::c_int = 0x4000;
+const TUNGETFEATURES: libc::c_ulong = 0x800454CF;
+const TUNSETOFFLOAD: libc::c_ulong = 0x400454D0;
+const TUN_F_CSUM: libc::c_ulong = 0x01;
 
 #[repr(C)]
 #[derive(Debug)]
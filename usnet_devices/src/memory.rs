@@ -0,0 +1,295 @@
+use std::collections::VecDeque;
+use std::io;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::UnixDatagram;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use std::vec::Vec;
+
+use smoltcp::phy;
+use smoltcp::phy::{Device, DeviceCapabilities};
+use smoltcp::time::Instant as SmoltcpInstant;
+use smoltcp::Result;
+
+use crate::SMOLTCP_ETHERNET_HEADER;
+
+const MTU: usize = 1500;
+// extra delay of a reordered frame, following frames overtake it
+const REORDER_DELAY: Duration = Duration::from_millis(10);
+
+/// Impairments of an in-memory link, applied to the frames of both directions
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LinkConditions {
+    /// Probability between 0 and 1 that a frame is dropped
+    pub loss: f64,
+    /// Probability between 0 and 1 that a frame is held back, so that the following frames
+    /// overtake it
+    pub reorder: f64,
+    /// Delay of each frame
+    pub latency: Duration,
+    /// Seed for the random decisions, the same seed drops and reorders the same frames
+    pub seed: u64,
+}
+
+impl Default for LinkConditions {
+    /// A lossless link without delay
+    fn default() -> LinkConditions {
+        LinkConditions {
+            loss: 0.0,
+            reorder: 0.0,
+            latency: Duration::from_secs(0),
+            seed: 1,
+        }
+    }
+}
+
+struct Side {
+    queue: VecDeque<(Instant, Vec<u8>)>, // frames for this side, sorted by delivery time
+    notify: Option<UnixDatagram>,        // wakes the attached stack, None if detached
+}
+
+struct Link {
+    name: String,
+    conditions: LinkConditions,
+    rng: u64,
+    sides: [Side; 2],
+}
+
+static LINKS: Mutex<Vec<Arc<Mutex<Link>>>> = Mutex::new(Vec::new());
+
+impl Link {
+    fn set_conditions(&mut self, conditions: LinkConditions) {
+        self.conditions = conditions;
+        self.rng = conditions.seed | 1; // xorshift needs a state other than zero
+    }
+    fn random(&mut self) -> f64 {
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 7;
+        self.rng ^= self.rng << 17;
+        (self.rng >> 11) as f64 / (1u64 << 53) as f64
+    }
+    fn send(&mut self, from: usize, frame: Vec<u8>) {
+        let to = 1 - from;
+        if self.sides[to].notify.is_none() {
+            return; // no stack on the other side
+        }
+        if self.conditions.loss > 0.0 && self.random() < self.conditions.loss {
+            return;
+        }
+        let mut due = Instant::now() + self.conditions.latency;
+        if self.conditions.reorder > 0.0 && self.random() < self.conditions.reorder {
+            due += REORDER_DELAY;
+        }
+        let side = &mut self.sides[to];
+        let pos = side
+            .queue
+            .iter()
+            .rposition(|&(d, _)| d <= due)
+            .map_or(0, |p| p + 1);
+        side.queue.insert(pos, (due, frame));
+        if let Some(ref notify) = side.notify {
+            let _ = notify.send(b"$"); // a full socket already wakes the stack
+        }
+    }
+    fn recv(&mut self, side: usize) -> Option<Vec<u8>> {
+        let queue = &mut self.sides[side].queue;
+        match queue.front() {
+            Some(&(due, _)) if due <= Instant::now() => queue.pop_front().map(|(_, frame)| frame),
+            _ => None,
+        }
+    }
+}
+
+/// Returns the link with the name and creates it if it does not exist
+fn link_for(links: &mut Vec<Arc<Mutex<Link>>>, name: &str) -> Arc<Mutex<Link>> {
+    if let Some(link) = links.iter().find(|l| l.lock().unwrap().name == name) {
+        return link.clone();
+    }
+    let mut link = Link {
+        name: name.to_string(),
+        conditions: LinkConditions::default(),
+        rng: 0,
+        sides: [
+            Side {
+                queue: VecDeque::new(),
+                notify: None,
+            },
+            Side {
+                queue: VecDeque::new(),
+                notify: None,
+            },
+        ],
+    };
+    link.set_conditions(LinkConditions::default());
+    let link = Arc::new(Mutex::new(link));
+    links.push(link.clone());
+    link
+}
+
+/// Changes the loss, reordering, and latency of the named link, also while stacks use it
+/// A link which no stack uses yet is created with these conditions.
+pub fn set_link_conditions(link: &str, conditions: LinkConditions) -> io::Result<()> {
+    if !(0.0..=1.0).contains(&conditions.loss) || !(0.0..=1.0).contains(&conditions.reorder) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "loss and reorder must be probabilities between 0 and 1",
+        ));
+    }
+    let mut links = LINKS.lock().unwrap();
+    link_for(&mut links, link)
+        .lock()
+        .unwrap()
+        .set_conditions(conditions);
+    Ok(())
+}
+
+/// Returns the conditions of the named link, None if it does not exist
+pub fn link_conditions(link: &str) -> Option<LinkConditions> {
+    let links = LINKS.lock().unwrap();
+    let conditions = links
+        .iter()
+        .map(|l| l.lock().unwrap())
+        .find(|l| l.name == link)
+        .map(|l| l.conditions);
+    conditions
+}
+
+/// One end of a link between two stacks of the same process
+/// The first two devices with the same link name are connected, without a NIC or kernel
+/// interface. The file descriptor is readable when a frame was queued for this end.
+pub struct InMemory {
+    link: Arc<Mutex<Link>>,
+    side: usize,
+    listener: UnixDatagram,
+    mtu: usize,
+    reduce_mtu_by: Option<usize>,
+}
+
+impl AsRawFd for InMemory {
+    fn as_raw_fd(&self) -> RawFd {
+        self.listener.as_raw_fd()
+    }
+}
+
+impl InMemory {
+    pub fn new(link: &str, reduce_mtu_by: Option<usize>) -> io::Result<InMemory> {
+        let mut links = LINKS.lock().unwrap();
+        let shared = link_for(&mut links, link);
+        let (side, listener) = {
+            let mut l = shared.lock().unwrap();
+            let side = l
+                .sides
+                .iter()
+                .position(|s| s.notify.is_none())
+                .ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::AddrInUse,
+                        format!("in-memory link {} already connects two stacks", link),
+                    )
+                })?;
+            let (notify, listener) = UnixDatagram::pair()?;
+            notify.set_nonblocking(true)?;
+            listener.set_nonblocking(true)?;
+            l.sides[side].notify = Some(notify);
+            (side, listener)
+        };
+        Ok(InMemory {
+            link: shared,
+            side: side,
+            listener: listener,
+            mtu: MTU + SMOLTCP_ETHERNET_HEADER,
+            reduce_mtu_by: reduce_mtu_by,
+        })
+    }
+    /// Time until the next queued frame is delivered because of the link latency, None if
+    /// no frame is queued for this end
+    pub fn next_delivery(&self) -> Option<Duration> {
+        let link = self.link.lock().unwrap();
+        let front = link.sides[self.side].queue.front().map(|&(due, _)| due);
+        front.map(|due| due.saturating_duration_since(Instant::now()))
+    }
+}
+
+impl Drop for InMemory {
+    fn drop(&mut self) {
+        let mut links = LINKS.lock().unwrap();
+        let unused = {
+            let mut link = self.link.lock().unwrap();
+            link.sides[self.side].notify = None;
+            link.sides[self.side].queue.clear();
+            link.sides.iter().all(|s| s.notify.is_none())
+        };
+        if unused {
+            links.retain(|l| !Arc::ptr_eq(l, &self.link));
+        }
+    }
+}
+
+impl<'a> Device<'a> for InMemory {
+    type RxToken = RxToken;
+    type TxToken = TxToken;
+
+    fn capabilities(&self) -> DeviceCapabilities {
+        let mut caps = DeviceCapabilities::default();
+        caps.max_transmission_unit = self.mtu - self.reduce_mtu_by.unwrap_or(0);
+        caps
+    }
+
+    fn receive(&'a mut self) -> Option<(Self::RxToken, Self::TxToken)> {
+        let mut wakeups = [0; 64];
+        while self.listener.recv(&mut wakeups).is_ok() {
+            // consume all notifications, the queue is checked afterwards
+        }
+        let buffer = self.link.lock().unwrap().recv(self.side)?;
+        let rx = RxToken { buffer };
+        let tx = TxToken {
+            link: self.link.clone(),
+            side: self.side,
+        };
+        Some((rx, tx))
+    }
+
+    fn transmit(&'a mut self) -> Option<Self::TxToken> {
+        Some(TxToken {
+            link: self.link.clone(),
+            side: self.side,
+        })
+    }
+}
+
+#[doc(hidden)]
+pub struct RxToken {
+    buffer: Vec<u8>,
+}
+
+impl phy::RxToken for RxToken {
+    fn consume<R, F: FnOnce(&mut [u8]) -> Result<R>>(
+        mut self,
+        _timestamp: SmoltcpInstant,
+        f: F,
+    ) -> Result<R> {
+        f(&mut self.buffer[..])
+    }
+}
+
+#[doc(hidden)]
+pub struct TxToken {
+    link: Arc<Mutex<Link>>,
+    side: usize,
+}
+
+impl phy::TxToken for TxToken {
+    fn consume<R, F: FnOnce(&mut [u8]) -> Result<R>>(
+        self,
+        _timestamp: SmoltcpInstant,
+        len: usize,
+        f: F,
+    ) -> Result<R> {
+        let mut buffer = vec![0; len];
+        let result = f(&mut buffer);
+        if result.is_ok() {
+            self.link.lock().unwrap().send(self.side, buffer);
+        }
+        result
+    }
+}
@@ -8,12 +8,12 @@ use smoltcp::phy::{Device, DeviceCapabilities};
 use smoltcp::time::Instant;
 use smoltcp::Result;
 
-use SMOLTCP_ETHERNET_HEADER;
+use {RingOccupancy, SMOLTCP_ETHERNET_HEADER};
 
 pub use nm::nmreq;
 
 /// Netmap provies a virtual Ethernet interface.
-/// smoltcp compatible Netmap (w/ rx sync ioctl, implicit batched tx, parent mtu, no recv_ready, no explicit issue_tx_sync, no zc_forward)
+/// smoltcp compatible Netmap (w/ rx sync ioctl, batched tx synced by flush, parent mtu, no recv_ready, no zc_forward)
 #[derive(Debug)]
 pub struct Netmap {
     lower: Arc<RwLock<nm::NetmapDesc>>,
@@ -80,6 +80,13 @@ impl Netmap {
         lower.tx_flush()
     }
 
+    /// Issues one TXSYNC for the frames which were sent since the last flush, they are
+    /// otherwise only synced once the TX rings are full or by a wait
+    pub fn flush(&mut self) -> Result<()> {
+        let mut lower = self.lower.write().unwrap();
+        lower.flush()
+    }
+
     pub fn set_uses_wait(&mut self, uses_wait: bool) {
         let mut lower = self.lower.write().unwrap();
         lower.set_uses_wait(uses_wait);
@@ -95,6 +102,12 @@ impl Netmap {
         lower.get_nmreq()
     }
 
+    /// Returns the occupied slots of the RX and TX rings
+    pub fn ring_occupancy(&self) -> (RingOccupancy, RingOccupancy) {
+        let lower = self.lower.read().unwrap();
+        lower.ring_occupancy()
+    }
+
     pub fn zc_forward(&mut self, from: &mut Netmap) -> Result<()> {
         let mut lower = self.lower.write().unwrap();
         let mut from_lower = from.lower.write().unwrap();
@@ -12,14 +12,14 @@ extern crate netmap_sys;
 
 pub use self::netmap_sys::netmap::nmreq;
 use self::netmap_sys::netmap::{
-    netmap_slot, nm_ring_empty, NETMAP_RING_MASK, NIOCRXSYNC, NIOCTXSYNC, NR_REG_ALL_NIC,
-    NR_REG_MASK, NR_REG_NIC_SW, NR_REG_ONE_NIC, NR_REG_SW, NS_BUF_CHANGED,
+    netmap_ring, netmap_slot, nm_ring_empty, NETMAP_RING_MASK, NIOCRXSYNC, NIOCTXSYNC,
+    NR_REG_ALL_NIC, NR_REG_MASK, NR_REG_NIC_SW, NR_REG_ONE_NIC, NR_REG_SW, NS_BUF_CHANGED,
 };
 use self::netmap_sys::netmap_user::{
     nm_close, nm_desc, nm_open, nm_ring_next, NETMAP_BUF, NETMAP_FD, NETMAP_RXRING, NETMAP_TXRING,
 };
 
-use super::{ifreq, ifreq_for, ifreq_ioctl, SIOCGIFMTU};
+use super::{ifreq, ifreq_for, ifreq_ioctl, RingOccupancy, SIOCGIFMTU};
 use libc;
 
 use libc::c_int;
@@ -28,6 +28,16 @@ extern "C" {
     pub fn nm_mmap(nm_desc: *mut nm_desc, parent: *const nm_desc) -> c_int;
 }
 
+/// Slots from head to tail, the received frames of an RX ring or the free slots of a TX ring
+unsafe fn ring_space(ring: *mut netmap_ring) -> u32 {
+    let (head, tail) = ((*ring).head, (*ring).tail);
+    if tail >= head {
+        tail - head
+    } else {
+        tail + (*ring).num_slots - head
+    }
+}
+
 #[derive(Debug)]
 pub struct NetmapDesc {
     nm_desc: *mut nm_desc,
@@ -36,6 +46,7 @@ pub struct NetmapDesc {
     buf_size: u16,
     ifreq: ifreq,
     uses_wait: bool,
+    tx_pending: bool, // frames were queued in the TX rings since the last TXSYNC
 }
 
 unsafe impl Send for NetmapDesc {}
@@ -74,6 +85,7 @@ impl NetmapDesc {
                 buf_size: buf_size,
                 ifreq: ifreq_for(parent),
                 uses_wait: uses_wait,
+                tx_pending: false,
             })
         }
     }
@@ -141,6 +153,7 @@ impl NetmapDesc {
                 buf_size: buf_size,
                 ifreq: ifreq_for(parent),
                 uses_wait: uses_wait,
+                tx_pending: false,
             })
         }
     }
@@ -150,9 +163,20 @@ impl NetmapDesc {
         if res == -1 {
             return Err(Error::Illegal);
         }
+        self.tx_pending = false;
         Ok(())
     }
 
+    /// Issues one TXSYNC for all frames queued by send since the last sync, the frames are
+    /// otherwise only synced once the TX rings are full or the poll/select call of a wait
+    pub fn flush(&mut self) -> Result<()> {
+        if self.tx_pending {
+            self.tx_flush()
+        } else {
+            Ok(())
+        }
+    }
+
     pub fn set_uses_wait(&mut self, uses_wait: bool) {
         self.uses_wait = uses_wait;
     }
@@ -276,7 +300,8 @@ impl NetmapDesc {
                     let next = nm_ring_next(ring, current);
                     (*ring).head = next;
                     (*ring).cur = next;
-                    if !self.uses_wait || self.send_ready().is_err() {
+                    self.tx_pending = true;
+                    if self.send_ready().is_err() {
                         // workaround for https://github.com/luigirizzo/netmap/issues/457
                         self.tx_flush()?;
                     }
@@ -313,6 +338,7 @@ impl NetmapDesc {
                     let next = nm_ring_next(dst_ring, (*dst_ring).cur);
                     (*dst_ring).head = next;
                     (*dst_ring).cur = next;
+                    self.tx_pending = true;
                     if !self.uses_wait || self.send_ready().is_err() {
                         // workaround for https://github.com/luigirizzo/netmap/issues/457
                         self.tx_flush()?;
@@ -327,6 +353,28 @@ impl NetmapDesc {
     pub fn get_nmreq(&self) -> nmreq {
         unsafe { (*self.nm_desc).req }
     }
+
+    /// Returns the received frames between head and tail of the RX rings, and the sent frames
+    /// which the kernel did not release yet in the TX rings, one slot of each ring stays empty
+    pub fn ring_occupancy(&self) -> (RingOccupancy, RingOccupancy) {
+        let mut rx = RingOccupancy::default();
+        let mut tx = RingOccupancy::default();
+        unsafe {
+            for i in (*self.nm_desc).first_rx_ring..=(*self.nm_desc).last_rx_ring {
+                let ring = NETMAP_RXRING((*self.nm_desc).nifp, i as isize);
+                let slots = (*ring).num_slots as usize;
+                rx.used += ring_space(ring) as usize;
+                rx.size += slots - 1;
+            }
+            for i in (*self.nm_desc).first_tx_ring..=(*self.nm_desc).last_tx_ring {
+                let ring = NETMAP_TXRING((*self.nm_desc).nifp, i as isize);
+                let slots = (*ring).num_slots as usize;
+                tx.used += slots - 1 - ring_space(ring) as usize;
+                tx.size += slots - 1;
+            }
+        }
+        (rx, tx)
+    }
 }
 
 impl Drop for NetmapDesc {
@@ -0,0 +1,157 @@
+use std::io;
+use std::net::SocketAddrV4;
+
+use libc;
+
+use crate::xsk::{check, Fd};
+
+const SIOCETHTOOL: libc::c_ulong = 0x8946;
+const ETHTOOL_GRXFH: u32 = 0x29;
+const ETHTOOL_GRSSH: u32 = 0x46;
+const TCP_V4_FLOW: u32 = 0x01;
+const ETH_RSS_HASH_TOP: u8 = 1 << 0;
+const RXH_IP_SRC: u64 = 1 << 4;
+const RXH_IP_DST: u64 = 1 << 5;
+const RXH_L4_B_0_1: u64 = 1 << 6; // source port
+const RXH_L4_B_2_3: u64 = 1 << 7; // destination port
+const RXFH_HEADER_WORDS: usize = 6; // struct ethtool_rxfh without rss_config
+
+#[repr(C)]
+struct ifreq_data {
+    ifr_name: [libc::c_char; libc::IF_NAMESIZE],
+    ifr_data: *mut libc::c_void,
+    _pad: [u8; 16], // the union in struct ifreq is larger than the pointer
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct ethtool_rxnfc_hash {
+    cmd: u32,
+    flow_type: u32,
+    data: u64, // the kernel only copies up to here for ETHTOOL_GRXFH
+}
+
+/// Receive side scaling configuration of a NIC, which selects the queue of a received packet
+/// by the Toeplitz hash of its addresses and ports
+#[derive(Debug)]
+pub struct Rss {
+    key: Vec<u8>,
+    indirection: Vec<u32>,
+    fields: u64, // RXH_* bits hashed for TCP over IPv4
+}
+
+impl Rss {
+    /// Reads the hash key, the indirection table, and the hashed header fields for TCP over
+    /// IPv4 through ethtool, fails if the NIC does not use the Toeplitz hash
+    pub fn read(interface: &str) -> io::Result<Rss> {
+        let fd = Fd(check(unsafe {
+            libc::socket(libc::AF_INET, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0)
+        })?);
+        let mut header = [0u32; RXFH_HEADER_WORDS];
+        header[0] = ETHTOOL_GRSSH;
+        ethtool(&fd, interface, header.as_mut_ptr() as *mut libc::c_void)?;
+        let (indir_size, key_size) = (header[2] as usize, header[3] as usize);
+        if indir_size == 0 || key_size < 4 {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!("{} has no RSS indirection table or hash key", interface),
+            ));
+        }
+        let mut rxfh = vec![0u32; RXFH_HEADER_WORDS + indir_size + (key_size + 3) / 4];
+        rxfh[0] = ETHTOOL_GRSSH;
+        rxfh[2] = indir_size as u32;
+        rxfh[3] = key_size as u32;
+        ethtool(&fd, interface, rxfh.as_mut_ptr() as *mut libc::c_void)?;
+        let hfunc = rxfh[4].to_ne_bytes()[0];
+        if hfunc & ETH_RSS_HASH_TOP == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!("the RSS hash function of {} is not Toeplitz", interface),
+            ));
+        }
+        let indirection = rxfh[RXFH_HEADER_WORDS..RXFH_HEADER_WORDS + indir_size].to_vec();
+        let key = rxfh[RXFH_HEADER_WORDS + indir_size..]
+            .iter()
+            .flat_map(|word| word.to_ne_bytes().to_vec())
+            .take(key_size)
+            .collect();
+        let mut nfc = ethtool_rxnfc_hash {
+            cmd: ETHTOOL_GRXFH,
+            flow_type: TCP_V4_FLOW,
+            ..Default::default()
+        };
+        ethtool(
+            &fd,
+            interface,
+            &mut nfc as *mut ethtool_rxnfc_hash as *mut libc::c_void,
+        )?;
+        Ok(Rss {
+            key: key,
+            indirection: indirection,
+            fields: nfc.data,
+        })
+    }
+
+    /// Queue on which the NIC receives a TCP packet from the source to the destination
+    pub fn queue(&self, src: SocketAddrV4, dst: SocketAddrV4) -> u32 {
+        let mut input = Vec::with_capacity(12);
+        if self.fields & RXH_IP_SRC != 0 {
+            input.extend_from_slice(&src.ip().octets());
+        }
+        if self.fields & RXH_IP_DST != 0 {
+            input.extend_from_slice(&dst.ip().octets());
+        }
+        if self.fields & RXH_L4_B_0_1 != 0 {
+            input.extend_from_slice(&src.port().to_be_bytes());
+        }
+        if self.fields & RXH_L4_B_2_3 != 0 {
+            input.extend_from_slice(&dst.port().to_be_bytes());
+        }
+        let hash = toeplitz(&self.key, &input);
+        self.indirection[hash as usize % self.indirection.len()]
+    }
+
+    /// Whether the ports are part of the hash, otherwise all connections between two
+    /// addresses end up on the same queue
+    pub fn hashes_ports(&self) -> bool {
+        self.fields & (RXH_L4_B_0_1 | RXH_L4_B_2_3) != 0
+    }
+}
+
+fn ethtool(fd: &Fd, interface: &str, data: *mut libc::c_void) -> io::Result<()> {
+    let mut ifreq = ifreq_data {
+        ifr_name: [0; libc::IF_NAMESIZE],
+        ifr_data: data,
+        _pad: [0; 16],
+    };
+    if interface.len() >= libc::IF_NAMESIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "invalid interface name",
+        ));
+    }
+    for (i, byte) in interface.as_bytes().iter().enumerate() {
+        ifreq.ifr_name[i] = *byte as libc::c_char;
+    }
+    check(unsafe { libc::ioctl(fd.0, SIOCETHTOOL, &mut ifreq as *mut ifreq_data) })?;
+    Ok(())
+}
+
+/// Each set bit of the input XORs the 32 bits of the key which start at its position
+fn toeplitz(key: &[u8], input: &[u8]) -> u32 {
+    let key_bit = |i: usize| match key.get(i / 8) {
+        Some(byte) => ((byte >> (7 - i % 8)) & 1) as u32,
+        None => 0,
+    };
+    let mut window = u32::from_be_bytes([key[0], key[1], key[2], key[3]]);
+    let mut hash = 0;
+    for (i, byte) in input.iter().enumerate() {
+        for bit in 0..8 {
+            if byte & (0x80 >> bit) != 0 {
+                hash ^= window;
+            }
+            window = (window << 1) | key_bit(32 + i * 8 + bit);
+        }
+    }
+    hash
+}
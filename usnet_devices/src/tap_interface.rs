@@ -4,13 +4,14 @@ use std::sync::{Arc, RwLock};
 use std::vec::Vec;
 
 use smoltcp::phy;
-use smoltcp::phy::{Device, DeviceCapabilities};
+use smoltcp::phy::{Checksum, Device, DeviceCapabilities};
 use smoltcp::time::Instant;
 use smoltcp::Result;
 
 use crate::tap_interface_sys;
+use crate::vnet::{self, GSO_MAX_FRAME, VNET_HDR_LEN};
 
-use crate::SMOLTCP_ETHERNET_HEADER;
+use crate::{RingOccupancy, SMOLTCP_ETHERNET_HEADER};
 
 /// A virtual Ethernet interface.
 #[derive(Debug)]
@@ -18,6 +19,7 @@ pub struct TapInterface {
     lower: Arc<RwLock<tap_interface_sys::TapInterfaceDesc>>,
     mtu: usize,
     reduce_mtu_by: Option<usize>,
+    offload: bool, // frames with the virtio-net header
 }
 
 impl AsRawFd for TapInterface {
@@ -33,14 +35,8 @@ impl TapInterface {
     /// no special privileges are needed. Otherwise, this requires superuser privileges
     /// or a corresponding capability set on the executable.
     pub fn new(name: &str, reduce_mtu_by: Option<usize>) -> io::Result<TapInterface> {
-        let mut lower = tap_interface_sys::TapInterfaceDesc::new(name)?;
-        lower.attach_interface()?;
-        let mtu = lower.interface_mtu()?;
-        Ok(TapInterface {
-            lower: Arc::new(RwLock::new(lower)),
-            mtu: mtu + SMOLTCP_ETHERNET_HEADER,
-            reduce_mtu_by: reduce_mtu_by,
-        })
+        let lower = tap_interface_sys::TapInterfaceDesc::new(name)?;
+        TapInterface::attach(lower, reduce_mtu_by)
     }
 
     /// Attaches to a MACVTAP interface called `name`.
@@ -53,15 +49,53 @@ impl TapInterface {
     /// The attached MAC address must also be used in smoltcp
     /// (for passthru it is the same as the underlying device).
     pub fn new_macvtap(name: &str, reduce_mtu_by: Option<usize>) -> io::Result<TapInterface> {
-        let mut lower = tap_interface_sys::TapInterfaceDesc::new_macvtap(name)?;
-        lower.attach_interface()?;
+        let lower = tap_interface_sys::TapInterfaceDesc::new_macvtap(name)?;
+        TapInterface::attach(lower, reduce_mtu_by)
+    }
+
+    fn attach(
+        mut lower: tap_interface_sys::TapInterfaceDesc,
+        reduce_mtu_by: Option<usize>,
+    ) -> io::Result<TapInterface> {
+        let offload = lower.attach_interface()?;
         let mtu = lower.interface_mtu()?;
+        if offload {
+            lower.enable_io_uring(GSO_MAX_FRAME);
+        } else {
+            lower.enable_io_uring(mtu + SMOLTCP_ETHERNET_HEADER);
+        }
         Ok(TapInterface {
             lower: Arc::new(RwLock::new(lower)),
             mtu: mtu + SMOLTCP_ETHERNET_HEADER,
             reduce_mtu_by: reduce_mtu_by,
+            offload: offload,
         })
     }
+
+    /// Whether the frames are read and written in batches over an io_uring, which is set up
+    /// if the kernel supports it
+    pub fn uses_io_uring(&self) -> bool {
+        self.lower.read().unwrap().uses_io_uring()
+    }
+
+    /// Returns the occupied RX and TX buffers of the io_uring, None if the frames are read and
+    /// written with syscalls
+    pub fn ring_occupancy(&self) -> Option<(RingOccupancy, RingOccupancy)> {
+        self.lower.read().unwrap().ring_occupancy()
+    }
+
+    /// Whether the checksums of TCP and UDP and the segmentation of TCP are offloaded to the
+    /// kernel through the virtio-net header, otherwise the stack computes the checksums and
+    /// sends each segment on its own
+    pub fn uses_offload(&self) -> bool {
+        self.offload
+    }
+
+    /// Submits the frames which were sent since the last flush, they are otherwise
+    /// submitted once a batch is full
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.lower.write().unwrap().flush()
+    }
 }
 
 impl<'a> Device<'a> for TapInterface {
@@ -71,18 +105,31 @@ impl<'a> Device<'a> for TapInterface {
     fn capabilities(&self) -> DeviceCapabilities {
         let mut caps = DeviceCapabilities::default();
         caps.max_transmission_unit = self.mtu - self.reduce_mtu_by.unwrap_or(0);
+        if self.offload {
+            // the received checksums are verified by the RxToken if the kernel did not
+            caps.checksum.tcp = Checksum::None;
+            caps.checksum.udp = Checksum::None;
+        }
         caps
     }
 
     fn receive(&'a mut self) -> Option<(Self::RxToken, Self::TxToken)> {
         let mut lower = self.lower.write().unwrap();
-        let mut buffer = vec![0; self.mtu];
+        let header_len = if self.offload { VNET_HDR_LEN } else { 0 };
+        let mut buffer = vec![0; header_len + self.mtu];
         match lower.recv(&mut buffer[..]) {
+            Ok(size) if size < header_len => None,
             Ok(size) => {
                 buffer.resize(size, 0);
-                let rx = RxToken { buffer };
+                let verify = self.offload && !vnet::checksum_verified(&buffer);
+                buffer.drain(..header_len);
+                let rx = RxToken {
+                    buffer: buffer,
+                    verify: verify,
+                };
                 let tx = TxToken {
                     lower: self.lower.clone(),
+                    offload: self.offload,
                 };
                 Some((rx, tx))
             }
@@ -94,6 +141,7 @@ impl<'a> Device<'a> for TapInterface {
     fn transmit(&'a mut self) -> Option<Self::TxToken> {
         Some(TxToken {
             lower: self.lower.clone(),
+            offload: self.offload,
         })
     }
 }
@@ -101,6 +149,7 @@ impl<'a> Device<'a> for TapInterface {
 #[doc(hidden)]
 pub struct RxToken {
     buffer: Vec<u8>,
+    verify: bool, // the checksum was not verified by the kernel
 }
 
 impl phy::RxToken for RxToken {
@@ -108,6 +157,9 @@ impl phy::RxToken for RxToken {
     where
         F: FnOnce(&mut [u8]) -> Result<R>,
     {
+        if self.verify && !vnet::checksum_valid(&self.buffer) {
+            return Err(smoltcp::Error::Checksum);
+        }
         let mut buffer = self.buffer.clone();
         f(&mut buffer[..])
     }
@@ -116,6 +168,7 @@ impl phy::RxToken for RxToken {
 #[doc(hidden)]
 pub struct TxToken {
     lower: Arc<RwLock<tap_interface_sys::TapInterfaceDesc>>,
+    offload: bool,
 }
 
 impl phy::TxToken for TxToken {
@@ -124,6 +177,12 @@ impl phy::TxToken for TxToken {
         F: FnOnce(&mut [u8]) -> Result<R>,
     {
         let mut lower = self.lower.write().unwrap();
+        if self.offload {
+            let mut buffer = vec![0; VNET_HDR_LEN + len];
+            let result = f(&mut buffer[VNET_HDR_LEN..]);
+            lower.send_merged(buffer).unwrap();
+            return result;
+        }
         let mut buffer = vec![0; len];
         let result = f(&mut buffer);
         lower.send(&buffer[..]).unwrap();
@@ -4,18 +4,29 @@ use std::os::unix::io::{AsRawFd, RawFd};
 use std::string::{String, ToString};
 
 use super::{
-    ifreq, ifreq_for, ifreq_ioctl, IFF_NO_PI, IFF_TAP, SIOCGIFINDEX, SIOCGIFMTU, TUNSETIFF,
+    ifreq, ifreq_for, ifreq_ioctl, IFF_NO_PI, IFF_TAP, IFF_VNET_HDR, SIOCGIFINDEX, SIOCGIFMTU,
+    TUNGETFEATURES, TUNSETIFF, TUNSETOFFLOAD, TUN_F_CSUM,
 };
+use crate::uring::Uring;
+use crate::vnet::Segments;
+use crate::RingOccupancy;
 
 #[derive(Debug)]
 pub struct TapInterfaceDesc {
+    ring: Option<Uring>,
     lower: libc::c_int,
     ifreq: ifreq,
+    segments: Segments, // merged TCP segments not sent yet
 }
 
 impl AsRawFd for TapInterfaceDesc {
+    /// The file descriptor of the io_uring if it is used, the one of the interface is then
+    /// not readable because the queued reads take all frames
     fn as_raw_fd(&self) -> RawFd {
-        self.lower
+        match self.ring {
+            Some(ref ring) => ring.poll_fd(),
+            None => self.lower,
+        }
     }
 }
 
@@ -33,8 +44,10 @@ impl TapInterfaceDesc {
         };
 
         Ok(TapInterfaceDesc {
+            ring: None,
             lower: lower,
             ifreq: ifreq_for(name),
+            segments: Segments::default(),
         })
     }
 
@@ -72,14 +85,30 @@ impl TapInterfaceDesc {
         };
 
         Ok(TapInterfaceDesc {
+            ring: None,
             lower: lower,
             ifreq: ifreq,
+            segments: Segments::default(),
         })
     }
 
-    pub fn attach_interface(&mut self) -> io::Result<()> {
+    /// Attaches with a virtio-net header in front of each frame if the kernel supports it,
+    /// returns whether the header is used
+    pub fn attach_interface(&mut self) -> io::Result<bool> {
+        let mut features: libc::c_uint = 0;
+        let res = unsafe { libc::ioctl(self.lower, TUNGETFEATURES, &mut features as *mut _) };
+        let vnet_hdr = res != -1 && features & IFF_VNET_HDR as libc::c_uint != 0;
         self.ifreq.ifr_data = IFF_TAP | IFF_NO_PI;
-        ifreq_ioctl(self.lower, &mut self.ifreq, TUNSETIFF).map(|_| ())
+        if vnet_hdr {
+            self.ifreq.ifr_data |= IFF_VNET_HDR;
+        }
+        ifreq_ioctl(self.lower, &mut self.ifreq, TUNSETIFF)?;
+        if vnet_hdr {
+            // the frames from the same host may then come with a partial checksum,
+            // which saves the kernel the checksum computation
+            unsafe { libc::ioctl(self.lower, TUNSETOFFLOAD, TUN_F_CSUM) };
+        }
+        Ok(vnet_hdr)
     }
 
     pub fn interface_mtu(&mut self) -> io::Result<usize> {
@@ -100,7 +129,42 @@ impl TapInterfaceDesc {
         mtu
     }
 
+    /// Switches from one read and write syscall per frame to batches submitted over an
+    /// io_uring, keeps using the syscalls if io_uring is not available
+    pub fn enable_io_uring(&mut self, frame_len: usize) -> bool {
+        match Uring::new(self.lower, frame_len) {
+            Ok(ring) => {
+                self.ring = Some(ring);
+                true
+            }
+            Err(_) => false,
+        }
+    }
+
+    pub fn uses_io_uring(&self) -> bool {
+        self.ring.is_some()
+    }
+
+    /// The occupied RX and TX buffers of the io_uring, None without it
+    pub fn ring_occupancy(&self) -> Option<(RingOccupancy, RingOccupancy)> {
+        self.ring.as_ref().map(Uring::occupancy)
+    }
+
+    /// Submits the frames which were queued by `send` or are merged by `send_merged`
+    pub fn flush(&mut self) -> io::Result<()> {
+        if let Some(frame) = self.segments.take() {
+            self.send(&frame)?;
+        }
+        match self.ring {
+            Some(ref mut ring) => ring.flush(),
+            None => Ok(()),
+        }
+    }
+
     pub fn recv(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        if let Some(ref mut ring) = self.ring {
+            return ring.recv(buffer);
+        }
         unsafe {
             let len = libc::read(
                 self.lower,
@@ -115,6 +179,9 @@ impl TapInterfaceDesc {
     }
 
     pub fn send(&mut self, buffer: &[u8]) -> io::Result<usize> {
+        if let Some(ref mut ring) = self.ring {
+            return ring.send(buffer);
+        }
         unsafe {
             let len = libc::write(
                 self.lower,
@@ -127,10 +194,20 @@ impl TapInterfaceDesc {
             Ok(len as usize)
         }
     }
+
+    /// Sends a frame with the virtio-net header space in front, consecutive TCP segments
+    /// are merged and sent on the next frame or `flush`
+    pub fn send_merged(&mut self, buffer: Vec<u8>) -> io::Result<()> {
+        for frame in self.segments.push(buffer) {
+            self.send(&frame)?;
+        }
+        Ok(())
+    }
 }
 
 impl Drop for TapInterfaceDesc {
     fn drop(&mut self) {
+        self.ring.take(); // cancels the queued reads and writes
         unsafe {
             libc::close(self.lower);
         }
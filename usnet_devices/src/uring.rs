@@ -0,0 +1,403 @@
+use std::collections::VecDeque;
+use std::io;
+use std::mem;
+use std::ptr;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+use libc;
+use libc::c_int;
+
+use crate::xsk::{check, Fd, Mapping};
+use crate::RingOccupancy;
+
+const SYS_IO_URING_SETUP: libc::c_long = 425;
+const SYS_IO_URING_ENTER: libc::c_long = 426;
+const SYS_IO_URING_REGISTER: libc::c_long = 427;
+const IORING_OFF_SQ_RING: libc::off_t = 0;
+const IORING_OFF_CQ_RING: libc::off_t = 0x8000000;
+const IORING_OFF_SQES: libc::off_t = 0x10000000;
+const IORING_OP_READ_FIXED: u8 = 4;
+const IORING_OP_WRITE_FIXED: u8 = 5;
+const IORING_ENTER_GETEVENTS: u32 = 1;
+const IORING_REGISTER_BUFFERS: u32 = 0;
+
+const RING_ENTRIES: u32 = 256;
+// reads which are kept queued in the kernel, each on its own registered buffer
+const RX_FRAMES: usize = 16;
+// writes which can be in flight, on the buffers after the RX buffers
+const TX_FRAMES: usize = 64;
+// queued writes are submitted together once there are this many
+const TX_BATCH: u32 = 32;
+const TX_FLAG: u64 = 1 << 63;
+
+#[repr(C)]
+#[derive(Default)]
+struct io_sqring_offsets {
+    head: u32,
+    tail: u32,
+    ring_mask: u32,
+    ring_entries: u32,
+    flags: u32,
+    dropped: u32,
+    array: u32,
+    resv1: u32,
+    user_addr: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct io_cqring_offsets {
+    head: u32,
+    tail: u32,
+    ring_mask: u32,
+    ring_entries: u32,
+    overflow: u32,
+    cqes: u32,
+    flags: u32,
+    resv1: u32,
+    user_addr: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct io_uring_params {
+    sq_entries: u32,
+    cq_entries: u32,
+    flags: u32,
+    sq_thread_cpu: u32,
+    sq_thread_idle: u32,
+    features: u32,
+    wq_fd: u32,
+    resv: [u32; 3],
+    sq_off: io_sqring_offsets,
+    cq_off: io_cqring_offsets,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct io_uring_sqe {
+    opcode: u8,
+    flags: u8,
+    ioprio: u16,
+    fd: i32,
+    off: u64,
+    addr: u64,
+    len: u32,
+    rw_flags: u32,
+    user_data: u64,
+    buf_index: u16,
+    personality: u16,
+    splice_fd_in: i32,
+    addr3: u64,
+    pad: u64,
+}
+
+#[repr(C)]
+struct io_uring_cqe {
+    user_data: u64,
+    res: i32,
+    flags: u32,
+}
+
+/// io_uring for the reads and writes of a file descriptor with one frame per operation
+///
+/// All RX buffers have a read queued in the kernel, except the ones with a received frame
+/// that was not handed out yet. A TX buffer is free again when the completion of its write
+/// arrives. Completions arrive in any order, the user data of an operation tells which
+/// buffer it used. The ring file descriptor is readable while the CQ has completions.
+#[derive(Debug)]
+pub struct Uring {
+    // closed first so that the kernel is done with the buffers before they are unmapped,
+    // the registered buffers stay pinned until the ring is gone
+    fd: Fd,
+    target: c_int,
+    _sq_mapping: Mapping,
+    _cq_mapping: Mapping,
+    sqes: Mapping,
+    buffers: Mapping,
+    frame_size: usize,
+    sq_ktail: *const AtomicU32,
+    sq_mask: u32,
+    sq_array: *mut u32,
+    sq_tail: u32,
+    to_submit: u32,
+    cq_khead: *const AtomicU32,
+    cq_ktail: *const AtomicU32,
+    cq_mask: u32,
+    cqes: *const io_uring_cqe,
+    received: VecDeque<(usize, usize)>, // RX buffer and frame length, in completion order
+    tx_free: Vec<usize>,
+    error: Option<io::Error>, // of a failed read, reported on the next receive
+}
+
+unsafe impl Send for Uring {}
+unsafe impl Sync for Uring {}
+
+fn at<T>(mapping: &Mapping, offset: u32) -> *mut T {
+    unsafe { mapping.addr.add(offset as usize) as *mut T }
+}
+
+impl Uring {
+    /// Sets up a ring for the file descriptor and queues the reads, fails on kernels without
+    /// io_uring support or when the buffers cannot be registered
+    pub fn new(target: c_int, frame_len: usize) -> io::Result<Uring> {
+        let mut params = io_uring_params::default();
+        let fd = Fd(check(unsafe {
+            libc::syscall(
+                SYS_IO_URING_SETUP,
+                RING_ENTRIES,
+                &mut params as *mut io_uring_params,
+            )
+        } as c_int)?);
+        let sq_len = params.sq_off.array as usize + params.sq_entries as usize * 4;
+        let cq_len = params.cq_off.cqes as usize
+            + params.cq_entries as usize * mem::size_of::<io_uring_cqe>();
+        let sq_mapping = Mapping::new(fd.0, IORING_OFF_SQ_RING, sq_len)?;
+        let cq_mapping = Mapping::new(fd.0, IORING_OFF_CQ_RING, cq_len)?;
+        let sqes = Mapping::new(
+            fd.0,
+            IORING_OFF_SQES,
+            params.sq_entries as usize * mem::size_of::<io_uring_sqe>(),
+        )?;
+
+        let frame_size = (frame_len + 63) & !63;
+        let buffers = Mapping::new(-1, 0, frame_size * (RX_FRAMES + TX_FRAMES))?;
+        let iovecs: Vec<libc::iovec> = (0..RX_FRAMES + TX_FRAMES)
+            .map(|i| libc::iovec {
+                iov_base: unsafe { buffers.addr.add(i * frame_size) } as *mut libc::c_void,
+                iov_len: frame_size,
+            })
+            .collect();
+        check(unsafe {
+            libc::syscall(
+                SYS_IO_URING_REGISTER,
+                fd.0,
+                IORING_REGISTER_BUFFERS,
+                iovecs.as_ptr(),
+                iovecs.len() as u32,
+            )
+        } as c_int)?;
+
+        let sq_ktail = at::<AtomicU32>(&sq_mapping, params.sq_off.tail);
+        let mut ring = Uring {
+            sq_ktail: sq_ktail,
+            sq_mask: unsafe { *at::<u32>(&sq_mapping, params.sq_off.ring_mask) },
+            sq_array: at(&sq_mapping, params.sq_off.array),
+            sq_tail: unsafe { (*sq_ktail).load(Ordering::Relaxed) },
+            to_submit: 0,
+            cq_khead: at(&cq_mapping, params.cq_off.head),
+            cq_ktail: at(&cq_mapping, params.cq_off.tail),
+            cq_mask: unsafe { *at::<u32>(&cq_mapping, params.cq_off.ring_mask) },
+            cqes: at(&cq_mapping, params.cq_off.cqes),
+            fd: fd,
+            target: target,
+            _sq_mapping: sq_mapping,
+            _cq_mapping: cq_mapping,
+            sqes: sqes,
+            buffers: buffers,
+            frame_size: frame_size,
+            received: VecDeque::with_capacity(RX_FRAMES),
+            tx_free: (RX_FRAMES..RX_FRAMES + TX_FRAMES).collect(),
+            error: None,
+        };
+        for frame in 0..RX_FRAMES {
+            ring.queue_read(frame);
+        }
+        ring.enter(0)?;
+        Ok(ring)
+    }
+
+    /// The ring file descriptor, readable when completions arrived
+    pub fn poll_fd(&self) -> c_int {
+        self.fd.0
+    }
+
+    fn frame(&self, buffer: usize) -> *mut u8 {
+        unsafe { self.buffers.addr.add(buffer * self.frame_size) }
+    }
+
+    fn push(&mut self, sqe: io_uring_sqe) {
+        // at most all buffers are in flight and the SQ has room for more
+        debug_assert!(self.to_submit < RING_ENTRIES);
+        let index = self.sq_tail & self.sq_mask;
+        unsafe {
+            ptr::write(
+                (self.sqes.addr as *mut io_uring_sqe).add(index as usize),
+                sqe,
+            );
+            *self.sq_array.add(index as usize) = index;
+        }
+        self.sq_tail = self.sq_tail.wrapping_add(1);
+        unsafe { (*self.sq_ktail).store(self.sq_tail, Ordering::Release) };
+        self.to_submit += 1;
+    }
+
+    fn queue_read(&mut self, buffer: usize) {
+        let sqe = io_uring_sqe {
+            opcode: IORING_OP_READ_FIXED,
+            fd: self.target,
+            addr: self.frame(buffer) as u64,
+            len: self.frame_size as u32,
+            buf_index: buffer as u16,
+            user_data: buffer as u64,
+            ..Default::default()
+        };
+        self.push(sqe);
+    }
+
+    /// Submits the queued operations and waits for at least `min_complete` completions
+    fn enter(&mut self, min_complete: u32) -> io::Result<()> {
+        loop {
+            let flags = if min_complete > 0 {
+                IORING_ENTER_GETEVENTS
+            } else {
+                0
+            };
+            let res = unsafe {
+                libc::syscall(
+                    SYS_IO_URING_ENTER,
+                    self.fd.0,
+                    self.to_submit,
+                    min_complete,
+                    flags,
+                    ptr::null::<libc::sigset_t>(),
+                    0usize,
+                )
+            };
+            if res == -1 {
+                let err = io::Error::last_os_error();
+                if err.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(err);
+            }
+            self.to_submit -= res as u32;
+            if self.to_submit == 0 {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Takes the completions from the CQ, the buffers of writes become free and the ones of
+    /// reads are queued for receiving
+    fn reap(&mut self) {
+        let mut head = unsafe { (*self.cq_khead).load(Ordering::Relaxed) };
+        let tail = unsafe { (*self.cq_ktail).load(Ordering::Acquire) };
+        while head != tail {
+            let (user_data, res) = unsafe {
+                let cqe = &*self.cqes.add((head & self.cq_mask) as usize);
+                (cqe.user_data, cqe.res)
+            };
+            head = head.wrapping_add(1);
+            if user_data & TX_FLAG != 0 {
+                // a failed write is a dropped frame, as with a full NIC queue
+                self.tx_free.push((user_data & !TX_FLAG) as usize);
+                continue;
+            }
+            let buffer = user_data as usize;
+            if res > 0 {
+                self.received.push_back((buffer, res as usize));
+                continue;
+            }
+            if res < 0 && ![libc::EAGAIN, libc::EINTR, libc::ECANCELED].contains(&-res) {
+                self.error = Some(io::Error::from_raw_os_error(-res));
+            }
+            self.queue_read(buffer);
+        }
+        unsafe { (*self.cq_khead).store(head, Ordering::Release) };
+    }
+
+    /// Copies the next received frame and queues the read on its buffer again
+    pub fn recv(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        if self.received.is_empty() {
+            self.reap();
+        }
+        if let Some(err) = self.error.take() {
+            return Err(err);
+        }
+        match self.received.pop_front() {
+            Some((frame, len)) => {
+                let len = len.min(buffer.len());
+                unsafe { ptr::copy_nonoverlapping(self.frame(frame), buffer.as_mut_ptr(), len) };
+                self.queue_read(frame);
+                Ok(len)
+            }
+            None => {
+                // the reads of the handed out frames go to the kernel before the stack waits
+                self.enter(0)?;
+                Err(io::Error::new(
+                    io::ErrorKind::WouldBlock,
+                    "no frame received",
+                ))
+            }
+        }
+    }
+
+    /// Queues a write of the frame, the writes are submitted in batches or on `flush`
+    /// Waits for a completion if all TX buffers are in flight.
+    pub fn send(&mut self, buffer: &[u8]) -> io::Result<usize> {
+        if self.tx_free.is_empty() {
+            self.reap();
+        }
+        while self.tx_free.is_empty() {
+            self.enter(1)?;
+            self.reap();
+        }
+        let frame = self.tx_free.pop().unwrap();
+        let len = buffer.len().min(self.frame_size);
+        unsafe { ptr::copy_nonoverlapping(buffer.as_ptr(), self.frame(frame), len) };
+        let sqe = io_uring_sqe {
+            opcode: IORING_OP_WRITE_FIXED,
+            fd: self.target,
+            addr: self.frame(frame) as u64,
+            len: len as u32,
+            buf_index: frame as u16,
+            user_data: TX_FLAG | frame as u64,
+            ..Default::default()
+        };
+        self.push(sqe);
+        if self.to_submit >= TX_BATCH {
+            self.enter(0)?;
+        }
+        Ok(len)
+    }
+
+    /// Returns the received frames which were not read yet, also the completions between head
+    /// and tail of the CQ which were not taken, and the queued or unfinished writes, of the
+    /// RX and TX buffers
+    pub fn occupancy(&self) -> (RingOccupancy, RingOccupancy) {
+        let mut received = self.received.len();
+        let mut written = 0;
+        let mut head = unsafe { (*self.cq_khead).load(Ordering::Relaxed) };
+        let tail = unsafe { (*self.cq_ktail).load(Ordering::Acquire) };
+        while head != tail {
+            let (user_data, res) = unsafe {
+                let cqe = &*self.cqes.add((head & self.cq_mask) as usize);
+                (cqe.user_data, cqe.res)
+            };
+            head = head.wrapping_add(1);
+            if user_data & TX_FLAG != 0 {
+                written += 1;
+            } else if res > 0 {
+                received += 1;
+            }
+        }
+        let rx = RingOccupancy {
+            used: received,
+            size: RX_FRAMES,
+        };
+        let tx = RingOccupancy {
+            used: TX_FRAMES - self.tx_free.len() - written,
+            size: TX_FRAMES,
+        };
+        (rx, tx)
+    }
+
+    /// Submits the queued writes and reads
+    pub fn flush(&mut self) -> io::Result<()> {
+        if self.to_submit > 0 {
+            self.enter(0)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,273 @@
+/// The virtio-net header in front of each frame of a TAP or MACVTAP file descriptor with
+/// IFF_VNET_HDR, which carries the checksum and segmentation offload metadata.
+/// Outgoing TCP and UDP frames leave the checksum to the kernel or the NIC, and consecutive
+/// TCP segments of a connection are merged into one frame which is segmented again there.
+
+/// Size of struct virtio_net_hdr, the default of the kernel for TAP and MACVTAP
+pub const VNET_HDR_LEN: usize = 10;
+/// Largest merged frame including the header, also the buffer size of the io_uring then
+pub const GSO_MAX_FRAME: usize = VNET_HDR_LEN + 32768;
+
+const F_NEEDS_CSUM: u8 = 1;
+const F_DATA_VALID: u8 = 2;
+const GSO_TCPV4: u8 = 1;
+
+const ETH_HDR: usize = 14;
+const IPV4_HDR: usize = 20;
+const IPV6_HDR: usize = 40;
+const PROTO_TCP: u8 = 6;
+const PROTO_UDP: u8 = 17;
+const TCP_PSH: u8 = 0x08;
+const TCP_ACK: u8 = 0x10;
+
+/// Location of the TCP or UDP header in an Ethernet frame
+struct L4 {
+    start: usize,
+    len: usize,
+    protocol: u8,
+    pseudo_header: u32, // sum of the addresses and the protocol, without the length
+    ipv4: bool,
+}
+
+fn be16(data: &[u8], at: usize) -> u16 {
+    u16::from_be_bytes([data[at], data[at + 1]])
+}
+
+fn sum(data: &[u8]) -> u32 {
+    let mut sum = 0u32;
+    for word in data.chunks(2) {
+        let high = (word[0] as u32) << 8;
+        sum += high | word.get(1).map_or(0, |low| *low as u32);
+        if sum > 0xffff {
+            sum = (sum & 0xffff) + 1;
+        }
+    }
+    sum
+}
+
+fn fold(mut sum: u32) -> u16 {
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    sum as u16
+}
+
+fn l4(frame: &[u8]) -> Option<L4> {
+    if frame.len() < ETH_HDR + IPV4_HDR {
+        return None;
+    }
+    let ip = &frame[ETH_HDR..];
+    let (start, len, protocol, addresses, ipv4) = match be16(frame, 12) {
+        0x0800 => {
+            let header_len = (ip[0] & 0x0f) as usize * 4;
+            let total_len = be16(ip, 2) as usize;
+            if ip[0] >> 4 != 4 || be16(ip, 6) & 0x3fff != 0 {
+                return None; // fragmented
+            }
+            if header_len < IPV4_HDR || total_len < header_len || total_len > ip.len() {
+                return None;
+            }
+            (header_len, total_len - header_len, ip[9], &ip[12..20], true)
+        }
+        0x86dd if ip.len() >= IPV6_HDR => {
+            let payload_len = be16(ip, 4) as usize;
+            if IPV6_HDR + payload_len > ip.len() {
+                return None;
+            }
+            // the stack sends no extension headers, the others are not offloaded
+            (IPV6_HDR, payload_len, ip[6], &ip[8..40], false)
+        }
+        _ => return None,
+    };
+    let header_len = match protocol {
+        PROTO_TCP => 20,
+        PROTO_UDP => 8,
+        _ => return None,
+    };
+    if len < header_len {
+        return None;
+    }
+    Some(L4 {
+        start: ETH_HDR + start,
+        len: len,
+        protocol: protocol,
+        pseudo_header: sum(addresses) + protocol as u32,
+        ipv4: ipv4,
+    })
+}
+
+/// Fills the header in front of the frame, the checksum of TCP and UDP is completed by
+/// the kernel or the NIC from the pseudo header checksum in the frame
+pub fn offload_checksum(buffer: &mut [u8]) {
+    let (header, frame) = buffer.split_at_mut(VNET_HDR_LEN);
+    for byte in header.iter_mut() {
+        *byte = 0;
+    }
+    let l4 = match l4(frame) {
+        Some(l4) => l4,
+        None => return,
+    };
+    let offset = if l4.protocol == PROTO_TCP { 16 } else { 6 };
+    let checksum = fold(l4.pseudo_header + l4.len as u32);
+    frame[l4.start + offset..l4.start + offset + 2].copy_from_slice(&checksum.to_be_bytes());
+    header[0] = F_NEEDS_CSUM;
+    header[6..8].copy_from_slice(&(l4.start as u16).to_ne_bytes());
+    header[8..10].copy_from_slice(&(offset as u16).to_ne_bytes());
+}
+
+/// Whether the kernel already verified the checksum of the received frame, or left it
+/// partial because the frame comes from the same host
+pub fn checksum_verified(header: &[u8]) -> bool {
+    header[0] & (F_NEEDS_CSUM | F_DATA_VALID) != 0
+}
+
+/// Verifies the TCP or UDP checksum of a received Ethernet frame, other frames are left
+/// to the stack
+pub fn checksum_valid(frame: &[u8]) -> bool {
+    let l4 = match l4(frame) {
+        Some(l4) => l4,
+        None => return true,
+    };
+    if l4.protocol == PROTO_UDP && l4.ipv4 && be16(frame, l4.start + 6) == 0 {
+        return true; // sent without checksum
+    }
+    let data = &frame[l4.start..l4.start + l4.len];
+    fold(l4.pseudo_header + l4.len as u32 + sum(data)) == 0xffff
+}
+
+/// A TCP segment over IPv4 which can be merged with the following ones
+struct Segment {
+    payload: usize, // offset in the frame
+    len: usize,
+    seq: u32,
+    flags: u8,
+}
+
+fn segment(frame: &[u8]) -> Option<Segment> {
+    let l4 = l4(frame)?;
+    if !l4.ipv4 || l4.protocol != PROTO_TCP || l4.start != ETH_HDR + IPV4_HDR {
+        return None;
+    }
+    let tcp = &frame[l4.start..];
+    let header_len = (tcp[12] >> 4) as usize * 4;
+    let flags = tcp[13];
+    if l4.start + l4.len != frame.len() || header_len < 20 || header_len >= l4.len {
+        return None;
+    }
+    if flags & !TCP_PSH != TCP_ACK || tcp[1] & 0x01 != 0 {
+        return None; // SYN, FIN, RST, URG, or ECN flags
+    }
+    Some(Segment {
+        payload: l4.start + header_len,
+        len: l4.len - header_len,
+        seq: u32::from_be_bytes([tcp[4], tcp[5], tcp[6], tcp[7]]),
+        flags: flags,
+    })
+}
+
+/// Whether the headers only differ in the IP identification, the lengths, the checksums,
+/// the sequence number, and the PSH flag
+fn same_connection(first: &[u8], frame: &[u8], payload: usize) -> bool {
+    let tcp = ETH_HDR + IPV4_HDR;
+    first[..ETH_HDR] == frame[..ETH_HDR]
+        && first[ETH_HDR + 1] == frame[ETH_HDR + 1]
+        && first[ETH_HDR + 8..ETH_HDR + 10] == frame[ETH_HDR + 8..ETH_HDR + 10]
+        && first[ETH_HDR + 12..tcp] == frame[ETH_HDR + 12..tcp]
+        && first[tcp..tcp + 4] == frame[tcp..tcp + 4]
+        && first[tcp + 8..tcp + 13] == frame[tcp + 8..tcp + 13]
+        && first[tcp + 14..tcp + 16] == frame[tcp + 14..tcp + 16]
+        && first.len() > payload
+        && first[tcp + 20..payload] == frame[tcp + 20..payload]
+}
+
+/// Merges consecutive TCP segments of a connection over IPv4 into one frame for the
+/// segmentation offload, the segments must have the size of the first one except for the
+/// last, which may be shorter
+#[derive(Debug, Default)]
+pub struct Segments {
+    pending: Option<Vec<u8>>, // with the header in front
+    payload: usize,           // offset of the payload in the frame
+    segment_size: usize,
+    count: usize,
+    next_seq: u32,
+    closed: bool,
+}
+
+impl Segments {
+    /// Adds a frame with the header space in front, returns the frames to send before the
+    /// next one is added
+    pub fn push(&mut self, mut buffer: Vec<u8>) -> Vec<Vec<u8>> {
+        let mut ready = vec![];
+        match segment(&buffer[VNET_HDR_LEN..]) {
+            Some(segment) => {
+                if self.merge(&buffer, &segment) {
+                    return ready;
+                }
+                ready.extend(self.take());
+                self.payload = segment.payload;
+                self.segment_size = segment.len;
+                self.count = 1;
+                self.next_seq = segment.seq.wrapping_add(segment.len as u32);
+                self.closed = segment.flags & TCP_PSH != 0;
+                self.pending = Some(buffer);
+            }
+            None => {
+                ready.extend(self.take());
+                offload_checksum(&mut buffer);
+                ready.push(buffer);
+            }
+        }
+        ready
+    }
+
+    fn merge(&mut self, buffer: &[u8], segment: &Segment) -> bool {
+        let pending = match self.pending {
+            Some(ref mut pending) => pending,
+            None => return false,
+        };
+        if self.closed
+            || segment.seq != self.next_seq
+            || segment.len > self.segment_size
+            || segment.payload != self.payload
+            || pending.len() + segment.len > GSO_MAX_FRAME
+            || !same_connection(
+                &pending[VNET_HDR_LEN..],
+                &buffer[VNET_HDR_LEN..],
+                segment.payload,
+            )
+        {
+            return false;
+        }
+        pending.extend_from_slice(&buffer[VNET_HDR_LEN + segment.payload..]);
+        if segment.flags & TCP_PSH != 0 {
+            pending[VNET_HDR_LEN + ETH_HDR + IPV4_HDR + 13] |= TCP_PSH;
+            self.closed = true;
+        }
+        if segment.len < self.segment_size {
+            self.closed = true;
+        }
+        self.next_seq = segment.seq.wrapping_add(segment.len as u32);
+        self.count += 1;
+        true
+    }
+
+    /// Completes the headers of the merged frame
+    pub fn take(&mut self) -> Option<Vec<u8>> {
+        let mut buffer = self.pending.take()?;
+        if self.count > 1 {
+            let ip = VNET_HDR_LEN + ETH_HDR;
+            let total_len = (buffer.len() - ip) as u16;
+            buffer[ip + 2..ip + 4].copy_from_slice(&total_len.to_be_bytes());
+            buffer[ip + 10..ip + 12].copy_from_slice(&[0, 0]);
+            let checksum = !fold(sum(&buffer[ip..ip + IPV4_HDR]));
+            buffer[ip + 10..ip + 12].copy_from_slice(&checksum.to_be_bytes());
+        }
+        offload_checksum(&mut buffer);
+        if self.count > 1 {
+            buffer[1] = GSO_TCPV4;
+            buffer[2..4].copy_from_slice(&(self.payload as u16).to_ne_bytes());
+            buffer[4..6].copy_from_slice(&(self.segment_size as u16).to_ne_bytes());
+        }
+        Some(buffer)
+    }
+}
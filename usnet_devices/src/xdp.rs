@@ -0,0 +1,141 @@
+use std::io;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::slice;
+use std::sync::{Arc, RwLock};
+
+use smoltcp::phy;
+use smoltcp::phy::{Device, DeviceCapabilities};
+use smoltcp::time::Instant;
+use smoltcp::Result;
+
+use crate::xsk;
+
+use crate::SMOLTCP_ETHERNET_HEADER;
+
+/// AF_XDP socket on one queue of a NIC, the frames are steered to it by an XDP program.
+/// The UMEM frames are owned by the kernel while they are in the fill and TX ring.
+/// Received frames are handed to smoltcp without copying and go back to the fill ring
+/// after processing.
+#[derive(Debug)]
+pub struct Xdp {
+    lower: Arc<RwLock<xsk::XskDesc>>,
+    mtu: usize,
+    reduce_mtu_by: Option<usize>,
+}
+
+impl AsRawFd for Xdp {
+    fn as_raw_fd(&self) -> RawFd {
+        self.lower.read().unwrap().as_raw_fd()
+    }
+}
+
+impl Xdp {
+    /// Binds an AF_XDP socket to the queue of the interface, in zero-copy mode if the
+    /// driver supports it and otherwise in copy mode.
+    ///
+    /// The `program` is the path of an XDP program pinned in the BPF filesystem which is
+    /// attached to the interface and detached again on drop. Without it, the program that is
+    /// already attached is used. The socket is registered for the queue in the XSKMAP of the
+    /// program.
+    /// If `busy_poll` is set, the socket prefers busy polling and every flush and drained
+    /// RX ring lets the driver process the rings, otherwise it only does so when the driver
+    /// waits for a wakeup.
+    pub fn new(
+        interface: &str,
+        queue_id: u32,
+        busy_poll: bool,
+        program: Option<&str>,
+        reduce_mtu_by: Option<usize>,
+    ) -> io::Result<Xdp> {
+        let lower = xsk::XskDesc::new(interface, queue_id, busy_poll, program)?;
+        let mtu = lower.interface_mtu()? + SMOLTCP_ETHERNET_HEADER;
+        Ok(Xdp {
+            lower: Arc::new(RwLock::new(lower)),
+            mtu: mtu.min(xsk::MAX_FRAME_LEN),
+            reduce_mtu_by: reduce_mtu_by,
+        })
+    }
+
+    /// Whether the driver hands the UMEM frames to the NIC without copying
+    pub fn zero_copy(&self) -> bool {
+        self.lower.read().unwrap().zero_copy()
+    }
+
+    /// Submits the frames which were sent and received since the last flush and wakes up
+    /// the driver once, otherwise this only happens once the rings are full or drained
+    pub fn flush(&mut self) {
+        self.lower.write().unwrap().flush();
+    }
+}
+
+impl<'a> Device<'a> for Xdp {
+    type RxToken = RxToken;
+    type TxToken = TxToken;
+
+    fn capabilities(&self) -> DeviceCapabilities {
+        let mut caps = DeviceCapabilities::default();
+        caps.max_transmission_unit = self.mtu - self.reduce_mtu_by.unwrap_or(0);
+        caps
+    }
+
+    fn receive(&'a mut self) -> Option<(Self::RxToken, Self::TxToken)> {
+        let (desc, data) = self.lower.write().unwrap().recv()?;
+        let rx = RxToken {
+            lower: self.lower.clone(),
+            desc: desc,
+            data: data,
+        };
+        // the responses are sent while the received frame is processed
+        let tx = TxToken {
+            lower: self.lower.clone(),
+        };
+        Some((rx, tx))
+    }
+
+    fn transmit(&'a mut self) -> Option<Self::TxToken> {
+        if !self.lower.write().unwrap().send_ready() {
+            return None;
+        }
+        Some(TxToken {
+            lower: self.lower.clone(),
+        })
+    }
+}
+
+#[doc(hidden)]
+pub struct RxToken {
+    lower: Arc<RwLock<xsk::XskDesc>>,
+    desc: xsk::xdp_desc,
+    data: *mut u8, // into the UMEM, owned by the token until it is dropped
+}
+
+impl phy::RxToken for RxToken {
+    fn consume<R, F: FnOnce(&mut [u8]) -> Result<R>>(self, _timestamp: Instant, f: F) -> Result<R> {
+        // no lock is held because the TxToken is used inside of f
+        let buffer = unsafe { slice::from_raw_parts_mut(self.data, self.desc.len as usize) };
+        f(buffer)
+    }
+}
+
+impl Drop for RxToken {
+    fn drop(&mut self) {
+        self.lower.write().unwrap().recycle(self.desc);
+    }
+}
+
+#[doc(hidden)]
+pub struct TxToken {
+    lower: Arc<RwLock<xsk::XskDesc>>,
+}
+
+impl phy::TxToken for TxToken {
+    fn consume<R, F: FnOnce(&mut [u8]) -> Result<R>>(
+        self,
+        _timestamp: Instant,
+        len: usize,
+        f: F,
+    ) -> Result<R> {
+        let mut lower = self.lower.write().unwrap();
+        lower.send(len, f)
+    }
+}
@@ -0,0 +1,842 @@
+use std::ffi::CString;
+use std::fs;
+use std::io;
+use std::mem;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::ptr;
+use std::slice;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+use smoltcp::{Error, Result};
+
+use libc;
+use libc::c_int;
+
+const AF_XDP: c_int = 44;
+const SOL_XDP: c_int = 283;
+const XDP_MMAP_OFFSETS: c_int = 1;
+const XDP_RX_RING: c_int = 2;
+const XDP_TX_RING: c_int = 3;
+const XDP_UMEM_REG: c_int = 4;
+const XDP_UMEM_FILL_RING: c_int = 5;
+const XDP_UMEM_COMPLETION_RING: c_int = 6;
+const XDP_OPTIONS: c_int = 8;
+const XDP_OPTIONS_ZEROCOPY: u32 = 1;
+const XDP_COPY: u16 = 1 << 1;
+const XDP_ZEROCOPY: u16 = 1 << 2;
+const XDP_USE_NEED_WAKEUP: u16 = 1 << 3;
+const XDP_RING_NEED_WAKEUP: u32 = 1;
+const XDP_PGOFF_RX_RING: libc::off_t = 0;
+const XDP_PGOFF_TX_RING: libc::off_t = 0x80000000;
+const XDP_UMEM_PGOFF_FILL_RING: libc::off_t = 0x100000000;
+const XDP_UMEM_PGOFF_COMPLETION_RING: libc::off_t = 0x180000000;
+const XDP_PACKET_HEADROOM: usize = 256;
+
+const SO_BUSY_POLL: c_int = 46;
+const SO_PREFER_BUSY_POLL: c_int = 69;
+const SO_BUSY_POLL_BUDGET: c_int = 70;
+const BUSY_POLL_USECS: c_int = 20;
+const BUSY_POLL_BUDGET: c_int = 64;
+
+const BPF_MAP_UPDATE_ELEM: libc::c_long = 2;
+const BPF_OBJ_GET: libc::c_long = 7;
+const BPF_PROG_GET_FD_BY_ID: libc::c_long = 13;
+const BPF_MAP_GET_FD_BY_ID: libc::c_long = 14;
+const BPF_OBJ_GET_INFO_BY_FD: libc::c_long = 15;
+const BPF_MAP_TYPE_XSKMAP: u32 = 17;
+const MAX_PROG_MAPS: usize = 64;
+
+const NETLINK_ROUTE: c_int = 0;
+const NLMSG_ERROR: u16 = 2;
+const NLM_F_REQUEST: u16 = 1;
+const NLM_F_ACK: u16 = 4;
+const RTM_NEWLINK: u16 = 16;
+const RTM_GETLINK: u16 = 18;
+const RTM_SETLINK: u16 = 19;
+const IFLA_XDP: u16 = 43;
+const IFLA_XDP_FD: u16 = 1;
+const IFLA_XDP_FLAGS: u16 = 3;
+const IFLA_XDP_PROG_ID: u16 = 4;
+const NLA_F_NESTED: u16 = 1 << 15;
+const XDP_FLAGS_UPDATE_IF_NOEXIST: u32 = 1;
+
+/// Size of a UMEM frame, a page as needed by the zero-copy mode of most drivers
+pub const FRAME_SIZE: usize = 4096;
+/// Largest frame that fits into a UMEM frame after the headroom the kernel reserves
+pub const MAX_FRAME_LEN: usize = FRAME_SIZE - XDP_PACKET_HEADROOM;
+const RING_SIZE: u32 = 2048;
+// the first half of the frames is owned by the fill and RX ring, the second half by TX
+const FRAME_COUNT: usize = 2 * RING_SIZE as usize;
+
+#[repr(C)]
+#[derive(Default)]
+struct xdp_ring_offset {
+    producer: u64,
+    consumer: u64,
+    desc: u64,
+    flags: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct xdp_mmap_offsets {
+    rx: xdp_ring_offset,
+    tx: xdp_ring_offset,
+    fr: xdp_ring_offset,
+    cr: xdp_ring_offset,
+}
+
+#[repr(C)]
+struct xdp_umem_reg {
+    addr: u64,
+    len: u64,
+    chunk_size: u32,
+    headroom: u32,
+    flags: u32,
+    tx_metadata_len: u32,
+}
+
+#[repr(C)]
+struct sockaddr_xdp {
+    sxdp_family: u16,
+    sxdp_flags: u16,
+    sxdp_ifindex: u32,
+    sxdp_queue_id: u32,
+    sxdp_shared_umem_fd: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct xdp_desc {
+    pub addr: u64,
+    pub len: u32,
+    options: u32,
+}
+
+#[derive(Debug)]
+pub struct Fd(pub c_int);
+
+impl Drop for Fd {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.0);
+        }
+    }
+}
+
+pub fn check(res: c_int) -> io::Result<c_int> {
+    if res == -1 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(res)
+    }
+}
+
+fn setsockopt<T>(fd: c_int, level: c_int, name: c_int, value: &T) -> io::Result<()> {
+    check(unsafe {
+        libc::setsockopt(
+            fd,
+            level,
+            name,
+            value as *const T as *const libc::c_void,
+            mem::size_of::<T>() as libc::socklen_t,
+        )
+    })
+    .map(|_| ())
+}
+
+fn getsockopt<T>(fd: c_int, level: c_int, name: c_int, value: &mut T) -> io::Result<()> {
+    let mut len = mem::size_of::<T>() as libc::socklen_t;
+    check(unsafe {
+        libc::getsockopt(
+            fd,
+            level,
+            name,
+            value as *mut T as *mut libc::c_void,
+            &mut len,
+        )
+    })
+    .map(|_| ())
+}
+
+/// Memory shared with the kernel, unmapped on drop
+#[derive(Debug)]
+pub struct Mapping {
+    pub addr: *mut u8,
+    len: usize,
+}
+
+impl Mapping {
+    pub fn new(fd: c_int, offset: libc::off_t, len: usize) -> io::Result<Mapping> {
+        let flags = if fd == -1 {
+            libc::MAP_PRIVATE | libc::MAP_ANONYMOUS
+        } else {
+            libc::MAP_SHARED | libc::MAP_POPULATE
+        };
+        let addr = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                flags,
+                fd,
+                offset,
+            )
+        };
+        if addr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Mapping {
+            addr: addr as *mut u8,
+            len: len,
+        })
+    }
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.addr as *mut libc::c_void, self.len);
+        }
+    }
+}
+
+/// Single producer and single consumer ring shared with the kernel
+#[derive(Debug)]
+struct Ring<T> {
+    _mapping: Mapping, // unmapped on drop
+    producer: *const AtomicU32,
+    consumer: *const AtomicU32,
+    flags: *const AtomicU32,
+    descs: *mut T,
+    cached_prod: u32,
+    cached_cons: u32,
+}
+
+impl<T: Copy> Ring<T> {
+    fn new(fd: c_int, offsets: &xdp_ring_offset, pgoff: libc::off_t) -> io::Result<Ring<T>> {
+        let len = offsets.desc as usize + RING_SIZE as usize * mem::size_of::<T>();
+        let mapping = Mapping::new(fd, pgoff, len)?;
+        let at = |offset: u64| unsafe { mapping.addr.add(offset as usize) };
+        let producer = at(offsets.producer) as *const AtomicU32;
+        let consumer = at(offsets.consumer) as *const AtomicU32;
+        let flags = at(offsets.flags) as *const AtomicU32;
+        let descs = at(offsets.desc) as *mut T;
+        let (cached_prod, cached_cons) = unsafe {
+            (
+                (*producer).load(Ordering::Acquire),
+                (*consumer).load(Ordering::Acquire),
+            )
+        };
+        Ok(Ring {
+            _mapping: mapping,
+            producer: producer,
+            consumer: consumer,
+            flags: flags,
+            descs: descs,
+            cached_prod: cached_prod,
+            cached_cons: cached_cons,
+        })
+    }
+    /// Entries that can be produced, for the fill and TX ring
+    fn free(&self) -> u32 {
+        let consumer = unsafe { (*self.consumer).load(Ordering::Acquire) };
+        RING_SIZE - self.cached_prod.wrapping_sub(consumer)
+    }
+    /// Queues an entry, it is handed to the kernel with submit
+    fn produce(&mut self, item: T) {
+        unsafe {
+            *self
+                .descs
+                .add((self.cached_prod & (RING_SIZE - 1)) as usize) = item;
+        }
+        self.cached_prod = self.cached_prod.wrapping_add(1);
+    }
+    fn submit(&mut self) {
+        unsafe { (*self.producer).store(self.cached_prod, Ordering::Release) };
+    }
+    /// Whether entries were produced since the last submit
+    fn pending(&self) -> bool {
+        unsafe { (*self.producer).load(Ordering::Relaxed) != self.cached_prod }
+    }
+    /// Takes the next entry from the RX or completion ring
+    fn consume(&mut self) -> Option<T> {
+        let producer = unsafe { (*self.producer).load(Ordering::Acquire) };
+        if producer == self.cached_cons {
+            return None;
+        }
+        let item = unsafe {
+            *self
+                .descs
+                .add((self.cached_cons & (RING_SIZE - 1)) as usize)
+        };
+        self.cached_cons = self.cached_cons.wrapping_add(1);
+        unsafe { (*self.consumer).store(self.cached_cons, Ordering::Release) };
+        Some(item)
+    }
+    /// Whether the driver only processes the ring after a syscall
+    fn needs_wakeup(&self) -> bool {
+        unsafe { (*self.flags).load(Ordering::Acquire) & XDP_RING_NEED_WAKEUP != 0 }
+    }
+}
+
+/// XDP program redirecting the frames of the queue to the socket
+#[derive(Debug)]
+struct Program {
+    ifindex: u32,
+    attached: bool, // attached by us and detached on drop
+    xsks_map: Fd,
+}
+
+/// AF_XDP socket bound to one queue of an interface, with its UMEM and the four rings
+#[derive(Debug)]
+pub struct XskDesc {
+    program: Option<Program>,
+    rx: Ring<xdp_desc>,
+    tx: Ring<xdp_desc>,
+    fill: Ring<u64>,
+    completion: Ring<u64>,
+    fd: Fd,
+    umem: Mapping,
+    free_tx_frames: Vec<u64>,
+    busy_poll: bool,
+    zero_copy: bool,
+    interface: String,
+}
+
+unsafe impl Send for XskDesc {}
+unsafe impl Sync for XskDesc {}
+
+impl AsRawFd for XskDesc {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.0
+    }
+}
+
+impl XskDesc {
+    /// Tries the zero-copy mode first and falls back to the copy mode if the driver does not
+    /// support it
+    pub fn new(
+        interface: &str,
+        queue_id: u32,
+        busy_poll: bool,
+        program: Option<&str>,
+    ) -> io::Result<XskDesc> {
+        let ifname = CString::new(interface)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "invalid interface name"))?;
+        let ifindex = unsafe { libc::if_nametoindex(ifname.as_ptr()) };
+        if ifindex == 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut desc = match XskDesc::open(interface, ifindex, queue_id, busy_poll, XDP_ZEROCOPY) {
+            Ok(desc) => desc,
+            Err(_) => XskDesc::open(interface, ifindex, queue_id, busy_poll, XDP_COPY)?,
+        };
+        desc.program = Some(steer(ifindex, queue_id, desc.fd.0, program)?);
+        Ok(desc)
+    }
+
+    fn open(
+        interface: &str,
+        ifindex: u32,
+        queue_id: u32,
+        busy_poll: bool,
+        mode: u16,
+    ) -> io::Result<XskDesc> {
+        let umem = Mapping::new(-1, 0, FRAME_COUNT * FRAME_SIZE)?;
+        let fd = Fd(check(unsafe {
+            libc::socket(AF_XDP, libc::SOCK_RAW | libc::SOCK_CLOEXEC, 0)
+        })?);
+        let reg = xdp_umem_reg {
+            addr: umem.addr as u64,
+            len: umem.len as u64,
+            chunk_size: FRAME_SIZE as u32,
+            headroom: 0,
+            flags: 0,
+            tx_metadata_len: 0,
+        };
+        setsockopt(fd.0, SOL_XDP, XDP_UMEM_REG, &reg)?;
+        for &ring in &[
+            XDP_UMEM_FILL_RING,
+            XDP_UMEM_COMPLETION_RING,
+            XDP_RX_RING,
+            XDP_TX_RING,
+        ] {
+            setsockopt(fd.0, SOL_XDP, ring, &RING_SIZE)?;
+        }
+        let mut offsets = xdp_mmap_offsets::default();
+        getsockopt(fd.0, SOL_XDP, XDP_MMAP_OFFSETS, &mut offsets)?;
+        let mut fill = Ring::new(fd.0, &offsets.fr, XDP_UMEM_PGOFF_FILL_RING)?;
+        let completion = Ring::new(fd.0, &offsets.cr, XDP_UMEM_PGOFF_COMPLETION_RING)?;
+        let rx = Ring::new(fd.0, &offsets.rx, XDP_PGOFF_RX_RING)?;
+        let tx = Ring::new(fd.0, &offsets.tx, XDP_PGOFF_TX_RING)?;
+        for frame in 0..RING_SIZE as u64 {
+            fill.produce(frame * FRAME_SIZE as u64);
+        }
+        fill.submit();
+
+        let sockaddr = sockaddr_xdp {
+            sxdp_family: AF_XDP as u16,
+            sxdp_flags: mode | XDP_USE_NEED_WAKEUP,
+            sxdp_ifindex: ifindex,
+            sxdp_queue_id: queue_id,
+            sxdp_shared_umem_fd: 0,
+        };
+        check(unsafe {
+            libc::bind(
+                fd.0,
+                &sockaddr as *const sockaddr_xdp as *const libc::sockaddr,
+                mem::size_of::<sockaddr_xdp>() as libc::socklen_t,
+            )
+        })?;
+        if busy_poll {
+            setsockopt(fd.0, libc::SOL_SOCKET, SO_PREFER_BUSY_POLL, &(1 as c_int))?;
+            setsockopt(fd.0, libc::SOL_SOCKET, SO_BUSY_POLL, &BUSY_POLL_USECS)?;
+            setsockopt(
+                fd.0,
+                libc::SOL_SOCKET,
+                SO_BUSY_POLL_BUDGET,
+                &BUSY_POLL_BUDGET,
+            )?;
+        }
+        let mut options = 0u32;
+        getsockopt(fd.0, SOL_XDP, XDP_OPTIONS, &mut options)?;
+
+        Ok(XskDesc {
+            program: None,
+            rx: rx,
+            tx: tx,
+            fill: fill,
+            completion: completion,
+            fd: fd,
+            umem: umem,
+            free_tx_frames: (RING_SIZE as u64..FRAME_COUNT as u64)
+                .map(|frame| frame * FRAME_SIZE as u64)
+                .collect(),
+            busy_poll: busy_poll,
+            zero_copy: options & XDP_OPTIONS_ZEROCOPY != 0,
+            interface: interface.to_string(),
+        })
+    }
+
+    pub fn interface_mtu(&self) -> io::Result<usize> {
+        let mtu = fs::read_to_string(format!("/sys/class/net/{}/mtu", self.interface))?;
+        mtu.trim()
+            .parse()
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid interface MTU"))
+    }
+
+    pub fn zero_copy(&self) -> bool {
+        self.zero_copy
+    }
+
+    /// Lets the driver process the rings in busy-poll mode or when it waits for a wakeup
+    fn kick(&self) {
+        unsafe {
+            libc::sendto(
+                self.fd.0,
+                ptr::null(),
+                0,
+                libc::MSG_DONTWAIT,
+                ptr::null(),
+                0,
+            );
+        }
+    }
+
+    /// Returns the next received frame, it must be given back with recycle.
+    /// The driver is only woken up once the RX ring is drained, i.e., once per batch.
+    pub fn recv(&mut self) -> Option<(xdp_desc, *mut u8)> {
+        if let Some(desc) = self.rx.consume() {
+            let data = unsafe { self.umem.addr.add(desc.addr as usize) };
+            return Some((desc, data));
+        }
+        if self.fill.pending() {
+            self.fill.submit();
+        }
+        if self.busy_poll || self.fill.needs_wakeup() {
+            unsafe {
+                libc::recvfrom(
+                    self.fd.0,
+                    ptr::null_mut(),
+                    0,
+                    libc::MSG_DONTWAIT,
+                    ptr::null_mut(),
+                    ptr::null_mut(),
+                );
+            }
+        }
+        let desc = self.rx.consume()?;
+        let data = unsafe { self.umem.addr.add(desc.addr as usize) };
+        Some((desc, data))
+    }
+
+    /// Hands the frame of a received descriptor back to the kernel through the fill ring,
+    /// it is submitted with the next flush or when the RX ring is drained
+    pub fn recycle(&mut self, desc: xdp_desc) {
+        // the fill ring has room for all receive frames
+        self.fill.produce(desc.addr - desc.addr % FRAME_SIZE as u64);
+    }
+
+    /// Submits the recycled frames and the frames queued by send since the last flush,
+    /// and wakes up the driver once for all of them
+    pub fn flush(&mut self) {
+        if self.fill.pending() {
+            self.fill.submit();
+        }
+        if self.tx.pending() {
+            self.tx.submit();
+            if self.busy_poll || self.tx.needs_wakeup() {
+                self.kick();
+            }
+        }
+    }
+
+    fn reclaim(&mut self) {
+        while let Some(addr) = self.completion.consume() {
+            self.free_tx_frames.push(addr);
+        }
+    }
+
+    pub fn send_ready(&mut self) -> bool {
+        self.reclaim();
+        if self.free_tx_frames.is_empty() {
+            self.flush();
+            self.kick(); // lets the kernel complete the pending frames
+            return false;
+        }
+        true
+    }
+
+    pub fn send<R, F>(&mut self, len: usize, f: F) -> Result<R>
+    where
+        F: FnOnce(&mut [u8]) -> Result<R>,
+    {
+        if len > MAX_FRAME_LEN {
+            return Err(Error::Truncated);
+        }
+        self.reclaim();
+        let addr = match self.free_tx_frames.pop() {
+            Some(addr) if self.tx.free() > 0 => addr,
+            Some(addr) => {
+                self.free_tx_frames.push(addr);
+                self.flush();
+                return Err(Error::Exhausted);
+            }
+            None => {
+                self.flush(); // the queued frames are completed and reclaimed later
+                return Err(Error::Exhausted);
+            }
+        };
+        let buffer = unsafe { slice::from_raw_parts_mut(self.umem.addr.add(addr as usize), len) };
+        match f(buffer) {
+            Ok(r) => {
+                self.tx.produce(xdp_desc {
+                    addr: addr,
+                    len: len as u32,
+                    options: 0,
+                });
+                Ok(r)
+            }
+            Err(err) => {
+                self.free_tx_frames.push(addr);
+                Err(err)
+            }
+        }
+    }
+}
+
+impl Drop for XskDesc {
+    fn drop(&mut self) {
+        // the kernel removes the closed socket from the XSKMAP
+        if let Some(Program {
+            ifindex,
+            attached: true,
+            ..
+        }) = self.program
+        {
+            let _ = set_xdp_fd(ifindex, -1, 0);
+        }
+    }
+}
+
+/// Registers the socket in the XSKMAP of the XDP program for the queue, the program is
+/// loaded from the pinned path and attached, or the one already attached to the interface
+fn steer(ifindex: u32, queue_id: u32, xsk_fd: c_int, path: Option<&str>) -> io::Result<Program> {
+    let (prog, attached) = match path {
+        Some(path) => {
+            let prog = bpf_obj_get(path)?;
+            set_xdp_fd(ifindex, prog.0, XDP_FLAGS_UPDATE_IF_NOEXIST)?;
+            (prog, true)
+        }
+        None => {
+            let id = attached_prog_id(ifindex)?.ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::NotFound,
+                    "no XDP program attached to the interface, give the path of a pinned program",
+                )
+            })?;
+            (bpf_get_fd_by_id(BPF_PROG_GET_FD_BY_ID, id)?, false)
+        }
+    };
+    let program = Program {
+        ifindex: ifindex,
+        attached: attached,
+        xsks_map: find_xsks_map(&prog)?,
+    };
+    bpf_map_update(&program.xsks_map, queue_id, xsk_fd as u32)?;
+    Ok(program)
+}
+
+fn bpf<T>(cmd: libc::c_long, attr: &mut T) -> io::Result<c_int> {
+    let res = unsafe {
+        libc::syscall(
+            libc::SYS_bpf,
+            cmd,
+            attr as *mut T,
+            mem::size_of::<T>() as libc::c_uint,
+        )
+    };
+    check(res as c_int)
+}
+
+fn bpf_obj_get(path: &str) -> io::Result<Fd> {
+    #[repr(C)]
+    struct ObjGet {
+        pathname: u64,
+        bpf_fd: u32,
+        file_flags: u32,
+    }
+    let path = CString::new(path)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "invalid program path"))?;
+    let mut attr = ObjGet {
+        pathname: path.as_ptr() as u64,
+        bpf_fd: 0,
+        file_flags: 0,
+    };
+    bpf(BPF_OBJ_GET, &mut attr).map(Fd)
+}
+
+fn bpf_get_fd_by_id(cmd: libc::c_long, id: u32) -> io::Result<Fd> {
+    #[repr(C)]
+    struct GetFdById {
+        id: u32,
+        next_id: u32,
+        open_flags: u32,
+    }
+    let mut attr = GetFdById {
+        id: id,
+        next_id: 0,
+        open_flags: 0,
+    };
+    bpf(cmd, &mut attr).map(Fd)
+}
+
+fn bpf_info<T>(fd: &Fd, info: &mut T) -> io::Result<()> {
+    #[repr(C)]
+    struct InfoByFd {
+        bpf_fd: u32,
+        info_len: u32,
+        info: u64,
+    }
+    let mut attr = InfoByFd {
+        bpf_fd: fd.0 as u32,
+        info_len: mem::size_of::<T>() as u32,
+        info: info as *mut T as u64,
+    };
+    bpf(BPF_OBJ_GET_INFO_BY_FD, &mut attr).map(|_| ())
+}
+
+fn find_xsks_map(prog: &Fd) -> io::Result<Fd> {
+    #[repr(C)]
+    #[derive(Default)]
+    struct ProgInfo {
+        prog_type: u32,
+        id: u32,
+        tag: [u8; 8],
+        jited_prog_len: u32,
+        xlated_prog_len: u32,
+        jited_prog_insns: u64,
+        xlated_prog_insns: u64,
+        load_time: u64,
+        created_by_uid: u32,
+        nr_map_ids: u32,
+        map_ids: u64,
+    }
+    #[repr(C)]
+    #[derive(Default)]
+    struct MapInfo {
+        map_type: u32,
+        id: u32,
+        key_size: u32,
+        value_size: u32,
+        max_entries: u32,
+        map_flags: u32,
+    }
+    let mut map_ids = [0u32; MAX_PROG_MAPS];
+    let mut info = ProgInfo::default();
+    info.nr_map_ids = MAX_PROG_MAPS as u32;
+    info.map_ids = map_ids.as_mut_ptr() as u64;
+    bpf_info(prog, &mut info)?;
+    let count = (info.nr_map_ids as usize).min(MAX_PROG_MAPS);
+    for &id in &map_ids[..count] {
+        let map = bpf_get_fd_by_id(BPF_MAP_GET_FD_BY_ID, id)?;
+        let mut map_info = MapInfo::default();
+        bpf_info(&map, &mut map_info)?;
+        if map_info.map_type == BPF_MAP_TYPE_XSKMAP {
+            return Ok(map);
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::InvalidInput,
+        "XDP program has no XSKMAP for redirecting to AF_XDP sockets",
+    ))
+}
+
+fn bpf_map_update(map: &Fd, key: u32, value: u32) -> io::Result<()> {
+    #[repr(C)]
+    struct MapUpdate {
+        map_fd: u32,
+        pad: u32,
+        key: u64,
+        value: u64,
+        flags: u64,
+    }
+    let mut attr = MapUpdate {
+        map_fd: map.0 as u32,
+        pad: 0,
+        key: &key as *const u32 as u64,
+        value: &value as *const u32 as u64,
+        flags: 0,
+    };
+    bpf(BPF_MAP_UPDATE_ELEM, &mut attr).map(|_| ())
+}
+
+fn push_attr(msg: &mut Vec<u8>, attr_type: u16, payload: &[u8]) {
+    msg.extend_from_slice(&((4 + payload.len()) as u16).to_ne_bytes());
+    msg.extend_from_slice(&attr_type.to_ne_bytes());
+    msg.extend_from_slice(payload);
+    while msg.len() % 4 != 0 {
+        msg.push(0);
+    }
+}
+
+/// Sends a route netlink request for the interface and returns the attributes of the
+/// RTM_NEWLINK response, empty for an acknowledged request
+fn link_request(msg_type: u16, ifindex: u32, attrs: &[u8]) -> io::Result<Vec<u8>> {
+    let mut msg = Vec::new();
+    let flags = if msg_type == RTM_GETLINK {
+        NLM_F_REQUEST
+    } else {
+        NLM_F_REQUEST | NLM_F_ACK
+    };
+    msg.extend_from_slice(&((16 + 16 + attrs.len()) as u32).to_ne_bytes());
+    msg.extend_from_slice(&msg_type.to_ne_bytes());
+    msg.extend_from_slice(&flags.to_ne_bytes());
+    msg.extend_from_slice(&1u32.to_ne_bytes()); // sequence number
+    msg.extend_from_slice(&0u32.to_ne_bytes()); // port ID, set by the kernel
+    msg.extend_from_slice(&[libc::AF_UNSPEC as u8, 0, 0, 0]); // family, padding, type
+    msg.extend_from_slice(&(ifindex as i32).to_ne_bytes());
+    msg.extend_from_slice(&[0; 8]); // flags and change mask
+    msg.extend_from_slice(attrs);
+
+    let fd = Fd(check(unsafe {
+        libc::socket(
+            libc::AF_NETLINK,
+            libc::SOCK_RAW | libc::SOCK_CLOEXEC,
+            NETLINK_ROUTE,
+        )
+    })?);
+    let mut addr: libc::sockaddr_nl = unsafe { mem::zeroed() };
+    addr.nl_family = libc::AF_NETLINK as u16;
+    let sent = unsafe {
+        libc::sendto(
+            fd.0,
+            msg.as_ptr() as *const libc::c_void,
+            msg.len(),
+            0,
+            &addr as *const libc::sockaddr_nl as *const libc::sockaddr,
+            mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t,
+        )
+    };
+    check(sent as c_int)?;
+    let mut buffer = vec![0u8; 32768];
+    let len = unsafe {
+        libc::recv(
+            fd.0,
+            buffer.as_mut_ptr() as *mut libc::c_void,
+            buffer.len(),
+            0,
+        )
+    };
+    let len = check(len as c_int)? as usize;
+    let response = &buffer[..len];
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "invalid netlink response");
+    if response.len() < 16 {
+        return Err(invalid());
+    }
+    let response_len = u32_at(response, 0) as usize;
+    if response_len > response.len() || response_len < 16 {
+        return Err(invalid());
+    }
+    match u16::from_ne_bytes([response[4], response[5]]) {
+        NLMSG_ERROR if response_len >= 20 => {
+            let error = u32_at(response, 16) as i32;
+            if error != 0 {
+                return Err(io::Error::from_raw_os_error(-error));
+            }
+            Ok(vec![])
+        }
+        RTM_NEWLINK if response_len >= 32 => Ok(response[32..response_len].to_vec()),
+        _ => Err(invalid()),
+    }
+}
+
+fn u32_at(data: &[u8], offset: usize) -> u32 {
+    u32::from_ne_bytes([
+        data[offset],
+        data[offset + 1],
+        data[offset + 2],
+        data[offset + 3],
+    ])
+}
+
+/// Iterates over the (type, payload) pairs of netlink attributes
+fn attrs(mut data: &[u8]) -> impl Iterator<Item = (u16, &[u8])> {
+    std::iter::from_fn(move || {
+        if data.len() < 4 {
+            return None;
+        }
+        let len = u16::from_ne_bytes([data[0], data[1]]) as usize;
+        if len < 4 || len > data.len() {
+            return None;
+        }
+        let attr = (
+            u16::from_ne_bytes([data[2], data[3]]) & !NLA_F_NESTED,
+            &data[4..len],
+        );
+        data = &data[((len + 3) & !3).min(data.len())..];
+        Some(attr)
+    })
+}
+
+/// Attaches the XDP program to the interface, -1 detaches the current program
+fn set_xdp_fd(ifindex: u32, prog_fd: c_int, flags: u32) -> io::Result<()> {
+    let mut nested = Vec::new();
+    push_attr(&mut nested, IFLA_XDP_FD, &prog_fd.to_ne_bytes());
+    push_attr(&mut nested, IFLA_XDP_FLAGS, &flags.to_ne_bytes());
+    let mut attrs = Vec::new();
+    push_attr(&mut attrs, IFLA_XDP | NLA_F_NESTED, &nested);
+    link_request(RTM_SETLINK, ifindex, &attrs).map(|_| ())
+}
+
+fn attached_prog_id(ifindex: u32) -> io::Result<Option<u32>> {
+    let response = link_request(RTM_GETLINK, ifindex, &[])?;
+    let id = attrs(&response)
+        .filter(|&(attr_type, _)| attr_type == IFLA_XDP)
+        .flat_map(|(_, nested)| attrs(nested))
+        .find(|&(attr_type, payload)| attr_type == IFLA_XDP_PROG_ID && payload.len() >= 4)
+        .map(|(_, payload)| u32_at(payload, 0))
+        .filter(|&id| id != 0);
+    Ok(id)
+}
@@ -0,0 +1,99 @@
+/// Measures the round-trip latency of one active TCP connection while many idle connections
+/// are open on the same userspace network stack
+/// Both ends of the connections are sockets of the stack, so it has to run with
+/// USNET_LOOPBACK=true and, for many connections, a small SOCKET_BUFFER, e.g., 4096.
+/// Usage: idleconns [CONNECTIONS [SECONDS]], the defaults are 50000 idle connections and 10 s.
+extern crate usnet_sockets;
+
+use std::env;
+use std::io::{self, Read, Write};
+use std::process;
+use std::str::FromStr;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use usnet_sockets::{TcpListener, TcpStream};
+
+const DEFAULT_CONNECTIONS: usize = 50000;
+const DEFAULT_SECONDS: u64 = 10;
+const ADDRESS: &str = "127.0.0.1:7777";
+
+fn usage() -> ! {
+    eprintln!("usage: idleconns [CONNECTIONS [SECONDS]]");
+    process::exit(2);
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let arg = |i: usize| args.get(i).map(|a| a.as_str());
+    let connections = arg(0).map_or(DEFAULT_CONNECTIONS, |s| {
+        usize::from_str(s).unwrap_or_else(|_| usage())
+    });
+    let duration = Duration::from_secs(arg(1).map_or(DEFAULT_SECONDS, |s| {
+        u64::from_str(s).unwrap_or_else(|_| usage())
+    }));
+    let listener = TcpListener::bind(ADDRESS).expect("cannot bind listener");
+    let acceptor = thread::spawn(move || {
+        let mut accepted = Vec::with_capacity(connections + 1);
+        for _ in 0..connections + 1 {
+            let (stream, _) = listener.accept().expect("accept failed");
+            accepted.push(stream);
+        }
+        accepted
+    });
+    let start = Instant::now();
+    let mut idle = Vec::with_capacity(connections);
+    for _ in 0..connections {
+        idle.push(TcpStream::connect(ADDRESS).expect("cannot connect"));
+    }
+    let mut active = TcpStream::connect(ADDRESS).expect("cannot connect");
+    let mut accepted = acceptor.join().expect("acceptor failed");
+    println!(
+        "{} idle connections opened in {:.1} s",
+        connections,
+        start.elapsed().as_secs_f64()
+    );
+    // the connections are accepted in order, the last one is the peer of the active one
+    let mut peer = accepted.pop().unwrap();
+    let echo = thread::spawn(move || echo(&mut peer));
+    active.set_nodelay(true).expect("cannot set TCP_NODELAY");
+    let mut rtts = vec![];
+    let mut buf = [0; 1];
+    let start = Instant::now();
+    while start.elapsed() < duration {
+        let sent = Instant::now();
+        active.write_all(&[0x55]).expect("send failed");
+        active.read_exact(&mut buf).expect("receive failed");
+        rtts.push(sent.elapsed());
+    }
+    drop(active);
+    let _ = echo.join();
+    rtts.sort();
+    let percentile = |p: usize| rtts[(rtts.len() - 1) * p / 100].as_micros();
+    println!(
+        "{} round trips: p50 {} us, p99 {} us, max {} us",
+        rtts.len(),
+        percentile(50),
+        percentile(99),
+        percentile(100)
+    );
+    let start = Instant::now();
+    drop(idle);
+    drop(accepted);
+    println!(
+        "{} idle connections closed in {:.1} s",
+        connections,
+        start.elapsed().as_secs_f64()
+    );
+}
+
+fn echo(stream: &mut TcpStream) -> io::Result<()> {
+    stream.set_nodelay(true)?;
+    let mut buf = [0; 1];
+    loop {
+        if stream.read(&mut buf)? == 0 {
+            return Ok(());
+        }
+        stream.write_all(&buf)?;
+    }
+}
@@ -0,0 +1,59 @@
+/// Sends ICMP echo requests through the userspace network stack
+/// The stack is configured through the USNET_* environment variables as usual.
+/// Usage: ping [ADDRESS [COUNT]], the default address is the configured gateway.
+extern crate usnet_sockets;
+
+use std::env;
+use std::net::IpAddr;
+use std::process;
+use std::str::FromStr;
+use std::thread;
+use std::time::Duration;
+
+use usnet_sockets::{runtime_config, IcmpSocket};
+
+const PAYLOAD_SIZE: usize = 56;
+
+fn main() {
+    let mut args = env::args().skip(1);
+    let addr = match args.next() {
+        Some(addr) => IpAddr::from_str(&addr).expect("invalid address"),
+        None => match runtime_config().gateway {
+            Some(gateway) => IpAddr::V4(gateway),
+            None => {
+                eprintln!("no gateway configured, give an address");
+                process::exit(2);
+            }
+        },
+    };
+    let count = args
+        .next()
+        .map(|c| u16::from_str(&c).expect("invalid count"))
+        .unwrap_or(4);
+
+    let socket = IcmpSocket::new().expect("cannot create ICMP socket");
+    let payload: Vec<u8> = (0..PAYLOAD_SIZE).map(|i| i as u8).collect();
+    println!("PING {} with {} bytes of data", addr, PAYLOAD_SIZE);
+    let mut received = 0;
+    for seq_no in 1..=count {
+        match socket.ping(addr, seq_no, &payload, Duration::from_secs(1)) {
+            Ok(rtt) => {
+                received += 1;
+                println!(
+                    "reply from {}: icmp_seq={} time={:.3} ms",
+                    addr,
+                    seq_no,
+                    rtt.as_secs_f64() * 1000.0
+                );
+            }
+            Err(err) => println!("icmp_seq={}: {}", seq_no, err),
+        }
+        if seq_no < count {
+            thread::sleep(Duration::from_secs(1));
+        }
+    }
+    println!("{} packets transmitted, {} received", count, received);
+    if received == 0 {
+        process::exit(1);
+    }
+}
@@ -0,0 +1,81 @@
+/// Measures the total throughput of bulk transfers on parallel TCP connections, each written
+/// by its own thread, to see how it scales with the number of connections
+/// Both ends of the connections are sockets of the stack, so it has to run with
+/// USNET_LOOPBACK=true.
+/// Usage: tcpscale [STREAMS [SECONDS]], the defaults are 8 streams and 10 s.
+extern crate usnet_sockets;
+
+use std::env;
+use std::io::{Read, Write};
+use std::process;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use usnet_sockets::{TcpListener, TcpStream};
+
+const DEFAULT_STREAMS: usize = 8;
+const DEFAULT_SECONDS: u64 = 10;
+const ADDRESS: &str = "127.0.0.1:7778";
+const CHUNK: usize = 16384;
+
+fn usage() -> ! {
+    eprintln!("usage: tcpscale [STREAMS [SECONDS]]");
+    process::exit(2);
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let arg = |i: usize| args.get(i).map(|a| a.as_str());
+    let streams = arg(0).map_or(DEFAULT_STREAMS, |s| {
+        usize::from_str(s).unwrap_or_else(|_| usage())
+    });
+    let duration = Duration::from_secs(arg(1).map_or(DEFAULT_SECONDS, |s| {
+        u64::from_str(s).unwrap_or_else(|_| usage())
+    }));
+    let listener = TcpListener::bind(ADDRESS).expect("cannot bind listener");
+    let stop = Arc::new(AtomicBool::new(false));
+    let mut writers = vec![];
+    let mut readers = vec![];
+    for _ in 0..streams {
+        let mut stream = TcpStream::connect(ADDRESS).expect("cannot connect");
+        let (mut peer, _) = listener.accept().expect("accept failed");
+        let stop = stop.clone();
+        writers.push(thread::spawn(move || {
+            let chunk = vec![0x55; CHUNK];
+            while !stop.load(Ordering::Relaxed) {
+                if stream.write_all(&chunk).is_err() {
+                    break;
+                }
+            }
+        }));
+        readers.push(thread::spawn(move || {
+            let mut buf = vec![0; CHUNK];
+            let mut received = 0u64;
+            loop {
+                match peer.read(&mut buf) {
+                    Ok(0) | Err(_) => return received,
+                    Ok(n) => received += n as u64,
+                }
+            }
+        }));
+    }
+    let start = Instant::now();
+    thread::sleep(duration);
+    stop.store(true, Ordering::Relaxed);
+    for writer in writers {
+        let _ = writer.join();
+    }
+    let received: u64 = readers.into_iter().map(|r| r.join().unwrap_or(0)).sum();
+    let elapsed = start.elapsed().as_secs_f64();
+    println!(
+        "{} streams: {:.0} MB in {:.1} s, {:.1} MB/s in total, {:.1} MB/s per stream",
+        streams,
+        received as f64 / 1e6,
+        elapsed,
+        received as f64 / 1e6 / elapsed,
+        received as f64 / 1e6 / elapsed / streams as f64
+    );
+}
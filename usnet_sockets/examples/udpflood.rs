@@ -0,0 +1,127 @@
+/// Floods small UDP datagrams through the userspace network stack to measure the packet rate
+/// The stack is configured through the USNET_* environment variables as usual.
+/// Usage: udpflood send ADDRESS:PORT [SIZE [SECONDS [BATCH]]] or
+/// udpflood recv PORT [SECONDS [BATCH]], the default size is 18 bytes (a minimal Ethernet
+/// frame), the default duration 10 s, and the default batch 1. With a batch above 1, the
+/// datagrams are sent and received through send_batch and recv_batch.
+extern crate usnet_sockets;
+
+use std::env;
+use std::io;
+use std::net::SocketAddr;
+use std::process;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use usnet_sockets::{stats, UdpSlot, UdpSocket};
+
+const DEFAULT_SIZE: usize = 18;
+const DEFAULT_SECONDS: u64 = 10;
+const DEFAULT_BATCH: usize = 1;
+
+fn usage() -> ! {
+    eprintln!("usage: udpflood send ADDRESS:PORT [SIZE [SECONDS [BATCH]]]");
+    eprintln!("       udpflood recv PORT [SECONDS [BATCH]]");
+    process::exit(2);
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let arg = |i: usize| args.get(i).map(|a| a.as_str());
+    let seconds = |i: usize| {
+        Duration::from_secs(arg(i).map_or(DEFAULT_SECONDS, |s| {
+            u64::from_str(s).expect("invalid duration")
+        }))
+    };
+    let batch = |i: usize| {
+        arg(i).map_or(DEFAULT_BATCH, |s| {
+            usize::from_str(s)
+                .ok()
+                .filter(|&b| b > 0)
+                .expect("invalid batch")
+        })
+    };
+    let before = stats();
+    let start = Instant::now();
+    let datagrams = match (arg(0), arg(1)) {
+        (Some("send"), Some(addr)) => {
+            let addr = SocketAddr::from_str(addr).expect("invalid address");
+            let size = arg(2).map_or(DEFAULT_SIZE, |s| usize::from_str(s).expect("invalid size"));
+            send(addr, size, seconds(3), batch(4))
+        }
+        (Some("recv"), Some(port)) => recv(
+            u16::from_str(port).expect("invalid port"),
+            seconds(2),
+            batch(3),
+        ),
+        _ => usage(),
+    };
+    let elapsed = start.elapsed().as_secs_f64();
+    let delta = stats().delta(&before);
+    println!(
+        "{} datagrams in {:.1} s: {:.0} datagrams/s",
+        datagrams,
+        elapsed,
+        datagrams as f64 / elapsed
+    );
+    println!(
+        "interface: {} frames sent ({:.0}/s), {} frames received ({:.0}/s)",
+        delta.tx_packets,
+        delta.tx_packets as f64 / elapsed,
+        delta.rx_packets,
+        delta.rx_packets as f64 / elapsed
+    );
+}
+
+fn send(addr: SocketAddr, size: usize, duration: Duration, batch: usize) -> u64 {
+    let socket = UdpSocket::bind("0.0.0.0:0").expect("cannot bind UDP socket");
+    let payload = vec![0x55; size];
+    let datagrams = vec![(&payload[..], addr); batch];
+    let start = Instant::now();
+    let mut sent = 0;
+    while start.elapsed() < duration {
+        let r = if batch == 1 {
+            socket.send_to(&payload, addr).map(|_| 1)
+        } else {
+            socket.send_batch(&datagrams)
+        };
+        match r {
+            Ok(n) => sent += n as u64,
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {}
+            Err(err) => {
+                eprintln!("send failed: {}", err);
+                break;
+            }
+        }
+    }
+    sent
+}
+
+fn recv(port: u16, duration: Duration, batch: usize) -> u64 {
+    let socket = UdpSocket::bind(("0.0.0.0", port)).expect("cannot bind UDP socket");
+    socket
+        .set_read_timeout(Some(Duration::from_millis(100)))
+        .expect("cannot set timeout");
+    let mut buf = vec![0; 2048];
+    let mut slots = vec![UdpSlot::new(2048); batch];
+    let start = Instant::now();
+    let mut received = 0;
+    while start.elapsed() < duration {
+        let r = if batch == 1 {
+            socket.recv_from(&mut buf).map(|_| 1)
+        } else {
+            socket.recv_batch(&mut slots)
+        };
+        match r {
+            Ok(n) => received += n as u64,
+            Err(ref err)
+                if err.kind() == io::ErrorKind::WouldBlock
+                    || err.kind() == io::ErrorKind::TimedOut => {}
+            Err(err) => {
+                eprintln!("receive failed: {}", err);
+                break;
+            }
+        }
+    }
+    received
+}
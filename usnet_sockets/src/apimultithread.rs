@@ -5,17 +5,24 @@
 /// multithreading application because they are movable and have locking.
 /// Since there is a background thread which handles the socket timeouts and incoming
 /// packets, the application may do longer computations without calling socket operations.
-use parking_lot::{Condvar, Mutex, RwLock};
-use smoltcp::time::Instant;
+use parking_lot::{Condvar, Mutex, MutexGuard, RwLock};
+use smoltcp::time::{Duration as SmoltcpDuration, Instant};
+use std::cell::RefCell;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BTreeSet, BinaryHeap, VecDeque};
+use std::fs::File;
 use std::io::{self, Write};
 use std::iter;
+use std::mem;
 use std::net::{SocketAddrV4, SocketAddrV6};
 use std::option;
-use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::path::Path;
 use std::slice;
 use std::str::FromStr;
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Weak};
 use std::thread;
 use std::thread::JoinHandle;
 use std::time::{Duration, Instant as StdInstant};
@@ -25,10 +32,12 @@ use crate::resolve;
 
 use smoltcp;
 use smoltcp::socket::{
-    SocketHandle, SocketSet, TcpSocket, TcpSocketBuffer, UdpPacketMetadata,
+    IcmpEndpoint, IcmpPacketMetadata, IcmpSocket as SmoltcpIcmpSocket, IcmpSocketBuffer,
+    RawPacketMetadata, RawSocket as SmoltcpRawSocket, RawSocketBuffer, Socket, SocketHandle,
+    SocketSet, TcpSocket, TcpSocketBuffer, TcpState, UdpPacketMetadata,
     UdpSocket as SmoltcpUdpSocket, UdpSocketBuffer,
 };
-use smoltcp::wire::{IpAddress, IpEndpoint, IpProtocol};
+use smoltcp::wire::{IpAddress, IpCidr, IpEndpoint, IpProtocol, IpVersion, Ipv4Address, Ipv4Cidr};
 
 use std::net::{
     IpAddr, Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpListener as SystemTcpListener,
@@ -36,19 +45,38 @@ use std::net::{
 };
 use std::os::unix::net::UnixDatagram;
 
+#[cfg(feature = "stack-log")]
+use log::Level;
 use nix::poll::{poll, PollFd, PollFlags};
 use nix::sched::{sched_setaffinity, CpuSet};
 use nix::unistd::Pid;
 use std::os::raw::c_int;
 
+use crate::capture::enable_pcap;
+use crate::congestion::{self, default_congestion, Congestion, Connections, Window};
 use crate::device::*;
+use crate::dhcp::{notify as notify_dhcp, DhcpClient, DhcpStatus};
+use crate::fallback::{self, PathPolicy, SocketPath};
+use crate::icmp::{self, EchoReply, IcmpResponse};
+use crate::neighbor::{self, NeighborEntry};
+use crate::pktinfo::{self, PktInfo};
+use crate::pmtu::PathMtu;
+use crate::queues::QueueGroup;
+use crate::rawip;
+use crate::routing::{self, Route};
+use crate::slaac::Slaac;
+use crate::stats::{add, spawn_stats_logger, InterfaceStats, TcpStats, COUNTERS};
 use rand::{thread_rng, Rng};
 use std::env;
 use std::io::prelude::*;
-use crate::system::read_kernel_local_port_range;
+use crate::system::{interface_index, read_kernel_local_port_range};
 use crate::usnetconfig::*;
 
-use serde_json;
+use lazy_static;
+use libc;
+
+extern crate usnet_devices;
+use self::usnet_devices::Rss;
 
 #[derive(Clone, Debug)]
 pub struct StcpNetRef {
@@ -61,20 +89,236 @@ pub struct StcpListenerRef {
 }
 
 lazy_static! {
-    static ref STCP_GLOBAL: StcpNetRef = StcpNetRef {
-        r: Arc::new((Mutex::new(StcpNet::new_from_env()), Condvar::new()))
-    };
+    static ref STCP_GLOBAL: StcpNetRef = StcpNetRef::spawn(StcpNet::new_from_init_config());
+    static ref INIT_CONFIG: Mutex<Option<StackConfig>> = Mutex::new(None);
+    static ref LOOPBACK_INTERFACE: u32 = interface_index("lo");
+}
+
+static STCP_GLOBAL_CREATED: AtomicBool = AtomicBool::new(false);
+
+/// Creates the global stack from the given configuration instead of the environment variables
+/// Must be called before the first socket is used, otherwise the stack was already created.
+pub fn init_with(config: StackConfig) -> io::Result<()> {
+    {
+        let mut init_config = INIT_CONFIG.lock();
+        if STCP_GLOBAL_CREATED.load(Ordering::SeqCst) || init_config.is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                "stack already initialized",
+            ));
+        }
+        *init_config = Some(config);
+    }
+    lazy_static::initialize(&STCP_GLOBAL);
+    Ok(())
+}
+
+/// Shuts the global stack and the stacks on the other queues down, e.g., before a re-exec or
+/// between tests in one process
+/// Afterwards no sockets can be created and the calls of the existing ones fail with
+/// NotConnected, also for threads which are blocked in them. The TCP connections are closed
+/// once their send rings are sent and the ones which did not end within the timeout are
+/// reset. Then the background threads are joined and the backends are detached, the global
+/// stack can't be initialized again in the process.
+pub fn shutdown(timeout: Duration) -> io::Result<()> {
+    if !STCP_GLOBAL_CREATED.load(Ordering::SeqCst) {
+        return Ok(()); // not created, nothing to stop
+    }
+    let deadline = StdInstant::now() + timeout;
+    for stack in queue_stacks() {
+        stack.shutdown(deadline.saturating_duration_since(StdInstant::now()))?;
+    }
+    Ok(())
+}
+
+/// Returns the stacks on the NIC queues, see StackConfig::queues, the first one is the global
+/// stack used by the socket types and the only one which has UDP, ICMP, and raw sockets
+/// A listener created with tcp_bind on one of the other stacks only accepts the connections
+/// which the NIC steers to its queue, the other stacks reset them.
+pub fn queue_stacks() -> Vec<StcpNetRef> {
+    iter::once(STCP_GLOBAL.clone())
+        .chain(STCP_GLOBAL.queue_stacks())
+        .collect()
+}
+
+/// Adds an address to the interface, e.g., a service address next to the primary address
+/// With usnetd, sockets bound to the unspecified address before do not receive packets for it.
+pub fn add_address(address: IpAddr, prefix_len: u8) -> io::Result<()> {
+    let &(ref stcpnetref, ref _cond) = &*STCP_GLOBAL.r;
+    let mut stcpnet = stcpnetref.lock();
+    routing::add_address(&mut stcpnet.iface, address, prefix_len)
+}
+
+/// Removes an added address, TCP connections using it are reset
+pub fn remove_address(address: IpAddr) -> io::Result<()> {
+    let &(ref stcpnetref, ref cond) = &*STCP_GLOBAL.r;
+    let mut stcpnet = stcpnetref.lock();
+    routing::remove_address(&mut stcpnet.iface, address)?;
+    stcpnet.reset_connections_from(IpAddress::from(address));
+    cond.notify_all();
+    Ok(())
+}
+
+/// Returns the addresses of the interface with their prefix length, the primary address first
+pub fn addresses() -> Vec<(IpAddr, u8)> {
+    let &(ref stcpnetref, ref _cond) = &*STCP_GLOBAL.r;
+    let stcpnet = stcpnetref.lock();
+    routing::interface_addresses(stcpnet.iface.ips())
+}
+
+/// Adds a static route, replacing a route to the same destination network
+pub fn add_route(route: Route) -> io::Result<()> {
+    let route = Route::new(route.destination, route.prefix_len, route.via)?;
+    let &(ref stcpnetref, ref _cond) = &*STCP_GLOBAL.r;
+    let mut stcpnet = stcpnetref.lock();
+    routing::add_route(&mut stcpnet.iface, &route);
+    stcpnet
+        .routes
+        .retain(|r| (r.destination, r.prefix_len) != (route.destination, route.prefix_len));
+    stcpnet.routes.push(route);
+    Ok(())
+}
+
+pub fn remove_route(destination: IpAddr, prefix_len: u8) -> io::Result<()> {
+    let &(ref stcpnetref, ref _cond) = &*STCP_GLOBAL.r;
+    let mut stcpnet = stcpnetref.lock();
+    let pos = stcpnet
+        .routes
+        .iter()
+        .position(|r| r.destination == destination && r.prefix_len == prefix_len)
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("no route to {}/{}", destination, prefix_len),
+            )
+        })?;
+    let route = stcpnet.routes.remove(pos);
+    routing::remove_route(&mut stcpnet.iface, &route);
+    Ok(())
+}
+
+/// Returns the static routes, without the default routes
+pub fn routes() -> Vec<Route> {
+    let &(ref stcpnetref, ref _cond) = &*STCP_GLOBAL.r;
+    let stcpnet = stcpnetref.lock();
+    stcpnet.routes.clone()
+}
+
+/// Replaces the policy which decides per destination whether TcpStream::connect,
+/// UdpSocket::send_to, and the DnsSocket use the userspace network stack or kernel sockets
+/// Existing connections and listeners stay on the stack they were created on.
+pub fn set_path_policy(policy: PathPolicy) {
+    let &(ref stcpnetref, ref _cond) = &*STCP_GLOBAL.r;
+    let mut stcpnet = stcpnetref.lock();
+    info!("path policy: {:?}", policy);
+    stcpnet.path_policy = policy;
+}
+
+/// Returns the current path policy, see set_path_policy
+pub fn path_policy() -> PathPolicy {
+    let &(ref stcpnetref, ref _cond) = &*STCP_GLOBAL.r;
+    let stcpnet = stcpnetref.lock();
+    stcpnet.path_policy.clone()
+}
+
+/// Changes the primary address, default gateway, MTU limit, and name servers of the running
+/// global stack, see StcpNetRef::reconfigure
+pub fn reconfigure<F: FnOnce(&mut RuntimeConfig)>(f: F) -> io::Result<()> {
+    STCP_GLOBAL.reconfigure(f)
+}
+
+/// Returns the current primary address, default gateway, MTU limit, and name servers
+pub fn runtime_config() -> RuntimeConfig {
+    STCP_GLOBAL.runtime_config()
+}
+
+/// Returns the entries of the neighbor table of the global stack, see StcpNetRef::neighbors
+/// for the one of another stack
+pub fn neighbors() -> Vec<NeighborEntry> {
+    STCP_GLOBAL.neighbors()
+}
+
+/// Flushes the neighbor tables of the global stack and the stacks on the other queues
+pub fn flush_neighbors() {
+    for stack in queue_stacks() {
+        stack.flush_neighbors();
+    }
+}
+
+/// Installs a static neighbor entry in the global stack and the stacks on the other queues,
+/// packets to the address are sent to the MAC without ARP or NDP
+pub fn add_static_neighbor(ip: IpAddr, mac: [u8; 6]) -> io::Result<()> {
+    for stack in queue_stacks() {
+        stack.add_static_neighbor(ip, mac)?;
+    }
+    Ok(())
+}
+
+/// Removes a static neighbor entry of the global stack and the stacks on the other queues,
+/// the stacks resolve the address again after their cache entry expired
+pub fn remove_static_neighbor(ip: IpAddr) -> io::Result<()> {
+    for stack in queue_stacks() {
+        stack.remove_static_neighbor(ip)?;
+    }
+    Ok(())
+}
+
+/// Returns the path MTUs of the global stack which are lower than the MTU of the interface,
+/// see StcpNetRef::path_mtus for the ones of another stack
+pub fn path_mtus() -> Vec<PathMtu> {
+    STCP_GLOBAL.path_mtus()
 }
 
-pub fn bg() {
+/// Removes the learned path MTUs of the global stack and the stacks on the other queues
+pub fn flush_path_mtus() {
+    for stack in queue_stacks() {
+        stack.flush_path_mtus();
+    }
+}
+
+/// Returns the counters of the interface of the global stack, see StcpNetRef::interface_stats
+/// for the one of another stack, e.g., of the other queues
+pub fn interface_stats() -> InterfaceStats {
+    STCP_GLOBAL.interface_stats()
+}
+
+// local ports tried for a connection on one of multiple queues before giving up
+const MAX_QUEUE_PORT_ATTEMPTS: usize = 1000;
+
+// first check of a dropped TCP socket for the closed state, doubled up to the maximum,
+// which is the TIME-WAIT duration of smoltcp
+const CLOSE_CHECK_MS: u64 = 100;
+const CLOSE_CHECK_MAX_MS: u64 = 10_000;
+
+// interval in which a connection attempt checks whether the next hop was resolved
+const NEIGHBOR_CHECK_MS: u64 = 500;
+
+// upper limit for the receive and send rings of a TCP connection, the smoltcp socket
+// holds the rest of the window
+const STREAM_RING_LIMIT: usize = 65536;
+
+// buffer of copy and write_file for kernel streams
+const COPY_CHUNK: usize = 65536;
+
+// stable identifier of a TCP connection for the log messages, handles are reused
+static NEXT_CONN_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Polls the stack until the last StcpNetRef of it is dropped
+pub fn bg(weak: Weak<(Mutex<StcpNet>, Condvar)>) {
     let mut dummy = vec![0; 1000];
     let mut fds = vec![];
     let mut fds_extra = vec![];
     let mut waiting_poll;
+    let mut spin_budget;
     let mut skip;
+    let mut last_traffic: Option<StdInstant> = None;
 
     {
-        let &(ref stcpnetref, ref _cond) = &*STCP_GLOBAL.r;
+        let r = match weak.upgrade() {
+            Some(r) => r,
+            None => return,
+        };
+        let &(ref stcpnetref, ref _cond) = &*r;
         let stcpnet = stcpnetref.lock();
         match stcpnet.bg_thread_pin_cpu_id {
             Some(cpuid) => {
@@ -87,10 +331,16 @@ pub fn bg() {
     }
 
     loop {
+        // no reference is held while waiting, so that the stack can be dropped
+        let r = match weak.upgrade() {
+            Some(r) => r,
+            None => return,
+        };
         let delay = {
-            let &(ref stcpnetref, ref _cond) = &*STCP_GLOBAL.r;
+            let &(ref stcpnetref, ref _cond) = &*r;
             let mut stcpnet = stcpnetref.lock();
             waiting_poll = stcpnet.waiting_poll;
+            spin_budget = stcpnet.spin_budget;
             if stcpnet.bg_skip_one_wait == Skip::Skip {
                 skip = true;
             } else {
@@ -123,7 +373,23 @@ pub fn bg() {
                 );
             }
 
-            let d = stcpnet.iface.poll_delay(&stcpnet.sockets, Instant::now());
+            let now = Instant::now();
+            let d = stcpnet.iface.poll_delay(&stcpnet.sockets, now);
+            let d = match stcpnet.dhcp {
+                Some(ref dhcp) => {
+                    let dhcp_delay = dhcp.next_poll(now);
+                    Some(d.map_or(dhcp_delay, |d| d.min(dhcp_delay)))
+                }
+                None => d,
+            };
+            let d = match stcpnet.slaac.as_ref().and_then(|s| s.next_poll(now)) {
+                Some(slaac_delay) => Some(d.map_or(slaac_delay, |d| d.min(slaac_delay))),
+                None => d,
+            };
+            let d = match stcpnet.next_close_check(now) {
+                Some(close_delay) => Some(d.map_or(close_delay, |d| d.min(close_delay))),
+                None => d,
+            };
             let delay = match &d {
                 Some(duration) => duration.total_millis() as c_int,
                 None => -1 as c_int,
@@ -131,18 +397,49 @@ pub fn bg() {
             stcpnet.current_wait_delay = delay;
             delay
         };
-        if waiting_poll && !skip {
+        drop(r);
+        let spin_start = StdInstant::now();
+        let wait = waiting_poll && !skip;
+        let spinning = wait && last_traffic.map_or(false, |last| spin_start - last < spin_budget);
+        if wait && !spinning {
             poll(&mut fds[..], delay).expect("wait error");
+            add(
+                &COUNTERS.blocked_time_us,
+                spin_start.elapsed().as_micros() as u64,
+            );
         }
-        {
-            let &(ref stcpnetref, ref cond) = &*STCP_GLOBAL.r;
+        let r = match weak.upgrade() {
+            Some(r) => r,
+            None => return,
+        };
+        let dhcp_event = {
+            let &(ref stcpnetref, ref cond) = &*r;
             let mut stcpnet = stcpnetref.lock();
+            stcpnet.notifier.pending.store(false, Ordering::SeqCst);
             while let Ok(_) = stcpnet.notify_poll_listener.recv(&mut dummy) {
                 // consume all notifications
             }
-            let _readyness_changed = stcpnet.poll();
+            if stcpnet.poll() {
+                last_traffic = Some(StdInstant::now());
+            }
             stcpnet.bg_skip_one_wait = Skip::Skippable;
             cond.notify_all();
+            if let Some((ref group, _)) = stcpnet.queue_group {
+                group.notify_polled(); // for listeners with a backlog on every stack
+            }
+            if stcpnet.stop_bg {
+                return; // after the poll which sent the resets of the shutdown
+            }
+            stcpnet.dhcp_event.take()
+        };
+        if spinning {
+            add(
+                &COUNTERS.spin_time_us,
+                spin_start.elapsed().as_micros() as u64,
+            );
+        }
+        if let Some(status) = dhcp_event {
+            notify_dhcp(&status); // without the lock, the callback may use sockets
         }
     }
 }
@@ -154,6 +451,124 @@ impl TcpListener {
     pub fn bind<A: UsnetToSocketAddrs>(addr: A) -> io::Result<StcpListenerRef> {
         STCP_GLOBAL.bind(addr)
     }
+    /// Binds to the address on the given network interface, see TcpListenerBuilder::device
+    pub fn bind_device<A: UsnetToSocketAddrs>(
+        addr: A,
+        device: &str,
+    ) -> io::Result<StcpListenerRef> {
+        STCP_GLOBAL.bind_device(addr, device, false)
+    }
+}
+
+/// Collects the options of a TcpListener, e.g., to bind it to one network interface
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TcpListenerBuilder {
+    device: Option<String>,
+    only_v6: bool,
+}
+
+impl TcpListenerBuilder {
+    pub fn new() -> TcpListenerBuilder {
+        TcpListenerBuilder::default()
+    }
+    /// Only accepts the connections which arrive on the interface, as SO_BINDTODEVICE does
+    /// The device must be the NIC of the stack (or its parent for macvtap and usnetd), then
+    /// connections through the kernel loopback or the loopback of the stack are closed, or
+    /// "lo", then the listener is bound to 127.0.0.1 for the unspecified address and only
+    /// gets connections through the loopback. Other names fail at bind time, with NotFound
+    /// if there is no such interface and with InvalidInput if the stack does not use it.
+    pub fn device(mut self, device: &str) -> TcpListenerBuilder {
+        self.device = Some(device.to_owned());
+        self
+    }
+    /// Sets IPV6_V6ONLY, see UdpBindOptions::only_v6
+    pub fn only_v6(mut self, only_v6: bool) -> TcpListenerBuilder {
+        self.only_v6 = only_v6;
+        self
+    }
+    /// Binds a listener on the global stack
+    pub fn bind<A: UsnetToSocketAddrs>(&self, addr: A) -> io::Result<StcpListenerRef> {
+        self.bind_on(&STCP_GLOBAL, addr)
+    }
+    /// Binds a listener on the given stack, e.g., one of queue_stacks()
+    pub fn bind_on<A: UsnetToSocketAddrs>(
+        &self,
+        stack: &StcpNetRef,
+        addr: A,
+    ) -> io::Result<StcpListenerRef> {
+        match self.device {
+            Some(ref device) => stack.bind_device(addr, device, self.only_v6),
+            None => stack.bind_to(addr, BoundDevice::Any, self.only_v6),
+        }
+    }
+}
+
+/// Interface to which a listener or UDP socket is bound
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum BoundDevice {
+    Any,
+    Nic,      // without the kernel loopback, the loopback of the stack and the kernel fallback
+    Loopback, // bound to a loopback address
+}
+
+impl BoundDevice {
+    /// Whether packets to the address leave through the interface, the ones to own
+    /// addresses go through a loopback
+    fn reaches(&self, iface: &StcpBackendInterface, ip: IpAddr) -> bool {
+        match *self {
+            BoundDevice::Any => true,
+            BoundDevice::Nic => !ip.is_loopback() && !iface.has_ip(ip),
+            BoundDevice::Loopback => ip.is_loopback(),
+        }
+    }
+}
+
+/// Returns an IPv4-mapped IPv6 address as IPv4 address, the sockets of the stack report the
+/// IPv4 peers of dual-stack sockets in this form
+fn unmapped(addr: SocketAddr) -> SocketAddr {
+    if let SocketAddr::V6(ref a) = addr {
+        let o = a.ip().octets();
+        if o[..12] == [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff] {
+            return SocketAddr::new(
+                IpAddr::V4(Ipv4Addr::new(o[12], o[13], o[14], o[15])),
+                a.port(),
+            );
+        }
+    }
+    addr
+}
+
+/// Returns the address to bind for the one of a listener or UDP socket: [::] is dual-stack
+/// and bound as 0.0.0.0, since the TCP and UDP sockets of the stack only support IPv4,
+/// unless the socket is IPv6-only, then as ::1 on the kernel loopback
+fn bind_address(addr: SocketAddr, only_v6: bool) -> io::Result<SocketAddr> {
+    match addr {
+        SocketAddr::V6(ref a) if a.ip().is_unspecified() => Ok(if only_v6 {
+            SocketAddr::new(IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1)), a.port())
+        } else {
+            SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), a.port())
+        }),
+        SocketAddr::V6(_) if unmapped(addr) != addr => {
+            if only_v6 {
+                Err(io::Error::new(
+                    io::ErrorKind::AddrNotAvailable,
+                    "an IPv6-only socket cannot bind to an IPv4-mapped address",
+                ))
+            } else {
+                Ok(unmapped(addr))
+            }
+        }
+        _ => Ok(addr),
+    }
+}
+
+/// Whether a packet from the address came through a loopback, given the addresses from
+/// which the loopback of the stack delivers packets
+fn from_loopback(ip: IpAddr, loopback_ips: &[IpCidr]) -> bool {
+    ip.is_loopback() || {
+        let ip = IpAddress::from(ip);
+        loopback_ips.iter().any(|cidr| cidr.address() == ip)
+    }
 }
 
 #[derive(PartialEq, Eq, Debug)]
@@ -167,78 +582,534 @@ enum Skip {
 pub struct StcpNet {
     sockets: SocketSet<'static>,
     iface: StcpBackendInterface,
-    bg: JoinHandle<()>,
+    bg: Option<JoinHandle<()>>,
+    shut_down: bool, // no new sockets, the calls of the existing ones fail
+    stop_bg: bool,   // the background thread exits after its next poll
     fd: RawFd,
     waiting_poll: bool,
+    spin_budget: Duration, // busy-polling after traffic before blocking
     notify_poll: UnixDatagram,
     notify_poll_listener: UnixDatagram,
+    notifier: Arc<PollNotifier>, // for the streams, which do not take the lock of the stack
+    streams: BTreeMap<SocketHandle, Arc<StreamBuffers>>,
     fds_add: Vec<RawFd>,
     fds_remove: Vec<RawFd>,
     bg_skip_one_wait: Skip, // temporary skip one poll call
     socket_backlog: usize,  // max number of parallel incoming SYNs
     current_wait_delay: c_int,
     socket_buffer_size: usize,
+    tcp_rx_buffer_size: usize, // the largest receive window, see tcp_receive_buffer_size
+    tcp_initial_window: usize,
     bg_thread_pin_cpu_id: Option<usize>,
     kernel_local_port_range: (u16, u16),
+    conn_states: BTreeMap<SocketHandle, (u64, TcpState)>, // connection ID and last logged state
+    #[cfg(feature = "stack-log")]
+    conn_retransmits: BTreeMap<SocketHandle, ((IpAddr, u16, u16), Arc<AtomicU64>, u64)>, // and last logged count
+    closing: BinaryHeap<Reverse<(Instant, SocketHandle, u64)>>, // next check and its interval in ms
+    name_servers: Vec<SocketAddr>,
+    device_names: Vec<String>, // kernel interfaces used by the backend
+    interface_index: u32,      // of the first one, 0 without
+    dhcp: Option<DhcpClient>,
+    dhcp_event: Option<DhcpStatus>, // reported by the background thread
+    slaac: Option<Slaac>,
+    slaac_name_servers: Vec<SocketAddr>, // RDNSS entries in name_servers
+    routes: Vec<Route>,                  // static routes, also installed in the interface
+    mtu: Option<usize>,                  // configured MTU limit
+    address_removed: BTreeSet<SocketHandle>, // reset because the local address was removed
+    icmp_match: bool,                    // ICMP packets for the stack requested from usnetd
+    raw_sockets: bool,                   // RawSocket allowed by the configuration
+    raw_matches: BTreeSet<u8>,           // IP protocols of raw sockets requested from usnetd
+    loopback: bool,                      // frames to the own addresses stay in the stack
+    path_policy: PathPolicy,             // stack or kernel sockets for remote destinations
+    queue_group: Option<(Arc<QueueGroup>, usize)>, // and the index of the stack's queue
+    queue_stacks: Vec<StcpNetRef>,       // on the first queue, the stacks of the other queues
 }
 
 impl StcpNet {
+    /// State of the frame inspections of the device, e.g., the ICMP errors of the sockets
+    pub(crate) fn inspection(&self) -> &Inspection {
+        self.iface.inspection()
+    }
     pub fn poll(&mut self) -> bool {
-        match self.iface.poll(&mut self.sockets, Instant::now()) {
+        let r = match self.iface.poll(&mut self.sockets, Instant::now()) {
             Ok(r) => r,
             Err(err) => {
                 debug!("poll result: {}", err);
                 true
             }
+        };
+        if let Some(ref mut dhcp) = self.dhcp {
+            if let Some(status) = dhcp.poll(&mut self.iface, &mut self.sockets, Instant::now()) {
+                if let DhcpStatus::Bound(ref lease) = status {
+                    if !lease.dns_servers.is_empty() {
+                        self.name_servers = lease.name_servers();
+                    }
+                }
+                self.dhcp_event = Some(status);
+            }
+        }
+        if let Some(ref mut slaac) = self.slaac {
+            if let Some(status) = slaac.poll(&mut self.iface, &mut self.sockets, Instant::now()) {
+                let previous = &self.slaac_name_servers;
+                self.name_servers.retain(|ns| !previous.contains(ns));
+                self.slaac_name_servers = status
+                    .dns_servers
+                    .iter()
+                    .map(|ip| SocketAddr::new(IpAddr::V6(*ip), 53))
+                    .collect();
+                self.name_servers.extend_from_slice(&self.slaac_name_servers);
+            }
+        }
+        self.transfer_streams();
+        self.remove_closed(Instant::now());
+        #[cfg(feature = "stack-log")]
+        {
+            if log_enabled!(Level::Debug) {
+                self.log_state_changes();
+            }
+        }
+        r
+    }
+    /// Creates the rings through which the application reads and writes the connection
+    fn register_stream(&mut self, handle: SocketHandle) -> Arc<StreamBuffers> {
+        let size = self.socket_buffer_size.min(STREAM_RING_LIMIT);
+        let socket = self.sockets.get::<TcpSocket>(handle);
+        let buffers = Arc::new(StreamBuffers {
+            rings: Mutex::new(StreamRings {
+                rx: TcpSocketBuffer::new(vec![0; size]),
+                tx: TcpSocketBuffer::new(vec![0; size]),
+                may_recv: socket.may_recv(),
+                may_send: socket.may_send(),
+                reset: false,
+                state: socket.state(),
+                peer_reset: false,
+                shut_down: false,
+                close: false,
+                dropped: false,
+                congestion: Window::new(
+                    default_congestion(),
+                    congestion::DEFAULT_MSS,
+                    self.tcp_initial_window,
+                ),
+                tracked: None,
+            }),
+            cond: Condvar::new(),
+            notifier: self.notifier.clone(),
+        });
+        self.streams.insert(handle, buffers.clone());
+        buffers
+    }
+    /// Moves the received data from the sockets into the rings of the streams and the data
+    /// to send the other way, and wakes up the threads of the streams with new data, space,
+    /// or state
+    /// Dropped streams are released when their send ring is empty.
+    /// The data passed to a socket is limited by the congestion window of the connection.
+    /// Every stream is visited because smoltcp does not report which sockets a poll changed.
+    fn transfer_streams(&mut self) {
+        let mut released = vec![];
+        let now = StdInstant::now();
+        let connections = &self.iface.inspection().congestion;
+        for (handle, buffers) in self.streams.iter() {
+            let mut guard = buffers.rings.lock();
+            let rings = &mut *guard;
+            let mut socket = self.sockets.get::<TcpSocket>(*handle);
+            let mut changed = false;
+            while socket.can_recv() && !rings.rx.is_full() {
+                let rx = &mut rings.rx;
+                match socket.recv(|data| {
+                    let n = rx.enqueue_slice(data);
+                    (n, n)
+                }) {
+                    Ok(n) if n > 0 => changed = true,
+                    _ => break,
+                }
+            }
+            track_retransmits(connections, rings, &socket);
+            rings.congestion.on_queue(socket.send_queue(), now);
+            if let Some((_, ref retransmits)) = rings.tracked {
+                rings
+                    .congestion
+                    .on_retransmits(retransmits.load(Ordering::Relaxed));
+            }
+            while socket.can_send() && !rings.tx.is_empty() {
+                let available = rings.congestion.available();
+                if available == 0 {
+                    break;
+                }
+                let tx = &mut rings.tx;
+                match socket.send(|space| {
+                    let len = space.len().min(available);
+                    let n = tx.dequeue_slice(&mut space[..len]);
+                    (n, n)
+                }) {
+                    Ok(n) if n > 0 => {
+                        changed = true;
+                        rings.congestion.on_sent(n, now);
+                    }
+                    _ => break,
+                }
+            }
+            if rings.close && rings.tx.is_empty() && socket.may_send() {
+                socket.close();
+            }
+            let reset = self.address_removed.contains(handle);
+            let state = (socket.may_recv(), socket.may_send(), reset);
+            if state != (rings.may_recv, rings.may_send, rings.reset) {
+                rings.may_recv = state.0;
+                rings.may_send = state.1;
+                rings.reset = state.2;
+                changed = true;
+            }
+            // a regular close passes through LAST-ACK or TIME-WAIT
+            let tcp_state = socket.state();
+            if tcp_state != rings.state {
+                if tcp_state == TcpState::Closed
+                    && rings.state != TcpState::LastAck
+                    && rings.state != TcpState::TimeWait
+                {
+                    rings.peer_reset = true;
+                    changed = true;
+                }
+                rings.state = tcp_state;
+            }
+            if rings.dropped && (rings.tx.is_empty() || !socket.may_send()) {
+                if let Some((key, _)) = rings.tracked.take() {
+                    connections.untrack(key.0, key.1, key.2);
+                }
+                released.push(*handle);
+            }
+            if changed {
+                buffers.cond.notify_all();
+            }
+        }
+        for handle in released {
+            let _ = self.streams.remove(&handle);
+            self.release_tcp(handle);
+        }
+    }
+    /// Closes the dropped TCP socket and removes it from the set once it reached the closed
+    /// state, e.g., after TIME-WAIT
+    /// This replaces SocketSet::prune, which visits every socket of the set on each drop.
+    fn release_tcp(&mut self, handle: SocketHandle) {
+        let closed = {
+            let mut socket = self.sockets.get::<TcpSocket>(handle);
+            socket.close();
+            socket.state() == TcpState::Closed
+        };
+        if closed {
+            self.sockets.remove(handle);
+        } else {
+            let deadline = Instant::now() + SmoltcpDuration::from_millis(CLOSE_CHECK_MS);
+            self.closing
+                .push(Reverse((deadline, handle, CLOSE_CHECK_MS)));
+        }
+    }
+    /// Removes the dropped TCP sockets that are closed now, only the ones with an expired
+    /// check deadline are looked at and the others are checked again with a doubled interval
+    fn remove_closed(&mut self, now: Instant) {
+        while let Some(&Reverse((deadline, handle, interval))) = self.closing.peek() {
+            if deadline > now {
+                break;
+            }
+            let _ = self.closing.pop();
+            if self.sockets.get::<TcpSocket>(handle).state() == TcpState::Closed {
+                self.sockets.remove(handle);
+            } else {
+                let interval = (interval * 2).min(CLOSE_CHECK_MAX_MS);
+                let deadline = now + SmoltcpDuration::from_millis(interval);
+                self.closing.push(Reverse((deadline, handle, interval)));
+            }
+        }
+    }
+    fn next_close_check(&self, now: Instant) -> Option<SmoltcpDuration> {
+        self.closing.peek().map(|&Reverse((deadline, _, _))| {
+            if deadline > now {
+                deadline - now
+            } else {
+                SmoltcpDuration::from_millis(0)
+            }
+        })
+    }
+    fn runtime_config(&mut self) -> RuntimeConfig {
+        let ipv4 = match self.iface.ips()[0] {
+            IpCidr::Ipv4(cidr) if !cidr.address().is_unspecified() => {
+                Some((Ipv4Addr::from(cidr.address()), cidr.prefix_len()))
+            }
+            _ => None,
+        };
+        RuntimeConfig {
+            ipv4: ipv4,
+            gateway: routing::default_ipv4_gateway(&mut self.iface),
+            mtu: self.mtu,
+            name_servers: self.name_servers.clone(),
+        }
+    }
+    fn apply_runtime_config(&mut self, config: RuntimeConfig) -> io::Result<()> {
+        let current = self.runtime_config();
+        if config.ipv4 != current.ipv4 || config.gateway != current.gateway {
+            if self.dhcp.is_some() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "address and gateway are managed by DHCP",
+                ));
+            }
+            if config.ipv4 != current.ipv4 && self.iface.control().is_some() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "address change not supported with usnetd, port matches need the IP",
+                ));
+            }
+            if let Some((old, _)) = current.ipv4 {
+                if config.ipv4.map(|(address, _)| address) != Some(old) {
+                    self.reset_connections_from(IpAddress::from(IpAddr::V4(old)));
+                }
+            }
+            match config.ipv4 {
+                Some((address, prefix_len)) => self.iface.set_ipv4_config(
+                    Ipv4Cidr::new(address.into(), prefix_len),
+                    config.gateway.map(|gateway| gateway.into()),
+                ),
+                None => self.iface.clear_ipv4_config(),
+            }
+            info!(
+                "reconfigured address {:?} and gateway {:?}",
+                config.ipv4, config.gateway
+            );
+        }
+        if config.mtu != current.mtu {
+            match self.slaac {
+                Some(ref mut slaac) => slaac.set_mtu_limit(&mut self.iface, config.mtu),
+                None => self.iface.set_mtu_limit(config.mtu),
+            }
+            self.mtu = config.mtu;
+            info!("reconfigured MTU limit {:?}", config.mtu);
+        }
+        if config.name_servers != current.name_servers {
+            self.slaac_name_servers
+                .retain(|ns| config.name_servers.contains(ns));
+            self.name_servers = config.name_servers;
+            info!("reconfigured name servers {:?}", self.name_servers);
+        }
+        Ok(())
+    }
+    /// Aborts the established TCP connections from the address, the next use of them fails
+    fn check_running(&self) -> io::Result<()> {
+        if self.shut_down {
+            return Err(shut_down_error());
+        }
+        Ok(())
+    }
+    /// Stops the sockets for the shutdown and closes the TCP sockets, the ones of streams
+    /// only after their send rings are sent
+    fn close_all(&mut self) {
+        self.shut_down = true;
+        for buffers in self.streams.values() {
+            let mut rings = buffers.rings.lock();
+            rings.shut_down = true;
+            rings.close = true;
+            drop(rings);
+            buffers.cond.notify_all();
+        }
+        for handle in self.tcp_handles() {
+            if !self.streams.contains_key(&handle) {
+                self.sockets.get::<TcpSocket>(handle).close();
+            }
+        }
+    }
+    fn tcp_handles(&self) -> Vec<SocketHandle> {
+        self.sockets
+            .iter()
+            .filter_map(|socket| match *socket {
+                Socket::Tcp(ref socket) => Some(socket.handle()),
+                _ => None,
+            })
+            .collect()
+    }
+    /// Whether a TCP socket still waits for the end of its connection, TIME-WAIT is not
+    /// waited for
+    fn tcp_closing(&mut self) -> bool {
+        self.tcp_handles().into_iter().any(|handle| {
+            let state = self.sockets.get::<TcpSocket>(handle).state();
+            state != TcpState::Closed && state != TcpState::TimeWait
+        })
+    }
+    fn abort_all(&mut self) {
+        for handle in self.tcp_handles() {
+            let mut socket = self.sockets.get::<TcpSocket>(handle);
+            if socket.state() != TcpState::Closed && socket.state() != TcpState::TimeWait {
+                if let Some(&(conn_id, _)) = self.conn_states.get(&handle) {
+                    stack_log!(debug, "[conn {}] reset for the shutdown", conn_id);
+                }
+                socket.abort();
+            }
+        }
+    }
+    fn reset_connections_from(&mut self, addr: IpAddress) {
+        for (handle, &(conn_id, _)) in self.conn_states.iter() {
+            let mut socket = self.sockets.get::<TcpSocket>(*handle);
+            if socket.local_endpoint().addr == addr {
+                stack_log!(
+                    debug,
+                    "[conn {}] reset, local address {} removed",
+                    conn_id,
+                    addr
+                );
+                socket.abort();
+                self.address_removed.insert(*handle);
+            }
+        }
+    }
+    fn track_connection(&mut self, handle: SocketHandle) -> u64 {
+        let conn_id = NEXT_CONN_ID.fetch_add(1, Ordering::Relaxed);
+        let state = self.sockets.get::<TcpSocket>(handle).state();
+        self.conn_states.insert(handle, (conn_id, state));
+        conn_id
+    }
+    /// Logs the state transitions and retransmissions of the connections since the last poll
+    #[cfg(feature = "stack-log")]
+    fn log_state_changes(&mut self) {
+        let conn_states = &self.conn_states;
+        let connections = &self.iface.inspection().congestion;
+        self.conn_retransmits.retain(|handle, &mut (key, _, _)| {
+            let open = conn_states.contains_key(handle);
+            if !open {
+                connections.untrack(key.0, key.1, key.2);
+            }
+            open
+        });
+        for (handle, &mut (conn_id, ref mut last_state)) in self.conn_states.iter_mut() {
+            let socket = self.sockets.get::<TcpSocket>(*handle);
+            let state = socket.state();
+            if state != *last_state {
+                debug!("[conn {}] state {} -> {}", conn_id, last_state, state);
+                *last_state = state;
+            }
+            if !self.conn_retransmits.contains_key(handle)
+                && socket.remote_endpoint().is_specified()
+            {
+                // counted by the device layer as for the congestion control
+                let remote = endpoint_to_socket_addr(&socket.remote_endpoint());
+                let key = (remote.ip(), socket.local_endpoint().port, remote.port());
+                let counter = connections.track(key.0, key.1, key.2);
+                self.conn_retransmits.insert(*handle, (key, counter, 0));
+            }
+            if let Some(&mut (_, ref counter, ref mut logged)) =
+                self.conn_retransmits.get_mut(handle)
+            {
+                let retransmits = counter.load(Ordering::Relaxed);
+                if retransmits > *logged {
+                    debug!(
+                        "[conn {}] {} retransmitted segments, {} in total",
+                        conn_id,
+                        retransmits - *logged,
+                        retransmits
+                    );
+                    *logged = retransmits;
+                }
+            }
         }
     }
     fn create_socket(&mut self) -> SocketHandle {
-        let tcp_rx_buffer = TcpSocketBuffer::new(vec![0; self.socket_buffer_size]);
+        let tcp_rx_buffer = TcpSocketBuffer::new(vec![0; self.tcp_rx_buffer_size]);
         let tcp_tx_buffer = TcpSocketBuffer::new(vec![0; self.socket_buffer_size]);
         let tcp_socket = TcpSocket::new(tcp_rx_buffer, tcp_tx_buffer);
         self.sockets.add(tcp_socket)
     }
-    pub fn new_from_env() -> StcpNet {
-        let confvar = env::var("USNET_SOCKETS").unwrap();
-        let conf: StcpBackend = serde_json::from_str(&confvar).unwrap();
-        let waiting_poll = env::var("USNET_SOCKETS_WAIT").unwrap_or("true".to_string()) == "true";
-        info!("USNET_SOCKETS_WAIT: {}", waiting_poll);
-        let socket_buffer_size =
-            usize::from_str(&env::var("SOCKET_BUFFER").unwrap_or("500000".to_string()))
-                .expect("SOCKET_BUFFER not an usize");
-        assert!(socket_buffer_size > 0);
-        info!("SOCKET_BUFFER: {}", socket_buffer_size);
-        let bg_thread_pin_cpu_id_nr =
-            i16::from_str(&env::var("BG_THREAD_PIN_CPU_ID").unwrap_or("-1".to_string()))
-                .expect("BG_THREAD_PIN_CPU_ID not a number");
-        let bg_thread_pin_cpu_id = if bg_thread_pin_cpu_id_nr < 0 {
-            None
+    /// Whether the address is handled by the loopback of the stack, i.e., 127.0.0.1 or an
+    /// own address when the loopback is enabled
+    fn loopback_address(&self, ip: IpAddr) -> bool {
+        self.loopback
+            && (ip == IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1))
+                || (!ip.is_loopback() && self.iface.has_ip(ip)))
+    }
+    /// The addresses from which packets come through the loopback of the stack
+    fn loopback_ips(&self) -> &[IpCidr] {
+        if self.loopback {
+            self.iface.ips()
         } else {
-            Some(bg_thread_pin_cpu_id_nr as usize)
+            &[]
+        }
+    }
+    /// Whether a TCP or UDP socket of the stack is bound to the destination, then the
+    /// connection goes through the loopback of the stack instead of the kernel
+    /// The TCP and UDP sockets of the stack only support IPv4.
+    fn loopback_target(&self, addr: &SocketAddr, protocol: IpProtocol) -> bool {
+        if !addr.is_ipv4() || !self.loopback_address(addr.ip()) {
+            return false;
+        }
+        let ip = IpAddress::from(addr.ip());
+        let matches = |endpoint: IpEndpoint| {
+            endpoint.port == addr.port() && (endpoint.addr.is_unspecified() || endpoint.addr == ip)
         };
-        info!("BG_THREAD_PIN_CPU_ID: {:?}", bg_thread_pin_cpu_id);
-        let socket_backlog =
-            usize::from_str(&env::var("SOCKET_BACKLOG").unwrap_or("32".to_string()))
-                .expect("SOCKET_BACKLOG not an usize");
-        assert!(socket_backlog > 0);
-        info!("SOCKET_BACKLOG: {}", socket_backlog);
-        let reduce_mtu_by_nr =
-            usize::from_str(&env::var("REDUCE_MTU_BY").unwrap_or("0".to_string()))
-                .expect("BG_THREAD_PIN_CPU_ID not a number");
-        let reduce_mtu_by = if reduce_mtu_by_nr == 0 {
-            None
+        self.sockets.iter().any(|socket| match *socket {
+            Socket::Tcp(ref socket) if protocol == IpProtocol::Tcp => {
+                socket.is_listening() && matches(socket.local_endpoint())
+            }
+            Socket::Udp(ref socket) if protocol == IpProtocol::Udp => {
+                socket.is_open() && matches(socket.endpoint())
+            }
+            _ => false,
+        })
+    }
+    /// Fails with ENETUNREACH if no route reaches the destination, so that its packets would
+    /// be dropped, own, broadcast, and multicast addresses are always reached
+    fn check_route(&mut self, ip: IpAddr) -> io::Result<()> {
+        if ip.is_loopback()
+            || ip.is_unspecified()
+            || ip.is_multicast()
+            || ip == IpAddr::V4(Ipv4Addr::BROADCAST)
+            || self.iface.has_ip(ip)
+            || routing::next_hop(&mut self.iface, ip).is_some()
+        {
+            Ok(())
+        } else {
+            Err(io::Error::from_raw_os_error(libc::ENETUNREACH))
+        }
+    }
+    /// Whether a UDP datagram to the address goes through the stack without consulting the
+    /// path policy, otherwise UdpSocket::send_to decides on the path
+    fn udp_through_stack(&self, addr: &SocketAddr) -> bool {
+        if addr.ip().is_loopback() {
+            self.loopback_target(addr, IpProtocol::Udp)
         } else {
-            Some(reduce_mtu_by_nr)
+            !self.path_policy.has_fallback()
+                || addr.ip().is_unspecified()
+                || self.iface.has_ip(addr.ip())
+        }
+    }
+    /// Whether a UDP socket of the stack is bound to the port, which a socket with
+    /// SO_REUSEADDR or SO_REUSEPORT may share
+    fn udp_port_bound(&self, port: u16) -> bool {
+        self.sockets.iter().any(|socket| match *socket {
+            Socket::Udp(ref socket) => socket.is_open() && socket.endpoint().port == port,
+            _ => false,
+        })
+    }
+    fn new_from_init_config() -> StcpNet {
+        let config = {
+            let mut init_config = INIT_CONFIG.lock();
+            STCP_GLOBAL_CREATED.store(true, Ordering::SeqCst);
+            init_config.take()
         };
-        info!("REDUCE_MTU_BY: {:?}", reduce_mtu_by);
-        StcpNet::new(
-            conf,
-            waiting_poll,
-            socket_buffer_size,
-            bg_thread_pin_cpu_id,
-            socket_backlog,
-            reduce_mtu_by,
-        )
+        match config {
+            Some(config) => StcpNet::from_config(config),
+            None => StcpNet::new_from_env(),
+        }
+    }
+    pub fn new_from_env() -> StcpNet {
+        let config = StackConfig::from_env();
+        let stats_log_interval =
+            u64::from_str(&env::var("USNET_STATS_LOG_INTERVAL").unwrap_or("0".to_string()))
+                .expect("USNET_STATS_LOG_INTERVAL not a number");
+        info!("USNET_STATS_LOG_INTERVAL: {}", stats_log_interval);
+        if stats_log_interval > 0 {
+            let _ = spawn_stats_logger(Duration::from_secs(stats_log_interval));
+        }
+        if let Ok(pcap_path) = env::var("USNET_PCAP") {
+            info!("USNET_PCAP: {}", pcap_path);
+            enable_pcap(&pcap_path).expect("cannot create USNET_PCAP file");
+        }
+        StcpNet::from_config(config)
     }
     pub fn new(
         backend: StcpBackend,
@@ -248,38 +1119,482 @@ impl StcpNet {
         socket_backlog: usize,
         reduce_mtu_by: Option<usize>,
     ) -> StcpNet {
-        let (fd, iface_backend) = backend.to_interface(waiting_poll, reduce_mtu_by);
+        StcpNet::from_config(StackConfig {
+            backend: backend,
+            waiting_poll: waiting_poll,
+            spin_budget: Duration::from_secs(0),
+            socket_buffer_size: socket_buffer_size,
+            bg_thread_pin_cpu_id: bg_thread_pin_cpu_id,
+            socket_backlog: socket_backlog,
+            reduce_mtu_by: reduce_mtu_by,
+            mtu: None,
+            mss_clamp: None,
+            pmtu_probing: false,
+            tcp_sack: true,
+            tcp_window_scaling: true,
+            tcp_receive_window: None,
+            tcp_initial_window: congestion::DEFAULT_INITIAL_WINDOW,
+            name_servers: DEFAULT_NAME_SERVERS
+                .iter()
+                .map(|ns| SocketAddr::from_str(ns).unwrap())
+                .collect(),
+            slaac: false,
+            raw_sockets: false,
+            loopback: false,
+            usnet_prefixes: vec![],
+            addresses: vec![],
+            routes: vec![],
+            queues: 1,
+        })
+    }
+    pub fn from_config(mut config: StackConfig) -> StcpNet {
+        let queue_configs = (1..config.queues)
+            .map(|index| {
+                config
+                    .for_queue(index)
+                    .expect("invalid queue configuration")
+            })
+            .collect::<Vec<_>>();
+        if config.queues > 1 {
+            config.backend = config
+                .backend
+                .for_queue(0)
+                .expect("invalid queue configuration");
+        }
+        let first_queue = config.backend.queue_id();
+        let device_names = config.backend.device_names();
+        let uses_dhcp = config.backend.uses_dhcp();
+        let (fd, mut iface_backend) = config
+            .backend
+            .to_interface(config.waiting_poll, config.reduce_mtu_by)
+            .unwrap_or_else(|err| panic!("backend setup failed: {}", err));
+        iface_backend.set_mtu_limit(config.mtu);
+        routing::configure(&mut iface_backend, &config.addresses, &config.routes)
+            .expect("invalid address configuration");
+        iface_backend.inspection().pmtu.configure(
+            config.mss_clamp,
+            config.pmtu_probing,
+            config.tcp_sack,
+            config.tcp_window_scaling,
+        );
+        if config.loopback {
+            iface_backend.enable_loopback();
+        }
+        let path_policy = if config.usnet_prefixes.is_empty() {
+            PathPolicy::Usnet
+        } else {
+            PathPolicy::prefixes(config.usnet_prefixes.clone())
+                .expect("invalid prefix configuration")
+        };
         info!("created backend: {}", iface_backend);
         let (notify_poll, notify_poll_listener) = UnixDatagram::pair().unwrap();
         let _ = notify_poll.set_nonblocking(true).unwrap();
         let _ = notify_poll_listener.set_nonblocking(true).unwrap();
-        StcpNet {
+        let mut sockets = SocketSet::new(vec![]);
+        let dhcp = if uses_dhcp {
+            Some(DhcpClient::new(&mut sockets, Instant::now()))
+        } else {
+            None
+        };
+        let slaac = if config.slaac {
+            Some(Slaac::new(
+                &mut sockets,
+                iface_backend.ethernet_addr(),
+                config.mtu,
+                Instant::now(),
+            ))
+        } else {
+            None
+        };
+        let mut stcpnet = StcpNet {
             current_wait_delay: -1 as c_int,
             bg_skip_one_wait: Skip::Wait,
-            bg: thread::spawn(bg),
+            bg: None, // started by StcpNetRef::spawn
+            shut_down: false,
+            stop_bg: false,
             fd: fd,
             fds_add: vec![],
             fds_remove: vec![],
-            sockets: SocketSet::new(vec![]),
+            sockets: sockets,
+            notifier: Arc::new(PollNotifier {
+                socket: notify_poll.try_clone().unwrap(),
+                pending: AtomicBool::new(false),
+            }),
+            streams: BTreeMap::new(),
             notify_poll: notify_poll,
             notify_poll_listener: notify_poll_listener,
             iface: iface_backend,
-            waiting_poll: waiting_poll,
-            socket_backlog: socket_backlog,
-            socket_buffer_size: socket_buffer_size,
-            bg_thread_pin_cpu_id: bg_thread_pin_cpu_id,
+            waiting_poll: config.waiting_poll,
+            spin_budget: config.spin_budget,
+            socket_backlog: config.socket_backlog,
+            socket_buffer_size: config.socket_buffer_size,
+            tcp_rx_buffer_size: config.tcp_receive_buffer_size(),
+            tcp_initial_window: config.tcp_initial_window,
+            bg_thread_pin_cpu_id: config.bg_thread_pin_cpu_id,
             kernel_local_port_range: read_kernel_local_port_range(),
+            name_servers: config.name_servers,
+            interface_index: device_names.first().map_or(0, |name| interface_index(name)),
+            device_names: device_names,
+            dhcp: dhcp,
+            dhcp_event: None,
+            slaac: slaac,
+            slaac_name_servers: vec![],
+            routes: config.routes,
+            mtu: config.mtu,
+            address_removed: BTreeSet::new(),
+            icmp_match: false,
+            raw_sockets: config.raw_sockets,
+            raw_matches: BTreeSet::new(),
+            loopback: config.loopback,
+            path_policy: path_policy,
+            conn_states: BTreeMap::new(),
+            #[cfg(feature = "stack-log")]
+            conn_retransmits: BTreeMap::new(),
+            closing: BinaryHeap::new(),
+            queue_group: None,
+            queue_stacks: vec![],
+        };
+        if !queue_configs.is_empty() {
+            stcpnet.start_queues(first_queue, queue_configs);
         }
+        stcpnet
     }
-}
-
+    /// Creates the stacks of the other queues and lets the devices of all stacks hand over
+    /// the frames which are not for their stack
+    fn start_queues(&mut self, first_queue: u32, configs: Vec<StackConfig>) {
+        let rss = Rss::read(&self.device_names[0]).expect("cannot read the RSS configuration");
+        if !rss.hashes_ports() {
+            warn!(
+                "the RSS of {} only hashes the addresses, connections to a peer share one queue",
+                self.device_names[0]
+            );
+        }
+        let mut stacks = configs
+            .into_iter()
+            .map(StcpNet::from_config)
+            .collect::<Vec<_>>();
+        let notify_polls = iter::once(&*self)
+            .chain(stacks.iter())
+            .map(|stcpnet| stcpnet.notify_poll.try_clone().unwrap())
+            .collect();
+        let group = Arc::new(QueueGroup::new(rss, first_queue, notify_polls));
+        for (index, stcpnet) in iter::once(&mut *self).chain(stacks.iter_mut()).enumerate() {
+            stcpnet
+                .iface
+                .join_queue_group(group.clone(), index)
+                .expect("invalid queue configuration");
+            stcpnet.queue_group = Some((group.clone(), index));
+        }
+        info!(
+            "stacks on {} queues starting at queue {}",
+            group.stacks(),
+            first_queue
+        );
+        self.queue_stacks = stacks.into_iter().map(StcpNetRef::spawn).collect();
+    }
+    /// Whether the NIC receives the packets of a connection on the queue of this stack,
+    /// always true for a single queue
+    fn receives_on_queue(&self, own_ip: IpAddr, local_port: u16, remote: &SocketAddr) -> bool {
+        match (&self.queue_group, own_ip, remote) {
+            (&Some((ref group, index)), IpAddr::V4(ip), &SocketAddr::V4(remote)) => {
+                group.index_for(SocketAddrV4::new(ip, local_port), remote) == Some(index)
+            }
+            _ => true,
+        }
+    }
+}
+
+impl Drop for StcpNet {
+    fn drop(&mut self) {
+        let _ = self.notify_poll.send(b"$"); // lets the background thread exit
+    }
+}
+
 impl StcpNetRef {
+    /// Creates another stack next to the global one, e.g., the server side of an in-memory link
+    /// The stack runs until the returned reference and all its sockets are dropped.
+    pub fn new(config: StackConfig) -> StcpNetRef {
+        StcpNetRef::spawn(StcpNet::from_config(config))
+    }
+    fn spawn(stcpnet: StcpNet) -> StcpNetRef {
+        let stcpnetref = StcpNetRef {
+            r: Arc::new((Mutex::new(stcpnet), Condvar::new())),
+        };
+        let weak = Arc::downgrade(&stcpnetref.r);
+        let handle = thread::spawn(move || bg(weak));
+        stcpnetref.r.0.lock().bg = Some(handle);
+        stcpnetref
+    }
+    /// TcpListener::bind on this stack
+    pub fn tcp_bind<A: UsnetToSocketAddrs>(&self, addr: A) -> io::Result<StcpListenerRef> {
+        self.bind(addr)
+    }
+    /// TcpStream::connect on this stack
+    pub fn tcp_connect<A: UsnetToSocketAddrs>(&self, addr: A) -> io::Result<TcpStream> {
+        self.connect(addr)
+    }
+    /// TcpStream::connect_timeout on this stack
+    pub fn tcp_connect_timeout(
+        &self,
+        addr: &SocketAddr,
+        timeout: Duration,
+    ) -> io::Result<TcpStream> {
+        self.connect_timeout(addr, timeout)
+    }
+    /// UdpSocket::bind on this stack
+    pub fn udp_bind<A: UsnetToSocketAddrs>(&self, addr: A) -> io::Result<UdpSocket> {
+        self.bind_udp(addr)
+    }
+    fn queue_stacks(&self) -> Vec<StcpNetRef> {
+        let &(ref stcpnetref, ref _cond) = &*self.r;
+        stcpnetref.lock().queue_stacks.clone()
+    }
+    fn queue_group(&self) -> Option<Arc<QueueGroup>> {
+        let &(ref stcpnetref, ref _cond) = &*self.r;
+        let stcpnet = stcpnetref.lock();
+        stcpnet
+            .queue_group
+            .as_ref()
+            .map(|&(ref group, _)| group.clone())
+    }
+    /// On the first of multiple queues, returns the stack of the queue on which the NIC
+    /// receives a connection to the address from a random local port if it is another one
+    fn queue_stack(&self, addr: &SocketAddr) -> Option<StcpNetRef> {
+        let &(ref stcpnetref, ref _cond) = &*self.r;
+        let mut stcpnet = stcpnetref.lock();
+        let group = match stcpnet.queue_group {
+            Some((ref group, 0)) => group.clone(),
+            _ => return None,
+        };
+        let remote = match *addr {
+            SocketAddr::V4(remote) => remote,
+            SocketAddr::V6(_) => return None,
+        };
+        if remote.ip().is_loopback()
+            || remote.ip().is_unspecified()
+            || stcpnet.iface.has_ip(addr.ip())
+        {
+            return None;
+        }
+        let local_ip = match routing::source_address(&mut stcpnet.iface, addr.ip()) {
+            Some(IpAddr::V4(ip)) => ip,
+            _ => return None,
+        };
+        let local_port: u16 = 1u16 + thread_rng().gen_range(1024..std::u16::MAX);
+        match group.index_for(SocketAddrV4::new(local_ip, local_port), remote) {
+            Some(index) if index > 0 => Some(stcpnet.queue_stacks[index - 1].clone()),
+            _ => None,
+        }
+    }
+    /// Creates the listening sockets of a listener on the stack of another queue
+    fn listen_backlog(
+        &self,
+        ipa: IpAddr,
+        listen_addr: IpAddress,
+        port: u16,
+    ) -> io::Result<Vec<SocketHandle>> {
+        let &(ref stcpnetref, ref _cond) = &*self.r;
+        let mut stcpnet = stcpnetref.lock();
+        stcpnet
+            .iface
+            .add_port_match(ipa, Some(port), None, None, IpProtocol::Tcp)?;
+        let mut listen_handles = vec![];
+        for _ in 0..stcpnet.socket_backlog {
+            let tcp_handle = stcpnet.create_socket();
+            let mut socket = stcpnet.sockets.get::<TcpSocket>(tcp_handle);
+            socket.listen(IpEndpoint::new(listen_addr, port)).unwrap();
+            listen_handles.push(tcp_handle);
+        }
+        Ok(listen_handles)
+    }
+    /// Closes the TCP connections of this stack and stops it, see shutdown
+    pub fn shutdown(&self, timeout: Duration) -> io::Result<()> {
+        let deadline = StdInstant::now() + timeout;
+        let &(ref stcpnetref, ref cond) = &*self.r;
+        let bg = {
+            let mut stcpnet = stcpnetref.lock();
+            if stcpnet.shut_down {
+                return Ok(());
+            }
+            info!("shutting down the stack");
+            stcpnet.close_all();
+            if stcpnet.bg_skip_one_wait == Skip::Wait {
+                let _ = stcpnet.notify_poll.send(b"$").unwrap();
+            } else {
+                stcpnet.bg_skip_one_wait = Skip::Skip;
+            }
+            cond.notify_all(); // blocked socket calls fail now
+            if let Some((ref group, _)) = stcpnet.queue_group {
+                group.notify_polled(); // for accept calls waiting for a poll
+            }
+            while stcpnet.tcp_closing() {
+                if cond.wait_until(&mut stcpnet, deadline).timed_out() {
+                    break;
+                }
+            }
+            stcpnet.abort_all();
+            stcpnet.stop_bg = true;
+            if stcpnet.bg_skip_one_wait == Skip::Wait {
+                let _ = stcpnet.notify_poll.send(b"$").unwrap();
+            } else {
+                stcpnet.bg_skip_one_wait = Skip::Skip;
+            }
+            stcpnet.bg.take()
+        };
+        if let Some(bg) = bg {
+            // unless called from a DHCP callback
+            if bg.thread().id() != thread::current().id() {
+                bg.join().map_err(|_| {
+                    io::Error::new(io::ErrorKind::Other, "background thread panicked")
+                })?;
+            }
+        }
+        stcpnetref.lock().iface.detach();
+        info!("stack shut down");
+        Ok(())
+    }
+    /// Changes the primary address, default gateway, MTU limit, and name servers of the stack
+    /// The closure gets the current settings and runs while the stack lock is held, so that
+    /// the settings can't change in between, e.g., through DHCP, SLAAC, or another reconfigure
+    /// call, and it must not use sockets or other functions of the stack.
+    /// Nothing changes if the closure produces an invalid configuration.
+    /// Established TCP connections from a removed address are reset and their next read or write
+    /// fails with ConnectionReset, also for threads which are already blocked in it.
+    /// Listeners on the unspecified address keep working. The new name servers are used for the
+    /// next host name lookup since no resolver state is kept between lookups.
+    /// The address can't be changed if it is managed by DHCP or with usnetd.
+    pub fn reconfigure<F: FnOnce(&mut RuntimeConfig)>(&self, f: F) -> io::Result<()> {
+        let &(ref stcpnetref, ref cond) = &*self.r;
+        let mut stcpnet = stcpnetref.lock();
+        let mut config = stcpnet.runtime_config();
+        f(&mut config);
+        config.validate()?;
+        stcpnet.apply_runtime_config(config)?;
+        if stcpnet.bg_skip_one_wait == Skip::Wait {
+            let _ = stcpnet.notify_poll.send(b"$").unwrap();
+        } else {
+            stcpnet.bg_skip_one_wait = Skip::Skip;
+        }
+        cond.notify_all(); // blocked threads check for reset connections
+        Ok(())
+    }
+    /// Returns the current primary address, default gateway, MTU limit, and name servers
+    pub fn runtime_config(&self) -> RuntimeConfig {
+        let &(ref stcpnetref, ref _cond) = &*self.r;
+        let mut stcpnet = stcpnetref.lock();
+        stcpnet.runtime_config()
+    }
+    /// Returns the entries of the neighbor table of the stack
+    pub fn neighbors(&self) -> Vec<NeighborEntry> {
+        let &(ref stcpnetref, ref _cond) = &*self.r;
+        let stcpnet = stcpnetref.lock();
+        stcpnet.inspection().neighbors.entries()
+    }
+    /// Removes all entries of the neighbor table except the static ones and sends new
+    /// requests for the learned entries
+    pub fn flush_neighbors(&self) {
+        let &(ref stcpnetref, ref _cond) = &*self.r;
+        let stcpnet = stcpnetref.lock();
+        stcpnet.inspection().neighbors.flush()
+    }
+    /// Installs a static neighbor entry, packets to the address are sent to the MAC without
+    /// ARP or NDP
+    pub fn add_static_neighbor(&self, ip: IpAddr, mac: [u8; 6]) -> io::Result<()> {
+        let &(ref stcpnetref, ref _cond) = &*self.r;
+        let stcpnet = stcpnetref.lock();
+        stcpnet.inspection().neighbors.add_static(ip, mac)
+    }
+    /// Removes a static neighbor entry
+    pub fn remove_static_neighbor(&self, ip: IpAddr) -> io::Result<()> {
+        let &(ref stcpnetref, ref _cond) = &*self.r;
+        let stcpnet = stcpnetref.lock();
+        stcpnet.inspection().neighbors.remove_static(ip)
+    }
+    /// Returns the path MTUs of the stack which are lower than the MTU of the interface
+    pub fn path_mtus(&self) -> Vec<PathMtu> {
+        let &(ref stcpnetref, ref _cond) = &*self.r;
+        let stcpnet = stcpnetref.lock();
+        stcpnet.inspection().pmtu.entries()
+    }
+    /// Removes all path MTUs learned by the stack
+    pub fn flush_path_mtus(&self) {
+        let &(ref stcpnetref, ref _cond) = &*self.r;
+        let stcpnet = stcpnetref.lock();
+        stcpnet.inspection().pmtu.flush()
+    }
+    /// Returns the frames and bytes which the interface of the stack received and transmitted,
+    /// and the occupancy of the netmap rings or of the io_uring of tap and macvtap
+    pub fn interface_stats(&self) -> InterfaceStats {
+        let &(ref stcpnetref, ref _cond) = &*self.r;
+        let stcpnet = stcpnetref.lock();
+        stcpnet.iface.interface_stats()
+    }
+    fn check_running(&self) -> io::Result<()> {
+        let &(ref stcpnetref, ref _cond) = &*self.r;
+        stcpnetref.lock().check_running()
+    }
+    fn check_first_queue(&self) -> io::Result<()> {
+        let &(ref stcpnetref, ref _cond) = &*self.r;
+        match stcpnetref.lock().queue_group {
+            Some((_, index)) if index > 0 => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "UDP, ICMP, and raw sockets are only supported on the stack of the first queue",
+            )),
+            _ => Ok(()),
+        }
+    }
+    fn loopback_target(&self, addr: &SocketAddr, protocol: IpProtocol) -> bool {
+        let &(ref stcpnetref, ref _cond) = &*self.r;
+        stcpnetref.lock().loopback_target(addr, protocol)
+    }
+    /// Returns whether a remote destination is reached through the stack or kernel sockets,
+    /// loopback, unspecified, and own addresses are not affected by the policy
+    fn socket_path(&self, ip: IpAddr) -> SocketPath {
+        let policy = {
+            let &(ref stcpnetref, ref _cond) = &*self.r;
+            let stcpnet = stcpnetref.lock();
+            if !stcpnet.path_policy.has_fallback()
+                || ip.is_loopback()
+                || ip.is_unspecified()
+                || stcpnet.iface.has_ip(ip)
+            {
+                return SocketPath::Usnet;
+            }
+            stcpnet.path_policy.clone()
+        };
+        policy.path(ip)
+    }
     fn bind<A: UsnetToSocketAddrs>(&self, addr: A) -> io::Result<StcpListenerRef> {
+        self.bind_to(addr, BoundDevice::Any, false)
+    }
+    fn bind_device<A: UsnetToSocketAddrs>(
+        &self,
+        addr: A,
+        device: &str,
+        only_v6: bool,
+    ) -> io::Result<StcpListenerRef> {
+        let (addrs, device) = self.device_addrs(addr, device, only_v6)?;
+        self.bind_to(&addrs[..], device, only_v6)
+    }
+    fn bind_to<A: UsnetToSocketAddrs>(
+        &self,
+        addr: A,
+        device: BoundDevice,
+        only_v6: bool,
+    ) -> io::Result<StcpListenerRef> {
+        self.check_running()?;
         let mut r = Err(io::Error::new(
             io::ErrorKind::Other,
             "to_socket_addrs is empty",
         ));
-        for mut sockaddr in addr.usnet_to_socket_addrs()? {
+        for sockaddr in addr.usnet_to_socket_addrs()? {
+            let mut sockaddr = match bind_address(sockaddr, only_v6) {
+                Ok(sockaddr) => sockaddr,
+                Err(e) => {
+                    r = Err(e);
+                    continue;
+                }
+            };
             let ipa = sockaddr.ip();
             if !ipa.is_loopback() && ipa.is_ipv6() {
                 r = Err(io::Error::new(io::ErrorKind::Other, "ipv6 bind"));
@@ -288,9 +1603,14 @@ impl StcpNetRef {
 
             let mut listen_handles = vec![];
             let lolisten;
+            let listen_addr = routing::endpoint_address(ipa);
             {
                 let &(ref stcpnetref, ref _cond) = &*self.r;
                 let mut stcpnet = stcpnetref.lock();
+                if let Err(e) = routing::check_local_address(&stcpnet.iface, ipa) {
+                    r = Err(e);
+                    continue;
+                }
 
                 let mut lo_result = Err(io::Error::new(io::ErrorKind::Other, "uninit"));
                 let mut usnet_result = Err(io::Error::new(io::ErrorKind::Other, "uninit"));
@@ -326,13 +1646,16 @@ impl StcpNetRef {
                         continue;
                     }
                 }
-                if sockaddr.ip().is_loopback() {
+                if sockaddr.ip().is_loopback() && !stcpnet.loopback_address(sockaddr.ip()) {
                     return Ok(StcpListenerRef {
                         l: Arc::new(Mutex::new(StcpListener {
                             stcpnet: (*self).clone(),
+                            listen_addr: listen_addr,
                             port: sockaddr.port(),
                             lo: lolisten,
                             listen_handles: None,
+                            queue_handles: vec![],
+                            device: device,
                             ttl: None,
                             nonblocking: false,
                         })),
@@ -340,6 +1663,7 @@ impl StcpNetRef {
                 }
                 match usnet_result {
                     Ok(_) => {}
+                    Err(_) if sockaddr.ip().is_loopback() => {} // only reached through the loopback
                     Err(e) => {
                         r = Err(e);
                         continue;
@@ -351,19 +1675,32 @@ impl StcpNetRef {
                 for _ in 0..stcpnet.socket_backlog {
                     let tcp_handle = stcpnet.create_socket();
                     let mut socket = stcpnet.sockets.get::<TcpSocket>(tcp_handle);
-                    socket.listen(sockaddr.port()).unwrap();
+                    socket
+                        .listen(IpEndpoint::new(listen_addr, sockaddr.port()))
+                        .unwrap();
                     listen_handles.push(tcp_handle);
                 }
             }
+            let mut listener = StcpListener {
+                stcpnet: (*self).clone(),
+                listen_addr: listen_addr,
+                port: sockaddr.port(),
+                listen_handles: Some(listen_handles),
+                queue_handles: vec![],
+                lo: lolisten,
+                device: device,
+                ttl: None,
+                nonblocking: false,
+            };
+            if !ipa.is_loopback() {
+                // the NIC steers the connections to any queue
+                for stcpnet in self.queue_stacks() {
+                    let handles = stcpnet.listen_backlog(ipa, listen_addr, sockaddr.port())?;
+                    listener.queue_handles.push((stcpnet, handles));
+                }
+            }
             return Ok(StcpListenerRef {
-                l: Arc::new(Mutex::new(StcpListener {
-                    stcpnet: (*self).clone(),
-                    port: sockaddr.port(),
-                    listen_handles: Some(listen_handles),
-                    lo: lolisten,
-                    ttl: None,
-                    nonblocking: false,
-                })),
+                l: Arc::new(Mutex::new(listener)),
             });
         }
         r
@@ -379,21 +1716,46 @@ impl StcpNetRef {
         addr: A,
         timeout: Option<Duration>,
     ) -> io::Result<TcpStream> {
+        self.check_running()?;
         let mut r = Err(io::Error::new(
             io::ErrorKind::Other,
             "to_socket_addrs is empty",
         ));
-        for addr in addr.usnet_to_socket_addrs()? {
-            let mut error = false;
+        'addrs: for addr in addr.usnet_to_socket_addrs()?.map(unmapped) {
+            if self.socket_path(addr.ip()) == SocketPath::Kernel {
+                r = match timeout {
+                    Some(timeout) => SystemTcpStream::connect_timeout(&addr, timeout),
+                    None => SystemTcpStream::connect(addr),
+                }
+                .map(|s| TcpStream::System(s))
+                .map_err(|e| fallback::path_error(e, SocketPath::Kernel));
+                if r.is_ok() {
+                    return r;
+                } else {
+                    continue;
+                }
+            }
+            if let Some(stcpnet) = self.queue_stack(&addr) {
+                r = stcpnet.connect_timeout_opt(addr, timeout);
+                if r.is_ok() {
+                    return r;
+                } else {
+                    continue;
+                }
+            }
+            let mut error = None;
             let tcp_handle;
+            let conn_id;
+            let mut buffers = None;
             {
                 let &(ref stcpnetref, ref cond) = &*self.r;
                 let mut stcpnet = stcpnetref.lock();
 
-                let ipv4 = stcpnet.iface.ips()[0].address();
-                if addr.ip().is_loopback()
-                    || IpAddress::from(addr.ip()) == ipv4
-                    || addr.ip().is_unspecified()
+                let loopback = stcpnet.loopback_target(&addr, IpProtocol::Tcp);
+                if (addr.ip().is_loopback()
+                    || stcpnet.iface.has_ip(addr.ip())
+                    || addr.ip().is_unspecified())
+                    && !loopback
                 {
                     r = match timeout {
                         Some(timeout) => SystemTcpStream::connect_timeout(&addr, timeout),
@@ -410,30 +1772,53 @@ impl StcpNetRef {
                     r = Err(io::Error::new(io::ErrorKind::Other, "ipv6 address"));
                     continue;
                 }
+                let next_hop = if loopback {
+                    None
+                } else {
+                    if let Err(e) = stcpnet.check_route(addr.ip()) {
+                        r = Err(fallback::path_error(e, SocketPath::Usnet));
+                        continue;
+                    }
+                    routing::next_hop(&mut stcpnet.iface, addr.ip())
+                };
 
+                let own_ip = if loopback {
+                    addr.ip()
+                } else {
+                    routing::source_address(&mut stcpnet.iface, addr.ip())
+                        .unwrap_or(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)))
+                };
                 let mut local_port: u16;
+                let mut queue_attempts = 0;
                 loop {
                     local_port = 1u16 + thread_rng().gen_range(1024..std::u16::MAX);
                     let (lower, upper) = stcpnet.kernel_local_port_range;
                     if local_port >= lower && local_port <= upper {
                         continue;
                     }
-                    let own_ip = match ipv4.into() {
-                        IpAddress::Ipv4(v) => IpAddr::V4(Ipv4Addr::from(v)),
-                        IpAddress::Ipv6(v) => IpAddr::V6(Ipv6Addr::from(v)),
-                        IpAddress::Unspecified => IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)),
-                        _ => panic!("not covered"),
-                    };
-                    if stcpnet
-                        .iface
-                        .add_port_match(
-                            own_ip,
-                            Some(local_port),
-                            Some(addr.ip()),
-                            Some(addr.port()),
-                            IpProtocol::Tcp,
-                        )
-                        .is_ok()
+                    if !loopback && !stcpnet.receives_on_queue(own_ip, local_port, &addr) {
+                        queue_attempts += 1;
+                        if queue_attempts == MAX_QUEUE_PORT_ATTEMPTS {
+                            r = Err(io::Error::new(
+                                io::ErrorKind::AddrNotAvailable,
+                                "the NIC steers no connection to the address to this queue",
+                            ));
+                            continue 'addrs;
+                        }
+                        continue;
+                    }
+                    // the packets through the loopback don't pass usnetd
+                    if loopback
+                        || stcpnet
+                            .iface
+                            .add_port_match(
+                                own_ip,
+                                Some(local_port),
+                                Some(addr.ip()),
+                                Some(addr.port()),
+                                IpProtocol::Tcp,
+                            )
+                            .is_ok()
                     {
                         break;
                     }
@@ -442,8 +1827,22 @@ impl StcpNetRef {
                 tcp_handle = stcpnet.create_socket();
                 {
                     let mut socket = stcpnet.sockets.get::<TcpSocket>(tcp_handle);
-                    socket.connect(addr, local_port).unwrap();
+                    socket
+                        .connect(
+                            addr,
+                            IpEndpoint::new(routing::endpoint_address(own_ip), local_port),
+                        )
+                        .unwrap();
                 }
+                conn_id = stcpnet.track_connection(tcp_handle);
+                stack_log!(
+                    debug,
+                    "[conn {}] connecting from {}:{} to {}",
+                    conn_id,
+                    own_ip,
+                    local_port,
+                    addr
+                );
 
                 if stcpnet.bg_skip_one_wait == Skip::Wait {
                     let _ = stcpnet.notify_poll.send(b"$").unwrap();
@@ -451,44 +1850,60 @@ impl StcpNetRef {
                     stcpnet.bg_skip_one_wait = Skip::Skip;
                 }
 
+                // the SYN is answered with a RST (ECONNREFUSED), the next hop is not resolved
+                // (EHOSTUNREACH), or the timeout expires (ETIMEDOUT)
                 let start = StdInstant::now();
-                while !{
-                    let socket = stcpnet.sockets.get::<TcpSocket>(tcp_handle);
-                    if !socket.is_active() {
-                        error = true;
-                        true // break
-                    } else {
-                        socket.may_recv() && socket.may_send()
-                    }
-                } {
-                    match timeout {
-                        Some(timeout) => {
-                            if cond.wait_until(&mut stcpnet, start + timeout).timed_out() {
-                                error = true;
-                                let mut socket = stcpnet.sockets.get::<TcpSocket>(tcp_handle);
-                                socket.abort();
-                                break;
-                            }
+                loop {
+                    {
+                        let socket = stcpnet.sockets.get::<TcpSocket>(tcp_handle);
+                        if !socket.is_active() {
+                            error = Some(io::Error::from_raw_os_error(libc::ECONNREFUSED));
+                            break;
                         }
-                        None => {
-                            cond.wait(&mut stcpnet);
+                        if socket.may_recv() && socket.may_send() {
+                            break;
                         }
                     }
+                    let unresolved = next_hop.map_or(false, |hop| {
+                        start.elapsed() >= neighbor::UNRESOLVED_TIMEOUT
+                            && stcpnet.iface.inspection().neighbors.unresolved(hop)
+                    });
+                    if !unresolved {
+                        let check = StdInstant::now() + Duration::from_millis(NEIGHBOR_CHECK_MS);
+                        let deadline = timeout.map(|timeout| start + timeout);
+                        let until = deadline.map_or(check, |deadline| deadline.min(check));
+                        let _ = cond.wait_until(&mut stcpnet, until);
+                        if deadline.map_or(true, |deadline| StdInstant::now() < deadline) {
+                            continue;
+                        }
+                    }
+                    error = Some(io::Error::from_raw_os_error(if unresolved {
+                        libc::EHOSTUNREACH
+                    } else {
+                        libc::ETIMEDOUT
+                    }));
+                    stcpnet.sockets.get::<TcpSocket>(tcp_handle).abort();
+                    break;
                 }
-                if error {
-                    stcpnet.sockets.release(tcp_handle);
-                    stcpnet.sockets.prune();
+                if let Some(ref e) = error {
+                    stack_log!(debug, "[conn {}] connection not successful: {}", conn_id, e);
+                    stcpnet.conn_states.remove(&tcp_handle);
+                    stcpnet.address_removed.remove(&tcp_handle);
+                    stcpnet.release_tcp(tcp_handle);
+                } else {
+                    buffers = Some(stcpnet.register_stream(tcp_handle));
                 }
             }
-            if error {
-                r = Err(io::Error::new(
-                    io::ErrorKind::Other,
-                    "connection not successful",
-                ));
+            if let Some(e) = error {
+                r = Err(fallback::path_error(e, SocketPath::Usnet));
             } else {
+                stack_log!(debug, "[conn {}] established", conn_id);
                 return Ok(TcpStream::Stcp(StcpStream {
                     stcpnet: (*self).clone(),
                     sockethandle: tcp_handle,
+                    conn_id: conn_id,
+                    handle_refs: Arc::new(AtomicUsize::new(1)),
+                    buffers: buffers.unwrap(),
                     nonblocking: Arc::new(AtomicBool::new(false)),
                     read_timeout: Arc::new(RwLock::new(None)),
                     write_timeout: Arc::new(RwLock::new(None)),
@@ -497,12 +1912,111 @@ impl StcpNetRef {
         }
         r
     }
+    /// Returns the interface with the name, which must be the NIC of the stack (or its parent
+    /// for macvtap and usnetd) or "lo", others fail as not used or not existing
+    fn bound_device(&self, device: &str) -> io::Result<BoundDevice> {
+        if device == "lo" {
+            return Ok(BoundDevice::Loopback);
+        }
+        let device_names = {
+            let &(ref stcpnetref, ref _cond) = &*self.r;
+            let stcpnet = stcpnetref.lock();
+            stcpnet.device_names.clone()
+        };
+        if !device_names.iter().any(|d| d == device) {
+            if Path::new("/sys/class/net").join(device).exists() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "device {} is not used by the userspace network stack, it runs on {}",
+                        device,
+                        device_names.join(", ")
+                    ),
+                ));
+            }
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("no such device: {}", device),
+            ));
+        }
+        Ok(BoundDevice::Nic)
+    }
+    /// Resolves the addresses of a socket bound to the named interface, the unspecified
+    /// address stands for 127.0.0.1 on "lo" and loopback addresses are not on the NIC
+    fn device_addrs<A: UsnetToSocketAddrs>(
+        &self,
+        addr: A,
+        device: &str,
+        only_v6: bool,
+    ) -> io::Result<(Vec<SocketAddr>, BoundDevice)> {
+        let bound = self.bound_device(device)?;
+        let mut addrs = vec![];
+        for a in addr.usnet_to_socket_addrs()? {
+            let mut a = bind_address(a, only_v6)?;
+            if bound == BoundDevice::Loopback && a.ip().is_unspecified() {
+                a.set_ip(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)));
+            }
+            addrs.push(a);
+        }
+        if bound == BoundDevice::Loopback && addrs.iter().any(|a| !a.ip().is_loopback()) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "device lo needs a loopback or unspecified address",
+            ));
+        }
+        if bound == BoundDevice::Nic && addrs.iter().any(|a| a.ip().is_loopback()) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("device {} has no loopback address", device),
+            ));
+        }
+        Ok((addrs, bound))
+    }
+    fn bind_udp_device<A: UsnetToSocketAddrs>(
+        &self,
+        addr: A,
+        options: UdpBindOptions,
+        device: &str,
+    ) -> io::Result<UdpSocket> {
+        let (addrs, device) = self.device_addrs(addr, device, options.only_v6)?;
+        self.bind_udp_to(&addrs[..], options, device)
+    }
     fn bind_udp<A: UsnetToSocketAddrs>(&self, addr: A) -> io::Result<UdpSocket> {
+        self.bind_udp_with(addr, UdpBindOptions::default())
+    }
+    fn bind_udp_with<A: UsnetToSocketAddrs>(
+        &self,
+        addr: A,
+        options: UdpBindOptions,
+    ) -> io::Result<UdpSocket> {
+        self.bind_udp_to(addr, options, BoundDevice::Any)
+    }
+    fn bind_udp_to<A: UsnetToSocketAddrs>(
+        &self,
+        addr: A,
+        options: UdpBindOptions,
+        device: BoundDevice,
+    ) -> io::Result<UdpSocket> {
+        self.check_running()?;
+        self.check_first_queue()?;
+        if options.buffer_size == Some(0) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "zero-sized socket buffer",
+            ));
+        }
         let mut r = Err(io::Error::new(
             io::ErrorKind::Other,
             "to_socket_addrs is empty",
         ));
-        for mut sockaddr in addr.usnet_to_socket_addrs()? {
+        for sockaddr in addr.usnet_to_socket_addrs()? {
+            let mut sockaddr = match bind_address(sockaddr, options.only_v6) {
+                Ok(sockaddr) => sockaddr,
+                Err(e) => {
+                    r = Err(e);
+                    continue;
+                }
+            };
             let ipa = sockaddr.ip();
             if !ipa.is_loopback() && ipa.is_ipv6() {
                 r = Err(io::Error::new(io::ErrorKind::Other, "ipv6 bind"));
@@ -514,6 +2028,10 @@ impl StcpNetRef {
             {
                 let &(ref stcpnetref, ref _cond) = &*self.r;
                 let mut stcpnet = stcpnetref.lock();
+                if let Err(e) = routing::check_local_address(&stcpnet.iface, ipa) {
+                    r = Err(e);
+                    continue;
+                }
 
                 let mut lo_result = Err(io::Error::new(io::ErrorKind::Other, "uninit"));
                 let mut usnet_result = Err(io::Error::new(io::ErrorKind::Other, "uninit"));
@@ -527,7 +2045,12 @@ impl StcpNetRef {
                     if loaddr.ip().is_unspecified() {
                         loaddr.set_ip(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)));
                     }
-                    lo_result = SystemUdpSocket::bind(loaddr);
+                    // a random port is not shared, as with the kernel
+                    lo_result = if find_random {
+                        SystemUdpSocket::bind(loaddr)
+                    } else {
+                        bind_kernel_udp(loaddr, options)
+                    };
                     if lo_result.is_ok() && !sockaddr.ip().is_loopback() {
                         usnet_result = stcpnet.iface.add_port_match(
                             ipa,
@@ -536,6 +2059,14 @@ impl StcpNetRef {
                             None,
                             IpProtocol::Udp,
                         );
+                        // usnetd already forwards the port to the stack
+                        if usnet_result.is_err()
+                            && !find_random
+                            && options.shares_port()
+                            && stcpnet.udp_port_bound(sockaddr.port())
+                        {
+                            usnet_result = Ok(());
+                        }
                     }
                     if !find_random {
                         break;
@@ -549,12 +2080,19 @@ impl StcpNetRef {
                         continue;
                     }
                 }
-                if sockaddr.ip().is_loopback() {
+                if sockaddr.ip().is_loopback() && !stcpnet.loopback_address(sockaddr.ip()) {
                     return Ok(UdpSocket {
                         stcpnet: (*self).clone(),
                         socket_handle: None,
                         lo: lolisten,
                         connected: Arc::new(RwLock::new(None)),
+                        connected_lo: Arc::new(AtomicBool::new(false)),
+                        kernel: Arc::new(Mutex::new(None)),
+                        connected_kernel: Arc::new(AtomicBool::new(false)),
+                        handle_refs: Arc::new(AtomicUsize::new(1)),
+                        device: device,
+                        tos: Arc::new(AtomicU32::new(0)),
+                        ttl: Arc::new(AtomicU32::new(DEFAULT_HOP_LIMIT)),
                         nonblocking: Arc::new(AtomicBool::new(false)),
                         read_timeout: Arc::new(RwLock::new(None)),
                         write_timeout: Arc::new(RwLock::new(None)),
@@ -562,6 +2100,7 @@ impl StcpNetRef {
                 }
                 match usnet_result {
                     Ok(_) => {}
+                    Err(_) if sockaddr.ip().is_loopback() => {} // only reached through the loopback
                     Err(e) => {
                         r = Err(e);
                         continue;
@@ -570,25 +2109,39 @@ impl StcpNetRef {
                 lolisten.set_nonblocking(true)?;
                 stcpnet.fds_add.push(lolisten.as_raw_fd());
 
+                let buffer_size = options.buffer_size.unwrap_or(stcpnet.socket_buffer_size);
                 let udp_rx_buffer = UdpSocketBuffer::new(
                     vec![UdpPacketMetadata::EMPTY; 1000],
-                    vec![0; stcpnet.socket_buffer_size],
+                    vec![0; buffer_size],
                 );
                 let udp_tx_buffer = UdpSocketBuffer::new(
                     vec![UdpPacketMetadata::EMPTY; 1000],
-                    vec![0; stcpnet.socket_buffer_size],
+                    vec![0; buffer_size],
                 );
                 let udp_socket = SmoltcpUdpSocket::new(udp_rx_buffer, udp_tx_buffer);
 
                 listen_handle = stcpnet.sockets.add(udp_socket);
                 let mut socket = stcpnet.sockets.get::<SmoltcpUdpSocket>(listen_handle);
-                socket.bind(sockaddr.port()).unwrap();
+                socket
+                    .bind(IpEndpoint::new(
+                        routing::endpoint_address(ipa),
+                        sockaddr.port(),
+                    ))
+                    .unwrap();
+                debug!("UDP socket {} bound to {}", listen_handle, sockaddr);
             }
             return Ok(UdpSocket {
                 stcpnet: (*self).clone(),
                 socket_handle: Some(listen_handle),
                 lo: lolisten,
                 connected: Arc::new(RwLock::new(None)),
+                connected_lo: Arc::new(AtomicBool::new(false)),
+                kernel: Arc::new(Mutex::new(None)),
+                connected_kernel: Arc::new(AtomicBool::new(false)),
+                handle_refs: Arc::new(AtomicUsize::new(1)),
+                device: device,
+                tos: Arc::new(AtomicU32::new(0)),
+                ttl: Arc::new(AtomicU32::new(DEFAULT_HOP_LIMIT)),
                 nonblocking: Arc::new(AtomicBool::new(false)),
                 read_timeout: Arc::new(RwLock::new(None)),
                 write_timeout: Arc::new(RwLock::new(None)),
@@ -596,28 +2149,161 @@ impl StcpNetRef {
         }
         r
     }
+    fn bind_icmp(&self, ident: Option<u16>) -> io::Result<IcmpSocket> {
+        self.check_running()?;
+        self.check_first_queue()?;
+        let &(ref stcpnetref, ref _cond) = &*self.r;
+        let mut stcpnet = stcpnetref.lock();
+        let ident = match ident {
+            Some(ident) => {
+                if !stcpnet.iface.inspection().icmp.register(ident) {
+                    return Err(io::Error::new(
+                        io::ErrorKind::AddrInUse,
+                        format!("ICMP identifier {} is already used", ident),
+                    ));
+                }
+                ident
+            }
+            None => loop {
+                let ident: u16 = thread_rng().gen();
+                if stcpnet.iface.inspection().icmp.register(ident) {
+                    break ident;
+                }
+            },
+        };
+        if !stcpnet.icmp_match {
+            // with usnetd, echo replies and errors are only forwarded after adding a match
+            if let Err(e) = stcpnet.iface.add_port_match(
+                IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)),
+                None,
+                None,
+                None,
+                IpProtocol::Icmp,
+            ) {
+                stcpnet.iface.inspection().icmp.unregister(ident);
+                return Err(e);
+            }
+            stcpnet.icmp_match = true;
+        }
+        let icmp_rx_buffer = IcmpSocketBuffer::new(
+            vec![IcmpPacketMetadata::EMPTY; 1000],
+            vec![0; stcpnet.socket_buffer_size],
+        );
+        let icmp_tx_buffer = IcmpSocketBuffer::new(
+            vec![IcmpPacketMetadata::EMPTY; 1000],
+            vec![0; stcpnet.socket_buffer_size],
+        );
+        let icmp_socket = SmoltcpIcmpSocket::new(icmp_rx_buffer, icmp_tx_buffer);
+        let socket_handle = stcpnet.sockets.add(icmp_socket);
+        stcpnet
+            .sockets
+            .get::<SmoltcpIcmpSocket>(socket_handle)
+            .bind(IcmpEndpoint::Ident(ident))
+            .unwrap();
+        debug!(
+            "ICMP socket {} bound to identifier {}",
+            socket_handle, ident
+        );
+        Ok(IcmpSocket {
+            stcpnet: (*self).clone(),
+            socket_handle: socket_handle,
+            ident: ident,
+            sent: Mutex::new(VecDeque::new()),
+            nonblocking: AtomicBool::new(false),
+            read_timeout: RwLock::new(None),
+            ttl: AtomicU32::new(DEFAULT_HOP_LIMIT),
+        })
+    }
+    fn bind_raw(&self, protocol: u8, ipv6: bool) -> io::Result<RawSocket> {
+        rawip::check_protocol(protocol)?;
+        self.check_running()?;
+        self.check_first_queue()?;
+        let &(ref stcpnetref, ref _cond) = &*self.r;
+        let mut stcpnet = stcpnetref.lock();
+        if !stcpnet.raw_sockets {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "raw sockets are not enabled, set USNET_RAW_SOCKETS=true",
+            ));
+        }
+        if !ipv6 && !stcpnet.raw_matches.contains(&protocol) {
+            // with usnetd, packets of the protocol are only forwarded after adding a match
+            stcpnet.iface.add_port_match(
+                IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)),
+                None,
+                None,
+                None,
+                IpProtocol::from(protocol),
+            )?;
+            stcpnet.raw_matches.insert(protocol);
+        }
+        let raw_rx_buffer = RawSocketBuffer::new(
+            vec![RawPacketMetadata::EMPTY; 1000],
+            vec![0; stcpnet.socket_buffer_size],
+        );
+        let raw_tx_buffer = RawSocketBuffer::new(
+            vec![RawPacketMetadata::EMPTY; 1000],
+            vec![0; stcpnet.socket_buffer_size],
+        );
+        let raw_socket = SmoltcpRawSocket::new(
+            if ipv6 {
+                IpVersion::Ipv6
+            } else {
+                IpVersion::Ipv4
+            },
+            IpProtocol::from(protocol),
+            raw_rx_buffer,
+            raw_tx_buffer,
+        );
+        let socket_handle = stcpnet.sockets.add(raw_socket);
+        debug!(
+            "raw socket {} for IP protocol {} (IPv6: {})",
+            socket_handle, protocol, ipv6
+        );
+        Ok(RawSocket {
+            stcpnet: (*self).clone(),
+            socket_handle: socket_handle,
+            protocol: protocol,
+            ipv6: ipv6,
+            header_included: AtomicBool::new(false),
+            nonblocking: AtomicBool::new(false),
+            read_timeout: RwLock::new(None),
+            ttl: AtomicU32::new(DEFAULT_HOP_LIMIT),
+        })
+    }
 }
 
 #[derive(Debug)]
 pub struct StcpListener {
     stcpnet: StcpNetRef,
     listen_handles: Option<Vec<SocketHandle>>,
+    queue_handles: Vec<(StcpNetRef, Vec<SocketHandle>)>, // backlogs on the other queues
     lo: SystemTcpListener,
+    listen_addr: IpAddress, // unspecified for all interface addresses
     port: u16,
+    device: BoundDevice,
     ttl: Option<u8>,
     nonblocking: bool,
 }
 
 impl Drop for StcpListener {
     fn drop(&mut self) {
-        let &(ref stcpnetref, ref _cond) = &*self.stcpnet.r;
-        let mut stcpnet = stcpnetref.lock();
-        stcpnet.fds_remove.push(self.lo.as_raw_fd());
-        if let Some(ref listen_handles) = self.listen_handles {
+        {
+            let &(ref stcpnetref, ref _cond) = &*self.stcpnet.r;
+            let mut stcpnet = stcpnetref.lock();
+            stcpnet.fds_remove.push(self.lo.as_raw_fd());
+            if let Some(ref listen_handles) = self.listen_handles {
+                for listen_handle in listen_handles.iter() {
+                    debug!("drop-closing listener");
+                    stcpnet.release_tcp(*listen_handle);
+                }
+            }
+        }
+        for &(ref queue_stcpnet, ref listen_handles) in self.queue_handles.iter() {
+            let &(ref stcpnetref, ref _cond) = &*queue_stcpnet.r;
+            let mut stcpnet = stcpnetref.lock();
             for listen_handle in listen_handles.iter() {
-                debug!("drop-closing listener");
-                stcpnet.sockets.release(*listen_handle);
-                stcpnet.sockets.prune();
+                stcpnet.release_tcp(*listen_handle);
             }
         }
     }
@@ -627,11 +2313,81 @@ impl Drop for StcpListener {
 pub struct StcpStream {
     stcpnet: StcpNetRef,
     sockethandle: SocketHandle,
+    conn_id: u64,
+    handle_refs: Arc<AtomicUsize>, // clones sharing the socket, removed with the last one
+    buffers: Arc<StreamBuffers>,
     nonblocking: Arc<AtomicBool>,
     read_timeout: Arc<RwLock<Option<Duration>>>,
     write_timeout: Arc<RwLock<Option<Duration>>>,
 }
 
+/// The application side of a TCP connection on the stack
+/// Reads and writes only copy out of and into the rings under the lock of the connection,
+/// so that the threads of different connections do not wait for each other. The background
+/// thread moves the data between the rings and the smoltcp socket under the lock of the
+/// stack after each poll.
+#[derive(Debug)]
+struct StreamBuffers {
+    rings: Mutex<StreamRings>,
+    cond: Condvar, // notified when the background thread moved data or the state changed
+    notifier: Arc<PollNotifier>,
+}
+
+#[derive(Debug)]
+struct StreamRings {
+    rx: TcpSocketBuffer<'static>,
+    tx: TcpSocketBuffer<'static>,
+    may_recv: bool, // as for the smoltcp socket, updated by the background thread
+    may_send: bool,
+    reset: bool,      // the local address was removed
+    state: TcpState,  // of the smoltcp socket to tell a reset from a regular close
+    peer_reset: bool, // a RST was received
+    shut_down: bool,  // the stack was shut down
+    close: bool,      // the socket is closed for writing as soon as the send ring is empty
+    dropped: bool,    // the socket is released as soon as the send ring is empty
+    congestion: Window,
+    // retransmissions counted by the device layer while there is congestion control
+    tracked: Option<((IpAddr, u16, u16), Arc<AtomicU64>)>,
+}
+
+/// Starts or stops counting the retransmissions of the connection for its congestion control
+fn track_retransmits(connections: &Connections, rings: &mut StreamRings, socket: &TcpSocket) {
+    if rings.congestion.algorithm() == Congestion::None {
+        if let Some((key, _)) = rings.tracked.take() {
+            connections.untrack(key.0, key.1, key.2);
+        }
+    } else if rings.tracked.is_none() && socket.remote_endpoint().is_specified() {
+        let remote = endpoint_to_socket_addr(&socket.remote_endpoint());
+        let key = (remote.ip(), socket.local_endpoint().port, remote.port());
+        rings.tracked = Some((key, connections.track(key.0, key.1, key.2)));
+    }
+}
+
+/// Wakes up the background thread without the lock of the stack, the notifications until
+/// the next poll are combined into one
+#[derive(Debug)]
+struct PollNotifier {
+    socket: UnixDatagram,
+    pending: AtomicBool,
+}
+
+impl PollNotifier {
+    fn notify(&self) {
+        if !self.pending.swap(true, Ordering::SeqCst) {
+            let _ = self.socket.send(b"$");
+        }
+    }
+}
+
+/// Why a stream cannot read or write yet
+enum NotReady {
+    Reset,
+    PeerReset,
+    ShutDown,
+    Closed, // end of the data or no more sending
+    WouldBlock,
+}
+
 pub struct StcpListenerIt {
     listener: StcpListenerRef,
 }
@@ -684,6 +2440,14 @@ impl StcpListenerRef {
                 socket.set_hop_limit(listener.ttl);
             }
         }
+        for &(ref queue_stcpnet, ref handles) in listener.queue_handles.iter() {
+            let &(ref stcpnetref, ref _cond) = &*queue_stcpnet.r;
+            let mut stcpnet = stcpnetref.lock();
+            for handle in handles.iter() {
+                let mut socket = stcpnet.sockets.get::<TcpSocket>(*handle);
+                socket.set_hop_limit(listener.ttl);
+            }
+        }
         listener.lo.set_ttl(ttl)
     }
     pub fn take_error(&self) -> io::Result<Option<io::Error>> {
@@ -703,7 +2467,8 @@ impl StcpListenerRef {
         Ok(StcpListenerRef { l: self.l.clone() })
     }
     pub fn accept(&self) -> io::Result<(TcpStream, SocketAddr)> {
-        let mut listener = self.l.lock();
+        let mut guard = self.l.lock();
+        let listener = &mut *guard;
         if listener.listen_handles.is_none() {
             return listener.lo.accept().map(|(s, a)| {
                 s.set_nonblocking(listener.nonblocking)
@@ -711,61 +2476,57 @@ impl StcpListenerRef {
                 (TcpStream::System(s), a)
             });
         }
+        // a backlog on every queue is checked after a poll of any of their stacks
+        let queue_group = if listener.queue_handles.is_empty() {
+            None
+        } else {
+            listener.stcpnet.queue_group()
+        };
         loop {
-            let mut r = None;
+            let polls = queue_group.as_ref().map(|group| group.polls());
             {
                 let &(ref stcpnetref, ref cond) = &*listener.stcpnet.r;
                 let mut stcpnet = stcpnetref.lock();
+                stcpnet.check_running()?;
 
-                if !listener.nonblocking {
+                if !listener.nonblocking && queue_group.is_none() {
                     cond.wait(&mut stcpnet);
                 }
 
                 let sys_res = listener.lo.accept();
                 if let Ok((s, a)) = sys_res {
+                    if listener.device == BoundDevice::Nic {
+                        // closed as the connection did not arrive on the NIC
+                        continue;
+                    }
                     s.set_nonblocking(listener.nonblocking)
                         .expect("couldn't set nonblocking option");
                     return Ok((TcpStream::System(s), a));
                 }
-
-                for (i, handle) in listener.listen_handles.as_ref().unwrap().iter().enumerate() {
-                    let socket = stcpnet.sockets.get::<TcpSocket>(*handle);
-                    if socket.is_active() && socket.may_recv() && socket.may_send() {
-                        let ep = socket.remote_endpoint();
-                        let sadd = endpoint_to_socket_addr(&ep);
-                        r = Some((
-                            i,
-                            Ok((
-                                TcpStream::Stcp(StcpStream {
-                                    stcpnet: listener.stcpnet.clone(),
-                                    sockethandle: *handle,
-                                    nonblocking: Arc::new(AtomicBool::new(listener.nonblocking)),
-                                    read_timeout: Arc::new(RwLock::new(None)),
-                                    write_timeout: Arc::new(RwLock::new(None)),
-                                }),
-                                sadd,
-                            )),
-                        ));
-                        break;
-                    }
-                }
             }
-            match r {
-                Some((i, r)) => {
-                    listener.listen_handles.as_mut().unwrap().remove(i);
-                    let tcp_handle;
-                    {
-                        let &(ref stcpnetref, ref _cond) = &*listener.stcpnet.r;
-                        let mut stcpnet = stcpnetref.lock();
-                        tcp_handle = stcpnet.create_socket();
-                        let mut socket = stcpnet.sockets.get::<TcpSocket>(tcp_handle);
-                        socket.set_hop_limit(listener.ttl);
-                        socket.listen(listener.port).unwrap();
-                    }
-                    listener.listen_handles.as_mut().unwrap().push(tcp_handle);
-                    return r;
+            if let Some(r) = accept_established(
+                &listener.stcpnet,
+                listener.listen_handles.as_mut().unwrap(),
+                listener.listen_addr,
+                listener.port,
+                listener.device,
+                listener.ttl,
+                listener.nonblocking,
+            ) {
+                return Ok(r);
+            }
+            for &mut (ref stcpnet, ref mut handles) in listener.queue_handles.iter_mut() {
+                if let Some(r) = accept_established(
+                    stcpnet,
+                    handles,
+                    listener.listen_addr,
+                    listener.port,
+                    listener.device,
+                    listener.ttl,
+                    listener.nonblocking,
+                ) {
+                    return Ok(r);
                 }
-                _ => {}
             }
             if listener.nonblocking {
                 return Err(io::Error::new(
@@ -773,10 +2534,75 @@ impl StcpListenerRef {
                     "no active connection found",
                 ));
             }
+            if let (Some(group), Some(polls)) = (queue_group.as_ref(), polls) {
+                group.wait_for_poll(polls);
+            }
         }
     }
 }
 
+/// Takes an established connection out of the backlog of a listener on the stack and puts a
+/// new listening socket in its place
+/// A listener bound to the NIC resets the connections through the loopback of the stack.
+fn accept_established(
+    stcpnetref: &StcpNetRef,
+    handles: &mut Vec<SocketHandle>,
+    listen_addr: IpAddress,
+    port: u16,
+    device: BoundDevice,
+    ttl: Option<u8>,
+    nonblocking: bool,
+) -> Option<(TcpStream, SocketAddr)> {
+    let &(ref stcpnet_mutex, ref _cond) = &*stcpnetref.r;
+    let mut stcpnet = stcpnet_mutex.lock();
+    let (handle, sadd) = loop {
+        let i = handles.iter().position(|handle| {
+            let socket = stcpnet.sockets.get::<TcpSocket>(*handle);
+            socket.is_active() && socket.may_recv() && socket.may_send()
+        })?;
+        let handle = handles.remove(i);
+        let sadd = {
+            let socket = stcpnet.sockets.get::<TcpSocket>(handle);
+            endpoint_to_socket_addr(&socket.remote_endpoint())
+        };
+        let tcp_handle = stcpnet.create_socket();
+        {
+            let mut socket = stcpnet.sockets.get::<TcpSocket>(tcp_handle);
+            socket.set_hop_limit(ttl);
+            socket.listen(IpEndpoint::new(listen_addr, port)).unwrap();
+        }
+        handles.push(tcp_handle);
+        if device == BoundDevice::Nic && from_loopback(sadd.ip(), stcpnet.loopback_ips()) {
+            debug!("resetting connection from {} through the loopback", sadd);
+            stcpnet.sockets.get::<TcpSocket>(handle).abort();
+            stcpnet.release_tcp(handle);
+            continue;
+        }
+        break (handle, sadd);
+    };
+    let conn_id = stcpnet.track_connection(handle);
+    stack_log!(
+        debug,
+        "[conn {}] accepted from {} on port {}",
+        conn_id,
+        sadd,
+        port
+    );
+    Some((
+        TcpStream::Stcp(StcpStream {
+            stcpnet: stcpnetref.clone(),
+            sockethandle: handle,
+            conn_id: conn_id,
+            handle_refs: Arc::new(AtomicUsize::new(1)),
+            buffers: stcpnet.register_stream(handle),
+            nonblocking: Arc::new(AtomicBool::new(nonblocking)),
+            read_timeout: Arc::new(RwLock::new(None)),
+            write_timeout: Arc::new(RwLock::new(None)),
+        }),
+        sadd,
+    ))
+}
+
 pub enum TcpStream {
     System(SystemTcpStream),
     Stcp(StcpStream),
@@ -905,48 +2731,58 @@ impl TcpStream {
         }
     }
 
-    pub fn write_no_copy<F, R>(&mut self, f: F) -> smoltcp::Result<R>
-    // returns smoltcp err Illegal instead of io err ConnectionReset
-    where
-        F: FnOnce(&mut [u8]) -> (usize, R),
+    /// Selects the congestion control of a connection on the stack, new connections get
+    /// the one of set_default_congestion
+    pub fn set_congestion(&self, congestion: Congestion) -> io::Result<()> {
+        match self {
+            TcpStream::Stcp(slf) => slf.set_congestion(congestion),
+            TcpStream::System(_) => Err(io::Error::new(
+                io::ErrorKind::Other,
+                "the congestion control of kernel connections is configured in the kernel",
+            )),
+        }
+    }
+    pub fn congestion(&self) -> io::Result<Congestion> {
+        self.stats().map(|stats| stats.congestion)
+    }
+    /// Replaces the initial congestion window in segments of the stack configuration for
+    /// this connection, which is only possible before the first data is written
+    pub fn set_initial_window(&self, segments: usize) -> io::Result<()> {
+        match self {
+            TcpStream::Stcp(slf) => slf.set_initial_window(segments),
+            TcpStream::System(_) => Err(io::Error::new(
+                io::ErrorKind::Other,
+                "the initial window of kernel connections is configured in the kernel",
+            )),
+        }
+    }
+    /// Returns the congestion state of a connection on the stack
+    pub fn stats(&self) -> io::Result<TcpStats> {
+        match self {
+            TcpStream::Stcp(slf) => slf.stats(),
+            TcpStream::System(_) => Err(io::Error::new(
+                io::ErrorKind::Other,
+                "no statistics for kernel connections",
+            )),
+        }
+    }
+
+    pub fn write_no_copy<F, R>(&mut self, f: F) -> smoltcp::Result<R>
+    // returns smoltcp err Illegal instead of io err ConnectionReset
+    where
+        F: FnOnce(&mut [u8]) -> (usize, R),
     {
         match self {
             TcpStream::Stcp(slf) => {
-                let start = StdInstant::now();
-                let &(ref stcpnetref, ref cond) = &*slf.stcpnet.r;
-                let mut stcpnet = stcpnetref.lock();
-
-                while {
-                    let socket = stcpnet.sockets.get::<TcpSocket>(slf.sockethandle);
-                    if !socket.may_send() {
-                        return Err(smoltcp::Error::Illegal);
-                    }
-                    !socket.can_send()
-                } {
-                    if slf.nonblocking.load(Ordering::SeqCst) {
-                        return Err(smoltcp::Error::Exhausted);
-                    }
-                    match *slf.write_timeout.read() {
-                        Some(timeout) => {
-                            if cond.wait_until(&mut stcpnet, start + timeout).timed_out() {
-                                return Err(smoltcp::Error::Exhausted);
-                            }
-                        }
-                        None => {
-                            cond.wait(&mut stcpnet);
-                        }
-                    }
-                }
-                let r;
-                {
-                    let mut socket = stcpnet.sockets.get::<TcpSocket>(slf.sockethandle);
-                    r = socket.send(f);
-                }
-                if stcpnet.bg_skip_one_wait == Skip::Wait {
-                    let _ = stcpnet.notify_poll.send(b"$").unwrap();
-                } else {
-                    stcpnet.bg_skip_one_wait = Skip::Skip;
-                }
+                let r = match slf.wait_rings(true) {
+                    Ok(mut rings) => Ok(rings.tx.enqueue_many_with(f).1),
+                    Err(NotReady::Reset)
+                    | Err(NotReady::PeerReset)
+                    | Err(NotReady::ShutDown)
+                    | Err(NotReady::Closed) => Err(smoltcp::Error::Illegal),
+                    Err(NotReady::WouldBlock) => Err(smoltcp::Error::Exhausted),
+                };
+                slf.buffers.notifier.notify();
                 r
             }
             TcpStream::System(slf) => {
@@ -964,45 +2800,22 @@ impl TcpStream {
         F: FnOnce(&mut [u8]) -> (usize, R),
     {
         match self {
-            TcpStream::Stcp(slf) => {
-                let start = StdInstant::now();
-                let &(ref stcpnetref, ref cond) = &*slf.stcpnet.r;
-                let mut stcpnet = stcpnetref.lock();
-                while {
-                    let socket = stcpnet.sockets.get::<TcpSocket>(slf.sockethandle);
-                    if !socket.may_recv() {
-                        let (_, rt) = f(&mut []);
-                        return Ok(rt);
-                    }
-                    !socket.can_recv()
-                } {
-                    if slf.nonblocking.load(Ordering::SeqCst) {
-                        return Err(smoltcp::Error::Exhausted);
-                    }
-                    match *slf.read_timeout.read() {
-                        Some(timeout) => {
-                            if cond.wait_until(&mut stcpnet, start + timeout).timed_out() {
-                                return Err(smoltcp::Error::Exhausted);
-                            }
-                        }
-                        None => {
-                            cond.wait(&mut stcpnet);
-                        }
+            TcpStream::Stcp(slf) => match slf.wait_rings(false) {
+                Ok(mut rings) => {
+                    let was_full = rings.rx.is_full();
+                    let (_, r) = rings.rx.dequeue_many_with(f);
+                    drop(rings);
+                    if was_full {
+                        slf.buffers.notifier.notify(); // the socket may hold more data
                     }
+                    Ok(r)
                 }
-                let r;
-                {
-                    let mut socket = stcpnet.sockets.get::<TcpSocket>(slf.sockethandle);
-                    r = socket.recv(f);
-                }
-                if stcpnet.bg_skip_one_wait == Skip::Wait
-                    && (stcpnet.current_wait_delay == -1 as c_int
-                        || stcpnet.current_wait_delay > 10 as c_int)
-                {
-                    let _ = stcpnet.notify_poll.send(b"$").unwrap();
+                Err(NotReady::Closed) => Ok(f(&mut []).1),
+                Err(NotReady::Reset) | Err(NotReady::PeerReset) | Err(NotReady::ShutDown) => {
+                    Err(smoltcp::Error::Illegal)
                 }
-                r
-            }
+                Err(NotReady::WouldBlock) => Err(smoltcp::Error::Exhausted),
+            },
             TcpStream::System(slf) => {
                 let mut g = vec![0; 8];
                 match slf.peek(&mut g[..]) {
@@ -1019,136 +2832,318 @@ impl TcpStream {
             }
         }
     }
+    /// Writes up to `len` bytes of the file from the offset on and returns how many were
+    /// written, less if the file ends before
+    /// For a stream on a stack, the file is read in large chunks straight into the send
+    /// ring. A non-blocking stream writes what fits and returns a WouldBlock error only if
+    /// nothing did.
+    pub fn write_file(&mut self, file: &File, offset: u64, len: u64) -> io::Result<u64> {
+        let mut buf = vec![];
+        let mut written = 0;
+        while written < len {
+            let limit = (len - written).min(COPY_CHUNK as u64) as usize;
+            let r = match self {
+                TcpStream::Stcp(slf) => slf.write_file_chunk(file, offset + written, limit),
+                TcpStream::System(slf) => {
+                    buf.resize(COPY_CHUNK, 0);
+                    match file.read_at(&mut buf[..limit], offset + written) {
+                        Ok(0) => Ok(0),
+                        Ok(n) => write_some(slf, &buf[..n]),
+                        Err(e) => Err(e),
+                    }
+                }
+            };
+            match r {
+                Ok(0) => break,
+                Ok(n) => written += n as u64,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock && written > 0 => break,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(written)
+    }
+}
+
+/// Copies the data of a stream to another one until the end of its data or up to `limit`
+/// bytes, and returns how many bytes were copied
+/// If both streams are on a stack, the data is moved from the receive ring of the source
+/// to the send ring of the destination, without the lock of the stack or a buffer of the
+/// application. Otherwise, it goes through a buffer. Only the data which the destination
+/// took is consumed from the source. A non-blocking stream makes the copy return what it
+/// copied so far when it cannot proceed, or a WouldBlock error if there was nothing.
+/// The end of the data is not passed on, the destination can be closed with
+/// `shutdown(Shutdown::Write)` afterwards.
+pub fn copy(src: &mut TcpStream, dst: &mut TcpStream, limit: Option<u64>) -> io::Result<u64> {
+    let limit = limit.unwrap_or(u64::max_value());
+    let mut buf = vec![];
+    let mut copied = 0;
+    while copied < limit {
+        let remaining = (limit - copied).min(usize::max_value() as u64) as usize;
+        let r = match (&*src, &*dst) {
+            (TcpStream::Stcp(from), TcpStream::Stcp(to)) => from.move_to(to, remaining),
+            _ => copy_chunk(src, dst, &mut buf, remaining),
+        };
+        match r {
+            Ok(0) => break,
+            Ok(n) => copied += n as u64,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock && copied > 0 => break,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(copied)
+}
+
+/// Copies data which the source has through the buffer and consumes as much of it as
+/// the destination took
+fn copy_chunk(
+    src: &mut TcpStream,
+    dst: &mut TcpStream,
+    buf: &mut Vec<u8>,
+    limit: usize,
+) -> io::Result<usize> {
+    buf.resize(COPY_CHUNK, 0);
+    let n = src.peek(&mut buf[..limit.min(COPY_CHUNK)])?;
+    if n == 0 {
+        return Ok(0);
+    }
+    let written = write_some(dst, &buf[..n])?;
+    src.read_exact(&mut buf[..written])?;
+    Ok(written)
+}
+
+/// Writes once, with an error instead of no progress
+fn write_some<W: Write>(dst: &mut W, buf: &[u8]) -> io::Result<usize> {
+    match dst.write(buf)? {
+        0 => Err(io::Error::new(
+            io::ErrorKind::WriteZero,
+            "failed to write the data",
+        )),
+        n => Ok(n),
+    }
 }
 
 impl Drop for StcpStream {
     fn drop(&mut self) {
         let &(ref stcpnetref, ref _cond) = &*self.stcpnet.r;
         let mut stcpnet = stcpnetref.lock();
-        debug!("drop-closing");
-        stcpnet.sockets.release(self.sockethandle);
-        stcpnet.sockets.prune();
+        if self.handle_refs.fetch_sub(1, Ordering::SeqCst) > 1 {
+            return;
+        }
+        debug!("[conn {}] drop-closing", self.conn_id);
+        stcpnet.conn_states.remove(&self.sockethandle);
+        stcpnet.address_removed.remove(&self.sockethandle);
+        // released by the background thread when the send ring is empty
+        let mut rings = self.buffers.rings.lock();
+        rings.close = true;
+        rings.dropped = true;
+        self.buffers.notifier.notify();
     }
 }
 
 impl StcpStream {
-    pub fn peek(&self, buf: &mut [u8]) -> io::Result<usize> {
-        let start = StdInstant::now();
-        let &(ref stcpnetref, ref cond) = &*self.stcpnet.r;
-        let mut stcpnet = stcpnetref.lock();
-        while {
-            let socket = stcpnet.sockets.get::<TcpSocket>(self.sockethandle);
-            if !socket.may_recv() {
-                return Ok(0);
+    /// Locks the rings and waits until there is data to receive or space to send
+    fn wait_rings(&self, send: bool) -> Result<MutexGuard<StreamRings>, NotReady> {
+        let timeout = if send {
+            *self.write_timeout.read()
+        } else {
+            *self.read_timeout.read()
+        };
+        let deadline = timeout.map(|timeout| StdInstant::now() + timeout);
+        let cond = &self.buffers.cond;
+        let mut rings = self.buffers.rings.lock();
+        loop {
+            if rings.shut_down {
+                return Err(NotReady::ShutDown);
             }
-            !socket.can_recv()
-        } {
-            if self.nonblocking.load(Ordering::SeqCst) {
-                return Err(io::Error::new(io::ErrorKind::WouldBlock, "peek not ready"));
+            if rings.reset {
+                return Err(NotReady::Reset);
             }
-            match *self.read_timeout.read() {
-                Some(timeout) => {
-                    if cond.wait_until(&mut stcpnet, start + timeout).timed_out() {
-                        return Err(io::Error::new(io::ErrorKind::WouldBlock, "peek not ready"));
-                    }
+            // the received data before the RST can still be read
+            if rings.peer_reset && (send || rings.rx.is_empty()) {
+                return Err(NotReady::PeerReset);
+            }
+            if send {
+                if !rings.may_send || rings.close {
+                    return Err(NotReady::Closed);
                 }
-                None => {
-                    cond.wait(&mut stcpnet);
+                if !rings.tx.is_full() {
+                    return Ok(rings);
+                }
+            } else {
+                if !rings.rx.is_empty() {
+                    return Ok(rings);
+                }
+                if !rings.may_recv {
+                    return Err(NotReady::Closed);
                 }
             }
-        }
-
-        {
-            let mut socket = stcpnet.sockets.get::<TcpSocket>(self.sockethandle);
-            socket
-                .peek_slice(buf)
-                .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))
-        }
-    }
-    pub fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        let start = StdInstant::now();
-        let &(ref stcpnetref, ref cond) = &*self.stcpnet.r;
-        let mut stcpnet = stcpnetref.lock();
-        while {
-            let socket = stcpnet.sockets.get::<TcpSocket>(self.sockethandle);
-            if !socket.may_recv() {
-                return Ok(0);
-            }
-            !socket.can_recv()
-        } {
             if self.nonblocking.load(Ordering::SeqCst) {
-                return Err(io::Error::new(io::ErrorKind::WouldBlock, "read not ready"));
+                return Err(NotReady::WouldBlock);
             }
-            match *self.read_timeout.read() {
-                Some(timeout) => {
-                    if cond.wait_until(&mut stcpnet, start + timeout).timed_out() {
-                        return Err(io::Error::new(io::ErrorKind::WouldBlock, "read not ready"));
+            match deadline {
+                Some(deadline) => {
+                    if cond.wait_until(&mut rings, deadline).timed_out() {
+                        return Err(NotReady::WouldBlock);
                     }
                 }
                 None => {
-                    cond.wait(&mut stcpnet);
+                    cond.wait(&mut rings);
                 }
             }
         }
-
-        let r;
-        {
-            let mut socket = stcpnet.sockets.get::<TcpSocket>(self.sockethandle);
-            r = socket
-                .recv_slice(buf)
-                .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()));
-        }
-        if stcpnet.bg_skip_one_wait == Skip::Wait
-            && (stcpnet.current_wait_delay == -1 as c_int
-                || stcpnet.current_wait_delay > 10 as c_int)
-        {
-            let _ = stcpnet.notify_poll.send(b"$").unwrap();
+    }
+    fn not_ready_error(not_ready: NotReady, operation: &str) -> io::Error {
+        match not_ready {
+            NotReady::Reset => io::Error::new(
+                io::ErrorKind::ConnectionReset,
+                "connection reset because the local address was removed",
+            ),
+            NotReady::PeerReset => io::Error::from_raw_os_error(libc::ECONNRESET),
+            NotReady::ShutDown => shut_down_error(),
+            NotReady::Closed => {
+                io::Error::new(io::ErrorKind::NotConnected, "not connected (anymore)")
+            }
+            NotReady::WouldBlock => io::Error::new(
+                io::ErrorKind::WouldBlock,
+                format!("{} not ready", operation),
+            ),
+        }
+    }
+    pub fn peek(&self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.wait_rings(false) {
+            Ok(mut rings) => Ok(rings.rx.read_allocated(0, buf)),
+            Err(NotReady::Closed) => Ok(0),
+            Err(e) => Err(StcpStream::not_ready_error(e, "peek")),
         }
+    }
+    pub fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let r = match self.wait_rings(false) {
+            Ok(mut rings) => {
+                let was_full = rings.rx.is_full();
+                let n = rings.rx.dequeue_slice(buf);
+                drop(rings);
+                if was_full {
+                    self.buffers.notifier.notify(); // the socket may hold more data
+                }
+                Ok(n)
+            }
+            Err(NotReady::Closed) => Ok(0),
+            Err(e) => Err(StcpStream::not_ready_error(e, "read")),
+        };
+        stack_log!(trace, "[conn {}] read: {:?}", self.conn_id, r);
         r
     }
 
     pub fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        let start = StdInstant::now();
-        let &(ref stcpnetref, ref cond) = &*self.stcpnet.r;
-        let mut stcpnet = stcpnetref.lock();
-        while {
-            let socket = stcpnet.sockets.get::<TcpSocket>(self.sockethandle);
-            if !socket.may_send() {
-                return Err(io::Error::new(
-                    io::ErrorKind::Other,
-                    "not connected (anymore)",
-                ));
-            }
-            !socket.can_send()
-        } {
-            if self.nonblocking.load(Ordering::SeqCst) {
-                return Err(io::Error::new(io::ErrorKind::WouldBlock, "write not ready"));
-            }
-            match *self.write_timeout.read() {
-                Some(timeout) => {
-                    if cond.wait_until(&mut stcpnet, start + timeout).timed_out() {
-                        return Err(io::Error::new(io::ErrorKind::WouldBlock, "write not ready"));
-                    }
+        let r = match self.wait_rings(true) {
+            Ok(mut rings) => Ok(rings.tx.enqueue_slice(buf)),
+            Err(e) => Err(StcpStream::not_ready_error(e, "write")),
+        };
+        stack_log!(trace, "[conn {}] write: {:?}", self.conn_id, r);
+        if r.is_ok() {
+            self.buffers.notifier.notify();
+        }
+        r
+    }
+    /// Moves received data into the send ring of the other stream, waits until there is
+    /// both data and space, returns 0 at the end of the data
+    fn move_to(&self, dst: &StcpStream, limit: usize) -> io::Result<usize> {
+        loop {
+            // waits for space first, so that the received data is not held back
+            drop(
+                dst.wait_rings(true)
+                    .map_err(|e| StcpStream::not_ready_error(e, "write"))?,
+            );
+            let mut from = match self.wait_rings(false) {
+                Ok(rings) => rings,
+                Err(NotReady::Closed) => return Ok(0),
+                Err(e) => return Err(StcpStream::not_ready_error(e, "read")),
+            };
+            let was_full = from.rx.is_full();
+            let moved = if Arc::ptr_eq(&self.buffers, &dst.buffers) {
+                // clones of one stream, e.g., an echo
+                let rings = &mut *from;
+                if !rings.may_send || rings.close {
+                    return Err(StcpStream::not_ready_error(NotReady::Closed, "write"));
                 }
-                None => {
-                    cond.wait(&mut stcpnet);
+                move_data(&mut rings.rx, &mut rings.tx, limit)
+            } else {
+                // locked in the order of their addresses, so that copies in the other
+                // direction do not deadlock
+                let first: *const StreamBuffers = &*self.buffers;
+                let mut to = if first < &*dst.buffers as *const StreamBuffers {
+                    dst.buffers.rings.lock()
+                } else {
+                    drop(from);
+                    let to = dst.buffers.rings.lock();
+                    from = self.buffers.rings.lock();
+                    to
+                };
+                if to.shut_down {
+                    return Err(StcpStream::not_ready_error(NotReady::ShutDown, "write"));
+                }
+                if to.reset {
+                    return Err(StcpStream::not_ready_error(NotReady::Reset, "write"));
+                }
+                if to.peer_reset {
+                    return Err(StcpStream::not_ready_error(NotReady::PeerReset, "write"));
+                }
+                if !to.may_send || to.close {
+                    return Err(StcpStream::not_ready_error(NotReady::Closed, "write"));
+                }
+                move_data(&mut from.rx, &mut to.tx, limit)
+            };
+            drop(from);
+            stack_log!(
+                trace,
+                "[conn {}] copy to [conn {}]: {}",
+                self.conn_id,
+                dst.conn_id,
+                moved
+            );
+            if moved > 0 {
+                if was_full {
+                    self.buffers.notifier.notify(); // the socket may hold more data
                 }
+                dst.buffers.notifier.notify();
+                return Ok(moved);
             }
+            // another clone took the data or the space
         }
-
-        let r;
-        {
-            let mut socket = stcpnet.sockets.get::<TcpSocket>(self.sockethandle);
-            r = socket
-                .send_slice(buf)
-                .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()));
+    }
+    /// Reads a chunk of the file into the send ring after waiting for space
+    /// The ring stays locked during the read, which is short for files in the page cache.
+    fn write_file_chunk(&self, file: &File, offset: u64, limit: usize) -> io::Result<usize> {
+        let mut rings = self
+            .wait_rings(true)
+            .map_err(|e| StcpStream::not_ready_error(e, "write"))?;
+        let mut written = 0;
+        // twice if the free space wraps around the end of the ring
+        while written < limit && !rings.tx.is_full() {
+            let max = limit - written;
+            let position = offset + written as u64;
+            let (_, r) = rings.tx.enqueue_many_with(|space| {
+                let len = space.len().min(max);
+                match file.read_at(&mut space[..len], position) {
+                    Ok(n) => (n, Ok(n)),
+                    Err(e) => (0, Err(e)),
+                }
+            });
+            match r {
+                Ok(0) => break,
+                Ok(n) => written += n,
+                Err(_) if written > 0 => break,
+                Err(e) => return Err(e),
+            }
         }
-        if stcpnet.bg_skip_one_wait == Skip::Wait {
-            let _ = stcpnet.notify_poll.send(b"$").unwrap();
-        } else {
-            stcpnet.bg_skip_one_wait = Skip::Skip;
+        drop(rings);
+        stack_log!(trace, "[conn {}] write_file: {}", self.conn_id, written);
+        if written > 0 {
+            self.buffers.notifier.notify();
         }
-        r
+        Ok(written)
     }
     pub fn set_read_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
         *self.read_timeout.write() = dur;
@@ -1177,6 +3172,54 @@ impl StcpStream {
     pub fn nodelay(&self) -> io::Result<bool> {
         Ok(true)
     }
+    pub fn set_congestion(&self, congestion: Congestion) -> io::Result<()> {
+        stack_log!(
+            debug,
+            "[conn {}] congestion control {:?}",
+            self.conn_id,
+            congestion
+        );
+        self.buffers
+            .rings
+            .lock()
+            .congestion
+            .set_algorithm(congestion);
+        self.buffers.notifier.notify(); // the window may allow more data
+        Ok(())
+    }
+    pub fn set_initial_window(&self, segments: usize) -> io::Result<()> {
+        if segments == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "initial window must not be 0",
+            ));
+        }
+        if !self
+            .buffers
+            .rings
+            .lock()
+            .congestion
+            .set_initial_window(segments)
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "initial window cannot be changed after data was sent",
+            ));
+        }
+        Ok(())
+    }
+    pub fn stats(&self) -> io::Result<TcpStats> {
+        let rings = self.buffers.rings.lock();
+        let window = &rings.congestion;
+        Ok(TcpStats {
+            congestion: window.algorithm(),
+            cwnd: window.cwnd(),
+            ssthresh: window.ssthresh(),
+            srtt: window.srtt(),
+            in_flight: window.in_flight(),
+            losses: window.losses(),
+        })
+    }
     pub fn flush(&mut self) -> io::Result<()> {
         Ok(()) // because of nodelay
     }
@@ -1219,31 +3262,39 @@ impl StcpStream {
         }
     }
     pub fn shutdown(&self, how: Shutdown) -> io::Result<()> {
-        let &(ref stcpnetref, ref _cond) = &*self.stcpnet.r;
-        let mut stcpnet = stcpnetref.lock();
-        let mut socket = stcpnet.sockets.get::<TcpSocket>(self.sockethandle);
+        stack_log!(debug, "[conn {}] shutdown {:?}", self.conn_id, how);
         match how {
             Shutdown::Read => {
                 error!("TcpStream.shutdown(Shutdown::Read) not implemented");
                 Ok(())
             }
             Shutdown::Write => {
-                socket.close();
+                // closed by the background thread after the send ring
+                self.buffers.rings.lock().close = true;
+                self.buffers.notifier.notify();
                 Ok(())
             }
             Shutdown::Both => {
-                socket.abort();
+                let &(ref stcpnetref, ref _cond) = &*self.stcpnet.r;
+                let mut stcpnet = stcpnetref.lock();
+                stcpnet.sockets.get::<TcpSocket>(self.sockethandle).abort();
+                let mut rings = self.buffers.rings.lock();
+                rings.state = TcpState::Closed; // not reported as reset by the peer
+                rings.tx.clear();
+                rings.close = true;
+                self.buffers.notifier.notify();
                 Ok(())
             }
         }
     }
     pub fn try_clone(&self) -> io::Result<StcpStream> {
-        let &(ref stcpnetref, ref _cond) = &*self.stcpnet.r;
-        let mut stcpnet = stcpnetref.lock();
-        stcpnet.sockets.retain(self.sockethandle); // cloning needs to increase the ref count
+        let _ = self.handle_refs.fetch_add(1, Ordering::SeqCst);
         Ok(StcpStream {
             stcpnet: self.stcpnet.clone(),
             sockethandle: self.sockethandle.clone(),
+            conn_id: self.conn_id,
+            handle_refs: self.handle_refs.clone(),
+            buffers: self.buffers.clone(),
             nonblocking: self.nonblocking.clone(),
             read_timeout: self.read_timeout.clone(),
             write_timeout: self.write_timeout.clone(),
@@ -1251,7 +3302,45 @@ impl StcpStream {
     }
 }
 
-fn endpoint_to_socket_addr(ep: &IpEndpoint) -> SocketAddr {
+/// Moves up to `limit` bytes from a receive ring into a send ring
+fn move_data(
+    rx: &mut TcpSocketBuffer<'static>,
+    tx: &mut TcpSocketBuffer<'static>,
+    limit: usize,
+) -> usize {
+    let mut moved = 0;
+    // the received data may wrap around the end of the ring
+    while moved < limit && !rx.is_empty() && !tx.is_full() {
+        let max = limit - moved;
+        let (n, _) = rx.dequeue_many_with(|data| {
+            let n = tx.enqueue_slice(&data[..data.len().min(max)]);
+            (n, ())
+        });
+        if n == 0 {
+            break;
+        }
+        moved += n;
+    }
+    moved
+}
+
+fn shut_down_error() -> io::Error {
+    io::Error::new(io::ErrorKind::NotConnected, "the stack was shut down")
+}
+
+/// Maps the errors of the smoltcp sockets to the closest kind, e.g., WouldBlock for a full
+/// send buffer
+pub(crate) fn stack_error(e: smoltcp::Error) -> io::Error {
+    let kind = match e {
+        smoltcp::Error::Exhausted => io::ErrorKind::WouldBlock,
+        smoltcp::Error::Truncated => io::ErrorKind::InvalidInput,
+        smoltcp::Error::Unaddressable => io::ErrorKind::AddrNotAvailable,
+        _ => io::ErrorKind::Other,
+    };
+    io::Error::new(kind, e.to_string())
+}
+
+pub(crate) fn endpoint_to_socket_addr(ep: &IpEndpoint) -> SocketAddr {
     let stip = match ep.addr {
         IpAddress::Ipv4(v) => IpAddr::V4(Ipv4Addr::from(v)),
         IpAddress::Ipv6(v) => IpAddr::V6(Ipv6Addr::from(v)),
@@ -1262,26 +3351,316 @@ fn endpoint_to_socket_addr(ep: &IpEndpoint) -> SocketAddr {
 }
 
 // UDP
+/// Options of a UdpSocket that have to be set before it is bound, see UdpSocket::bind_with
+/// The options apply to the socket of the kernel loopback, so that sockets in several
+/// processes can share the port as with kernel sockets. Within the stack, the datagrams for
+/// the port go to the first of its sockets that is bound to it, while the kernel spreads
+/// the ones over the loopback. SO_REUSEPORT is available on Linux 3.9 and newer.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct UdpBindOptions {
+    /// SO_REUSEADDR, allows binding a port that another socket with this option uses
+    pub reuse_address: bool,
+    /// SO_REUSEPORT, allows binding a port that other sockets of the same user with this
+    /// option use, the kernel then spreads the datagrams over the sockets
+    pub reuse_port: bool,
+    /// Size of the receive and send buffers of the socket on the stack, None for the
+    /// socket buffer size of the stack configuration
+    pub buffer_size: Option<usize>,
+    /// IPV6_V6ONLY, a socket bound to [::] then does not get IPv4 traffic
+    /// Without it, the socket is dual-stack as with the kernel. Since the TCP and UDP sockets
+    /// of the stack only support IPv4, [::] is bound as 0.0.0.0 and with this option as ::1,
+    /// i.e., only on the kernel loopback. The same applies to TCP listeners.
+    pub only_v6: bool,
+}
+
+impl UdpBindOptions {
+    fn shares_port(&self) -> bool {
+        self.reuse_address || self.reuse_port
+    }
+}
+
+/// Collects the options of a UdpSocket, the ones of UdpBindOptions and the network
+/// interface to bind to
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct UdpSocketBuilder {
+    options: UdpBindOptions,
+    device: Option<String>,
+}
+
+impl UdpSocketBuilder {
+    pub fn new() -> UdpSocketBuilder {
+        UdpSocketBuilder::default()
+    }
+    /// Sets SO_REUSEADDR, see UdpBindOptions
+    pub fn reuse_address(mut self, reuse_address: bool) -> UdpSocketBuilder {
+        self.options.reuse_address = reuse_address;
+        self
+    }
+    /// Sets SO_REUSEPORT, see UdpBindOptions
+    pub fn reuse_port(mut self, reuse_port: bool) -> UdpSocketBuilder {
+        self.options.reuse_port = reuse_port;
+        self
+    }
+    /// Sets the size of the receive and send buffers of the socket on the stack
+    pub fn buffer_size(mut self, buffer_size: usize) -> UdpSocketBuilder {
+        self.options.buffer_size = Some(buffer_size);
+        self
+    }
+    /// Sets IPV6_V6ONLY, see UdpBindOptions
+    pub fn only_v6(mut self, only_v6: bool) -> UdpSocketBuilder {
+        self.options.only_v6 = only_v6;
+        self
+    }
+    /// Only sends and receives through the interface, as SO_BINDTODEVICE does
+    /// The device must be the NIC of the stack (or its parent for macvtap and usnetd), then
+    /// the datagrams through the kernel loopback or the loopback of the stack are dropped,
+    /// all destinations are sent to through the NIC, also the ones which the path policy
+    /// reaches through the kernel, and sending to loopback or own addresses fails with
+    /// ENETUNREACH. With "lo", the socket is bound to 127.0.0.1 for the unspecified address
+    /// and only sends to loopback addresses. Other names fail at bind time, with NotFound
+    /// if there is no such interface and with InvalidInput if the stack does not use it.
+    pub fn device(mut self, device: &str) -> UdpSocketBuilder {
+        self.device = Some(device.to_owned());
+        self
+    }
+    /// Returns the options which are set before the bind
+    pub fn bind_options(&self) -> UdpBindOptions {
+        self.options
+    }
+    /// Binds a socket on the global stack
+    pub fn bind<A: UsnetToSocketAddrs>(&self, addr: A) -> io::Result<UdpSocket> {
+        self.bind_on(&STCP_GLOBAL, addr)
+    }
+    /// Binds a socket on the given stack
+    pub fn bind_on<A: UsnetToSocketAddrs>(
+        &self,
+        stack: &StcpNetRef,
+        addr: A,
+    ) -> io::Result<UdpSocket> {
+        match self.device {
+            Some(ref device) => stack.bind_udp_device(addr, self.options, device),
+            None => stack.bind_udp_with(addr, self.options),
+        }
+    }
+}
+
+/// Creates the kernel socket with the options set before it is bound
+pub(crate) fn bind_kernel_udp(
+    addr: SocketAddr,
+    options: UdpBindOptions,
+) -> io::Result<SystemUdpSocket> {
+    if !options.shares_port() {
+        return SystemUdpSocket::bind(addr);
+    }
+    let family = if addr.is_ipv4() {
+        libc::AF_INET
+    } else {
+        libc::AF_INET6
+    };
+    let fd = unsafe { libc::socket(family, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // closes the fd on errors
+    let socket = unsafe { SystemUdpSocket::from_raw_fd(fd) };
+    if options.reuse_address {
+        set_socket_flag(fd, libc::SO_REUSEADDR)?;
+    }
+    if options.reuse_port {
+        set_reuse_port(fd)?;
+    }
+    let r = match addr {
+        SocketAddr::V4(ref a) => {
+            let mut sin: libc::sockaddr_in = unsafe { mem::zeroed() };
+            sin.sin_family = libc::AF_INET as libc::sa_family_t;
+            sin.sin_port = a.port().to_be();
+            sin.sin_addr.s_addr = u32::from(*a.ip()).to_be();
+            unsafe {
+                libc::bind(
+                    fd,
+                    &sin as *const libc::sockaddr_in as *const libc::sockaddr,
+                    mem::size_of::<libc::sockaddr_in>() as libc::socklen_t,
+                )
+            }
+        }
+        SocketAddr::V6(ref a) => {
+            let mut sin6: libc::sockaddr_in6 = unsafe { mem::zeroed() };
+            sin6.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+            sin6.sin6_port = a.port().to_be();
+            sin6.sin6_flowinfo = a.flowinfo();
+            sin6.sin6_addr.s6_addr = a.ip().octets();
+            sin6.sin6_scope_id = a.scope_id();
+            unsafe {
+                libc::bind(
+                    fd,
+                    &sin6 as *const libc::sockaddr_in6 as *const libc::sockaddr,
+                    mem::size_of::<libc::sockaddr_in6>() as libc::socklen_t,
+                )
+            }
+        }
+    };
+    if r < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(socket)
+}
+
+fn set_socket_flag(fd: RawFd, option: c_int) -> io::Result<()> {
+    let enabled: c_int = 1;
+    let r = unsafe {
+        libc::setsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            option,
+            &enabled as *const c_int as *const libc::c_void,
+            mem::size_of::<c_int>() as libc::socklen_t,
+        )
+    };
+    if r < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+#[cfg(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "freebsd",
+    target_os = "netbsd",
+    target_os = "openbsd",
+    target_os = "dragonfly",
+    target_os = "macos"
+))]
+fn set_reuse_port(fd: RawFd) -> io::Result<()> {
+    set_socket_flag(fd, libc::SO_REUSEPORT).map_err(|e| {
+        if e.raw_os_error() == Some(libc::ENOPROTOOPT) {
+            io::Error::new(
+                io::ErrorKind::Other,
+                "SO_REUSEPORT is not supported by the kernel (needs Linux 3.9 or newer)",
+            )
+        } else {
+            e
+        }
+    })
+}
+
+#[cfg(not(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "freebsd",
+    target_os = "netbsd",
+    target_os = "openbsd",
+    target_os = "dragonfly",
+    target_os = "macos"
+)))]
+fn set_reuse_port(_fd: RawFd) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "SO_REUSEPORT is not supported on this platform",
+    ))
+}
+
+/// Buffer for a datagram of UdpSocket::recv_batch, which can be reused for the next batch
+#[derive(Clone, Debug)]
+pub struct UdpSlot {
+    buf: Vec<u8>,
+    len: usize,
+    addr: SocketAddr,
+}
+
+impl UdpSlot {
+    /// Creates a slot for datagrams of up to the size, longer ones are truncated
+    pub fn new(size: usize) -> UdpSlot {
+        UdpSlot {
+            buf: vec![0; size],
+            len: 0,
+            addr: SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), 0),
+        }
+    }
+    /// Payload of the received datagram
+    pub fn data(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+    /// Sender of the received datagram
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+}
+
 #[derive(Debug)]
 pub struct UdpSocket {
     stcpnet: StcpNetRef,
     socket_handle: Option<SocketHandle>,
     lo: SystemUdpSocket,
     connected: Arc<RwLock<Option<SocketAddr>>>,
+    connected_lo: Arc<AtomicBool>, // connected through the loopback of the kernel
+    kernel: Arc<Mutex<Option<KernelUdpSocket>>>, // created on first use by the path policy
+    connected_kernel: Arc<AtomicBool>, // connected through the kernel socket
+    handle_refs: Arc<AtomicUsize>, // clones sharing the socket, removed with the last one
+    device: BoundDevice,
+    tos: Arc<AtomicU32>,
+    ttl: Arc<AtomicU32>, // of the stack socket and the kernel sockets
     nonblocking: Arc<AtomicBool>,
     read_timeout: Arc<RwLock<Option<Duration>>>,
     write_timeout: Arc<RwLock<Option<Duration>>>,
 }
 
+/// Socket from which a UdpSocket receives a datagram
+enum RecvSource<'a> {
+    // and the index of the interface, the addresses of the loopback, and the noted
+    // destinations of the stack
+    Stack(
+        &'a mut SmoltcpUdpSocket<'static>,
+        u32,
+        &'a [IpCidr],
+        &'a pktinfo::Ports,
+    ),
+    Loopback(&'a SystemUdpSocket),
+    Kernel(&'a SystemUdpSocket),
+}
+
+// largest UDP payload, for datagrams which recv_with has to copy from a system socket
+pub(crate) const UDP_PAYLOAD_LIMIT: usize = 65536;
+
+thread_local! {
+    static RECV_COPY: RefCell<Vec<u8>> = RefCell::new(Vec::new());
+}
+
+/// Kernel socket of a UdpSocket for the destinations which the path policy reaches through
+/// the kernel, polled by the background thread like the loopback socket
+struct KernelUdpSocket {
+    stcpnet: StcpNetRef,
+    socket: SystemUdpSocket,
+}
+
+impl Drop for KernelUdpSocket {
+    fn drop(&mut self) {
+        let &(ref stcpnetref, ref _cond) = &*self.stcpnet.r;
+        stcpnetref.lock().fds_remove.push(self.socket.as_raw_fd());
+    }
+}
+
 impl Drop for UdpSocket {
     fn drop(&mut self) {
         let &(ref stcpnetref, ref _cond) = &*self.stcpnet.r;
         let mut stcpnet = stcpnetref.lock();
         stcpnet.fds_remove.push(self.lo.as_raw_fd());
         if let Some(sockethandle) = self.socket_handle {
-            debug!("drop-closing UDP");
-            stcpnet.sockets.release(sockethandle);
-            stcpnet.sockets.prune();
+            if self.handle_refs.fetch_sub(1, Ordering::SeqCst) == 1 {
+                debug!("drop-closing UDP");
+                let port = stcpnet
+                    .sockets
+                    .get::<SmoltcpUdpSocket>(sockethandle)
+                    .endpoint()
+                    .port;
+                let inspection = stcpnet.iface.inspection();
+                if self.tos.load(Ordering::SeqCst) != 0 {
+                    inspection.tos.set(port, 0);
+                }
+                inspection.pktinfo.release(port);
+                let _ = stcpnet.sockets.remove(sockethandle);
+            }
         }
     }
 }
@@ -1290,21 +3669,256 @@ impl UdpSocket {
     pub fn bind<A: UsnetToSocketAddrs>(addr: A) -> io::Result<UdpSocket> {
         STCP_GLOBAL.bind_udp(addr)
     }
+    /// Binds to the address with the options that have to be set before, e.g., to share the
+    /// port with other processes through SO_REUSEPORT
+    pub fn bind_with<A: UsnetToSocketAddrs>(
+        addr: A,
+        options: UdpBindOptions,
+    ) -> io::Result<UdpSocket> {
+        STCP_GLOBAL.bind_udp_with(addr, options)
+    }
+    /// Binds to the address on the given network interface, see UdpSocketBuilder::device
+    pub fn bind_device<A: UsnetToSocketAddrs>(addr: A, device: &str) -> io::Result<UdpSocket> {
+        STCP_GLOBAL.bind_udp_device(addr, UdpBindOptions::default(), device)
+    }
     pub fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
         self.peek_or_recv_from(true, buf)
     }
+    /// Receives a datagram and calls the function with the payload in the receive buffer of
+    /// the stack and the sender address, the datagram is removed when the function returns
+    /// This saves the copy into a caller buffer, but the function runs while the stack is
+    /// locked: as long as it holds the payload, no socket can send or receive and the receive
+    /// ring of the NIC fills up and drops packets. It should only parse or copy the payload
+    /// and must not use other sockets of the stack, which would deadlock.
+    /// Datagrams through the loopback or kernel socket are still copied once.
+    pub fn recv_with<F, R>(&self, f: F) -> io::Result<R>
+    where
+        F: FnOnce(&[u8], SocketAddr) -> R,
+    {
+        let mut f = Some(f);
+        self.receive(|source| match source {
+            RecvSource::Stack(socket, _, loopback_ips, _) => match socket.recv() {
+                Ok((payload, endpoint)) => {
+                    let addr = endpoint_to_socket_addr(&endpoint);
+                    if !self.accepts_from(&addr, loopback_ips) {
+                        return None;
+                    }
+                    Some(Ok(f.take().unwrap()(payload, addr)))
+                }
+                Err(e) => Some(Err(stack_error(e))),
+            },
+            RecvSource::Loopback(socket) | RecvSource::Kernel(socket) => {
+                // taken out of the thread-local for a recv_with of another socket in f
+                let mut copy = RECV_COPY.with(|c| c.replace(Vec::new()));
+                copy.resize(UDP_PAYLOAD_LIMIT, 0);
+                let r = socket
+                    .recv_from(&mut copy)
+                    .map(|(n, addr)| f.take().unwrap()(&copy[..n], addr));
+                RECV_COPY.with(|c| *c.borrow_mut() = copy);
+                Some(r)
+            }
+        })
+    }
+    /// Receives a datagram like recv_from and also returns its full length, which is larger
+    /// than the received length when the buffer was too small and the rest was discarded,
+    /// like recvmsg with MSG_TRUNC on Linux
+    pub fn recv_from_trunc(&self, buf: &mut [u8]) -> io::Result<(usize, usize, SocketAddr)> {
+        self.recv_with(|data, addr| {
+            let n = data.len().min(buf.len());
+            buf[..n].copy_from_slice(&data[..n]);
+            (n, data.len(), addr)
+        })
+    }
+    /// Receives datagrams into the slots and returns how many were received
+    /// It waits for the first datagram as recv_from does, and takes the others which the stack
+    /// has already received under the same lock. Datagrams through the loopback or the kernel
+    /// socket fallback are received one per call.
+    pub fn recv_batch(&self, slots: &mut [UdpSlot]) -> io::Result<usize> {
+        if slots.is_empty() {
+            return Ok(0);
+        }
+        self.receive(|source| match source {
+            RecvSource::Stack(socket, _, loopback_ips, _) => {
+                let mut received = 0;
+                while received < slots.len() && socket.can_recv() {
+                    let slot = &mut slots[received];
+                    match socket.recv_slice(&mut slot.buf) {
+                        Ok((len, endpoint)) => {
+                            let addr = endpoint_to_socket_addr(&endpoint);
+                            if self.accepts_from(&addr, loopback_ips) {
+                                slot.len = len;
+                                slot.addr = addr;
+                                received += 1;
+                            }
+                        }
+                        Err(_) if received > 0 => break,
+                        Err(e) => return Some(Err(stack_error(e))),
+                    }
+                }
+                if received == 0 {
+                    None
+                } else {
+                    Some(Ok(received))
+                }
+            }
+            RecvSource::Loopback(socket) | RecvSource::Kernel(socket) => {
+                let slot = &mut slots[0];
+                Some(socket.recv_from(&mut slot.buf).map(|(len, addr)| {
+                    slot.len = len;
+                    slot.addr = addr;
+                    1
+                }))
+            }
+        })
+    }
+    /// Receives a datagram like recv_from and also returns the local address that it was
+    /// sent to and the interface, e.g., to reply from this address with send_msg when the
+    /// socket is bound to the unspecified address
+    /// The stack notes the destinations from the first call on, for datagrams received before
+    /// and through the kernel socket fallback the bound address is returned.
+    pub fn recv_msg(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr, PktInfo)> {
+        self.receive(|source| match source {
+            RecvSource::Stack(socket, interface, loopback_ips, ports) => {
+                match socket.recv_slice(buf) {
+                    Ok((read, endpoint)) => {
+                        let addr = endpoint_to_socket_addr(&endpoint);
+                        if !self.accepts_from(&addr, loopback_ips) {
+                            return None;
+                        }
+                        let local = socket.endpoint();
+                        let dst = match ports.destination(local.port, endpoint) {
+                            Some(dst) => IpAddr::V4(Ipv4Addr::from(dst)),
+                            None => endpoint_to_socket_addr(&local).ip(),
+                        };
+                        let info = PktInfo {
+                            addr: dst,
+                            interface: interface,
+                        };
+                        Some(Ok((read, addr, info)))
+                    }
+                    Err(e) => Some(Err(stack_error(e))),
+                }
+            }
+            RecvSource::Loopback(socket) => Some(socket.recv_from(buf).and_then(|(read, addr)| {
+                let info = PktInfo {
+                    addr: socket.local_addr()?.ip(),
+                    interface: *LOOPBACK_INTERFACE,
+                };
+                Ok((read, addr, info))
+            })),
+            RecvSource::Kernel(socket) => Some(socket.recv_from(buf).and_then(|(read, addr)| {
+                let info = PktInfo {
+                    addr: socket.local_addr()?.ip(),
+                    interface: 0,
+                };
+                Ok((read, addr, info))
+            })),
+        })
+    }
+    /// Runs the function with the kernel socket for destinations outside of the networks of
+    /// the stack (the socket is created for the address family of the destination on first use)
+    fn with_kernel_socket<R, F: FnOnce(&SystemUdpSocket) -> io::Result<R>>(
+        &self,
+        dst: IpAddr,
+        f: F,
+    ) -> io::Result<R> {
+        let mut kernel = self.kernel.lock();
+        if kernel.is_none() {
+            let unspecified = match dst {
+                IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)),
+                IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 0)),
+            };
+            let socket = SystemUdpSocket::bind(SocketAddr::new(unspecified, 0))?;
+            socket.set_nonblocking(true)?;
+            let tos = self.tos.load(Ordering::SeqCst);
+            if tos != 0 {
+                set_traffic_class(&socket, tos)?;
+            }
+            if dst.is_ipv4() {
+                socket.set_ttl(self.ttl.load(Ordering::SeqCst))?;
+            }
+            let &(ref stcpnetref, ref _cond) = &*self.stcpnet.r;
+            let mut stcpnet = stcpnetref.lock();
+            stcpnet.fds_add.push(socket.as_raw_fd());
+            let _ = stcpnet.notify_poll.send(b"$");
+            *kernel = Some(KernelUdpSocket {
+                stcpnet: self.stcpnet.clone(),
+                socket: socket,
+            });
+        }
+        f(&kernel.as_ref().unwrap().socket)
+    }
     fn peek_or_recv_from(&self, recv: bool, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
-        if self.socket_handle.is_none()
-            || match *self.connected.read() {
-                Some(sa) => sa.ip().is_loopback(),
-                None => false,
+        self.receive(|source| match source {
+            RecvSource::Stack(socket, _, loopback_ips, _) => {
+                let r = if recv {
+                    socket.recv_slice(buf)
+                } else {
+                    socket.peek_slice(buf).map(|(r, e)| (r, e.clone()))
+                };
+                match r {
+                    Ok((read, endpoint)) => {
+                        let addr = endpoint_to_socket_addr(&endpoint);
+                        if !self.accepts_from(&addr, loopback_ips) {
+                            return None;
+                        }
+                        Some(Ok((read, addr)))
+                    }
+                    Err(e) => Some(Err(stack_error(e))),
+                }
             }
-        {
-            return if recv {
-                self.lo.recv_from(buf)
+            RecvSource::Loopback(socket) | RecvSource::Kernel(socket) => Some(if recv {
+                socket.recv_from(buf)
             } else {
-                self.lo.peek_from(buf)
-            };
+                socket.peek_from(buf)
+            }),
+        })
+    }
+    /// Fails with ENETUNREACH for a destination which the device of the socket does not reach
+    fn check_reaches(&self, ip: IpAddr) -> io::Result<()> {
+        if self.device == BoundDevice::Any {
+            return Ok(());
+        }
+        let reaches = {
+            let &(ref stcpnetref, ref _cond) = &*self.stcpnet.r;
+            self.device.reaches(&stcpnetref.lock().iface, ip)
+        };
+        if reaches {
+            Ok(())
+        } else {
+            Err(io::Error::from_raw_os_error(libc::ENETUNREACH))
+        }
+    }
+    /// Whether a datagram from the address is received, i.e., the socket is not connected or
+    /// the address matches the connected one (which may have an unspecified address or port)
+    /// A socket bound to the NIC skips the datagrams through the loopback of the stack.
+    fn accepts_from(&self, sockaddr: &SocketAddr, loopback_ips: &[IpCidr]) -> bool {
+        if self.device == BoundDevice::Nic && from_loopback(sockaddr.ip(), loopback_ips) {
+            return false;
+        }
+        match *self.connected.read() {
+            Some(ref conn_remote_addr) => {
+                sockaddr == conn_remote_addr
+                    || (conn_remote_addr.ip().is_unspecified() && conn_remote_addr.port() == 0)
+                    || (conn_remote_addr.ip().is_unspecified()
+                        && sockaddr.port() == conn_remote_addr.port())
+                    || (conn_remote_addr.port() == 0 && conn_remote_addr.ip() == sockaddr.ip())
+            }
+            None => true,
+        }
+    }
+    /// Waits for a datagram on the socket of the stack, the loopback socket, or the kernel
+    /// socket and receives it through the function, which returns None for a datagram of the
+    /// stack it skips because it is not from the connected address
+    fn receive<R, F>(&self, mut f: F) -> io::Result<R>
+    where
+        F: FnMut(RecvSource) -> Option<io::Result<R>>,
+    {
+        let not_ready = || Err(io::Error::new(io::ErrorKind::WouldBlock, "recv not ready"));
+        if self.socket_handle.is_none()
+            || ((*self.connected.read()).is_some() && self.connected_lo.load(Ordering::SeqCst))
+        {
+            return f(RecvSource::Loopback(&self.lo)).unwrap_or_else(not_ready);
         }
         let socket_handle = self.socket_handle.unwrap();
 
@@ -1312,43 +3926,35 @@ impl UdpSocket {
         let &(ref stcpnetref, ref cond) = &*self.stcpnet.r;
         let mut stcpnet = stcpnetref.lock();
         loop {
+            stcpnet.check_running()?;
             {
+                let stcpnet = &mut *stcpnet;
+                let loopback_ips = if stcpnet.loopback {
+                    stcpnet.iface.ips()
+                } else {
+                    &[]
+                };
+                let ports = &stcpnet.iface.inspection().pktinfo;
                 let mut socket = stcpnet.sockets.get::<SmoltcpUdpSocket>(socket_handle);
                 if socket.can_recv() {
-                    let r = if recv {
-                        socket.recv_slice(buf)
-                    } else {
-                        socket.peek_slice(buf).map(|(r, e)| (r, e.clone()))
-                    };
-                    let r = r
-                        .map(|(read, addr)| (read, endpoint_to_socket_addr(&addr)))
-                        .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()));
-                    if let Some(ref conn_remote_addr) = *self.connected.read() {
-                        match &r {
-                            Ok((_, sockaddr)) => {
-                                if sockaddr != conn_remote_addr
-                                    && !(conn_remote_addr.ip().is_unspecified()
-                                        && conn_remote_addr.port() == 0)
-                                    && !(conn_remote_addr.ip().is_unspecified()
-                                        && sockaddr.port() == conn_remote_addr.port())
-                                    && !(conn_remote_addr.port() == 0
-                                        && conn_remote_addr.ip() == sockaddr.ip())
-                                {
-                                    continue;
-                                }
-                            }
-                            Err(_) => {}
-                        }
+                    let source = RecvSource::Stack(
+                        &mut socket,
+                        stcpnet.interface_index,
+                        loopback_ips,
+                        ports,
+                    );
+                    match f(source) {
+                        Some(r) => return r,
+                        None => continue,
                     }
-                    return r;
                 }
             }
-            if (*self.connected.read()).is_none() {
-                let sys_res = if recv {
-                    self.lo.recv_from(buf)
-                } else {
-                    self.lo.peek_from(buf)
-                };
+            if self.device == BoundDevice::Nic {
+                // the datagrams through the kernel loopback did not arrive on the NIC
+                let mut discard = [0; 1];
+                while self.lo.recv_from(&mut discard).is_ok() {}
+            } else if (*self.connected.read()).is_none() {
+                let sys_res = f(RecvSource::Loopback(&self.lo)).unwrap_or_else(not_ready);
                 if sys_res
                     .as_ref()
                     .err()
@@ -1358,13 +3964,32 @@ impl UdpSocket {
                     return sys_res;
                 }
             }
+            if (*self.connected.read()).is_none() || self.connected_kernel.load(Ordering::SeqCst) {
+                // the lock order is the kernel socket before the stack, a concurrent send_to
+                // holds it only briefly and the background thread wakes us again
+                if let Some(kernel) = self.kernel.try_lock() {
+                    if let Some(ref kernel) = *kernel {
+                        let sys_res =
+                            f(RecvSource::Kernel(&kernel.socket)).unwrap_or_else(not_ready);
+                        if sys_res
+                            .as_ref()
+                            .err()
+                            .map(|e| e.kind() == io::ErrorKind::WouldBlock)
+                            != Some(true)
+                        {
+                            return sys_res
+                                .map_err(|e| fallback::path_error(e, SocketPath::Kernel));
+                        }
+                    }
+                }
+            }
             if self.nonblocking.load(Ordering::SeqCst) {
-                return Err(io::Error::new(io::ErrorKind::WouldBlock, "recv not ready"));
+                return not_ready();
             }
             match *self.read_timeout.read() {
                 Some(timeout) => {
                     if cond.wait_until(&mut stcpnet, start + timeout).timed_out() {
-                        return Err(io::Error::new(io::ErrorKind::WouldBlock, "recv not ready"));
+                        return not_ready();
                     }
                 }
                 None => {
@@ -1381,7 +4006,139 @@ impl UdpSocket {
             .usnet_to_socket_addrs()?
             .next()
             .ok_or(io::Error::new(io::ErrorKind::Other, "to sock addr empty"))?;
-        if self.socket_handle.is_none() || addr.ip().is_loopback() {
+        self.send_from(buf, addr, None)
+    }
+    /// Sends a datagram like send_to, from the address of the PktInfo if one is given, e.g.,
+    /// the destination of the request that recv_msg returned
+    /// The address must be one of the stack, or the loopback address of the socket for
+    /// destinations on the loopback. It cannot be chosen through the kernel socket fallback.
+    pub fn send_msg<A: UsnetToSocketAddrs>(
+        &self,
+        buf: &[u8],
+        addr: A,
+        info: Option<PktInfo>,
+    ) -> io::Result<usize> {
+        let addr = addr
+            .usnet_to_socket_addrs()?
+            .next()
+            .ok_or(io::Error::new(io::ErrorKind::Other, "to sock addr empty"))?;
+        let source = info.map(|info| info.addr).filter(|a| !a.is_unspecified());
+        self.send_from(buf, addr, source)
+    }
+    /// Sends the datagrams in order and returns how many were sent
+    /// In blocking mode, it waits until the first datagram can be sent, the others are only
+    /// sent while there is space in the send buffer. The datagrams through the stack are
+    /// queued under one lock of the stack, which is notified once, the ones through the
+    /// loopback or the kernel socket fallback are sent one by one as with send_to. An error
+    /// is only returned if no datagram was sent.
+    pub fn send_batch(&self, datagrams: &[(&[u8], SocketAddr)]) -> io::Result<usize> {
+        let mut sent = 0;
+        while sent < datagrams.len() {
+            let r = match self.queue_batch(&datagrams[sent..], sent == 0) {
+                Ok(0) => {
+                    let (buf, addr) = datagrams[sent];
+                    self.send_from(buf, addr, None).map(|_| 1)
+                }
+                r => r,
+            };
+            match r {
+                Ok(n) => sent += n,
+                Err(e) if sent == 0 => return Err(e),
+                Err(_) => break,
+            }
+        }
+        Ok(sent)
+    }
+    /// Queues the leading datagrams which go through the stack, waits for space for the first
+    /// one if allowed, and returns how many were queued
+    fn queue_batch(&self, datagrams: &[(&[u8], SocketAddr)], wait: bool) -> io::Result<usize> {
+        let socket_handle = match self.socket_handle {
+            Some(socket_handle) => socket_handle,
+            None => return Ok(0),
+        };
+        let start = StdInstant::now();
+        let &(ref stcpnetref, ref cond) = &*self.stcpnet.r;
+        let mut stcpnet = stcpnetref.lock();
+        let mut queued = 0;
+        let error = loop {
+            if let Err(e) = stcpnet.check_running() {
+                break Some(e);
+            }
+            let (buf, addr) = match datagrams.get(queued) {
+                Some(&(buf, addr)) => (buf, unmapped(addr)),
+                None => break None,
+            };
+            let through_stack = self.device == BoundDevice::Nic || stcpnet.udp_through_stack(&addr);
+            // send_from fails for a datagram without a route
+            if !through_stack
+                || !self.device.reaches(&stcpnet.iface, addr.ip())
+                || stcpnet.check_route(addr.ip()).is_err()
+            {
+                break None;
+            }
+            let mut socket = stcpnet.sockets.get::<SmoltcpUdpSocket>(socket_handle);
+            if !socket.can_send() {
+                if queued > 0 {
+                    break None;
+                }
+                if !wait || self.nonblocking.load(Ordering::SeqCst) {
+                    break Some(io::Error::new(io::ErrorKind::WouldBlock, "send not ready"));
+                }
+                drop(socket);
+                match *self.write_timeout.read() {
+                    Some(timeout) => {
+                        if cond.wait_until(&mut stcpnet, start + timeout).timed_out() {
+                            break Some(io::Error::new(
+                                io::ErrorKind::WouldBlock,
+                                "send not ready",
+                            ));
+                        }
+                    }
+                    None => {
+                        cond.wait(&mut stcpnet);
+                    }
+                }
+                continue;
+            }
+            match socket.send_slice(buf, addr.into()) {
+                Ok(()) => {
+                    let port = socket.endpoint().port;
+                    drop(socket);
+                    let ports = &stcpnet.iface.inspection().pktinfo;
+                    ports.note_sent(port, addr.into(), None);
+                    queued += 1;
+                }
+                // the rest of the buffer is too small for the datagram
+                Err(_) if queued > 0 => break None,
+                Err(e) => break Some(stack_error(e)),
+            }
+        };
+        if queued > 0 {
+            if stcpnet.bg_skip_one_wait == Skip::Wait {
+                let _ = stcpnet.notify_poll.send(b"$").unwrap();
+            } else {
+                stcpnet.bg_skip_one_wait = Skip::Skip;
+            }
+        }
+        match error {
+            Some(e) => Err(e),
+            None => Ok(queued),
+        }
+    }
+    fn send_from(&self, buf: &[u8], addr: SocketAddr, source: Option<IpAddr>) -> io::Result<usize> {
+        let addr = unmapped(addr);
+        self.check_reaches(addr.ip())?;
+        if self.socket_handle.is_none()
+            || (addr.ip().is_loopback() && !self.stcpnet.loopback_target(&addr, IpProtocol::Udp))
+        {
+            if let Some(source) = source {
+                if source != self.lo.local_addr()?.ip() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::AddrNotAvailable,
+                        format!("cannot send from {} through the loopback", source),
+                    ));
+                }
+            }
             if self.socket_handle.is_some() && !self.nonblocking.load(Ordering::SeqCst) {
                 self.lo.set_nonblocking(false)?;
             }
@@ -1391,22 +4148,51 @@ impl UdpSocket {
             }
             return r;
         }
+        if self.device != BoundDevice::Nic
+            && self.stcpnet.socket_path(addr.ip()) == SocketPath::Kernel
+        {
+            if source.is_some() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "the source address cannot be chosen through the kernel socket fallback",
+                ));
+            }
+            return self
+                .with_kernel_socket(addr.ip(), |kernel| kernel.send_to(buf, addr))
+                .map_err(|e| fallback::path_error(e, SocketPath::Kernel));
+        }
         let socket_handle = self.socket_handle.unwrap();
 
         let start = StdInstant::now();
         let &(ref stcpnetref, ref cond) = &*self.stcpnet.r;
         let mut stcpnet = stcpnetref.lock();
+        stcpnet.check_route(addr.ip())?;
+        let source = match source {
+            Some(IpAddr::V4(src)) if stcpnet.iface.has_ip(IpAddr::V4(src)) => {
+                Some(Ipv4Address::from(src))
+            }
+            Some(src) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::AddrNotAvailable,
+                    format!("cannot send from {}, not an address of the interface", src),
+                ));
+            }
+            None => None,
+        };
         loop {
+            stcpnet.check_running()?;
             let mut r = None;
             {
+                let stcpnet = &mut *stcpnet;
                 let mut socket = stcpnet.sockets.get::<SmoltcpUdpSocket>(socket_handle);
                 if socket.can_send() {
-                    r = Some(
-                        socket
-                            .send_slice(buf, addr.into())
-                            .map(|_| buf.len())
-                            .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string())),
-                    );
+                    let sent = socket.send_slice(buf, addr.into());
+                    if sent.is_ok() {
+                        let port = socket.endpoint().port;
+                        let ports = &stcpnet.iface.inspection().pktinfo;
+                        ports.note_sent(port, addr.into(), source);
+                    }
+                    r = Some(sent.map(|_| buf.len()).map_err(stack_error));
                 }
             }
             if let Some(r) = r {
@@ -1447,26 +4233,32 @@ impl UdpSocket {
         }
     }
     pub fn try_clone(&self) -> io::Result<UdpSocket> {
-        let &(ref stcpnetref, ref _cond) = &*self.stcpnet.r;
-        let mut stcpnet = stcpnetref.lock();
-        if let Some(socket_handle) = self.socket_handle {
-            stcpnet.sockets.retain(socket_handle); // cloning needs to increase the ref count
-        }
+        let lo = self.lo.try_clone()?;
+        let _ = self.handle_refs.fetch_add(1, Ordering::SeqCst);
         Ok(UdpSocket {
             stcpnet: self.stcpnet.clone(),
             socket_handle: self.socket_handle.clone(),
-            lo: self.lo.try_clone()?,
+            lo: lo,
             connected: self.connected.clone(),
+            connected_lo: self.connected_lo.clone(),
+            kernel: self.kernel.clone(),
+            connected_kernel: self.connected_kernel.clone(),
+            handle_refs: self.handle_refs.clone(),
+            device: self.device,
+            tos: self.tos.clone(),
+            ttl: self.ttl.clone(),
             nonblocking: self.nonblocking.clone(),
             read_timeout: self.read_timeout.clone(),
             write_timeout: self.write_timeout.clone(),
         })
     }
     pub fn set_read_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
+        check_timeout(dur)?;
         *self.read_timeout.write() = dur;
         self.lo.set_read_timeout(*self.read_timeout.read())
     }
     pub fn set_write_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
+        check_timeout(dur)?;
         *self.write_timeout.write() = dur;
         self.lo.set_write_timeout(*self.write_timeout.read())
     }
@@ -1500,25 +4292,108 @@ impl UdpSocket {
     pub fn multicast_loop_v6(&self) -> io::Result<bool> {
         panic!("unimpl")
     }
+    /// Sets the IPv4 TTL for all following packets sent on this socket
     pub fn set_ttl(&self, ttl: u32) -> io::Result<()> {
+        if ttl == 0 || ttl > 255 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "TTL must be in the range 1..=255",
+            ));
+        }
         if let Some(socket_handle) = self.socket_handle {
             let &(ref stcpnetref, ref _cond) = &*self.stcpnet.r;
             let mut stcpnet = stcpnetref.lock();
             let mut socket = stcpnet.sockets.get::<SmoltcpUdpSocket>(socket_handle);
             socket.set_hop_limit(Some(ttl as u8));
         }
-        self.lo.set_ttl(ttl)
+        self.lo.set_ttl(ttl)?;
+        if let Some(ref kernel) = *self.kernel.lock() {
+            if kernel.socket.local_addr()?.is_ipv4() {
+                kernel.socket.set_ttl(ttl)?;
+            }
+        }
+        self.ttl.store(ttl, Ordering::SeqCst);
+        Ok(())
     }
     pub fn ttl(&self) -> io::Result<u32> {
-        self.lo.ttl()
+        Ok(self.ttl.load(Ordering::SeqCst))
+    }
+    /// Sets the TOS byte (IP_TOS, or IPV6_TCLASS for IPv6) for all following packets sent on
+    /// this socket, i.e., the DSCP value shifted left by 2 with the ECN bits left to 0
+    /// The userspace network stack sets it on the outgoing IPv4 packets of the socket's port.
+    pub fn set_tos(&self, tos: u32) -> io::Result<()> {
+        if tos > 255 || tos & 0x03 != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "TOS must be a DSCP value shifted left by 2, the ECN bits must be 0",
+            ));
+        }
+        set_traffic_class(&self.lo, tos)?;
+        if let Some(ref kernel) = *self.kernel.lock() {
+            set_traffic_class(&kernel.socket, tos)?;
+        }
+        if let Some(socket_handle) = self.socket_handle {
+            let &(ref stcpnetref, ref _cond) = &*self.stcpnet.r;
+            let mut stcpnet = stcpnetref.lock();
+            let port = stcpnet
+                .sockets
+                .get::<SmoltcpUdpSocket>(socket_handle)
+                .endpoint()
+                .port;
+            stcpnet.inspection().tos.set(port, tos as u8);
+        }
+        self.tos.store(tos, Ordering::SeqCst);
+        Ok(())
+    }
+    pub fn tos(&self) -> io::Result<u32> {
+        Ok(self.tos.load(Ordering::SeqCst))
     }
-    pub fn join_multicast_v4(&self, _multiaddr: &Ipv4Addr, _interface: &Ipv4Addr) -> io::Result<()> {
+    /// Sets the IPv6 hop limit for all following packets sent on this socket
+    /// This only works for loopback sockets because the userspace network stack has no IPv6.
+    pub fn set_hop_limit(&self, hop_limit: u32) -> io::Result<()> {
+        if hop_limit == 0 || hop_limit > 255 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "hop limit must be in the range 1..=255",
+            ));
+        }
+        self.check_ipv6_loopback()?;
+        set_ipv6_unicast_hops(self.lo.as_raw_fd(), hop_limit as c_int)
+    }
+    pub fn hop_limit(&self) -> io::Result<u32> {
+        self.check_ipv6_loopback()?;
+        ipv6_unicast_hops(self.lo.as_raw_fd())
+    }
+    fn check_ipv6_loopback(&self) -> io::Result<()> {
+        if self.socket_handle.is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "IPv6 hop limit not supported by the userspace network stack",
+            ));
+        }
+        if self.lo.local_addr()?.is_ipv4() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "hop limit only applies to IPv6 sockets, use the TTL",
+            ));
+        }
+        Ok(())
+    }
+    pub fn join_multicast_v4(
+        &self,
+        _multiaddr: &Ipv4Addr,
+        _interface: &Ipv4Addr,
+    ) -> io::Result<()> {
         panic!("unimpl")
     }
     pub fn join_multicast_v6(&self, _multiaddr: &Ipv6Addr, _interface: u32) -> io::Result<()> {
         panic!("unimpl")
     }
-    pub fn leave_multicast_v4(&self, _multiaddr: &Ipv4Addr, _interface: &Ipv4Addr) -> io::Result<()> {
+    pub fn leave_multicast_v4(
+        &self,
+        _multiaddr: &Ipv4Addr,
+        _interface: &Ipv4Addr,
+    ) -> io::Result<()> {
         panic!("unimpl")
     }
     pub fn leave_multicast_v6(&self, _multiaddr: &Ipv6Addr, _interface: u32) -> io::Result<()> {
@@ -1532,20 +4407,36 @@ impl UdpSocket {
             .usnet_to_socket_addrs()?
             .next()
             .ok_or(io::Error::new(io::ErrorKind::Other, "to sock addr empty"))?;
+        let addr = unmapped(addr);
+        self.check_reaches(addr.ip())?;
+        let path = if self.socket_handle.is_some() && self.device != BoundDevice::Nic {
+            self.stcpnet.socket_path(addr.ip())
+        } else {
+            SocketPath::Usnet
+        };
+        let via_lo = {
+            let &(ref stcpnetref, ref _cond) = &*self.stcpnet.r;
+            let stcpnet = stcpnetref.lock();
+            (addr.ip().is_loopback() || stcpnet.iface.has_ip(addr.ip()))
+                && !stcpnet.loopback_target(&addr, IpProtocol::Udp)
+        };
         if !self.nonblocking.load(Ordering::SeqCst) {
-            self.lo.set_nonblocking(addr.ip().is_loopback())?;
+            self.lo.set_nonblocking(!via_lo)?;
         }
-
-        let &(ref stcpnetref, ref _cond) = &*self.stcpnet.r;
-        let stcpnet = stcpnetref.lock();
-        let ipv4 = stcpnet.iface.ips()[0].address();
-        if addr.ip().is_loopback() || IpAddress::from(addr.ip()) == ipv4 {
+        self.connected_lo.store(via_lo, Ordering::SeqCst);
+        self.connected_kernel
+            .store(path == SocketPath::Kernel, Ordering::SeqCst);
+        if via_lo {
             *self.connected.write() = Some(SocketAddr::new(
                 IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
                 addr.port(),
             ));
             self.lo.connect(addr)
         } else {
+            if path == SocketPath::Kernel {
+                self.with_kernel_socket(addr.ip(), |kernel| kernel.connect(addr))
+                    .map_err(|e| fallback::path_error(e, SocketPath::Kernel))?;
+            }
             *self.connected.write() = Some(addr.clone());
             Ok(())
         }
@@ -1572,9 +4463,9 @@ impl UdpSocket {
     }
     pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
         match *self.connected.read() {
-            Some(addr) => {
+            Some(_) => {
                 self.lo.set_nonblocking(if !nonblocking {
-                    !addr.ip().is_loopback()
+                    !self.connected_lo.load(Ordering::SeqCst)
                 } else {
                     true
                 })?;
@@ -1592,6 +4483,528 @@ impl UdpSocket {
     }
 }
 
+// ICMP
+const DEFAULT_HOP_LIMIT: u32 = 64; // default hop limit of smoltcp
+const MAX_PENDING_ECHOS: usize = 256; // send times kept for the RTT of replies
+
+/// Socket for ICMP and ICMPv6 echo requests (ping) through the userspace network stack
+/// It receives the echo replies with the identifier of the socket and the ICMP errors
+/// which quote one of its echo requests, i.e., destination unreachable, packet too big,
+/// and time exceeded. With usnetd, the first socket requests all ICMP packets for the
+/// addresses of the stack, so that no other usnetd client can receive them.
+/// The stack itself answers echo requests, also without a socket.
+#[derive(Debug)]
+pub struct IcmpSocket {
+    stcpnet: StcpNetRef,
+    socket_handle: SocketHandle,
+    ident: u16,
+    sent: Mutex<VecDeque<(IpAddr, u16, StdInstant)>>,
+    nonblocking: AtomicBool,
+    read_timeout: RwLock<Option<Duration>>,
+    ttl: AtomicU32,
+}
+
+impl Drop for IcmpSocket {
+    fn drop(&mut self) {
+        let &(ref stcpnetref, ref _cond) = &*self.stcpnet.r;
+        let mut stcpnet = stcpnetref.lock();
+        debug!("drop-closing ICMP socket with identifier {}", self.ident);
+        let _ = stcpnet.sockets.remove(self.socket_handle);
+        stcpnet.inspection().icmp.unregister(self.ident);
+    }
+}
+
+impl IcmpSocket {
+    /// Creates a socket with a random identifier which is not used by another socket
+    pub fn new() -> io::Result<IcmpSocket> {
+        STCP_GLOBAL.bind_icmp(None)
+    }
+    /// Creates a socket with the given identifier, fails if another socket uses it
+    pub fn with_ident(ident: u16) -> io::Result<IcmpSocket> {
+        STCP_GLOBAL.bind_icmp(Some(ident))
+    }
+    /// Returns the identifier of the echo requests of the socket
+    pub fn ident(&self) -> u16 {
+        self.ident
+    }
+    /// Sends an echo request to the address, the RTT of the reply is measured from now
+    /// Loopback and local addresses can't be reached through the userspace network stack,
+    /// except for 127.0.0.1 and the own addresses when the loopback of the stack is enabled.
+    /// Since smoltcp does not fragment packets, the request must fit the MTU.
+    pub fn send_echo(&self, addr: IpAddr, seq_no: u16, payload: &[u8]) -> io::Result<()> {
+        let packet = icmp::echo_request(addr.is_ipv6(), self.ident, seq_no, payload);
+        let start = StdInstant::now();
+        let &(ref stcpnetref, ref cond) = &*self.stcpnet.r;
+        let mut stcpnet = stcpnetref.lock();
+        if (addr.is_loopback() || addr.is_unspecified() || stcpnet.iface.has_ip(addr))
+            && !stcpnet.loopback_address(addr)
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "only remote addresses can be pinged through the userspace network stack",
+            ));
+        }
+        if routing::source_address(&mut stcpnet.iface, addr).is_none() {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrNotAvailable,
+                format!("no local address to reach {}", addr),
+            ));
+        }
+        loop {
+            stcpnet.check_running()?;
+            let mut r = None;
+            {
+                let mut socket = stcpnet.sockets.get::<SmoltcpIcmpSocket>(self.socket_handle);
+                if socket.can_send() {
+                    r = Some(
+                        socket
+                            .send_slice(&packet, routing::endpoint_address(addr))
+                            .map_err(stack_error),
+                    );
+                }
+            }
+            if let Some(r) = r {
+                if r.is_ok() {
+                    self.record_sent(addr, seq_no);
+                    trace!("ICMP echo request {} {} to {}", self.ident, seq_no, addr);
+                }
+                if stcpnet.bg_skip_one_wait == Skip::Wait {
+                    let _ = stcpnet.notify_poll.send(b"$").unwrap();
+                } else {
+                    stcpnet.bg_skip_one_wait = Skip::Skip;
+                }
+                return r;
+            }
+            if self.nonblocking.load(Ordering::SeqCst) {
+                return Err(io::Error::new(io::ErrorKind::WouldBlock, "send not ready"));
+            }
+            match *self.read_timeout.read() {
+                Some(timeout) => {
+                    if cond.wait_until(&mut stcpnet, start + timeout).timed_out() {
+                        return Err(io::Error::new(io::ErrorKind::WouldBlock, "send not ready"));
+                    }
+                }
+                None => {
+                    cond.wait(&mut stcpnet);
+                }
+            }
+        }
+    }
+    /// Receives the next echo reply or ICMP error for an echo request of the socket
+    pub fn recv(&self) -> io::Result<IcmpResponse> {
+        let deadline = self
+            .read_timeout
+            .read()
+            .map(|timeout| StdInstant::now() + timeout);
+        self.recv_until(self.nonblocking.load(Ordering::SeqCst), deadline)
+    }
+    /// Sends an echo request and waits for its reply, returns the RTT
+    /// Other replies and errors received in the meantime are discarded. An ICMP error for the
+    /// request is returned as error of kind Other which wraps the IcmpError, and no reply
+    /// within the timeout as TimedOut. This blocks also in nonblocking mode.
+    pub fn ping(
+        &self,
+        addr: IpAddr,
+        seq_no: u16,
+        payload: &[u8],
+        timeout: Duration,
+    ) -> io::Result<Duration> {
+        let start = StdInstant::now();
+        self.send_echo(addr, seq_no, payload)?;
+        loop {
+            match self.recv_until(false, Some(start + timeout)) {
+                Ok(IcmpResponse::Reply(reply)) => {
+                    if reply.from == addr && reply.seq_no == seq_no {
+                        return Ok(reply.rtt.unwrap_or_else(|| start.elapsed()));
+                    }
+                }
+                Ok(IcmpResponse::Error(error)) => {
+                    if error.dst == addr && error.seq_no == seq_no {
+                        return Err(io::Error::new(io::ErrorKind::Other, error));
+                    }
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        format!("no echo reply from {}", addr),
+                    ));
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+    fn recv_until(
+        &self,
+        nonblocking: bool,
+        deadline: Option<StdInstant>,
+    ) -> io::Result<IcmpResponse> {
+        let &(ref stcpnetref, ref cond) = &*self.stcpnet.r;
+        let mut stcpnet = stcpnetref.lock();
+        loop {
+            stcpnet.check_running()?;
+            if let Some(error) = stcpnet.inspection().icmp.take_error(self.ident) {
+                return Ok(IcmpResponse::Error(error));
+            }
+            {
+                let mut socket = stcpnet.sockets.get::<SmoltcpIcmpSocket>(self.socket_handle);
+                while socket.can_recv() {
+                    let (packet, from) = socket.recv().map_err(stack_error)?;
+                    let from = match routing::to_std(from) {
+                        Some(from) => from,
+                        None => continue,
+                    };
+                    // echo requests with the identifier of the socket are skipped
+                    if let Some((seq_no, payload)) = icmp::parse_echo_reply(packet) {
+                        return Ok(IcmpResponse::Reply(EchoReply {
+                            from: from,
+                            seq_no: seq_no,
+                            payload: payload.to_vec(),
+                            rtt: self.take_rtt(from, seq_no),
+                        }));
+                    }
+                }
+            }
+            if nonblocking {
+                return Err(io::Error::new(io::ErrorKind::WouldBlock, "recv not ready"));
+            }
+            match deadline {
+                Some(deadline) => {
+                    if cond.wait_until(&mut stcpnet, deadline).timed_out() {
+                        return Err(io::Error::new(io::ErrorKind::WouldBlock, "recv not ready"));
+                    }
+                }
+                None => {
+                    cond.wait(&mut stcpnet);
+                }
+            }
+        }
+    }
+    fn record_sent(&self, addr: IpAddr, seq_no: u16) {
+        let mut sent = self.sent.lock();
+        sent.retain(|&(a, s, _)| (a, s) != (addr, seq_no));
+        if sent.len() == MAX_PENDING_ECHOS {
+            sent.pop_front();
+        }
+        sent.push_back((addr, seq_no, StdInstant::now()));
+    }
+    fn take_rtt(&self, from: IpAddr, seq_no: u16) -> Option<Duration> {
+        let mut sent = self.sent.lock();
+        let pos = sent
+            .iter()
+            .position(|&(a, s, _)| (a, s) == (from, seq_no))?;
+        sent.remove(pos).map(|(_, _, time)| time.elapsed())
+    }
+    /// In nonblocking mode, send_echo and recv return WouldBlock instead of waiting
+    /// ping always waits for the reply or its timeout.
+    pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        self.nonblocking.store(nonblocking, Ordering::SeqCst);
+        Ok(())
+    }
+    /// Sets how long send_echo waits for a free buffer and recv for a response, after
+    /// which they return WouldBlock (None waits forever, a zero duration is invalid)
+    pub fn set_read_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
+        check_timeout(dur)?;
+        *self.read_timeout.write() = dur;
+        Ok(())
+    }
+    /// Returns the timeout of send_echo and recv
+    pub fn read_timeout(&self) -> io::Result<Option<Duration>> {
+        Ok(*self.read_timeout.read())
+    }
+    /// Sets the IPv4 TTL or IPv6 hop limit for all following echo requests, e.g., for traceroute
+    pub fn set_ttl(&self, ttl: u32) -> io::Result<()> {
+        if ttl == 0 || ttl > 255 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "TTL must be in the range 1..=255",
+            ));
+        }
+        let &(ref stcpnetref, ref _cond) = &*self.stcpnet.r;
+        let mut stcpnet = stcpnetref.lock();
+        let mut socket = stcpnet.sockets.get::<SmoltcpIcmpSocket>(self.socket_handle);
+        socket.set_hop_limit(Some(ttl as u8));
+        self.ttl.store(ttl, Ordering::SeqCst);
+        Ok(())
+    }
+    /// Returns the IPv4 TTL or IPv6 hop limit of the echo requests
+    pub fn ttl(&self) -> io::Result<u32> {
+        Ok(self.ttl.load(Ordering::SeqCst))
+    }
+}
+
+/// Socket for the IP packets of a protocol other than TCP and UDP, e.g., GRE or OSPF
+/// It has to be enabled with USNET_RAW_SOCKETS=true (or StackConfigBuilder::raw_sockets)
+/// because it bypasses the ports of the stack. Every socket receives a copy of all
+/// packets of its protocol, also of those which the stack handles itself, such as ICMP.
+/// With usnetd, the first IPv4 socket for a protocol requests all its packets for the
+/// addresses of the stack, so that no other usnetd client can receive them.
+#[derive(Debug)]
+pub struct RawSocket {
+    stcpnet: StcpNetRef,
+    socket_handle: SocketHandle,
+    protocol: u8,
+    ipv6: bool,
+    header_included: AtomicBool,
+    nonblocking: AtomicBool,
+    read_timeout: RwLock<Option<Duration>>,
+    ttl: AtomicU32,
+}
+
+impl Drop for RawSocket {
+    fn drop(&mut self) {
+        let &(ref stcpnetref, ref _cond) = &*self.stcpnet.r;
+        let mut stcpnet = stcpnetref.lock();
+        debug!("drop-closing raw socket for IP protocol {}", self.protocol);
+        let _ = stcpnet.sockets.remove(self.socket_handle);
+    }
+}
+
+impl RawSocket {
+    /// Creates an IPv4 socket for the IP protocol number
+    pub fn new(protocol: u8) -> io::Result<RawSocket> {
+        STCP_GLOBAL.bind_raw(protocol, false)
+    }
+    /// Creates an IPv6 socket for the next header number
+    pub fn new_v6(protocol: u8) -> io::Result<RawSocket> {
+        STCP_GLOBAL.bind_raw(protocol, true)
+    }
+    pub fn protocol(&self) -> u8 {
+        self.protocol
+    }
+    /// With the header included, send_to expects a full IP packet and recv_from returns
+    /// the IP header, too (like IP_HDRINCL)
+    /// An unspecified source address in the header is replaced with the local address for
+    /// the destination. The header checksum is filled in by the stack.
+    pub fn set_header_included(&self, header_included: bool) -> io::Result<()> {
+        self.header_included
+            .store(header_included, Ordering::SeqCst);
+        Ok(())
+    }
+    pub fn header_included(&self) -> io::Result<bool> {
+        Ok(self.header_included.load(Ordering::SeqCst))
+    }
+    /// Sends the payload in an IP packet to the address, returns the number of payload bytes
+    /// With the header included, buf is the whole packet and its destination address must
+    /// be the given address. Since smoltcp does not fragment packets, it must fit the MTU.
+    pub fn send_to(&self, buf: &[u8], addr: IpAddr) -> io::Result<usize> {
+        if addr.is_ipv6() != self.ipv6 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "address family differs from the socket",
+            ));
+        }
+        let start = StdInstant::now();
+        let &(ref stcpnetref, ref cond) = &*self.stcpnet.r;
+        let mut stcpnet = stcpnetref.lock();
+        let local = routing::source_address(&mut stcpnet.iface, addr).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::AddrNotAvailable,
+                format!("no local address to reach {}", addr),
+            )
+        })?;
+        let packet = if self.header_included.load(Ordering::SeqCst) {
+            let (src, dst) = rawip::header_addresses(self.ipv6, self.protocol, buf)?;
+            if dst != addr {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "destination address of the header differs from the address",
+                ));
+            }
+            let mut packet = buf.to_vec();
+            if src.is_unspecified() {
+                rawip::set_source_address(&mut packet, local);
+            } else if !stcpnet.iface.has_ip(src) {
+                return Err(io::Error::new(
+                    io::ErrorKind::AddrNotAvailable,
+                    format!("source address {} is not an address of the interface", src),
+                ));
+            }
+            packet
+        } else {
+            let ttl = self.ttl.load(Ordering::SeqCst) as u8;
+            rawip::build_packet(local, addr, self.protocol, ttl, buf)?
+        };
+        loop {
+            stcpnet.check_running()?;
+            let mut r = None;
+            {
+                let mut socket = stcpnet.sockets.get::<SmoltcpRawSocket>(self.socket_handle);
+                if socket.can_send() {
+                    r = Some(
+                        socket
+                            .send_slice(&packet)
+                            .map(|_| buf.len())
+                            .map_err(stack_error),
+                    );
+                }
+            }
+            if let Some(r) = r {
+                if stcpnet.bg_skip_one_wait == Skip::Wait {
+                    let _ = stcpnet.notify_poll.send(b"$").unwrap();
+                } else {
+                    stcpnet.bg_skip_one_wait = Skip::Skip;
+                }
+                return r;
+            }
+            if self.nonblocking.load(Ordering::SeqCst) {
+                return Err(io::Error::new(io::ErrorKind::WouldBlock, "send not ready"));
+            }
+            match *self.read_timeout.read() {
+                Some(timeout) => {
+                    if cond.wait_until(&mut stcpnet, start + timeout).timed_out() {
+                        return Err(io::Error::new(io::ErrorKind::WouldBlock, "send not ready"));
+                    }
+                }
+                None => {
+                    cond.wait(&mut stcpnet);
+                }
+            }
+        }
+    }
+    /// Receives the payload of the next packet and returns its length and source address
+    /// With the header included, the whole IP packet is received. Like for UDP, the rest
+    /// is discarded if buf is too small.
+    pub fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, IpAddr)> {
+        let deadline = self
+            .read_timeout
+            .read()
+            .map(|timeout| StdInstant::now() + timeout);
+        let &(ref stcpnetref, ref cond) = &*self.stcpnet.r;
+        let mut stcpnet = stcpnetref.lock();
+        loop {
+            stcpnet.check_running()?;
+            {
+                let mut socket = stcpnet.sockets.get::<SmoltcpRawSocket>(self.socket_handle);
+                while socket.can_recv() {
+                    let packet = socket.recv().map_err(stack_error)?;
+                    let (from, header_len) = match rawip::parse_packet(packet) {
+                        Some(r) => r,
+                        None => continue,
+                    };
+                    let data = if self.header_included.load(Ordering::SeqCst) {
+                        packet
+                    } else {
+                        &packet[header_len..]
+                    };
+                    let len = data.len().min(buf.len());
+                    buf[..len].copy_from_slice(&data[..len]);
+                    return Ok((len, from));
+                }
+            }
+            if self.nonblocking.load(Ordering::SeqCst) {
+                return Err(io::Error::new(io::ErrorKind::WouldBlock, "recv not ready"));
+            }
+            match deadline {
+                Some(deadline) => {
+                    if cond.wait_until(&mut stcpnet, deadline).timed_out() {
+                        return Err(io::Error::new(io::ErrorKind::WouldBlock, "recv not ready"));
+                    }
+                }
+                None => {
+                    cond.wait(&mut stcpnet);
+                }
+            }
+        }
+    }
+    pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        self.nonblocking.store(nonblocking, Ordering::SeqCst);
+        Ok(())
+    }
+    pub fn set_read_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
+        check_timeout(dur)?;
+        *self.read_timeout.write() = dur;
+        Ok(())
+    }
+    pub fn read_timeout(&self) -> io::Result<Option<Duration>> {
+        Ok(*self.read_timeout.read())
+    }
+    /// Sets the IPv4 TTL or IPv6 hop limit of the packets without included header
+    pub fn set_ttl(&self, ttl: u32) -> io::Result<()> {
+        if ttl == 0 || ttl > 255 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "TTL must be in the range 1..=255",
+            ));
+        }
+        self.ttl.store(ttl, Ordering::SeqCst);
+        Ok(())
+    }
+    pub fn ttl(&self) -> io::Result<u32> {
+        Ok(self.ttl.load(Ordering::SeqCst))
+    }
+}
+
+// same as std, None is used for no timeout
+fn check_timeout(dur: Option<Duration>) -> io::Result<()> {
+    if dur == Some(Duration::new(0, 0)) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "cannot set a 0 duration timeout",
+        ));
+    }
+    Ok(())
+}
+
+/// Sets IP_TOS or IPV6_TCLASS, depending on the address family of the kernel socket
+fn set_traffic_class(socket: &SystemUdpSocket, tos: u32) -> io::Result<()> {
+    let (level, option) = if socket.local_addr()?.is_ipv4() {
+        (libc::IPPROTO_IP, libc::IP_TOS)
+    } else {
+        (libc::IPPROTO_IPV6, libc::IPV6_TCLASS)
+    };
+    let value = tos as c_int;
+    let r = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            level,
+            option,
+            &value as *const c_int as *const libc::c_void,
+            mem::size_of::<c_int>() as libc::socklen_t,
+        )
+    };
+    if r < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+fn set_ipv6_unicast_hops(fd: RawFd, hops: c_int) -> io::Result<()> {
+    let r = unsafe {
+        libc::setsockopt(
+            fd,
+            libc::IPPROTO_IPV6,
+            libc::IPV6_UNICAST_HOPS,
+            &hops as *const c_int as *const libc::c_void,
+            mem::size_of::<c_int>() as libc::socklen_t,
+        )
+    };
+    if r < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+fn ipv6_unicast_hops(fd: RawFd) -> io::Result<u32> {
+    let mut hops: c_int = 0;
+    let mut len = mem::size_of::<c_int>() as libc::socklen_t;
+    let r = unsafe {
+        libc::getsockopt(
+            fd,
+            libc::IPPROTO_IPV6,
+            libc::IPV6_UNICAST_HOPS,
+            &mut hops as *mut c_int as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if r < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(hops as u32)
+    }
+}
+
 // unfortunately need to port Rust std lib type from /src/std/net/addr.rs (copyright MIT/Apache 2.0, see https://thanks.rust-lang.org)
 pub trait UsnetToSocketAddrs {
     type Iter: Iterator<Item = SocketAddr>;
@@ -1611,10 +5024,19 @@ fn usnet_resolve_socket_addr(s: &str, p: u16) -> io::Result<vec::IntoIter<Socket
             return Ok(vec![SocketAddr::new(addr, p)].into_iter());
         }
     }
-    let config = resolve::DnsConfig::with_name_servers(vec![
-        "1.1.1.1:53".parse().unwrap(),
-        "1.0.0.1:53".parse().unwrap(),
-    ]);
+    let name_servers = {
+        let &(ref stcpnetref, ref _cond) = &*STCP_GLOBAL.r;
+        let stcpnet = stcpnetref.lock();
+        // IPv6 name servers from router advertisements cannot be used yet because
+        // the UDP sockets of the userspace network stack are IPv4 only
+        stcpnet
+            .name_servers
+            .iter()
+            .filter(|ns| ns.is_ipv4() || ns.ip().is_loopback())
+            .cloned()
+            .collect::<Vec<_>>()
+    };
+    let config = resolve::DnsConfig::with_name_servers(name_servers);
     let resolver = resolve::DnsResolver::new(config)?;
     let ips = resolver.resolve_host(s)?;
     let v: Vec<_> = ips.map(|a| SocketAddr::new(a, p)).collect();
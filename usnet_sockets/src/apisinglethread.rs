@@ -9,33 +9,46 @@ use smoltcp::time::Instant;
 use std::io::{self, Write};
 use std::os::unix::io::{AsRawFd, RawFd};
 
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
 use std::rc::Rc;
 use std::str::FromStr;
+use std::time::Duration;
 
 extern crate libusnetd;
 use self::libusnetd::ClientMessageIp;
 use smoltcp;
 use smoltcp::socket::SocketSet;
-use smoltcp::socket::{SocketHandle, TcpSocket, TcpSocketBuffer};
-use smoltcp::wire::{IpAddress, IpProtocol};
+use smoltcp::socket::{
+    SocketHandle, TcpSocket, TcpSocketBuffer, UdpPacketMetadata, UdpSocket as SmoltcpUdpSocket,
+    UdpSocketBuffer,
+};
+use smoltcp::wire::{IpAddress, IpEndpoint, IpProtocol};
 
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs};
 
-use std::net::{TcpListener as SystemTcpListener, TcpStream as SystemTcpStream};
+use std::net::{
+    TcpListener as SystemTcpListener, TcpStream as SystemTcpStream, UdpSocket as SystemUdpSocket,
+};
 
 use nix::poll::{poll, PollFd, PollFlags};
 use std::os::raw::c_int;
 
+use crate::apimultithread::{endpoint_to_socket_addr, stack_error, UDP_PAYLOAD_LIMIT};
+use crate::capture::enable_pcap;
+use crate::congestion;
 use crate::device::*;
+use crate::dhcp::{notify as notify_dhcp, DhcpClient};
+use crate::neighbor::NeighborEntry;
+use crate::pmtu::PathMtu;
+use crate::routing;
+use crate::slaac::Slaac;
+use crate::stats::{spawn_stats_logger, InterfaceStats};
 use std::env;
 use std::io::prelude::*;
 use crate::usnetconfig::*;
 
 use rand::{thread_rng, Rng};
 
-use serde_json;
-
 pub struct StcpNetRef {
     pub r: Rc<RefCell<StcpNet>>,
 }
@@ -47,6 +60,24 @@ thread_local! {
   pub static STCP_LOCAL: RefCell<Option<StcpNetRef>> = RefCell::new(None);
 }
 
+/// Creates the stack of the current thread from the given configuration instead of
+/// the environment variables, must be called before the first socket is used
+pub fn init_with(config: StackConfig) -> io::Result<()> {
+    STCP_LOCAL.with(|stcp| {
+        let mut stcp = stcp.borrow_mut();
+        if stcp.is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                "stack already initialized",
+            ));
+        }
+        *stcp = Some(StcpNetRef {
+            r: StcpNet::from_config(config),
+        });
+        Ok(())
+    })
+}
+
 fn init_thread() {
     STCP_LOCAL.with(|stcp| {
         let _ = stcp.borrow_mut().get_or_insert(StcpNetRef {
@@ -55,6 +86,57 @@ fn init_thread() {
     });
 }
 
+fn with_inspection<R, F: FnOnce(&Inspection) -> R>(f: F) -> R {
+    init_thread();
+    STCP_LOCAL.with(|stcp| {
+        let stcp = stcp.borrow();
+        let stcpnet = stcp.as_ref().unwrap().r.borrow();
+        f(stcpnet.iface.inspection())
+    })
+}
+
+/// Returns the entries of the neighbor table of the stack of the current thread
+pub fn neighbors() -> Vec<NeighborEntry> {
+    with_inspection(|inspection| inspection.neighbors.entries())
+}
+
+/// Removes all entries of the neighbor table except the static ones and sends new requests
+/// for the learned entries
+pub fn flush_neighbors() {
+    with_inspection(|inspection| inspection.neighbors.flush())
+}
+
+/// Installs a static neighbor entry, packets to the address are sent to the MAC without
+/// ARP or NDP
+pub fn add_static_neighbor(ip: IpAddr, mac: [u8; 6]) -> io::Result<()> {
+    with_inspection(|inspection| inspection.neighbors.add_static(ip, mac))
+}
+
+/// Removes a static neighbor entry
+pub fn remove_static_neighbor(ip: IpAddr) -> io::Result<()> {
+    with_inspection(|inspection| inspection.neighbors.remove_static(ip))
+}
+
+/// Returns the path MTUs which are lower than the MTU of the interface
+pub fn path_mtus() -> Vec<PathMtu> {
+    with_inspection(|inspection| inspection.pmtu.entries())
+}
+
+/// Removes all learned path MTUs
+pub fn flush_path_mtus() {
+    with_inspection(|inspection| inspection.pmtu.flush())
+}
+
+/// Returns the counters of the interface of the stack of the current thread
+pub fn interface_stats() -> InterfaceStats {
+    init_thread();
+    STCP_LOCAL.with(|stcp| {
+        let stcp = stcp.borrow();
+        let stcpnet = stcp.as_ref().unwrap().r.borrow();
+        stcpnet.iface.interface_stats()
+    })
+}
+
 pub struct TcpListener {}
 
 impl TcpListener {
@@ -64,36 +146,45 @@ impl TcpListener {
     }
 }
 
+// stable identifier of a TCP connection for the log messages, handles are reused
+thread_local! {
+    static NEXT_CONN_ID: Cell<u64> = Cell::new(1);
+}
+
+fn next_conn_id() -> u64 {
+    NEXT_CONN_ID.with(|c| {
+        let conn_id = c.get();
+        c.set(conn_id + 1);
+        conn_id
+    })
+}
+
 pub struct StcpNet {
     sockets: SocketSet<'static>,
     iface: StcpBackendInterface,
     fd: RawFd,
     waiting_poll: bool,
     socket_buffer_size: usize,
+    tcp_rx_buffer_size: usize, // the largest receive window, see tcp_receive_buffer_size
+    dhcp: Option<DhcpClient>,
+    slaac: Option<Slaac>,
 }
 
 impl StcpNet {
     pub fn new_from_env() -> Rc<RefCell<StcpNet>> {
-        let confvar = env::var("USNET_SOCKETS").unwrap();
-        let conf: StcpBackend = serde_json::from_str(&confvar).unwrap();
-        let waiting_poll = env::var("USNET_SOCKETS_WAIT").unwrap_or("true".to_string()) == "true";
-        // Not touched in the evaluation, should normally stay "true" and was only used with "false" for testing busy polling with netmap
-        info!("USNET_SOCKETS_WAIT: {}", waiting_poll);
-        let socket_buffer_size =
-            usize::from_str(&env::var("SOCKET_BUFFER").unwrap_or("500000".to_string()))
-                .expect("SOCKET_BUFFER not an usize");
-        assert!(socket_buffer_size > 0);
-        info!("SOCKET_BUFFER: {}", socket_buffer_size);
-        let reduce_mtu_by_nr =
-            usize::from_str(&env::var("REDUCE_MTU_BY").unwrap_or("0".to_string()))
-                .expect("BG_THREAD_PIN_CPU_ID not a number");
-        let reduce_mtu_by = if reduce_mtu_by_nr == 0 {
-            None
-        } else {
-            Some(reduce_mtu_by_nr)
-        };
-        info!("REDUCE_MTU_BY: {:?}", reduce_mtu_by);
-        StcpNet::new(conf, waiting_poll, socket_buffer_size, reduce_mtu_by)
+        let config = StackConfig::from_env();
+        let stats_log_interval =
+            u64::from_str(&env::var("USNET_STATS_LOG_INTERVAL").unwrap_or("0".to_string()))
+                .expect("USNET_STATS_LOG_INTERVAL not a number");
+        info!("USNET_STATS_LOG_INTERVAL: {}", stats_log_interval);
+        if stats_log_interval > 0 {
+            let _ = spawn_stats_logger(Duration::from_secs(stats_log_interval));
+        }
+        if let Ok(pcap_path) = env::var("USNET_PCAP") {
+            info!("USNET_PCAP: {}", pcap_path);
+            enable_pcap(&pcap_path).expect("cannot create USNET_PCAP file");
+        }
+        StcpNet::from_config(config)
     }
     pub fn new(
         backend: StcpBackend,
@@ -101,23 +192,107 @@ impl StcpNet {
         socket_buffer_size: usize,
         reduce_mtu_by: Option<usize>,
     ) -> Rc<RefCell<StcpNet>> {
-        let (fd, iface_backend) = backend.to_interface(waiting_poll, reduce_mtu_by);
+        StcpNet::from_config(StackConfig {
+            backend: backend,
+            waiting_poll: waiting_poll,
+            spin_budget: Duration::from_secs(0),
+            socket_buffer_size: socket_buffer_size,
+            bg_thread_pin_cpu_id: None,
+            socket_backlog: 1,
+            reduce_mtu_by: reduce_mtu_by,
+            mtu: None,
+            mss_clamp: None,
+            pmtu_probing: false,
+            tcp_sack: true,
+            tcp_window_scaling: true,
+            tcp_receive_window: None,
+            tcp_initial_window: congestion::DEFAULT_INITIAL_WINDOW,
+            name_servers: vec![],
+            slaac: false,
+            raw_sockets: false,
+            loopback: false,
+            usnet_prefixes: vec![],
+            addresses: vec![],
+            routes: vec![],
+            queues: 1,
+        })
+    }
+    /// The name servers and the background thread options are not used by the singlethread API,
+    /// which also runs only on the first of multiple queues
+    pub fn from_config(config: StackConfig) -> Rc<RefCell<StcpNet>> {
+        if config.queues > 1 {
+            warn!("the singlethread API only uses the first queue");
+        }
+        let uses_dhcp = config.backend.uses_dhcp();
+        let (fd, mut iface_backend) = config
+            .backend
+            .to_interface(config.waiting_poll, config.reduce_mtu_by)
+            .unwrap_or_else(|err| panic!("backend setup failed: {}", err));
+        iface_backend.set_mtu_limit(config.mtu);
+        routing::configure(&mut iface_backend, &config.addresses, &config.routes)
+            .expect("invalid address configuration");
+        iface_backend.inspection().pmtu.configure(
+            config.mss_clamp,
+            config.pmtu_probing,
+            config.tcp_sack,
+            config.tcp_window_scaling,
+        );
         info!("created backend: {}", iface_backend);
+        let mut sockets = SocketSet::new(vec![]);
+        let dhcp = if uses_dhcp {
+            Some(DhcpClient::new(&mut sockets, Instant::now()))
+        } else {
+            None
+        };
+        let slaac = if config.slaac {
+            Some(Slaac::new(
+                &mut sockets,
+                iface_backend.ethernet_addr(),
+                config.mtu,
+                Instant::now(),
+            ))
+        } else {
+            None
+        };
         Rc::new(RefCell::new(StcpNet {
-            sockets: SocketSet::new(vec![]),
+            sockets: sockets,
             fd: fd,
             iface: iface_backend,
-            waiting_poll: waiting_poll,
-            socket_buffer_size: socket_buffer_size,
+            waiting_poll: config.waiting_poll,
+            socket_buffer_size: config.socket_buffer_size,
+            tcp_rx_buffer_size: config.tcp_receive_buffer_size(),
+            dhcp: dhcp,
+            slaac: slaac,
         }))
     }
     fn create_socket(&mut self) -> SocketHandle {
-        let tcp_rx_buffer = TcpSocketBuffer::new(vec![0; self.socket_buffer_size]);
+        let tcp_rx_buffer = TcpSocketBuffer::new(vec![0; self.tcp_rx_buffer_size]);
         let tcp_tx_buffer = TcpSocketBuffer::new(vec![0; self.socket_buffer_size]);
         let tcp_socket = TcpSocket::new(tcp_rx_buffer, tcp_tx_buffer);
         self.sockets.add(tcp_socket)
     }
+    fn poll(&mut self) -> smoltcp::Result<bool> {
+        let r = self.iface.poll(&mut self.sockets, Instant::now());
+        if let Some(ref mut dhcp) = self.dhcp {
+            if let Some(status) = dhcp.poll(&mut self.iface, &mut self.sockets, Instant::now()) {
+                notify_dhcp(&status);
+            }
+        }
+        if let Some(ref mut slaac) = self.slaac {
+            let _ = slaac.poll(&mut self.iface, &mut self.sockets, Instant::now());
+        }
+        r
+    }
     pub fn poll_wait(&mut self, once: bool, poll_other: Option<&[RawFd]>) {
+        self.poll_wait_until(once, poll_other, None);
+    }
+    /// Like poll_wait but returns at the deadline at the latest, e.g., for a read timeout
+    pub fn poll_wait_until(
+        &mut self,
+        once: bool,
+        poll_other: Option<&[RawFd]>,
+        deadline: Option<std::time::Instant>,
+    ) {
         let mut fds = vec![PollFd::new(self.fd, PollFlags::POLLIN)];
         if let Some(poll_others) = poll_other {
             fds.extend(
@@ -126,7 +301,7 @@ impl StcpNet {
                     .map(|fd| PollFd::new(*fd, PollFlags::POLLIN)),
             );
         }
-        while !match self.iface.poll(&mut self.sockets, Instant::now()) {
+        while !match self.poll() {
             Err(err) => {
                 debug!("poll result: {}", err);
                 true
@@ -147,11 +322,40 @@ impl StcpNet {
             if !self.waiting_poll {
                 break;
             }
-            let d = self.iface.poll_delay(&self.sockets, Instant::now());
-            let d_cint = match d {
+            let wait_limit = match deadline {
+                Some(deadline) => {
+                    let now = std::time::Instant::now();
+                    if now >= deadline {
+                        break;
+                    }
+                    Some(deadline - now)
+                }
+                None => None,
+            };
+            let now = Instant::now();
+            let d = self.iface.poll_delay(&self.sockets, now);
+            let d = match self.dhcp {
+                Some(ref dhcp) => {
+                    let dhcp_delay = dhcp.next_poll(now);
+                    Some(d.map_or(dhcp_delay, |d| d.min(dhcp_delay)))
+                }
+                None => d,
+            };
+            let d = match self.slaac.as_ref().and_then(|s| s.next_poll(now)) {
+                Some(slaac_delay) => Some(d.map_or(slaac_delay, |d| d.min(slaac_delay))),
+                None => d,
+            };
+            let mut d_cint = match d {
                 Some(duration) => duration.total_millis() as c_int,
                 None => -1 as c_int,
             };
+            if let Some(limit) = wait_limit {
+                // rounded up, so that the deadline has passed when poll returns
+                let limit_ms = ((limit.as_micros() + 999) / 1000) as c_int;
+                if d_cint < 0 || limit_ms < d_cint {
+                    d_cint = limit_ms;
+                }
+            }
             poll(&mut fds[..], d_cint).expect("wait error");
         }
     }
@@ -167,8 +371,13 @@ impl StcpNetRef {
         let lolisten;
         {
             let mut stcpnet = self.r.borrow_mut();
+            routing::check_local_address(&stcpnet.iface, ipa)?;
 
-            let ipv4 = stcpnet.iface.ips()[0].address();
+            let ipv4 = if ipa.is_unspecified() {
+                stcpnet.iface.ips()[0].address()
+            } else {
+                IpAddress::from(ipa)
+            };
             match stcpnet.iface.control() {
                 Some(control) => {
                     if sockaddr.port() != 0 {
@@ -204,6 +413,7 @@ impl StcpNetRef {
             if sockaddr.ip().is_loopback() {
                 return Ok(StcpListenerRef {
                     l: Rc::new(RefCell::new(StcpListener {
+                        listen_addr: IpAddress::Unspecified,
                         port: sockaddr.port(),
                         stcpnet: self.r.clone(),
                         lo: lolisten_res?,
@@ -217,7 +427,12 @@ impl StcpNetRef {
             tcp_handle = stcpnet.create_socket();
             {
                 let mut socket = stcpnet.sockets.get::<TcpSocket>(tcp_handle);
-                socket.listen(sockaddr.port()).unwrap();
+                socket
+                    .listen(IpEndpoint::new(
+                        routing::endpoint_address(ipa),
+                        sockaddr.port(),
+                    ))
+                    .unwrap();
             }
             stcpnet.iface.add_port_match(
                 ipa,
@@ -229,6 +444,7 @@ impl StcpNetRef {
         }
         Ok(StcpListenerRef {
             l: Rc::new(RefCell::new(StcpListener {
+                listen_addr: routing::endpoint_address(ipa),
                 port: sockaddr.port(),
                 stcpnet: self.r.clone(),
                 listen_handles: Some(vec![tcp_handle]),
@@ -239,21 +455,26 @@ impl StcpNetRef {
     fn connect<A: ToSocketAddrs>(&self, addr: A) -> io::Result<TcpStream> {
         let addr = addr.to_socket_addrs().unwrap().next().unwrap();
 
-        let ipv4 = {
+        let is_own_ip = {
             let stcpnet = self.r.borrow();
-            stcpnet.iface.ips()[0].address()
+            stcpnet.iface.has_ip(addr.ip())
         };
-        if addr.ip().is_loopback() || IpAddress::from(addr.ip()) == ipv4 {
+        if addr.ip().is_loopback() || is_own_ip {
             return SystemTcpStream::connect(addr).map(|s| TcpStream::System(s));
         }
 
         let tcp_handle;
+        let conn_id = next_conn_id();
         {
             let mut stcpnet = self.r.borrow_mut();
 
             let mut local_port: u16 = 1u16 + thread_rng().gen_range(1024..std::u16::MAX);
 
-            let ipv4 = stcpnet.iface.ips()[0].address();
+            let own_ip = routing::source_address(&mut stcpnet.iface, addr.ip());
+            let ipv4 = match own_ip {
+                Some(ip) => IpAddress::from(ip),
+                None => stcpnet.iface.ips()[0].address(),
+            };
             match stcpnet.iface.control() {
                 Some(control) => {
                     local_port = find_free_connecting_or_listening_port_for(
@@ -268,7 +489,17 @@ impl StcpNetRef {
             tcp_handle = stcpnet.create_socket();
             let mut socket = stcpnet.sockets.get::<TcpSocket>(tcp_handle);
 
-            socket.connect(addr, local_port).unwrap();
+            let local_addr = own_ip.map_or(IpAddress::Unspecified, IpAddress::from);
+            socket
+                .connect(addr, IpEndpoint::new(local_addr, local_port))
+                .unwrap();
+            stack_log!(
+                debug,
+                "[conn {}] connecting from port {} to {}",
+                conn_id,
+                local_port,
+                addr
+            );
         }
         loop {
             {
@@ -280,9 +511,11 @@ impl StcpNetRef {
                 }
             }
         }
+        stack_log!(debug, "[conn {}] established", conn_id);
         Ok(StcpStream {
             stcpnet: self.r.clone(),
             sockethandle: tcp_handle,
+            conn_id: conn_id,
         })
         .map(|s| TcpStream::Stcp(s))
     }
@@ -292,12 +525,14 @@ pub struct StcpListener {
     stcpnet: Rc<RefCell<StcpNet>>,
     listen_handles: Option<Vec<SocketHandle>>,
     lo: SystemTcpListener,
+    listen_addr: IpAddress, // unspecified for all interface addresses
     port: u16,
 }
 
 pub struct StcpStream {
     sockethandle: SocketHandle,
     stcpnet: Rc<RefCell<StcpNet>>,
+    conn_id: u64,
 }
 
 pub struct StcpListenerIt {
@@ -350,12 +585,21 @@ impl StcpListenerRef {
                             _ => panic!("not covered"),
                         };
                         let sadd: SocketAddr = SocketAddr::new(stip, ep.port);
+                        let conn_id = next_conn_id();
+                        stack_log!(
+                            debug,
+                            "[conn {}] accepted from {} on port {}",
+                            conn_id,
+                            sadd,
+                            listener.port
+                        );
                         r = Some((
                             i,
                             Ok((
                                 TcpStream::Stcp(StcpStream {
                                     sockethandle: *handle,
                                     stcpnet: listener.stcpnet.clone(),
+                                    conn_id: conn_id,
                                 }),
                                 sadd,
                             )),
@@ -372,7 +616,9 @@ impl StcpListenerRef {
                         let mut stcpnet = listener.stcpnet.borrow_mut();
                         tcp_handle = stcpnet.create_socket();
                         let mut socket = stcpnet.sockets.get::<TcpSocket>(tcp_handle);
-                        socket.listen(listener.port).unwrap();
+                        socket
+                            .listen(IpEndpoint::new(listener.listen_addr, listener.port))
+                            .unwrap();
                     }
                     listener.listen_handles.as_mut().unwrap().push(tcp_handle);
                     return r;
@@ -493,7 +739,7 @@ impl Drop for StcpStream {
             socket.close();
         }
         stcpnet.sockets.release(self.sockethandle);
-        debug!("drop-closing");
+        debug!("[conn {}] drop-closing", self.conn_id);
     }
 }
 
@@ -590,3 +836,248 @@ impl StcpStream {
         Ok(())
     }
 }
+
+// UDP
+
+thread_local! {
+    static RECV_COPY: RefCell<Vec<u8>> = RefCell::new(Vec::new());
+}
+
+/// UDP socket, on the kernel for a loopback address and on the stack otherwise
+/// The socket operations poll the stack of the current thread: a blocking receive until a
+/// datagram arrives or the read timeout passes, a nonblocking one once without waiting.
+pub enum UdpSocket {
+    System(SystemUdpSocket),
+    Stcp(StcpUdpSocket),
+}
+
+pub struct StcpUdpSocket {
+    sockethandle: SocketHandle,
+    stcpnet: Rc<RefCell<StcpNet>>,
+    lo: SystemUdpSocket, // port on the kernel loopback for loopback peers, polled with the stack
+    local: SocketAddr,
+    nonblocking: Cell<bool>,
+    read_timeout: Cell<Option<Duration>>,
+}
+
+impl StcpNetRef {
+    fn bind_udp<A: ToSocketAddrs>(&self, addr: A) -> io::Result<UdpSocket> {
+        let mut sockaddr = addr
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no address to bind to"))?;
+        let ipa = sockaddr.ip();
+        if ipa.is_loopback() {
+            return SystemUdpSocket::bind(sockaddr).map(|s| UdpSocket::System(s));
+        }
+        if ipa.is_ipv6() {
+            return Err(io::Error::new(io::ErrorKind::Other, "ipv6 bind"));
+        }
+        let mut stcpnet = self.r.borrow_mut();
+        routing::check_local_address(&stcpnet.iface, ipa)?;
+
+        let find_random = sockaddr.port() == 0;
+        let lo = loop {
+            if find_random {
+                let local_port: u16 = 1u16 + thread_rng().gen_range(1024..std::u16::MAX);
+                sockaddr.set_port(local_port);
+            }
+            let loaddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), sockaddr.port());
+            let r = SystemUdpSocket::bind(loaddr).and_then(|lo| {
+                stcpnet.iface.add_port_match(
+                    ipa,
+                    Some(sockaddr.port()),
+                    None,
+                    None,
+                    IpProtocol::Udp,
+                )?;
+                Ok(lo)
+            });
+            match r {
+                Ok(lo) => break lo,
+                Err(e) => {
+                    if !find_random {
+                        return Err(e);
+                    }
+                }
+            }
+        };
+        lo.set_nonblocking(true)?;
+
+        let udp_rx_buffer = UdpSocketBuffer::new(
+            vec![UdpPacketMetadata::EMPTY; 1000],
+            vec![0; stcpnet.socket_buffer_size],
+        );
+        let udp_tx_buffer = UdpSocketBuffer::new(
+            vec![UdpPacketMetadata::EMPTY; 1000],
+            vec![0; stcpnet.socket_buffer_size],
+        );
+        let udp_handle = stcpnet
+            .sockets
+            .add(SmoltcpUdpSocket::new(udp_rx_buffer, udp_tx_buffer));
+        stcpnet
+            .sockets
+            .get::<SmoltcpUdpSocket>(udp_handle)
+            .bind(IpEndpoint::new(
+                routing::endpoint_address(ipa),
+                sockaddr.port(),
+            ))
+            .unwrap();
+        debug!("UDP socket {} bound to {}", udp_handle, sockaddr);
+        Ok(UdpSocket::Stcp(StcpUdpSocket {
+            sockethandle: udp_handle,
+            stcpnet: self.r.clone(),
+            lo: lo,
+            local: sockaddr,
+            nonblocking: Cell::new(false),
+            read_timeout: Cell::new(None),
+        }))
+    }
+}
+
+impl UdpSocket {
+    pub fn bind<A: ToSocketAddrs>(addr: A) -> io::Result<UdpSocket> {
+        init_thread();
+        STCP_LOCAL.with(|stcp| stcp.borrow().as_ref().unwrap().bind_udp(addr))
+    }
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        match self {
+            UdpSocket::Stcp(slf) => Ok(slf.local),
+            UdpSocket::System(slf) => slf.local_addr(),
+        }
+    }
+    pub fn send_to<A: ToSocketAddrs>(&self, buf: &[u8], addr: A) -> io::Result<usize> {
+        let addr = addr
+            .to_socket_addrs()?
+            .next()
+            .ok_or(io::Error::new(io::ErrorKind::Other, "to sock addr empty"))?;
+        match self {
+            UdpSocket::Stcp(slf) => slf.send_to(buf, addr),
+            UdpSocket::System(slf) => slf.send_to(buf, addr),
+        }
+    }
+    pub fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        self.recv_from_trunc(buf).map(|(n, _, addr)| (n, addr))
+    }
+    /// Receives a datagram like recv_from and also returns its full length, which is larger
+    /// than the received length when the buffer was too small and the rest was discarded
+    pub fn recv_from_trunc(&self, buf: &mut [u8]) -> io::Result<(usize, usize, SocketAddr)> {
+        match self {
+            UdpSocket::Stcp(slf) => slf.recv_from_trunc(buf),
+            UdpSocket::System(slf) => recv_system_trunc(slf, buf)?
+                .ok_or_else(|| io::Error::new(io::ErrorKind::WouldBlock, "no datagram received")),
+        }
+    }
+    pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        match self {
+            UdpSocket::Stcp(slf) => {
+                slf.nonblocking.set(nonblocking);
+                Ok(())
+            }
+            UdpSocket::System(slf) => slf.set_nonblocking(nonblocking),
+        }
+    }
+    pub fn set_read_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
+        match self {
+            UdpSocket::Stcp(slf) => {
+                if dur == Some(Duration::from_secs(0)) {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "cannot set a 0 duration timeout",
+                    ));
+                }
+                slf.read_timeout.set(dur);
+                Ok(())
+            }
+            UdpSocket::System(slf) => slf.set_read_timeout(dur),
+        }
+    }
+    pub fn read_timeout(&self) -> io::Result<Option<Duration>> {
+        match self {
+            UdpSocket::Stcp(slf) => Ok(slf.read_timeout.get()),
+            UdpSocket::System(slf) => slf.read_timeout(),
+        }
+    }
+}
+
+/// Receives into a copy buffer to learn the full length of the datagram, None if the
+/// nonblocking socket has none
+fn recv_system_trunc(
+    socket: &SystemUdpSocket,
+    buf: &mut [u8],
+) -> io::Result<Option<(usize, usize, SocketAddr)>> {
+    let mut copy = RECV_COPY.with(|c| c.replace(Vec::new()));
+    copy.resize(UDP_PAYLOAD_LIMIT, 0);
+    let r = match socket.recv_from(&mut copy) {
+        Ok((len, addr)) => {
+            let n = len.min(buf.len());
+            buf[..n].copy_from_slice(&copy[..n]);
+            Ok(Some((n, len, addr)))
+        }
+        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Ok(None),
+        Err(e) => Err(e),
+    };
+    RECV_COPY.with(|c| *c.borrow_mut() = copy);
+    r
+}
+
+impl Drop for StcpUdpSocket {
+    fn drop(&mut self) {
+        let mut stcpnet = self.stcpnet.borrow_mut();
+        let _ = stcpnet.sockets.remove(self.sockethandle);
+        debug!("drop-closing UDP");
+    }
+}
+
+impl StcpUdpSocket {
+    fn send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        if addr.ip().is_loopback() {
+            return self.lo.send_to(buf, addr);
+        }
+        let mut stcpnet = self.stcpnet.borrow_mut();
+        stcpnet
+            .sockets
+            .get::<SmoltcpUdpSocket>(self.sockethandle)
+            .send_slice(buf, addr.into())
+            .map_err(stack_error)?;
+        // the datagram goes out now and not with the next receive
+        stcpnet.poll_wait(true, None);
+        Ok(buf.len())
+    }
+    fn recv_from_trunc(&self, buf: &mut [u8]) -> io::Result<(usize, usize, SocketAddr)> {
+        let deadline = self
+            .read_timeout
+            .get()
+            .map(|timeout| std::time::Instant::now() + timeout);
+        let mut first = true;
+        loop {
+            {
+                let mut stcpnet = self.stcpnet.borrow_mut();
+                let mut socket = stcpnet.sockets.get::<SmoltcpUdpSocket>(self.sockethandle);
+                if socket.can_recv() {
+                    let (payload, endpoint) = socket.recv().map_err(stack_error)?;
+                    let n = payload.len().min(buf.len());
+                    buf[..n].copy_from_slice(&payload[..n]);
+                    return Ok((n, payload.len(), endpoint_to_socket_addr(&endpoint)));
+                }
+            }
+            if let Some(r) = recv_system_trunc(&self.lo, buf)? {
+                return Ok(r);
+            }
+            let expired = deadline.map_or(false, |d| std::time::Instant::now() >= d);
+            if !first && (self.nonblocking.get() || expired) {
+                return Err(io::Error::new(
+                    io::ErrorKind::WouldBlock,
+                    "no datagram received",
+                ));
+            }
+            // the first poll only takes what has arrived already
+            self.stcpnet.borrow_mut().poll_wait_until(
+                first,
+                Some(&[self.lo.as_raw_fd()]),
+                deadline,
+            );
+            first = false;
+        }
+    }
+}
@@ -0,0 +1,233 @@
+/// Packet capture for the userspace network stack
+/// Every frame passing the device layer can be handed to a callback or appended to a
+/// pcap file. The pcap file is written by a separate thread which is fed through a
+/// bounded queue, so that slow disk I/O does not stall the datapath. If the queue
+/// is full, the frame is not captured and the capture_dropped counter is increased.
+use parking_lot::RwLock;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::iter;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TrySendError};
+use std::sync::Arc;
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::stats::{inc, COUNTERS};
+
+const PCAP_QUEUE_SIZE: usize = 4096;
+const PCAP_SNAPLEN: u32 = 65535;
+const PCAP_LINKTYPE_ETHERNET: u32 = 1;
+
+/// Whether a frame was received or transmitted by the stack
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    Rx,
+    Tx,
+}
+
+pub type CaptureCallback = Arc<dyn Fn(&[u8], Direction) + Send + Sync>;
+
+struct CaptureFrame {
+    timestamp: (u32, u32),
+    orig_len: u32,
+    data: Vec<u8>,
+}
+
+#[derive(Default)]
+struct Capture {
+    pcap: Option<SyncSender<CaptureFrame>>,
+    callback: Option<CaptureCallback>,
+}
+
+static CAPTURE_ACTIVE: AtomicBool = AtomicBool::new(false);
+
+lazy_static! {
+    static ref CAPTURE: RwLock<Capture> = RwLock::new(Capture::default());
+}
+
+fn update_active(capture: &Capture) {
+    CAPTURE_ACTIVE.store(
+        capture.pcap.is_some() || capture.callback.is_some(),
+        Ordering::Relaxed,
+    );
+}
+
+/// Appends all frames of the stack to a new pcap file at the given path
+/// (replaces an active pcap capture)
+pub fn enable_pcap<P: AsRef<Path>>(path: P) -> io::Result<()> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    write_pcap_header(&mut writer)?;
+    writer.flush()?;
+    let (sender, receiver) = sync_channel(PCAP_QUEUE_SIZE);
+    thread::spawn(move || pcap_writer(writer, receiver));
+    let mut capture = CAPTURE.write();
+    capture.pcap = Some(sender);
+    update_active(&capture);
+    Ok(())
+}
+
+/// Stops the pcap capture, the file is closed when the queue is drained
+pub fn disable_pcap() {
+    let mut capture = CAPTURE.write();
+    capture.pcap = None;
+    update_active(&capture);
+}
+
+/// Calls the function for every frame of the stack (replaces an active callback)
+/// It runs on the datapath and should return quickly.
+pub fn set_capture_callback<F: Fn(&[u8], Direction) + Send + Sync + 'static>(f: F) {
+    let mut capture = CAPTURE.write();
+    capture.callback = Some(Arc::new(f));
+    update_active(&capture);
+}
+
+/// Removes the callback set with set_capture_callback, frames which are being
+/// passed to it on other threads may still reach it
+pub fn clear_capture_callback() {
+    let mut capture = CAPTURE.write();
+    capture.callback = None;
+    update_active(&capture);
+}
+
+/// Called by the device layer for every received or transmitted frame
+pub(crate) fn capture(frame: &[u8], direction: Direction) {
+    if !CAPTURE_ACTIVE.load(Ordering::Relaxed) {
+        return;
+    }
+    // not called under the lock, so that the callback can replace or clear itself
+    let (callback, pcap) = {
+        let capture = CAPTURE.read();
+        (capture.callback.clone(), capture.pcap.clone())
+    };
+    if let Some(callback) = callback {
+        callback(frame, direction);
+    }
+    if let Some(sender) = pcap {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let snap = frame.len().min(PCAP_SNAPLEN as usize);
+        let record = CaptureFrame {
+            timestamp: (now.as_secs() as u32, now.subsec_micros()),
+            orig_len: frame.len() as u32,
+            data: frame[..snap].to_vec(),
+        };
+        match sender.try_send(record) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) | Err(TrySendError::Disconnected(_)) => {
+                inc(&COUNTERS.capture_dropped)
+            }
+        }
+    }
+}
+
+fn write_pcap_header<W: Write>(w: &mut W) -> io::Result<()> {
+    w.write_all(&0xa1b2c3d4u32.to_ne_bytes())?; // magic, microsecond resolution
+    w.write_all(&2u16.to_ne_bytes())?; // major version
+    w.write_all(&4u16.to_ne_bytes())?; // minor version
+    w.write_all(&0i32.to_ne_bytes())?; // timezone offset
+    w.write_all(&0u32.to_ne_bytes())?; // timestamp accuracy
+    w.write_all(&PCAP_SNAPLEN.to_ne_bytes())?;
+    w.write_all(&PCAP_LINKTYPE_ETHERNET.to_ne_bytes())
+}
+
+fn pcap_writer<W: Write>(mut w: W, receiver: Receiver<CaptureFrame>) {
+    while let Ok(frame) = receiver.recv() {
+        // write all queued frames before flushing
+        let r = iter::once(frame)
+            .chain(receiver.try_iter())
+            .try_for_each(|f| write_pcap_record(&mut w, &f))
+            .and_then(|_| w.flush());
+        if let Err(err) = r {
+            error!("pcap capture stopped: {}", err);
+            return;
+        }
+    }
+    let _ = w.flush();
+}
+
+fn write_pcap_record<W: Write>(w: &mut W, frame: &CaptureFrame) -> io::Result<()> {
+    let (secs, micros) = frame.timestamp;
+    w.write_all(&secs.to_ne_bytes())?;
+    w.write_all(&micros.to_ne_bytes())?;
+    w.write_all(&(frame.data.len() as u32).to_ne_bytes())?;
+    w.write_all(&frame.orig_len.to_ne_bytes())?;
+    w.write_all(&frame.data)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        capture, clear_capture_callback, pcap_writer, set_capture_callback, write_pcap_header,
+        CaptureFrame, Direction,
+    };
+    use std::convert::TryInto;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::mpsc::sync_channel;
+    use std::sync::Arc;
+
+    fn u32_at(data: &[u8], offset: usize) -> u32 {
+        u32::from_ne_bytes(data[offset..offset + 4].try_into().unwrap())
+    }
+
+    #[test]
+    fn test_pcap_layout() {
+        let mut file = Vec::new();
+        write_pcap_header(&mut file).unwrap();
+        let (sender, receiver) = sync_channel(2);
+        sender
+            .send(CaptureFrame {
+                timestamp: (1_500_000_000, 250),
+                orig_len: 70000,
+                data: vec![0xab; 4],
+            })
+            .unwrap();
+        sender
+            .send(CaptureFrame {
+                timestamp: (1_500_000_001, 999_999),
+                orig_len: 2,
+                data: vec![1, 2],
+            })
+            .unwrap();
+        drop(sender);
+        pcap_writer(&mut file, receiver);
+
+        assert_eq!(file.len(), 24 + 16 + 4 + 16 + 2);
+        assert_eq!(u32_at(&file, 0), 0xa1b2c3d4);
+        assert_eq!(&file[4..6], &2u16.to_ne_bytes());
+        assert_eq!(&file[6..8], &4u16.to_ne_bytes());
+        assert_eq!(&file[8..16], &[0; 8]);
+        assert_eq!(u32_at(&file, 16), 65535);
+        assert_eq!(u32_at(&file, 20), 1);
+        // the record header holds the captured and the original length
+        let record = &file[24..];
+        assert_eq!(u32_at(record, 0), 1_500_000_000);
+        assert_eq!(u32_at(record, 4), 250);
+        assert_eq!(u32_at(record, 8), 4);
+        assert_eq!(u32_at(record, 12), 70000);
+        assert_eq!(&record[16..20], &[0xab; 4]);
+        let record = &record[20..];
+        assert_eq!(u32_at(record, 0), 1_500_000_001);
+        assert_eq!(u32_at(record, 4), 999_999);
+        assert_eq!(u32_at(record, 8), 2);
+        assert_eq!(u32_at(record, 12), 2);
+        assert_eq!(&record[16..], &[1, 2]);
+    }
+
+    #[test]
+    fn test_callback_clears_itself() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counter = calls.clone();
+        set_capture_callback(move |frame, direction| {
+            assert_eq!(frame, &[1, 2, 3]);
+            assert_eq!(direction, Direction::Rx);
+            counter.fetch_add(1, Ordering::SeqCst);
+            clear_capture_callback();
+        });
+        capture(&[1, 2, 3], Direction::Rx);
+        capture(&[1, 2, 3], Direction::Rx);
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+}
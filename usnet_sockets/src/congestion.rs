@@ -0,0 +1,485 @@
+/// Congestion control for the TCP connections of the stack
+/// smoltcp sends as much as the receive window of the peer allows, which overloads the
+/// bottleneck of paths with a higher latency until packets are lost. Therefore the stack
+/// passes only as much data to the smoltcp socket of a connection as its congestion window
+/// allows, so that the data in flight and waiting in the socket does not exceed it. The
+/// window grows with the acknowledged data, which is the data that left the socket, and it
+/// is reduced once per window of data when the device layer sees a retransmission of the
+/// connection, because smoltcp does not report them.
+use parking_lot::Mutex;
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use smoltcp::wire::{IpProtocol, TcpPacket, TcpSeqNumber};
+
+use crate::frame::Headers;
+
+/// Congestion control algorithm of a TCP connection on the stack
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub enum Congestion {
+    /// No congestion window, the sender is only limited by the receive window of the peer
+    None,
+    /// Slow start and additive increase, halving the window on loss (RFC 5681)
+    Reno,
+    /// Window growth by a cubic function of the time since the last loss (RFC 8312), which
+    /// recovers faster on paths with a large bandwidth-delay product
+    Cubic,
+}
+
+impl Default for Congestion {
+    fn default() -> Congestion {
+        Congestion::None
+    }
+}
+
+// segment size for the window, the one of the Ethernet MTU
+pub(crate) const DEFAULT_MSS: usize = 1460;
+// initial window of RFC 6928 in segments
+pub(crate) const DEFAULT_INITIAL_WINDOW: usize = 10;
+const MIN_WINDOW: usize = 2;
+// constants of RFC 8312
+const CUBIC_C: f64 = 0.4;
+const CUBIC_BETA: f64 = 0.7;
+
+static DEFAULT_CONGESTION: AtomicUsize = AtomicUsize::new(0);
+
+/// Sets the congestion control for the TCP connections created from now on, existing
+/// ones keep theirs (see TcpStream::set_congestion)
+pub fn set_default_congestion(congestion: Congestion) {
+    let value = match congestion {
+        Congestion::None => 0,
+        Congestion::Reno => 1,
+        Congestion::Cubic => 2,
+    };
+    DEFAULT_CONGESTION.store(value, Ordering::Relaxed);
+}
+
+/// Returns the congestion control for new TCP connections, see set_default_congestion
+pub fn default_congestion() -> Congestion {
+    match DEFAULT_CONGESTION.load(Ordering::Relaxed) {
+        1 => Congestion::Reno,
+        2 => Congestion::Cubic,
+        _ => Congestion::None,
+    }
+}
+
+/// Congestion window of a connection, fed with the amount of data in the smoltcp socket
+/// and the retransmissions
+#[derive(Debug)]
+pub(crate) struct Window {
+    algorithm: Congestion,
+    mss: usize,
+    cwnd: usize,
+    ssthresh: Option<usize>,
+    sent: u64,  // bytes passed to the socket
+    acked: u64, // bytes which left the socket
+    // the window is not reduced again until the data sent before the loss is acknowledged
+    recovery: u64,
+    growth: usize, // acknowledged bytes towards the next Reno increase
+    // CUBIC window before the last reduction in segments, and the start of the growth
+    // period with the time K to reach it again and the window it started from
+    w_max: f64,
+    epoch: Option<(Instant, f64, f64)>,
+    srtt: Option<Duration>,
+    sample: Option<(u64, Instant)>, // end of the data of the running RTT sample
+    losses: u64,
+    retransmits: u64,
+}
+
+impl Window {
+    /// Starts with the initial window in segments
+    pub(crate) fn new(algorithm: Congestion, mss: usize, initial_window: usize) -> Window {
+        Window {
+            algorithm: algorithm,
+            mss: mss,
+            cwnd: initial_window * mss,
+            ssthresh: None,
+            sent: 0,
+            acked: 0,
+            recovery: 0,
+            growth: 0,
+            w_max: 0.0,
+            epoch: None,
+            srtt: None,
+            sample: None,
+            losses: 0,
+            retransmits: 0,
+        }
+    }
+    /// Changes the algorithm, the window is kept and grows with the new one
+    pub(crate) fn set_algorithm(&mut self, algorithm: Congestion) {
+        self.algorithm = algorithm;
+        self.epoch = None;
+        self.growth = 0;
+        if self.cwnd < MIN_WINDOW * self.mss {
+            self.cwnd = MIN_WINDOW * self.mss;
+        }
+    }
+    /// Replaces the initial window in segments, returns false once data was sent
+    pub(crate) fn set_initial_window(&mut self, initial_window: usize) -> bool {
+        if self.sent > 0 {
+            return false;
+        }
+        self.cwnd = initial_window * self.mss;
+        true
+    }
+    pub(crate) fn algorithm(&self) -> Congestion {
+        self.algorithm
+    }
+    /// Congestion window in bytes, None without congestion control
+    pub(crate) fn cwnd(&self) -> Option<usize> {
+        match self.algorithm {
+            Congestion::None => None,
+            _ => Some(self.cwnd),
+        }
+    }
+    /// Slow start threshold in bytes, None until the first loss
+    pub(crate) fn ssthresh(&self) -> Option<usize> {
+        self.ssthresh
+    }
+    pub(crate) fn srtt(&self) -> Option<Duration> {
+        self.srtt
+    }
+    pub(crate) fn losses(&self) -> u64 {
+        self.losses
+    }
+    pub(crate) fn in_flight(&self) -> usize {
+        (self.sent - self.acked) as usize
+    }
+    /// How many more bytes may be passed to the socket
+    pub(crate) fn available(&self) -> usize {
+        match self.algorithm {
+            Congestion::None => usize::max_value(),
+            _ => self.cwnd.saturating_sub(self.in_flight()),
+        }
+    }
+    /// Accounts data passed to the socket
+    pub(crate) fn on_sent(&mut self, len: usize, now: Instant) {
+        self.sent += len as u64;
+        if self.sample.is_none() && len > 0 {
+            self.sample = Some((self.sent, now));
+        }
+    }
+    /// Accounts the data that left the socket, given the bytes which are still in it
+    pub(crate) fn on_queue(&mut self, queued: usize, now: Instant) {
+        let acked = self.sent.saturating_sub(queued as u64);
+        if acked <= self.acked {
+            return;
+        }
+        // data sent before the last loss does not grow the window
+        let newly = acked.saturating_sub(self.acked.max(self.recovery)) as usize;
+        self.acked = acked;
+        if let Some((end, start)) = self.sample {
+            if acked >= end {
+                self.update_rtt(now.duration_since(start));
+                self.sample = None;
+            }
+        }
+        if newly > 0 {
+            self.grow(newly, now);
+        }
+    }
+    /// Reduces the window if the count of retransmissions increased
+    pub(crate) fn on_retransmits(&mut self, retransmits: u64) {
+        if retransmits <= self.retransmits {
+            return;
+        }
+        self.retransmits = retransmits;
+        // the RTT of retransmitted data is ambiguous
+        self.sample = None;
+        if self.acked < self.recovery {
+            return; // the same loss event
+        }
+        self.recovery = self.sent;
+        self.losses += 1;
+        let min = MIN_WINDOW * self.mss;
+        match self.algorithm {
+            Congestion::None => {}
+            Congestion::Reno => {
+                let ssthresh = (self.in_flight() / 2).max(min);
+                self.ssthresh = Some(ssthresh);
+                self.cwnd = ssthresh;
+            }
+            Congestion::Cubic => {
+                let segments = self.cwnd as f64 / self.mss as f64;
+                // fast convergence releases bandwidth for new flows
+                self.w_max = if segments < self.w_max {
+                    segments * (1.0 + CUBIC_BETA) / 2.0
+                } else {
+                    segments
+                };
+                let ssthresh = ((self.cwnd as f64 * CUBIC_BETA) as usize).max(min);
+                self.ssthresh = Some(ssthresh);
+                self.cwnd = ssthresh;
+                self.epoch = None;
+            }
+        }
+    }
+    fn update_rtt(&mut self, rtt: Duration) {
+        self.srtt = Some(match self.srtt {
+            Some(srtt) => (srtt * 7 + rtt) / 8,
+            None => rtt,
+        });
+    }
+    fn grow(&mut self, acked: usize, now: Instant) {
+        if self.algorithm == Congestion::None {
+            return;
+        }
+        if self.ssthresh.map_or(true, |ssthresh| self.cwnd < ssthresh) {
+            // slow start counts the acknowledged bytes (RFC 3465)
+            self.cwnd += acked;
+            return;
+        }
+        match self.algorithm {
+            Congestion::None => {}
+            Congestion::Reno => {
+                self.growth += acked;
+                if self.growth >= self.cwnd {
+                    self.growth -= self.cwnd;
+                    self.cwnd += self.mss;
+                }
+            }
+            Congestion::Cubic => {
+                let target = self.cubic_target(now) * self.mss as f64;
+                let cwnd = self.cwnd as f64;
+                if target > cwnd {
+                    // at most 1.5 times the window per RTT
+                    let increase = (acked as f64 * (target - cwnd) / cwnd).min(acked as f64 / 2.0);
+                    self.cwnd += increase.max(1.0) as usize;
+                }
+            }
+        }
+    }
+    /// Window of CUBIC in segments one RTT ahead, at least the one of Reno
+    fn cubic_target(&mut self, now: Instant) -> f64 {
+        let segments = self.cwnd as f64 / self.mss as f64;
+        let w_max = self.w_max;
+        let (start, k, origin) = *self.epoch.get_or_insert_with(|| {
+            if segments < w_max {
+                (now, ((w_max - segments) / CUBIC_C).cbrt(), w_max)
+            } else {
+                (now, 0.0, segments)
+            }
+        });
+        let srtt = self.srtt.unwrap_or(Duration::from_millis(100));
+        let t = (now.duration_since(start) + srtt).as_secs_f64();
+        let cubic = CUBIC_C * (t - k).powi(3) + origin;
+        // the window which Reno would have (TCP-friendly region)
+        let reno = origin * CUBIC_BETA
+            + 3.0 * (1.0 - CUBIC_BETA) / (1.0 + CUBIC_BETA) * t / srtt.as_secs_f64();
+        cubic.max(reno)
+    }
+}
+
+/// Retransmissions seen by the device layer for a connection
+#[derive(Debug)]
+struct Tracked {
+    end: Option<TcpSeqNumber>, // end of the highest data sent
+    retransmits: Arc<AtomicU64>,
+}
+
+/// TCP connections of one stack whose retransmissions are counted
+#[derive(Debug, Default)]
+pub(crate) struct Connections {
+    // number of tracked connections, the frames are not inspected if there are none
+    tracked: AtomicUsize,
+    // by the remote address, the local port, and the remote port
+    connections: Mutex<BTreeMap<(IpAddr, u16, u16), Tracked>>,
+}
+
+impl Connections {
+    /// Starts counting the retransmissions of a connection
+    pub fn track(&self, remote: IpAddr, local_port: u16, remote_port: u16) -> Arc<AtomicU64> {
+        let mut connections = self.connections.lock();
+        let tracked = &self.tracked;
+        let entry = connections
+            .entry((remote, local_port, remote_port))
+            .or_insert_with(|| {
+                let _ = tracked.fetch_add(1, Ordering::Relaxed);
+                Tracked {
+                    end: None,
+                    retransmits: Arc::new(AtomicU64::new(0)),
+                }
+            });
+        entry.retransmits.clone()
+    }
+
+    /// Stops counting the retransmissions of a connection
+    pub fn untrack(&self, remote: IpAddr, local_port: u16, remote_port: u16) {
+        if self
+            .connections
+            .lock()
+            .remove(&(remote, local_port, remote_port))
+            .is_some()
+        {
+            let _ = self.tracked.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Counts an outgoing TCP segment with data below the highest sent one as retransmission
+    /// (called for every transmitted frame)
+    pub fn inspect_tx_frame(&self, buffer: &[u8], headers: &Headers) {
+        if self.tracked.load(Ordering::Relaxed) == 0 {
+            return;
+        }
+        let ip = match headers.ip(IpProtocol::Tcp) {
+            Some(ip) if ip.first_fragment() => ip,
+            _ => return,
+        };
+        let tcp = match TcpPacket::new_checked(ip.payload(buffer)) {
+            Ok(tcp) => tcp,
+            Err(_) => return,
+        };
+        let len = tcp.payload().len();
+        if len == 0 {
+            return;
+        }
+        let mut connections = self.connections.lock();
+        if let Some(tracked) = connections.get_mut(&(ip.dst, tcp.src_port(), tcp.dst_port())) {
+            let end = tcp.seq_number() + len;
+            match tracked.end {
+                Some(highest) if tcp.seq_number() < highest => {
+                    let _ = tracked.retransmits.fetch_add(1, Ordering::Relaxed);
+                    if end > highest {
+                        tracked.end = Some(end);
+                    }
+                }
+                _ => tracked.end = Some(end),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::frame;
+    use std::net::Ipv4Addr;
+
+    const MSS: usize = 1000;
+    const RECEIVE_WINDOW: usize = 1 << 30;
+
+    /// Sends as much as the window allows each RTT, the whole flight is acknowledged one
+    /// RTT later, and the flights in `lossy` have a retransmission
+    fn simulate(algorithm: Congestion, rtt: Duration, rounds: usize, lossy: &[usize]) -> Window {
+        let mut window = Window::new(algorithm, MSS, DEFAULT_INITIAL_WINDOW);
+        let mut now = Instant::now();
+        for round in 0..rounds {
+            let flight = window.available().min(RECEIVE_WINDOW);
+            window.on_sent(flight, now);
+            if lossy.contains(&round) {
+                window.on_retransmits(window.retransmits + 1);
+            }
+            now += rtt;
+            window.on_queue(0, now);
+        }
+        window
+    }
+
+    #[test]
+    fn test_slow_start() {
+        let window = simulate(Congestion::Reno, Duration::from_millis(50), 3, &[]);
+        // the window doubles each RTT
+        assert_eq!(window.cwnd(), Some(DEFAULT_INITIAL_WINDOW * 8 * MSS));
+        assert_eq!(window.ssthresh(), None);
+        assert_eq!(window.srtt(), Some(Duration::from_millis(50)));
+        assert_eq!(window.losses(), 0);
+    }
+
+    #[test]
+    fn test_reno_loss() {
+        let mut window = Window::new(Congestion::Reno, MSS, DEFAULT_INITIAL_WINDOW);
+        let now = Instant::now();
+        window.on_sent(window.available(), now);
+        assert_eq!(window.available(), 0);
+        window.on_retransmits(1);
+        assert_eq!(window.ssthresh(), Some(DEFAULT_INITIAL_WINDOW / 2 * MSS));
+        assert_eq!(window.cwnd(), Some(DEFAULT_INITIAL_WINDOW / 2 * MSS));
+        // retransmissions of the same flight are one loss event
+        window.on_retransmits(3);
+        assert_eq!(window.losses(), 1);
+        assert_eq!(window.cwnd(), Some(DEFAULT_INITIAL_WINDOW / 2 * MSS));
+        // after recovery, the window grows by one segment per window of data
+        window.on_queue(0, now + Duration::from_millis(10));
+        window.on_sent(5 * MSS, now);
+        window.on_queue(0, now + Duration::from_millis(20));
+        assert_eq!(window.cwnd(), Some(6 * MSS));
+        window.on_retransmits(4);
+        assert_eq!(window.losses(), 2);
+        assert_eq!(window.cwnd(), Some(MIN_WINDOW * MSS));
+    }
+
+    #[test]
+    fn test_cubic_recovers_faster() {
+        let rtt = Duration::from_millis(100);
+        let reno = simulate(Congestion::Reno, rtt, 60, &[8]);
+        let cubic = simulate(Congestion::Cubic, rtt, 60, &[8]);
+        assert_eq!(reno.losses(), 1);
+        assert_eq!(cubic.losses(), 1);
+        // CUBIC gives up less of the window at the loss and grows back to it in the time K
+        assert!(cubic.cwnd().unwrap() > reno.cwnd().unwrap());
+        let before = simulate(Congestion::Cubic, rtt, 8, &[]).cwnd().unwrap();
+        let after = simulate(Congestion::Cubic, rtt, 9, &[8]).cwnd().unwrap();
+        assert!(after >= (before as f64 * CUBIC_BETA) as usize);
+        assert!(after < before);
+    }
+
+    #[test]
+    fn test_retransmission() {
+        let remote = Ipv4Addr::new(192, 0, 2, 1);
+        let segment = |seq: u32, len: usize| {
+            let mut frame = vec![0; 54 + len];
+            frame[12..14].copy_from_slice(&[0x08, 0x00]);
+            frame[14] = 0x45;
+            frame[16..18].copy_from_slice(&(40 + len as u16).to_be_bytes());
+            frame[22] = 64;
+            frame[23] = 6;
+            frame[26..30].copy_from_slice(&[192, 0, 2, 2]);
+            frame[30..34].copy_from_slice(&remote.octets());
+            frame[34..36].copy_from_slice(&40000u16.to_be_bytes());
+            frame[36..38].copy_from_slice(&80u16.to_be_bytes());
+            frame[38..42].copy_from_slice(&seq.to_be_bytes());
+            frame[46] = 0x50;
+            frame
+        };
+        let connections = Connections::default();
+        let inspect = |frame: Vec<u8>| connections.inspect_tx_frame(&frame, &frame::parse(&frame));
+        let retransmits = connections.track(IpAddr::V4(remote), 40000, 80);
+        inspect(segment(u32::max_value() - 99, 100));
+        // across the wrap-around of the sequence numbers
+        inspect(segment(0, 100));
+        inspect(segment(100, 0));
+        assert_eq!(retransmits.load(Ordering::Relaxed), 0);
+        inspect(segment(0, 100));
+        assert_eq!(retransmits.load(Ordering::Relaxed), 1);
+        // the same connection on another stack
+        let other = Connections::default();
+        let _ = other.track(IpAddr::V4(remote), 40000, 80);
+        other.inspect_tx_frame(&segment(0, 100), &frame::parse(&segment(0, 100)));
+        assert_eq!(retransmits.load(Ordering::Relaxed), 1);
+        connections.untrack(IpAddr::V4(remote), 40000, 80);
+        inspect(segment(0, 100));
+        assert_eq!(retransmits.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn test_no_congestion_control() {
+        let window = simulate(Congestion::None, Duration::from_millis(10), 5, &[1, 3]);
+        assert_eq!(window.cwnd(), None);
+        assert_eq!(window.available(), usize::max_value());
+    }
+
+    #[test]
+    fn test_initial_window() {
+        let mut window = Window::new(Congestion::Cubic, MSS, 4);
+        assert_eq!(window.available(), 4 * MSS);
+        assert!(window.set_initial_window(2));
+        assert_eq!(window.cwnd(), Some(2 * MSS));
+        window.on_sent(MSS, Instant::now());
+        assert!(!window.set_initial_window(10));
+        assert_eq!(window.available(), MSS);
+    }
+}
@@ -6,16 +6,38 @@ use std::fs::remove_file;
 extern crate usnet_devices;
 #[cfg(feature = "netmap")]
 use self::usnet_devices::Netmap;
-use self::usnet_devices::{RawSocket, TapInterface, UnixDomainSocket};
+use self::usnet_devices::{
+    InMemory, RawSocket, TapInterface, UnixDomainSocket, Xdp, SMOLTCP_ETHERNET_HEADER,
+};
 
 use smoltcp;
-use smoltcp::iface::EthernetInterface;
+use smoltcp::dhcp::{Dhcpv4Client, Dhcpv4Config};
+use smoltcp::iface::{EthernetInterface, Routes};
+use smoltcp::phy::{self, Device, DeviceCapabilities, TxToken};
 use smoltcp::socket::SocketSet;
-use smoltcp::wire::{EthernetAddress, IpProtocol};
+use smoltcp::wire::{
+    ArpOperation, ArpPacket, EthernetAddress, EthernetFrame, Icmpv4Message, Icmpv4Packet,
+    Icmpv6Message, Icmpv6Packet, IpAddress, IpCidr, IpProtocol, Ipv4Address, Ipv4Cidr, Ipv6Address,
+    TcpPacket,
+};
+use std::collections::VecDeque;
+use std::sync::Arc;
 
 use serde_json;
 use std::io;
-use std::net::IpAddr;
+use std::net::{IpAddr, Ipv4Addr};
+use std::os::unix::io::{AsRawFd, RawFd};
+
+use crate::capture::{capture, Direction};
+use crate::congestion;
+use crate::frame::{self, Headers, ETHERNET_HEADER_LEN};
+use crate::icmp;
+use crate::neighbor;
+use crate::pktinfo;
+use crate::pmtu;
+use crate::queues::QueueGroup;
+use crate::stats::{inc, InterfaceCounters, InterfaceStats, COUNTERS};
+use crate::tos;
 use std::os::unix::net::UnixDatagram;
 use std::process::Command;
 
@@ -23,26 +45,32 @@ extern crate libusnetd;
 use self::libusnetd::{ClientMessage, ClientMessageIp, WantMsg, SOCKET_PATH};
 
 pub enum StcpBackendInterface {
-    Raw(EthernetInterface<'static, RawSocket>),
+    Raw(EthernetInterface<'static, Monitored<RawSocket>>),
     Tap {
-        interface: EthernetInterface<'static, TapInterface>,
+        interface: EthernetInterface<'static, Monitored<TapInterface>>,
         destroy: Option<String>,
     },
     MacVtap {
-        interface: EthernetInterface<'static, TapInterface>,
+        interface: EthernetInterface<'static, Monitored<TapInterface>>,
         destroy: Option<String>,
     },
     #[cfg(feature = "netmap")]
     Netmap {
-        interface: EthernetInterface<'static, Netmap>,
+        interface: EthernetInterface<'static, Monitored<Netmap>>,
     },
     UsnetUds {
-        interface: EthernetInterface<'static, UnixDomainSocket>,
+        interface: EthernetInterface<'static, Monitored<UnixDomainSocket>>,
         control: UnixDatagram,
     },
+    InMemory {
+        interface: EthernetInterface<'static, Monitored<InMemory>>,
+    },
+    Xdp {
+        interface: EthernetInterface<'static, Monitored<Xdp>>,
+    },
     #[cfg(feature = "netmap")]
     UsnetNetmap {
-        interface: EthernetInterface<'static, Netmap>,
+        interface: EthernetInterface<'static, Monitored<Netmap>>,
         control: UnixDatagram,
     },
 }
@@ -76,12 +104,24 @@ impl fmt::Display for StcpBackendInterface {
                 interface: _,
                 control: _,
             } => "usnetd unix domain socket",
+            StcpBackendInterface::InMemory { interface: _ } => "in-memory link",
+            StcpBackendInterface::Xdp { interface: _ } => "direct AF_XDP",
+        };
+        let offload = match self {
+            StcpBackendInterface::Tap { interface, .. }
+            | StcpBackendInterface::MacVtap { interface, .. }
+                if interface.device().get_ref().uses_offload() =>
+            {
+                " with offload"
+            }
+            _ => "",
         };
         let ips: Vec<String> = self.ips().iter().map(|c| format!("{}", c)).collect();
         write!(
             f,
-            "{} {} {}",
+            "{}{} {} {}",
             typedesc,
+            offload,
             ips[..].join(", "),
             self.ethernet_addr()
         )
@@ -97,42 +137,51 @@ impl StcpBackendInterface {
         remote_port: Option<u16>,
         protocol: IpProtocol,
     ) -> io::Result<()> {
-        let ipv4 = self.ips()[0].address();
+        // the unspecified address matches packets for all IPv4 addresses of the interface
+        let ipstrs: Vec<String> = if ipa.is_unspecified() {
+            self.ips()
+                .iter()
+                .filter_map(|cidr| match cidr.address() {
+                    IpAddress::Ipv4(a) if !a.is_unspecified() && !a.is_loopback() => {
+                        Some(format!("{}", a))
+                    }
+                    _ => None,
+                })
+                .collect()
+        } else {
+            vec![format!("{}", ipa)]
+        };
         match self.control() {
             Some(control) => {
-                let ipstr = if ipa.is_unspecified() {
-                    format!("{}", ipv4)
-                } else {
-                    format!("{}", ipa)
-                };
-                let remote_ip = remote_addr.map(|i| ClientMessageIp::Ipv4(format!("{}", i)));
-                let want = WantMsg {
-                    dst_addr: ClientMessageIp::Ipv4(ipstr),
-                    dst_port: port,
-                    src_addr: remote_ip,
-                    src_port: remote_port,
-                    protocol: u8::from(protocol),
-                };
-                let payl = serde_json::to_string(&ClientMessage::AddMatch(want)).unwrap();
-                let sent_bytes = control
-                    .send_to(payl.as_bytes(), SOCKET_PATH)
-                    .expect("cannot send to service unix domain socket");
-                assert_eq!(sent_bytes, payl.len());
-                let mut succ = vec![0; 2];
-                if let (2, ua) = control.recv_from(&mut succ)? {
-                    assert_eq!(
-                        ua.as_pathname()
-                            .map(|p| p.to_str().map(|i| i == SOCKET_PATH)),
-                        Some(Some(true))
-                    );
-                    if succ != "OK".as_bytes() {
-                        Err(io::Error::new(io::ErrorKind::Other, "port not free"))
+                for ipstr in ipstrs {
+                    let remote_ip = remote_addr.map(|i| ClientMessageIp::Ipv4(format!("{}", i)));
+                    let want = WantMsg {
+                        dst_addr: ClientMessageIp::Ipv4(ipstr),
+                        dst_port: port,
+                        src_addr: remote_ip,
+                        src_port: remote_port,
+                        protocol: u8::from(protocol),
+                    };
+                    let payl = serde_json::to_string(&ClientMessage::AddMatch(want)).unwrap();
+                    let sent_bytes = control
+                        .send_to(payl.as_bytes(), SOCKET_PATH)
+                        .expect("cannot send to service unix domain socket");
+                    assert_eq!(sent_bytes, payl.len());
+                    let mut succ = vec![0; 2];
+                    if let (2, ua) = control.recv_from(&mut succ)? {
+                        assert_eq!(
+                            ua.as_pathname()
+                                .map(|p| p.to_str().map(|i| i == SOCKET_PATH)),
+                            Some(Some(true))
+                        );
+                        if succ != "OK".as_bytes() {
+                            return Err(io::Error::new(io::ErrorKind::Other, "port not free"));
+                        }
                     } else {
-                        Ok(())
+                        panic!("wrong answer");
                     }
-                } else {
-                    panic!("wrong answer");
                 }
+                Ok(())
             }
             _ => Ok(()),
         }
@@ -175,8 +224,19 @@ impl StcpBackendInterface {
                 interface: ref iface,
                 control: _,
             } => iface.ethernet_addr(),
+            StcpBackendInterface::InMemory {
+                interface: ref iface,
+            } => iface.ethernet_addr(),
+            StcpBackendInterface::Xdp {
+                interface: ref iface,
+            } => iface.ethernet_addr(),
         }
     }
+    /// Whether the address is one of the interface addresses
+    pub fn has_ip(&self, ip: IpAddr) -> bool {
+        let ip = IpAddress::from(ip);
+        self.ips().iter().any(|cidr| cidr.address() == ip)
+    }
     pub fn ips(&self) -> &[smoltcp::wire::IpCidr] {
         match self {
             StcpBackendInterface::Raw(ref iface) => iface.ip_addrs(),
@@ -201,11 +261,242 @@ impl StcpBackendInterface {
                 interface: ref iface,
                 control: _,
             } => iface.ip_addrs(),
+            StcpBackendInterface::InMemory {
+                interface: ref iface,
+            } => iface.ip_addrs(),
+            StcpBackendInterface::Xdp {
+                interface: ref iface,
+            } => iface.ip_addrs(),
+        }
+    }
+    /// State of the frame inspections of the stack
+    pub(crate) fn inspection(&self) -> &Inspection {
+        match self {
+            StcpBackendInterface::Raw(ref iface) => &iface.device().inspection,
+            StcpBackendInterface::MacVtap {
+                interface: ref iface,
+                destroy: _,
+            }
+            | StcpBackendInterface::Tap {
+                interface: ref iface,
+                destroy: _,
+            } => &iface.device().inspection,
+            #[cfg(feature = "netmap")]
+            StcpBackendInterface::Netmap {
+                interface: ref iface,
+            } => &iface.device().inspection,
+            #[cfg(feature = "netmap")]
+            StcpBackendInterface::UsnetNetmap {
+                interface: ref iface,
+                control: _,
+            } => &iface.device().inspection,
+            StcpBackendInterface::UsnetUds {
+                interface: ref iface,
+                control: _,
+            } => &iface.device().inspection,
+            StcpBackendInterface::InMemory {
+                interface: ref iface,
+            } => &iface.device().inspection,
+            StcpBackendInterface::Xdp {
+                interface: ref iface,
+            } => &iface.device().inspection,
+        }
+    }
+    /// Counters of the frames of the interface and the occupancy of the device rings
+    pub fn interface_stats(&self) -> InterfaceStats {
+        match self {
+            StcpBackendInterface::Raw(ref iface) => iface.device().counters.snapshot(None),
+            StcpBackendInterface::MacVtap {
+                interface: ref iface,
+                destroy: _,
+            }
+            | StcpBackendInterface::Tap {
+                interface: ref iface,
+                destroy: _,
+            } => {
+                let device = iface.device();
+                device.counters.snapshot(device.get_ref().ring_occupancy())
+            }
+            #[cfg(feature = "netmap")]
+            StcpBackendInterface::Netmap {
+                interface: ref iface,
+            } => {
+                let device = iface.device();
+                device
+                    .counters
+                    .snapshot(Some(device.get_ref().ring_occupancy()))
+            }
+            #[cfg(feature = "netmap")]
+            StcpBackendInterface::UsnetNetmap {
+                interface: ref iface,
+                control: _,
+            } => {
+                let device = iface.device();
+                device
+                    .counters
+                    .snapshot(Some(device.get_ref().ring_occupancy()))
+            }
+            StcpBackendInterface::UsnetUds {
+                interface: ref iface,
+                control: _,
+            } => iface.device().counters.snapshot(None),
+            StcpBackendInterface::InMemory {
+                interface: ref iface,
+            } => iface.device().counters.snapshot(None),
+            StcpBackendInterface::Xdp {
+                interface: ref iface,
+            } => iface.device().counters.snapshot(None),
+        }
+    }
+    pub fn set_mtu_limit(&mut self, mtu_limit: Option<usize>) {
+        match self {
+            StcpBackendInterface::Raw(ref mut iface) => iface.device_mut().set_mtu_limit(mtu_limit),
+            StcpBackendInterface::MacVtap {
+                interface: ref mut iface,
+                destroy: _,
+            }
+            | StcpBackendInterface::Tap {
+                interface: ref mut iface,
+                destroy: _,
+            } => iface.device_mut().set_mtu_limit(mtu_limit),
+            #[cfg(feature = "netmap")]
+            StcpBackendInterface::Netmap {
+                interface: ref mut iface,
+            } => iface.device_mut().set_mtu_limit(mtu_limit),
+            #[cfg(feature = "netmap")]
+            StcpBackendInterface::UsnetNetmap {
+                interface: ref mut iface,
+                control: _,
+            } => iface.device_mut().set_mtu_limit(mtu_limit),
+            StcpBackendInterface::UsnetUds {
+                interface: ref mut iface,
+                control: _,
+            } => iface.device_mut().set_mtu_limit(mtu_limit),
+            StcpBackendInterface::InMemory {
+                interface: ref mut iface,
+            } => iface.device_mut().set_mtu_limit(mtu_limit),
+            StcpBackendInterface::Xdp {
+                interface: ref mut iface,
+            } => iface.device_mut().set_mtu_limit(mtu_limit),
         }
     }
+    /// Adds 127.0.0.1 to the interface and lets the device hand the frames for the own
+    /// addresses back to the stack instead of sending them
+    pub fn enable_loopback(&mut self) {
+        self.update_ip_addrs(|addrs| {
+            addrs.push(IpCidr::new(IpAddress::v4(127, 0, 0, 1), 8));
+        });
+        match self {
+            StcpBackendInterface::Raw(ref mut iface) => iface.device_mut().enable_loopback(),
+            StcpBackendInterface::MacVtap {
+                interface: ref mut iface,
+                destroy: _,
+            }
+            | StcpBackendInterface::Tap {
+                interface: ref mut iface,
+                destroy: _,
+            } => iface.device_mut().enable_loopback(),
+            #[cfg(feature = "netmap")]
+            StcpBackendInterface::Netmap {
+                interface: ref mut iface,
+            } => iface.device_mut().enable_loopback(),
+            #[cfg(feature = "netmap")]
+            StcpBackendInterface::UsnetNetmap {
+                interface: ref mut iface,
+                control: _,
+            } => iface.device_mut().enable_loopback(),
+            StcpBackendInterface::UsnetUds {
+                interface: ref mut iface,
+                control: _,
+            } => iface.device_mut().enable_loopback(),
+            StcpBackendInterface::InMemory {
+                interface: ref mut iface,
+            } => iface.device_mut().enable_loopback(),
+            StcpBackendInterface::Xdp {
+                interface: ref mut iface,
+            } => iface.device_mut().enable_loopback(),
+        }
+    }
+    /// Lets the device hand the received frames which are not for the stack of its queue
+    /// over to the other stacks of the group
+    pub fn join_queue_group(&mut self, group: Arc<QueueGroup>, index: usize) -> io::Result<()> {
+        match self {
+            #[cfg(feature = "netmap")]
+            StcpBackendInterface::Netmap {
+                interface: ref mut iface,
+            } => iface.device_mut().join_queue_group(group, index),
+            StcpBackendInterface::Xdp {
+                interface: ref mut iface,
+            } => iface.device_mut().join_queue_group(group, index),
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "only the AF_XDP and netmap backends run on multiple queues",
+                ))
+            }
+        }
+        Ok(())
+    }
     pub fn poll(&mut self, sockets: &mut SocketSet, timestamp: Instant) -> smoltcp::Result<bool> {
+        let result = match self {
+            StcpBackendInterface::Raw(ref mut iface) => poll_iface(iface, sockets, timestamp),
+            StcpBackendInterface::MacVtap {
+                interface: ref mut iface,
+                destroy: _,
+            }
+            | StcpBackendInterface::Tap {
+                interface: ref mut iface,
+                destroy: _,
+            } => {
+                let result = poll_iface(iface, sockets, timestamp);
+                flush_batch(iface);
+                result
+            }
+            #[cfg(feature = "netmap")]
+            StcpBackendInterface::Netmap {
+                interface: ref mut iface,
+            } => {
+                let result = poll_iface(iface, sockets, timestamp);
+                flush_batch(iface);
+                result
+            }
+            #[cfg(feature = "netmap")]
+            StcpBackendInterface::UsnetNetmap {
+                interface: ref mut iface,
+                control: _,
+            } => {
+                let result = poll_iface(iface, sockets, timestamp);
+                flush_batch(iface);
+                result
+            }
+            StcpBackendInterface::UsnetUds {
+                interface: ref mut iface,
+                control: _,
+            } => poll_iface(iface, sockets, timestamp),
+            StcpBackendInterface::InMemory {
+                interface: ref mut iface,
+            } => poll_iface(iface, sockets, timestamp),
+            StcpBackendInterface::Xdp {
+                interface: ref mut iface,
+            } => {
+                let result = poll_iface(iface, sockets, timestamp);
+                flush_batch(iface);
+                result
+            }
+        };
+        if let Err(ref err) = result {
+            crate::stats::record_poll_error(err);
+        }
+        result
+    }
+    pub fn dhcp_poll(
+        &mut self,
+        client: &mut Dhcpv4Client,
+        sockets: &mut SocketSet,
+        timestamp: Instant,
+    ) -> smoltcp::Result<Option<Dhcpv4Config>> {
         match self {
-            StcpBackendInterface::Raw(ref mut iface) => iface.poll(sockets, timestamp),
+            StcpBackendInterface::Raw(ref mut iface) => client.poll(iface, sockets, timestamp),
             StcpBackendInterface::MacVtap {
                 interface: ref mut iface,
                 destroy: _,
@@ -213,20 +504,177 @@ impl StcpBackendInterface {
             | StcpBackendInterface::Tap {
                 interface: ref mut iface,
                 destroy: _,
-            } => iface.poll(sockets, timestamp),
+            } => {
+                let result = client.poll(iface, sockets, timestamp);
+                flush_batch(iface);
+                result
+            }
             #[cfg(feature = "netmap")]
             StcpBackendInterface::Netmap {
                 interface: ref mut iface,
-            } => iface.poll(sockets, timestamp),
+            } => {
+                let result = client.poll(iface, sockets, timestamp);
+                flush_batch(iface);
+                result
+            }
             #[cfg(feature = "netmap")]
             StcpBackendInterface::UsnetNetmap {
                 interface: ref mut iface,
                 control: _,
-            } => iface.poll(sockets, timestamp),
+            } => {
+                let result = client.poll(iface, sockets, timestamp);
+                flush_batch(iface);
+                result
+            }
+            StcpBackendInterface::UsnetUds {
+                interface: ref mut iface,
+                control: _,
+            } => client.poll(iface, sockets, timestamp),
+            StcpBackendInterface::InMemory {
+                interface: ref mut iface,
+            } => client.poll(iface, sockets, timestamp),
+            StcpBackendInterface::Xdp {
+                interface: ref mut iface,
+            } => {
+                let result = client.poll(iface, sockets, timestamp);
+                flush_batch(iface);
+                result
+            }
+        }
+    }
+    /// Replaces the IP address and the default route, used for DHCP
+    pub fn set_ipv4_config(&mut self, cidr: Ipv4Cidr, router: Option<Ipv4Address>) {
+        match self {
+            StcpBackendInterface::Raw(ref mut iface) => update_ipv4_config(iface, cidr, router),
+            StcpBackendInterface::MacVtap {
+                interface: ref mut iface,
+                destroy: _,
+            }
+            | StcpBackendInterface::Tap {
+                interface: ref mut iface,
+                destroy: _,
+            } => update_ipv4_config(iface, cidr, router),
+            #[cfg(feature = "netmap")]
+            StcpBackendInterface::Netmap {
+                interface: ref mut iface,
+            } => update_ipv4_config(iface, cidr, router),
+            #[cfg(feature = "netmap")]
+            StcpBackendInterface::UsnetNetmap {
+                interface: ref mut iface,
+                control: _,
+            } => update_ipv4_config(iface, cidr, router),
             StcpBackendInterface::UsnetUds {
                 interface: ref mut iface,
                 control: _,
-            } => iface.poll(sockets, timestamp),
+            } => update_ipv4_config(iface, cidr, router),
+            StcpBackendInterface::InMemory {
+                interface: ref mut iface,
+            } => update_ipv4_config(iface, cidr, router),
+            StcpBackendInterface::Xdp {
+                interface: ref mut iface,
+            } => update_ipv4_config(iface, cidr, router),
+        }
+    }
+    pub fn clear_ipv4_config(&mut self) {
+        self.set_ipv4_config(Ipv4Cidr::new(Ipv4Address::UNSPECIFIED, 0), None);
+    }
+    /// Changes the list of IP addresses, the first one is expected to be the IPv4 address
+    pub fn update_ip_addrs<F: FnOnce(&mut Vec<IpCidr>)>(&mut self, f: F) {
+        match self {
+            StcpBackendInterface::Raw(ref mut iface) => update_ip_addrs(iface, f),
+            StcpBackendInterface::MacVtap {
+                interface: ref mut iface,
+                destroy: _,
+            }
+            | StcpBackendInterface::Tap {
+                interface: ref mut iface,
+                destroy: _,
+            } => update_ip_addrs(iface, f),
+            #[cfg(feature = "netmap")]
+            StcpBackendInterface::Netmap {
+                interface: ref mut iface,
+            } => update_ip_addrs(iface, f),
+            #[cfg(feature = "netmap")]
+            StcpBackendInterface::UsnetNetmap {
+                interface: ref mut iface,
+                control: _,
+            } => update_ip_addrs(iface, f),
+            StcpBackendInterface::UsnetUds {
+                interface: ref mut iface,
+                control: _,
+            } => update_ip_addrs(iface, f),
+            StcpBackendInterface::InMemory {
+                interface: ref mut iface,
+            } => update_ip_addrs(iface, f),
+            StcpBackendInterface::Xdp {
+                interface: ref mut iface,
+            } => update_ip_addrs(iface, f),
+        }
+    }
+    /// Replaces the IPv6 default route, used for SLAAC
+    pub fn set_default_ipv6_route(&mut self, router: Option<Ipv6Address>) {
+        match self {
+            StcpBackendInterface::Raw(ref mut iface) => set_default_ipv6_route(iface, router),
+            StcpBackendInterface::MacVtap {
+                interface: ref mut iface,
+                destroy: _,
+            }
+            | StcpBackendInterface::Tap {
+                interface: ref mut iface,
+                destroy: _,
+            } => set_default_ipv6_route(iface, router),
+            #[cfg(feature = "netmap")]
+            StcpBackendInterface::Netmap {
+                interface: ref mut iface,
+            } => set_default_ipv6_route(iface, router),
+            #[cfg(feature = "netmap")]
+            StcpBackendInterface::UsnetNetmap {
+                interface: ref mut iface,
+                control: _,
+            } => set_default_ipv6_route(iface, router),
+            StcpBackendInterface::UsnetUds {
+                interface: ref mut iface,
+                control: _,
+            } => set_default_ipv6_route(iface, router),
+            StcpBackendInterface::InMemory {
+                interface: ref mut iface,
+            } => set_default_ipv6_route(iface, router),
+            StcpBackendInterface::Xdp {
+                interface: ref mut iface,
+            } => set_default_ipv6_route(iface, router),
+        }
+    }
+    /// Changes the routing table, used for static routes
+    pub fn update_routes<F: FnOnce(&mut Routes<'static>)>(&mut self, f: F) {
+        match self {
+            StcpBackendInterface::Raw(ref mut iface) => f(iface.routes_mut()),
+            StcpBackendInterface::MacVtap {
+                interface: ref mut iface,
+                destroy: _,
+            }
+            | StcpBackendInterface::Tap {
+                interface: ref mut iface,
+                destroy: _,
+            } => f(iface.routes_mut()),
+            #[cfg(feature = "netmap")]
+            StcpBackendInterface::Netmap {
+                interface: ref mut iface,
+            } => f(iface.routes_mut()),
+            #[cfg(feature = "netmap")]
+            StcpBackendInterface::UsnetNetmap {
+                interface: ref mut iface,
+                control: _,
+            } => f(iface.routes_mut()),
+            StcpBackendInterface::UsnetUds {
+                interface: ref mut iface,
+                control: _,
+            } => f(iface.routes_mut()),
+            StcpBackendInterface::InMemory {
+                interface: ref mut iface,
+            } => f(iface.routes_mut()),
+            StcpBackendInterface::Xdp {
+                interface: ref mut iface,
+            } => f(iface.routes_mut()),
         }
     }
     pub fn poll_delay(&self, sockets: &SocketSet, timestamp: Instant) -> Option<Duration> {
@@ -253,28 +701,739 @@ impl StcpBackendInterface {
                 interface: ref iface,
                 control: _,
             } => iface.poll_delay(sockets, timestamp),
+            StcpBackendInterface::Xdp {
+                interface: ref iface,
+            } => iface.poll_delay(sockets, timestamp),
+            StcpBackendInterface::InMemory {
+                interface: ref iface,
+            } => {
+                // frames held back by the link latency become receivable without a wakeup
+                let delay = iface.poll_delay(sockets, timestamp);
+                let delivery = iface
+                    .device()
+                    .get_ref()
+                    .next_delivery()
+                    .map(|d| Duration::from_millis((d.as_micros() as u64 + 999) / 1000));
+                match (delay, delivery) {
+                    (Some(a), Some(b)) => Some(a.min(b)),
+                    (a, b) => a.or(b),
+                }
+            }
         }
     }
 }
 
-impl Drop for StcpBackendInterface {
-    fn drop(&mut self) {
+/// Hands the static neighbors to the interface and sends the requests of a neighbor flush
+/// before polling, also updates the addresses of the loopback and of the frame inspection
+/// after a configuration change and sends the segments of split frames which did not fit
+/// into the poll
+fn poll_iface<D>(
+    iface: &mut EthernetInterface<'static, Monitored<D>>,
+    sockets: &mut SocketSet,
+    timestamp: Instant,
+) -> smoltcp::Result<bool>
+where
+    D: for<'b> Device<'b>,
+{
+    let outdated = match iface.device().loopback {
+        Some(ref loopback) => {
+            loopback.ethernet_addr != iface.ethernet_addr()
+                || !loopback
+                    .local
+                    .iter()
+                    .cloned()
+                    .eq(iface.ip_addrs().iter().map(|cidr| cidr.address()))
+        }
+        None => false,
+    };
+    if outdated {
+        let loopback = Loopback {
+            ethernet_addr: iface.ethernet_addr(),
+            local: iface.ip_addrs().iter().map(|cidr| cidr.address()).collect(),
+        };
+        iface.device_mut().loopback = Some(loopback);
+    }
+    if !iface
+        .device()
+        .local
+        .iter()
+        .cloned()
+        .eq(iface.ip_addrs().iter().map(|cidr| cidr.address()))
+    {
+        let local = iface.ip_addrs().iter().map(|cidr| cidr.address()).collect();
+        iface.device_mut().local = local;
+    }
+    let (rx, tx) = iface
+        .device()
+        .inspection
+        .neighbors
+        .poll(iface.ethernet_addr(), iface.ip_addrs());
+    for frame in rx {
+        iface.device_mut().injected.push_back(frame);
+    }
+    let handed_off = match iface.device().queue_group {
+        Some((ref group, index)) => group.take(index),
+        None => VecDeque::new(),
+    };
+    iface.device_mut().handed_off.extend(handed_off);
+    send_deferred(iface.device_mut(), timestamp);
+    for frame in tx {
+        if let Some(token) = iface.device_mut().transmit() {
+            let _ = token.consume(timestamp, frame.len(), |buffer| {
+                buffer.copy_from_slice(&frame);
+                Ok(())
+            });
+        }
+    }
+    let result = iface.poll(sockets, timestamp);
+    send_deferred(iface.device_mut(), timestamp);
+    result
+}
+
+/// Sends the remaining segments of frames split for the path MTU, they are not inspected again
+fn send_deferred<D>(device: &mut Monitored<D>, timestamp: Instant)
+where
+    D: for<'b> Device<'b>,
+{
+    let Monitored {
+        ref mut inner,
+        ref mut deferred,
+        ref inspection,
+        ref counters,
+        ..
+    } = *device;
+    while let Some(frame) = deferred.pop_front() {
+        match inner.transmit() {
+            Some(token) => {
+                let _ = token.consume(timestamp, frame.len(), |buffer| {
+                    buffer.copy_from_slice(&frame);
+                    account_tx(inspection, counters, buffer, &frame::parse(buffer));
+                    Ok(())
+                });
+            }
+            None => {
+                deferred.push_front(frame);
+                break;
+            }
+        }
+    }
+}
+
+/// Devices which queue the frames sent and received during a poll and hand them to the
+/// kernel with one ring sync or syscall afterwards
+trait Batched {
+    fn flush_batch(&mut self) -> io::Result<()>;
+}
+
+impl Batched for TapInterface {
+    fn flush_batch(&mut self) -> io::Result<()> {
+        self.flush()
+    }
+}
+
+#[cfg(feature = "netmap")]
+impl Batched for Netmap {
+    fn flush_batch(&mut self) -> io::Result<()> {
+        self.flush()
+            .map_err(|err| io::Error::new(io::ErrorKind::Other, err.to_string()))
+    }
+}
+
+impl Batched for Xdp {
+    fn flush_batch(&mut self) -> io::Result<()> {
+        self.flush();
+        Ok(())
+    }
+}
+
+/// Submits the frames which the device queued while polling
+fn flush_batch<D>(iface: &mut EthernetInterface<'static, Monitored<D>>)
+where
+    D: for<'b> Device<'b> + Batched,
+{
+    if let Err(err) = iface.device_mut().get_mut().flush_batch() {
+        warn!("Submitting the queued frames failed: {}", err);
+    }
+}
+
+fn update_ipv4_config<D>(
+    iface: &mut EthernetInterface<'static, D>,
+    cidr: Ipv4Cidr,
+    router: Option<Ipv4Address>,
+) where
+    D: for<'d> Device<'d>,
+{
+    iface.update_ip_addrs(|addrs| {
+        if let Some(addr) = addrs.iter_mut().next() {
+            *addr = IpCidr::Ipv4(cidr);
+        }
+    });
+    match router {
+        Some(router) => {
+            let _ = iface.routes_mut().add_default_ipv4_route(router);
+        }
+        None => {
+            let _ = iface.routes_mut().remove_default_ipv4_route();
+        }
+    }
+}
+
+fn update_ip_addrs<D, F>(iface: &mut EthernetInterface<'static, D>, f: F)
+where
+    D: for<'d> Device<'d>,
+    F: FnOnce(&mut Vec<IpCidr>),
+{
+    iface.update_ip_addrs(|addrs| {
+        let mut v = addrs.to_vec();
+        f(&mut v);
+        *addrs = v.into();
+    });
+}
+
+fn set_default_ipv6_route<D>(iface: &mut EthernetInterface<'static, D>, router: Option<Ipv6Address>)
+where
+    D: for<'d> Device<'d>,
+{
+    match router {
+        Some(router) => {
+            let _ = iface.routes_mut().add_default_ipv6_route(router);
+        }
+        None => {
+            let _ = iface.routes_mut().remove_default_ipv6_route();
+        }
+    }
+}
+
+/// Device wrapper that accounts all frames passing the device layer in the stack-wide counters
+/// and hands them to the packet capture
+#[derive(Debug)]
+pub struct Monitored<D> {
+    inner: D,
+    mtu_limit: Option<usize>,
+    injected: VecDeque<Vec<u8>>, // frames from the stack itself to receive first
+    loopback: Option<Loopback>,
+    queue_group: Option<(Arc<QueueGroup>, usize)>, // and the index of the device's queue
+    handed_off: VecDeque<Vec<u8>>,                 // received on the queues of other stacks
+    deferred: VecDeque<Vec<u8>>,                   // segments of split frames to send
+    local: Vec<IpAddress>, // addresses of the interface for the inspection of received frames
+    inspection: Inspection,
+    counters: InterfaceCounters,
+}
+
+/// State of the frame inspections, kept in the device so that each stack of a process and
+/// each stack of a queue group has its own
+#[derive(Debug, Default)]
+pub(crate) struct Inspection {
+    pub congestion: congestion::Connections,
+    pub icmp: icmp::Errors,
+    pub neighbors: neighbor::Neighbors,
+    pub pktinfo: pktinfo::Ports,
+    pub pmtu: pmtu::PathMtus,
+    pub tos: tos::Marks,
+}
+
+impl Inspection {
+    /// Inspects a received frame which the stack processes
+    fn inspect_rx_frame(&self, buffer: &mut [u8], headers: &Headers, local: &[IpAddress]) {
+        self.icmp.inspect_rx_frame(buffer, headers, local);
+        self.neighbors.inspect_rx_frame(buffer, headers, local);
+        self.pmtu.inspect_rx_frame(buffer, headers);
+    }
+
+    /// Sets the TOS byte and the pinned source address of a transmitted frame
+    fn rewrite_tx_frame(&self, buffer: &mut [u8], headers: &mut Headers) {
+        self.tos.mark_tx_frame(buffer, headers);
+        self.pktinfo.rewrite_tx_frame(buffer, headers);
+    }
+}
+
+/// Addresses of the interface for which the frames are handed back to the stack
+#[derive(Debug)]
+struct Loopback {
+    ethernet_addr: EthernetAddress,
+    local: Vec<IpAddress>,
+}
+
+impl Loopback {
+    /// Whether the frame is sent to the own MAC address, or is an ARP request or a neighbor
+    /// solicitation for an own address, through which smoltcp learns its own MAC address
+    fn is_local(&self, buffer: &[u8], headers: &Headers) -> bool {
+        let frame = match EthernetFrame::new_checked(buffer) {
+            Ok(frame) => frame,
+            Err(_) => return false,
+        };
+        if frame.dst_addr() == self.ethernet_addr {
+            return true;
+        }
+        match *headers {
+            Headers::Arp => match ArpPacket::new_checked(frame.payload()) {
+                Ok(ref arp)
+                    if arp.operation() == ArpOperation::Request && arp.protocol_len() == 4 =>
+                {
+                    let tpa = Ipv4Address::from_bytes(arp.target_protocol_addr());
+                    self.local.contains(&IpAddress::Ipv4(tpa))
+                }
+                _ => false,
+            },
+            Headers::Ip(_) => neighbor::solicitation_target(buffer, headers)
+                .map(|ip| self.local.contains(&IpAddress::from(ip)))
+                .unwrap_or(false),
+            Headers::Other => false,
+        }
+    }
+}
+
+/// Frames from the network to or from 127.0.0.0/8 are dropped as done by the kernel because
+/// the stack would accept them for its loopback address
+fn is_martian(buffer: &[u8], headers: &Headers) -> bool {
+    match *headers {
+        Headers::Arp => match ArpPacket::new_checked(&buffer[ETHERNET_HEADER_LEN..]) {
+            Ok(ref arp) if arp.protocol_len() == 4 => {
+                Ipv4Address::from_bytes(arp.target_protocol_addr()).is_loopback()
+                    || Ipv4Address::from_bytes(arp.source_protocol_addr()).is_loopback()
+            }
+            _ => false,
+        },
+        Headers::Ip(ref ip) => ip.src.is_ipv4() && (ip.src.is_loopback() || ip.dst.is_loopback()),
+        Headers::Other => false,
+    }
+}
+
+impl<D> Monitored<D> {
+    pub fn new(inner: D) -> Monitored<D> {
+        Monitored {
+            inner: inner,
+            mtu_limit: None,
+            injected: VecDeque::new(),
+            loopback: None,
+            queue_group: None,
+            handed_off: VecDeque::new(),
+            deferred: VecDeque::new(),
+            local: vec![],
+            inspection: Inspection::default(),
+            counters: InterfaceCounters::default(),
+        }
+    }
+    /// Limits the IP MTU to be lower than the one of the device
+    pub fn set_mtu_limit(&mut self, mtu_limit: Option<usize>) {
+        self.mtu_limit = mtu_limit;
+    }
+    /// Hands the frames for the own addresses back to the stack, the addresses are taken
+    /// from the interface when it is polled
+    /// Every transmitted frame is first written to a separate buffer to look at its
+    /// destination before it is copied to the device.
+    pub fn enable_loopback(&mut self) {
+        self.loopback = Some(Loopback {
+            ethernet_addr: EthernetAddress([0; 6]),
+            local: vec![],
+        });
+    }
+    /// Hands the received frames over to the other stacks of the group, see QueueGroup::hand_off
+    pub fn join_queue_group(&mut self, group: Arc<QueueGroup>, index: usize) {
+        self.queue_group = Some((group, index));
+    }
+    pub fn get_ref(&self) -> &D {
+        &self.inner
+    }
+    pub fn get_mut(&mut self) -> &mut D {
+        &mut self.inner
+    }
+}
+
+impl<D: AsRawFd> AsRawFd for Monitored<D> {
+    fn as_raw_fd(&self) -> RawFd {
+        self.inner.as_raw_fd()
+    }
+}
+
+impl<'a, D> Device<'a> for Monitored<D>
+where
+    D: for<'b> Device<'b>,
+{
+    type RxToken = MonitoredRxToken<'a, <D as Device<'a>>::RxToken>;
+    type TxToken = MonitoredTxToken<'a, <D as Device<'a>>::TxToken>;
+
+    fn capabilities(&self) -> DeviceCapabilities {
+        let mut caps = self.inner.capabilities();
+        if let Some(mtu_limit) = self.mtu_limit {
+            caps.max_transmission_unit = caps
+                .max_transmission_unit
+                .min(mtu_limit + SMOLTCP_ETHERNET_HEADER);
+        }
+        caps
+    }
+
+    fn receive(&'a mut self) -> Option<(Self::RxToken, Self::TxToken)> {
+        let Monitored {
+            ref mut inner,
+            ref mut injected,
+            ref loopback,
+            ref queue_group,
+            ref mut handed_off,
+            ref mut deferred,
+            ref local,
+            ref inspection,
+            ref counters,
+        } = *self;
+        let drop_martians = loopback.is_some();
+        if let Some(frame) = injected.pop_front() {
+            // the stack does not answer the injected ARP replies and neighbor advertisements,
+            // the answers to looped back frames are looped back again
+            return Some((
+                MonitoredRxToken {
+                    inner: None,
+                    injected: frame,
+                    handed_off: false,
+                    drop_martians: false,
+                    queue_group: None,
+                    local: local,
+                    inspection: inspection,
+                    counters: counters,
+                },
+                MonitoredTxToken {
+                    inner: None,
+                    loopback: loopback.as_ref().map(|l| (l, injected)),
+                    deferred: deferred,
+                    inspection: inspection,
+                    counters: counters,
+                    reply: true,
+                },
+            ));
+        }
+        if !handed_off.is_empty() {
+            // the answers go out through the own queue, without a free transmit buffer the
+            // device is not read until the next poll
+            return inner.transmit().map(move |tx| {
+                (
+                    MonitoredRxToken {
+                        inner: None,
+                        injected: handed_off.pop_front().unwrap(),
+                        handed_off: true,
+                        drop_martians: false,
+                        queue_group: None,
+                        local: local,
+                        inspection: inspection,
+                        counters: counters,
+                    },
+                    MonitoredTxToken {
+                        inner: Some(tx),
+                        loopback: loopback.as_ref().map(|l| (l, injected)),
+                        deferred: deferred,
+                        inspection: inspection,
+                        counters: counters,
+                        reply: true,
+                    },
+                )
+            });
+        }
+        inner.receive().map(move |(rx, tx)| {
+            (
+                MonitoredRxToken {
+                    inner: Some(rx),
+                    injected: vec![],
+                    handed_off: false,
+                    drop_martians: drop_martians,
+                    queue_group: queue_group.clone(),
+                    local: local,
+                    inspection: inspection,
+                    counters: counters,
+                },
+                MonitoredTxToken {
+                    inner: Some(tx),
+                    loopback: loopback.as_ref().map(|l| (l, injected)),
+                    deferred: deferred,
+                    inspection: inspection,
+                    counters: counters,
+                    reply: true,
+                },
+            )
+        })
+    }
+
+    fn transmit(&'a mut self) -> Option<Self::TxToken> {
+        let Monitored {
+            ref mut inner,
+            ref mut injected,
+            ref loopback,
+            ref mut deferred,
+            ref inspection,
+            ref counters,
+            ..
+        } = *self;
+        let tx = inner.transmit();
+        if tx.is_none() {
+            stack_log!(trace, "device has no free transmit buffer");
+        }
+        tx.map(move |tx| MonitoredTxToken {
+            inner: Some(tx),
+            loopback: loopback.as_ref().map(|l| (l, injected)),
+            deferred: deferred,
+            inspection: inspection,
+            counters: counters,
+            reply: false,
+        })
+    }
+}
+
+#[doc(hidden)]
+pub struct MonitoredRxToken<'a, Rx: phy::RxToken> {
+    inner: Option<Rx>, // None for an injected or handed off frame
+    injected: Vec<u8>,
+    handed_off: bool, // received on the queue of another stack and not yet inspected
+    drop_martians: bool, // set when the loopback is enabled
+    queue_group: Option<(Arc<QueueGroup>, usize)>,
+    local: &'a [IpAddress],
+    inspection: &'a Inspection,
+    counters: &'a InterfaceCounters,
+}
+
+impl<'a, Rx: phy::RxToken> phy::RxToken for MonitoredRxToken<'a, Rx> {
+    fn consume<R, F: FnOnce(&mut [u8]) -> smoltcp::Result<R>>(
+        self,
+        timestamp: Instant,
+        f: F,
+    ) -> smoltcp::Result<R> {
+        let drop_martians = self.drop_martians;
+        let queue_group = self.queue_group;
+        let local = self.local;
+        let inspection = self.inspection;
+        let counters = self.counters;
+        let inner = match self.inner {
+            Some(inner) => inner,
+            None => {
+                let mut buffer = self.injected;
+                let headers = frame::parse(&buffer);
+                if self.handed_off {
+                    inspection.inspect_rx_frame(&mut buffer, &headers, local);
+                } else {
+                    stack_log!(trace, "processing injected frame of {} bytes", buffer.len());
+                }
+                return receive(inspection, &mut buffer, &headers, f);
+            }
+        };
+        inner.consume(timestamp, |buffer| {
+            counters.count_rx(buffer.len());
+            stack_log!(trace, "received frame of {} bytes", buffer.len());
+            capture(buffer, Direction::Rx);
+            let headers = frame::parse(buffer);
+            if drop_martians && is_martian(buffer, &headers) {
+                stack_log!(
+                    trace,
+                    "dropping frame from the network for the loopback network"
+                );
+                return Err(smoltcp::Error::Dropped);
+            }
+            if let Some((ref group, index)) = queue_group {
+                if !group.hand_off(index, buffer) {
+                    stack_log!(trace, "frame handed over to the stack of the first queue");
+                    return Err(smoltcp::Error::Dropped);
+                }
+            }
+            // the frames for other stacks are inspected there
+            inspection.inspect_rx_frame(buffer, &headers, local);
+            receive(inspection, buffer, &headers, f)
+        })
+    }
+}
+
+/// Hands a received frame to the stack and notes the destination of an accepted datagram
+/// for recv_msg
+fn receive<R, F: FnOnce(&mut [u8]) -> smoltcp::Result<R>>(
+    inspection: &Inspection,
+    buffer: &mut [u8],
+    headers: &Headers,
+    f: F,
+) -> smoltcp::Result<R> {
+    let datagram = inspection.pktinfo.parse_rx_frame(buffer, headers);
+    let result = f(buffer)?;
+    if let Some(datagram) = datagram {
+        inspection.pktinfo.note_received(datagram);
+    }
+    Ok(result)
+}
+
+#[doc(hidden)]
+pub struct MonitoredTxToken<'a, Tx: phy::TxToken> {
+    inner: Option<Tx>, // None for the answer to an injected frame
+    loopback: Option<(&'a Loopback, &'a mut VecDeque<Vec<u8>>)>, // and the injected frames
+    deferred: &'a mut VecDeque<Vec<u8>>,
+    inspection: &'a Inspection,
+    counters: &'a InterfaceCounters,
+    reply: bool, // answers a received frame
+}
+
+impl<'a, Tx: phy::TxToken> phy::TxToken for MonitoredTxToken<'a, Tx> {
+    fn consume<R, F: FnOnce(&mut [u8]) -> smoltcp::Result<R>>(
+        self,
+        timestamp: Instant,
+        len: usize,
+        f: F,
+    ) -> smoltcp::Result<R> {
+        let inspection = self.inspection;
+        let counters = self.counters;
+        let reply = self.reply;
+        let inspect = inspection.pmtu.enabled();
+        if self.loopback.is_none() && !inspect {
+            let inner = match self.inner {
+                Some(inner) => inner,
+                None => return Err(smoltcp::Error::Exhausted),
+            };
+            return inner.consume(timestamp, len, |buffer| {
+                let result = f(buffer);
+                if result.is_ok() {
+                    let mut headers = frame::parse(buffer);
+                    inspection.rewrite_tx_frame(buffer, &mut headers);
+                    if reply {
+                        count_unmatched(buffer, &headers);
+                    }
+                    account_tx(inspection, counters, buffer, &headers);
+                }
+                result
+            });
+        }
+        let mut frame = vec![0; len];
+        let result = f(&mut frame)?;
+        let mut headers = frame::parse(&frame);
+        inspection.rewrite_tx_frame(&mut frame, &mut headers);
+        if reply {
+            count_unmatched(&frame, &headers);
+        }
+        if let Some((loopback, injected)) = self.loopback {
+            if loopback.is_local(&frame, &headers) {
+                inc(&COUNTERS.loopback_frames);
+                stack_log!(trace, "looping back frame of {} bytes", frame.len());
+                capture(&frame, Direction::Tx);
+                injected.push_back(frame);
+                return Ok(result);
+            }
+        }
+        let inner = match self.inner {
+            Some(inner) => inner,
+            None => return Err(smoltcp::Error::Exhausted),
+        };
+        let mut frames = if inspect {
+            inspection.pmtu.inspect_tx_frame(frame, &headers)
+        } else {
+            vec![frame]
+        };
+        let first = frames.remove(0);
+        if !frames.is_empty() {
+            // the first segment of a split frame has another length
+            headers = frame::parse(&first);
+        }
+        self.deferred.extend(frames);
+        inner.consume(timestamp, first.len(), |buffer| {
+            buffer.copy_from_slice(&first);
+            account_tx(inspection, counters, buffer, &headers);
+            Ok(result)
+        })
+    }
+}
+
+fn account_tx(
+    inspection: &Inspection,
+    counters: &InterfaceCounters,
+    buffer: &[u8],
+    headers: &Headers,
+) {
+    counters.count_tx(buffer.len());
+    stack_log!(trace, "transmitting frame of {} bytes", buffer.len());
+    account_tx_frame(inspection, buffer, headers);
+    inspection.congestion.inspect_tx_frame(buffer, headers);
+    capture(buffer, Direction::Tx);
+}
+
+/// Looks into the headers of an outgoing frame to find events the stack does not report
+fn account_tx_frame(inspection: &Inspection, buffer: &[u8], headers: &Headers) {
+    let ip = match *headers {
+        Headers::Arp => {
+            if let Ok(arp) = ArpPacket::new_checked(&buffer[ETHERNET_HEADER_LEN..]) {
+                if arp.operation() == ArpOperation::Request {
+                    inc(&COUNTERS.tx_arp_requests);
+                    if arp.protocol_len() == 4 {
+                        let tpa = arp.target_protocol_addr();
+                        let ip = Ipv4Addr::new(tpa[0], tpa[1], tpa[2], tpa[3]);
+                        inspection.neighbors.inspect_tx_request(IpAddr::V4(ip));
+                    }
+                }
+            }
+            return;
+        }
+        Headers::Ip(ref ip) => ip,
+        Headers::Other => return,
+    };
+    match ip.protocol {
+        IpProtocol::Tcp => {
+            if let Ok(tcp) = TcpPacket::new_checked(ip.payload(buffer)) {
+                if tcp.rst() {
+                    inc(&COUNTERS.tx_tcp_resets);
+                }
+            }
+        }
+        IpProtocol::Icmpv6 if ip.src.is_ipv6() => {
+            if let Some(target) = neighbor::solicitation_target(buffer, headers) {
+                inspection.neighbors.inspect_tx_request(target);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Counts the received frame to which the stack answers, if no socket matched it, i.e., the
+/// answer is a port unreachable error or a TCP reset
+fn count_unmatched(buffer: &[u8], headers: &Headers) {
+    let ip = match *headers {
+        Headers::Ip(ref ip) => ip,
+        _ => return,
+    };
+    let unmatched = match ip.protocol {
+        IpProtocol::Tcp => match TcpPacket::new_checked(ip.payload(buffer)) {
+            Ok(tcp) => tcp.rst(),
+            Err(_) => false,
+        },
+        IpProtocol::Icmp if ip.src.is_ipv4() => {
+            match Icmpv4Packet::new_checked(ip.payload(buffer)) {
+                // code 3: port unreachable
+                Ok(icmp) => {
+                    icmp.msg_type() == Icmpv4Message::DstUnreachable && icmp.msg_code() == 3
+                }
+                Err(_) => false,
+            }
+        }
+        IpProtocol::Icmpv6 if ip.src.is_ipv6() => {
+            match Icmpv6Packet::new_checked(ip.payload(buffer)) {
+                // code 4: port unreachable
+                Ok(icmp) => {
+                    icmp.msg_type() == Icmpv6Message::DstUnreachable && icmp.msg_code() == 4
+                }
+                Err(_) => false,
+            }
+        }
+        _ => false,
+    };
+    if unmatched {
+        inc(&COUNTERS.rx_no_socket);
+    }
+}
+
+impl StcpBackendInterface {
+    /// Deletes a created tap or macvtap device and unregisters from usnetd, which is otherwise
+    /// done when the interface is dropped
+    pub fn detach(&mut self) {
         match self {
             StcpBackendInterface::MacVtap {
                 interface: _,
-                destroy: Some(ref name),
-            } => {
-                let _ = Command::new("pkexec")
-                    .args(&["ip", "link", "delete", name])
-                    .status();
+                ref mut destroy,
             }
-            StcpBackendInterface::Tap {
+            | StcpBackendInterface::Tap {
                 interface: _,
-                destroy: Some(ref name),
+                ref mut destroy,
             } => {
-                let _ = Command::new("pkexec")
-                    .args(&["ip", "link", "delete", name])
-                    .status();
+                if let Some(name) = destroy.take() {
+                    let _ = Command::new("pkexec")
+                        .args(&["ip", "link", "delete", &name[..]])
+                        .status();
+                }
             }
             StcpBackendInterface::UsnetUds {
                 interface: _,
@@ -290,7 +1449,20 @@ impl Drop for StcpBackendInterface {
     }
 }
 
+impl Drop for StcpBackendInterface {
+    fn drop(&mut self) {
+        self.detach();
+    }
+}
+
 fn delete(control: &mut UnixDatagram) {
+    if control
+        .local_addr()
+        .ok()
+        .map_or(false, |a| a.as_pathname().is_none())
+    {
+        return; // already unregistered by detach
+    }
     // unregister from usnetd
     let payl = serde_json::to_string(&ClientMessage::DeleteClient).unwrap();
     let sent_bytes = control
@@ -311,4 +1483,7 @@ fn delete(control: &mut UnixDatagram) {
     } else {
         error!("drop: no local addr");
     }
+    if let Ok(unbound) = UnixDatagram::unbound() {
+        *control = unbound; // the drop after detach does not unregister again
+    }
 }
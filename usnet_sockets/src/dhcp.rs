@@ -0,0 +1,250 @@
+/// DHCPv4 client for backends configured with "ipv4": "Dhcp"
+/// The client runs as part of the interface polling, i.e., in the background thread for the
+/// multithread API. It installs the leased address and default gateway on the interface,
+/// and renews the lease before it expires. Changes of the lease are reported through
+/// dhcp_status() and an optional callback, so that a lost lease does not go unnoticed.
+/// The DNS servers of the lease are used by DnsConfig::load_default(), and thus by
+/// resolve_host() and the other resolver functions, for both APIs. Only the host name
+/// lookups of the multithread API's sockets (connect("name:port")) use the per-stack list,
+/// the singlethread API resolves them with the configuration of the kernel.
+use parking_lot::RwLock;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+
+use smoltcp;
+use smoltcp::dhcp::{Dhcpv4Client, Dhcpv4Config};
+use smoltcp::socket::{RawPacketMetadata, RawSocketBuffer, SocketSet};
+use smoltcp::time::{Duration, Instant};
+
+use crate::device::StcpBackendInterface;
+
+/// Address configuration received from the DHCP server
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DhcpLease {
+    pub address: Ipv4Addr,
+    pub prefix_len: u8,
+    pub router: Option<Ipv4Addr>,
+    pub dns_servers: Vec<Ipv4Addr>,
+}
+
+impl DhcpLease {
+    /// Returns the DNS servers of the lease as name server addresses (port 53)
+    pub fn name_servers(&self) -> Vec<SocketAddr> {
+        self.dns_servers
+            .iter()
+            .map(|ip| SocketAddr::new(IpAddr::V4(*ip), 53))
+            .collect()
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DhcpStatus {
+    /// The backend does not use DHCP
+    Disabled,
+    /// Waiting for the first lease
+    Discovering,
+    Bound(DhcpLease),
+    /// The lease expired without renewal, the address was removed from the interface
+    /// and existing connections will fail until a new lease is received
+    Lost,
+}
+
+pub type DhcpCallback = Arc<dyn Fn(&DhcpStatus) + Send + Sync>;
+
+lazy_static! {
+    static ref STATUS: RwLock<DhcpStatus> = RwLock::new(DhcpStatus::Disabled);
+    static ref CALLBACK: RwLock<Option<DhcpCallback>> = RwLock::new(None);
+}
+
+/// Returns the current state of the DHCP client
+pub fn dhcp_status() -> DhcpStatus {
+    STATUS.read().clone()
+}
+
+/// Calls the function whenever a lease is received, renewed with a different configuration, or lost
+/// (replaces an active callback)
+/// For the singlethread API it runs while the stack is in use and must not call socket operations.
+pub fn set_dhcp_callback<F: Fn(&DhcpStatus) + Send + Sync + 'static>(f: F) {
+    *CALLBACK.write() = Some(Arc::new(f));
+}
+
+pub fn clear_dhcp_callback() {
+    *CALLBACK.write() = None;
+}
+
+/// Returns the name servers of the current lease, which replace the ones of
+/// /etc/resolv.conf in DnsConfig::load_default() (empty if no lease is bound)
+pub(crate) fn lease_name_servers() -> Vec<SocketAddr> {
+    match *STATUS.read() {
+        DhcpStatus::Bound(ref lease) => lease.name_servers(),
+        _ => Vec::new(),
+    }
+}
+
+/// Calls the callback without holding the lock of the callback
+pub(crate) fn notify(status: &DhcpStatus) {
+    let callback = CALLBACK.read().clone();
+    if let Some(callback) = callback {
+        callback(status);
+    }
+}
+
+pub(crate) struct DhcpClient {
+    client: Dhcpv4Client,
+}
+
+impl fmt::Debug for DhcpClient {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "DHCP client {:?}", *STATUS.read())
+    }
+}
+
+impl DhcpClient {
+    pub fn new(sockets: &mut SocketSet<'static>, timestamp: Instant) -> DhcpClient {
+        let rx_buffer = RawSocketBuffer::new(vec![RawPacketMetadata::EMPTY; 1], vec![0; 900]);
+        let tx_buffer = RawSocketBuffer::new(vec![RawPacketMetadata::EMPTY; 1], vec![0; 600]);
+        *STATUS.write() = DhcpStatus::Discovering;
+        DhcpClient {
+            client: Dhcpv4Client::new(sockets, rx_buffer, tx_buffer, timestamp),
+        }
+    }
+
+    /// Processes DHCP packets and timers, returns the new status if it changed
+    pub fn poll(
+        &mut self,
+        iface: &mut StcpBackendInterface,
+        sockets: &mut SocketSet,
+        timestamp: Instant,
+    ) -> Option<DhcpStatus> {
+        let config = match iface.dhcp_poll(&mut self.client, sockets, timestamp) {
+            Ok(Some(config)) => config,
+            Ok(None) => return None,
+            Err(err) => {
+                debug!("DHCP poll result: {}", err);
+                return None;
+            }
+        };
+        let status = match lease_from_config(&config) {
+            Some(lease) => {
+                iface.set_ipv4_config(
+                    smoltcp::wire::Ipv4Cidr::new(lease.address.into(), lease.prefix_len),
+                    lease.router.map(|r| r.into()),
+                );
+                DhcpStatus::Bound(lease)
+            }
+            None => {
+                if *STATUS.read() != DhcpStatus::Lost {
+                    iface.clear_ipv4_config();
+                }
+                DhcpStatus::Lost
+            }
+        };
+        update_status(&mut STATUS.write(), status)
+    }
+
+    pub fn next_poll(&self, timestamp: Instant) -> Duration {
+        self.client.next_poll(timestamp)
+    }
+}
+
+/// Stores the new status, returns it if it differs from the current one
+fn update_status(current: &mut DhcpStatus, status: DhcpStatus) -> Option<DhcpStatus> {
+    if *current == status {
+        return None;
+    }
+    match status {
+        DhcpStatus::Bound(ref lease) => info!("DHCP lease: {:?}", lease),
+        _ => warn!("DHCP lease lost"),
+    }
+    *current = status.clone();
+    Some(status)
+}
+
+fn lease_from_config(config: &Dhcpv4Config) -> Option<DhcpLease> {
+    config.address.map(|cidr| DhcpLease {
+        address: Ipv4Addr::from(cidr.address()),
+        prefix_len: cidr.prefix_len(),
+        router: config.router.map(Ipv4Addr::from),
+        dns_servers: config
+            .dns_servers
+            .iter()
+            .filter_map(|s| s.map(Ipv4Addr::from))
+            .collect(),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::{lease_from_config, update_status, DhcpLease, DhcpStatus};
+    use smoltcp::dhcp::Dhcpv4Config;
+    use smoltcp::wire::{Ipv4Address, Ipv4Cidr};
+    use std::net::{Ipv4Addr, SocketAddr};
+
+    fn config(dns_servers: [Option<Ipv4Address>; 3]) -> Dhcpv4Config {
+        Dhcpv4Config {
+            address: Some(Ipv4Cidr::new(Ipv4Address::new(192, 168, 1, 20), 24)),
+            router: Some(Ipv4Address::new(192, 168, 1, 1)),
+            dns_servers: dns_servers,
+        }
+    }
+
+    #[test]
+    fn test_lease_from_config() {
+        let lease = lease_from_config(&config([
+            None,
+            Some(Ipv4Address::new(192, 168, 1, 2)),
+            Some(Ipv4Address::new(8, 8, 8, 8)),
+        ]))
+        .unwrap();
+        assert_eq!(
+            lease,
+            DhcpLease {
+                address: Ipv4Addr::new(192, 168, 1, 20),
+                prefix_len: 24,
+                router: Some(Ipv4Addr::new(192, 168, 1, 1)),
+                dns_servers: vec![Ipv4Addr::new(192, 168, 1, 2), Ipv4Addr::new(8, 8, 8, 8)],
+            }
+        );
+        let expected: Vec<SocketAddr> = vec![
+            "192.168.1.2:53".parse().unwrap(),
+            "8.8.8.8:53".parse().unwrap(),
+        ];
+        assert_eq!(lease.name_servers(), expected);
+
+        let no_dns = lease_from_config(&config([None; 3])).unwrap();
+        assert!(no_dns.name_servers().is_empty());
+
+        // a configuration without an address means the lease expired
+        let mut expired = config([None; 3]);
+        expired.address = None;
+        assert_eq!(lease_from_config(&expired), None);
+    }
+
+    #[test]
+    fn test_update_status() {
+        let lease = lease_from_config(&config([None; 3])).unwrap();
+        let mut current = DhcpStatus::Discovering;
+        assert_eq!(
+            update_status(&mut current, DhcpStatus::Bound(lease.clone())),
+            Some(DhcpStatus::Bound(lease.clone()))
+        );
+        // a renewal with the same configuration is not reported
+        assert_eq!(
+            update_status(&mut current, DhcpStatus::Bound(lease.clone())),
+            None
+        );
+        let mut changed = lease.clone();
+        changed.router = None;
+        assert_eq!(
+            update_status(&mut current, DhcpStatus::Bound(changed.clone())),
+            Some(DhcpStatus::Bound(changed))
+        );
+        assert_eq!(
+            update_status(&mut current, DhcpStatus::Lost),
+            Some(DhcpStatus::Lost)
+        );
+        assert_eq!(update_status(&mut current, DhcpStatus::Lost), None);
+        assert_eq!(current, DhcpStatus::Lost);
+    }
+}
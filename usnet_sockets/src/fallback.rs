@@ -0,0 +1,102 @@
+/// Selection between the userspace network stack and kernel sockets per destination
+/// In mixed deployments only the destinations in the networks of the userspace stack use
+/// it, while others, e.g., a metrics server in the office network, are reached through
+/// kernel sockets. The socket types stay the same: a TcpStream to such a destination is a
+/// TcpStream::System, and a UdpSocket sends to it through a kernel socket which it creates
+/// on first use and also receives from.
+/// Loopback and local destinations are not affected, they always go through the kernel
+/// loopback interface or the loopback of the stack.
+use std::fmt;
+use std::io;
+use std::net::IpAddr;
+use std::sync::Arc;
+
+use crate::routing;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum SocketPath {
+    /// The userspace network stack
+    Usnet,
+    /// A socket of the kernel network stack
+    Kernel,
+}
+
+impl fmt::Display for SocketPath {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SocketPath::Usnet => write!(f, "userspace network stack"),
+            SocketPath::Kernel => write!(f, "kernel network stack"),
+        }
+    }
+}
+
+/// Decides for each remote destination which network stack is used
+#[derive(Clone)]
+pub enum PathPolicy {
+    /// All destinations are reached through the userspace network stack (the default)
+    Usnet,
+    /// Destinations in these networks (address and prefix length) are reached through the
+    /// userspace network stack, all others through kernel sockets
+    Prefixes(Vec<(IpAddr, u8)>),
+    /// The function returns the path for a destination, it is called without holding the
+    /// stack lock
+    Callback(Arc<dyn Fn(IpAddr) -> SocketPath + Send + Sync>),
+}
+
+impl fmt::Debug for PathPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PathPolicy::Usnet => write!(f, "Usnet"),
+            PathPolicy::Prefixes(prefixes) => write!(f, "Prefixes({:?})", prefixes),
+            PathPolicy::Callback(_) => write!(f, "Callback"),
+        }
+    }
+}
+
+impl Default for PathPolicy {
+    fn default() -> PathPolicy {
+        PathPolicy::Usnet
+    }
+}
+
+impl PathPolicy {
+    /// Checks the prefix lengths and clears the host bits, as for routes
+    pub fn prefixes(prefixes: Vec<(IpAddr, u8)>) -> io::Result<PathPolicy> {
+        let prefixes = prefixes
+            .into_iter()
+            .map(|(network, prefix_len)| {
+                routing::check_prefix_len(network, prefix_len)?;
+                Ok((routing::network(network, prefix_len), prefix_len))
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+        Ok(PathPolicy::Prefixes(prefixes))
+    }
+    pub fn path(&self, dst: IpAddr) -> SocketPath {
+        match self {
+            PathPolicy::Usnet => SocketPath::Usnet,
+            PathPolicy::Prefixes(prefixes) => {
+                if prefixes
+                    .iter()
+                    .any(|&(network, prefix_len)| routing::in_network(network, prefix_len, dst))
+                {
+                    SocketPath::Usnet
+                } else {
+                    SocketPath::Kernel
+                }
+            }
+            PathPolicy::Callback(f) => f(dst),
+        }
+    }
+    /// Whether some destinations may use kernel sockets
+    pub(crate) fn has_fallback(&self) -> bool {
+        match self {
+            PathPolicy::Usnet => false,
+            _ => true,
+        }
+    }
+}
+
+/// Adds the path to the error message, so that it tells which network stack failed
+pub(crate) fn path_error(err: io::Error, path: SocketPath) -> io::Error {
+    io::Error::new(err.kind(), format!("{} (through the {})", err, path))
+}
@@ -0,0 +1,97 @@
+/// Headers of a frame which the device layer parses once for all inspections
+/// The inspections of the stack state and the counters look at the located IP payload
+/// instead of each parsing the Ethernet and IP headers of every frame again.
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use smoltcp::wire::{EthernetFrame, EthernetProtocol, IpProtocol, Ipv4Packet, Ipv6Packet};
+
+pub(crate) const ETHERNET_HEADER_LEN: usize = 14;
+const IPV6_HEADER_LEN: usize = 40;
+
+/// Fields of the IPv4 or IPv6 header of a frame
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(crate) struct IpHeader {
+    pub src: IpAddr,
+    pub dst: IpAddr,
+    /// The next header for IPv6, the stack sends and inspects no extension headers
+    pub protocol: IpProtocol,
+    pub hop_limit: u8,
+    pub more_frags: bool,
+    pub frag_offset: u16,
+    /// Start and end of the IP payload in the frame
+    pub payload: usize,
+    pub end: usize,
+}
+
+impl IpHeader {
+    /// Whether the payload starts with the header of the protocol
+    pub fn first_fragment(&self) -> bool {
+        self.frag_offset == 0
+    }
+    /// Whether the payload is neither a fragment nor followed by one
+    pub fn unfragmented(&self) -> bool {
+        !self.more_frags && self.frag_offset == 0
+    }
+    pub fn payload<'a>(&self, buffer: &'a [u8]) -> &'a [u8] {
+        &buffer[self.payload..self.end]
+    }
+    pub fn payload_mut<'a>(&self, buffer: &'a mut [u8]) -> &'a mut [u8] {
+        &mut buffer[self.payload..self.end]
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(crate) enum Headers {
+    Arp,
+    Ip(IpHeader),
+    /// Another ethertype, or a truncated frame
+    Other,
+}
+
+impl Headers {
+    /// Returns the IP header if the frame carries the protocol
+    pub fn ip(&self, protocol: IpProtocol) -> Option<&IpHeader> {
+        match *self {
+            Headers::Ip(ref ip) if ip.protocol == protocol => Some(ip),
+            _ => None,
+        }
+    }
+}
+
+/// Parses the Ethernet and IP headers of a frame
+pub(crate) fn parse(buffer: &[u8]) -> Headers {
+    let frame = match EthernetFrame::new_checked(buffer) {
+        Ok(frame) => frame,
+        Err(_) => return Headers::Other,
+    };
+    match frame.ethertype() {
+        EthernetProtocol::Arp => Headers::Arp,
+        EthernetProtocol::Ipv4 => match Ipv4Packet::new_checked(frame.payload()) {
+            Ok(ip) => Headers::Ip(IpHeader {
+                src: IpAddr::V4(Ipv4Addr::from(ip.src_addr())),
+                dst: IpAddr::V4(Ipv4Addr::from(ip.dst_addr())),
+                protocol: ip.protocol(),
+                hop_limit: ip.hop_limit(),
+                more_frags: ip.more_frags(),
+                frag_offset: ip.frag_offset(),
+                payload: ETHERNET_HEADER_LEN + ip.header_len() as usize,
+                end: ETHERNET_HEADER_LEN + ip.total_len() as usize,
+            }),
+            Err(_) => Headers::Other,
+        },
+        EthernetProtocol::Ipv6 => match Ipv6Packet::new_checked(frame.payload()) {
+            Ok(ip) => Headers::Ip(IpHeader {
+                src: IpAddr::V6(Ipv6Addr::from(ip.src_addr())),
+                dst: IpAddr::V6(Ipv6Addr::from(ip.dst_addr())),
+                protocol: ip.next_header(),
+                hop_limit: ip.hop_limit(),
+                more_frags: false,
+                frag_offset: 0,
+                payload: ETHERNET_HEADER_LEN + IPV6_HEADER_LEN,
+                end: ETHERNET_HEADER_LEN + IPV6_HEADER_LEN + ip.payload_len() as usize,
+            }),
+            Err(_) => Headers::Other,
+        },
+        _ => Headers::Other,
+    }
+}
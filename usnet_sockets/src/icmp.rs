@@ -0,0 +1,554 @@
+/// ICMP echo messages and errors for the IcmpSocket
+/// smoltcp delivers echo replies with the identifier of a socket but not the ICMP errors
+/// which routers send back for an echo request. Therefore the device layer looks into
+/// received frames for destination unreachable, packet too big, and time exceeded
+/// messages which quote an echo request and queues them for the socket with the
+/// identifier of the quoted request. Only errors sent to an address of the stack or
+/// quoting a request sent from one are queued, the identifiers of other hosts and stacks
+/// on the link may be the same, and each stack keeps the identifiers of its own sockets.
+use parking_lot::Mutex;
+use std::collections::{BTreeMap, VecDeque};
+use std::error;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use smoltcp::wire::{IpAddress, IpProtocol};
+
+use crate::frame::Headers;
+
+const ICMPV4_ECHO_REPLY: u8 = 0;
+const ICMPV4_DST_UNREACHABLE: u8 = 3;
+const ICMPV4_ECHO_REQUEST: u8 = 8;
+const ICMPV4_TIME_EXCEEDED: u8 = 11;
+const ICMPV4_FRAG_NEEDED: u8 = 4; // code of destination unreachable
+const ICMPV6_DST_UNREACHABLE: u8 = 1;
+const ICMPV6_PKT_TOO_BIG: u8 = 2;
+const ICMPV6_TIME_EXCEEDED: u8 = 3;
+const ICMPV6_ECHO_REQUEST: u8 = 128;
+const ICMPV6_ECHO_REPLY: u8 = 129;
+const ICMP_HEADER_LEN: usize = 8;
+const IPV6_HEADER_LEN: usize = 40;
+const MAX_QUEUED_ERRORS: usize = 64;
+
+/// Echo reply received for an echo request of the socket
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct EchoReply {
+    /// Sender of the reply
+    pub from: IpAddr,
+    /// Sequence number of the answered echo request
+    pub seq_no: u16,
+    /// Data of the reply, the same as the one of the request if the sender echoes it
+    pub payload: Vec<u8>,
+    /// Time since the echo request with this sequence number was sent to the address,
+    /// None if the socket did not send it or it was sent too long ago
+    pub rtt: Option<Duration>,
+}
+
+/// Type of an ICMP or ICMPv6 error, with the code or MTU of the message
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum IcmpErrorKind {
+    /// Destination unreachable with the ICMP or ICMPv6 code
+    DestinationUnreachable(u8),
+    /// Packet too big, or destination unreachable with "fragmentation needed",
+    /// with the MTU of the next hop (0 if the router did not report it)
+    PacketTooBig(u32),
+    /// Time exceeded with the code (0 for TTL exceeded in transit)
+    TimeExceeded(u8),
+}
+
+/// ICMP error sent back by a router or the destination for an echo request of the socket
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct IcmpError {
+    /// Sender of the error
+    pub from: IpAddr,
+    /// Destination of the echo request
+    pub dst: IpAddr,
+    /// Sequence number of the echo request
+    pub seq_no: u16,
+    /// Type of the error
+    pub kind: IcmpErrorKind,
+}
+
+impl fmt::Display for IcmpError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.kind {
+            IcmpErrorKind::DestinationUnreachable(code) => write!(
+                f,
+                "destination {} unreachable (code {}) reported by {}",
+                self.dst, code, self.from
+            ),
+            IcmpErrorKind::PacketTooBig(mtu) => write!(
+                f,
+                "packet to {} too big for MTU {} reported by {}",
+                self.dst, mtu, self.from
+            ),
+            IcmpErrorKind::TimeExceeded(code) => write!(
+                f,
+                "time exceeded (code {}) for {} reported by {}",
+                code, self.dst, self.from
+            ),
+        }
+    }
+}
+
+impl error::Error for IcmpError {}
+
+/// Result of receiving on an IcmpSocket
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum IcmpResponse {
+    /// Echo reply for a request of the socket
+    Reply(EchoReply),
+    /// ICMP error for a request of the socket
+    Error(IcmpError),
+}
+
+/// Identifiers of the IcmpSockets of one stack and their queued errors
+#[derive(Debug, Default)]
+pub(crate) struct Errors {
+    // number of registered identifiers, the frames are not inspected if there are none
+    registered: AtomicUsize,
+    errors: Mutex<BTreeMap<u16, VecDeque<IcmpError>>>,
+}
+
+impl Errors {
+    /// Reserves the identifier for a socket, returns false if it is used by another socket
+    pub fn register(&self, ident: u16) -> bool {
+        let mut errors = self.errors.lock();
+        if errors.contains_key(&ident) {
+            return false;
+        }
+        errors.insert(ident, VecDeque::new());
+        self.registered.fetch_add(1, Ordering::Relaxed);
+        true
+    }
+
+    /// Releases the identifier and drops its queued errors
+    pub fn unregister(&self, ident: u16) {
+        if self.errors.lock().remove(&ident).is_some() {
+            self.registered.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Returns the oldest queued error for the identifier
+    pub fn take_error(&self, ident: u16) -> Option<IcmpError> {
+        self.errors
+            .lock()
+            .get_mut(&ident)
+            .and_then(|queue| queue.pop_front())
+    }
+
+    /// Queues an ICMP error for an echo request of a registered identifier (called for every
+    /// received frame with the addresses of the stack)
+    pub fn inspect_rx_frame(&self, buffer: &[u8], headers: &Headers, local: &[IpAddress]) {
+        if self.registered.load(Ordering::Relaxed) == 0 {
+            return;
+        }
+        if let Some((ident, error)) = parse_error_frame(buffer, headers, local) {
+            let mut errors = self.errors.lock();
+            if let Some(queue) = errors.get_mut(&ident) {
+                if queue.len() == MAX_QUEUED_ERRORS {
+                    queue.pop_front();
+                }
+                trace!("ICMP error for identifier {}: {}", ident, error);
+                queue.push_back(error);
+            }
+        }
+    }
+}
+
+/// Returns the ICMP or ICMPv6 echo request, the checksum is filled in by smoltcp
+pub(crate) fn echo_request(ipv6: bool, ident: u16, seq_no: u16, payload: &[u8]) -> Vec<u8> {
+    let mut packet = Vec::with_capacity(ICMP_HEADER_LEN + payload.len());
+    packet.push(if ipv6 {
+        ICMPV6_ECHO_REQUEST
+    } else {
+        ICMPV4_ECHO_REQUEST
+    });
+    packet.extend_from_slice(&[0, 0, 0]); // code and checksum
+    packet.extend_from_slice(&ident.to_be_bytes());
+    packet.extend_from_slice(&seq_no.to_be_bytes());
+    packet.extend_from_slice(payload);
+    packet
+}
+
+/// Returns the sequence number and payload if the ICMP packet is an echo reply
+/// smoltcp also delivers echo requests with the identifier of the socket.
+pub(crate) fn parse_echo_reply(packet: &[u8]) -> Option<(u16, &[u8])> {
+    if packet.len() < ICMP_HEADER_LEN
+        || (packet[0] != ICMPV4_ECHO_REPLY && packet[0] != ICMPV6_ECHO_REPLY)
+        || packet[1] != 0
+    {
+        return None;
+    }
+    Some((be16(&packet[6..]), &packet[ICMP_HEADER_LEN..]))
+}
+
+/// Returns the identifier of the quoted echo request and the error, None if the frame
+/// is no ICMP error for an echo request or neither was it sent to a local address nor
+/// does it quote a request from one
+fn parse_error_frame(
+    buffer: &[u8],
+    headers: &Headers,
+    local: &[IpAddress],
+) -> Option<(u16, IcmpError)> {
+    let ip = match *headers {
+        Headers::Ip(ref ip) => ip,
+        _ => return None,
+    };
+    let (from, to, icmp) = (ip.src, ip.dst, ip.payload(buffer));
+    match ip.protocol {
+        IpProtocol::Icmp if from.is_ipv4() => {
+            if icmp.len() < ICMP_HEADER_LEN {
+                return None;
+            }
+            let kind = match (icmp[0], icmp[1]) {
+                (ICMPV4_DST_UNREACHABLE, ICMPV4_FRAG_NEEDED) => {
+                    IcmpErrorKind::PacketTooBig(be16(&icmp[6..]) as u32)
+                }
+                (ICMPV4_DST_UNREACHABLE, code) => IcmpErrorKind::DestinationUnreachable(code),
+                (ICMPV4_TIME_EXCEEDED, code) => IcmpErrorKind::TimeExceeded(code),
+                _ => return None,
+            };
+            // the quoted IPv4 header and the first 8 bytes of the echo request
+            let quoted = &icmp[ICMP_HEADER_LEN..];
+            if quoted.len() < 20 || quoted[0] >> 4 != 4 || quoted[9] != u8::from(IpProtocol::Icmp) {
+                return None;
+            }
+            let header_len = ((quoted[0] & 0x0f) as usize) * 4;
+            let mut src = [0; 4];
+            src.copy_from_slice(&quoted[12..16]);
+            if !is_local(local, to) && !is_local(local, IpAddr::V4(Ipv4Addr::from(src))) {
+                return None;
+            }
+            let mut dst = [0; 4];
+            dst.copy_from_slice(&quoted[16..20]);
+            let request = quoted.get(header_len..)?;
+            if request.len() < ICMP_HEADER_LEN || request[0] != ICMPV4_ECHO_REQUEST {
+                return None;
+            }
+            Some((
+                be16(&request[4..]),
+                IcmpError {
+                    from: from,
+                    dst: IpAddr::V4(Ipv4Addr::from(dst)),
+                    seq_no: be16(&request[6..]),
+                    kind: kind,
+                },
+            ))
+        }
+        IpProtocol::Icmpv6 if from.is_ipv6() => {
+            if icmp.len() < ICMP_HEADER_LEN {
+                return None;
+            }
+            let kind = match icmp[0] {
+                ICMPV6_DST_UNREACHABLE => IcmpErrorKind::DestinationUnreachable(icmp[1]),
+                ICMPV6_PKT_TOO_BIG => IcmpErrorKind::PacketTooBig(be32(&icmp[4..])),
+                ICMPV6_TIME_EXCEEDED => IcmpErrorKind::TimeExceeded(icmp[1]),
+                _ => return None,
+            };
+            // the quoted IPv6 header (extension headers are not supported) and echo request
+            let quoted = &icmp[ICMP_HEADER_LEN..];
+            if quoted.len() < IPV6_HEADER_LEN + ICMP_HEADER_LEN
+                || quoted[0] >> 4 != 6
+                || quoted[6] != u8::from(IpProtocol::Icmpv6)
+            {
+                return None;
+            }
+            let mut src = [0; 16];
+            src.copy_from_slice(&quoted[8..24]);
+            if !is_local(local, to) && !is_local(local, IpAddr::V6(Ipv6Addr::from(src))) {
+                return None;
+            }
+            let mut dst = [0; 16];
+            dst.copy_from_slice(&quoted[24..40]);
+            let request = &quoted[IPV6_HEADER_LEN..];
+            if request[0] != ICMPV6_ECHO_REQUEST {
+                return None;
+            }
+            Some((
+                be16(&request[4..]),
+                IcmpError {
+                    from: from,
+                    dst: IpAddr::V6(Ipv6Addr::from(dst)),
+                    seq_no: be16(&request[6..]),
+                    kind: kind,
+                },
+            ))
+        }
+        _ => None,
+    }
+}
+
+fn is_local(local: &[IpAddress], ip: IpAddr) -> bool {
+    local.contains(&IpAddress::from(ip))
+}
+
+fn be16(data: &[u8]) -> u16 {
+    u16::from_be_bytes([data[0], data[1]])
+}
+
+fn be32(data: &[u8]) -> u32 {
+    u32::from_be_bytes([data[0], data[1], data[2], data[3]])
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        echo_request, parse_echo_reply, parse_error_frame, Errors, IcmpError, IcmpErrorKind,
+        MAX_QUEUED_ERRORS,
+    };
+    use crate::frame;
+    use smoltcp::wire::IpAddress;
+    use std::net::IpAddr;
+
+    /// Time exceeded in transit from the router 10.0.0.1 to 10.0.0.2 for the echo request with
+    /// the identifier 0x1234 and sequence number 7 to 192.0.2.7, quoting its first 8 bytes
+    const V4_TIME_EXCEEDED: [u8; 70] = [
+        0x52, 0x54, 0x00, 0x12, 0x34, 0x56, 0x52, 0x54, 0x00, 0x00, 0x00, 0x01, //
+        0x08, 0x00, 0x45, 0x00, 0x00, 0x38, 0x01, 0x00, 0x00, 0x00, 0x40, 0x01, //
+        0x65, 0xc3, 0x0a, 0x00, 0x00, 0x01, 0x0a, 0x00, 0x00, 0x02, 0x0b, 0x00, //
+        0x2b, 0xca, 0x00, 0x00, 0x00, 0x00, 0x45, 0x00, 0x00, 0x26, 0x00, 0x01, //
+        0x00, 0x00, 0x01, 0x01, 0xed, 0xcd, 0x0a, 0x00, 0x00, 0x02, 0xc0, 0x00, //
+        0x02, 0x07, 0x08, 0x00, 0xae, 0xfa, 0x12, 0x34, 0x00, 0x07,
+    ];
+
+    /// Host unreachable (code 1) from 10.0.0.1 for the same echo request
+    const V4_HOST_UNREACHABLE: [u8; 70] = [
+        0x52, 0x54, 0x00, 0x12, 0x34, 0x56, 0x52, 0x54, 0x00, 0x00, 0x00, 0x01, //
+        0x08, 0x00, 0x45, 0x00, 0x00, 0x38, 0x01, 0x01, 0x00, 0x00, 0x40, 0x01, //
+        0x65, 0xc2, 0x0a, 0x00, 0x00, 0x01, 0x0a, 0x00, 0x00, 0x02, 0x03, 0x01, //
+        0x33, 0xc9, 0x00, 0x00, 0x00, 0x00, 0x45, 0x00, 0x00, 0x26, 0x00, 0x01, //
+        0x00, 0x00, 0x01, 0x01, 0xed, 0xcd, 0x0a, 0x00, 0x00, 0x02, 0xc0, 0x00, //
+        0x02, 0x07, 0x08, 0x00, 0xae, 0xfa, 0x12, 0x34, 0x00, 0x07,
+    ];
+
+    /// Fragmentation needed with a next-hop MTU of 1400 from 10.0.0.1 for the same echo request
+    const V4_FRAG_NEEDED: [u8; 70] = [
+        0x52, 0x54, 0x00, 0x12, 0x34, 0x56, 0x52, 0x54, 0x00, 0x00, 0x00, 0x01, //
+        0x08, 0x00, 0x45, 0x00, 0x00, 0x38, 0x01, 0x02, 0x00, 0x00, 0x40, 0x01, //
+        0x65, 0xc1, 0x0a, 0x00, 0x00, 0x01, 0x0a, 0x00, 0x00, 0x02, 0x03, 0x04, //
+        0x2e, 0x4e, 0x00, 0x00, 0x05, 0x78, 0x45, 0x00, 0x00, 0x26, 0x00, 0x01, //
+        0x00, 0x00, 0x01, 0x01, 0xed, 0xcd, 0x0a, 0x00, 0x00, 0x02, 0xc0, 0x00, //
+        0x02, 0x07, 0x08, 0x00, 0xae, 0xfa, 0x12, 0x34, 0x00, 0x07,
+    ];
+
+    /// Address unreachable (code 3) from the router 2001:db8:1::1 to 2001:db8:1::2 for the echo
+    /// request with the identifier 0x1234 and sequence number 7 to 2001:db8:2::7
+    const V6_ADDRESS_UNREACHABLE: [u8; 110] = [
+        0x52, 0x54, 0x00, 0x12, 0x34, 0x56, 0x52, 0x54, 0x00, 0x00, 0x00, 0x01, //
+        0x86, 0xdd, 0x60, 0x00, 0x00, 0x00, 0x00, 0x38, 0x3a, 0x40, 0x20, 0x01, //
+        0x0d, 0xb8, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, //
+        0x00, 0x01, 0x20, 0x01, 0x0d, 0xb8, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, //
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x01, 0x03, 0x40, 0x16, 0x00, 0x00, //
+        0x00, 0x00, 0x60, 0x00, 0x00, 0x00, 0x00, 0x12, 0x3a, 0x01, 0x20, 0x01, //
+        0x0d, 0xb8, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, //
+        0x00, 0x02, 0x20, 0x01, 0x0d, 0xb8, 0x00, 0x02, 0x00, 0x00, 0x00, 0x00, //
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x07, 0x80, 0x00, 0xdb, 0x2f, 0x12, 0x34, //
+        0x00, 0x07,
+    ];
+
+    /// Packet too big with an MTU of 1280 from 2001:db8:1::1 for the same echo request
+    const V6_PACKET_TOO_BIG: [u8; 110] = [
+        0x52, 0x54, 0x00, 0x12, 0x34, 0x56, 0x52, 0x54, 0x00, 0x00, 0x00, 0x01, //
+        0x86, 0xdd, 0x60, 0x00, 0x00, 0x00, 0x00, 0x38, 0x3a, 0x40, 0x20, 0x01, //
+        0x0d, 0xb8, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, //
+        0x00, 0x01, 0x20, 0x01, 0x0d, 0xb8, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, //
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x02, 0x00, 0x3a, 0x19, 0x00, 0x00, //
+        0x05, 0x00, 0x60, 0x00, 0x00, 0x00, 0x00, 0x12, 0x3a, 0x01, 0x20, 0x01, //
+        0x0d, 0xb8, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, //
+        0x00, 0x02, 0x20, 0x01, 0x0d, 0xb8, 0x00, 0x02, 0x00, 0x00, 0x00, 0x00, //
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x07, 0x80, 0x00, 0xdb, 0x2f, 0x12, 0x34, //
+        0x00, 0x07,
+    ];
+
+    /// Hop limit exceeded in transit from 2001:db8:1::1 for the same echo request
+    const V6_TIME_EXCEEDED: [u8; 110] = [
+        0x52, 0x54, 0x00, 0x12, 0x34, 0x56, 0x52, 0x54, 0x00, 0x00, 0x00, 0x01, //
+        0x86, 0xdd, 0x60, 0x00, 0x00, 0x00, 0x00, 0x38, 0x3a, 0x40, 0x20, 0x01, //
+        0x0d, 0xb8, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, //
+        0x00, 0x01, 0x20, 0x01, 0x0d, 0xb8, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, //
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x03, 0x00, 0x3e, 0x19, 0x00, 0x00, //
+        0x00, 0x00, 0x60, 0x00, 0x00, 0x00, 0x00, 0x12, 0x3a, 0x01, 0x20, 0x01, //
+        0x0d, 0xb8, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, //
+        0x00, 0x02, 0x20, 0x01, 0x0d, 0xb8, 0x00, 0x02, 0x00, 0x00, 0x00, 0x00, //
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x07, 0x80, 0x00, 0xdb, 0x2f, 0x12, 0x34, //
+        0x00, 0x07,
+    ];
+
+    /// ICMP part of the echo reply from 192.0.2.7 with the payload "usnet ping"
+    const V4_ECHO_REPLY: [u8; 18] = [
+        0x00, 0x00, 0xb6, 0xfa, 0x12, 0x34, 0x00, 0x07, 0x75, 0x73, 0x6e, 0x65, //
+        0x74, 0x20, 0x70, 0x69, 0x6e, 0x67,
+    ];
+
+    /// ICMPv6 part of the echo reply from 2001:db8:2::7
+    const V6_ECHO_REPLY: [u8; 18] = [
+        0x81, 0x00, 0xda, 0x2f, 0x12, 0x34, 0x00, 0x07, 0x75, 0x73, 0x6e, 0x65, //
+        0x74, 0x20, 0x70, 0x69, 0x6e, 0x67,
+    ];
+
+    fn local(addr: &str) -> Vec<IpAddress> {
+        vec![IpAddress::from(addr.parse::<IpAddr>().unwrap())]
+    }
+
+    fn error(from: &str, dst: &str, kind: IcmpErrorKind) -> IcmpError {
+        IcmpError {
+            from: from.parse().unwrap(),
+            dst: dst.parse().unwrap(),
+            seq_no: 7,
+            kind: kind,
+        }
+    }
+
+    fn parse(frame: &[u8], local: &[IpAddress]) -> Option<(u16, IcmpError)> {
+        parse_error_frame(frame, &frame::parse(frame), local)
+    }
+
+    fn inspect(errors: &Errors, frame: &[u8], local: &[IpAddress]) {
+        errors.inspect_rx_frame(frame, &frame::parse(frame), local);
+    }
+
+    #[test]
+    fn test_echo_request() {
+        // the quoted requests with the checksum left for smoltcp
+        let mut request = echo_request(false, 0x1234, 7, b"usnet ping");
+        assert_eq!(request.len(), 18);
+        assert_eq!(&request[8..], b"usnet ping");
+        request[2..4].copy_from_slice(&V4_TIME_EXCEEDED[64..66]);
+        assert_eq!(&request[..8], &V4_TIME_EXCEEDED[62..70]);
+        let mut request = echo_request(true, 0x1234, 7, b"usnet ping");
+        request[2..4].copy_from_slice(&V6_TIME_EXCEEDED[104..106]);
+        assert_eq!(&request[..8], &V6_TIME_EXCEEDED[102..110]);
+        assert_eq!(
+            echo_request(true, 1, 2, &[]),
+            vec![128, 0, 0, 0, 0, 1, 0, 2]
+        );
+    }
+
+    #[test]
+    fn test_parse_echo_reply() {
+        assert_eq!(
+            parse_echo_reply(&V4_ECHO_REPLY),
+            Some((7, &b"usnet ping"[..]))
+        );
+        assert_eq!(
+            parse_echo_reply(&V6_ECHO_REPLY),
+            Some((7, &b"usnet ping"[..]))
+        );
+        assert_eq!(parse_echo_reply(&V4_ECHO_REPLY[..8]), Some((7, &[][..])));
+        assert_eq!(parse_echo_reply(&V4_ECHO_REPLY[..7]), None);
+        // echo requests with the identifier of the socket and replies with a code
+        let request = echo_request(false, 0x1234, 7, b"usnet ping");
+        assert_eq!(parse_echo_reply(&request), None);
+        let mut reply = V6_ECHO_REPLY;
+        reply[1] = 1;
+        assert_eq!(parse_echo_reply(&reply), None);
+    }
+
+    #[test]
+    fn test_parse_ipv4_errors() {
+        let local = local("10.0.0.2");
+        let cases = [
+            (&V4_TIME_EXCEEDED, IcmpErrorKind::TimeExceeded(0)),
+            (
+                &V4_HOST_UNREACHABLE,
+                IcmpErrorKind::DestinationUnreachable(1),
+            ),
+            (&V4_FRAG_NEEDED, IcmpErrorKind::PacketTooBig(1400)),
+        ];
+        for &(frame, kind) in &cases {
+            assert_eq!(
+                parse(frame, &local),
+                Some((0x1234, error("10.0.0.1", "192.0.2.7", kind)))
+            );
+            // for another host or stack on the link
+            assert_eq!(parse(frame, &[]), None);
+            assert_eq!(parse(frame, &self::local("10.0.0.3")), None);
+        }
+        // the quoted request was sent from a local address to which the error was not sent
+        let mut frame = V4_TIME_EXCEEDED;
+        frame[33] = 3;
+        assert_eq!(
+            parse(&frame, &local).map(|(_, error)| error.kind),
+            Some(IcmpErrorKind::TimeExceeded(0))
+        );
+        // a quoted echo reply, and a truncated quote
+        let mut frame = V4_TIME_EXCEEDED;
+        frame[62] = 0;
+        assert_eq!(parse(&frame, &local), None);
+        assert_eq!(parse(&V4_TIME_EXCEEDED[..60], &local), None);
+    }
+
+    #[test]
+    fn test_parse_ipv6_errors() {
+        let local = local("2001:db8:1::2");
+        let cases = [
+            (
+                &V6_ADDRESS_UNREACHABLE,
+                IcmpErrorKind::DestinationUnreachable(3),
+            ),
+            (&V6_PACKET_TOO_BIG, IcmpErrorKind::PacketTooBig(1280)),
+            (&V6_TIME_EXCEEDED, IcmpErrorKind::TimeExceeded(0)),
+        ];
+        for &(frame, kind) in &cases {
+            assert_eq!(
+                parse(frame, &local),
+                Some((0x1234, error("2001:db8:1::1", "2001:db8:2::7", kind)))
+            );
+            assert_eq!(parse(frame, &self::local("10.0.0.2")), None);
+        }
+        // a quoted echo reply
+        let mut frame = V6_TIME_EXCEEDED;
+        frame[102] = 129;
+        assert_eq!(parse(&frame, &local), None);
+    }
+
+    #[test]
+    fn test_queued_errors() {
+        let local = local("10.0.0.2");
+        let errors = Errors::default();
+        // no socket with the identifier
+        inspect(&errors, &V4_TIME_EXCEEDED, &local);
+        assert!(errors.register(0x1234));
+        assert!(!errors.register(0x1234));
+        assert_eq!(errors.take_error(0x1234), None);
+        inspect(&errors, &V4_HOST_UNREACHABLE, &[]);
+        inspect(&errors, &V4_TIME_EXCEEDED, &local);
+        inspect(&errors, &V4_FRAG_NEEDED, &local);
+        let kinds = [
+            IcmpErrorKind::TimeExceeded(0),
+            IcmpErrorKind::PacketTooBig(1400),
+        ];
+        for &kind in &kinds {
+            assert_eq!(
+                errors.take_error(0x1234).map(|error| error.kind),
+                Some(kind)
+            );
+        }
+        assert_eq!(errors.take_error(0x1234), None);
+        // the oldest errors are dropped
+        inspect(&errors, &V4_HOST_UNREACHABLE, &local);
+        for _ in 0..MAX_QUEUED_ERRORS {
+            inspect(&errors, &V4_TIME_EXCEEDED, &local);
+        }
+        let mut count = 0;
+        while let Some(error) = errors.take_error(0x1234) {
+            assert_eq!(error.kind, IcmpErrorKind::TimeExceeded(0));
+            count += 1;
+        }
+        assert_eq!(count, MAX_QUEUED_ERRORS);
+        inspect(&errors, &V4_TIME_EXCEEDED, &local);
+        errors.unregister(0x1234);
+        assert_eq!(errors.take_error(0x1234), None);
+        assert!(errors.register(0x1234));
+        assert_eq!(errors.take_error(0x1234), None);
+        // the identifier of a socket on another stack
+        let other = Errors::default();
+        inspect(&other, &V4_TIME_EXCEEDED, &local);
+        assert_eq!(errors.take_error(0x1234), None);
+        assert!(other.register(0x1234));
+        errors.unregister(0x1234);
+    }
+}
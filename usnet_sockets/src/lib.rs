@@ -9,6 +9,18 @@ extern crate smoltcp;
 extern crate log;
 
 extern crate rand;
+#[cfg(any(feature = "dnssec", feature = "tsig", feature = "dot"))]
+extern crate ring;
+#[cfg(feature = "doq")]
+extern crate bytes;
+#[cfg(feature = "doq")]
+extern crate quinn_proto;
+#[cfg(any(feature = "doq", feature = "dot"))]
+extern crate rustls;
+#[cfg(feature = "dot")]
+extern crate webpki;
+#[cfg(any(feature = "doq", feature = "dot"))]
+extern crate webpki_roots;
 
 #[macro_use]
 extern crate serde_derive;
@@ -20,17 +32,75 @@ pub mod resolve;
 extern crate nix;
 extern crate serde_json;
 
+// Connection and device tracing of the stack, compiled out without the stack-log feature
+#[cfg(feature = "stack-log")]
+macro_rules! stack_log {
+    ($level:ident, $($arg:tt)+) => {
+        $level!($($arg)+)
+    };
+}
+
+// the arguments are only borrowed, so that variables for the messages count as used
+#[cfg(not(feature = "stack-log"))]
+macro_rules! stack_log {
+    ($level:ident, $fmt:expr $(, $arg:expr)*) => {{
+        $(let _ = &$arg;)*
+    }};
+}
+
 pub mod apimultithread;
 pub mod apisinglethread;
+pub mod capture;
+pub mod congestion;
 pub mod device;
+pub mod dhcp;
+pub mod fallback;
+pub mod frame;
+pub mod icmp;
+pub mod neighbor;
+pub mod pktinfo;
+pub mod pmtu;
+pub mod queues;
+pub mod rawip;
+pub mod routing;
+pub mod slaac;
+pub mod stats;
 pub mod system;
+pub mod tos;
 pub mod usnetconfig;
 
+pub use crate::capture::{
+    clear_capture_callback, disable_pcap, enable_pcap, set_capture_callback, Direction,
+};
+pub use crate::congestion::{default_congestion, set_default_congestion, Congestion};
+pub use crate::dhcp::{dhcp_status, set_dhcp_callback, DhcpLease, DhcpStatus};
+pub use crate::fallback::{PathPolicy, SocketPath};
+pub use crate::icmp::{EchoReply, IcmpError, IcmpErrorKind, IcmpResponse};
+pub use crate::neighbor::{NeighborEntry, NeighborState};
+pub use crate::pktinfo::PktInfo;
+pub use crate::pmtu::PathMtu;
+pub use crate::routing::Route;
+pub use crate::slaac::{slaac_status, SlaacStatus};
+pub use crate::stats::{stats, InterfaceStats, RingOccupancy, StackStats, TcpStats};
+pub use crate::usnetconfig::{
+    link_conditions, set_link_conditions, LinkConditions, RuntimeConfig, StackConfig,
+    StackConfigBuilder,
+};
+
 #[cfg(feature = "multi")]
-pub use crate::apimultithread::{TcpListener, TcpStream, UdpSocket, UsnetToSocketAddrs};
+pub use crate::apimultithread::{
+    add_address, add_route, add_static_neighbor, addresses, copy, flush_neighbors, flush_path_mtus,
+    init_with, interface_stats, neighbors, path_mtus, path_policy, queue_stacks, reconfigure,
+    remove_address, remove_route, remove_static_neighbor, routes, runtime_config, set_path_policy,
+    shutdown, IcmpSocket, RawSocket, StcpNetRef, TcpListener, TcpListenerBuilder, TcpStream,
+    UdpBindOptions, UdpSlot, UdpSocket, UdpSocketBuilder, UsnetToSocketAddrs,
+};
 
 #[cfg(feature = "single")]
-pub use apisinglethread::{TcpListener, TcpStream};
+pub use apisinglethread::{
+    add_static_neighbor, flush_neighbors, flush_path_mtus, init_with, interface_stats, neighbors,
+    path_mtus, remove_static_neighbor, TcpListener, TcpStream, UdpSocket,
+};
 
 #[cfg(feature = "host")]
 pub use std::net::{TcpListener, TcpStream};
@@ -0,0 +1,462 @@
+/// ARP and NDP neighbor table of the stack with static entries
+/// smoltcp does not expose its neighbor cache, therefore the device layer keeps a copy
+/// which it learns from the senders of ARP packets for the stack's addresses and of
+/// neighbor advertisements and solicitations, in the same way as smoltcp fills its cache.
+/// Outgoing ARP requests and neighbor solicitations mark an entry as incomplete and if no
+/// answer arrives within UNRESOLVED_TIMEOUT it is marked as failed and counted in
+/// unresolved_neighbors of the stack counters.
+/// Static entries are handed to smoltcp as injected ARP replies or neighbor advertisements
+/// in every poll before its cache entry expires, so that it never needs to send a request.
+/// Since smoltcp can't remove cache entries, a flush sends new requests for the learned
+/// entries, so that the answers replace the cached hardware addresses.
+use parking_lot::Mutex;
+use std::collections::BTreeMap;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::time::{Duration, Instant};
+
+use smoltcp::wire::{
+    ArpOperation, ArpPacket, EthernetAddress, IpAddress, IpCidr, IpProtocol, Ipv4Address,
+};
+
+use crate::frame::{Headers, IpHeader, ETHERNET_HEADER_LEN};
+use crate::slaac::{ipv6_packet, solicited_node};
+use crate::stats::{inc, COUNTERS};
+
+const ENTRY_LIFETIME: Duration = Duration::from_secs(60); // same as smoltcp
+const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(30);
+pub(crate) const UNRESOLVED_TIMEOUT: Duration = Duration::from_secs(3);
+const ICMPV6_NEIGHBOR_SOLICIT: u8 = 135;
+const ICMPV6_NEIGHBOR_ADVERT: u8 = 136;
+const NDISC_OPTION_SOURCE_LLADDR: u8 = 1;
+const NDISC_OPTION_TARGET_LLADDR: u8 = 2;
+const NDISC_FLAGS_SOLICITED_OVERRIDE: u8 = 0x60;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum NeighborState {
+    /// A request was sent and no answer arrived yet
+    Incomplete,
+    /// Learned within the lifetime of the stack's cache entries
+    Reachable,
+    /// Learned before the lifetime expired, the stack sends a request for the next packet
+    Stale,
+    /// A request was not answered, packets to the address are not sent
+    Failed,
+    /// Installed with add_static_neighbor, the stack sends no requests
+    Static,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct NeighborEntry {
+    pub ip: IpAddr,
+    /// None if the address was never resolved
+    pub mac: Option<[u8; 6]>,
+    pub state: NeighborState,
+    /// Time since the entry was learned or the request was sent
+    pub age: Duration,
+}
+
+#[derive(Debug)]
+struct Entry {
+    mac: Option<[u8; 6]>,
+    state: NeighborState,
+    updated: Instant,
+    announced: Option<Instant>,  // for static entries
+    unanswered: Option<Instant>, // first request since the address was last resolved
+}
+
+#[derive(Debug, Default)]
+struct Table {
+    entries: BTreeMap<IpAddr, Entry>,
+    probes: Vec<IpAddr>, // requests to send after a flush
+}
+
+/// Neighbor table of one stack
+#[derive(Debug, Default)]
+pub(crate) struct Neighbors {
+    table: Mutex<Table>,
+}
+
+impl Neighbors {
+    /// Returns the entries of the neighbor table
+    pub fn entries(&self) -> Vec<NeighborEntry> {
+        let mut table = self.table.lock();
+        expire(&mut table);
+        table
+            .entries
+            .iter()
+            .map(|(ip, entry)| {
+                let age = entry.updated.elapsed();
+                let state = match entry.state {
+                    NeighborState::Reachable if age >= ENTRY_LIFETIME => NeighborState::Stale,
+                    state => state,
+                };
+                NeighborEntry {
+                    ip: *ip,
+                    mac: entry.mac,
+                    state: state,
+                    age: age,
+                }
+            })
+            .collect()
+    }
+
+    /// Removes all entries except the static ones and sends new requests for the learned
+    /// entries
+    pub fn flush(&self) {
+        let mut table = self.table.lock();
+        let mut probes = vec![];
+        table.entries.retain(|ip, entry| {
+            if entry.state == NeighborState::Static {
+                return true;
+            }
+            if entry.mac.is_some() {
+                probes.push(*ip);
+            }
+            false
+        });
+        debug!("flushed neighbor table, probing {:?}", probes);
+        table.probes = probes;
+    }
+
+    /// Installs a static entry, packets to the address are sent to the MAC without ARP or NDP
+    pub fn add_static(&self, ip: IpAddr, mac: [u8; 6]) -> io::Result<()> {
+        if ip.is_unspecified()
+            || ip.is_multicast()
+            || ip.is_loopback()
+            || ip == IpAddr::V4(Ipv4Addr::BROADCAST)
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "static neighbor address must be a unicast address",
+            ));
+        }
+        if !EthernetAddress(mac).is_unicast() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "static neighbor MAC must be a unicast address",
+            ));
+        }
+        debug!("adding static neighbor {} at {}", ip, EthernetAddress(mac));
+        self.table.lock().entries.insert(
+            ip,
+            Entry {
+                mac: Some(mac),
+                state: NeighborState::Static,
+                updated: Instant::now(),
+                announced: None,
+                unanswered: None,
+            },
+        );
+        Ok(())
+    }
+
+    /// Removes a static entry, the stack resolves the address again after its cache entry
+    /// expired
+    pub fn remove_static(&self, ip: IpAddr) -> io::Result<()> {
+        let mut table = self.table.lock();
+        match table.entries.get(&ip) {
+            Some(entry) if entry.state == NeighborState::Static => {}
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("no static neighbor {}", ip),
+                ))
+            }
+        }
+        table.entries.remove(&ip);
+        Ok(())
+    }
+
+    /// Returns the frames to inject as received for the static entries and the requests to
+    /// send for a flush (called before every interface poll)
+    pub fn poll(
+        &self,
+        ethernet_addr: EthernetAddress,
+        ips: &[IpCidr],
+    ) -> (Vec<Vec<u8>>, Vec<Vec<u8>>) {
+        let mut table = self.table.lock();
+        expire(&mut table);
+        let now = Instant::now();
+        let mut rx = vec![];
+        for (ip, entry) in table.entries.iter_mut() {
+            if entry.state != NeighborState::Static
+                || entry
+                    .announced
+                    .map_or(false, |t| now - t < ANNOUNCE_INTERVAL)
+            {
+                continue;
+            }
+            let mac = EthernetAddress(entry.mac.unwrap());
+            if let Some(frame) = announcement(*ip, mac, ethernet_addr, ips) {
+                trace!("injecting static neighbor {} at {}", ip, mac);
+                rx.push(frame);
+                entry.announced = Some(now);
+            }
+        }
+        let tx = table
+            .probes
+            .drain(..)
+            .filter_map(|ip| request(ip, ethernet_addr, ips))
+            .collect();
+        (rx, tx)
+    }
+
+    /// Learns the sender of ARP and neighbor discovery frames (called for every received
+    /// frame with the addresses of the stack)
+    pub fn inspect_rx_frame(&self, buffer: &[u8], headers: &Headers, local: &[IpAddress]) {
+        // ARP packets are only learned if they are for the stack, like smoltcp does
+        let (ip, mac) = match *headers {
+            Headers::Arp => {
+                let arp = match ArpPacket::new_checked(&buffer[ETHERNET_HEADER_LEN..]) {
+                    Ok(arp) => arp,
+                    Err(_) => return,
+                };
+                if arp.hardware_len() != 6 || arp.protocol_len() != 4 {
+                    return;
+                }
+                let tpa = IpAddress::Ipv4(Ipv4Address::from_bytes(arp.target_protocol_addr()));
+                if !local.contains(&tpa) {
+                    return;
+                }
+                let mut mac = [0; 6];
+                mac.copy_from_slice(arp.source_hardware_addr());
+                let spa = arp.source_protocol_addr();
+                (
+                    IpAddr::V4(Ipv4Addr::new(spa[0], spa[1], spa[2], spa[3])),
+                    mac,
+                )
+            }
+            Headers::Ip(ref ip) => match parse_ndisc(buffer, ip) {
+                Some((ip, mac)) => (ip, mac),
+                None => return,
+            },
+            Headers::Other => return,
+        };
+        if ip.is_unspecified() || !EthernetAddress(mac).is_unicast() {
+            return;
+        }
+        let mut table = self.table.lock();
+        match table.entries.get_mut(&ip) {
+            Some(entry) if entry.state == NeighborState::Static => {}
+            Some(entry) => {
+                entry.mac = Some(mac);
+                entry.state = NeighborState::Reachable;
+                entry.updated = Instant::now();
+                entry.unanswered = None;
+            }
+            None => {
+                table.entries.insert(
+                    ip,
+                    Entry {
+                        mac: Some(mac),
+                        state: NeighborState::Reachable,
+                        updated: Instant::now(),
+                        announced: None,
+                        unanswered: None,
+                    },
+                );
+            }
+        }
+    }
+
+    /// Marks the target of an outgoing ARP request or neighbor solicitation as incomplete
+    pub fn inspect_tx_request(&self, ip: IpAddr) {
+        let mut table = self.table.lock();
+        let entry = table.entries.entry(ip).or_insert(Entry {
+            mac: None,
+            state: NeighborState::Incomplete,
+            updated: Instant::now(),
+            announced: None,
+            unanswered: None,
+        });
+        match entry.state {
+            // the injected entry expired before it was refreshed
+            NeighborState::Static => entry.announced = None,
+            NeighborState::Reachable | NeighborState::Failed => {
+                entry.state = NeighborState::Incomplete;
+                entry.updated = Instant::now();
+            }
+            NeighborState::Incomplete | NeighborState::Stale => {}
+        }
+        if entry.state != NeighborState::Static && entry.unanswered.is_none() {
+            entry.unanswered = Some(Instant::now());
+        }
+    }
+
+    /// Whether the requests for the address were not answered for UNRESOLVED_TIMEOUT, also
+    /// when the entry is incomplete again because the stack sent another request after it
+    /// failed
+    pub fn unresolved(&self, ip: IpAddr) -> bool {
+        self.table.lock().entries.get(&ip).map_or(false, |entry| {
+            entry
+                .unanswered
+                .map_or(false, |t| t.elapsed() >= UNRESOLVED_TIMEOUT)
+        })
+    }
+}
+
+/// Returns the target of an outgoing neighbor solicitation (not for DAD)
+pub(crate) fn solicitation_target(buffer: &[u8], headers: &Headers) -> Option<IpAddr> {
+    let ip = headers
+        .ip(IpProtocol::Icmpv6)
+        .filter(|ip| ip.src.is_ipv6())?;
+    let icmp = ip.payload(buffer);
+    if ip.src.is_unspecified() || icmp.len() < 24 || icmp[0] != ICMPV6_NEIGHBOR_SOLICIT {
+        return None;
+    }
+    Some(IpAddr::V6(ipv6_from_slice(&icmp[8..24])))
+}
+
+fn expire(table: &mut Table) {
+    for (ip, entry) in table.entries.iter_mut() {
+        if entry.state == NeighborState::Incomplete && entry.updated.elapsed() >= UNRESOLVED_TIMEOUT
+        {
+            warn!("neighbor {} unresolved, packets to it are dropped", ip);
+            inc(&COUNTERS.unresolved_neighbors);
+            entry.state = NeighborState::Failed;
+        }
+    }
+}
+
+/// Returns the source and link-layer address of a neighbor advertisement or solicitation
+fn parse_ndisc(buffer: &[u8], ip: &IpHeader) -> Option<(IpAddr, [u8; 6])> {
+    let icmp = ip.payload(buffer);
+    if ip.protocol != IpProtocol::Icmpv6
+        || !ip.src.is_ipv6()
+        || ip.hop_limit != 255
+        || icmp.len() < 24
+    {
+        return None;
+    }
+    let option = match icmp[0] {
+        ICMPV6_NEIGHBOR_ADVERT => NDISC_OPTION_TARGET_LLADDR,
+        ICMPV6_NEIGHBOR_SOLICIT => NDISC_OPTION_SOURCE_LLADDR,
+        _ => return None,
+    };
+    let mut options = &icmp[24..];
+    while options.len() >= 8 && options[1] != 0 {
+        let len = options[1] as usize * 8;
+        if options[0] == option && options[1] == 1 {
+            let mut mac = [0; 6];
+            mac.copy_from_slice(&options[2..8]);
+            return Some((ip.src, mac));
+        }
+        options = options.get(len..)?;
+    }
+    None
+}
+
+/// Selects the local address in the same network as the neighbor, or the first one
+fn local_address(ip: IpAddr, ips: &[IpCidr]) -> Option<IpAddr> {
+    let candidates: Vec<(&IpCidr, IpAddr)> = ips
+        .iter()
+        .filter_map(|cidr| match cidr.address() {
+            IpAddress::Ipv4(a) if ip.is_ipv4() && !a.is_unspecified() => {
+                Some((cidr, IpAddr::V4(Ipv4Addr::from(a))))
+            }
+            IpAddress::Ipv6(a) if ip.is_ipv6() && !a.is_unspecified() => {
+                Some((cidr, IpAddr::V6(Ipv6Addr::from(a))))
+            }
+            _ => None,
+        })
+        .collect();
+    candidates
+        .iter()
+        .find(|(cidr, _)| cidr.contains_addr(&IpAddress::from(ip)))
+        .or_else(|| candidates.first())
+        .map(|&(_, local)| local)
+}
+
+/// Frame with an ARP reply or neighbor advertisement from the neighbor to the stack
+fn announcement(
+    ip: IpAddr,
+    mac: EthernetAddress,
+    ethernet_addr: EthernetAddress,
+    ips: &[IpCidr],
+) -> Option<Vec<u8>> {
+    let local = local_address(ip, ips)?;
+    Some(match (ip, local) {
+        (IpAddr::V4(ip), IpAddr::V4(local)) => arp_frame(
+            ArpOperation::Reply,
+            ethernet_addr,
+            mac,
+            ip,
+            ethernet_addr,
+            local,
+        ),
+        (IpAddr::V6(ip), IpAddr::V6(local)) => {
+            let mut icmp = vec![ICMPV6_NEIGHBOR_ADVERT, 0, 0, 0];
+            icmp.extend_from_slice(&[NDISC_FLAGS_SOLICITED_OVERRIDE, 0, 0, 0]);
+            icmp.extend_from_slice(&ip.octets());
+            icmp.extend_from_slice(&[NDISC_OPTION_TARGET_LLADDR, 1]);
+            icmp.extend_from_slice(mac.as_bytes());
+            ipv6_frame(ethernet_addr, mac, ipv6_packet(&ip, &local, icmp))
+        }
+        _ => return None,
+    })
+}
+
+/// Frame with an ARP request or neighbor solicitation from the stack for the neighbor
+fn request(ip: IpAddr, ethernet_addr: EthernetAddress, ips: &[IpCidr]) -> Option<Vec<u8>> {
+    let local = local_address(ip, ips)?;
+    Some(match (ip, local) {
+        (IpAddr::V4(ip), IpAddr::V4(local)) => arp_frame(
+            ArpOperation::Request,
+            EthernetAddress::BROADCAST,
+            ethernet_addr,
+            local,
+            EthernetAddress([0; 6]),
+            ip,
+        ),
+        (IpAddr::V6(ip), IpAddr::V6(local)) => {
+            let mut icmp = vec![ICMPV6_NEIGHBOR_SOLICIT, 0, 0, 0, 0, 0, 0, 0];
+            icmp.extend_from_slice(&ip.octets());
+            icmp.extend_from_slice(&[NDISC_OPTION_SOURCE_LLADDR, 1]);
+            icmp.extend_from_slice(ethernet_addr.as_bytes());
+            let dst = solicited_node(&ip);
+            let o = dst.octets();
+            let multicast = EthernetAddress([0x33, 0x33, o[12], o[13], o[14], o[15]]);
+            ipv6_frame(multicast, ethernet_addr, ipv6_packet(&local, &dst, icmp))
+        }
+        _ => return None,
+    })
+}
+
+fn arp_frame(
+    operation: ArpOperation,
+    dst: EthernetAddress,
+    sha: EthernetAddress,
+    spa: Ipv4Addr,
+    tha: EthernetAddress,
+    tpa: Ipv4Addr,
+) -> Vec<u8> {
+    let mut frame = ethernet_header(dst, sha, 0x0806);
+    frame.extend_from_slice(&[0, 1, 0x08, 0, 6, 4]); // Ethernet and IPv4
+    frame.extend_from_slice(&u16::from(operation).to_be_bytes());
+    frame.extend_from_slice(sha.as_bytes());
+    frame.extend_from_slice(&spa.octets());
+    frame.extend_from_slice(tha.as_bytes());
+    frame.extend_from_slice(&tpa.octets());
+    frame
+}
+
+fn ipv6_frame(dst: EthernetAddress, src: EthernetAddress, packet: Vec<u8>) -> Vec<u8> {
+    let mut frame = ethernet_header(dst, src, 0x86dd);
+    frame.extend_from_slice(&packet);
+    frame
+}
+
+fn ethernet_header(dst: EthernetAddress, src: EthernetAddress, ethertype: u16) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(90);
+    frame.extend_from_slice(dst.as_bytes());
+    frame.extend_from_slice(src.as_bytes());
+    frame.extend_from_slice(&ethertype.to_be_bytes());
+    frame
+}
+
+fn ipv6_from_slice(s: &[u8]) -> Ipv6Addr {
+    let mut a = [0u8; 16];
+    a.copy_from_slice(&s[..16]);
+    Ipv6Addr::from(a)
+}
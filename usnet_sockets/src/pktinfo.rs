@@ -0,0 +1,195 @@
+/// Destination and source addresses of UDP datagrams for UdpSocket::recv_msg and send_msg
+/// smoltcp keeps only the remote endpoint of a datagram. Therefore the device layer notes the
+/// destination address of the received IPv4 UDP packets which the stack accepted for the
+/// ports which use recv_msg, and sets the source address of the packets which send_msg
+/// pinned and updates the IPv4 and UDP checksums. The sockets look up the entries in the
+/// order of their datagrams. Each stack keeps its own entries since the stacks of a
+/// process may use the same ports.
+use parking_lot::Mutex;
+use std::collections::{BTreeMap, VecDeque};
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use smoltcp::wire::{IpAddress, IpEndpoint, IpProtocol, Ipv4Address, Ipv4Packet, UdpPacket};
+
+use crate::frame::{Headers, ETHERNET_HEADER_LEN};
+
+/// Local address of a datagram: the destination of a received one for UdpSocket::recv_msg,
+/// or the source address to send one from for UdpSocket::send_msg
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub struct PktInfo {
+    /// Local address the datagram was sent to or is sent from
+    pub addr: IpAddr,
+    /// Index of the kernel interface it was received on, 0 if there is none (it is not known
+    /// for the kernel socket fallback and ignored by send_msg)
+    pub interface: u32,
+}
+
+// as many entries as datagrams fit into the buffers of a socket
+const QUEUE_LIMIT: usize = 1000;
+
+#[derive(Debug, Default)]
+struct Port {
+    receiving: bool, // recv_msg was called
+    // remote endpoints and destinations of the received datagrams
+    received: VecDeque<(IpEndpoint, Ipv4Address)>,
+    pinning: bool, // send_msg pinned a source address
+    // remote endpoints and pinned sources of the queued datagrams
+    sent: VecDeque<(IpEndpoint, Option<Ipv4Address>)>,
+}
+
+/// Received datagram to a port with recv_msg, noted once the stack accepted it
+pub(crate) struct Datagram {
+    port: u16,
+    remote: IpEndpoint,
+    dst: Ipv4Address,
+}
+
+/// Noted destinations and pinned sources of the UDP ports of one stack
+#[derive(Debug, Default)]
+pub(crate) struct Ports {
+    // number of ports with received or sent entries, frames are not inspected if there are none
+    receiving: AtomicUsize,
+    pinning: AtomicUsize,
+    ports: Mutex<BTreeMap<u16, Port>>,
+}
+
+impl Ports {
+    /// Returns the destination address of the datagram from the remote endpoint which the
+    /// socket on the local port received next, starts noting them on the first call
+    pub fn destination(&self, port: u16, remote: IpEndpoint) -> Option<Ipv4Address> {
+        let mut ports = self.ports.lock();
+        let entry = ports.entry(port).or_insert_with(Port::default);
+        if !entry.receiving {
+            entry.receiving = true;
+            let _ = self.receiving.fetch_add(1, Ordering::Relaxed);
+        }
+        // skips the entries of datagrams which the socket skipped
+        while let Some((from, dst)) = entry.received.pop_front() {
+            if from == remote {
+                return Some(dst);
+            }
+        }
+        None
+    }
+
+    /// Notes a datagram which the socket on the local port queued for sending, with the
+    /// source address to set, once sources are pinned all datagrams of the port are noted
+    /// in order
+    pub fn note_sent(&self, port: u16, remote: IpEndpoint, source: Option<Ipv4Address>) {
+        if source.is_none() && self.pinning.load(Ordering::Relaxed) == 0 {
+            return;
+        }
+        let mut ports = self.ports.lock();
+        let entry = ports.entry(port).or_insert_with(Port::default);
+        if !entry.pinning {
+            if source.is_none() {
+                return;
+            }
+            entry.pinning = true;
+            let _ = self.pinning.fetch_add(1, Ordering::Relaxed);
+        }
+        if entry.sent.len() == QUEUE_LIMIT {
+            let _ = entry.sent.pop_front();
+        }
+        entry.sent.push_back((remote, source));
+    }
+
+    /// Removes the entries of the port when its socket is closed
+    pub fn release(&self, port: u16) {
+        if self.receiving.load(Ordering::Relaxed) == 0 && self.pinning.load(Ordering::Relaxed) == 0
+        {
+            return;
+        }
+        if let Some(entry) = self.ports.lock().remove(&port) {
+            if entry.receiving {
+                let _ = self.receiving.fetch_sub(1, Ordering::Relaxed);
+            }
+            if entry.pinning {
+                let _ = self.pinning.fetch_sub(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Returns the incoming IPv4 UDP datagram of a frame while ports use recv_msg, it is
+    /// noted with note_received once the stack accepted it (called for every received frame)
+    pub fn parse_rx_frame(&self, buffer: &[u8], headers: &Headers) -> Option<Datagram> {
+        if self.receiving.load(Ordering::Relaxed) == 0 {
+            return None;
+        }
+        let ip = headers.ip(IpProtocol::Udp)?;
+        let (src, dst) = match (ip.src, ip.dst) {
+            (IpAddr::V4(src), IpAddr::V4(dst)) if ip.first_fragment() => (src, dst),
+            _ => return None,
+        };
+        let udp = UdpPacket::new_checked(ip.payload(buffer)).ok()?;
+        Some(Datagram {
+            port: udp.dst_port(),
+            remote: IpEndpoint::new(IpAddress::Ipv4(Ipv4Address::from(src)), udp.src_port()),
+            dst: Ipv4Address::from(dst),
+        })
+    }
+
+    /// Notes the destination address of a datagram which the stack accepted for a port
+    /// with recv_msg, the ones which it dropped, e.g., for a full buffer, are not noted
+    pub fn note_received(&self, datagram: Datagram) {
+        let mut ports = self.ports.lock();
+        if let Some(entry) = ports.get_mut(&datagram.port) {
+            if entry.receiving {
+                if entry.received.len() == QUEUE_LIMIT {
+                    let _ = entry.received.pop_front();
+                }
+                entry.received.push_back((datagram.remote, datagram.dst));
+            }
+        }
+    }
+
+    /// Sets the pinned source address of an outgoing IPv4 UDP packet from a port with
+    /// send_msg and in the headers (called for every transmitted frame)
+    pub fn rewrite_tx_frame(&self, buffer: &mut [u8], headers: &mut Headers) {
+        if self.pinning.load(Ordering::Relaxed) == 0 {
+            return;
+        }
+        let ip = match *headers {
+            Headers::Ip(ref mut ip) if ip.protocol == IpProtocol::Udp && ip.first_fragment() => ip,
+            _ => return,
+        };
+        let dst = match (ip.src, ip.dst) {
+            (IpAddr::V4(_), IpAddr::V4(dst)) => Ipv4Address::from(dst),
+            _ => return,
+        };
+        let (port, remote) = match UdpPacket::new_checked(ip.payload(buffer)) {
+            Ok(udp) => (
+                udp.src_port(),
+                IpEndpoint::new(IpAddress::Ipv4(dst), udp.dst_port()),
+            ),
+            Err(_) => return,
+        };
+        let source = {
+            let mut ports = self.ports.lock();
+            let entry = match ports.get_mut(&port) {
+                Some(entry) => entry,
+                None => return,
+            };
+            match entry.sent.front() {
+                Some(&(to, source)) if to == remote => {
+                    let _ = entry.sent.pop_front();
+                    match source {
+                        Some(source) => source,
+                        None => return,
+                    }
+                }
+                _ => return,
+            }
+        };
+        if IpAddr::V4(Ipv4Addr::from(source)) == ip.src {
+            return;
+        }
+        let mut packet = Ipv4Packet::new_unchecked(&mut buffer[ETHERNET_HEADER_LEN..ip.end]);
+        packet.set_src_addr(source);
+        packet.fill_checksum();
+        let mut udp = UdpPacket::new_unchecked(ip.payload_mut(buffer));
+        udp.fill_checksum(&IpAddress::Ipv4(source), &IpAddress::Ipv4(dst));
+        ip.src = IpAddr::V4(Ipv4Addr::from(source));
+    }
+}
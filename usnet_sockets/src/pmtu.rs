@@ -0,0 +1,471 @@
+/// Path MTU discovery and MSS clamping for TCP
+/// smoltcp sends segments up to the MSS of the peer with DF set and ignores ICMP
+/// fragmentation needed and packet too big messages, which makes connections over tunnels
+/// with a lower MTU hang. Therefore the device layer learns the path MTU of a destination
+/// from these messages when they quote a TCP segment and keeps it for PMTU_LIFETIME.
+/// The MSS option of SYN segments in both directions is clamped to the path MTU and the
+/// configured MSS limit, so that new connections use smaller segments from the start, and
+/// outgoing segments of existing connections which do not fit the path MTU are split.
+/// With probing enabled, a full-size segment which is retransmitted without any ICMP error
+/// is taken as a sign of a black hole, and the path MTU is lowered to the next plateau of
+/// RFC 1191 until the entry expires and full-size segments are tried again.
+/// SACK and window scaling are negotiated by smoltcp, so when they are disabled in the
+/// configuration, their options are replaced with NOPs in the SYN segments in both
+/// directions, and neither side uses them on the connection.
+use parking_lot::Mutex;
+use std::collections::BTreeMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use smoltcp::wire::{IpAddress, IpProtocol, Ipv4Packet, Ipv6Packet, TcpPacket};
+
+use crate::frame::{Headers, ETHERNET_HEADER_LEN};
+
+const PMTU_LIFETIME: Duration = Duration::from_secs(600); // same as Linux
+const MIN_IPV4_MTU: usize = 576;
+const MIN_IPV6_MTU: usize = 1280;
+const ICMPV4_DST_UNREACHABLE: u8 = 3;
+const ICMPV4_FRAG_NEEDED: u8 = 4; // code of destination unreachable
+const ICMPV6_PKT_TOO_BIG: u8 = 2;
+const ICMP_HEADER_LEN: usize = 8;
+const IPV4_HEADER_LEN: usize = 20;
+const IPV6_HEADER_LEN: usize = 40;
+const TCP_HEADER_LEN: usize = 20;
+const TCP_OPTION_END: u8 = 0;
+const TCP_OPTION_NOP: u8 = 1;
+const TCP_OPTION_MSS: u8 = 2;
+const TCP_OPTION_WINDOW_SCALE: u8 = 3;
+const TCP_OPTION_SACK_PERMITTED: u8 = 4;
+// the third transmission of the same full-size segment lowers the path MTU when probing
+const BLACK_HOLE_TRANSMISSIONS: usize = 3;
+const MAX_TRACKED_SEGMENTS: usize = 1024;
+// RFC 1191 plateaus without the ones above the Ethernet MTU
+const PLATEAUS: [usize; 7] = [1492, 1480, 1440, 1400, 1280, 1006, 576];
+
+/// Path MTU learned for a destination
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PathMtu {
+    pub destination: IpAddr,
+    pub mtu: usize,
+    /// Learned from a retransmitted segment instead of an ICMP error
+    pub probed: bool,
+    /// Time until the full MTU of the interface is tried again
+    pub expires: Duration,
+}
+
+#[derive(Debug)]
+struct Entry {
+    mtu: usize,
+    probed: bool,
+    updated: Instant,
+}
+
+#[derive(Debug, Default)]
+struct Cache {
+    entries: BTreeMap<IpAddr, Entry>,
+    // last full-size segment per connection and how often it was sent when probing
+    segments: BTreeMap<(IpAddr, u16, u16), (u32, usize)>,
+}
+
+/// Path MTU cache and TCP option settings of one stack
+#[derive(Debug, Default)]
+pub(crate) struct PathMtus {
+    // set once an entry is learned or clamping or probing is configured, until then the
+    // outgoing frames are not inspected
+    active: AtomicBool,
+    mss_clamp: AtomicUsize,
+    probing: AtomicBool,
+    strip_sack: AtomicBool,
+    strip_window_scale: AtomicBool,
+    cache: Mutex<Cache>,
+}
+
+impl PathMtus {
+    /// Returns the path MTUs which are lower than the MTU of the interface
+    pub fn entries(&self) -> Vec<PathMtu> {
+        let mut cache = self.cache.lock();
+        expire(&mut cache);
+        cache
+            .entries
+            .iter()
+            .map(|(destination, entry)| PathMtu {
+                destination: *destination,
+                mtu: entry.mtu,
+                probed: entry.probed,
+                expires: PMTU_LIFETIME - entry.updated.elapsed(),
+            })
+            .collect()
+    }
+
+    /// Removes all learned path MTUs
+    pub fn flush(&self) {
+        let mut cache = self.cache.lock();
+        cache.entries.clear();
+        cache.segments.clear();
+    }
+
+    pub fn configure(
+        &self,
+        mss_clamp: Option<u16>,
+        probing: bool,
+        sack: bool,
+        window_scaling: bool,
+    ) {
+        self.mss_clamp
+            .store(mss_clamp.unwrap_or(0) as usize, Ordering::Relaxed);
+        self.probing.store(probing, Ordering::Relaxed);
+        self.strip_sack.store(!sack, Ordering::Relaxed);
+        self.strip_window_scale
+            .store(!window_scaling, Ordering::Relaxed);
+        if mss_clamp.is_some() || probing || !sack || !window_scaling {
+            self.active.store(true, Ordering::Relaxed);
+        }
+    }
+
+    /// Whether outgoing frames have to go through inspect_tx_frame
+    pub fn enabled(&self) -> bool {
+        self.active.load(Ordering::Relaxed)
+    }
+
+    /// Learns the path MTU from ICMP errors which quote a TCP segment and rewrites the
+    /// options of a received SYN segment (called for every received frame)
+    pub fn inspect_rx_frame(&self, buffer: &mut [u8], headers: &Headers) {
+        if let Some((destination, mtu)) = parse_error_frame(buffer, headers) {
+            let mut cache = self.cache.lock();
+            self.learn(&mut cache, destination, mtu, false);
+            return;
+        }
+        if self.enabled() {
+            if let Some(tcp) = locate_tcp(buffer, headers) {
+                self.rewrite_syn(buffer, &tcp, tcp.src);
+            }
+        }
+    }
+
+    /// Rewrites the options of an outgoing SYN segment and splits TCP segments which do not
+    /// fit the path MTU of their destination, returns the frames to send in order
+    pub fn inspect_tx_frame(&self, mut frame: Vec<u8>, headers: &Headers) -> Vec<Vec<u8>> {
+        let tcp = match locate_tcp(&frame, headers) {
+            Some(tcp) => tcp,
+            None => return vec![frame],
+        };
+        if self.rewrite_syn(&mut frame, &tcp, tcp.dst) {
+            return vec![frame];
+        }
+        let ip_len = frame.len() - ETHERNET_HEADER_LEN;
+        let mut cache = self.cache.lock();
+        expire(&mut cache);
+        if self.probing.load(Ordering::Relaxed) {
+            self.detect_black_hole(&mut cache, &frame, &tcp, ip_len);
+        }
+        let mtu = cache.entries.get(&tcp.dst).map(|entry| entry.mtu);
+        drop(cache);
+        match mtu {
+            Some(mtu) if ip_len > mtu && tcp.payload < frame.len() => split(&frame, &tcp, mtu),
+            _ => vec![frame],
+        }
+    }
+
+    fn learn(&self, cache: &mut Cache, destination: IpAddr, mtu: usize, probed: bool) {
+        let mtu = mtu.max(min_mtu(destination));
+        if let Some(entry) = cache.entries.get(&destination) {
+            if entry.mtu <= mtu && entry.updated.elapsed() < PMTU_LIFETIME {
+                return;
+            }
+        }
+        debug!("path MTU of {} is {}", destination, mtu);
+        cache.entries.insert(
+            destination,
+            Entry {
+                mtu: mtu,
+                probed: probed,
+                updated: Instant::now(),
+            },
+        );
+        self.active.store(true, Ordering::Relaxed);
+    }
+
+    fn detect_black_hole(&self, cache: &mut Cache, frame: &[u8], tcp: &Tcp, ip_len: usize) {
+        let key = (tcp.dst, tcp.src_port, tcp.dst_port);
+        if tcp.payload == frame.len() || ip_len <= min_mtu(tcp.dst) {
+            return;
+        }
+        if cache.segments.len() >= MAX_TRACKED_SEGMENTS && !cache.segments.contains_key(&key) {
+            cache.segments.clear();
+        }
+        let transmissions = {
+            let segment = cache.segments.entry(key).or_insert((tcp.seq, 0));
+            if segment.0 != tcp.seq {
+                *segment = (tcp.seq, 0);
+            }
+            segment.1 += 1;
+            segment.1
+        };
+        if transmissions >= BLACK_HOLE_TRANSMISSIONS {
+            debug!(
+                "segment of {} bytes to {} retransmitted without ICMP error",
+                ip_len, tcp.dst
+            );
+            cache.segments.remove(&key);
+            self.learn(cache, tcp.dst, plateau_below(ip_len), true);
+        }
+    }
+
+    /// The largest MSS for segments to the peer, None if it is not limited
+    fn mss_limit(&self, peer: IpAddr) -> Option<usize> {
+        let clamp = self.mss_clamp.load(Ordering::Relaxed);
+        let headers = match peer {
+            IpAddr::V4(_) => IPV4_HEADER_LEN + TCP_HEADER_LEN,
+            IpAddr::V6(_) => IPV6_HEADER_LEN + TCP_HEADER_LEN,
+        };
+        let path = {
+            let cache = self.cache.lock();
+            cache
+                .entries
+                .get(&peer)
+                .filter(|entry| entry.updated.elapsed() < PMTU_LIFETIME)
+                .map(|entry| entry.mtu - headers)
+        };
+        match (clamp, path) {
+            (0, path) => path,
+            (clamp, Some(path)) => Some(clamp.min(path)),
+            (clamp, None) => Some(clamp),
+        }
+    }
+
+    /// Lowers the MSS option of a SYN segment to the limit for the peer and removes the
+    /// disabled options, returns whether the segment is a SYN
+    fn rewrite_syn(&self, buffer: &mut [u8], tcp: &Tcp, peer: IpAddr) -> bool {
+        if buffer[tcp.start + 13] & 0x02 == 0 {
+            return false;
+        }
+        let limit = self.mss_limit(peer).map(|limit| limit as u16);
+        let strip_sack = self.strip_sack.load(Ordering::Relaxed);
+        let strip_window_scale = self.strip_window_scale.load(Ordering::Relaxed);
+        if limit.is_none() && !strip_sack && !strip_window_scale {
+            return true;
+        }
+        let mut i = tcp.start + TCP_HEADER_LEN;
+        while i < tcp.payload {
+            match buffer[i] {
+                TCP_OPTION_END => break,
+                TCP_OPTION_NOP => i += 1,
+                kind => {
+                    let len = match buffer.get(i + 1) {
+                        Some(len) if *len >= 2 => *len as usize,
+                        _ => break,
+                    };
+                    if i + len > tcp.payload {
+                        break;
+                    }
+                    match (kind, limit) {
+                        (TCP_OPTION_MSS, Some(limit)) if len == 4 => {
+                            let mss = be16(&buffer[i + 2..]);
+                            if mss > limit {
+                                trace!("clamping MSS {} of SYN with {} to {}", mss, peer, limit);
+                                buffer[i + 2..i + 4].copy_from_slice(&limit.to_be_bytes());
+                                let checksum = be16(&buffer[tcp.start + 16..]);
+                                let checksum = update_checksum(checksum, mss, limit);
+                                buffer[tcp.start + 16..tcp.start + 18]
+                                    .copy_from_slice(&checksum.to_be_bytes());
+                            }
+                        }
+                        (TCP_OPTION_SACK_PERMITTED, _) if strip_sack => {
+                            trace!("removing SACK permitted option of SYN with {}", peer);
+                            replace_with_nops(buffer, tcp, i, len);
+                        }
+                        (TCP_OPTION_WINDOW_SCALE, _) if strip_window_scale => {
+                            trace!("removing window scale option of SYN with {}", peer);
+                            replace_with_nops(buffer, tcp, i, len);
+                        }
+                        _ => {}
+                    }
+                    i += len;
+                }
+            }
+        }
+        true
+    }
+}
+
+fn expire(cache: &mut Cache) {
+    cache
+        .entries
+        .retain(|_, entry| entry.updated.elapsed() < PMTU_LIFETIME);
+}
+
+fn min_mtu(destination: IpAddr) -> usize {
+    match destination {
+        IpAddr::V4(_) => MIN_IPV4_MTU,
+        IpAddr::V6(_) => MIN_IPV6_MTU,
+    }
+}
+
+/// The next plateau below the length of a packet which did not get through
+fn plateau_below(len: usize) -> usize {
+    PLATEAUS
+        .iter()
+        .cloned()
+        .find(|plateau| *plateau < len)
+        .unwrap_or(MIN_IPV4_MTU)
+}
+
+/// Location and header fields of a TCP segment in an Ethernet frame
+struct Tcp {
+    ipv4: bool,
+    src: IpAddr,
+    dst: IpAddr,
+    src_port: u16,
+    dst_port: u16,
+    seq: u32,
+    start: usize,   // of the TCP header in the frame
+    payload: usize, // start of the payload in the frame
+}
+
+fn locate_tcp(buffer: &[u8], headers: &Headers) -> Option<Tcp> {
+    let ip = headers.ip(IpProtocol::Tcp).filter(|ip| ip.unfragmented())?;
+    let tcp = TcpPacket::new_checked(ip.payload(buffer)).ok()?;
+    let start = ip.payload;
+    Some(Tcp {
+        ipv4: ip.src.is_ipv4(),
+        src: ip.src,
+        dst: ip.dst,
+        src_port: tcp.src_port(),
+        dst_port: tcp.dst_port(),
+        seq: tcp.seq_number().0 as u32,
+        start: start,
+        payload: start + tcp.header_len() as usize,
+    })
+}
+
+/// Overwrites an option with NOPs and updates the checksum for each changed 16 bit word of
+/// the segment
+fn replace_with_nops(buffer: &mut [u8], tcp: &Tcp, start: usize, len: usize) {
+    for i in start..start + len {
+        let word = tcp.start + ((i - tcp.start) & !1);
+        let old = be16(&buffer[word..]);
+        buffer[i] = TCP_OPTION_NOP;
+        let new = be16(&buffer[word..]);
+        let checksum = be16(&buffer[tcp.start + 16..]);
+        let checksum = update_checksum(checksum, old, new);
+        buffer[tcp.start + 16..tcp.start + 18].copy_from_slice(&checksum.to_be_bytes());
+    }
+}
+
+/// Incremental update of a checksum when one 16 bit word changes (RFC 1624)
+fn update_checksum(checksum: u16, old: u16, new: u16) -> u16 {
+    let mut sum = !checksum as u32 + !old as u32 + new as u32;
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// Splits a TCP segment into segments which fit the MTU, only the last one keeps the PSH
+/// and FIN flags
+fn split(frame: &[u8], tcp: &Tcp, mtu: usize) -> Vec<Vec<u8>> {
+    let headers = &frame[..tcp.payload];
+    let chunk = mtu - (tcp.payload - ETHERNET_HEADER_LEN);
+    let chunks = frame[tcp.payload..].chunks(chunk).collect::<Vec<_>>();
+    trace!(
+        "splitting segment of {} bytes to {} into {} segments",
+        frame.len() - tcp.payload,
+        tcp.dst,
+        chunks.len()
+    );
+    let mut offset = 0;
+    let mut segments = Vec::with_capacity(chunks.len());
+    for (i, data) in chunks.iter().enumerate() {
+        let mut segment = Vec::with_capacity(headers.len() + data.len());
+        segment.extend_from_slice(headers);
+        segment.extend_from_slice(data);
+        let ip_len = segment.len() - ETHERNET_HEADER_LEN;
+        let (src, dst) = if tcp.ipv4 {
+            let mut ip = Ipv4Packet::new_unchecked(&mut segment[ETHERNET_HEADER_LEN..]);
+            ip.set_total_len(ip_len as u16);
+            ip.fill_checksum();
+            (
+                IpAddress::from(ip.src_addr()),
+                IpAddress::from(ip.dst_addr()),
+            )
+        } else {
+            let mut ip = Ipv6Packet::new_unchecked(&mut segment[ETHERNET_HEADER_LEN..]);
+            ip.set_payload_len((ip_len - IPV6_HEADER_LEN) as u16);
+            (
+                IpAddress::from(ip.src_addr()),
+                IpAddress::from(ip.dst_addr()),
+            )
+        };
+        let mut packet = TcpPacket::new_unchecked(&mut segment[tcp.start..]);
+        packet.set_seq_number(packet.seq_number() + offset);
+        if i + 1 < chunks.len() {
+            packet.set_psh(false);
+            packet.set_fin(false);
+        }
+        packet.fill_checksum(&src, &dst);
+        offset += data.len();
+        segments.push(segment);
+    }
+    segments
+}
+
+/// Destination and MTU of an ICMP error about a TCP segment
+fn parse_error_frame(buffer: &[u8], headers: &Headers) -> Option<(IpAddr, usize)> {
+    let ip = match *headers {
+        Headers::Ip(ref ip) => ip,
+        _ => return None,
+    };
+    let icmp = ip.payload(buffer);
+    match ip.protocol {
+        IpProtocol::Icmp if ip.src.is_ipv4() => {
+            if icmp.len() < ICMP_HEADER_LEN {
+                return None;
+            }
+            if icmp[0] != ICMPV4_DST_UNREACHABLE || icmp[1] != ICMPV4_FRAG_NEEDED {
+                return None;
+            }
+            // the quoted IPv4 header and the first 8 bytes of the segment
+            let quoted = &icmp[ICMP_HEADER_LEN..];
+            if quoted.len() < IPV4_HEADER_LEN
+                || quoted[0] >> 4 != 4
+                || quoted[9] != u8::from(IpProtocol::Tcp)
+            {
+                return None;
+            }
+            let mut dst = [0; 4];
+            dst.copy_from_slice(&quoted[16..20]);
+            let quoted_len = be16(&quoted[2..]) as usize;
+            let mtu = match be16(&icmp[6..]) as usize {
+                0 => plateau_below(quoted_len), // router without RFC 1191 support
+                mtu if mtu >= quoted_len => return None,
+                mtu => mtu,
+            };
+            Some((IpAddr::V4(Ipv4Addr::from(dst)), mtu))
+        }
+        IpProtocol::Icmpv6 if ip.src.is_ipv6() => {
+            if icmp.len() < ICMP_HEADER_LEN {
+                return None;
+            }
+            if icmp[0] != ICMPV6_PKT_TOO_BIG {
+                return None;
+            }
+            let quoted = &icmp[ICMP_HEADER_LEN..];
+            if quoted.len() < IPV6_HEADER_LEN
+                || quoted[0] >> 4 != 6
+                || quoted[6] != u8::from(IpProtocol::Tcp)
+            {
+                return None;
+            }
+            let mut dst = [0; 16];
+            dst.copy_from_slice(&quoted[24..40]);
+            let mtu = u32::from_be_bytes([icmp[4], icmp[5], icmp[6], icmp[7]]) as usize;
+            Some((IpAddr::V6(Ipv6Addr::from(dst)), mtu))
+        }
+        _ => None,
+    }
+}
+
+fn be16(data: &[u8]) -> u16 {
+    u16::from_be_bytes([data[0], data[1]])
+}
@@ -0,0 +1,160 @@
+/// One stack per receive queue of a NIC, see StackConfig::queues
+/// The NIC spreads the TCP connections over its queues by the RSS hash of their addresses
+/// and ports, so the TCP packets stay with the stack of the queue they arrive on. A connection
+/// is created on a stack with a local port for which the hash selects the stack's queue and a
+/// listener has a backlog on every stack.
+/// UDP, ICMP, and raw IP sockets only exist on the stack of the first queue, which gets all
+/// other packets handed over from the other stacks. ARP and neighbor discovery frames are
+/// copied to every stack because each has its own neighbor cache.
+use parking_lot::{Condvar, Mutex};
+use std::collections::VecDeque;
+use std::net::SocketAddrV4;
+use std::os::unix::net::UnixDatagram;
+
+extern crate usnet_devices;
+use self::usnet_devices::Rss;
+
+use smoltcp::wire::{EthernetFrame, EthernetProtocol, IpProtocol, Ipv4Packet, Ipv6Packet};
+
+const ICMPV6_ROUTER_SOLICIT: u8 = 133;
+const ICMPV6_REDIRECT: u8 = 137;
+
+/// The stacks on consecutive queues of the NIC, the stack with index 0 is on the first queue
+#[derive(Debug)]
+pub struct QueueGroup {
+    rss: Rss,
+    first_queue: u32,
+    inboxes: Vec<Inbox>,
+    polls: Mutex<u64>,
+    polled: Condvar,
+}
+
+/// Frames handed over from the other stacks, and the notification which wakes up the
+/// background thread of the stack
+#[derive(Debug)]
+struct Inbox {
+    frames: Mutex<VecDeque<Vec<u8>>>,
+    notify_poll: UnixDatagram,
+}
+
+enum Destination {
+    Local,
+    Everywhere,
+    First,
+}
+
+impl QueueGroup {
+    /// Takes the notification sockets of the stacks in the order of their queues
+    pub fn new(rss: Rss, first_queue: u32, notify_polls: Vec<UnixDatagram>) -> QueueGroup {
+        QueueGroup {
+            rss: rss,
+            first_queue: first_queue,
+            inboxes: notify_polls
+                .into_iter()
+                .map(|notify_poll| Inbox {
+                    frames: Mutex::new(VecDeque::new()),
+                    notify_poll: notify_poll,
+                })
+                .collect(),
+            polls: Mutex::new(0),
+            polled: Condvar::new(),
+        }
+    }
+
+    pub fn stacks(&self) -> usize {
+        self.inboxes.len()
+    }
+
+    /// Index of the stack which receives the packets of a TCP connection, None if the NIC
+    /// steers them to a queue without a stack
+    pub fn index_for(&self, local: SocketAddrV4, remote: SocketAddrV4) -> Option<usize> {
+        let queue = self.rss.queue(remote, local);
+        if queue < self.first_queue {
+            return None;
+        }
+        let index = (queue - self.first_queue) as usize;
+        if index < self.inboxes.len() {
+            Some(index)
+        } else {
+            None
+        }
+    }
+
+    /// Hands a frame received by the stack with the index over to the other stacks,
+    /// returns whether the stack itself processes it
+    pub fn hand_off(&self, index: usize, frame: &[u8]) -> bool {
+        match destination(frame) {
+            Destination::Local => true,
+            Destination::Everywhere => {
+                for other in (0..self.inboxes.len()).filter(|&other| other != index) {
+                    self.deliver(other, frame);
+                }
+                true
+            }
+            Destination::First if index == 0 => true,
+            Destination::First => {
+                self.deliver(0, frame);
+                false
+            }
+        }
+    }
+
+    fn deliver(&self, index: usize, frame: &[u8]) {
+        let inbox = &self.inboxes[index];
+        inbox.frames.lock().push_back(frame.to_vec());
+        let _ = inbox.notify_poll.send(b"$");
+    }
+
+    /// Removes the frames which other stacks handed over to the stack with the index
+    pub fn take(&self, index: usize) -> VecDeque<Vec<u8>> {
+        let mut frames = self.inboxes[index].frames.lock();
+        frames.split_off(0)
+    }
+
+    /// Number of polls of all stacks, for waiting until any stack polled with wait_for_poll
+    pub fn polls(&self) -> u64 {
+        *self.polls.lock()
+    }
+
+    /// Called by the background threads after each poll
+    pub fn notify_polled(&self) {
+        let mut polls = self.polls.lock();
+        *polls += 1;
+        self.polled.notify_all();
+    }
+
+    /// Blocks until a stack polled after the number of polls was seen
+    pub fn wait_for_poll(&self, seen: u64) {
+        let mut polls = self.polls.lock();
+        while *polls == seen {
+            self.polled.wait(&mut polls);
+        }
+    }
+}
+
+fn destination(frame: &[u8]) -> Destination {
+    let frame = match EthernetFrame::new_checked(frame) {
+        Ok(frame) => frame,
+        Err(_) => return Destination::First,
+    };
+    match frame.ethertype() {
+        EthernetProtocol::Arp => Destination::Everywhere,
+        EthernetProtocol::Ipv4 => match Ipv4Packet::new_checked(frame.payload()) {
+            Ok(ref ip) if ip.protocol() == IpProtocol::Tcp => Destination::Local,
+            _ => Destination::First,
+        },
+        EthernetProtocol::Ipv6 => match Ipv6Packet::new_checked(frame.payload()) {
+            Ok(ref ip) if ip.next_header() == IpProtocol::Tcp => Destination::Local,
+            Ok(ref ip)
+                if ip.next_header() == IpProtocol::Icmpv6
+                    && ip.payload().first().map_or(false, |&t| {
+                        t >= ICMPV6_ROUTER_SOLICIT && t <= ICMPV6_REDIRECT
+                    }) =>
+            {
+                Destination::Everywhere
+            }
+            _ => Destination::First,
+        },
+        _ => Destination::First,
+    }
+}
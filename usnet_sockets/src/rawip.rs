@@ -0,0 +1,153 @@
+/// IP headers for the RawSocket
+/// smoltcp hands every received packet of a protocol to the raw sockets for it in addition
+/// to the normal processing, so a raw socket gets a copy and does not take packets away
+/// from other sockets or the stack. Raw sockets for TCP and UDP are still not allowed
+/// because sending through them would interfere with the connections of the stack.
+/// smoltcp rebuilds the IP header of outgoing packets from the addresses, the protocol,
+/// the TTL, and the payload, i.e., options and the TOS field are not sent.
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use smoltcp::wire::IpProtocol;
+
+const IPV4_HEADER_LEN: usize = 20;
+const IPV6_HEADER_LEN: usize = 40;
+const IPV4_DONT_FRAGMENT: u8 = 0x40;
+
+pub(crate) fn check_protocol(protocol: u8) -> io::Result<()> {
+    if protocol == u8::from(IpProtocol::Tcp) || protocol == u8::from(IpProtocol::Udp) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "raw sockets for TCP and UDP are not supported, use the TcpStream and UdpSocket",
+        ));
+    }
+    Ok(())
+}
+
+/// Returns the payload with an IPv4 or IPv6 header, the checksum is filled in by smoltcp
+pub(crate) fn build_packet(
+    src: IpAddr,
+    dst: IpAddr,
+    protocol: u8,
+    ttl: u8,
+    payload: &[u8],
+) -> io::Result<Vec<u8>> {
+    let too_long = || io::Error::new(io::ErrorKind::InvalidInput, "payload too long");
+    let mut packet;
+    match (src, dst) {
+        (IpAddr::V4(src), IpAddr::V4(dst)) => {
+            let total_len = IPV4_HEADER_LEN + payload.len();
+            if total_len > 0xffff {
+                return Err(too_long());
+            }
+            packet = Vec::with_capacity(total_len);
+            packet.extend_from_slice(&[0x45, 0]); // version, header length, and TOS
+            packet.extend_from_slice(&(total_len as u16).to_be_bytes());
+            packet.extend_from_slice(&[0, 0, IPV4_DONT_FRAGMENT, 0, ttl, protocol, 0, 0]);
+            packet.extend_from_slice(&src.octets());
+            packet.extend_from_slice(&dst.octets());
+        }
+        (IpAddr::V6(src), IpAddr::V6(dst)) => {
+            if payload.len() > 0xffff {
+                return Err(too_long());
+            }
+            packet = Vec::with_capacity(IPV6_HEADER_LEN + payload.len());
+            packet.extend_from_slice(&[0x60, 0, 0, 0]); // version, traffic class, and flow label
+            packet.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+            packet.extend_from_slice(&[protocol, ttl]);
+            packet.extend_from_slice(&src.octets());
+            packet.extend_from_slice(&dst.octets());
+        }
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "source and destination address family differ",
+            ))
+        }
+    }
+    packet.extend_from_slice(payload);
+    Ok(packet)
+}
+
+/// Checks a packet given with its IP header and returns the source and destination address
+pub(crate) fn header_addresses(
+    ipv6: bool,
+    protocol: u8,
+    packet: &[u8],
+) -> io::Result<(IpAddr, IpAddr)> {
+    let invalid = |msg| io::Error::new(io::ErrorKind::InvalidInput, msg);
+    if !ipv6 {
+        if packet.len() < IPV4_HEADER_LEN || packet[0] >> 4 != 4 {
+            return Err(invalid("not an IPv4 packet"));
+        }
+        let header_len = ((packet[0] & 0x0f) as usize) * 4;
+        if header_len < IPV4_HEADER_LEN
+            || header_len > packet.len()
+            || be16(&packet[2..]) as usize != packet.len()
+        {
+            return Err(invalid("IPv4 header length fields do not match the packet"));
+        }
+        if packet[9] != protocol {
+            return Err(invalid("IP protocol of the header differs from the socket"));
+        }
+        Ok((
+            IpAddr::V4(ipv4_address(&packet[12..16])),
+            IpAddr::V4(ipv4_address(&packet[16..20])),
+        ))
+    } else {
+        if packet.len() < IPV6_HEADER_LEN || packet[0] >> 4 != 6 {
+            return Err(invalid("not an IPv6 packet"));
+        }
+        if be16(&packet[4..]) as usize != packet.len() - IPV6_HEADER_LEN {
+            return Err(invalid("IPv6 payload length does not match the packet"));
+        }
+        if packet[6] != protocol {
+            return Err(invalid(
+                "next header of the IPv6 header differs from the socket",
+            ));
+        }
+        Ok((
+            IpAddr::V6(ipv6_address(&packet[8..24])),
+            IpAddr::V6(ipv6_address(&packet[24..40])),
+        ))
+    }
+}
+
+/// Replaces the source address of a packet which was checked with header_addresses
+pub(crate) fn set_source_address(packet: &mut [u8], src: IpAddr) {
+    match src {
+        IpAddr::V4(src) => packet[12..16].copy_from_slice(&src.octets()),
+        IpAddr::V6(src) => packet[8..24].copy_from_slice(&src.octets()),
+    }
+}
+
+/// Returns the source address and the header length of a received packet
+pub(crate) fn parse_packet(packet: &[u8]) -> Option<(IpAddr, usize)> {
+    match packet.first().map(|b| b >> 4) {
+        Some(4) if packet.len() >= IPV4_HEADER_LEN => {
+            let header_len = ((packet[0] & 0x0f) as usize) * 4;
+            if header_len < IPV4_HEADER_LEN || header_len > packet.len() {
+                return None;
+            }
+            Some((IpAddr::V4(ipv4_address(&packet[12..16])), header_len))
+        }
+        Some(6) if packet.len() >= IPV6_HEADER_LEN => {
+            Some((IpAddr::V6(ipv6_address(&packet[8..24])), IPV6_HEADER_LEN))
+        }
+        _ => None,
+    }
+}
+
+fn ipv4_address(data: &[u8]) -> Ipv4Addr {
+    Ipv4Addr::new(data[0], data[1], data[2], data[3])
+}
+
+fn ipv6_address(data: &[u8]) -> Ipv6Addr {
+    let mut octets = [0; 16];
+    octets.copy_from_slice(data);
+    Ipv6Addr::from(octets)
+}
+
+fn be16(data: &[u8]) -> u16 {
+    u16::from_be_bytes([data[0], data[1]])
+}
@@ -78,8 +78,8 @@ pub fn address_name(addr: &IpAddr) -> String {
 
 #[cfg(test)]
 mod test {
-    use super::{address_equal, address_name};
-    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+    use super::{address_equal, address_name, socket_address_equal};
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 
     #[test]
     fn test_address_equal() {
@@ -94,6 +94,19 @@ mod test {
         ));
     }
 
+    #[test]
+    fn test_socket_address_equal() {
+        let v4: SocketAddr = "192.0.2.1:53".parse().unwrap();
+        let mapped: SocketAddr = "[::ffff:192.0.2.1]:53".parse().unwrap();
+        let other_port: SocketAddr = "[::ffff:192.0.2.1]:54".parse().unwrap();
+        let v6: SocketAddr = "[2001:db8::c000:201]:53".parse().unwrap();
+
+        assert!(socket_address_equal(&v4, &mapped));
+        assert!(socket_address_equal(&mapped, &v4));
+        assert!(!socket_address_equal(&v4, &other_port));
+        assert!(!socket_address_equal(&v4, &v6));
+    }
+
     #[test]
     fn test_address_name() {
         assert_eq!(
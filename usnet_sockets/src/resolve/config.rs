@@ -1,9 +1,18 @@
 //! DNS resolver configuration
 
+use std::cmp::{max, min};
+use std::collections::BTreeMap;
 use std::io;
 use std::net::SocketAddr;
+use std::sync::Arc;
 use std::time::Duration;
 
+use super::iterative::root_hints;
+use super::message::{ClientSubnet, RCode};
+use super::rng::{OsRandom, RandSource};
+#[cfg(feature = "dot")]
+use super::stream::TlsAuth;
+
 /// Configures the behavior of DNS requests
 #[derive(Clone, Debug)]
 pub struct DnsConfig {
@@ -18,9 +27,51 @@ pub struct DnsConfig {
     pub timeout: Duration,
     /// Number of attempts made before returning an error
     pub attempts: u32,
+    /// Transports of the name servers which are not queried over UDP; a
+    /// server missing here is queried over UDP, and `DnsResolver::query_fastest`
+    /// and `DnsResolver::resolve_batch` always use UDP
+    pub transports: BTreeMap<SocketAddr, Transport>,
+    /// Duration in which a TCP or TLS connection to a name server has to be
+    /// established
+    pub handshake_timeout: Duration,
+    /// Growth and randomization of the timeout for the following attempts
+    pub retry: RetryPolicy,
+    /// Whether a response with the response code is returned or the query goes
+    /// to the next name server, see `default_retry_decision`
+    pub retry_decision: fn(&RCode) -> RetryDecision,
+
+    /// Number of name servers which `DnsResolver::query_fastest` queries at
+    /// the same time
+    pub fan_out: usize,
+    /// Number of queries which `DnsResolver::resolve_batch` has in flight at
+    /// the same time
+    pub batch_limit: usize,
+    /// Name servers which `DnsResolver::trace` starts an iterative resolution
+    /// at, the root servers of `iterative::root_hints` by default
+    pub root_hints: Vec<SocketAddr>,
+    /// EDNS Client Subnet option of the queries, none by default; with
+    /// `ClientSubnet::opt_out()`, servers must not use the network of the
+    /// resolver's address in its place either
+    pub client_subnet: Option<ClientSubnet>,
 
     /// Whether to rotate through available nameservers
     pub rotate: bool,
+    /// Whether to randomize the case of the letters of each queried name and
+    /// discard responses which don't echo it exactly (the "0x20" trick)
+    pub randomize_case: bool,
+    /// Whether queries carry DNS cookies (RFC 7873), which some name servers
+    /// require to answer above their rate limit
+    pub cookies: bool,
+    /// Whether queries ask the name servers for their identifier (NSID, RFC
+    /// 5001), e.g., the instance of an anycast service, which responses carry
+    /// in `Message::nsid`
+    pub nsid: bool,
+    /// Whether names under `local.` are resolved through multicast DNS instead
+    /// of the name servers, without search domains; needs the `mdns` build
+    /// feature, with which it is the default
+    pub mdns: bool,
+    /// Duration in which the mDNS responses of several hosts are collected
+    pub mdns_window: Duration,
     /// If `true`, perform `AAAA` queries first and return IPv4 addresses
     /// as IPv4-mapped IPv6 addresses.
     pub use_inet6: bool,
@@ -28,6 +79,9 @@ pub struct DnsConfig {
 
 impl DnsConfig {
     /// Returns the default system configuration for DNS requests.
+    ///
+    /// If a DHCP lease with DNS servers is bound, its servers replace the
+    /// name servers of the system configuration.
     pub fn load_default() -> io::Result<DnsConfig> {
         default_config_impl()
     }
@@ -42,14 +96,285 @@ impl DnsConfig {
             n_dots: 1,
             timeout: Duration::from_secs(5),
             attempts: 5,
+            transports: BTreeMap::new(),
+            handshake_timeout: DEFAULT_HANDSHAKE_TIMEOUT,
+            retry: RetryPolicy::default(),
+            retry_decision: default_retry_decision,
+            fan_out: DEFAULT_FAN_OUT,
+            batch_limit: DEFAULT_BATCH_LIMIT,
+            root_hints: root_hints(),
+            client_subnet: None,
 
             rotate: false,
+            randomize_case: false,
+            cookies: false,
+            nsid: false,
+            mdns: cfg!(feature = "mdns"),
+            mdns_window: DEFAULT_MDNS_WINDOW,
             use_inet6: false,
         }
     }
 }
 
+/// Default of `DnsConfig::fan_out`
+pub const DEFAULT_FAN_OUT: usize = 3;
+
+/// Default of `DnsConfig::batch_limit`
+pub const DEFAULT_BATCH_LIMIT: usize = 64;
+
+/// Default of `DnsConfig::mdns_window`
+pub const DEFAULT_MDNS_WINDOW: Duration = Duration::from_millis(500);
+
+/// Default of `DnsConfig::handshake_timeout`
+pub const DEFAULT_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How the resolver sends queries to a name server, see `DnsConfig::transports`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Transport {
+    /// UDP datagrams, the default
+    Udp,
+    /// A TCP connection with length-prefixed messages (RFC 7766)
+    Tcp,
+    /// A TLS connection with length-prefixed messages (DoT, RFC 7858),
+    /// usually to port `stream::DOT_PORT`
+    #[cfg(feature = "dot")]
+    Tls(TlsAuth),
+}
+
+/// What the resolver does with a response, decided from its response code
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum RetryDecision {
+    /// The response is final and returned, whatever its response code
+    Accept,
+    /// The query is sent to the next name server which has not answered yet;
+    /// once every server has answered so or the attempts are used up, the
+    /// error of the last such response is returned
+    TryNext,
+}
+
+/// The default of `DnsConfig::retry_decision`
+///
+/// * `ServerFailure` tries the next server, the failure is often one of the
+///   server or its upstream (a timeout does the same).
+/// * `Refused` tries the next server too, as it either means that this server
+///   does not serve the client, so that another one may, or a policy block,
+///   which the other servers are likely to repeat; if all of them refuse,
+///   `Refused` is returned without further attempts.
+/// * Every other response code is final, e.g., `NoError` also without answers
+///   and `NameError` (NXDOMAIN), which any other server would give as well.
+pub fn default_retry_decision(rcode: &RCode) -> RetryDecision {
+    match *rcode {
+        RCode::ServerFailure | RCode::Refused => RetryDecision::TryNext,
+        _ => RetryDecision::Accept,
+    }
+}
+
+/// Randomization of the timeouts, so that many clients which start together don't
+/// retransmit their queries at the same time
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Jitter {
+    /// Each timeout is the previous one multiplied by the backoff factor
+    None,
+    /// Each timeout is random between zero and the timeout without jitter
+    Full,
+    /// Each timeout is random between the initial timeout and three times the previous
+    /// timeout (or the initial one for the first attempt), the backoff factor is not used
+    Decorrelated,
+}
+
+/// Timeouts of the attempts of a request, starting with `DnsConfig::timeout`
+/// The default keeps the timeout constant for all attempts.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// Factor by which the timeout grows for each attempt, 1 for a constant timeout
+    pub backoff: u32,
+    /// Upper bound for the timeout of an attempt
+    pub max_timeout: Duration,
+    /// Randomization of each timeout
+    pub jitter: Jitter,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> RetryPolicy {
+        RetryPolicy {
+            backoff: 1,
+            max_timeout: Duration::from_secs(30),
+            jitter: Jitter::None,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Exponential backoff with a factor of 2, e.g., 1, 2, 4, 8 seconds
+    pub fn exponential(max_timeout: Duration, jitter: Jitter) -> RetryPolicy {
+        RetryPolicy {
+            backoff: 2,
+            max_timeout: max_timeout,
+            jitter: jitter,
+        }
+    }
+
+    /// Returns the endless sequence of timeouts for the attempts of one request
+    pub fn timeouts(&self, initial: Duration) -> RetryTimeouts {
+        self.timeouts_with(initial, Arc::new(OsRandom))
+    }
+
+    /// Returns the timeouts like `timeouts`, with the jitter from the source
+    pub fn timeouts_with(&self, initial: Duration, rand: Arc<dyn RandSource>) -> RetryTimeouts {
+        RetryTimeouts {
+            policy: *self,
+            initial: min(initial, self.max_timeout),
+            ceiling: None,
+            previous: None,
+            rand: rand,
+        }
+    }
+}
+
+/// Timeouts of the attempts of one request, see `RetryPolicy::timeouts`
+#[derive(Clone, Debug)]
+pub struct RetryTimeouts {
+    policy: RetryPolicy,
+    initial: Duration,
+    ceiling: Option<Duration>, // timeout of the last attempt without jitter
+    previous: Option<Duration>,
+    rand: Arc<dyn RandSource>,
+}
+
+// the socket rejects a zero read timeout
+const MIN_TIMEOUT: Duration = Duration::from_millis(1);
+
+impl Iterator for RetryTimeouts {
+    type Item = Duration;
+
+    fn next(&mut self) -> Option<Duration> {
+        let cap = self.policy.max_timeout;
+        let ceiling = match self.ceiling {
+            None => self.initial,
+            Some(ceiling) => ceiling
+                .checked_mul(self.policy.backoff)
+                .map_or(cap, |c| min(c, cap)),
+        };
+        self.ceiling = Some(ceiling);
+        let timeout = match self.policy.jitter {
+            Jitter::None => ceiling,
+            Jitter::Full => random_between(&*self.rand, MIN_TIMEOUT, ceiling),
+            Jitter::Decorrelated => {
+                let previous = self.previous.unwrap_or(self.initial);
+                let upper = previous.checked_mul(3).map_or(cap, |p| min(p, cap));
+                random_between(&*self.rand, self.initial, upper)
+            }
+        };
+        let timeout = max(timeout, MIN_TIMEOUT);
+        self.previous = Some(timeout);
+        Some(timeout)
+    }
+}
+
+fn random_between(rand: &dyn RandSource, low: Duration, high: Duration) -> Duration {
+    if high <= low {
+        return low;
+    }
+    let (low, high) = (low.as_nanos() as u64, high.as_nanos() as u64);
+    Duration::from_nanos(rand.between(low, high))
+}
+
 fn default_config_impl() -> io::Result<DnsConfig> {
     use super::resolv_conf::load;
-    load()
+    // the DNS servers of a DHCP lease take precedence over the ones of the kernel
+    let lease_servers = crate::dhcp::lease_name_servers();
+    match load() {
+        Ok(mut config) => {
+            if !lease_servers.is_empty() {
+                config.name_servers = lease_servers;
+            }
+            Ok(config)
+        }
+        Err(_) if !lease_servers.is_empty() => Ok(DnsConfig::with_name_servers(lease_servers)),
+        Err(err) => Err(err),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{default_retry_decision, Jitter, RetryDecision, RetryPolicy};
+    use crate::resolve::message::RCode;
+    use std::collections::BTreeSet;
+    use std::time::Duration;
+
+    const INITIAL: Duration = Duration::from_secs(1);
+    const MAX: Duration = Duration::from_secs(10);
+
+    fn schedules(jitter: Jitter) -> Vec<Vec<Duration>> {
+        let policy = RetryPolicy::exponential(MAX, jitter);
+        (0..200)
+            .map(|_| policy.timeouts(INITIAL).take(6).collect())
+            .collect()
+    }
+
+    #[test]
+    fn test_backoff() {
+        let secs = |s| Duration::from_secs(s);
+        let timeouts: Vec<_> = RetryPolicy::default().timeouts(INITIAL).take(3).collect();
+        assert_eq!(timeouts, vec![secs(1), secs(1), secs(1)]);
+        for schedule in schedules(Jitter::None) {
+            assert_eq!(
+                schedule,
+                vec![secs(1), secs(2), secs(4), secs(8), secs(10), secs(10)]
+            );
+        }
+    }
+
+    #[test]
+    fn test_default_retry_decision() {
+        for rcode in &[RCode::ServerFailure, RCode::Refused] {
+            assert_eq!(default_retry_decision(rcode), RetryDecision::TryNext);
+        }
+        for rcode in &[
+            RCode::NoError,
+            RCode::FormatError,
+            RCode::NameError,
+            RCode::NotImplemented,
+            RCode::YxDomain,
+            RCode::YxRrSet,
+            RCode::NxRrSet,
+            RCode::NotAuth,
+            RCode::NotZone,
+            RCode::Other(16),
+        ] {
+            assert_eq!(default_retry_decision(rcode), RetryDecision::Accept);
+        }
+    }
+
+    #[test]
+    fn test_full_jitter() {
+        let schedules = schedules(Jitter::Full);
+        for schedule in &schedules {
+            let mut ceiling = INITIAL;
+            for &timeout in schedule {
+                assert!(timeout > Duration::from_secs(0) && timeout <= ceiling);
+                ceiling = (ceiling * 2).min(MAX);
+            }
+        }
+        for attempt in 0..6 {
+            let distinct: BTreeSet<_> = schedules.iter().map(|s| s[attempt]).collect();
+            assert!(distinct.len() > 100);
+        }
+    }
+
+    #[test]
+    fn test_decorrelated_jitter() {
+        let schedules = schedules(Jitter::Decorrelated);
+        for schedule in &schedules {
+            let mut previous = INITIAL;
+            for &timeout in schedule {
+                assert!(timeout >= INITIAL && timeout <= (previous * 3).min(MAX));
+                previous = timeout;
+            }
+        }
+        for attempt in 0..6 {
+            let distinct: BTreeSet<_> = schedules.iter().map(|s| s[attempt]).collect();
+            assert!(distinct.len() > 100);
+        }
+    }
 }
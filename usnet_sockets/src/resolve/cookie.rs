@@ -0,0 +1,228 @@
+//! Client side of DNS cookies (RFC 7873)
+
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use super::rng::{OsRandom, RandSource};
+
+/// Extended response code of a response to a query without a valid server
+/// cookie, which is answered again with the cookie of the response
+pub const BADCOOKIE: u16 = 23;
+
+/// Maximum number of name servers whose cookies are kept, the least recently
+/// used one is forgotten for a new one
+pub const MAX_SERVERS: usize = 256;
+
+/// Duration after which an unused server cookie is forgotten
+pub const IDLE_TIMEOUT: Duration = Duration::from_secs(3600);
+
+/// Returns the client and server cookie of the data of a cookie option, or
+/// `None` if their lengths are invalid: 8 bytes for the client cookie and 8 to
+/// 32 bytes for the optional server cookie.
+pub fn split_cookie(option: &[u8]) -> Option<(&[u8], &[u8])> {
+    match option.len() {
+        8 | 16..=40 => Some(option.split_at(8)),
+        _ => None,
+    }
+}
+
+/// The client cookies of a resolver and the server cookies it received
+pub(crate) struct Cookies {
+    /// Key of the SipHash-2-4 which derives the client cookies, random for
+    /// each resolver
+    secret: [u64; 2],
+    servers: Mutex<BTreeMap<IpAddr, (Vec<u8>, Instant)>>,
+}
+
+impl Default for Cookies {
+    fn default() -> Cookies {
+        Cookies::new(&OsRandom)
+    }
+}
+
+impl Cookies {
+    /// Returns cookies with a key from the source.
+    pub fn new(rand: &dyn RandSource) -> Cookies {
+        Cookies {
+            secret: [rand.next_u64(), rand.next_u64()],
+            servers: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Returns the client cookie for the client and server address, the same
+    /// for the lifetime of the resolver.
+    ///
+    /// The cookie is the SipHash-2-4 of the addresses keyed with the secret,
+    /// as in RFC 9018 for server cookies, which unlike the hasher of the
+    /// standard library is specified and does not change between releases.
+    pub fn client_cookie(&self, client: IpAddr, server: IpAddr) -> [u8; 8] {
+        let mut data = Vec::with_capacity(32);
+        for addr in &[client, server] {
+            match *addr {
+                IpAddr::V4(addr) => data.extend_from_slice(&addr.octets()),
+                IpAddr::V6(addr) => data.extend_from_slice(&addr.octets()),
+            }
+        }
+        siphash24(self.secret, &data).to_be_bytes()
+    }
+
+    /// Returns the data of the cookie option of a query to the server, with
+    /// the last server cookie it sent if there is one.
+    pub fn option(&self, client: IpAddr, server: IpAddr) -> Vec<u8> {
+        let mut option = self.client_cookie(client, server).to_vec();
+        let now = Instant::now();
+        let mut servers = self.servers.lock().unwrap();
+        servers.retain(|_, &mut (_, used)| now.duration_since(used) < IDLE_TIMEOUT);
+        if let Some(&mut (ref cookie, ref mut used)) = servers.get_mut(&server) {
+            option.extend_from_slice(cookie);
+            *used = now;
+        }
+        option
+    }
+
+    /// Checks the cookie option of a response from the server and keeps its
+    /// server cookie, returns whether the client cookie is the one sent.
+    pub fn update(&self, client: IpAddr, server: IpAddr, option: &[u8]) -> bool {
+        let (client_cookie, server_cookie) = match split_cookie(option) {
+            Some(cookies) => cookies,
+            None => return false,
+        };
+        if client_cookie[..] != self.client_cookie(client, server)[..] {
+            return false;
+        }
+        if server_cookie.is_empty() {
+            return true;
+        }
+        let mut servers = self.servers.lock().unwrap();
+        if !servers.contains_key(&server) && servers.len() >= MAX_SERVERS {
+            let oldest = servers
+                .iter()
+                .min_by_key(|&(_, &(_, used))| used)
+                .map(|(&addr, _)| addr);
+            if let Some(oldest) = oldest {
+                servers.remove(&oldest);
+            }
+        }
+        servers.insert(server, (server_cookie.to_vec(), Instant::now()));
+        true
+    }
+}
+
+/// Returns the SipHash-2-4 of the data with the key as two little-endian words
+fn siphash24(key: [u64; 2], data: &[u8]) -> u64 {
+    fn round(v: &mut [u64; 4]) {
+        v[0] = v[0].wrapping_add(v[1]);
+        v[1] = v[1].rotate_left(13) ^ v[0];
+        v[0] = v[0].rotate_left(32);
+        v[2] = v[2].wrapping_add(v[3]);
+        v[3] = v[3].rotate_left(16) ^ v[2];
+        v[0] = v[0].wrapping_add(v[3]);
+        v[3] = v[3].rotate_left(21) ^ v[0];
+        v[2] = v[2].wrapping_add(v[1]);
+        v[1] = v[1].rotate_left(17) ^ v[2];
+        v[2] = v[2].rotate_left(32);
+    }
+    fn compress(v: &mut [u64; 4], m: u64) {
+        v[3] ^= m;
+        round(v);
+        round(v);
+        v[0] ^= m;
+    }
+
+    let mut v = [
+        key[0] ^ 0x736f_6d65_7073_6575,
+        key[1] ^ 0x646f_7261_6e64_6f6d,
+        key[0] ^ 0x6c79_6765_6e65_7261,
+        key[1] ^ 0x7465_6462_7974_6573,
+    ];
+    let mut chunks = data.chunks_exact(8);
+    for chunk in &mut chunks {
+        let mut word = [0; 8];
+        word.copy_from_slice(chunk);
+        compress(&mut v, u64::from_le_bytes(word));
+    }
+    // the remaining bytes and the length modulo 256 in the last word
+    let mut last = [0; 8];
+    last[..chunks.remainder().len()].copy_from_slice(chunks.remainder());
+    last[7] = data.len() as u8;
+    compress(&mut v, u64::from_le_bytes(last));
+    v[2] ^= 0xff;
+    for _ in 0..4 {
+        round(&mut v);
+    }
+    v[0] ^ v[1] ^ v[2] ^ v[3]
+}
+
+#[cfg(test)]
+mod test {
+    use super::{siphash24, split_cookie, Cookies, MAX_SERVERS};
+    use crate::resolve::rng::SeededRandom;
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+    #[test]
+    fn test_siphash() {
+        // the vectors of the SipHash paper, key 00..0f and messages 00, 00 01, and so on
+        let key = [0x0706_0504_0302_0100, 0x0f0e_0d0c_0b0a_0908];
+        let data: Vec<u8> = (0..64).collect();
+        assert_eq!(siphash24(key, &[]), 0x726f_db47_dd0e_0e31);
+        assert_eq!(siphash24(key, &data[..8]), 0x93f5_f579_9a93_2462);
+        assert_eq!(siphash24(key, &data[..15]), 0xa129_ca61_49be_45e5);
+
+        // the hash of the client and then the server address, the same for the same key
+        let client = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 100));
+        let server = IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1));
+        let cookies = Cookies::new(&SeededRandom::new(7));
+        let mut addrs = vec![192, 0, 2, 100];
+        addrs.extend_from_slice(&[0x20, 0x01, 0x0d, 0xb8]);
+        addrs.extend_from_slice(&[0; 11]);
+        addrs.push(1);
+        assert_eq!(
+            cookies.client_cookie(client, server),
+            siphash24(cookies.secret, &addrs).to_be_bytes()
+        );
+        assert_eq!(
+            Cookies::new(&SeededRandom::new(7)).client_cookie(client, server),
+            cookies.client_cookie(client, server)
+        );
+    }
+
+    #[test]
+    fn test_cookies() {
+        let client = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 100));
+        let server = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+        let other = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 2));
+        let cookies = Cookies::default();
+        let client_cookie = cookies.client_cookie(client, server);
+        assert_eq!(cookies.client_cookie(client, server), client_cookie);
+        assert_ne!(cookies.client_cookie(client, other), client_cookie);
+        assert_ne!(
+            Cookies::default().client_cookie(client, server),
+            client_cookie
+        );
+        assert_eq!(cookies.option(client, server), client_cookie.to_vec());
+
+        let mut response = client_cookie.to_vec();
+        response.extend_from_slice(&[1, 2, 3, 4, 5, 6, 7, 8]);
+        assert!(cookies.update(client, server, &response));
+        assert_eq!(cookies.option(client, server), response);
+        assert_eq!(cookies.option(client, other).len(), 8);
+
+        // a response for another client cookie or of a wrong length
+        assert!(!cookies.update(client, other, &response));
+        assert!(!cookies.update(client, server, &response[..12]));
+        assert_eq!(split_cookie(&[0; 41]), None);
+        assert_eq!(cookies.option(client, server), response);
+
+        for i in 0..MAX_SERVERS {
+            let server = IpAddr::V4(Ipv4Addr::from(0x0a00_0000 + i as u32));
+            let mut response = cookies.client_cookie(client, server).to_vec();
+            response.extend_from_slice(&[0; 8]);
+            assert!(cookies.update(client, server, &response));
+        }
+        assert_eq!(cookies.servers.lock().unwrap().len(), MAX_SERVERS);
+        // the least recently used was forgotten
+        assert_eq!(cookies.option(client, server).len(), 8);
+    }
+}
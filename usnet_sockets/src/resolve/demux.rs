@@ -0,0 +1,171 @@
+//! Routing of the responses on a shared socket to the outstanding queries
+//!
+//! An event loop which sends several queries on one `DnsSocket` keeps them in
+//! `Outstanding` under their transaction IDs, receives whatever arrives with
+//! `DnsSocket::recv_any`, and gets the request which a response belongs to
+//! from `Outstanding::take`:
+//!
+//! ```ignore
+//! let mut outstanding = Outstanding::new();
+//! for (name, server) in lookups {
+//!     let mut query = Message::new();
+//!     query.header.recursion_desired = true;
+//!     query.question.push(Question::new(name.clone(), RecordType::A, Class::Internet));
+//!     outstanding.insert(&mut query, server, name).unwrap();
+//!     sock.send_message(&query, &server)?;
+//! }
+//! while !outstanding.is_empty() {
+//!     let (msg, from) = sock.recv_any(&mut buf)?;
+//!     if let Some(name) = outstanding.take(&msg, &from) {
+//!         // the response to the query for name
+//!     }
+//! }
+//! ```
+//!
+//! Unlike `DnsSocket::recv_message` and `DnsResolver`, this leaves the
+//! defenses against spoofed responses to the caller: `take` only checks the
+//! ID and the sender, so the caller should use random IDs (which `insert`
+//! keeps), a random source port, compare the question of the response with
+//! the one of its query, and drop the queries which timed out with `remove`.
+
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+
+use super::address::socket_address_equal;
+use super::message::{Message, Qr};
+use super::rng::{OsRandom, RandSource};
+
+/// Requests of the queries on a socket which wait for their responses, by the
+/// transaction IDs of the queries
+#[derive(Debug)]
+pub struct Outstanding<T> {
+    queries: BTreeMap<u16, (SocketAddr, T)>,
+}
+
+impl<T> Default for Outstanding<T> {
+    fn default() -> Outstanding<T> {
+        Outstanding {
+            queries: BTreeMap::new(),
+        }
+    }
+}
+
+impl<T> Outstanding<T> {
+    /// Returns an empty set of outstanding queries.
+    pub fn new() -> Outstanding<T> {
+        Outstanding::default()
+    }
+
+    /// Keeps the request of a query to the server until its response arrives
+    /// and returns the ID of the query. The random ID of the query stays
+    /// unless another outstanding query has it, then a new random one is set.
+    ///
+    /// Returns `None` if all 65536 IDs are in use.
+    pub fn insert(&mut self, query: &mut Message, server: SocketAddr, request: T) -> Option<u16> {
+        if self.queries.len() > u16::max_value() as usize {
+            return None;
+        }
+        let mut id = query.id();
+        while self.queries.contains_key(&id) {
+            id = OsRandom.id();
+        }
+        query.header.id = id;
+        self.queries.insert(id, (server, request));
+        Some(id)
+    }
+
+    /// Returns the request which a response answers and forgets its query,
+    /// if it has the ID of an outstanding query and comes from the server
+    /// the query was sent to.
+    ///
+    /// Other messages return `None`, e.g., late responses to queries which
+    /// were removed after their timeout, or spoofed ones.
+    pub fn take(&mut self, response: &Message, from: &SocketAddr) -> Option<T> {
+        if response.header.qr != Qr::Response {
+            return None;
+        }
+        match self.queries.get(&response.id()) {
+            Some(&(ref server, _)) if socket_address_equal(server, from) => {}
+            _ => return None,
+        }
+        self.queries
+            .remove(&response.id())
+            .map(|(_, request)| request)
+    }
+
+    /// Forgets the query with the ID, e.g., after its timeout, and returns its
+    /// request.
+    pub fn remove(&mut self, id: u16) -> Option<T> {
+        self.queries.remove(&id).map(|(_, request)| request)
+    }
+
+    /// Returns the server and request of the query with the ID.
+    pub fn get(&self, id: u16) -> Option<(&SocketAddr, &T)> {
+        self.queries
+            .get(&id)
+            .map(|&(ref server, ref request)| (server, request))
+    }
+
+    /// Returns the number of outstanding queries.
+    pub fn len(&self) -> usize {
+        self.queries.len()
+    }
+
+    /// Returns whether no query is outstanding.
+    pub fn is_empty(&self) -> bool {
+        self.queries.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Outstanding;
+    use crate::resolve::message::{Message, Qr};
+    use crate::resolve::socket::DnsSocket;
+    use std::net::{SocketAddr, UdpSocket};
+    use std::time::Duration;
+
+    #[test]
+    fn test_outstanding() {
+        let server: SocketAddr = "192.0.2.1:53".parse().unwrap();
+        let other: SocketAddr = "192.0.2.2:53".parse().unwrap();
+        let mut outstanding = Outstanding::new();
+        let mut first = Message::with_id(7);
+        let mut second = Message::with_id(7);
+        assert_eq!(outstanding.insert(&mut first, server, "first"), Some(7));
+        let id = outstanding.insert(&mut second, server, "second").unwrap();
+        assert_ne!(id, 7);
+        assert_eq!(second.id(), id);
+        assert_eq!(outstanding.len(), 2);
+
+        // a query, and a response from another server
+        assert_eq!(outstanding.take(&first, &server), None);
+        let mut response = first.clone();
+        response.header.qr = Qr::Response;
+        assert_eq!(outstanding.take(&response, &other), None);
+        assert_eq!(outstanding.take(&response, &server), Some("first"));
+        assert_eq!(outstanding.take(&response, &server), None);
+        assert_eq!(outstanding.remove(id), Some("second"));
+        assert!(outstanding.is_empty());
+    }
+
+    #[test]
+    fn test_recv_any() {
+        let a = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let b = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let sock = DnsSocket::from_socket(UdpSocket::bind("127.0.0.1:0").unwrap());
+        sock.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let addr = sock.get().local_addr().unwrap();
+        let mut buf = [0; 512];
+        for (peer, id) in [(&a, 1), (&b, 2)].iter() {
+            let mut msg = Message::with_id(*id);
+            msg.header.qr = Qr::Response;
+            peer.send_to(msg.encode(&mut buf).unwrap(), addr).unwrap();
+        }
+        // both arrive, whichever address they come from
+        let (msg, from) = sock.recv_any(&mut buf).unwrap();
+        assert_eq!((msg.id(), from), (1, a.local_addr().unwrap()));
+        let (msg, from) = sock.recv_any(&mut buf).unwrap();
+        assert_eq!((msg.id(), from), (2, b.local_addr().unwrap()));
+    }
+}
@@ -0,0 +1,1900 @@
+//! Validation of DNSSEC signatures, as defined by RFC 4033, RFC 4034, RFC 4035,
+//! and RFC 5155.
+//!
+//! Starting from a trust anchor, the keys of each zone are authenticated by the
+//! DS records of the parent zone. An answer is secure if every RRset carries a
+//! valid signature of its zone and the nonexistence of names and types is proven
+//! by NSEC or NSEC3 records. It is insecure if it belongs to a zone below an
+//! unsigned delegation, and bogus if a signature or proof is missing or invalid.
+//!
+//! The NSEC and NSEC3 records of validated denials are kept for their TTL, and
+//! a name in one of their spans is known not to exist without a query (RFC 8198).
+
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+use ring::digest;
+use ring::signature::{self, RsaPublicKeyComponents, UnparsedPublicKey};
+
+use super::idna;
+use super::message::{
+    DecodeError, DnsError, ExtendedError, Message, MsgReader, MsgWriter, RCode, Resource,
+    MESSAGE_LIMIT,
+};
+use super::name::{canonical_cmp, is_subdomain};
+use super::record::{CName, Class, Dnskey, Ds, Nsec, Nsec3, Record, RecordType, Rrsig};
+use super::socket::Error;
+
+/// Cached zone keys and insecure zones expire after at most this many seconds
+const MAX_CACHE_TTL: u32 = 3600;
+/// NSEC3 records with more iterations are treated as insecure, see RFC 9276
+const MAX_NSEC3_ITERATIONS: u16 = 150;
+/// Longest CNAME chain followed in an answer
+const MAX_CNAME_CHAIN: usize = 16;
+/// Most NSEC or NSEC3 records kept for each zone, the oldest are forgotten first
+const MAX_CACHED_SPANS: usize = 256;
+
+/// The key signing keys of the root zone, as published by IANA
+const ROOT_DS: &[(u16, &str)] = &[
+    (
+        20326,
+        "e06d44b80b8f1d39a95c0b0d7c65d08458e880409bbc683457104237c7f8ec8d",
+    ),
+    (
+        38696,
+        "683d2d0acb8c9b712a1948b27f741219298d0a450d612c483af444a4c0fb2b16",
+    ),
+];
+
+/// Keys of a zone which are trusted without validation, usually those of the root zone
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TrustAnchor {
+    /// Zone of the keys
+    pub zone: String,
+    /// Digests of trusted keys
+    pub ds: Vec<Ds>,
+    /// Trusted keys
+    pub dnskeys: Vec<Dnskey>,
+}
+
+impl TrustAnchor {
+    /// Returns the current key signing keys of the root zone.
+    pub fn root() -> TrustAnchor {
+        TrustAnchor {
+            zone: ".".to_string(),
+            ds: ROOT_DS
+                .iter()
+                .map(|&(key_tag, digest)| Ds {
+                    key_tag: key_tag,
+                    algorithm: 8,
+                    digest_type: 2,
+                    digest: (0..digest.len())
+                        .step_by(2)
+                        .map(|i| u8::from_str_radix(&digest[i..i + 2], 16).unwrap())
+                        .collect(),
+                })
+                .collect(),
+            dnskeys: Vec::new(),
+        }
+    }
+}
+
+/// Result of a successful validation
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Security {
+    /// Every record is signed by a key authenticated from the trust anchor
+    Secure,
+    /// The records belong to a zone which is proven to be unsigned, or which is
+    /// not below the trust anchor
+    Insecure,
+}
+
+impl Security {
+    fn and(self, other: Security) -> Security {
+        if self == Security::Secure {
+            other
+        } else {
+            Security::Insecure
+        }
+    }
+}
+
+/// The answer and authority sections of a response with the names and record
+/// data in canonical form
+pub(crate) struct Response {
+    rcode: RCode,
+    answer: Vec<Rr>,
+    authority: Vec<Rr>,
+}
+
+impl Response {
+    /// Converts the records of the response, other classes than `IN` are ignored
+    pub(crate) fn new(msg: &Message) -> Result<Response, DecodeError> {
+        let convert = |records: &[Resource]| -> Result<Vec<Rr>, DecodeError> {
+            records
+                .iter()
+                .filter(|res| res.r_class == Class::Internet)
+                .map(Rr::new)
+                .collect()
+        };
+        Ok(Response {
+            rcode: msg.header.rcode,
+            answer: convert(&msg.answer)?,
+            authority: convert(&msg.authority)?,
+        })
+    }
+
+    fn min_ttl(&self) -> u32 {
+        self.answer
+            .iter()
+            .chain(self.authority.iter())
+            .map(|rr| rr.ttl)
+            .min()
+            .unwrap_or(0)
+    }
+}
+
+/// A resource record with a lowercase owner name in A-label form and the record
+/// data in the canonical form of RFC 4034 section 6.2
+#[derive(Clone, Debug)]
+struct Rr {
+    owner: String,
+    r_type: RecordType,
+    ttl: u32,
+    rdata: Vec<u8>,
+}
+
+impl Rr {
+    fn new(res: &Resource) -> Result<Rr, DecodeError> {
+        Ok(Rr {
+            owner: canonical_name(&res.name)?,
+            r_type: res.r_type,
+            ttl: res.ttl,
+            rdata: canonical_rdata(res)?,
+        })
+    }
+
+    fn decode<R: Record>(&self) -> Option<R> {
+        let mut r = MsgReader::new(&self.rdata);
+        match R::decode(&mut r) {
+            Ok(rec) if r.remaining() == 0 => Some(rec),
+            _ => None,
+        }
+    }
+}
+
+/// A set of records with the same owner and type, and the signatures over it
+struct RrSet<'a> {
+    owner: &'a str,
+    r_type: RecordType,
+    records: Vec<&'a Rr>,
+    signatures: Vec<Rrsig>,
+}
+
+/// Groups the records of a section by owner and type, in the order of appearance
+fn rrsets(records: &[Rr]) -> Vec<RrSet> {
+    let mut sets: Vec<RrSet> = Vec::new();
+    for rr in records.iter().filter(|rr| rr.r_type != RecordType::Rrsig) {
+        match sets
+            .iter_mut()
+            .find(|set| set.owner == rr.owner && set.r_type == rr.r_type)
+        {
+            Some(set) => set.records.push(rr),
+            None => sets.push(RrSet {
+                owner: &rr.owner,
+                r_type: rr.r_type,
+                records: vec![rr],
+                signatures: Vec::new(),
+            }),
+        }
+    }
+    for rr in records.iter().filter(|rr| rr.r_type == RecordType::Rrsig) {
+        if let Some(sig) = rr.decode::<Rrsig>() {
+            if let Some(set) = sets
+                .iter_mut()
+                .find(|set| set.owner == rr.owner && set.r_type == sig.type_covered)
+            {
+                set.signatures.push(sig);
+            }
+        }
+    }
+    sets
+}
+
+#[derive(Clone)]
+enum Trust {
+    Secure(Vec<Dnskey>),
+    Insecure,
+}
+
+/// Validates responses against a trust anchor and caches the keys of the zones
+pub(crate) struct Validator {
+    anchor: TrustAnchor,
+    /// Authenticated keys or proven insecurity of zones, with their expiry
+    zones: Mutex<BTreeMap<String, (Trust, u64)>>,
+    /// Authenticated NSEC and NSEC3 records of secure denials by zone
+    spans: Mutex<BTreeMap<String, Spans>>,
+}
+
+/// NSEC and NSEC3 records of a zone with their expiry
+#[derive(Default)]
+struct Spans {
+    nsec: Vec<(NsecProof, u64)>,
+    nsec3: Vec<(Nsec3Proof, u64)>,
+}
+
+impl Validator {
+    pub(crate) fn new(anchor: TrustAnchor) -> Validator {
+        let zone = canonical_name(&anchor.zone).unwrap_or_else(|_| ".".to_string());
+        Validator {
+            anchor: TrustAnchor {
+                zone: zone,
+                ..anchor
+            },
+            zones: Mutex::new(BTreeMap::new()),
+            spans: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Returns whether the NSEC or NSEC3 records of earlier secure denials prove
+    /// that the name does not exist, so that NXDOMAIN can be answered without a
+    /// query (RFC 8198). Opt-out spans and the spans below delegations prove
+    /// nothing. `now` is the time in seconds since the epoch.
+    pub(crate) fn cached_nxdomain(&self, name: &str, now: u64) -> bool {
+        let name = match canonical_name(name) {
+            Ok(name) => name,
+            Err(_) => return false,
+        };
+        let mut spans = self.spans.lock().unwrap();
+        spans.iter_mut().any(|(zone, spans)| {
+            if !is_subdomain(&name, zone) {
+                return false;
+            }
+            spans.nsec.retain(|&(_, expiry)| now < expiry);
+            spans.nsec3.retain(|&(_, expiry)| now < expiry);
+            let proof = Proof {
+                zone: zone.clone(),
+                nsec: spans.nsec.iter().map(|(n, _)| n.clone()).collect(),
+                nsec3: spans.nsec3.iter().map(|(n, _)| n.clone()).collect(),
+            };
+            !proof.delegates(&name) && proof.nxdomain(&name) == Some(Security::Secure)
+        })
+    }
+
+    /// Keeps the records of a secure denial until their TTL expires.
+    fn remember(&self, proof: Proof, ttl: u32, now: u64) {
+        let expiry = now + u64::from(ttl.min(MAX_CACHE_TTL));
+        let mut spans = self.spans.lock().unwrap();
+        let spans = spans.entry(proof.zone).or_insert_with(Spans::default);
+        for nsec in proof.nsec {
+            spans.nsec.retain(|&(ref n, _)| n.owner != nsec.owner);
+            spans.nsec.push((nsec, expiry));
+        }
+        for nsec3 in proof.nsec3 {
+            spans.nsec3.retain(|&(ref n, _)| n.hash != nsec3.hash);
+            spans.nsec3.push((nsec3, expiry));
+        }
+        if spans.nsec.len() > MAX_CACHED_SPANS {
+            let excess = spans.nsec.len() - MAX_CACHED_SPANS;
+            spans.nsec.drain(..excess);
+        }
+        if spans.nsec3.len() > MAX_CACHED_SPANS {
+            let excess = spans.nsec3.len() - MAX_CACHED_SPANS;
+            spans.nsec3.drain(..excess);
+        }
+    }
+
+    /// Validates a response to the question, fetching the records to build the chain of
+    /// trust with `fetch`; `now` is the time in seconds since the epoch
+    ///
+    /// Failed validations are returned as `Error::DnsError` with the response code
+    /// `ServerFailure` and an extended error.
+    pub(crate) fn validate<F>(
+        &self,
+        name: &str,
+        q_type: RecordType,
+        response: &Response,
+        fetch: &F,
+        now: u64,
+    ) -> Result<Security, Error>
+    where
+        F: Fn(&str, RecordType) -> Result<Response, Error>,
+    {
+        let name = canonical_name(name).map_err(|_| bogus(ExtendedError::DNSSEC_BOGUS))?;
+        let mut security = Security::Secure;
+        let mut expanded = Vec::new();
+
+        for set in rrsets(&response.answer) {
+            let (sec, wildcard) = self.verify_section_rrset(&set, None, fetch, now)?;
+            security = security.and(sec);
+            if let Some(closest_encloser) = wildcard {
+                expanded.push((set.owner.to_string(), closest_encloser));
+            }
+        }
+
+        // The name the answer ends at after following the CNAME chain
+        let mut target = name;
+        for _ in 0..MAX_CNAME_CHAIN {
+            if q_type == RecordType::CName {
+                break;
+            }
+            let next = response
+                .answer
+                .iter()
+                .find(|rr| rr.owner == target && rr.r_type == RecordType::CName)
+                .and_then(|rr| rr.decode::<CName>())
+                .and_then(|cname| canonical_name(&cname.name).ok());
+            match next {
+                Some(next) => target = next,
+                None => break,
+            }
+        }
+        let answered = response
+            .answer
+            .iter()
+            .any(|rr| rr.owner == target && rr.r_type == q_type);
+
+        if !answered || response.rcode == RCode::NameError || !expanded.is_empty() {
+            let nxdomain = response.rcode == RCode::NameError;
+            let sec = if answered && !nxdomain {
+                self.prove_expansions(&expanded, response, fetch, now)?
+            } else {
+                self.denial(&target, q_type, nxdomain, response, fetch, now)?
+            };
+            security = security.and(sec);
+        }
+        Ok(security)
+    }
+
+    /// Verifies an RRset with the keys of its signer, which must be the zone `zone` if given
+    ///
+    /// Returns the closest encloser if the RRset was expanded from a wildcard.
+    fn verify_section_rrset<F>(
+        &self,
+        set: &RrSet,
+        zone: Option<&str>,
+        fetch: &F,
+        now: u64,
+    ) -> Result<(Security, Option<String>), Error>
+    where
+        F: Fn(&str, RecordType) -> Result<Response, Error>,
+    {
+        let signer = match set.signatures.first() {
+            Some(sig) => {
+                canonical_name(&sig.signer_name).map_err(|_| bogus(ExtendedError::DNSSEC_BOGUS))?
+            }
+            None => {
+                let zone = match zone {
+                    Some(zone) => zone.to_string(),
+                    None => self.zone_of(set.owner, fetch)?,
+                };
+                return self
+                    .unsigned(&zone, ExtendedError::RRSIGS_MISSING, fetch, now)
+                    .map(|sec| (sec, None));
+            }
+        };
+        if !is_subdomain(set.owner, &signer) || zone.map_or(false, |zone| zone != signer) {
+            return Err(bogus(ExtendedError::DNSSEC_BOGUS));
+        }
+        match self.zone_keys(&signer, fetch, now)? {
+            Trust::Insecure => Ok((Security::Insecure, None)),
+            Trust::Secure(keys) => {
+                let labels = verify_rrset(set, &signer, &keys, now)?;
+                // The labels of a wildcard owner do not count its asterisk
+                let owner_labels = if set.owner.starts_with("*.") {
+                    count_labels(set.owner) - 1
+                } else {
+                    count_labels(set.owner)
+                };
+                if labels < owner_labels {
+                    Ok((Security::Secure, Some(ancestor(set.owner, labels))))
+                } else {
+                    Ok((Security::Secure, None))
+                }
+            }
+        }
+    }
+
+    /// Checks that the names of answers expanded from wildcards do not exist
+    fn prove_expansions<F>(
+        &self,
+        expanded: &[(String, String)],
+        response: &Response,
+        fetch: &F,
+        now: u64,
+    ) -> Result<Security, Error>
+    where
+        F: Fn(&str, RecordType) -> Result<Response, Error>,
+    {
+        let (security, proof) = match self.authenticated_proof(None, None, response, fetch, now)? {
+            Some(proof) => proof,
+            None => return Ok(Security::Insecure),
+        };
+        for &(ref name, ref closest_encloser) in expanded {
+            let next_closer = ancestor(name, count_labels(closest_encloser) + 1);
+            let covered = proof.nsec.iter().any(|n| n.covers(name, &proof.zone))
+                || proof.nsec3.iter().any(|n| n.covers(&next_closer));
+            if !covered {
+                return Err(bogus(ExtendedError::NSEC_MISSING));
+            }
+        }
+        Ok(security)
+    }
+
+    /// Validates a response without an answer of the type at the name, the authority
+    /// section must prove that the name or the type does not exist
+    fn denial<F>(
+        &self,
+        name: &str,
+        q_type: RecordType,
+        nxdomain: bool,
+        response: &Response,
+        fetch: &F,
+        now: u64,
+    ) -> Result<Security, Error>
+    where
+        F: Fn(&str, RecordType) -> Result<Response, Error>,
+    {
+        // The parent zone answers for the DS records of a delegation
+        let within = if q_type == RecordType::Ds {
+            match parent(name) {
+                Some(parent) => parent,
+                None => return Err(bogus(ExtendedError::DNSSEC_BOGUS)),
+            }
+        } else {
+            name.to_string()
+        };
+        let (security, proof) =
+            match self.authenticated_proof(Some(&within), Some(name), response, fetch, now)? {
+                Some(proof) => proof,
+                None => return Ok(Security::Insecure),
+            };
+        let proven = if nxdomain {
+            proof.nxdomain(name)
+        } else {
+            proof.nodata(name, q_type)
+        };
+        match proven {
+            Some(sec) => {
+                let security = security.and(sec);
+                if security == Security::Secure {
+                    self.remember(proof, response.min_ttl(), now);
+                }
+                Ok(security)
+            }
+            None => Err(bogus(ExtendedError::NSEC_MISSING)),
+        }
+    }
+
+    /// Verifies the RRsets of the authority section and collects its NSEC and NSEC3
+    /// records, returns None if the zone of the response is insecure
+    ///
+    /// The signer of the records must be an ancestor of `within` if given. Records of
+    /// an unsigned authority section are checked to belong to an insecure zone, which
+    /// is looked up for `name`.
+    fn authenticated_proof<F>(
+        &self,
+        within: Option<&str>,
+        name: Option<&str>,
+        response: &Response,
+        fetch: &F,
+        now: u64,
+    ) -> Result<Option<(Security, Proof)>, Error>
+    where
+        F: Fn(&str, RecordType) -> Result<Response, Error>,
+    {
+        let sets = rrsets(&response.authority);
+        let signer = sets
+            .iter()
+            .filter(|set| set.r_type != RecordType::Ns)
+            .filter_map(|set| set.signatures.first())
+            .next()
+            .map(|sig| canonical_name(&sig.signer_name))
+            .transpose()
+            .map_err(|_| bogus(ExtendedError::DNSSEC_BOGUS))?;
+        let zone = match signer {
+            Some(zone) => zone,
+            None => {
+                // Without signatures the SOA record names the zone
+                let soa = sets.iter().find(|set| {
+                    set.r_type == RecordType::Soa
+                        && within.map_or(true, |within| is_subdomain(within, set.owner))
+                });
+                let zone = match (soa, within.or(name)) {
+                    (Some(soa), _) => soa.owner.to_string(),
+                    (None, Some(name)) => self.zone_of(name, fetch)?,
+                    (None, None) => return Err(bogus(ExtendedError::NSEC_MISSING)),
+                };
+                if within.map_or(false, |within| !is_subdomain(within, &zone)) {
+                    return Err(bogus(ExtendedError::DNSSEC_BOGUS));
+                }
+                self.unsigned(&zone, ExtendedError::NSEC_MISSING, fetch, now)?;
+                return Ok(None);
+            }
+        };
+        if within.map_or(false, |within| !is_subdomain(within, &zone)) {
+            return Err(bogus(ExtendedError::DNSSEC_BOGUS));
+        }
+
+        let mut security = Security::Secure;
+        let mut proof = Proof {
+            zone: zone.clone(),
+            nsec: Vec::new(),
+            nsec3: Vec::new(),
+        };
+        for set in sets.iter() {
+            // Referrals are not signed by the parent zone
+            if set.r_type == RecordType::Ns && set.signatures.is_empty() {
+                continue;
+            }
+            let (sec, wildcard) = self.verify_section_rrset(set, Some(&zone), fetch, now)?;
+            if sec == Security::Insecure {
+                return Ok(None);
+            }
+            match set.r_type {
+                // NSEC records expanded from a wildcard prove nothing
+                RecordType::Nsec if wildcard.is_none() => {
+                    for rr in set.records.iter() {
+                        if let Some(nsec) = rr.decode::<Nsec>() {
+                            let next = canonical_name(&nsec.next_domain)
+                                .map_err(|_| bogus(ExtendedError::DNSSEC_BOGUS))?;
+                            proof.nsec.push(NsecProof {
+                                owner: set.owner.to_string(),
+                                next: next,
+                                nsec: nsec,
+                            });
+                        }
+                    }
+                }
+                RecordType::Nsec3 if wildcard.is_none() => {
+                    for rr in set.records.iter() {
+                        let nsec3 = match rr.decode::<Nsec3>() {
+                            Some(nsec3) => nsec3,
+                            None => continue,
+                        };
+                        // Unknown hash algorithms and expensive iterations are ignored
+                        if nsec3.hash_algorithm != 1 || nsec3.iterations > MAX_NSEC3_ITERATIONS {
+                            security = Security::Insecure;
+                            continue;
+                        }
+                        let first = set.owner.split('.').next().unwrap_or("");
+                        let hash = match base32hex_decode(first) {
+                            Some(hash) => hash,
+                            None => continue,
+                        };
+                        let labels = count_labels(set.owner);
+                        if labels > 0 && ancestor(set.owner, labels - 1) == zone {
+                            proof.nsec3.push(Nsec3Proof {
+                                zone: zone.clone(),
+                                hash: hash,
+                                nsec3: nsec3,
+                            });
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        if security == Security::Insecure && proof.nsec.is_empty() && proof.nsec3.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some((security, proof)))
+    }
+
+    /// Returns an error if the zone is expected to be signed
+    fn unsigned<F>(
+        &self,
+        zone: &str,
+        missing: ExtendedError,
+        fetch: &F,
+        now: u64,
+    ) -> Result<Security, Error>
+    where
+        F: Fn(&str, RecordType) -> Result<Response, Error>,
+    {
+        match self.zone_keys(zone, fetch, now)? {
+            Trust::Secure(_) => Err(bogus(missing)),
+            Trust::Insecure => Ok(Security::Insecure),
+        }
+    }
+
+    /// Looks up the zone of a name by the owner of its SOA record
+    fn zone_of<F>(&self, name: &str, fetch: &F) -> Result<String, Error>
+    where
+        F: Fn(&str, RecordType) -> Result<Response, Error>,
+    {
+        let response = fetch(name, RecordType::Soa)?;
+        response
+            .answer
+            .iter()
+            .chain(response.authority.iter())
+            .find(|rr| rr.r_type == RecordType::Soa && is_subdomain(name, &rr.owner))
+            .map(|rr| rr.owner.clone())
+            .ok_or_else(|| bogus(ExtendedError::DNSSEC_BOGUS))
+    }
+
+    /// Returns the authenticated keys of the zone, which are validated by the DS records
+    /// of the parent zone or the trust anchor
+    fn zone_keys<F>(&self, zone: &str, fetch: &F, now: u64) -> Result<Trust, Error>
+    where
+        F: Fn(&str, RecordType) -> Result<Response, Error>,
+    {
+        if let Some(&(ref trust, expiry)) = self.zones.lock().unwrap().get(zone) {
+            if now < expiry {
+                return Ok(trust.clone());
+            }
+        }
+        if !is_subdomain(zone, &self.anchor.zone) {
+            return Ok(Trust::Insecure);
+        }
+
+        let (ds, ds_ttl) = if zone == self.anchor.zone {
+            (self.anchor.ds.clone(), MAX_CACHE_TTL)
+        } else {
+            let response = fetch(zone, RecordType::Ds)?;
+            let answer = rrsets(&response.answer);
+            match answer
+                .iter()
+                .find(|set| set.owner == zone && set.r_type == RecordType::Ds)
+            {
+                Some(set) => {
+                    let parent = canonical_name(
+                        &set.signatures
+                            .first()
+                            .ok_or_else(|| bogus(ExtendedError::RRSIGS_MISSING))?
+                            .signer_name,
+                    )
+                    .map_err(|_| bogus(ExtendedError::DNSSEC_BOGUS))?;
+                    if parent == zone {
+                        return Err(bogus(ExtendedError::DNSSEC_BOGUS));
+                    }
+                    match self.verify_section_rrset(set, Some(&parent), fetch, now)?.0 {
+                        Security::Secure => {}
+                        Security::Insecure => {
+                            self.cache(zone, Trust::Insecure, response.min_ttl(), now);
+                            return Ok(Trust::Insecure);
+                        }
+                    }
+                    let ds = set.records.iter().filter_map(|rr| rr.decode()).collect();
+                    (ds, response.min_ttl())
+                }
+                None => {
+                    // A proven absence of DS records is an unsigned delegation
+                    self.denial(zone, RecordType::Ds, false, &response, fetch, now)?;
+                    self.cache(zone, Trust::Insecure, response.min_ttl(), now);
+                    return Ok(Trust::Insecure);
+                }
+            }
+        };
+
+        let ds: Vec<Ds> = ds
+            .into_iter()
+            .filter(|ds| supported_algorithm(ds.algorithm) && supported_digest(ds.digest_type))
+            .collect();
+        if ds.is_empty() && (zone != self.anchor.zone || self.anchor.dnskeys.is_empty()) {
+            self.cache(zone, Trust::Insecure, ds_ttl, now);
+            return Ok(Trust::Insecure);
+        }
+
+        let response = fetch(zone, RecordType::Dnskey)?;
+        let answer = rrsets(&response.answer);
+        let set = answer
+            .iter()
+            .find(|set| set.owner == zone && set.r_type == RecordType::Dnskey)
+            .ok_or_else(|| bogus(ExtendedError::DNSKEY_MISSING))?;
+        let keys: Vec<Dnskey> = set.records.iter().filter_map(|rr| rr.decode()).collect();
+        let entry_points: Vec<Dnskey> = keys
+            .iter()
+            .filter(|key| {
+                key.zone_key()
+                    && ((zone == self.anchor.zone && self.anchor.dnskeys.contains(key))
+                        || ds.iter().any(|ds| ds_matches(ds, zone, key)))
+            })
+            .cloned()
+            .collect();
+        if entry_points.is_empty() {
+            return Err(bogus(ExtendedError::DNSKEY_MISSING));
+        }
+        verify_rrset(set, zone, &entry_points, now)?;
+
+        let zone_keys: Vec<Dnskey> = keys.into_iter().filter(|key| key.zone_key()).collect();
+        if zone_keys.is_empty() {
+            return Err(bogus(ExtendedError::NO_ZONE_KEY_BIT_SET));
+        }
+        let trust = Trust::Secure(zone_keys);
+        let ttl = set.records.iter().map(|rr| rr.ttl).min().unwrap_or(0);
+        self.cache(zone, trust.clone(), ttl.min(ds_ttl), now);
+        Ok(trust)
+    }
+
+    fn cache(&self, zone: &str, trust: Trust, ttl: u32, now: u64) {
+        let expiry = now + ttl.min(MAX_CACHE_TTL) as u64;
+        self.zones
+            .lock()
+            .unwrap()
+            .insert(zone.to_string(), (trust, expiry));
+    }
+}
+
+/// Authenticated NSEC and NSEC3 records of a zone
+struct Proof {
+    zone: String,
+    nsec: Vec<NsecProof>,
+    nsec3: Vec<Nsec3Proof>,
+}
+
+#[derive(Clone)]
+struct NsecProof {
+    owner: String,
+    next: String,
+    nsec: Nsec,
+}
+
+#[derive(Clone)]
+struct Nsec3Proof {
+    zone: String,
+    hash: Vec<u8>,
+    nsec3: Nsec3,
+}
+
+impl NsecProof {
+    /// Whether the name lies strictly between the owner and the next name, the last
+    /// NSEC of a zone points back to the apex
+    fn covers(&self, name: &str, zone: &str) -> bool {
+        canonical_cmp(&self.owner, name) == Ordering::Less
+            && (canonical_cmp(name, &self.next) == Ordering::Less
+                || (self.next == zone && is_subdomain(name, zone)))
+    }
+
+    /// Longest ancestor of the name which exists according to the NSEC record
+    fn closest_encloser(&self, name: &str) -> String {
+        let a = common_labels(name, &self.owner);
+        let b = common_labels(name, &self.next);
+        ancestor(name, a.max(b))
+    }
+}
+
+impl Nsec3Proof {
+    fn hash(&self, name: &str) -> Vec<u8> {
+        nsec3_hash(name, &self.nsec3.salt, self.nsec3.iterations)
+    }
+
+    fn matches(&self, name: &str) -> bool {
+        is_subdomain(name, &self.zone) && self.hash(name) == self.hash
+    }
+
+    fn covers(&self, name: &str) -> bool {
+        if !is_subdomain(name, &self.zone) {
+            return false;
+        }
+        let hash = self.hash(name);
+        let next = &self.nsec3.next_hashed_owner;
+        if self.hash < *next {
+            self.hash < hash && hash < *next
+        } else {
+            // The last NSEC3 of the zone wraps around
+            self.hash < hash || hash < *next
+        }
+    }
+}
+
+impl Proof {
+    /// Proves that the name does not exist
+    fn nxdomain(&self, name: &str) -> Option<Security> {
+        if let Some(covering) = self.nsec.iter().find(|n| n.covers(name, &self.zone)) {
+            let wildcard = format!("*.{}", covering.closest_encloser(name)).replace("*..", "*.");
+            if self.nsec.iter().any(|n| n.covers(&wildcard, &self.zone)) {
+                return Some(Security::Secure);
+            }
+        }
+        let (closest_encloser, opt_out) = self.closest_encloser_proof(name)?;
+        let wildcard = format!("*.{}", closest_encloser).replace("*..", "*.");
+        if self.nsec3.iter().any(|n| n.covers(&wildcard)) {
+            Some(if opt_out {
+                Security::Insecure
+            } else {
+                Security::Secure
+            })
+        } else {
+            None
+        }
+    }
+
+    /// Proves that the name has no records of the type
+    fn nodata(&self, name: &str, r_type: RecordType) -> Option<Security> {
+        let lacks = |types: &[RecordType]| {
+            !types.contains(&r_type)
+                && !types.contains(&RecordType::CName)
+                // The NSEC of the child zone cannot deny a DS record
+                && !(r_type == RecordType::Ds && types.contains(&RecordType::Soa))
+        };
+        for n in self.nsec.iter() {
+            if n.owner == name && lacks(&n.nsec.types) {
+                return Some(Security::Secure);
+            }
+            // An empty non-terminal precedes its descendants
+            if canonical_cmp(&n.owner, name) == Ordering::Less
+                && is_subdomain(&n.next, name)
+                && n.next != name
+            {
+                return Some(Security::Secure);
+            }
+        }
+        if let Some(covering) = self.nsec.iter().find(|n| n.covers(name, &self.zone)) {
+            let wildcard = format!("*.{}", covering.closest_encloser(name)).replace("*..", "*.");
+            if self
+                .nsec
+                .iter()
+                .any(|n| n.owner == wildcard && lacks(&n.nsec.types))
+            {
+                return Some(Security::Secure);
+            }
+        }
+
+        if self
+            .nsec3
+            .iter()
+            .any(|n| n.matches(name) && lacks(&n.nsec3.types))
+        {
+            return Some(Security::Secure);
+        }
+        let (closest_encloser, opt_out) = self.closest_encloser_proof(name)?;
+        // Opt-out spans may contain unsigned delegations
+        if opt_out && r_type == RecordType::Ds {
+            return Some(Security::Insecure);
+        }
+        let wildcard = format!("*.{}", closest_encloser).replace("*..", "*.");
+        if self
+            .nsec3
+            .iter()
+            .any(|n| n.matches(&wildcard) && lacks(&n.nsec3.types))
+        {
+            return Some(Security::Secure);
+        }
+        None
+    }
+
+    /// Whether an ancestor of the name below the zone is a delegation or a DNAME,
+    /// whose records belong to another zone or are redirected
+    fn delegates(&self, name: &str) -> bool {
+        let cut = |types: &[RecordType]| {
+            types.contains(&RecordType::Dname)
+                || (types.contains(&RecordType::Ns) && !types.contains(&RecordType::Soa))
+        };
+        let zone_labels = count_labels(&self.zone);
+        (zone_labels + 1..count_labels(name)).any(|n| {
+            let owner = ancestor(name, n);
+            self.nsec
+                .iter()
+                .any(|p| p.owner == owner && cut(&p.nsec.types))
+                || self
+                    .nsec3
+                    .iter()
+                    .any(|p| p.matches(&owner) && cut(&p.nsec3.types))
+        })
+    }
+
+    /// Finds the longest existing ancestor of the name for which the next closer name
+    /// is covered, returns it with the opt-out flag of the covering NSEC3
+    fn closest_encloser_proof(&self, name: &str) -> Option<(String, bool)> {
+        let labels = count_labels(name);
+        let zone_labels = count_labels(&self.zone);
+        (zone_labels..labels).rev().find_map(|n| {
+            let closest_encloser = ancestor(name, n);
+            if !self.nsec3.iter().any(|p| p.matches(&closest_encloser)) {
+                return None;
+            }
+            let next_closer = ancestor(name, n + 1);
+            self.nsec3
+                .iter()
+                .find(|p| p.covers(&next_closer))
+                .map(|p| (closest_encloser, p.nsec3.opt_out()))
+        })
+    }
+}
+
+/// Verifies the signatures over an RRset with the keys of the signer, returns the
+/// labels field of the valid signature
+///
+/// If no signature is valid, the error describes why the most promising one failed.
+fn verify_rrset(set: &RrSet, signer: &str, keys: &[Dnskey], now: u64) -> Result<usize, Error> {
+    let now = now as u32;
+    let mut error = ExtendedError::DNSKEY_MISSING;
+    for sig in set.signatures.iter() {
+        if canonical_name(&sig.signer_name)
+            .ok()
+            .as_ref()
+            .map(|s| &s[..])
+            != Some(signer)
+            || sig.labels as usize > count_labels(set.owner)
+        {
+            error = ExtendedError::DNSSEC_BOGUS;
+            continue;
+        }
+        if !serial_le(sig.inception, now) {
+            error = ExtendedError::SIGNATURE_NOT_YET_VALID;
+            continue;
+        }
+        if !serial_le(now, sig.expiration) {
+            error = ExtendedError::SIGNATURE_EXPIRED;
+            continue;
+        }
+        let data = match signed_data(set, sig) {
+            Some(data) => data,
+            None => continue,
+        };
+        let candidates = keys
+            .iter()
+            .filter(|key| key.algorithm == sig.algorithm && key.key_tag() == sig.key_tag);
+        for key in candidates {
+            if verify_signature(key, &data, &sig.signature) {
+                return Ok(sig.labels as usize);
+            }
+            error = ExtendedError::DNSSEC_BOGUS;
+        }
+    }
+    Err(bogus(error))
+}
+
+/// Builds the data covered by the signature, the RRSIG data without the signature and
+/// the records in canonical order, see RFC 4034 section 3.1.8.1
+fn signed_data(set: &RrSet, sig: &Rrsig) -> Option<Vec<u8>> {
+    let mut data = Vec::new();
+    data.extend_from_slice(&sig.type_covered.to_u16().to_be_bytes());
+    data.push(sig.algorithm);
+    data.push(sig.labels);
+    data.extend_from_slice(&sig.original_ttl.to_be_bytes());
+    data.extend_from_slice(&sig.expiration.to_be_bytes());
+    data.extend_from_slice(&sig.inception.to_be_bytes());
+    data.extend_from_slice(&sig.key_tag.to_be_bytes());
+    data.extend_from_slice(&name_wire(&canonical_name(&sig.signer_name).ok()?)?);
+
+    // A wildcard expansion is signed with the owner name of the wildcard
+    let labels = sig.labels as usize;
+    let owner = if labels < count_labels(set.owner) {
+        name_wire(&format!("*.{}", ancestor(set.owner, labels)).replace("*..", "*."))?
+    } else {
+        name_wire(set.owner)?
+    };
+
+    let mut rdatas: Vec<&[u8]> = set.records.iter().map(|rr| &rr.rdata[..]).collect();
+    rdatas.sort();
+    rdatas.dedup();
+    for rdata in rdatas {
+        data.extend_from_slice(&owner);
+        data.extend_from_slice(&set.r_type.to_u16().to_be_bytes());
+        data.extend_from_slice(&Class::Internet.to_u16().to_be_bytes());
+        data.extend_from_slice(&sig.original_ttl.to_be_bytes());
+        if rdata.len() > 0xffff {
+            return None;
+        }
+        data.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+        data.extend_from_slice(rdata);
+    }
+    Some(data)
+}
+
+fn supported_algorithm(algorithm: u8) -> bool {
+    match algorithm {
+        5 | 7 | 8 | 10 | 13 | 14 | 15 => true,
+        _ => false,
+    }
+}
+
+fn supported_digest(digest_type: u8) -> bool {
+    match digest_type {
+        1 | 2 | 4 => true,
+        _ => false,
+    }
+}
+
+fn verify_signature(key: &Dnskey, data: &[u8], sig: &[u8]) -> bool {
+    let rsa = |params: &'static signature::RsaParameters| {
+        let key = &key.public_key[..];
+        let (exponent_len, rest) = match key.split_first() {
+            Some((&0, rest)) if rest.len() >= 2 => {
+                (u16::from_be_bytes([rest[0], rest[1]]) as usize, &rest[2..])
+            }
+            Some((&len, rest)) => (len as usize, rest),
+            None => return false,
+        };
+        if exponent_len == 0 || rest.len() <= exponent_len {
+            return false;
+        }
+        let (e, n) = rest.split_at(exponent_len);
+        let trim = |b: &[u8]| {
+            let zeros = b.iter().take_while(|&&b| b == 0).count();
+            b[zeros..].to_vec()
+        };
+        RsaPublicKeyComponents {
+            n: trim(n),
+            e: trim(e),
+        }
+        .verify(params, data, sig)
+        .is_ok()
+    };
+    let ecdsa = |alg: &'static signature::EcdsaVerificationAlgorithm| {
+        // DNSKEY records omit the uncompressed point prefix
+        let mut point = vec![0x04];
+        point.extend_from_slice(&key.public_key);
+        UnparsedPublicKey::new(alg, point).verify(data, sig).is_ok()
+    };
+    match key.algorithm {
+        5 | 7 => rsa(&signature::RSA_PKCS1_1024_8192_SHA1_FOR_LEGACY_USE_ONLY),
+        8 => rsa(&signature::RSA_PKCS1_1024_8192_SHA256_FOR_LEGACY_USE_ONLY),
+        10 => rsa(&signature::RSA_PKCS1_1024_8192_SHA512_FOR_LEGACY_USE_ONLY),
+        13 => ecdsa(&signature::ECDSA_P256_SHA256_FIXED),
+        14 => ecdsa(&signature::ECDSA_P384_SHA384_FIXED),
+        15 => UnparsedPublicKey::new(&signature::ED25519, &key.public_key)
+            .verify(data, sig)
+            .is_ok(),
+        _ => false,
+    }
+}
+
+/// Whether the DS record is the digest of the key of the zone
+fn ds_matches(ds: &Ds, zone: &str, key: &Dnskey) -> bool {
+    if ds.algorithm != key.algorithm || ds.key_tag != key.key_tag() {
+        return false;
+    }
+    let algorithm = match ds.digest_type {
+        1 => &digest::SHA1_FOR_LEGACY_USE_ONLY,
+        2 => &digest::SHA256,
+        4 => &digest::SHA384,
+        _ => return false,
+    };
+    let mut data = match name_wire(zone) {
+        Some(data) => data,
+        None => return false,
+    };
+    data.extend_from_slice(&key.flags.to_be_bytes());
+    data.push(key.protocol);
+    data.push(key.algorithm);
+    data.extend_from_slice(&key.public_key);
+    digest::digest(algorithm, &data).as_ref() == &ds.digest[..]
+}
+
+/// Iterated SHA-1 hash of the name, see RFC 5155 section 5
+fn nsec3_hash(name: &str, salt: &[u8], iterations: u16) -> Vec<u8> {
+    let mut hash = name_wire(name).unwrap_or_default();
+    for _ in 0..=iterations {
+        hash.extend_from_slice(salt);
+        hash = digest::digest(&digest::SHA1_FOR_LEGACY_USE_ONLY, &hash)
+            .as_ref()
+            .to_vec();
+    }
+    hash
+}
+
+/// Decodes the base32 encoding with the extended hex alphabet of NSEC3 owner names
+fn base32hex_decode(s: &str) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(s.len() * 5 / 8);
+    let (mut bits, mut acc) = (0, 0u32);
+    for c in s.bytes() {
+        let value = match c {
+            b'0'..=b'9' => c - b'0',
+            b'a'..=b'v' => c - b'a' + 10,
+            b'A'..=b'V' => c - b'A' + 10,
+            _ => return None,
+        };
+        acc = (acc << 5) | value as u32;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((acc >> bits) as u8);
+            acc &= (1 << bits) - 1;
+        }
+    }
+    if out.is_empty() {
+        None
+    } else {
+        Some(out)
+    }
+}
+
+/// Whether a <= b in the serial number arithmetic of RFC 1982
+fn serial_le(a: u32, b: u32) -> bool {
+    (b.wrapping_sub(a) as i32) >= 0
+}
+
+fn bogus(error: ExtendedError) -> Error {
+    Error::DnsError(DnsError(RCode::ServerFailure, Some(error)))
+}
+
+/// Lowercase A-label form of a name with a trailing dot
+fn canonical_name(name: &str) -> Result<String, DecodeError> {
+    let mut name = idna::to_ascii(name)
+        .map_err(|_| DecodeError::InvalidName)?
+        .to_ascii_lowercase();
+    if !name.ends_with('.') {
+        name.push('.');
+    }
+    Ok(name)
+}
+
+/// Uncompressed wire format of a name
+fn name_wire(name: &str) -> Option<Vec<u8>> {
+    let mut buf = [0; 256];
+    let mut w = MsgWriter::new(&mut buf[..]);
+    w.write_name(name).ok()?;
+    Some(w.into_bytes().to_vec())
+}
+
+fn labels(name: &str) -> Vec<&str> {
+    name.split('.').filter(|label| !label.is_empty()).collect()
+}
+
+fn count_labels(name: &str) -> usize {
+    labels(name).len()
+}
+
+/// The name made of the last `n` labels of the name
+fn ancestor(name: &str, n: usize) -> String {
+    let labels = labels(name);
+    let skip = labels.len().saturating_sub(n);
+    let mut ancestor = labels[skip..].join(".");
+    ancestor.push('.');
+    ancestor
+}
+
+fn parent(name: &str) -> Option<String> {
+    match count_labels(name) {
+        0 => None,
+        n => Some(ancestor(name, n - 1)),
+    }
+}
+
+/// Number of labels the names have in common at the end
+fn common_labels(a: &str, b: &str) -> usize {
+    labels(a)
+        .iter()
+        .rev()
+        .zip(labels(b).iter().rev())
+        .take_while(|&(a, b)| a == b)
+        .count()
+}
+
+/// Rewrites the record data into canonical form, i.e., uncompressed and with the
+/// embedded names of the types listed in RFC 4034 section 6.2 in lowercase
+fn canonical_rdata(res: &Resource) -> Result<Vec<u8>, DecodeError> {
+    let mut r = res.rdata_reader();
+    let mut buf = [0; MESSAGE_LIMIT];
+    let mut w = MsgWriter::new(&mut buf[..]);
+    match res.r_type {
+        RecordType::Ns | RecordType::CName | RecordType::Dname | RecordType::Ptr => {
+            copy_name(&mut r, &mut w, true)?
+        }
+        RecordType::Mx => {
+            copy_bytes(&mut r, &mut w, 2)?;
+            copy_name(&mut r, &mut w, true)?;
+        }
+        RecordType::Soa => {
+            copy_name(&mut r, &mut w, true)?;
+            copy_name(&mut r, &mut w, true)?;
+        }
+        RecordType::Srv => {
+            copy_bytes(&mut r, &mut w, 6)?;
+            copy_name(&mut r, &mut w, true)?;
+        }
+        RecordType::Naptr => {
+            copy_bytes(&mut r, &mut w, 4)?;
+            for _ in 0..3 {
+                let string = r.read_character_string()?;
+                w.write_character_string(&string)
+                    .map_err(|_| DecodeError::InvalidMessage)?;
+            }
+            copy_name(&mut r, &mut w, true)?;
+        }
+        RecordType::Rrsig => {
+            copy_bytes(&mut r, &mut w, 18)?;
+            copy_name(&mut r, &mut w, true)?;
+        }
+        // RFC 6840 section 5.1 removed NSEC from the list
+        RecordType::Nsec => copy_name(&mut r, &mut w, false)?,
+        _ => {}
+    }
+    let rest = r.read_to_end()?;
+    w.write(&rest).map_err(|_| DecodeError::InvalidMessage)?;
+    Ok(w.into_bytes().to_vec())
+}
+
+fn copy_bytes(r: &mut MsgReader, w: &mut MsgWriter, n: usize) -> Result<(), DecodeError> {
+    let mut bytes = Vec::with_capacity(n);
+    r.read_into(&mut bytes, n)?;
+    w.write(&bytes).map_err(|_| DecodeError::InvalidMessage)
+}
+
+fn copy_name(r: &mut MsgReader, w: &mut MsgWriter, lowercase: bool) -> Result<(), DecodeError> {
+    let name = r.read_name()?;
+    let name = if lowercase {
+        canonical_name(&name)?
+    } else {
+        name
+    };
+    w.write_name(&name).map_err(|_| DecodeError::InvalidName)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        base32hex_decode, count_labels, ds_matches, nsec3_hash, signed_data, Response, Rr, RrSet,
+        Security, TrustAnchor, Validator,
+    };
+    use crate::resolve::message::{DnsError, ExtendedError, RCode, Resource};
+    use crate::resolve::record::{
+        Class, Dnskey, Ds, Mx, Nsec, Nsec3, Record, RecordType, Rrsig, Soa, A,
+    };
+    use crate::resolve::socket::Error;
+    use ring::digest;
+    use ring::signature::{Ed25519KeyPair, KeyPair};
+    use std::net::Ipv4Addr;
+
+    const NOW: u32 = 1_700_000_000;
+
+    struct Signer {
+        zone: &'static str,
+        key: Ed25519KeyPair,
+        dnskey: Dnskey,
+    }
+
+    impl Signer {
+        fn new(zone: &'static str, seed: u8) -> Signer {
+            let key = Ed25519KeyPair::from_seed_unchecked(&[seed; 32]).unwrap();
+            let dnskey = Dnskey {
+                flags: 257,
+                protocol: 3,
+                algorithm: 15,
+                public_key: key.public_key().as_ref().to_vec(),
+            };
+            Signer {
+                zone: zone,
+                key: key,
+                dnskey: dnskey,
+            }
+        }
+
+        fn ds(&self) -> Ds {
+            let mut data = b"\x07example\x00".to_vec();
+            if self.zone == "." {
+                data = vec![0];
+            }
+            data.extend_from_slice(&[1, 1, 3, 15]);
+            data.extend_from_slice(&self.dnskey.public_key);
+            Ds {
+                key_tag: self.dnskey.key_tag(),
+                algorithm: 15,
+                digest_type: 2,
+                digest: digest::digest(&digest::SHA256, &data).as_ref().to_vec(),
+            }
+        }
+
+        fn rrsig(
+            &self,
+            records: &[Resource<'static>],
+            labels: u8,
+            inception: u32,
+            expiration: u32,
+        ) -> Resource<'static> {
+            let rrs: Vec<Rr> = records.iter().map(|res| Rr::new(res).unwrap()).collect();
+            let set = RrSet {
+                owner: &rrs[0].owner,
+                r_type: rrs[0].r_type,
+                records: rrs.iter().collect(),
+                signatures: Vec::new(),
+            };
+            let mut sig = Rrsig {
+                type_covered: rrs[0].r_type,
+                algorithm: 15,
+                labels: labels,
+                original_ttl: records[0].ttl,
+                expiration: expiration,
+                inception: inception,
+                key_tag: self.dnskey.key_tag(),
+                signer_name: self.zone.to_string(),
+                signature: Vec::new(),
+            };
+            sig.signature = self
+                .key
+                .sign(&signed_data(&set, &sig).unwrap())
+                .as_ref()
+                .to_vec();
+            record(&records[0].name, &sig)
+        }
+
+        fn signed(&self, mut records: Vec<Resource<'static>>) -> Vec<Resource<'static>> {
+            let labels = count_labels(&records[0].name) as u8;
+            let sig = self.rrsig(&records, labels, NOW - 3600, NOW + 3600);
+            records.push(sig);
+            records
+        }
+    }
+
+    fn record<R: Record>(name: &str, rdata: &R) -> Resource<'static> {
+        let mut res = Resource::new(name.to_string(), R::record_type(), Class::Internet, 3600);
+        res.write_rdata(rdata).unwrap();
+        res
+    }
+
+    fn a(name: &str, last: u8) -> Resource<'static> {
+        record(
+            name,
+            &A {
+                address: Ipv4Addr::new(192, 0, 2, last),
+            },
+        )
+    }
+
+    fn soa(zone: &str) -> Resource<'static> {
+        record(
+            zone,
+            &Soa {
+                mname: format!("ns.{}", zone),
+                rname: format!("hostmaster.{}", zone),
+                serial: 1,
+                refresh: 7200,
+                retry: 3600,
+                expire: 1209600,
+                minimum: 3600,
+            },
+        )
+    }
+
+    fn nsec(owner: &str, next: &str, types: &[RecordType]) -> Resource<'static> {
+        record(
+            owner,
+            &Nsec {
+                next_domain: next.to_string(),
+                types: types.to_vec(),
+            },
+        )
+    }
+
+    fn response(rcode: RCode, answer: Vec<Resource>, authority: Vec<Resource>) -> Response {
+        let convert = |records: Vec<Resource>| {
+            records
+                .iter()
+                .map(|res| Rr::new(res).unwrap())
+                .collect::<Vec<_>>()
+        };
+        Response {
+            rcode: rcode,
+            answer: convert(answer),
+            authority: convert(authority),
+        }
+    }
+
+    fn base32hex(data: &[u8]) -> String {
+        let alphabet = b"0123456789abcdefghijklmnopqrstuv";
+        let mut out = String::new();
+        let (mut acc, mut bits) = (0u32, 0);
+        for &byte in data {
+            acc = (acc << 8) | byte as u32;
+            bits += 8;
+            while bits >= 5 {
+                bits -= 5;
+                out.push(alphabet[((acc >> bits) & 31) as usize] as char);
+            }
+            acc &= (1 << bits) - 1;
+        }
+        if bits > 0 {
+            out.push(alphabet[((acc << (5 - bits)) & 31) as usize] as char);
+        }
+        out
+    }
+
+    /// A signed root and example. zone with the unsigned delegation insecure.example.
+    struct Fixture {
+        root: Signer,
+        example: Signer,
+    }
+
+    impl Fixture {
+        fn new() -> Fixture {
+            Fixture {
+                root: Signer::new(".", 1),
+                example: Signer::new("example.", 2),
+            }
+        }
+
+        fn validator(&self) -> Validator {
+            Validator::new(TrustAnchor {
+                zone: ".".to_string(),
+                ds: vec![self.root.ds()],
+                dnskeys: Vec::new(),
+            })
+        }
+
+        fn apex_nsec(&self) -> Vec<Resource<'static>> {
+            self.example.signed(vec![nsec(
+                "example.",
+                "insecure.example.",
+                &[
+                    RecordType::Ns,
+                    RecordType::Soa,
+                    RecordType::Rrsig,
+                    RecordType::Nsec,
+                    RecordType::Dnskey,
+                ],
+            )])
+        }
+
+        fn delegation_nsec(&self, types: &[RecordType]) -> Vec<Resource<'static>> {
+            self.example
+                .signed(vec![nsec("insecure.example.", "www.example.", types)])
+        }
+
+        fn fetch(&self, name: &str, r_type: RecordType) -> Result<Response, Error> {
+            let (answer, authority) = match (name, r_type) {
+                (".", RecordType::Dnskey) => (
+                    self.root.signed(vec![record(".", &self.root.dnskey)]),
+                    vec![],
+                ),
+                ("example.", RecordType::Ds) => (
+                    self.root
+                        .signed(vec![record("example.", &self.example.ds())]),
+                    vec![],
+                ),
+                ("example.", RecordType::Dnskey) => (
+                    self.example
+                        .signed(vec![record("example.", &self.example.dnskey)]),
+                    vec![],
+                ),
+                ("insecure.example.", RecordType::Ds) => {
+                    let mut authority = self.example.signed(vec![soa("example.")]);
+                    authority.extend(self.delegation_nsec(&[
+                        RecordType::Ns,
+                        RecordType::Rrsig,
+                        RecordType::Nsec,
+                    ]));
+                    (vec![], authority)
+                }
+                ("insecure.example.", RecordType::Soa) => (vec![soa("insecure.example.")], vec![]),
+                (name, RecordType::Soa) if name.ends_with(".insecure.example.") => {
+                    (vec![], vec![soa("insecure.example.")])
+                }
+                (_, RecordType::Soa) => (vec![], self.example.signed(vec![soa("example.")])),
+                _ => panic!("unexpected query {} {:?}", name, r_type),
+            };
+            Ok(response(RCode::NoError, answer, authority))
+        }
+
+        fn validate(
+            &self,
+            validator: &Validator,
+            name: &str,
+            q_type: RecordType,
+            response: &Response,
+        ) -> Result<Security, Error> {
+            validator.validate(
+                name,
+                q_type,
+                response,
+                &|name, r_type| self.fetch(name, r_type),
+                NOW as u64,
+            )
+        }
+    }
+
+    fn extended_error(result: Result<Security, Error>) -> ExtendedError {
+        match result {
+            Err(Error::DnsError(DnsError(RCode::ServerFailure, Some(ede)))) => {
+                assert!(ede.is_bogus());
+                ede
+            }
+            Err(e) => panic!("unexpected error {}", e),
+            Ok(security) => panic!("unexpected {:?}", security),
+        }
+    }
+
+    #[test]
+    fn test_signed_data() {
+        let records: Vec<Rr> = [
+            (20, "Mail.Example."),
+            (10, "mail2.example."),
+            (20, "MAIL.example."),
+        ]
+        .iter()
+        .map(|&(preference, exchange)| {
+            let mx = Mx {
+                preference: preference,
+                exchange: exchange.to_string(),
+            };
+            Rr::new(&record("WWW.Example.", &mx)).unwrap()
+        })
+        .collect();
+        let set = RrSet {
+            owner: &records[0].owner,
+            r_type: RecordType::Mx,
+            records: records.iter().collect(),
+            signatures: Vec::new(),
+        };
+        let sig = Rrsig {
+            type_covered: RecordType::Mx,
+            algorithm: 15,
+            labels: 2,
+            original_ttl: 3600,
+            expiration: 1700003600,
+            inception: 1699996400,
+            key_tag: 12345,
+            signer_name: "Example.".to_string(),
+            signature: vec![1, 2, 3],
+        };
+        // Lowercase names, records sorted by their data and without duplicates
+        assert_eq!(
+            signed_data(&set, &sig).unwrap(),
+            &b"\
+            \x00\x0f\x0f\x02\x00\x00\x0e\x10\x65\x53\xff\x10\x65\x53\xe2\xf0\
+            \x30\x39\x07\x65\x78\x61\x6d\x70\x6c\x65\x00\x03\x77\x77\x77\x07\
+            \x65\x78\x61\x6d\x70\x6c\x65\x00\x00\x0f\x00\x01\x00\x00\x0e\x10\
+            \x00\x11\x00\x0a\x05\x6d\x61\x69\x6c\x32\x07\x65\x78\x61\x6d\x70\
+            \x6c\x65\x00\x03\x77\x77\x77\x07\x65\x78\x61\x6d\x70\x6c\x65\x00\
+            \x00\x0f\x00\x01\x00\x00\x0e\x10\x00\x10\x00\x14\x04\x6d\x61\x69\
+            \x6c\x07\x65\x78\x61\x6d\x70\x6c\x65\x00"[..]
+        );
+
+        // Wildcard expansions are signed with the wildcard owner
+        let sig = Rrsig { labels: 1, ..sig };
+        assert_eq!(
+            &signed_data(&set, &sig).unwrap()[27..38],
+            &b"\x01*\x07example\x00"[..]
+        );
+    }
+
+    #[test]
+    fn test_ds_digest_and_nsec3_hash() {
+        // dskey.example.com. from section 5.4 of RFC 4034
+        let dnskey = Dnskey {
+            flags: 256,
+            protocol: 3,
+            algorithm: 5,
+            public_key: b"\
+                \x01\x03\x9e\x8a\x24\x74\x18\xe3\x18\x90\x3b\x21\x5a\x84\x8a\xcf\
+                \xd5\xf3\x7f\x02\x6b\xd4\x06\x2d\xb2\x6c\x77\x4c\x69\x09\x68\xd5\
+                \xd5\x6d\xf8\xbf\xda\x91\xe6\xf3\x6d\x9a\x27\x98\x88\xf4\x13\x33\
+                \x35\x7c\x5e\x60\x29\x99\x0d\x10\xfd\xf5\x66\x30\x62\xa5\x12\x76\
+                \x33\x26\x98\x0a\x61\x5d\xdb\xf1\x7a\x05\xdd\xfc\xce\x7e\x5f\xb3\
+                \xab\xcc\xa0\x5a\x31\xb0\x95\x74\x52\xd4\x52\x1e\x83\x87\x07\x89\
+                \x06\x31\x15\xbf\x97\xf6\xc3\x08\xcc\xf5\x7c\xdc\x9c\xe7\xfe\x10\
+                \xf6\xed\x1b\xd0\xcc\x06\x60\x03\x8c\x50\xdc\xdb\x0f\xeb\x96\x3c\
+                \x2f\x17"
+                .to_vec(),
+        };
+        let mut ds = Ds {
+            key_tag: 60485,
+            algorithm: 5,
+            digest_type: 1,
+            digest:
+                b"\x2b\xb1\x83\xaf\x5f\x22\x58\x81\x79\xa5\x3b\x0a\x98\x63\x1f\xad\x1a\x29\x21\x18"
+                    .to_vec(),
+        };
+        assert!(ds_matches(&ds, "dskey.example.com.", &dnskey));
+        assert!(!ds_matches(&ds, "example.com.", &dnskey));
+        ds.key_tag += 1;
+        assert!(!ds_matches(&ds, "dskey.example.com.", &dnskey));
+
+        // Appendix A of RFC 5155
+        let salt = [0xaa, 0xbb, 0xcc, 0xdd];
+        let hash = nsec3_hash("example.", &salt, 12);
+        assert_eq!(base32hex(&hash), "0p9mhaveqvm6t7vbl5lop2u3t2rp3tom");
+        assert_eq!(
+            base32hex_decode("0P9MHAVEQVM6T7VBL5LOP2U3T2RP3TOM"),
+            Some(hash)
+        );
+        assert_eq!(
+            base32hex(&nsec3_hash("a.example.", &salt, 12)),
+            "35mthgpgcu1qg68fab165klnsnk3dpvl"
+        );
+        assert_eq!(base32hex_decode("0p9w"), None);
+    }
+
+    #[test]
+    fn test_secure_answer() {
+        let f = Fixture::new();
+        let validator = f.validator();
+        let answer = response(
+            RCode::NoError,
+            f.example
+                .signed(vec![a("www.example.", 1), a("www.example.", 2)]),
+            vec![],
+        );
+        assert_eq!(
+            f.validate(&validator, "WWW.example", RecordType::A, &answer)
+                .unwrap(),
+            Security::Secure
+        );
+        // The keys of the zones are cached
+        let cached = validator.validate(
+            "www.example.",
+            RecordType::A,
+            &answer,
+            &|name, r_type| panic!("unexpected query {} {:?}", name, r_type),
+            NOW as u64 + 60,
+        );
+        assert_eq!(cached.unwrap(), Security::Secure);
+
+        // A wildcard expansion requires the proof that the name does not exist
+        let mut records = vec![a("host.example.", 1)];
+        let sig = f.example.rrsig(&records, 1, NOW - 3600, NOW + 3600);
+        records.push(sig);
+        let expanded = response(RCode::NoError, records.clone(), f.apex_nsec());
+        assert_eq!(
+            f.validate(&validator, "host.example.", RecordType::A, &expanded)
+                .unwrap(),
+            Security::Secure
+        );
+        let unproven = response(RCode::NoError, records, vec![]);
+        assert_eq!(
+            extended_error(f.validate(&validator, "host.example.", RecordType::A, &unproven)),
+            ExtendedError::NSEC_MISSING
+        );
+    }
+
+    #[test]
+    fn test_bogus_answer() {
+        let f = Fixture::new();
+        let validator = f.validator();
+        let check = |records: Vec<Resource>| {
+            extended_error(f.validate(
+                &validator,
+                "www.example.",
+                RecordType::A,
+                &response(RCode::NoError, records, vec![]),
+            ))
+        };
+
+        let mut forged = f.example.signed(vec![a("www.example.", 1)]);
+        forged[0] = a("www.example.", 6);
+        assert_eq!(check(forged), ExtendedError::DNSSEC_BOGUS);
+
+        let records = vec![a("www.example.", 1)];
+        let expired = f.example.rrsig(&records, 2, NOW - 7200, NOW - 3600);
+        assert_eq!(
+            check(vec![records[0].clone(), expired]),
+            ExtendedError::SIGNATURE_EXPIRED
+        );
+        let early = f.example.rrsig(&records, 2, NOW + 3600, NOW + 7200);
+        assert_eq!(
+            check(vec![records[0].clone(), early]),
+            ExtendedError::SIGNATURE_NOT_YET_VALID
+        );
+        assert_eq!(check(records.clone()), ExtendedError::RRSIGS_MISSING);
+
+        // Signed by a key which the DS records of the zone do not refer to
+        let other = Signer::new("example.", 3);
+        assert_eq!(
+            check(other.signed(records.clone())),
+            ExtendedError::DNSKEY_MISSING
+        );
+
+        // The root key is not the trust anchor
+        let anchor = TrustAnchor {
+            zone: ".".to_string(),
+            ds: vec![Signer::new(".", 4).ds()],
+            dnskeys: Vec::new(),
+        };
+        let result = Validator::new(anchor).validate(
+            "www.example.",
+            RecordType::A,
+            &response(RCode::NoError, f.example.signed(records), vec![]),
+            &|name, r_type| f.fetch(name, r_type),
+            NOW as u64,
+        );
+        assert_eq!(extended_error(result), ExtendedError::DNSKEY_MISSING);
+    }
+
+    #[test]
+    fn test_insecure_delegation() {
+        let f = Fixture::new();
+        let validator = f.validator();
+        let answer = response(RCode::NoError, vec![a("host.insecure.example.", 1)], vec![]);
+        assert_eq!(
+            f.validate(&validator, "host.insecure.example.", RecordType::A, &answer)
+                .unwrap(),
+            Security::Insecure
+        );
+
+        // The NSEC record of the delegation must deny the DS records
+        let mut authority = f.example.signed(vec![soa("example.")]);
+        authority.extend(f.delegation_nsec(&[RecordType::Ns, RecordType::Ds, RecordType::Rrsig]));
+        let denial = response(RCode::NoError, vec![], authority);
+        assert_eq!(
+            extended_error(f.validate(&validator, "insecure.example.", RecordType::Ds, &denial)),
+            ExtendedError::NSEC_MISSING
+        );
+    }
+
+    #[test]
+    fn test_nsec_denial() {
+        let f = Fixture::new();
+        let validator = f.validator();
+        let mut authority = f.example.signed(vec![soa("example.")]);
+        authority.extend(f.apex_nsec());
+        authority.extend(f.delegation_nsec(&[RecordType::Ns, RecordType::Rrsig, RecordType::Nsec]));
+        let nxdomain = response(RCode::NameError, vec![], authority.clone());
+        assert_eq!(
+            f.validate(&validator, "nx.example.", RecordType::A, &nxdomain)
+                .unwrap(),
+            Security::Secure
+        );
+
+        // Without the apex NSEC record, a wildcard could have matched
+        authority.drain(2..4);
+        let nxdomain = response(RCode::NameError, vec![], authority);
+        assert_eq!(
+            extended_error(f.validate(&validator, "nx.example.", RecordType::A, &nxdomain)),
+            ExtendedError::NSEC_MISSING
+        );
+
+        let nodata = |types: &[RecordType]| {
+            let mut authority = f.example.signed(vec![soa("example.")]);
+            authority.extend(
+                f.example
+                    .signed(vec![nsec("www.example.", "example.", types)]),
+            );
+            f.validate(
+                &validator,
+                "www.example.",
+                RecordType::AAAA,
+                &response(RCode::NoError, vec![], authority),
+            )
+        };
+        assert_eq!(
+            nodata(&[RecordType::A, RecordType::Rrsig, RecordType::Nsec]).unwrap(),
+            Security::Secure
+        );
+        assert_eq!(
+            extended_error(nodata(&[RecordType::A, RecordType::AAAA])),
+            ExtendedError::NSEC_MISSING
+        );
+
+        // Unsigned denials of a signed zone
+        let unsigned = response(RCode::NameError, vec![], vec![soa("example.")]);
+        assert_eq!(
+            extended_error(f.validate(&validator, "nx.example.", RecordType::A, &unsigned)),
+            ExtendedError::NSEC_MISSING
+        );
+    }
+
+    #[test]
+    fn test_nsec3_denial() {
+        let f = Fixture::new();
+        let validator = f.validator();
+        let salt = vec![0xab];
+        let mut hashes: Vec<(Vec<u8>, Vec<RecordType>)> = vec![
+            (
+                nsec3_hash("example.", &salt, 1),
+                vec![
+                    RecordType::Ns,
+                    RecordType::Soa,
+                    RecordType::Rrsig,
+                    RecordType::Dnskey,
+                ],
+            ),
+            (
+                nsec3_hash("insecure.example.", &salt, 1),
+                vec![RecordType::Ns],
+            ),
+            (
+                nsec3_hash("www.example.", &salt, 1),
+                vec![RecordType::A, RecordType::Rrsig],
+            ),
+        ];
+        hashes.sort_by(|a, b| a.0.cmp(&b.0));
+        // The NSEC3 chain of the zone, in the order of the hashes
+        let chain: Vec<Vec<Resource>> = (0..hashes.len())
+            .map(|i| {
+                let nsec3 = Nsec3 {
+                    hash_algorithm: 1,
+                    flags: 0,
+                    iterations: 1,
+                    salt: salt.clone(),
+                    next_hashed_owner: hashes[(i + 1) % hashes.len()].0.clone(),
+                    types: hashes[i].1.clone(),
+                };
+                let owner = format!("{}.example.", base32hex(&hashes[i].0));
+                f.example.signed(vec![record(&owner, &nsec3)])
+            })
+            .collect();
+        let apex = nsec3_hash("example.", &salt, 1);
+        let denial = |rcode: RCode, name: &str, q_type: RecordType, skip_apex: bool| {
+            let mut authority = f.example.signed(vec![soa("example.")]);
+            for (i, records) in chain.iter().enumerate() {
+                if !(skip_apex && hashes[i].0 == apex) {
+                    authority.extend(records.iter().cloned());
+                }
+            }
+            f.validate(
+                &validator,
+                name,
+                q_type,
+                &response(rcode, vec![], authority),
+            )
+        };
+
+        assert_eq!(
+            denial(RCode::NameError, "nx.example.", RecordType::A, false).unwrap(),
+            Security::Secure
+        );
+        assert_eq!(
+            denial(RCode::NoError, "www.example.", RecordType::AAAA, false).unwrap(),
+            Security::Secure
+        );
+        assert_eq!(
+            extended_error(denial(RCode::NoError, "www.example.", RecordType::A, false)),
+            ExtendedError::NSEC_MISSING
+        );
+        // Without the closest encloser
+        assert_eq!(
+            extended_error(denial(RCode::NameError, "nx.example.", RecordType::A, true)),
+            ExtendedError::NSEC_MISSING
+        );
+    }
+
+    #[test]
+    fn test_cached_nxdomain() {
+        let f = Fixture::new();
+        let validator = f.validator();
+        let now = NOW as u64;
+        assert!(!validator.cached_nxdomain("other.example.", now));
+        let mut authority = f.example.signed(vec![soa("example.")]);
+        authority.extend(f.apex_nsec());
+        authority.extend(f.delegation_nsec(&[RecordType::Ns, RecordType::Rrsig, RecordType::Nsec]));
+        let nxdomain = response(RCode::NameError, vec![], authority);
+        f.validate(&validator, "nx.example.", RecordType::A, &nxdomain)
+            .unwrap();
+
+        // other names of the span from insecure.example. to www.example.
+        assert!(validator.cached_nxdomain("nx.example.", now));
+        assert!(validator.cached_nxdomain("Other.example", now + 60));
+        assert!(validator.cached_nxdomain("a.b.other.example.", now));
+        // names which exist, are outside of the spans, or below the delegation
+        assert!(!validator.cached_nxdomain("www.example.", now));
+        assert!(!validator.cached_nxdomain("zzz.example.", now));
+        assert!(!validator.cached_nxdomain("host.insecure.example.", now));
+        assert!(!validator.cached_nxdomain("nx.org.", now));
+        // the records expire with their TTL
+        assert!(!validator.cached_nxdomain("other.example.", now + 3600));
+    }
+
+    #[test]
+    fn test_cached_nxdomain_nsec3() {
+        let f = Fixture::new();
+        let validator = f.validator();
+        let salt = vec![0xab];
+        let mut hashes: Vec<(Vec<u8>, Vec<RecordType>)> = vec![
+            (
+                nsec3_hash("example.", &salt, 1),
+                vec![RecordType::Ns, RecordType::Soa, RecordType::Rrsig],
+            ),
+            (
+                nsec3_hash("insecure.example.", &salt, 1),
+                vec![RecordType::Ns],
+            ),
+        ];
+        hashes.sort_by(|a, b| a.0.cmp(&b.0));
+        let mut authority = f.example.signed(vec![soa("example.")]);
+        for i in 0..hashes.len() {
+            let nsec3 = Nsec3 {
+                hash_algorithm: 1,
+                flags: 0,
+                iterations: 1,
+                salt: salt.clone(),
+                next_hashed_owner: hashes[(i + 1) % hashes.len()].0.clone(),
+                types: hashes[i].1.clone(),
+            };
+            let owner = format!("{}.example.", base32hex(&hashes[i].0));
+            authority.extend(f.example.signed(vec![record(&owner, &nsec3)]));
+        }
+        let nxdomain = response(RCode::NameError, vec![], authority);
+        f.validate(&validator, "nx.example.", RecordType::A, &nxdomain)
+            .unwrap();
+
+        // the two spans cover every other hash of the zone
+        let now = NOW as u64;
+        assert!(validator.cached_nxdomain("other.example.", now));
+        assert!(validator.cached_nxdomain("a.other.example.", now));
+        assert!(!validator.cached_nxdomain("insecure.example.", now));
+        assert!(!validator.cached_nxdomain("host.insecure.example.", now));
+    }
+}
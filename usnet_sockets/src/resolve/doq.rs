@@ -0,0 +1,750 @@
+//! DNS over dedicated QUIC connections (DoQ), as defined by RFC 9250.
+//!
+//! All queries to a server share one QUIC connection to port 853. Each query
+//! goes on its own client-initiated bidirectional stream, prefixed with its
+//! length as over TCP, and the client finishes its side of the stream after
+//! it. The response arrives on the same stream, so a lost packet of one query
+//! does not hold up the others, and the DNS message ID is always 0.
+//!
+//! The QUIC state machine of `quinn-proto` is driven over a `DatagramSocket`,
+//! so that the connection runs on the userspace stack like the UDP queries.
+//! After the first connection, a reconnect resumes the TLS session and may
+//! send queries in 0-RTT data, which an attacker can replay. Only standard
+//! queries go in 0-RTT data, others wait for the handshake (RFC 9250,
+//! section 4.5).
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use bytes::{Bytes, BytesMut};
+use quinn_proto::{
+    ClientConfig, Connection, ConnectionHandle, DatagramEvent, Dir, Endpoint, EndpointConfig,
+    Event, ReadError, StreamId, TransportConfig, VarInt, WriteError,
+};
+use rustls;
+use webpki_roots;
+
+use crate::apimultithread::UdpSocket;
+
+use super::message::{
+    DecodeError, EncodeError, Message, MessageBuf, OpCode, Question, MESSAGE_LIMIT,
+};
+use super::name::is_same_name;
+use super::record::{Class, Record, RecordType, A, AAAA};
+use super::resolver::ResolveHost;
+use super::socket::{DatagramSocket, Error};
+
+/// UDP port of DoQ servers
+pub const DOQ_PORT: u16 = 853;
+
+/// ALPN protocol of DoQ
+pub const ALPN: &[u8] = b"doq";
+
+/// Closes the connection or stream without an error
+pub const DOQ_NO_ERROR: u32 = 0x0;
+/// The server or client failed on its side
+pub const DOQ_INTERNAL_ERROR: u32 = 0x1;
+/// The peer violated the framing of RFC 9250
+pub const DOQ_PROTOCOL_ERROR: u32 = 0x2;
+/// The client is no longer interested in the response
+pub const DOQ_REQUEST_CANCELLED: u32 = 0x3;
+/// The server is overloaded
+pub const DOQ_EXCESSIVE_LOAD: u32 = 0x4;
+/// Any other error
+pub const DOQ_UNSPECIFIED_ERROR: u32 = 0x5;
+
+/// Time to wait for a response by default
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Returns the client configuration for DoQ connections, which trusts the
+/// Mozilla root certificates of `webpki-roots`, requires TLS 1.3 and the `doq`
+/// ALPN protocol, and keeps TLS sessions to resume them with 0-RTT data.
+///
+/// Connections that share the configuration share the session cache.
+pub fn client_config() -> ClientConfig {
+    let mut tls = rustls::ClientConfig::new();
+    tls.versions = vec![rustls::ProtocolVersion::TLSv1_3];
+    tls.alpn_protocols = vec![ALPN.to_vec()];
+    tls.enable_early_data = true;
+    tls.root_store
+        .add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS);
+    ClientConfig {
+        transport: Arc::new(TransportConfig::default()),
+        crypto: Arc::new(tls),
+    }
+}
+
+/// Encodes a query for its stream, i.e., with the 2-byte length prefix and the
+/// message ID 0.
+pub fn encode_query(msg: &Message) -> Result<Vec<u8>, EncodeError> {
+    let encoded = msg.encode_vec()?;
+    let mut data = Vec::with_capacity(2 + encoded.len());
+    data.extend_from_slice(&(encoded.len() as u16).to_be_bytes());
+    data.extend_from_slice(&encoded);
+    // the stream identifies the query
+    data[2] = 0;
+    data[3] = 0;
+    Ok(data)
+}
+
+/// Decodes the response from the data of its stream, which must be one
+/// message with the 2-byte length prefix and the message ID 0.
+pub fn decode_response(data: &[u8]) -> Result<MessageBuf, DecodeError> {
+    if data.len() < 2 {
+        return Err(DecodeError::ShortMessage);
+    }
+    let len = u16::from_be_bytes([data[0], data[1]]) as usize;
+    if data.len() - 2 < len {
+        return Err(DecodeError::ShortMessage);
+    }
+    if data.len() - 2 > len {
+        return Err(DecodeError::ExtraneousData);
+    }
+    let msg = Message::decode_owned(&data[2..])?;
+    if msg.header.id != 0 {
+        return Err(DecodeError::InvalidMessage);
+    }
+    Ok(msg)
+}
+
+/// Whether a query may be sent in 0-RTT data, which can be replayed, i.e.,
+/// whether it is a standard query instead of, e.g., an UPDATE
+fn is_replay_safe(msg: &Message) -> bool {
+    msg.header.op == OpCode::Query
+}
+
+/// Identifies a query sent through `DoqConnection::send_query`.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct DoqQuery(u64);
+
+/// A query and its stream
+struct Pending {
+    request: Vec<u8>,
+    replay_safe: bool,
+    /// The stream once it is opened
+    stream: Option<StreamId>,
+    /// Whether the stream was opened before the handshake, i.e., in 0-RTT data
+    early: bool,
+    written: usize,
+    response: Vec<u8>,
+    result: Option<Result<MessageBuf, Error>>,
+}
+
+impl Pending {
+    fn fail(&mut self, err: Error) {
+        if self.result.is_none() {
+            self.result = Some(Err(err));
+        }
+    }
+}
+
+/// A QUIC connection to a DoQ server, over which queries are multiplexed.
+///
+/// Queries can be sent one after another with `query`, or all at once with
+/// `send_query` and `recv_response`, which drives the connection until the
+/// response of the given query arrives and meanwhile receives the others.
+pub struct DoqConnection<S: DatagramSocket = UdpSocket> {
+    sock: S,
+    endpoint: Endpoint,
+    handle: ConnectionHandle,
+    conn: Connection,
+    server: SocketAddr,
+    timeout: Duration,
+    connected: bool,
+    lost: Option<String>,
+    next_query: u64,
+    pending: BTreeMap<DoqQuery, Pending>,
+    streams: BTreeMap<StreamId, DoqQuery>,
+}
+
+impl DoqConnection {
+    /// Connects to the DoQ server through a new socket.
+    ///
+    /// The server name is checked against the certificate of the server.
+    /// The connection handshake runs with the first query.
+    pub fn connect(server: SocketAddr, server_name: &str) -> Result<DoqConnection, Error> {
+        DoqConnection::connect_with(server, server_name, client_config())
+    }
+
+    /// Connects to the DoQ server with the configuration, which may come from
+    /// `client_config` and be shared, so that TLS sessions are resumed.
+    pub fn connect_with(
+        server: SocketAddr,
+        server_name: &str,
+        config: ClientConfig,
+    ) -> Result<DoqConnection, Error> {
+        let local: IpAddr = match server {
+            SocketAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+            SocketAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+        };
+        let sock = UdpSocket::bind(SocketAddr::new(local, 0))?;
+        DoqConnection::with_socket(sock, server, server_name, config)
+    }
+}
+
+impl<S: DatagramSocket> DoqConnection<S> {
+    /// Connects to the DoQ server through the socket, e.g., a UDP socket of
+    /// the singlethread API or a kernel one.
+    pub fn with_socket(
+        sock: S,
+        server: SocketAddr,
+        server_name: &str,
+        config: ClientConfig,
+    ) -> Result<DoqConnection<S>, Error> {
+        let mut endpoint = Endpoint::new(Arc::new(EndpointConfig::default()), None);
+        let (handle, conn) = endpoint
+            .connect(config, server, server_name)
+            .map_err(|e| Error::IoError(io::Error::new(io::ErrorKind::InvalidInput, e)))?;
+        Ok(DoqConnection {
+            sock: sock,
+            endpoint: endpoint,
+            handle: handle,
+            conn: conn,
+            server: server,
+            timeout: DEFAULT_TIMEOUT,
+            connected: false,
+            lost: None,
+            next_query: 0,
+            pending: BTreeMap::new(),
+            streams: BTreeMap::new(),
+        })
+    }
+
+    /// Sets the time to wait for a response, five seconds by default.
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    /// Returns the address of the server.
+    pub fn server(&self) -> SocketAddr {
+        self.server
+    }
+
+    /// Returns whether the connection was closed, e.g., by the server after it
+    /// was idle, and a new one is needed.
+    pub fn is_closed(&self) -> bool {
+        self.lost.is_some() || self.conn.is_closed()
+    }
+
+    /// Sends the query on a new stream and returns its handle for
+    /// `recv_response`.
+    ///
+    /// The query is sent when the connection allows another stream, and only
+    /// after the handshake if it is not a standard query.
+    pub fn send_query(&mut self, msg: &Message) -> Result<DoqQuery, Error> {
+        if let Some(ref reason) = self.lost {
+            return Err(connection_lost(reason));
+        }
+        let query = DoqQuery(self.next_query);
+        self.next_query += 1;
+        self.pending.insert(
+            query,
+            Pending {
+                request: encode_query(msg)?,
+                replay_safe: is_replay_safe(msg),
+                stream: None,
+                early: false,
+                written: 0,
+                response: Vec::new(),
+                result: None,
+            },
+        );
+        self.process()?;
+        Ok(query)
+    }
+
+    /// Waits for the response to the query and returns it.
+    ///
+    /// Responses to other queries which arrive in the meantime are kept for
+    /// their `recv_response`. The response code is not checked.
+    pub fn recv_response(&mut self, query: DoqQuery) -> Result<MessageBuf, Error> {
+        if !self.pending.contains_key(&query) {
+            return Err(Error::IoError(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "unknown DoQ query",
+            )));
+        }
+        let deadline = Instant::now() + self.timeout;
+        let res = self.drive(deadline, |conn| {
+            conn.pending
+                .get(&query)
+                .map_or(true, |pending| pending.result.is_some())
+        });
+        if let Err(e) = res {
+            self.cancel(query);
+            return Err(e);
+        }
+        let pending = self.pending.remove(&query).unwrap();
+        if let Some(stream) = pending.stream {
+            self.streams.remove(&stream);
+        }
+        pending.result.unwrap()
+    }
+
+    /// Sends the query and waits for its response.
+    pub fn query(&mut self, msg: &Message) -> Result<MessageBuf, Error> {
+        let query = self.send_query(msg)?;
+        self.recv_response(query)
+    }
+
+    /// Sends all queries at once, each on its own stream, and returns their
+    /// responses in the same order.
+    pub fn query_all(&mut self, msgs: &[Message]) -> Vec<Result<MessageBuf, Error>> {
+        let queries: Vec<_> = msgs.iter().map(|msg| self.send_query(msg)).collect();
+        queries
+            .into_iter()
+            .map(|query| query.and_then(|query| self.recv_response(query)))
+            .collect()
+    }
+
+    /// Closes the connection without an error, as when it is dropped.
+    pub fn close(&mut self) {
+        if !self.is_closed() {
+            self.conn
+                .close(Instant::now(), VarInt::from_u32(DOQ_NO_ERROR), Bytes::new());
+            let _ = self.transmit();
+        }
+    }
+
+    /// Gives up on the response to the query, and tells the server so
+    fn cancel(&mut self, query: DoqQuery) {
+        if let Some(pending) = self.pending.remove(&query) {
+            if let Some(stream) = pending.stream {
+                self.streams.remove(&stream);
+                let _ = self
+                    .conn
+                    .stop_sending(stream, VarInt::from_u32(DOQ_REQUEST_CANCELLED));
+            }
+        }
+        let _ = self.transmit();
+    }
+
+    /// Handles received datagrams and timers until `done` or the deadline
+    fn drive<F>(&mut self, deadline: Instant, done: F) -> Result<(), Error>
+    where
+        F: Fn(&Self) -> bool,
+    {
+        let mut buf = [0; MESSAGE_LIMIT];
+        loop {
+            self.process()?;
+            if done(self) {
+                return Ok(());
+            }
+            if let Some(ref reason) = self.lost {
+                return Err(connection_lost(reason));
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(Error::IoError(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "DoQ response timed out",
+                )));
+            }
+            let wake = self
+                .conn
+                .poll_timeout()
+                .map_or(deadline, |timer| timer.min(deadline));
+            // a zero read timeout is invalid, and an expired timer is handled below
+            let wait = if wake > now {
+                wake - now
+            } else {
+                Duration::from_millis(1)
+            };
+            self.sock.set_read_timeout(Some(wait))?;
+            match self.sock.recv_datagram(&mut buf) {
+                Ok((n, len, from)) if n == len => {
+                    let now = Instant::now();
+                    let data = BytesMut::from(&buf[..n]);
+                    if let Some((handle, DatagramEvent::ConnectionEvent(event))) =
+                        self.endpoint.handle(now, from, None, None, data)
+                    {
+                        if handle == self.handle {
+                            self.conn.handle_event(event);
+                        }
+                    }
+                }
+                // a QUIC packet is never larger than the buffer
+                Ok(_) => {}
+                Err(ref e)
+                    if e.kind() == io::ErrorKind::WouldBlock
+                        || e.kind() == io::ErrorKind::TimedOut => {}
+                Err(e) => return Err(Error::IoError(e)),
+            }
+            let now = Instant::now();
+            if self.conn.poll_timeout().map_or(false, |timer| timer <= now) {
+                self.conn.handle_timeout(now);
+            }
+        }
+    }
+
+    /// Handles the events of the connection, opens the streams of the waiting
+    /// queries, writes and reads the streams, and sends the resulting packets
+    fn process(&mut self) -> Result<(), Error> {
+        while let Some(event) = self.conn.poll_endpoint_events() {
+            if let Some(event) = self.endpoint.handle_event(self.handle, event) {
+                self.conn.handle_event(event);
+            }
+        }
+        while let Some(event) = self.conn.poll() {
+            match event {
+                Event::Connected => {
+                    self.connected = true;
+                    if !self.conn.accepted_0rtt() {
+                        self.resend_early();
+                    }
+                }
+                Event::ConnectionLost { reason } => {
+                    let reason = reason.to_string();
+                    for pending in self.pending.values_mut() {
+                        pending.fail(connection_lost(&reason));
+                    }
+                    self.lost = Some(reason);
+                }
+                _ => {}
+            }
+        }
+        if self.lost.is_none() {
+            self.open_streams();
+            self.write_streams();
+            self.read_streams();
+        }
+        self.transmit()
+    }
+
+    /// Queries sent in rejected 0-RTT data are lost with their streams and
+    /// sent again on new ones
+    fn resend_early(&mut self) {
+        for pending in self.pending.values_mut() {
+            if pending.early && pending.result.is_none() {
+                if let Some(stream) = pending.stream.take() {
+                    self.streams.remove(&stream);
+                }
+                pending.early = false;
+                pending.written = 0;
+                pending.response.clear();
+            }
+        }
+    }
+
+    fn open_streams(&mut self) {
+        let early_data = !self.connected && self.conn.has_0rtt();
+        for (query, pending) in self.pending.iter_mut() {
+            if pending.stream.is_some() || pending.result.is_some() {
+                continue;
+            }
+            if !self.connected && !(early_data && pending.replay_safe) {
+                continue;
+            }
+            match self.conn.open(Dir::Bi) {
+                Some(stream) => {
+                    pending.stream = Some(stream);
+                    pending.early = !self.connected;
+                    self.streams.insert(stream, *query);
+                }
+                // the server allows no more streams for now
+                None => break,
+            }
+        }
+    }
+
+    fn write_streams(&mut self) {
+        for pending in self.pending.values_mut() {
+            let stream = match pending.stream {
+                Some(stream) if pending.written < pending.request.len() => stream,
+                _ => continue,
+            };
+            match self.conn.write(stream, &pending.request[pending.written..]) {
+                Ok(n) => {
+                    pending.written += n;
+                    if pending.written == pending.request.len() {
+                        if let Err(e) = self.conn.finish(stream) {
+                            pending.fail(stream_error(e));
+                        }
+                    }
+                }
+                Err(WriteError::Blocked) => {}
+                Err(e) => pending.fail(stream_error(e)),
+            }
+        }
+    }
+
+    fn read_streams(&mut self) {
+        let mut chunk = [0; 4096];
+        for pending in self.pending.values_mut() {
+            let stream = match pending.stream {
+                Some(stream) if pending.result.is_none() => stream,
+                _ => continue,
+            };
+            loop {
+                match self.conn.read(stream, &mut chunk) {
+                    Ok(Some(n)) => {
+                        pending.response.extend_from_slice(&chunk[..n]);
+                        if pending.response.len() > 2 + MESSAGE_LIMIT {
+                            let _ = self
+                                .conn
+                                .stop_sending(stream, VarInt::from_u32(DOQ_PROTOCOL_ERROR));
+                            pending.fail(Error::DecodeError(DecodeError::ExtraneousData));
+                            break;
+                        }
+                    }
+                    // the server finished the stream after the response
+                    Ok(None) => {
+                        pending.result =
+                            Some(decode_response(&pending.response).map_err(Error::from));
+                        break;
+                    }
+                    Err(ReadError::Blocked) => break,
+                    Err(e) => {
+                        pending.fail(stream_error(e));
+                        break;
+                    }
+                }
+            }
+        }
+    }
+
+    fn transmit(&mut self) -> Result<(), Error> {
+        let now = Instant::now();
+        while let Some(transmit) = self.conn.poll_transmit(now, 1) {
+            self.sock
+                .send_datagram(&transmit.contents, transmit.destination)?;
+        }
+        while let Some(transmit) = self.endpoint.poll_transmit() {
+            self.sock
+                .send_datagram(&transmit.contents, transmit.destination)?;
+        }
+        Ok(())
+    }
+}
+
+impl<S: DatagramSocket> Drop for DoqConnection<S> {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+fn connection_lost(reason: &str) -> Error {
+    Error::IoError(io::Error::new(
+        io::ErrorKind::ConnectionAborted,
+        format!("DoQ connection lost: {}", reason),
+    ))
+}
+
+fn stream_error<E: fmt::Display>(err: E) -> Error {
+    Error::IoError(io::Error::new(
+        io::ErrorKind::ConnectionReset,
+        format!("DoQ stream failed: {}", err),
+    ))
+}
+
+/// Performs resolution operations over one DoQ connection at a time.
+///
+/// The connection is opened with the first query and reused by the later
+/// ones until it is closed, e.g., after the idle timeout, when the next query
+/// opens a new one which resumes the TLS session.
+pub struct DoqResolver {
+    server: SocketAddr,
+    server_name: String,
+    config: ClientConfig,
+    timeout: Duration,
+    conn: Mutex<Option<DoqConnection>>,
+}
+
+impl DoqResolver {
+    /// Constructs a resolver for the DoQ server, whose certificate must be
+    /// valid for the server name.
+    pub fn new(server: SocketAddr, server_name: &str) -> DoqResolver {
+        DoqResolver::with_config(server, server_name, client_config())
+    }
+
+    /// Constructs a resolver with the QUIC and TLS configuration, e.g., with
+    /// other root certificates.
+    pub fn with_config(server: SocketAddr, server_name: &str, config: ClientConfig) -> DoqResolver {
+        DoqResolver {
+            server: server,
+            server_name: server_name.to_owned(),
+            config: config,
+            timeout: DEFAULT_TIMEOUT,
+            conn: Mutex::new(None),
+        }
+    }
+
+    /// Sets the time to wait for a response, five seconds by default.
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    /// Sends the messages on their own streams of the connection at once and
+    /// returns the responses in the same order, see `send_message`.
+    pub fn send_messages(&self, msgs: &[Message]) -> Vec<Result<MessageBuf, Error>> {
+        let mut conn = self.conn.lock().unwrap();
+        if conn.as_ref().map_or(true, DoqConnection::is_closed) {
+            match DoqConnection::connect_with(self.server, &self.server_name, self.config.clone()) {
+                Ok(mut new) => {
+                    new.set_timeout(self.timeout);
+                    *conn = Some(new);
+                }
+                Err(e) => {
+                    let reason = e.to_string();
+                    return msgs.iter().map(|_| Err(connection_lost(&reason))).collect();
+                }
+            }
+        }
+        let results = conn.as_mut().unwrap().query_all(msgs);
+        results
+            .into_iter()
+            .map(|res| {
+                let msg = res?;
+                msg.get_error()?;
+                Ok(msg)
+            })
+            .collect()
+    }
+
+    /// Sends a message to the server and returns the response, or an error
+    /// with the response code, as `DnsResolver::send_message` does.
+    pub fn send_message(&self, msg: &Message) -> Result<MessageBuf, Error> {
+        self.send_messages(::std::slice::from_ref(msg))
+            .pop()
+            .unwrap()
+    }
+
+    /// Resolves a hostname to its IPv4 and IPv6 addresses, whose queries go on
+    /// two streams at once.
+    pub fn resolve_host(&self, host: &str) -> io::Result<ResolveHost> {
+        let msgs = [query(host, RecordType::A), query(host, RecordType::AAAA)];
+        let mut addrs = Vec::new();
+        let mut err = None;
+        for res in self.send_messages(&msgs) {
+            match res {
+                Ok(msg) => {
+                    let name = msg.answer_name();
+                    for rr in &msg.answer {
+                        if name
+                            .as_ref()
+                            .map_or(false, |name| !is_same_name(&rr.name, name))
+                        {
+                            continue;
+                        }
+                        match rr.r_type {
+                            RecordType::A => addrs.push(IpAddr::V4(
+                                rr.read_rdata::<A>().map_err(decode_error)?.address,
+                            )),
+                            RecordType::AAAA => addrs.push(IpAddr::V6(
+                                rr.read_rdata::<AAAA>().map_err(decode_error)?.address,
+                            )),
+                            _ => {}
+                        }
+                    }
+                }
+                Err(Error::IoError(e)) => err = Some(e),
+                Err(e) => {
+                    err = err.or_else(|| {
+                        Some(io::Error::new(
+                            io::ErrorKind::Other,
+                            format!("failed to resolve host: {}", e),
+                        ))
+                    })
+                }
+            }
+        }
+        match err {
+            Some(e) if addrs.is_empty() => Err(e),
+            _ if addrs.is_empty() => Err(io::Error::new(
+                io::ErrorKind::Other,
+                "failed to resolve host: name not found",
+            )),
+            _ => Ok(ResolveHost(addrs.into_iter())),
+        }
+    }
+
+    /// Requests a type of record from the server and returns the results.
+    pub fn resolve_record<Rec: Record>(&self, name: &str) -> io::Result<Vec<Rec>> {
+        let r_ty = Rec::record_type();
+        let msg = self.send_message(&query(name, r_ty)).map_err(|e| match e {
+            Error::IoError(e) => e,
+            e => io::Error::new(
+                io::ErrorKind::Other,
+                format!("failed to resolve record: {}", e),
+            ),
+        })?;
+        msg.answer
+            .iter()
+            .filter(|rr| rr.r_type == r_ty)
+            .map(|rr| rr.read_rdata::<Rec>().map_err(decode_error))
+            .collect()
+    }
+}
+
+fn query(name: &str, r_type: RecordType) -> Message<'static> {
+    let mut msg = Message::with_id(0);
+    msg.header.recursion_desired = true;
+    msg.question
+        .push(Question::new(name.to_owned(), r_type, Class::Internet));
+    msg
+}
+
+fn decode_error(e: DecodeError) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{decode_response, encode_query, is_replay_safe};
+    use crate::resolve::message::{DecodeError, Message, OpCode, Qr, Question};
+    use crate::resolve::record::{Class, RecordType};
+
+    fn query() -> Message<'static> {
+        let mut msg = Message::with_id(0x1234);
+        msg.question.push(Question::new(
+            "example.com.".to_owned(),
+            RecordType::A,
+            Class::Internet,
+        ));
+        msg
+    }
+
+    #[test]
+    fn test_stream_framing() {
+        let msg = query();
+        let data = encode_query(&msg).unwrap();
+        let encoded = msg.encode_vec().unwrap();
+        assert_eq!(data.len(), 2 + encoded.len());
+        assert_eq!(&data[..2], &(encoded.len() as u16).to_be_bytes()[..]);
+        // the message ID is 0, the rest is the same
+        assert_eq!(&data[2..4], &[0, 0][..]);
+        assert_eq!(&data[4..], &encoded[2..]);
+
+        let mut response = Message::decode(&data[2..]).unwrap();
+        response.header.qr = Qr::Response;
+        let mut stream = encode_query(&response).unwrap();
+        let decoded = decode_response(&stream).unwrap();
+        assert_eq!(decoded.header.qr, Qr::Response);
+        assert_eq!(decoded.question, msg.question);
+
+        // a second message on the stream or a cut one are protocol errors
+        stream.push(0);
+        assert_eq!(decode_response(&stream), Err(DecodeError::ExtraneousData));
+        stream.truncate(stream.len() - 2);
+        assert_eq!(decode_response(&stream), Err(DecodeError::ShortMessage));
+        assert_eq!(decode_response(&[0]), Err(DecodeError::ShortMessage));
+        // as is a response with another message ID
+        let mut stream = encode_query(&response).unwrap();
+        stream[3] = 1;
+        assert_eq!(decode_response(&stream), Err(DecodeError::InvalidMessage));
+    }
+
+    #[test]
+    fn test_replay_safe() {
+        let mut msg = query();
+        assert!(is_replay_safe(&msg));
+        msg.header.op = OpCode::Update;
+        assert!(!is_replay_safe(&msg));
+        msg.header.op = OpCode::Notify;
+        assert!(!is_replay_safe(&msg));
+    }
+}
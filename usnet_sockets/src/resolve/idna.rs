@@ -1,7 +1,13 @@
 //! Implements RFC 3490, Internationalized Domain Names in Applications,
 //! encoding for domain name labels containing Unicode.
+//!
+//! The conversion to ASCII follows the UTS #46 processing of the `idna` crate,
+//! i.e., Unicode names are case folded and normalized, and the ideographic and
+//! fullwidth full stops separate labels like `.` does.
 
 use std::borrow::Cow::{self, Borrowed, Owned};
+use std::error;
+use std::fmt;
 
 use crate::external_idna;
 
@@ -9,9 +15,22 @@ use crate::external_idna;
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub struct Error;
 
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("invalid internationalized domain name")
+    }
+}
+
+impl error::Error for Error {}
+
 /// Converts a label or host to its ASCII format. If the string is already ASCII,
 /// it will be returned unmodified. If an error is encountered in encoding,
 /// `Err` will be returned.
+///
+/// ASCII input includes names with labels which are already encoded, these
+/// `xn--` labels are passed through unchanged. Labels mixing scripts are
+/// encoded as UTS #46 allows them; code points which it disallows, e.g.,
+/// noncharacters, return `Err`.
 pub fn to_ascii(s: &str) -> Result<Cow<str>, Error> {
     if s.is_ascii() {
         Ok(Borrowed(s))
@@ -26,10 +45,8 @@ pub fn to_ascii(s: &str) -> Result<Cow<str>, Error> {
 /// internationalized domain name, it will be returned unmodified. If an error
 /// is encountered in decoding, `Err` will be returned.
 pub fn to_unicode(s: &str) -> Result<Cow<str>, Error> {
-    let is_unicode = s.split('.').any(|s| s.starts_with("xn--"));
-
-    if is_unicode {
-        match external_idna::domain_to_unicode(s) {
+    if s.split('.').any(is_a_label) {
+        match external_idna::domain_to_unicode(&s.to_ascii_lowercase()) {
             (s, Ok(_)) => Ok(Owned(s)),
             (_, Err(_)) => Err(Error),
         }
@@ -38,9 +55,31 @@ pub fn to_unicode(s: &str) -> Result<Cow<str>, Error> {
     }
 }
 
+/// Converts a host to its Unicode format for display. Unlike `to_unicode`,
+/// this never fails: labels which cannot be decoded are kept in their
+/// ASCII format.
+pub fn to_unicode_lossy(s: &str) -> Cow<str> {
+    if !s.split('.').any(is_a_label) {
+        return Borrowed(s);
+    }
+
+    let labels = s
+        .split('.')
+        .map(|label| to_unicode(label).unwrap_or(Borrowed(label)))
+        .collect::<Vec<_>>();
+
+    Owned(labels.join("."))
+}
+
+/// Returns whether the label has the ACE prefix, case insensitively as DNS
+/// compares names.
+pub(crate) fn is_a_label(label: &str) -> bool {
+    label.len() >= 4 && label.as_bytes()[..4].eq_ignore_ascii_case(b"xn--")
+}
+
 #[cfg(test)]
 mod test {
-    use super::{to_ascii, to_unicode};
+    use super::{to_ascii, to_unicode, to_unicode_lossy, Error};
 
     static SAMPLE_HOSTS: &'static [(&'static str, &'static str)] = &[
         ("bücher.de.", "xn--bcher-kva.de."),
@@ -61,4 +100,31 @@ mod test {
             assert_eq!(to_unicode(uni).unwrap(), uni);
         }
     }
+
+    #[test]
+    fn test_mapping() {
+        assert_eq!(to_ascii("MÜNCHEN.de").unwrap(), "xn--mnchen-3ya.de");
+        assert_eq!(to_ascii("münchen。de").unwrap(), "xn--mnchen-3ya.de");
+        // Latin and Cyrillic in one label
+        assert_eq!(to_ascii("pаypal.com").unwrap(), "xn--pypal-4ve.com");
+    }
+
+    #[test]
+    fn test_encoded_input() {
+        assert_eq!(to_ascii("xn--mnchen-3ya.de").unwrap(), "xn--mnchen-3ya.de");
+        assert_eq!(to_ascii("XN--MNCHEN-3YA.de").unwrap(), "XN--MNCHEN-3YA.de");
+        assert_eq!(to_unicode("XN--MNCHEN-3YA.de").unwrap(), "münchen.de");
+    }
+
+    #[test]
+    fn test_invalid() {
+        assert_eq!(to_ascii("bad\u{ffff}.de"), Err(Error));
+        assert_eq!(to_unicode("xn--a_b.de"), Err(Error));
+        assert_eq!(to_unicode_lossy("xn--a_b.de"), "xn--a_b.de");
+        assert_eq!(
+            to_unicode_lossy("xn--a_b.xn--mnchen-3ya.de"),
+            "xn--a_b.münchen.de"
+        );
+        assert_eq!(to_unicode_lossy("example.com"), "example.com");
+    }
 }
@@ -0,0 +1,285 @@
+//! Iterative resolution from the root name servers
+
+use std::collections::BTreeMap;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use super::message::{OwnedMessage, RCode};
+use super::name::{is_same_name, is_subdomain, DnsName};
+use super::record::RecordData;
+
+/// IPv4 addresses of the root name servers `a.root-servers.net.` to
+/// `m.root-servers.net.`
+pub const ROOT_SERVERS: [Ipv4Addr; 13] = [
+    Ipv4Addr::new(198, 41, 0, 4),
+    Ipv4Addr::new(170, 247, 170, 2),
+    Ipv4Addr::new(192, 33, 4, 12),
+    Ipv4Addr::new(199, 7, 91, 13),
+    Ipv4Addr::new(192, 203, 230, 10),
+    Ipv4Addr::new(192, 5, 5, 241),
+    Ipv4Addr::new(192, 112, 36, 4),
+    Ipv4Addr::new(198, 97, 190, 53),
+    Ipv4Addr::new(192, 36, 148, 17),
+    Ipv4Addr::new(192, 58, 128, 30),
+    Ipv4Addr::new(193, 0, 14, 129),
+    Ipv4Addr::new(199, 7, 83, 42),
+    Ipv4Addr::new(202, 12, 27, 33),
+];
+
+/// Maximum number of queries of one iterative resolution, including those
+/// for the addresses of name servers without glue records
+pub const QUERY_LIMIT: usize = 32;
+
+/// Delegations are not cached longer than a day, whatever their TTL
+const MAX_DELEGATION_TTL: u32 = 86400;
+
+/// Returns the socket addresses of the root name servers, the default of
+/// `DnsConfig::root_hints`.
+pub fn root_hints() -> Vec<SocketAddr> {
+    ROOT_SERVERS
+        .iter()
+        .map(|&ip| SocketAddr::new(IpAddr::V4(ip), 53))
+        .collect()
+}
+
+/// A query of an iterative resolution
+#[derive(Clone, Debug)]
+pub struct TraceStep {
+    /// Zone whose name server was asked, `.` for the root
+    pub zone: String,
+    /// Name server which answered
+    pub server: SocketAddr,
+    /// Its response, a referral to a child zone or the answer
+    pub response: OwnedMessage,
+}
+
+/// The queries of an iterative resolution, see `DnsResolver::trace`
+#[derive(Clone, Debug)]
+pub struct Trace {
+    /// Queries in the order they were sent, including those for the addresses
+    /// of name servers which were referred to without glue records
+    pub steps: Vec<TraceStep>,
+    /// Response of the authoritative name server for the name
+    pub answer: OwnedMessage,
+}
+
+impl Trace {
+    /// Returns the name servers which were asked, in order.
+    pub fn servers(&self) -> Vec<SocketAddr> {
+        self.steps.iter().map(|step| step.server).collect()
+    }
+}
+
+/// A delegation to the name servers of a child zone
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct Referral {
+    pub zone: String,
+    pub name_servers: Vec<String>,
+    pub ttl: u32,
+}
+
+/// Returns the referral in the response of a name server of the zone, or
+/// `None` if the response is the answer, e.g., also a name error or an empty
+/// answer with the SOA record of the zone.
+pub(crate) fn referral(name: &str, zone: &str, response: &OwnedMessage) -> Option<Referral> {
+    if response.header.rcode != RCode::NoError || !response.answer.is_empty() {
+        return None;
+    }
+    let mut referral: Option<Referral> = None;
+    for rr in response.authority.iter() {
+        let ns = match rr.data {
+            RecordData::Ns(ref ns) => ns,
+            _ => continue,
+        };
+        // a referral has to get closer to the name
+        if is_same_name(&rr.name, zone)
+            || !is_subdomain(&rr.name, zone)
+            || !is_subdomain(name, &rr.name)
+        {
+            continue;
+        }
+        match referral {
+            Some(ref mut referral) if is_same_name(&referral.zone, &rr.name) => {
+                referral.name_servers.push(ns.name.clone());
+                referral.ttl = referral.ttl.min(rr.ttl);
+            }
+            Some(_) => {}
+            None => {
+                referral = Some(Referral {
+                    zone: rr.name.clone(),
+                    name_servers: vec![ns.name.clone()],
+                    ttl: rr.ttl,
+                })
+            }
+        }
+    }
+    referral
+}
+
+/// Returns the addresses of the name servers of the referral in the additional
+/// section of the response, for port 53.
+pub(crate) fn glue(referral: &Referral, response: &OwnedMessage) -> Vec<SocketAddr> {
+    let mut addrs = Vec::new();
+    for rr in response.additional.iter() {
+        if !referral
+            .name_servers
+            .iter()
+            .any(|ns| is_same_name(ns, &rr.name))
+        {
+            continue;
+        }
+        match rr.data {
+            RecordData::A(ref a) => addrs.push(SocketAddr::new(IpAddr::V4(a.address), 53)),
+            RecordData::AAAA(ref aaaa) => addrs.push(SocketAddr::new(IpAddr::V6(aaaa.address), 53)),
+            _ => {}
+        }
+    }
+    // IPv4 first, since the UDP sockets of the stack may be IPv4 only
+    addrs.sort_by_key(|addr| addr.is_ipv6());
+    addrs
+}
+
+/// Returns the addresses of the A records in the answer.
+pub(crate) fn answer_addrs(response: &OwnedMessage) -> Vec<SocketAddr> {
+    response
+        .answer
+        .iter()
+        .filter_map(|rr| match rr.data {
+            RecordData::A(ref a) => Some(SocketAddr::new(IpAddr::V4(a.address), 53)),
+            _ => None,
+        })
+        .collect()
+}
+
+/// The name servers of zones learned from referrals, until their TTL expires
+#[derive(Default)]
+pub(crate) struct Delegations {
+    zones: Mutex<BTreeMap<DnsName, (Vec<SocketAddr>, Instant)>>,
+}
+
+impl Delegations {
+    /// Returns the zone closest to the name with cached name servers.
+    pub fn closest(&self, name: &str) -> Option<(String, Vec<SocketAddr>)> {
+        let now = Instant::now();
+        let mut zones = self.zones.lock().unwrap();
+        zones.retain(|_, &mut (_, expiry)| expiry > now);
+        zones
+            .iter()
+            .filter(|&(zone, _)| is_subdomain(name, zone.as_str()))
+            .max_by_key(|&(zone, _)| zone.label_count())
+            .map(|(zone, &(ref servers, _))| (zone.to_string(), servers.clone()))
+    }
+
+    pub fn insert(&self, zone: &str, servers: Vec<SocketAddr>, ttl: u32) {
+        let ttl = Duration::from_secs(ttl.min(MAX_DELEGATION_TTL) as u64);
+        self.zones.lock().unwrap().insert(
+            DnsName::new(zone).normalize(),
+            (servers, Instant::now() + ttl),
+        );
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{glue, referral, Delegations};
+    use crate::resolve::message::{OwnedMessage, OwnedRecord, RCode};
+    use crate::resolve::name::is_subdomain;
+    use crate::resolve::record::{Class, Ns, RecordData, Soa, A};
+    use std::net::{Ipv4Addr, SocketAddr};
+
+    fn record(name: &str, data: RecordData) -> OwnedRecord {
+        OwnedRecord::new(name.to_owned(), Class::Internet, 3600, data)
+    }
+
+    fn ns(zone: &str, name: &str) -> OwnedRecord {
+        record(
+            zone,
+            RecordData::Ns(Ns {
+                name: name.to_owned(),
+            }),
+        )
+    }
+
+    fn a(name: &str, last: u8) -> OwnedRecord {
+        record(
+            name,
+            RecordData::A(A {
+                address: Ipv4Addr::new(192, 0, 2, last),
+            }),
+        )
+    }
+
+    #[test]
+    fn test_referral() {
+        let mut response = OwnedMessage::default();
+        response.authority = vec![
+            ns("com.", "a.gtld-servers.net."),
+            ns("com.", "b.gtld-servers.net."),
+        ];
+        response.additional = vec![
+            a("a.gtld-servers.net.", 1),
+            a("b.gtld-servers.net.", 2),
+            a("unrelated.net.", 3),
+        ];
+        let r = referral("www.example.com", ".", &response).unwrap();
+        assert_eq!(r.zone, "com.");
+        assert_eq!(
+            r.name_servers,
+            vec!["a.gtld-servers.net.", "b.gtld-servers.net."]
+        );
+        assert_eq!(
+            glue(&r, &response),
+            vec![
+                "192.0.2.1:53".parse::<SocketAddr>().unwrap(),
+                "192.0.2.2:53".parse().unwrap(),
+            ]
+        );
+
+        // the servers of com. are no referral for com. itself or another name
+        assert_eq!(referral("www.example.com.", "com.", &response), None);
+        assert_eq!(referral("www.example.org.", ".", &response), None);
+
+        // nor is an empty answer with the SOA record or an error
+        let mut nodata = OwnedMessage::default();
+        nodata.authority = vec![record(
+            "example.com.",
+            RecordData::Soa(Soa {
+                mname: "ns.example.com.".to_owned(),
+                rname: "hostmaster.example.com.".to_owned(),
+                serial: 1,
+                refresh: 3600,
+                retry: 600,
+                expire: 86400,
+                minimum: 300,
+            }),
+        )];
+        assert_eq!(referral("www.example.com.", "example.com.", &nodata), None);
+        response.header.rcode = RCode::NameError;
+        assert_eq!(referral("www.example.com.", ".", &response), None);
+    }
+
+    #[test]
+    fn test_delegations() {
+        assert!(is_subdomain("www.Example.com", "example.COM."));
+        assert!(is_subdomain("example.com.", "."));
+        assert!(!is_subdomain("www.badexample.com.", "example.com."));
+
+        let delegations = Delegations::default();
+        let com = vec!["192.0.2.1:53".parse().unwrap()];
+        let example = vec!["192.0.2.2:53".parse().unwrap()];
+        delegations.insert("com.", com.clone(), 3600);
+        delegations.insert("Example.com.", example.clone(), 3600);
+        delegations.insert("org.", com.clone(), 0);
+        assert_eq!(
+            delegations.closest("www.example.com."),
+            Some(("example.com.".to_owned(), example))
+        );
+        assert_eq!(
+            delegations.closest("www.example2.com."),
+            Some(("com.".to_owned(), com))
+        );
+        // expired
+        assert_eq!(delegations.closest("example.org."), None);
+    }
+}
@@ -0,0 +1,251 @@
+//! Multicast DNS (RFC 6762) for names under `local.`
+//!
+//! The userspace network stack has no multicast, so the queries go through
+//! kernel sockets on port 5353 which joined the mDNS groups, next to other
+//! mDNS responders of the host. The first query asks for unicast responses
+//! (QU), and if none arrives within the window, it is sent once more for
+//! multicast responses (QM). All responses within the window are collected,
+//! since several hosts may answer.
+
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::apimultithread::{bind_kernel_udp, UdpBindOptions};
+
+use super::message::{Message, OpCode, OwnedMessage, OwnedRecord, Qr, Question, RCode};
+use super::name::{is_same_name, is_subdomain};
+use super::record::{Class, RecordType};
+use super::socket::Error;
+
+/// IPv4 group of mDNS
+pub const MDNS_V4: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 251);
+
+/// IPv6 group of mDNS, link-local scope
+pub const MDNS_V6: Ipv6Addr = Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 0xfb);
+
+/// UDP port of mDNS, which responses have to come from
+pub const MDNS_PORT: u16 = 5353;
+
+/// Top bit of the class of a question which asks for a unicast response (QU)
+pub const UNICAST_RESPONSE: u16 = 0x8000;
+
+/// Maximum size of an mDNS message (RFC 6762 section 17)
+const MAX_MESSAGE: usize = 9000;
+
+/// Whether the name is under `local.` and resolved through mDNS
+pub fn is_local(name: &str) -> bool {
+    is_subdomain(name, "local.")
+}
+
+/// Sends the question to the mDNS groups and returns the responses which
+/// arrived within the window with their senders, none if no host answered.
+/// Without responses to the QU query, this takes twice the window.
+///
+/// Responses are accepted from any sender with port 5353, if they answer the
+/// name of the question. The hop limit of 255 (RFC 6762 section 11) is not
+/// checked, since the kernel sockets do not report it.
+pub fn query(
+    question: &Question,
+    window: Duration,
+) -> Result<Vec<(OwnedMessage, SocketAddr)>, Error> {
+    let v4 = bind_group(IpAddr::V4(Ipv4Addr::UNSPECIFIED))?;
+    // IPv6 only if the host has it
+    let v6 = bind_group(IpAddr::V6(Ipv6Addr::UNSPECIFIED))
+        .ok()
+        .map(|sock| {
+            let question = question.clone();
+            thread::spawn(move || collect(&sock, &question, window))
+        });
+    let mut responses = collect(&v4, question, window)?;
+    if let Some(v6) = v6 {
+        if let Ok(Ok(more)) = v6.join() {
+            responses.extend(more);
+        }
+    }
+    Ok(responses)
+}
+
+/// Combines the responses of several hosts into one, with each answer once.
+pub fn merge(question: Question, responses: &[(OwnedMessage, SocketAddr)]) -> OwnedMessage {
+    let mut msg = OwnedMessage::default();
+    msg.header.qr = Qr::Response;
+    msg.header.authoritative = true;
+    msg.question.push(question);
+    for &(ref response, _) in responses.iter() {
+        for rr in response.answer.iter() {
+            if !msg.answer.iter().any(|a| same_record(a, rr)) {
+                msg.answer.push(rr.clone());
+            }
+        }
+        for rr in response.additional.iter() {
+            if !msg.additional.iter().any(|a| same_record(a, rr)) {
+                msg.additional.push(rr.clone());
+            }
+        }
+    }
+    msg
+}
+
+fn same_record(a: &OwnedRecord, b: &OwnedRecord) -> bool {
+    is_same_name(&a.name, &b.name) && a.r_type == b.r_type && a.data == b.data
+}
+
+/// Binds a kernel socket to the mDNS port, which other responders of the host
+/// may share, and joins the group of the address family.
+fn bind_group(ip: IpAddr) -> io::Result<UdpSocket> {
+    let options = UdpBindOptions {
+        reuse_address: true,
+        ..Default::default()
+    };
+    let sock = bind_kernel_udp(SocketAddr::new(ip, MDNS_PORT), options)?;
+    match ip {
+        IpAddr::V4(_) => {
+            sock.join_multicast_v4(&MDNS_V4, &Ipv4Addr::UNSPECIFIED)?;
+            sock.set_multicast_ttl_v4(255)?;
+        }
+        IpAddr::V6(_) => sock.join_multicast_v6(&MDNS_V6, 0)?,
+    }
+    Ok(sock)
+}
+
+fn group(sock: &UdpSocket) -> io::Result<SocketAddr> {
+    Ok(match sock.local_addr()? {
+        SocketAddr::V4(_) => SocketAddr::new(IpAddr::V4(MDNS_V4), MDNS_PORT),
+        SocketAddr::V6(_) => SocketAddr::new(IpAddr::V6(MDNS_V6), MDNS_PORT),
+    })
+}
+
+/// Sends the question as QU and then as QM if there was no response, and
+/// collects the responses of each within the window.
+fn collect(
+    sock: &UdpSocket,
+    question: &Question,
+    window: Duration,
+) -> Result<Vec<(OwnedMessage, SocketAddr)>, Error> {
+    let group = group(sock)?;
+    let mut responses = Vec::new();
+    let mut buf = vec![0; MAX_MESSAGE];
+    for &unicast in [true, false].iter() {
+        // the ID is zero and recursion is not desired (RFC 6762 section 18)
+        let mut query = Message::with_id(0);
+        query.question.push(mdns_question(question, unicast));
+        sock.send_to(&query.encode_vec()?, group)?;
+        let deadline = Instant::now() + window;
+        loop {
+            let now = Instant::now();
+            if now >= deadline {
+                break;
+            }
+            sock.set_read_timeout(Some(deadline - now))?;
+            let (n, from) = match sock.recv_from(&mut buf) {
+                Ok(received) => received,
+                Err(ref e)
+                    if e.kind() == io::ErrorKind::WouldBlock
+                        || e.kind() == io::ErrorKind::TimedOut =>
+                {
+                    break
+                }
+                Err(e) => return Err(Error::IoError(e)),
+            };
+            if let Some(msg) = accept(&buf[..n], &from, question) {
+                responses.push((msg, from));
+            }
+        }
+        if !responses.is_empty() {
+            break;
+        }
+    }
+    Ok(responses)
+}
+
+/// Returns the question with the unicast-response bit in its class for QU.
+fn mdns_question(question: &Question, unicast: bool) -> Question {
+    let mut question = question.clone();
+    if unicast {
+        question.q_class = Class::Other(question.q_class.to_u16() | UNICAST_RESPONSE);
+    }
+    question
+}
+
+/// Decodes a response which answers the question, other datagrams on the
+/// group are ignored: queries, also the own ones which loop back, and answers
+/// for other names.
+fn accept(data: &[u8], from: &SocketAddr, question: &Question) -> Option<OwnedMessage> {
+    if from.port() != MDNS_PORT {
+        return None;
+    }
+    let msg = OwnedMessage::decode(data).ok()?;
+    if msg.header.qr != Qr::Response
+        || msg.header.op != OpCode::Query
+        || msg.header.rcode != RCode::NoError
+    {
+        return None;
+    }
+    let answers = msg.answer.iter().any(|rr| {
+        is_same_name(&rr.name, &question.name)
+            && (rr.r_type == question.q_type
+                || rr.r_type == RecordType::CName
+                || question.q_type == RecordType::Any)
+    });
+    if answers {
+        Some(msg)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{accept, is_local, mdns_question, merge, MDNS_PORT};
+    use crate::resolve::message::{OwnedMessage, OwnedRecord, Qr, Question};
+    use crate::resolve::record::{Class, RecordData, RecordType, A};
+    use std::net::{Ipv4Addr, SocketAddr};
+
+    fn response(last: u8) -> OwnedMessage {
+        let mut msg = OwnedMessage::default();
+        msg.header.qr = Qr::Response;
+        msg.answer.push(OwnedRecord::new(
+            "Printer.local.".to_owned(),
+            // the cache-flush bit
+            Class::Other(0x8001),
+            120,
+            RecordData::A(A {
+                address: Ipv4Addr::new(192, 168, 1, last),
+            }),
+        ));
+        msg
+    }
+
+    #[test]
+    fn test_mdns() {
+        assert!(is_local("printer.local"));
+        assert!(is_local("x.printer.LOCAL."));
+        assert!(!is_local("printer.localhost."));
+        assert!(!is_local("local.example.com"));
+
+        let question = Question::new("printer.local.".to_owned(), RecordType::A, Class::Internet);
+        assert_eq!(mdns_question(&question, true).q_class, Class::Other(0x8001));
+        assert_eq!(mdns_question(&question, false).q_class, Class::Internet);
+
+        let from = SocketAddr::new(Ipv4Addr::new(192, 168, 1, 7).into(), MDNS_PORT);
+        let data = response(7).encode_to_vec().unwrap();
+        let msg = accept(&data, &from, &question).unwrap();
+        assert_eq!(msg.answer.len(), 1);
+        // not from the mDNS port, another name, or a query
+        let legacy = SocketAddr::new(from.ip(), 40000);
+        assert!(accept(&data, &legacy, &question).is_none());
+        let other = Question::new("scanner.local.".to_owned(), RecordType::A, Class::Internet);
+        assert!(accept(&data, &from, &other).is_none());
+        let mut query = response(7);
+        query.header.qr = Qr::Query;
+        assert!(accept(&query.encode_to_vec().unwrap(), &from, &question).is_none());
+
+        // two hosts answer, one of them twice over IPv4 and IPv6
+        let responses = [(msg.clone(), from), (response(8), from), (msg, from)];
+        let merged = merge(question, &responses);
+        assert_eq!(merged.answer.len(), 2);
+        assert_eq!(merged.question[0].name, "printer.local.");
+    }
+}
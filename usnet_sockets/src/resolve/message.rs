@@ -6,32 +6,282 @@ use std::default::Default;
 use std::fmt;
 use std::io::{Cursor, Read, Write};
 use std::mem::{transmute, zeroed};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 use std::slice::Iter;
 use std::str::from_utf8_unchecked;
 use std::vec::IntoIter;
 
-use rand::random;
-
 use super::idna;
-use super::record::{Class, Record, RecordType};
+use super::name::{is_same_case, is_same_name, is_subdomain, DnsName, Labels};
+use super::record::{
+    CName, Caa, Class, Dname, Dnskey, Ds, Mx, Naptr, Ns, Nsec, Nsec3, Record, RecordData,
+    RecordType, Rrsig, Srv, Txt, A, AAAA,
+};
+use super::rng::{OsRandom, RandSource};
 
 /// Maximum size of a DNS message, in bytes.
 pub const MESSAGE_LIMIT: usize = 0xffff;
 
+/// Maximum number of CNAME and DNAME records followed to the answer name
+pub(crate) const ALIAS_LIMIT: usize = 16;
+
 /// Maximum length of a name segment (i.e. a `.`-separated identifier).
 pub const LABEL_LIMIT: usize = 63;
 
 /// Maximum total length of a name, in encoded format.
 pub const NAME_LIMIT: usize = 255;
 
-/// An error response code received in a response message.
-#[derive(Copy, Clone, Debug, Eq, PartialEq)]
-pub struct DnsError(pub RCode);
+/// An error response code received in a response message, with an extended
+/// error for responses which failed DNSSEC validation (see `DnsResolver::set_trust_anchor`).
+///
+/// An error can be compared with a response code regardless of the extended
+/// error, e.g., `assert_eq!(err, RCode::NameError)`.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub struct DnsError(pub RCode, pub Option<ExtendedError>);
+
+impl DnsError {
+    /// Returns whether the response failed DNSSEC validation ("bogus").
+    pub fn is_bogus(&self) -> bool {
+        self.1.map_or(false, |e| e.is_bogus())
+    }
+
+    /// Returns whether a prerequisite of an UPDATE failed (`YxDomain`,
+    /// `YxRrSet`, `NxDomain` as `NameError`, or `NxRrSet`), so the zone was
+    /// not changed because of its content rather than an error of the server.
+    pub fn is_prerequisite_failure(&self) -> bool {
+        match self.0 {
+            RCode::YxDomain | RCode::YxRrSet | RCode::NxRrSet | RCode::NameError => true,
+            _ => false,
+        }
+    }
+}
+
+impl From<RCode> for DnsError {
+    fn from(rcode: RCode) -> DnsError {
+        DnsError(rcode, None)
+    }
+}
+
+impl PartialEq<RCode> for DnsError {
+    fn eq(&self, rcode: &RCode) -> bool {
+        self.0 == *rcode
+    }
+}
 
 impl fmt::Display for DnsError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        f.write_str(self.0.get_error())
+        match self.1 {
+            Some(e) => write!(f, "{}: {}", self.0.get_error(), e),
+            None => f.write_str(self.0.get_error()),
+        }
+    }
+}
+
+/// An extended DNS error code, as defined by RFC 8914.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub struct ExtendedError(pub u16);
+
+impl ExtendedError {
+    /// The DNSKEY RRset only uses unsupported algorithms
+    pub const UNSUPPORTED_DNSKEY_ALGORITHM: ExtendedError = ExtendedError(1);
+    /// The DS RRset only uses unsupported digest types
+    pub const UNSUPPORTED_DS_DIGEST: ExtendedError = ExtendedError(2);
+    /// DNSSEC validation failed for another reason
+    pub const DNSSEC_BOGUS: ExtendedError = ExtendedError(6);
+    /// Every signature of the RRset has expired
+    pub const SIGNATURE_EXPIRED: ExtendedError = ExtendedError(7);
+    /// No signature of the RRset is valid yet
+    pub const SIGNATURE_NOT_YET_VALID: ExtendedError = ExtendedError(8);
+    /// No DNSKEY matches the DS records of the zone
+    pub const DNSKEY_MISSING: ExtendedError = ExtendedError(9);
+    /// An RRset of a signed zone has no signatures
+    pub const RRSIGS_MISSING: ExtendedError = ExtendedError(10);
+    /// No DNSKEY of the zone has the zone key bit set
+    pub const NO_ZONE_KEY_BIT_SET: ExtendedError = ExtendedError(11);
+    /// The nonexistence of a name or type in a signed zone is not proven
+    pub const NSEC_MISSING: ExtendedError = ExtendedError(12);
+
+    /// Returns a description of the error code.
+    pub fn description(&self) -> &'static str {
+        match self.0 {
+            0 => "other error",
+            1 => "unsupported DNSKEY algorithm",
+            2 => "unsupported DS digest type",
+            3 => "stale answer",
+            4 => "forged answer",
+            5 => "DNSSEC indeterminate",
+            6 => "DNSSEC bogus",
+            7 => "signature expired",
+            8 => "signature not yet valid",
+            9 => "DNSKEY missing",
+            10 => "RRSIGs missing",
+            11 => "no zone key bit set",
+            12 => "NSEC missing",
+            _ => "unknown extended error",
+        }
+    }
+
+    /// Returns whether the code indicates a failed DNSSEC validation.
+    pub fn is_bogus(&self) -> bool {
+        self.0 >= 6 && self.0 <= 12
+    }
+}
+
+impl fmt::Display for ExtendedError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.description())
+    }
+}
+
+/// EDNS option code of the name server identifier option
+pub const EDNS_NSID: u16 = 3;
+
+/// EDNS option code of the client subnet option
+pub const EDNS_CLIENT_SUBNET: u16 = 8;
+
+/// EDNS option code of the cookie option
+pub const EDNS_COOKIE: u16 = 10;
+
+/// The EDNS Client Subnet option (RFC 7871), which tells a server the network
+/// of the client a query is for, e.g., so that a CDN answers with the
+/// addresses closest to it.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct ClientSubnet {
+    /// Network address, without bits after the source prefix length
+    pub address: IpAddr,
+    /// Number of leading bits of the address disclosed to the server
+    pub source_prefix: u8,
+    /// In a response, the number of leading bits of the address for which
+    /// the answer is valid; 0 in a query
+    pub scope_prefix: u8,
+}
+
+impl ClientSubnet {
+    /// Constructs the option for the network of the address with the prefix
+    /// length, at most 32 for IPv4 and 128 for IPv6, and clears the bits of
+    /// the address after it.
+    pub fn new(address: IpAddr, source_prefix: u8) -> ClientSubnet {
+        let (address, source_prefix) = match address {
+            IpAddr::V4(ip) => {
+                let prefix = source_prefix.min(32);
+                let mask = u32::max_value()
+                    .checked_shl(32 - prefix as u32)
+                    .unwrap_or(0);
+                (IpAddr::V4(Ipv4Addr::from(u32::from(ip) & mask)), prefix)
+            }
+            IpAddr::V6(ip) => {
+                let prefix = source_prefix.min(128);
+                let mask = u128::max_value()
+                    .checked_shl(128 - prefix as u32)
+                    .unwrap_or(0);
+                (IpAddr::V6(Ipv6Addr::from(u128::from(ip) & mask)), prefix)
+            }
+        };
+        ClientSubnet {
+            address: address,
+            source_prefix: source_prefix,
+            scope_prefix: 0,
+        }
+    }
+
+    /// Constructs the option with the source prefix length 0, which opts out
+    /// of client subnets: servers must not use the address of the resolver in
+    /// its place (RFC 7871 section 7.1.2).
+    pub fn opt_out() -> ClientSubnet {
+        ClientSubnet::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0)
+    }
+
+    /// Returns the network for which the answer to the query of a response
+    /// with the option is valid, i.e., the address truncated to the scope
+    /// prefix length, e.g., to key a cache of the answers.
+    pub fn scope(&self) -> ClientSubnet {
+        ClientSubnet::new(self.address, self.scope_prefix)
+    }
+
+    /// Decodes the option from its data in an OPT record.
+    pub fn decode(data: &[u8]) -> Result<ClientSubnet, DecodeError> {
+        if data.len() < 4 {
+            return Err(DecodeError::ShortMessage);
+        }
+        let family = u16::from_be_bytes([data[0], data[1]]);
+        let (source_prefix, scope_prefix) = (data[2], data[3]);
+        let addr = &data[4..];
+        // only the bytes of the prefix are sent
+        if addr.len() != (source_prefix as usize + 7) / 8 {
+            return Err(DecodeError::InvalidMessage);
+        }
+        let address = match family {
+            1 if source_prefix <= 32 && scope_prefix <= 32 => {
+                let mut octets = [0; 4];
+                octets[..addr.len()].copy_from_slice(addr);
+                IpAddr::V4(octets.into())
+            }
+            2 if source_prefix <= 128 && scope_prefix <= 128 => {
+                let mut octets = [0; 16];
+                octets[..addr.len()].copy_from_slice(addr);
+                IpAddr::V6(octets.into())
+            }
+            _ => return Err(DecodeError::InvalidMessage),
+        };
+        if ClientSubnet::new(address, source_prefix).address != address {
+            return Err(DecodeError::InvalidMessage);
+        }
+        Ok(ClientSubnet {
+            address: address,
+            source_prefix: source_prefix,
+            scope_prefix: scope_prefix,
+        })
+    }
+
+    /// Encodes the option data for an OPT record.
+    pub fn encode(&self) -> Vec<u8> {
+        let (family, octets) = match self.address {
+            IpAddr::V4(ip) => (1u16, ip.octets().to_vec()),
+            IpAddr::V6(ip) => (2, ip.octets().to_vec()),
+        };
+        let mut data = family.to_be_bytes().to_vec();
+        data.push(self.source_prefix);
+        data.push(self.scope_prefix);
+        data.extend_from_slice(&octets[..(self.source_prefix as usize + 7) / 8]);
+        data
+    }
+}
+
+/// Returns the data of the first option with the code in the data of an OPT
+/// record.
+fn find_edns_option(rdata: &[u8], code: u16) -> Option<&[u8]> {
+    let mut rest = rdata;
+    while rest.len() >= 4 {
+        let option = u16::from_be_bytes([rest[0], rest[1]]);
+        let len = u16::from_be_bytes([rest[2], rest[3]]) as usize;
+        if rest.len() < 4 + len {
+            return None;
+        }
+        if option == code {
+            return Some(&rest[4..4 + len]);
+        }
+        rest = &rest[4 + len..];
+    }
+    None
+}
+
+/// Returns the data of an OPT record with the options of the code replaced by
+/// one with the value.
+fn replace_edns_option(rdata: &[u8], code: u16, value: &[u8]) -> Vec<u8> {
+    let mut data = Vec::with_capacity(rdata.len() + 4 + value.len());
+    let mut rest = rdata;
+    while rest.len() >= 4 {
+        let option = u16::from_be_bytes([rest[0], rest[1]]);
+        let len = (u16::from_be_bytes([rest[2], rest[3]]) as usize).min(rest.len() - 4);
+        if option != code {
+            data.extend_from_slice(&rest[..4 + len]);
+        }
+        rest = &rest[4 + len..];
     }
+    data.extend_from_slice(&code.to_be_bytes());
+    data.extend_from_slice(&(value.len() as u16).to_be_bytes());
+    data.extend_from_slice(value);
+    data
 }
 
 /// Represents an error in decoding a DNS message.
@@ -45,6 +295,13 @@ pub enum DecodeError {
     InvalidMessage,
     /// An invalid name was encountered
     InvalidName,
+    /// A label length byte with the reserved prefix `01` or `10`, i.e., in the
+    /// range 0x40 to 0xBF, which is neither a label of at most 63 bytes nor a
+    /// compression pointer
+    InvalidLabel,
+    /// A name longer than `NAME_LIMIT` bytes in wire format, including the
+    /// labels reached through compression pointers
+    NameTooLong,
 }
 
 impl fmt::Display for DecodeError {
@@ -54,6 +311,8 @@ impl fmt::Display for DecodeError {
             DecodeError::ShortMessage => "short message",
             DecodeError::InvalidMessage => "invalid message",
             DecodeError::InvalidName => "invalid name",
+            DecodeError::InvalidLabel => "invalid label length",
+            DecodeError::NameTooLong => "name too long",
         })
     }
 }
@@ -165,12 +424,43 @@ impl<'a> MsgReader<'a> {
 
     /// Reads a name from the message.
     pub fn read_name(&mut self) -> Result<String, DecodeError> {
+        let mut res = String::new();
+
+        self.walk_name(|r, len| r.read_segment(&mut res, len))?;
+
+        if res.is_empty() {
+            res.push('.');
+        } else {
+            res.shrink_to_fit();
+        }
+
+        Ok(res)
+    }
+
+    /// Skips a name in the message. The labels and compression pointers are
+    /// checked as by `read_name`, but not the characters of the labels.
+    pub(crate) fn skip_name(&mut self) -> Result<(), DecodeError> {
+        self.walk_name(|r, len| {
+            if r.remaining() < len {
+                return Err(DecodeError::ShortMessage);
+            }
+            r.consume(len as u64);
+            Ok(())
+        })
+    }
+
+    /// Follows the labels and compression pointers of a name, calling `label`
+    /// to read each label of the given length, and leaves the reader after
+    /// the name.
+    fn walk_name<F>(&mut self, mut label: F) -> Result<(), DecodeError>
+    where
+        F: FnMut(&mut MsgReader<'a>, usize) -> Result<(), DecodeError>,
+    {
         // Start position, used to check against pointer references
         let start_pos = self.data.position();
         // Offset to return to if we've finished parsing a pointer reference
         let mut restore = None;
 
-        let mut res = String::new();
         let mut total_read = 0;
 
         loop {
@@ -178,7 +468,7 @@ impl<'a> MsgReader<'a> {
 
             if len == 0 {
                 if total_read + 1 > NAME_LIMIT {
-                    return Err(DecodeError::InvalidName);
+                    return Err(DecodeError::NameTooLong);
                 }
                 break;
             }
@@ -186,12 +476,13 @@ impl<'a> MsgReader<'a> {
             // If the length flag starts with "11", it will be followed by a
             // pointer reference. For more information see RFC 1035 section
             // 4.1.4 (Message compression).
-            // Prefix "00" means "no compression". Prefixes 0x01 and 0x10
-            // are reserved for future use.
+            // Prefix "00" means "no compression". Prefixes 0b01 and 0b10
+            // are reserved for future use (RFC 6891 retired the extended
+            // label types) and are not taken as a longer label.
             let compressed = match len >> 6 {
                 0b11 => true,
                 0b00 => false,
-                _ => return Err(DecodeError::InvalidMessage),
+                _ => return Err(DecodeError::InvalidLabel),
             };
 
             if compressed {
@@ -215,25 +506,21 @@ impl<'a> MsgReader<'a> {
                 continue;
             }
 
+            // the labels gathered through compression pointers count as well,
+            // so that a chain of pointers cannot expand to a longer name
             if total_read + 1 + len as usize > NAME_LIMIT {
-                return Err(DecodeError::InvalidName);
+                return Err(DecodeError::NameTooLong);
             }
             total_read += 1 + len as usize;
 
-            self.read_segment(&mut res, len as usize)?;
-        }
-
-        if res.is_empty() {
-            res.push('.');
-        } else {
-            res.shrink_to_fit();
+            label(self, len as usize)?;
         }
 
         if let Some(pos) = restore {
             self.data.set_position(pos);
         }
 
-        Ok(res)
+        Ok(())
     }
 
     fn read_segment(&mut self, buf: &mut String, len: usize) -> Result<(), DecodeError> {
@@ -292,7 +579,7 @@ impl<'a> MsgReader<'a> {
         // 1 bit: query or response flag
         let qr = hdr.flags0 & 0b10000000;
         // 4 bits: opcode
-        let op = hdr.flags0 & 0b01111000;
+        let op = (hdr.flags0 & 0b01111000) >> 3;
         // 1 bit: authoritative answer flag
         let aa = hdr.flags0 & 0b00000100;
         // 1 bit: truncation flag
@@ -302,8 +589,12 @@ impl<'a> MsgReader<'a> {
 
         // 1 bit: recursion available flag
         let ra = hdr.flags1 & 0b10000000;
-        // 3 bits: reserved for future use
-        //     = hdr.flags1 & 0b01110000;
+        // 1 bit: reserved for future use
+        //     = hdr.flags1 & 0b01000000;
+        // 1 bit: authentic data flag
+        let ad = hdr.flags1 & 0b00100000;
+        // 1 bit: checking disabled flag
+        let cd = hdr.flags1 & 0b00010000;
         // 4 bits: response code
         let rc = hdr.flags1 & 0b00001111;
 
@@ -320,6 +611,8 @@ impl<'a> MsgReader<'a> {
             truncated: tc != 0,
             recursion_desired: rd != 0,
             recursion_available: ra != 0,
+            authentic_data: ad != 0,
+            checking_disabled: cd != 0,
             rcode: RCode::from_u8(rc),
             qd_count: qd_count,
             an_count: an_count,
@@ -350,8 +643,30 @@ impl<'a> MsgReader<'a> {
 
     /// Reads a resource record item
     fn read_resource(&mut self) -> Result<Resource<'a>, DecodeError> {
+        let start = self.data.position() as usize;
         let name = self.read_name()?;
+        let res = self.read_resource_fields(start)?;
+
+        Ok(Resource {
+            name: name,
+            r_type: res.r_type,
+            r_class: res.r_class,
+            ttl: res.ttl,
+            data: Borrowed(&res.data[..res.end]),
+            offset: res.offset,
+        })
+    }
+
+    /// Reads a resource record item without decoding its name
+    fn read_resource_ref(&mut self) -> Result<ResourceRef<'a>, DecodeError> {
+        let start = self.data.position() as usize;
+        self.skip_name()?;
+        self.read_resource_fields(start)
+    }
 
+    /// Reads the fields of a resource record after its name, which begins at
+    /// `name` in the message
+    fn read_resource_fields(&mut self, name: usize) -> Result<ResourceRef<'a>, DecodeError> {
         let mut buf = [0; 10];
 
         self.read(&mut buf)?;
@@ -363,19 +678,21 @@ impl<'a> MsgReader<'a> {
         let ttl = u32::from_be(msg.ttl);
         let length = u16::from_be(msg.length);
 
-        let data = *self.data.get_ref();
-        let offset = self.data.position() as usize;
+        if self.remaining() < length as usize {
+            return Err(DecodeError::ShortMessage);
+        }
 
-        let r_data = &data[..offset + length as usize];
+        let offset = self.data.position() as usize;
         self.consume(length as u64);
 
-        Ok(Resource {
-            name: name,
+        Ok(ResourceRef {
             r_type: RecordType::from_u16(r_type),
             r_class: Class::from_u16(r_class),
             ttl: ttl,
-            data: Borrowed(r_data),
+            data: *self.data.get_ref(),
+            name: name,
             offset: offset,
+            end: offset + length as usize,
         })
     }
 }
@@ -430,8 +747,18 @@ impl<'a> MsgWriter<'a> {
     }
 
     /// Writes a name to the message.
+    ///
+    /// Unicode names are written in their A-label (`xn--`) form, see
+    /// `idna::to_ascii`.
     pub fn write_name(&mut self, name: &str) -> Result<(), EncodeError> {
-        if !is_valid_name(name) {
+        // The whole name is converted because UTS #46 maps other full stops
+        // to label separators
+        let name = match idna::to_ascii(name) {
+            Ok(name) => name,
+            Err(_) => return Err(EncodeError::InvalidName),
+        };
+
+        if !is_valid_name(&name) {
             Err(EncodeError::InvalidName)
         } else if name == "." {
             self.write_byte(0)
@@ -439,12 +766,7 @@ impl<'a> MsgWriter<'a> {
             let mut total_len = 0;
 
             for seg in name.split('.') {
-                let seg = match idna::to_ascii(seg) {
-                    Ok(seg) => seg,
-                    Err(_) => return Err(EncodeError::InvalidName),
-                };
-
-                if !is_valid_segment(&seg) {
+                if !is_valid_segment(seg) {
                     return Err(EncodeError::InvalidName);
                 }
 
@@ -511,8 +833,12 @@ impl<'a> MsgWriter<'a> {
 
         // 1 bit: recursion available flag
         hdr.flags1 |= (header.recursion_available as u8) << 7;
-        // 3 bits: reserved for future use
-        // .flags1 |= (0 as u8 & 0b111) << 4;
+        // 1 bit: reserved for future use
+        // .flags1 |= (0 as u8 & 1) << 6;
+        // 1 bit: authentic data flag
+        hdr.flags1 |= (header.authentic_data as u8) << 5;
+        // 1 bit: checking disabled flag
+        hdr.flags1 |= (header.checking_disabled as u8) << 4;
         // 4 bits: response code
         hdr.flags1 |= header.rcode.to_u8() & 0b1111;
 
@@ -546,7 +872,14 @@ impl<'a> MsgWriter<'a> {
 
         let mut rd: ResourceData = unsafe { zeroed() };
 
-        let rdata = resource.get_rdata();
+        // names in the data of a received record may point into the received message
+        let decompressed;
+        let rdata = if resource.wire_message().is_some() && has_compressed_names(resource.r_type) {
+            decompressed = OwnedRecord::from(resource).to_resource()?;
+            decompressed.get_rdata()
+        } else {
+            resource.get_rdata()
+        };
 
         rd.r_type = resource.r_type.to_u16().to_be();
         rd.r_class = resource.r_class.to_u16().to_be();
@@ -560,11 +893,67 @@ impl<'a> MsgWriter<'a> {
     }
 }
 
+/// Returns the length of the name as written by `MsgWriter::write_name`.
+fn encoded_name_len(name: &str) -> usize {
+    match idna::to_ascii(name) {
+        Ok(ref name) if name == "." => 1,
+        // a size octet for each label and the root label
+        Ok(name) => name.len() + 1 + !name.ends_with('.') as usize,
+        Err(_) => name.len() + 2,
+    }
+}
+
+/// Returns the length of the record as written by `MsgWriter::write_resource`.
+fn encoded_resource_len(resource: &Resource) -> usize {
+    let rdata_len = if resource.wire_message().is_some() && has_compressed_names(resource.r_type) {
+        match OwnedRecord::from(resource).to_resource() {
+            Ok(decompressed) => decompressed.get_rdata().len(),
+            Err(_) => resource.get_rdata().len(),
+        }
+    } else {
+        resource.get_rdata().len()
+    };
+    encoded_name_len(&resource.name) + 10 + rdata_len
+}
+
+/// Whether the data of the record type may contain compressed names, which are only
+/// allowed for the types of RFC 1035 (RFC 3597, section 4) and for DNAME, which
+/// RFC 2672 allowed to compress
+fn has_compressed_names(r_type: RecordType) -> bool {
+    match r_type {
+        RecordType::CName
+        | RecordType::Dname
+        | RecordType::Mx
+        | RecordType::Ns
+        | RecordType::Ptr
+        | RecordType::Soa => true,
+        _ => false,
+    }
+}
+
+/// Returns the name a name strictly below the owner of a DNAME record becomes,
+/// i.e., the owner replaced by the target, or `None` if the name is not below it.
+fn substitute_dname(name: &str, owner: &str, target: &str) -> Option<String> {
+    let below = Labels::new(name)
+        .count()
+        .checked_sub(Labels::new(owner).count())?;
+    if below == 0 || !is_subdomain(name, owner) {
+        return None;
+    }
+    let prefix: Vec<&str> = Labels::new(name).take(below).collect();
+    let target = DnsName::new(target);
+    if target.is_root() {
+        Some(format!("{}.", prefix.join(".")))
+    } else {
+        Some(format!("{}.{}", prefix.join("."), target))
+    }
+}
+
 /// Returns a sequential ID value from a thread-local random starting value.
 pub fn generate_id() -> u16 {
     // It's not really necessary for these to be sequential, but it avoids the
     // 1-in-65536 chance of producing the same random number twice in a row.
-    thread_local!(static ID: Cell<u16> = Cell::new(random()));
+    thread_local!(static ID: Cell<u16> = Cell::new(OsRandom.id()));
     ID.with(|id| {
         let value = id.get();
         id.set(value.wrapping_add(1));
@@ -572,6 +961,24 @@ pub fn generate_id() -> u16 {
     })
 }
 
+/// Returns the offset of the last record of the additional section in the
+/// encoded message, where the transaction signature must be placed.
+pub(crate) fn last_additional_offset(data: &[u8]) -> Result<Option<usize>, DecodeError> {
+    let mut r = MsgReader::new(data);
+    let header = r.read_header()?;
+    if header.ar_count == 0 {
+        return Ok(None);
+    }
+    for _ in 0..header.qd_count {
+        r.read_question()?;
+    }
+    let records = header.an_count as usize + header.ns_count as usize + header.ar_count as usize;
+    for _ in 1..records {
+        r.read_resource()?;
+    }
+    Ok(Some(data.len() - r.remaining()))
+}
+
 /// Returns whether the given string appears to be a valid hostname.
 /// The contents of the name (i.e. characters in labels) are not checked here;
 /// only the structure of the name is validated.
@@ -580,6 +987,15 @@ fn is_valid_name(name: &str) -> bool {
     len != 0 && (len == 1 || !name.starts_with('.')) && !name.contains("..")
 }
 
+/// Returns whether the names are written the same to a message, i.e., after
+/// the A-label conversion and with the same case of the ASCII letters.
+fn is_same_wire_name(a: &str, b: &str) -> bool {
+    match (idna::to_ascii(a), idna::to_ascii(b)) {
+        (Ok(a), Ok(b)) => is_same_case(&a, &b),
+        _ => false,
+    }
+}
+
 /// Returns whether the given string constitutes a valid name segment.
 /// This check is not as strict as internet DNS servers will be. It only checks
 /// for basic sanity of input. If an invalid name is given, a DNS server will
@@ -606,7 +1022,36 @@ pub struct Message<'a> {
     pub additional: Vec<Resource<'a>>,
 }
 
+/// Represents a DNS message which owns the data of its records, e.g., to keep
+/// it in a struct or send it to another thread, see `Message::to_owned`.
+///
+/// Unlike `OwnedMessage`, the records are not decoded, so it has the methods
+/// of a `Message` and can be built up and encoded like one. Both are kept: a
+/// `MessageBuf` copies the data of unknown record types and forwards it as it
+/// is, while an `OwnedMessage` gives the fields of each record, e.g., to
+/// serialize them, and encodes through a `MessageBuf`.
+pub type MessageBuf = Message<'static>;
+
 impl<'a> Message<'a> {
+    /// Returns whether the question section of this response is the one of the
+    /// query, with the same case of the ASCII letters in each name.
+    ///
+    /// Servers echo the question as it was sent, so when the case of the query
+    /// was randomized with `Question::randomize_case`, a response with another
+    /// case is likely spoofed.
+    pub fn echoes_question(&self, query: &Message) -> bool {
+        self.question.len() == query.question.len()
+            && self
+                .question
+                .iter()
+                .zip(query.question.iter())
+                .all(|(r, q)| {
+                    r.q_type == q.q_type
+                        && r.q_class == q.q_class
+                        && is_same_wire_name(&r.name, &q.name)
+                })
+    }
+
     /// Constructs a new `Message` with a random id value.
     pub fn new() -> Message<'a> {
         Message {
@@ -623,7 +1068,286 @@ impl<'a> Message<'a> {
         }
     }
 
-    /// Decodes a message from a series of bytes.
+    /// Constructs an UPDATE message (RFC 2136) for the zone with a random id
+    /// value.
+    ///
+    /// The zone is the only question, the prerequisites are kept in the answer
+    /// section and the updates in the authority section, which `encode` emits
+    /// as the ZOCOUNT, PRCOUNT, and UPCOUNT header fields.
+    pub fn update(zone: &str, class: Class) -> Message<'a> {
+        let mut msg = Message::new();
+        msg.header.op = OpCode::Update;
+        msg.question
+            .push(Question::new(zone.to_owned(), RecordType::Soa, class));
+        msg
+    }
+
+    /// Returns the zone of an UPDATE message.
+    pub fn zone(&self) -> Option<&Question> {
+        self.question.first()
+    }
+
+    /// Returns the prerequisites of an UPDATE message.
+    pub fn prerequisites(&self) -> &[Resource<'a>] {
+        &self.answer
+    }
+
+    /// Returns the updates of an UPDATE message.
+    pub fn updates(&self) -> &[Resource<'a>] {
+        &self.authority
+    }
+
+    fn zone_class(&self) -> Class {
+        self.zone().map_or(Class::Internet, |zone| zone.q_class)
+    }
+
+    /// Requires that a record set of the type exists at the name, whatever
+    /// its data is.
+    pub fn require_rrset(&mut self, name: &str, r_type: RecordType) {
+        self.answer
+            .push(Resource::new(name.to_owned(), r_type, Class::Any, 0));
+    }
+
+    /// Requires that the record set at the name contains the record. All
+    /// records of the record set have to be required for an exact match.
+    pub fn require_record<R: Record>(&mut self, name: &str, record: &R) -> Result<(), EncodeError> {
+        let class = self.zone_class();
+        let mut res = Resource::new(name.to_owned(), R::record_type(), class, 0);
+        res.write_rdata(record)?;
+        self.answer.push(res);
+        Ok(())
+    }
+
+    /// Requires that no record set of the type exists at the name.
+    pub fn require_no_rrset(&mut self, name: &str, r_type: RecordType) {
+        self.answer
+            .push(Resource::new(name.to_owned(), r_type, Class::None, 0));
+    }
+
+    /// Requires that the name has a record of any type.
+    pub fn require_name(&mut self, name: &str) {
+        self.answer.push(Resource::new(
+            name.to_owned(),
+            RecordType::Any,
+            Class::Any,
+            0,
+        ));
+    }
+
+    /// Requires that the name has no records.
+    pub fn require_no_name(&mut self, name: &str) {
+        self.answer.push(Resource::new(
+            name.to_owned(),
+            RecordType::Any,
+            Class::None,
+            0,
+        ));
+    }
+
+    /// Adds the record to the record set at the name.
+    pub fn add_record<R: Record>(
+        &mut self,
+        name: &str,
+        ttl: u32,
+        record: &R,
+    ) -> Result<(), EncodeError> {
+        let class = self.zone_class();
+        let mut res = Resource::new(name.to_owned(), R::record_type(), class, ttl);
+        res.write_rdata(record)?;
+        self.authority.push(res);
+        Ok(())
+    }
+
+    /// Deletes the record from the record set at the name.
+    pub fn delete_record<R: Record>(&mut self, name: &str, record: &R) -> Result<(), EncodeError> {
+        let mut res = Resource::new(name.to_owned(), R::record_type(), Class::None, 0);
+        res.write_rdata(record)?;
+        self.authority.push(res);
+        Ok(())
+    }
+
+    /// Deletes the record set of the type at the name.
+    pub fn delete_rrset(&mut self, name: &str, r_type: RecordType) {
+        self.authority
+            .push(Resource::new(name.to_owned(), r_type, Class::Any, 0));
+    }
+
+    /// Deletes all record sets at the name.
+    pub fn delete_name(&mut self, name: &str) {
+        self.authority.push(Resource::new(
+            name.to_owned(),
+            RecordType::Any,
+            Class::Any,
+            0,
+        ));
+    }
+
+    /// Constructs a response to the query, with its ID, opcode, question, and
+    /// RD and CD bits, and the `NoError` response code. The AA and RA bits are
+    /// clear until they are set with `set_authoritative` and
+    /// `set_recursion_available`.
+    ///
+    /// If the query has an OPT record, the response gets one with its DNSSEC
+    /// OK bit, which advertises the UDP payload size of the query up to 1232
+    /// bytes. `DnsSocket::send_message` truncates a longer response to this
+    /// size, or to 512 bytes without an OPT record.
+    pub fn response(query: &Message) -> Message<'a> {
+        let mut msg = Message::with_id(query.header.id);
+        msg.header.qr = Qr::Response;
+        msg.header.op = query.header.op;
+        msg.header.recursion_desired = query.header.recursion_desired;
+        msg.header.checking_disabled = query.header.checking_disabled;
+        msg.question = query.question.clone();
+        if let Some(size) = query.edns_udp_size() {
+            msg.opt_mut().r_class = Class::Other(size.min(1232).max(512));
+            msg.set_dnssec_ok(query.dnssec_ok() == Some(true));
+        }
+        msg
+    }
+
+    /// Returns whether the message is an authoritative answer (AA).
+    pub fn authoritative(&self) -> bool {
+        self.header.authoritative
+    }
+
+    /// Sets or clears the AA bit of a response from an authority for the name
+    /// of the question.
+    pub fn set_authoritative(&mut self, authoritative: bool) {
+        self.header.authoritative = authoritative;
+    }
+
+    /// Returns whether the message was truncated (TC).
+    pub fn truncated(&self) -> bool {
+        self.header.truncated
+    }
+
+    /// Sets or clears the TC bit, which tells the client to repeat the query
+    /// over TCP.
+    pub fn set_truncated(&mut self, truncated: bool) {
+        self.header.truncated = truncated;
+    }
+
+    /// Returns whether recursion is desired (RD).
+    pub fn recursion_desired(&self) -> bool {
+        self.header.recursion_desired
+    }
+
+    /// Sets or clears the RD bit of a query, which a response copies.
+    pub fn set_recursion_desired(&mut self, recursion_desired: bool) {
+        self.header.recursion_desired = recursion_desired;
+    }
+
+    /// Returns whether recursion is available (RA).
+    pub fn recursion_available(&self) -> bool {
+        self.header.recursion_available
+    }
+
+    /// Sets or clears the RA bit of a response from a server which resolves
+    /// recursive queries.
+    pub fn set_recursion_available(&mut self, recursion_available: bool) {
+        self.header.recursion_available = recursion_available;
+    }
+
+    /// Returns whether the records are authentic data (AD).
+    pub fn authentic_data(&self) -> bool {
+        self.header.authentic_data
+    }
+
+    /// Sets or clears the AD bit of a response whose records were validated.
+    pub fn set_authentic_data(&mut self, authentic_data: bool) {
+        self.header.authentic_data = authentic_data;
+    }
+
+    /// Returns whether checking is disabled (CD).
+    pub fn checking_disabled(&self) -> bool {
+        self.header.checking_disabled
+    }
+
+    /// Sets or clears the CD bit of a query, which a response copies.
+    pub fn set_checking_disabled(&mut self, checking_disabled: bool) {
+        self.header.checking_disabled = checking_disabled;
+    }
+
+    /// Returns the response code of the header.
+    pub fn rcode(&self) -> RCode {
+        self.header.rcode
+    }
+
+    /// Sets the response code of the header, e.g., `NameError` (NXDOMAIN) or
+    /// `Refused`.
+    pub fn set_rcode(&mut self, rcode: RCode) {
+        self.header.rcode = rcode;
+    }
+
+    /// Sets the 12-bit response code, e.g., BADCOOKIE (23). The upper 8 bits
+    /// go into the OPT record, which is added if a code above 15 needs it.
+    pub fn set_extended_rcode(&mut self, rcode: u16) {
+        self.header.rcode = RCode::from_u8((rcode & 0b1111) as u8);
+        if rcode > 0b1111 || self.opt().is_some() {
+            let opt = self.opt_mut();
+            opt.ttl = (opt.ttl & 0x00ff_ffff) | (u32::from((rcode >> 4) & 0xff) << 24);
+        }
+    }
+
+    /// Adds the record to the answer section, with the class of the question.
+    pub fn add_answer<R: Record>(
+        &mut self,
+        name: &str,
+        ttl: u32,
+        record: &R,
+    ) -> Result<(), EncodeError> {
+        let res = self.resource(name, ttl, record)?;
+        self.answer.push(res);
+        Ok(())
+    }
+
+    /// Adds the record to the authority section, e.g., the SOA record of the
+    /// zone for a negative answer.
+    pub fn add_authority<R: Record>(
+        &mut self,
+        name: &str,
+        ttl: u32,
+        record: &R,
+    ) -> Result<(), EncodeError> {
+        let res = self.resource(name, ttl, record)?;
+        self.authority.push(res);
+        Ok(())
+    }
+
+    /// Adds the record to the additional section, in front of a TSIG record,
+    /// which has to stay last.
+    pub fn add_additional<R: Record>(
+        &mut self,
+        name: &str,
+        ttl: u32,
+        record: &R,
+    ) -> Result<(), EncodeError> {
+        let res = self.resource(name, ttl, record)?;
+        let pos = self
+            .additional
+            .iter()
+            .position(|r| r.r_type == RecordType::Tsig)
+            .unwrap_or(self.additional.len());
+        self.additional.insert(pos, res);
+        Ok(())
+    }
+
+    fn resource<R: Record>(
+        &self,
+        name: &str,
+        ttl: u32,
+        record: &R,
+    ) -> Result<Resource<'a>, EncodeError> {
+        let mut res = Resource::new(name.to_owned(), R::record_type(), self.zone_class(), ttl);
+        res.write_rdata(record)?;
+        Ok(res)
+    }
+
+    /// Decodes a message from a series of bytes, e.g., a UDP payload received
+    /// from any transport or read from a packet capture.
+    ///
+    /// The message borrows the data of its records from the bytes, see
+    /// `into_owned` for a message which does not.
     pub fn decode(data: &[u8]) -> Result<Message, DecodeError> {
         let mut r = MsgReader::new(data);
 
@@ -657,6 +1381,35 @@ impl<'a> Message<'a> {
         Ok(msg)
     }
 
+    /// Decodes a message from a series of bytes into a `MessageBuf`, which
+    /// does not borrow them.
+    pub fn decode_owned(data: &[u8]) -> Result<MessageBuf, DecodeError> {
+        Message::decode(data).map(|msg| msg.to_owned())
+    }
+
+    /// Returns an iterator over the answer records of the message in the
+    /// bytes, which decodes the records one at a time.
+    ///
+    /// Unlike `decode`, nothing is allocated: the owner names and the data of
+    /// the records are only decoded on demand, see `ResourceRef`, and the
+    /// sections after the answers are not read. A record which does not
+    /// decode is yielded as an error, after which the iterator ends.
+    pub fn answers_iter(data: &[u8]) -> Result<Answers, DecodeError> {
+        let mut r = MsgReader::new(data);
+        let header = r.read_header()?;
+        for _ in 0..header.qd_count {
+            r.skip_name()?;
+            if r.remaining() < 4 {
+                return Err(DecodeError::ShortMessage);
+            }
+            r.consume(4);
+        }
+        Ok(Answers {
+            reader: r,
+            remaining: header.an_count,
+        })
+    }
+
     /// Encodes a message to a series of bytes. On success, returns a subslice
     /// of the given buffer containing only the encoded message bytes.
     pub fn encode<'buf>(&self, buf: &'buf mut [u8]) -> Result<&'buf [u8], EncodeError> {
@@ -671,6 +1424,8 @@ impl<'a> Message<'a> {
             truncated: hdr.truncated,
             recursion_desired: hdr.recursion_desired,
             recursion_available: hdr.recursion_available,
+            authentic_data: hdr.authentic_data,
+            checking_disabled: hdr.checking_disabled,
             rcode: hdr.rcode,
             qd_count: to_u16(self.question.len())?,
             an_count: to_u16(self.answer.len())?,
@@ -696,42 +1451,430 @@ impl<'a> Message<'a> {
         Ok(w.into_bytes())
     }
 
+    /// Returns the number of bytes `encode` writes for this message, without
+    /// encoding it.
+    ///
+    /// Names are written without compression, so this is the exact length of
+    /// a successful encoding and an upper bound for any compressed form of
+    /// the message. A message that fails to encode may return any value.
+    pub fn encoded_len(&self) -> usize {
+        let questions: usize = self
+            .question
+            .iter()
+            .map(|q| encoded_name_len(&q.name) + 4)
+            .sum();
+        let records: usize = self.records().map(encoded_resource_len).sum();
+        12 + questions + records
+    }
+
+    /// Encodes a message into a new vector of exactly its length, which is
+    /// allocated from `encoded_len` instead of a `MESSAGE_LIMIT` buffer.
+    /// Messages longer than `MESSAGE_LIMIT` fail with `TooLong` as in `encode`.
+    ///
+    /// Names in the data of decoded records are written without compression,
+    /// so the bytes may differ from the ones the message was decoded from.
+    pub fn encode_vec(&self) -> Result<Vec<u8>, EncodeError> {
+        let len = self.encoded_len();
+        if len > MESSAGE_LIMIT {
+            return Err(EncodeError::TooLong);
+        }
+        let mut buf = vec![0; len];
+        let written = self.encode(&mut buf)?.len();
+        buf.truncate(written);
+        Ok(buf)
+    }
+
+    /// Encodes a message into a new vector of exactly its length, see
+    /// `encode_vec`.
+    pub fn encode_to_vec(&self) -> Result<Vec<u8>, EncodeError> {
+        self.encode_vec()
+    }
+
     /// Returns a `DnsError` if the message response code is an error.
     pub fn get_error(&self) -> Result<(), DnsError> {
         if self.header.rcode == RCode::NoError {
             Ok(())
         } else {
-            Err(DnsError(self.header.rcode))
+            Err(DnsError(self.header.rcode, None))
         }
     }
 
-    /// Returns an iterator over the records in this message.
-    pub fn records(&self) -> RecordIter {
-        RecordIter {
-            iters: [
-                self.answer.iter(),
-                self.authority.iter(),
-                self.additional.iter(),
-            ],
-        }
+    /// Returns the transaction ID, which a response has in common with its
+    /// query.
+    pub fn id(&self) -> u16 {
+        self.header.id
     }
 
-    /// Consumes the message and returns an iterator over its records.
-    pub fn into_records(self) -> RecordIntoIter<'a> {
-        RecordIntoIter {
-            iters: [
-                self.answer.into_iter(),
-                self.authority.into_iter(),
-                self.additional.into_iter(),
-            ],
-        }
+    /// Returns the EDNS version of the OPT record (RFC 6891), or `None` if the
+    /// message has no OPT record.
+    pub fn edns_version(&self) -> Option<u8> {
+        self.opt().map(|(_, ttl)| (ttl >> 16) as u8)
     }
-}
 
-/// Yields `&Resource` items from a Message.
-pub struct RecordIter<'a> {
-    iters: [Iter<'a, Resource<'a>>; 3],
-}
+    /// Returns the DNSSEC OK bit of the OPT record (RFC 3225), or `None` if
+    /// the message has no OPT record.
+    pub fn dnssec_ok(&self) -> Option<bool> {
+        self.opt().map(|(_, ttl)| ttl & 0x8000 != 0)
+    }
+
+    /// Returns the 12-bit response code of the upper 8 bits in the OPT record
+    /// and the 4 bits of the header, or `None` if the message has no OPT
+    /// record.
+    pub fn extended_rcode(&self) -> Option<u16> {
+        let rcode = u16::from(self.header.rcode.to_u8() & 0b1111);
+        self.opt().map(|(_, ttl)| ((ttl >> 24) as u16) << 4 | rcode)
+    }
+
+    /// Returns the UDP payload size advertised in the OPT record, or `None` if
+    /// the message has no OPT record.
+    pub fn edns_udp_size(&self) -> Option<u16> {
+        self.opt().map(|(size, _)| size)
+    }
+
+    /// Sets or clears the DNSSEC OK bit (RFC 3225), without which most
+    /// servers leave out the RRSIG, DNSKEY, and DS records of signed zones.
+    ///
+    /// Setting it adds an OPT record which advertises a UDP payload size of
+    /// 1232 bytes if the message has none, in front of a TSIG record, which
+    /// has to stay last. Clearing it keeps the OPT record.
+    pub fn set_dnssec_ok(&mut self, dnssec_ok: bool) {
+        if dnssec_ok {
+            self.opt_mut().ttl |= 0x8000;
+        } else if let Some(opt) = self
+            .additional
+            .iter_mut()
+            .find(|r| r.r_type == RecordType::Opt)
+        {
+            opt.ttl &= !0x8000;
+        }
+    }
+
+    /// Returns the data of the first EDNS option with the code in the OPT
+    /// record, or `None` if there is none.
+    pub fn edns_option(&self, code: u16) -> Option<&[u8]> {
+        self.additional
+            .iter()
+            .find(|r| r.r_type == RecordType::Opt)
+            .and_then(|r| find_edns_option(r.get_rdata(), code))
+    }
+
+    /// Returns the client subnet option of the OPT record, which holds the
+    /// scope prefix length in a response, or `None` if there is none or it
+    /// does not decode.
+    pub fn client_subnet(&self) -> Option<ClientSubnet> {
+        self.edns_option(EDNS_CLIENT_SUBNET)
+            .and_then(|data| ClientSubnet::decode(data).ok())
+    }
+
+    /// Sets the client subnet option of a query, in an OPT record which is
+    /// added as by `set_dnssec_ok` if the message has none.
+    pub fn set_client_subnet(&mut self, subnet: &ClientSubnet) {
+        self.set_edns_option(EDNS_CLIENT_SUBNET, &subnet.encode());
+    }
+
+    /// Returns the data of the cookie option (RFC 7873), the 8-byte client
+    /// cookie followed by the server cookie in a response.
+    pub fn cookie(&self) -> Option<&[u8]> {
+        self.edns_option(EDNS_COOKIE)
+    }
+
+    /// Returns the identifier of the name server which sent the response
+    /// (NSID, RFC 5001), often the name of an anycast instance in ASCII, or
+    /// `None` if the server does not support the option.
+    pub fn nsid(&self) -> Option<Vec<u8>> {
+        self.edns_option(EDNS_NSID).map(|data| data.to_vec())
+    }
+
+    /// Asks the server for its identifier with an empty NSID option, in an
+    /// OPT record which is added as by `set_dnssec_ok` if the message has none.
+    pub fn request_nsid(&mut self) {
+        self.set_edns_option(EDNS_NSID, &[]);
+    }
+
+    /// Sets the EDNS option with the code to the data, replacing the options
+    /// with the code in the OPT record, which is added as by `set_dnssec_ok`
+    /// if the message has none.
+    pub fn set_edns_option(&mut self, code: u16, data: &[u8]) {
+        let opt = self.opt_mut();
+        let data = replace_edns_option(opt.get_rdata(), code, data);
+        opt.data = Owned(data);
+        opt.offset = 0;
+    }
+
+    /// Returns the OPT record, which is added in front of a TSIG record with
+    /// a UDP payload size of 1232 bytes if the message has none.
+    fn opt_mut(&mut self) -> &mut Resource<'a> {
+        let pos = match self
+            .additional
+            .iter()
+            .position(|r| r.r_type == RecordType::Opt)
+        {
+            Some(pos) => pos,
+            None => {
+                let pos = self
+                    .additional
+                    .iter()
+                    .position(|r| r.r_type == RecordType::Tsig)
+                    .unwrap_or(self.additional.len());
+                let opt = Resource::new(".".to_string(), RecordType::Opt, Class::Other(1232), 0);
+                self.additional.insert(pos, opt);
+                pos
+            }
+        };
+        &mut self.additional[pos]
+    }
+
+    /// Returns the class and TTL fields of the first OPT record, which hold
+    /// the UDP payload size and the extended RCODE, version, and flags.
+    fn opt(&self) -> Option<(u16, u32)> {
+        self.additional
+            .iter()
+            .find(|r| r.r_type == RecordType::Opt)
+            .map(|r| (r.r_class.to_u16(), r.ttl))
+    }
+
+    /// Returns an iterator over the records in this message.
+    pub fn records(&self) -> RecordIter {
+        RecordIter {
+            iters: [
+                self.answer.iter(),
+                self.authority.iter(),
+                self.additional.iter(),
+            ],
+        }
+    }
+
+    /// Returns the NAPTR records of all sections, sorted by order and
+    /// preference. Records whose data does not decode are skipped.
+    pub fn naptr_records(&self) -> Vec<Naptr> {
+        sorted_naptr(
+            self.records()
+                .filter(|r| r.r_type == RecordType::Naptr)
+                .filter_map(|r| r.read_rdata().ok()),
+        )
+    }
+
+    /// Returns the CAA records of all sections. Records whose data does not
+    /// decode are skipped.
+    pub fn caa_records(&self) -> Vec<Caa> {
+        self.records()
+            .filter(|r| r.r_type == RecordType::Caa)
+            .filter_map(|r| r.read_rdata().ok())
+            .collect()
+    }
+
+    /// Returns the RRSIG records of all sections. Records whose data does not
+    /// decode are skipped.
+    pub fn rrsig_records(&self) -> Vec<Rrsig> {
+        self.records()
+            .filter(|r| r.r_type == RecordType::Rrsig)
+            .filter_map(|r| r.read_rdata().ok())
+            .collect()
+    }
+
+    /// Returns the DNSKEY records of all sections. Records whose data does not
+    /// decode are skipped.
+    pub fn dnskey_records(&self) -> Vec<Dnskey> {
+        self.records()
+            .filter(|r| r.r_type == RecordType::Dnskey)
+            .filter_map(|r| r.read_rdata().ok())
+            .collect()
+    }
+
+    /// Returns the DS records of all sections. Records whose data does not
+    /// decode are skipped.
+    pub fn ds_records(&self) -> Vec<Ds> {
+        self.records()
+            .filter(|r| r.r_type == RecordType::Ds)
+            .filter_map(|r| r.read_rdata().ok())
+            .collect()
+    }
+
+    /// Returns the NSEC records of all sections. Records whose data does not
+    /// decode are skipped.
+    pub fn nsec_records(&self) -> Vec<Nsec> {
+        self.records()
+            .filter(|r| r.r_type == RecordType::Nsec)
+            .filter_map(|r| r.read_rdata().ok())
+            .collect()
+    }
+
+    /// Returns the NSEC3 records in the message.
+    pub fn nsec3_records(&self) -> Vec<Nsec3> {
+        self.records()
+            .filter(|r| r.r_type == RecordType::Nsec3)
+            .filter_map(|r| r.read_rdata().ok())
+            .collect()
+    }
+
+    /// Returns the DNAME records of all sections. Records whose data does not
+    /// decode are skipped.
+    pub fn dname_records(&self) -> Vec<Dname> {
+        self.records()
+            .filter(|r| r.r_type == RecordType::Dname)
+            .filter_map(|r| r.read_rdata().ok())
+            .collect()
+    }
+
+    /// Returns an iterator over the records of the type `R` in the answer
+    /// section, whose data is decoded as the iterator reaches them. Records of
+    /// other types and records whose data does not decode are skipped.
+    pub fn answer_records<R: Record>(&self) -> impl Iterator<Item = R> + '_ {
+        self.answer
+            .iter()
+            .filter(|r| r.r_type == R::record_type())
+            .filter_map(|r| r.read_rdata().ok())
+    }
+
+    /// Returns an iterator over the addresses of the A records in the answer
+    /// section, see `answer_records`.
+    pub fn a_records_iter(&self) -> impl Iterator<Item = Ipv4Addr> + '_ {
+        self.answer_records::<A>().map(|a| a.address)
+    }
+
+    /// Returns the addresses of the A records in the answer section.
+    pub fn a_records(&self) -> Vec<Ipv4Addr> {
+        self.a_records_iter().collect()
+    }
+
+    /// Returns an iterator over the addresses of the AAAA records in the
+    /// answer section, see `answer_records`.
+    pub fn aaaa_records_iter(&self) -> impl Iterator<Item = Ipv6Addr> + '_ {
+        self.answer_records::<AAAA>().map(|aaaa| aaaa.address)
+    }
+
+    /// Returns the addresses of the AAAA records in the answer section.
+    pub fn aaaa_records(&self) -> Vec<Ipv6Addr> {
+        self.aaaa_records_iter().collect()
+    }
+
+    /// Returns an iterator over the MX records in the answer section, see
+    /// `answer_records`.
+    pub fn mx_records_iter(&self) -> impl Iterator<Item = Mx> + '_ {
+        self.answer_records()
+    }
+
+    /// Returns the MX records in the answer section.
+    pub fn mx_records(&self) -> Vec<Mx> {
+        self.mx_records_iter().collect()
+    }
+
+    /// Returns an iterator over the NS records in the answer section, see
+    /// `answer_records`.
+    pub fn ns_records_iter(&self) -> impl Iterator<Item = Ns> + '_ {
+        self.answer_records()
+    }
+
+    /// Returns the NS records in the answer section.
+    pub fn ns_records(&self) -> Vec<Ns> {
+        self.ns_records_iter().collect()
+    }
+
+    /// Returns an iterator over the SRV records in the answer section, see
+    /// `answer_records`.
+    pub fn srv_records_iter(&self) -> impl Iterator<Item = Srv> + '_ {
+        self.answer_records()
+    }
+
+    /// Returns the SRV records in the answer section.
+    pub fn srv_records(&self) -> Vec<Srv> {
+        self.srv_records_iter().collect()
+    }
+
+    /// Returns an iterator over the TXT records in the answer section, see
+    /// `answer_records`.
+    pub fn txt_records_iter(&self) -> impl Iterator<Item = Txt> + '_ {
+        self.answer_records()
+    }
+
+    /// Returns the TXT records in the answer section.
+    pub fn txt_records(&self) -> Vec<Txt> {
+        self.txt_records_iter().collect()
+    }
+
+    /// Returns the name at which the answer to the question ends, i.e., the
+    /// question name after following the CNAME records of the answer section
+    /// and applying the substitutions of its DNAME records (RFC 6672), or `None`
+    /// without a question.
+    ///
+    /// A DNAME record applies to the names below its owner whether or not the
+    /// server also sent the synthesized CNAME record. Queries for CNAME or DNAME
+    /// records themselves end at the question name.
+    pub fn answer_name(&self) -> Option<String> {
+        let question = self.question.first()?;
+        let mut name = question.name.clone();
+        if question.q_type == RecordType::CName || question.q_type == RecordType::Dname {
+            return Some(name);
+        }
+        for _ in 0..ALIAS_LIMIT {
+            let next = self
+                .answer
+                .iter()
+                .filter_map(|rr| match rr.r_type {
+                    RecordType::CName if is_same_name(&rr.name, &name) => {
+                        rr.read_rdata::<CName>().ok().map(|cname| cname.name)
+                    }
+                    RecordType::Dname => rr
+                        .read_rdata::<Dname>()
+                        .ok()
+                        .and_then(|dname| substitute_dname(&name, &rr.name, &dname.target)),
+                    _ => None,
+                })
+                .next();
+            match next {
+                Some(next) => name = next,
+                None => break,
+            }
+        }
+        Some(name)
+    }
+
+    /// Consumes the message and returns an iterator over its records.
+    pub fn into_records(self) -> RecordIntoIter<'a> {
+        RecordIntoIter {
+            iters: [
+                self.answer.into_iter(),
+                self.authority.into_iter(),
+                self.additional.into_iter(),
+            ],
+        }
+    }
+
+    /// Returns an `OwnedMessage` which does not borrow the receive buffer.
+    /// Names in resource data are decompressed as the records are decoded,
+    /// so the owned message is self-contained.
+    pub fn into_owned(self) -> OwnedMessage {
+        OwnedMessage::from(&self)
+    }
+
+    /// Returns a `MessageBuf` which does not borrow the receive buffer and
+    /// compares equal to this message. The records stay encoded, see
+    /// `Resource::to_owned`. Unlike `clone`, it also copies borrowed data.
+    pub fn to_owned(&self) -> MessageBuf {
+        fn to_owned<'a>(records: &[Resource<'a>]) -> Vec<Resource<'static>> {
+            records.iter().map(Resource::to_owned).collect()
+        }
+
+        Message {
+            header: self.header,
+            question: self.question.clone(),
+            answer: to_owned(&self.answer),
+            authority: to_owned(&self.authority),
+            additional: to_owned(&self.additional),
+        }
+    }
+}
+
+fn sorted_naptr<I: Iterator<Item = Naptr>>(records: I) -> Vec<Naptr> {
+    let mut records: Vec<Naptr> = records.collect();
+    records.sort_by_key(|r| (r.order, r.preference));
+    records
+}
+
+/// Yields `&Resource` items from a Message.
+pub struct RecordIter<'a> {
+    iters: [Iter<'a, Resource<'a>>; 3],
+}
 
 impl<'a> Iterator for RecordIter<'a> {
     type Item = &'a Resource<'a>;
@@ -760,8 +1903,33 @@ impl<'a> Iterator for RecordIntoIter<'a> {
     }
 }
 
+/// Yields `ResourceRef` items of the answer section of a message, see
+/// `Message::answers_iter`.
+pub struct Answers<'a> {
+    reader: MsgReader<'a>,
+    remaining: u16,
+}
+
+impl<'a> Iterator for Answers<'a> {
+    type Item = Result<ResourceRef<'a>, DecodeError>;
+
+    fn next(&mut self) -> Option<Result<ResourceRef<'a>, DecodeError>> {
+        if self.remaining == 0 {
+            return None;
+        }
+        let res = self.reader.read_resource_ref();
+        self.remaining = if res.is_ok() { self.remaining - 1 } else { 0 };
+        Some(res)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (0, Some(self.remaining as usize))
+    }
+}
+
 /// Represents a message header.
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "dns-serde", derive(Serialize, Deserialize))]
 pub struct Header {
     /// Transaction ID; corresponding replies will have the same ID.
     pub id: u16,
@@ -780,6 +1948,15 @@ pub struct Header {
     /// In a response, indicates whether recursive queries are available on the
     /// name server.
     pub recursion_available: bool,
+    /// In a response, indicates that the name server validated the records of
+    /// the answer and authority sections through DNSSEC; in a query, that the
+    /// client understands the flag (RFC 6840 section 5.7).
+    #[cfg_attr(feature = "dns-serde", serde(default))]
+    pub authentic_data: bool,
+    /// In a query, directs a validating name server to return the records
+    /// without validating them, e.g., to validate them on the client.
+    #[cfg_attr(feature = "dns-serde", serde(default))]
+    pub checking_disabled: bool,
     /// Response code
     pub rcode: RCode,
 }
@@ -812,6 +1989,8 @@ impl Default for Header {
             truncated: false,
             recursion_desired: false,
             recursion_available: false,
+            authentic_data: false,
+            checking_disabled: false,
             rcode: RCode::NoError,
         }
     }
@@ -827,6 +2006,8 @@ struct FullHeader {
     pub truncated: bool,
     pub recursion_desired: bool,
     pub recursion_available: bool,
+    pub authentic_data: bool,
+    pub checking_disabled: bool,
     pub rcode: RCode,
     pub qd_count: u16,
     pub an_count: u16,
@@ -844,6 +2025,8 @@ impl FullHeader {
             truncated: self.truncated,
             recursion_desired: self.recursion_desired,
             recursion_available: self.recursion_available,
+            authentic_data: self.authentic_data,
+            checking_disabled: self.checking_disabled,
             rcode: self.rcode,
         }
     }
@@ -859,6 +2042,8 @@ impl Default for FullHeader {
             truncated: false,
             recursion_desired: false,
             recursion_available: false,
+            authentic_data: false,
+            checking_disabled: false,
             rcode: RCode::NoError,
             qd_count: 0,
             an_count: 0,
@@ -870,6 +2055,7 @@ impl Default for FullHeader {
 
 /// Represents a question item.
 #[derive(Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "dns-serde", derive(Serialize, Deserialize))]
 pub struct Question {
     /// Query name
     pub name: String,
@@ -888,10 +2074,46 @@ impl Question {
             q_class: q_class,
         }
     }
+
+    /// Randomizes the case of the ASCII letters of the name (the "0x20" trick)
+    ///
+    /// A-labels and labels with other characters are kept unchanged.
+    pub fn randomize_case(&mut self) {
+        self.randomize_case_with(&OsRandom)
+    }
+
+    /// Randomizes the case like `randomize_case`, with the source.
+    pub fn randomize_case_with(&mut self, rand: &dyn RandSource) {
+        let labels = self
+            .name
+            .split('.')
+            .map(|label| {
+                if !label.is_ascii() || idna::is_a_label(label) {
+                    label.to_owned()
+                } else {
+                    label
+                        .chars()
+                        .map(|c| {
+                            if rand.coin() {
+                                c.to_ascii_uppercase()
+                            } else {
+                                c.to_ascii_lowercase()
+                            }
+                        })
+                        .collect()
+                }
+            })
+            .collect::<Vec<String>>();
+
+        self.name = labels.join(".");
+    }
 }
 
 /// Represents a resource record item.
-#[derive(Clone, Debug, Eq, PartialEq)]
+///
+/// Resources are equal if their fields and data are, whether the data is
+/// borrowed from a received message or owned.
+#[derive(Clone, Debug)]
 pub struct Resource<'a> {
     /// Resource name
     pub name: String,
@@ -925,6 +2147,21 @@ impl<'a> Resource<'a> {
         &self.data[self.offset..]
     }
 
+    /// Returns a reader positioned at the resource data, which resolves
+    /// compressed names against the rest of the message.
+    pub(crate) fn rdata_reader(&self) -> MsgReader {
+        MsgReader::with_offset(&self.data, self.offset)
+    }
+
+    /// Returns the received message up to the end of the resource data, or
+    /// `None` if the resource was not decoded from a message.
+    pub(crate) fn wire_message(&self) -> Option<&[u8]> {
+        match self.data {
+            Borrowed(data) => Some(data),
+            Owned(_) => None,
+        }
+    }
+
     /// Decodes resource data into the given `Record` type.
     pub fn read_rdata<R: Record>(&self) -> Result<R, DecodeError> {
         let mut r = MsgReader::with_offset(&self.data, self.offset);
@@ -942,120 +2179,623 @@ impl<'a> Resource<'a> {
         self.offset = 0;
         Ok(())
     }
-}
-
-/// Indicates a message is either a query or response.
-#[derive(Copy, Clone, Debug, Eq, PartialEq)]
-#[repr(u8)]
-pub enum Qr {
-    /// Query
-    Query = 0,
-    /// Response
-    Response = 1,
-}
-
-/// Represents the kind of message query.
-#[derive(Copy, Clone, Debug, Eq, PartialEq)]
-pub enum OpCode {
-    /// Query
-    Query,
-    /// Status
-    Status,
-    /// Notify
-    Notify,
-    /// Update
-    Update,
-    /// Unrecognized opcode
-    Other(u8),
-}
 
-impl OpCode {
-    /// Converts a `u8` to an `OpCode`.
-    pub fn from_u8(u: u8) -> OpCode {
-        match u {
-            0 => OpCode::Query,
-            2 => OpCode::Status,
-            4 => OpCode::Notify,
-            5 => OpCode::Update,
-            n => OpCode::Other(n),
+    /// Returns a copy of the resource which owns its data instead of borrowing
+    /// the received message.
+    ///
+    /// The names in the data of the RFC 1035 record types, which may point into
+    /// the rest of the message, are decompressed. Other data is copied as it is.
+    pub fn to_owned(&self) -> Resource<'static> {
+        if self.wire_message().is_some() && has_compressed_names(self.r_type) {
+            if let Ok(res) = OwnedRecord::from(self).to_resource() {
+                return res;
+            }
+        }
+        Resource {
+            name: self.name.clone(),
+            r_type: self.r_type,
+            r_class: self.r_class,
+            ttl: self.ttl,
+            data: Owned(self.get_rdata().to_vec()),
+            offset: 0,
         }
     }
+}
 
-    /// Converts an `OpCode` to a `u8`.
-    pub fn to_u8(&self) -> u8 {
-        match *self {
-            OpCode::Query => 0,
-            OpCode::Status => 2,
-            OpCode::Notify => 4,
-            OpCode::Update => 5,
-            OpCode::Other(n) => n,
-        }
+/// Compares the data as bytes, and only if they differ and a name in the data
+/// may be compressed, the decompressed data.
+impl<'a, 'b> PartialEq<Resource<'b>> for Resource<'a> {
+    fn eq(&self, other: &Resource<'b>) -> bool {
+        let compressed = has_compressed_names(self.r_type)
+            && (self.wire_message().is_some() || other.wire_message().is_some());
+        self.name == other.name
+            && self.r_type == other.r_type
+            && self.r_class == other.r_class
+            && self.ttl == other.ttl
+            && (self.get_rdata() == other.get_rdata()
+                || (compressed && OwnedRecord::from(self).data == OwnedRecord::from(other).data))
     }
 }
 
-/// Represents the response code of a message
+impl<'a> Eq for Resource<'a> {}
+
+/// Represents a resource record which borrows the received message, and whose
+/// name and data are decoded on demand, see `Message::answers_iter`.
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
-pub enum RCode {
-    /// No error condition.
-    NoError,
-    /// The server was unable to interpret the query.
-    FormatError,
-    /// The name server was unable to process the query due to a failure of
-    /// the name server.
-    ServerFailure,
-    /// Name referenced in query does not exist.
-    NameError,
-    /// Requested query kind is not supported by name server.
-    NotImplemented,
-    /// The name server refuses to perform the specified operation for policy
-    /// reasons.
-    Refused,
-    /// Unknown response code.
-    Other(u8),
+pub struct ResourceRef<'a> {
+    /// Resource type
+    pub r_type: RecordType,
+    /// Resource class
+    pub r_class: Class,
+    /// Time-to-live
+    pub ttl: u32,
+    /// Received message
+    data: &'a [u8],
+    /// Beginning of the name within `data`
+    name: usize,
+    /// Beginning of rdata within `data`
+    offset: usize,
+    /// End of rdata within `data`
+    end: usize,
 }
 
-impl RCode {
-    /// Returns an error string for the response code.
-    pub fn get_error(&self) -> &'static str {
-        match *self {
-            RCode::NoError => "no error",
-            RCode::FormatError => "format error",
-            RCode::ServerFailure => "server failure",
-            RCode::NameError => "no such name",
-            RCode::NotImplemented => "not implemented",
-            RCode::Refused => "refused",
-            RCode::Other(_) => "unknown response code",
-        }
+impl<'a> ResourceRef<'a> {
+    /// Decodes the resource name.
+    pub fn name(&self) -> Result<String, DecodeError> {
+        MsgReader::with_offset(self.data, self.name).read_name()
     }
 
-    /// Converts a `u8` to an `RCode`.
-    pub fn from_u8(u: u8) -> RCode {
-        match u {
-            0 => RCode::NoError,
-            1 => RCode::FormatError,
-            2 => RCode::ServerFailure,
-            3 => RCode::NameError,
-            4 => RCode::NotImplemented,
-            5 => RCode::Refused,
-            n => RCode::Other(n),
-        }
+    /// Returns resource data.
+    pub fn get_rdata(&self) -> &'a [u8] {
+        &self.data[self.offset..self.end]
     }
 
-    /// Converts an `RCode` to a `u8`.
-    pub fn to_u8(&self) -> u8 {
-        match *self {
-            RCode::NoError => 0,
-            RCode::FormatError => 1,
-            RCode::ServerFailure => 2,
-            RCode::NameError => 3,
-            RCode::NotImplemented => 4,
-            RCode::Refused => 5,
-            RCode::Other(n) => n,
-        }
+    /// Decodes resource data into the given `Record` type.
+    pub fn read_rdata<R: Record>(&self) -> Result<R, DecodeError> {
+        let mut r = MsgReader::with_offset(&self.data[..self.end], self.offset);
+        let res = Record::decode(&mut r)?;
+        r.finish()?;
+        Ok(res)
     }
-}
 
-#[derive(Copy, Clone, Debug)]
+    /// Returns the `Resource`, which borrows the same message, with the
+    /// decoded name.
+    pub fn to_resource(&self) -> Result<Resource<'a>, DecodeError> {
+        Ok(Resource {
+            name: self.name()?,
+            r_type: self.r_type,
+            r_class: self.r_class,
+            ttl: self.ttl,
+            data: Borrowed(&self.data[..self.end]),
+            offset: self.offset,
+        })
+    }
+}
+
+/// Represents a DNS message which owns its data and whose records are
+/// decoded, so that it can be stored, e.g., serialized with `serde` (`dns-serde` feature).
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+#[cfg_attr(feature = "dns-serde", derive(Serialize, Deserialize))]
+pub struct OwnedMessage {
+    /// Describes the content of the remainder of the message.
+    pub header: Header,
+    /// Carries the question of query type messages.
+    pub question: Vec<Question>,
+    /// Resource records that answer the query
+    pub answer: Vec<OwnedRecord>,
+    /// Resource records that point to an authoritative name server
+    pub authority: Vec<OwnedRecord>,
+    /// Resource records that relate to the query, but are not strictly
+    /// answers for the question.
+    pub additional: Vec<OwnedRecord>,
+}
+
+impl OwnedMessage {
+    /// Decodes a message from a series of bytes.
+    pub fn decode(data: &[u8]) -> Result<OwnedMessage, DecodeError> {
+        Message::decode(data).map(|msg| OwnedMessage::from(&msg))
+    }
+
+    /// Encodes a message to a series of bytes. On success, returns a subslice
+    /// of the given buffer containing only the encoded message bytes.
+    pub fn encode<'buf>(&self, buf: &'buf mut [u8]) -> Result<&'buf [u8], EncodeError> {
+        self.to_message()?.encode(buf)
+    }
+
+    /// Encodes a message into a new vector of exactly its length.
+    pub fn encode_to_vec(&self) -> Result<Vec<u8>, EncodeError> {
+        self.to_message()?.encode_to_vec()
+    }
+
+    /// Returns a `DnsError` if the message response code is an error.
+    pub fn get_error(&self) -> Result<(), DnsError> {
+        if self.header.rcode == RCode::NoError {
+            Ok(())
+        } else {
+            Err(DnsError(self.header.rcode, None))
+        }
+    }
+
+    /// Returns the transaction ID, which a response has in common with its
+    /// query.
+    pub fn id(&self) -> u16 {
+        self.header.id
+    }
+
+    /// Returns the EDNS version of the OPT record (RFC 6891), or `None` if the
+    /// message has no OPT record.
+    pub fn edns_version(&self) -> Option<u8> {
+        self.opt().map(|(_, ttl)| (ttl >> 16) as u8)
+    }
+
+    /// Returns the DNSSEC OK bit of the OPT record (RFC 3225), or `None` if
+    /// the message has no OPT record.
+    pub fn dnssec_ok(&self) -> Option<bool> {
+        self.opt().map(|(_, ttl)| ttl & 0x8000 != 0)
+    }
+
+    /// Returns the 12-bit response code of the upper 8 bits in the OPT record
+    /// and the 4 bits of the header, or `None` if the message has no OPT
+    /// record.
+    pub fn extended_rcode(&self) -> Option<u16> {
+        let rcode = u16::from(self.header.rcode.to_u8() & 0b1111);
+        self.opt().map(|(_, ttl)| ((ttl >> 24) as u16) << 4 | rcode)
+    }
+
+    /// Returns the UDP payload size advertised in the OPT record, or `None` if
+    /// the message has no OPT record.
+    pub fn edns_udp_size(&self) -> Option<u16> {
+        self.opt().map(|(size, _)| size)
+    }
+
+    /// Returns the data of the first EDNS option with the code in the OPT
+    /// record, or `None` if there is none.
+    pub fn edns_option(&self, code: u16) -> Option<&[u8]> {
+        self.additional
+            .iter()
+            .find(|r| r.r_type == RecordType::Opt)
+            .and_then(|r| match r.data {
+                RecordData::Other(ref data) => find_edns_option(data, code),
+                _ => None,
+            })
+    }
+
+    /// Returns the client subnet option of the OPT record, or `None` if there
+    /// is none or it does not decode.
+    pub fn client_subnet(&self) -> Option<ClientSubnet> {
+        self.edns_option(EDNS_CLIENT_SUBNET)
+            .and_then(|data| ClientSubnet::decode(data).ok())
+    }
+
+    /// Returns the identifier of the name server which sent the response, see
+    /// `Message::nsid`.
+    pub fn nsid(&self) -> Option<Vec<u8>> {
+        self.edns_option(EDNS_NSID).map(|data| data.to_vec())
+    }
+
+    /// Returns the class and TTL fields of the first OPT record, which hold
+    /// the UDP payload size and the extended RCODE, version, and flags.
+    fn opt(&self) -> Option<(u16, u32)> {
+        self.additional
+            .iter()
+            .find(|r| r.r_type == RecordType::Opt)
+            .map(|r| (r.r_class.to_u16(), r.ttl))
+    }
+
+    /// Returns an iterator over the records in this message.
+    pub fn records(&self) -> OwnedRecordIter {
+        OwnedRecordIter {
+            iters: [
+                self.answer.iter(),
+                self.authority.iter(),
+                self.additional.iter(),
+            ],
+        }
+    }
+
+    /// Returns the NAPTR records of all sections, sorted by order and
+    /// preference.
+    pub fn naptr_records(&self) -> Vec<Naptr> {
+        sorted_naptr(self.records().filter_map(|r| match r.data {
+            RecordData::Naptr(ref naptr) => Some(naptr.clone()),
+            _ => None,
+        }))
+    }
+
+    /// Returns the CAA records of all sections.
+    pub fn caa_records(&self) -> Vec<Caa> {
+        self.records()
+            .filter_map(|r| match r.data {
+                RecordData::Caa(ref caa) => Some(caa.clone()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Returns the DNAME records of all sections.
+    pub fn dname_records(&self) -> Vec<Dname> {
+        self.records()
+            .filter_map(|r| match r.data {
+                RecordData::Dname(ref dname) => Some(dname.clone()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Returns the RRSIG records of all sections.
+    pub fn rrsig_records(&self) -> Vec<Rrsig> {
+        self.records()
+            .filter_map(|r| match r.data {
+                RecordData::Rrsig(ref rrsig) => Some(rrsig.clone()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Returns the DNSKEY records of all sections.
+    pub fn dnskey_records(&self) -> Vec<Dnskey> {
+        self.records()
+            .filter_map(|r| match r.data {
+                RecordData::Dnskey(ref dnskey) => Some(dnskey.clone()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Returns the DS records of all sections.
+    pub fn ds_records(&self) -> Vec<Ds> {
+        self.records()
+            .filter_map(|r| match r.data {
+                RecordData::Ds(ref ds) => Some(ds.clone()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Returns the NSEC records of all sections.
+    pub fn nsec_records(&self) -> Vec<Nsec> {
+        self.records()
+            .filter_map(|r| match r.data {
+                RecordData::Nsec(ref nsec) => Some(nsec.clone()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Returns the NSEC3 records in the message.
+    pub fn nsec3_records(&self) -> Vec<Nsec3> {
+        self.records()
+            .filter_map(|r| match r.data {
+                RecordData::Nsec3(ref nsec3) => Some(nsec3.clone()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Consumes the message and returns an iterator over its records.
+    pub fn into_records(self) -> OwnedRecordIntoIter {
+        OwnedRecordIntoIter {
+            iters: [
+                self.answer.into_iter(),
+                self.authority.into_iter(),
+                self.additional.into_iter(),
+            ],
+        }
+    }
+
+    /// Returns a `Message` with the records of this message encoded.
+    pub fn to_message(&self) -> Result<Message<'static>, EncodeError> {
+        fn to_resources(records: &[OwnedRecord]) -> Result<Vec<Resource<'static>>, EncodeError> {
+            records.iter().map(OwnedRecord::to_resource).collect()
+        }
+
+        Ok(Message {
+            header: self.header,
+            question: self.question.clone(),
+            answer: to_resources(&self.answer)?,
+            authority: to_resources(&self.authority)?,
+            additional: to_resources(&self.additional)?,
+        })
+    }
+}
+
+impl<'a, 'b> From<&'b Message<'a>> for OwnedMessage {
+    fn from(msg: &'b Message<'a>) -> OwnedMessage {
+        OwnedMessage {
+            header: msg.header,
+            question: msg.question.clone(),
+            answer: msg.answer.iter().map(OwnedRecord::from).collect(),
+            authority: msg.authority.iter().map(OwnedRecord::from).collect(),
+            additional: msg.additional.iter().map(OwnedRecord::from).collect(),
+        }
+    }
+}
+
+/// Yields `&OwnedRecord` items from an OwnedMessage.
+pub struct OwnedRecordIter<'a> {
+    iters: [Iter<'a, OwnedRecord>; 3],
+}
+
+impl<'a> Iterator for OwnedRecordIter<'a> {
+    type Item = &'a OwnedRecord;
+
+    fn next(&mut self) -> Option<&'a OwnedRecord> {
+        self.iters[0]
+            .next()
+            .or_else(|| self.iters[1].next())
+            .or_else(|| self.iters[2].next())
+    }
+}
+
+/// Yields `OwnedRecord` items from an OwnedMessage.
+pub struct OwnedRecordIntoIter {
+    iters: [IntoIter<OwnedRecord>; 3],
+}
+
+impl Iterator for OwnedRecordIntoIter {
+    type Item = OwnedRecord;
+
+    fn next(&mut self) -> Option<OwnedRecord> {
+        self.iters[0]
+            .next()
+            .or_else(|| self.iters[1].next())
+            .or_else(|| self.iters[2].next())
+    }
+}
+
+/// Represents a resource record item with decoded data.
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "dns-serde", derive(Serialize, Deserialize))]
+pub struct OwnedRecord {
+    /// Resource name
+    pub name: String,
+    /// Resource type
+    pub r_type: RecordType,
+    /// Resource class
+    pub r_class: Class,
+    /// Time-to-live
+    pub ttl: u32,
+    /// Resource record data
+    pub data: RecordData,
+}
+
+impl OwnedRecord {
+    /// Constructs a new `OwnedRecord`, the record type is taken from the data.
+    ///
+    /// Raw data is stored with `RecordType::Other(0)`, set `r_type` afterwards
+    /// to the actual type.
+    pub fn new(name: String, r_class: Class, ttl: u32, data: RecordData) -> OwnedRecord {
+        OwnedRecord {
+            name: name,
+            r_type: data.record_type().unwrap_or(RecordType::Other(0)),
+            r_class: r_class,
+            ttl: ttl,
+            data: data,
+        }
+    }
+
+    /// Decodes resource data into the given `Record` type.
+    pub fn read_rdata<R: Record>(&self) -> Result<R, DecodeError> {
+        let res = self
+            .to_resource()
+            .map_err(|_| DecodeError::InvalidMessage)?;
+        res.read_rdata()
+    }
+
+    /// Returns a `Resource` with the record data encoded.
+    pub fn to_resource(&self) -> Result<Resource<'static>, EncodeError> {
+        let mut res = Resource::new(self.name.clone(), self.r_type, self.r_class, self.ttl);
+        match self.data {
+            RecordData::A(ref r) => res.write_rdata(r)?,
+            RecordData::AAAA(ref r) => res.write_rdata(r)?,
+            RecordData::CName(ref r) => res.write_rdata(r)?,
+            RecordData::Dname(ref r) => res.write_rdata(r)?,
+            RecordData::Mx(ref r) => res.write_rdata(r)?,
+            RecordData::Ns(ref r) => res.write_rdata(r)?,
+            RecordData::Ptr(ref r) => res.write_rdata(r)?,
+            RecordData::Soa(ref r) => res.write_rdata(r)?,
+            RecordData::Srv(ref r) => res.write_rdata(r)?,
+            RecordData::Txt(ref r) => res.write_rdata(r)?,
+            RecordData::Svcb(ref r) => res.write_rdata(r)?,
+            RecordData::Https(ref r) => res.write_rdata(r)?,
+            RecordData::Naptr(ref r) => res.write_rdata(r)?,
+            RecordData::Caa(ref r) => res.write_rdata(r)?,
+            RecordData::Rrsig(ref r) => res.write_rdata(r)?,
+            RecordData::Dnskey(ref r) => res.write_rdata(r)?,
+            RecordData::Ds(ref r) => res.write_rdata(r)?,
+            RecordData::Nsec(ref r) => res.write_rdata(r)?,
+            RecordData::Nsec3(ref r) => res.write_rdata(r)?,
+            RecordData::Tsig(ref r) => res.write_rdata(r)?,
+            RecordData::Other(ref data) => res.data = Owned(data.clone()),
+        }
+        Ok(res)
+    }
+}
+
+impl<'a, 'b> From<&'b Resource<'a>> for OwnedRecord {
+    /// Decodes the data of known record types. Data which is of an unknown
+    /// type or does not decode as its type is kept raw in `RecordData::Other`.
+    fn from(res: &'b Resource<'a>) -> OwnedRecord {
+        let data = match res.r_type {
+            RecordType::A => res.read_rdata().map(RecordData::A),
+            RecordType::AAAA => res.read_rdata().map(RecordData::AAAA),
+            RecordType::CName => res.read_rdata().map(RecordData::CName),
+            RecordType::Dname => res.read_rdata().map(RecordData::Dname),
+            RecordType::Mx => res.read_rdata().map(RecordData::Mx),
+            RecordType::Ns => res.read_rdata().map(RecordData::Ns),
+            RecordType::Ptr => res.read_rdata().map(RecordData::Ptr),
+            RecordType::Soa => res.read_rdata().map(RecordData::Soa),
+            RecordType::Srv => res.read_rdata().map(RecordData::Srv),
+            RecordType::Txt => res.read_rdata().map(RecordData::Txt),
+            RecordType::Svcb => res.read_rdata().map(RecordData::Svcb),
+            RecordType::Https => res.read_rdata().map(RecordData::Https),
+            RecordType::Naptr => res.read_rdata().map(RecordData::Naptr),
+            RecordType::Caa => res.read_rdata().map(RecordData::Caa),
+            RecordType::Rrsig => res.read_rdata().map(RecordData::Rrsig),
+            RecordType::Dnskey => res.read_rdata().map(RecordData::Dnskey),
+            RecordType::Ds => res.read_rdata().map(RecordData::Ds),
+            RecordType::Nsec => res.read_rdata().map(RecordData::Nsec),
+            RecordType::Nsec3 => res.read_rdata().map(RecordData::Nsec3),
+            RecordType::Tsig => res.read_rdata().map(RecordData::Tsig),
+            RecordType::Opt
+            | RecordType::Ixfr
+            | RecordType::Axfr
+            | RecordType::Any
+            | RecordType::Other(_) => Err(DecodeError::InvalidMessage),
+        };
+
+        OwnedRecord {
+            name: res.name.clone(),
+            r_type: res.r_type,
+            r_class: res.r_class,
+            ttl: res.ttl,
+            data: data.unwrap_or_else(|_| RecordData::Other(res.get_rdata().to_vec())),
+        }
+    }
+}
+
+/// Indicates a message is either a query or response.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "dns-serde", derive(Serialize, Deserialize))]
+#[repr(u8)]
+pub enum Qr {
+    /// Query
+    Query = 0,
+    /// Response
+    Response = 1,
+}
+
+/// Represents the kind of message query.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "dns-serde", derive(Serialize, Deserialize))]
+pub enum OpCode {
+    /// Query
+    Query,
+    /// Status
+    Status,
+    /// Notify
+    Notify,
+    /// Update
+    Update,
+    /// Unrecognized opcode
+    Other(u8),
+}
+
+impl OpCode {
+    /// Converts a `u8` to an `OpCode`.
+    pub fn from_u8(u: u8) -> OpCode {
+        match u {
+            0 => OpCode::Query,
+            2 => OpCode::Status,
+            4 => OpCode::Notify,
+            5 => OpCode::Update,
+            n => OpCode::Other(n),
+        }
+    }
+
+    /// Converts an `OpCode` to a `u8`.
+    pub fn to_u8(&self) -> u8 {
+        match *self {
+            OpCode::Query => 0,
+            OpCode::Status => 2,
+            OpCode::Notify => 4,
+            OpCode::Update => 5,
+            OpCode::Other(n) => n,
+        }
+    }
+}
+
+/// Represents the response code of a message
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+#[cfg_attr(feature = "dns-serde", derive(Serialize, Deserialize))]
+pub enum RCode {
+    /// No error condition.
+    NoError,
+    /// The server was unable to interpret the query.
+    FormatError,
+    /// The name server was unable to process the query due to a failure of
+    /// the name server.
+    ServerFailure,
+    /// Name referenced in query does not exist.
+    NameError,
+    /// Requested query kind is not supported by name server.
+    NotImplemented,
+    /// The name server refuses to perform the specified operation for policy
+    /// reasons.
+    Refused,
+    /// A name exists which should not exist according to an UPDATE prerequisite.
+    YxDomain,
+    /// A record set exists which should not exist according to an UPDATE
+    /// prerequisite.
+    YxRrSet,
+    /// A record set does not exist which should exist according to an UPDATE
+    /// prerequisite.
+    NxRrSet,
+    /// The server is not authoritative for the zone of an UPDATE.
+    NotAuth,
+    /// A name of an UPDATE is not within the zone.
+    NotZone,
+    /// Unknown response code.
+    Other(u8),
+}
+
+impl RCode {
+    /// Returns an error string for the response code.
+    pub fn get_error(&self) -> &'static str {
+        match *self {
+            RCode::NoError => "no error",
+            RCode::FormatError => "format error",
+            RCode::ServerFailure => "server failure",
+            RCode::NameError => "no such name",
+            RCode::NotImplemented => "not implemented",
+            RCode::Refused => "refused",
+            RCode::YxDomain => "name exists when it should not",
+            RCode::YxRrSet => "record set exists when it should not",
+            RCode::NxRrSet => "record set does not exist when it should",
+            RCode::NotAuth => "server not authoritative for zone",
+            RCode::NotZone => "name not contained in zone",
+            RCode::Other(_) => "unknown response code",
+        }
+    }
+
+    /// Converts a `u8` to an `RCode`.
+    pub fn from_u8(u: u8) -> RCode {
+        match u {
+            0 => RCode::NoError,
+            1 => RCode::FormatError,
+            2 => RCode::ServerFailure,
+            3 => RCode::NameError,
+            4 => RCode::NotImplemented,
+            5 => RCode::Refused,
+            6 => RCode::YxDomain,
+            7 => RCode::YxRrSet,
+            8 => RCode::NxRrSet,
+            9 => RCode::NotAuth,
+            10 => RCode::NotZone,
+            n => RCode::Other(n),
+        }
+    }
+
+    /// Converts an `RCode` to a `u8`.
+    pub fn to_u8(&self) -> u8 {
+        match *self {
+            RCode::NoError => 0,
+            RCode::FormatError => 1,
+            RCode::ServerFailure => 2,
+            RCode::NameError => 3,
+            RCode::NotImplemented => 4,
+            RCode::Refused => 5,
+            RCode::YxDomain => 6,
+            RCode::YxRrSet => 7,
+            RCode::NxRrSet => 8,
+            RCode::NotAuth => 9,
+            RCode::NotZone => 10,
+            RCode::Other(n) => n,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
 #[repr(C, packed)]
 struct HeaderData {
     id: u16,
@@ -1095,10 +2835,21 @@ fn to_u16(n: usize) -> Result<u16, EncodeError> {
 
 #[cfg(test)]
 mod test {
-    use super::{is_valid_name, EncodeError, MESSAGE_LIMIT};
-    use super::{Header, Message, OpCode, Qr, Question, RCode};
-    use super::{MsgReader, MsgWriter};
-    use crate::resolve::record::{Class, RecordType};
+    use super::{
+        encoded_name_len, is_valid_name, substitute_dname, ClientSubnet, DecodeError, DnsError,
+        EncodeError, ExtendedError, MESSAGE_LIMIT,
+    };
+    use super::{Header, Message, MessageBuf, OpCode, Qr, Question, RCode};
+    use super::{MsgReader, MsgWriter, OwnedMessage, OwnedRecord, Resource};
+    use crate::resolve::record::{
+        CName, Caa, Class, Dname, Dnskey, Ds, Https, Mx, Nsec, Nsec3, Record, RecordData,
+        RecordType, Rrsig, Srv, SvcParam, Txt, A, AAAA,
+    };
+    use crate::resolve::rng::{RandSource, SeededRandom};
+    #[cfg(feature = "dns-serde")]
+    use serde_json;
+    use std::borrow::Cow::Borrowed;
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 
     #[test]
     fn test_idna_name() {
@@ -1113,18 +2864,89 @@ mod test {
         assert_eq!(
             bytes,
             &b"\
-            \x0dxn--bcher-kva\x02de\x00\
-            \x0exn--kxae4bafwg\x09xn--pxaix\x02gr\x00\
+            \x0dxn--bcher-kva\x02de\x00\
+            \x0exn--kxae4bafwg\x09xn--pxaix\x02gr\x00\
+            "[..]
+        );
+
+        let mut r = MsgReader::new(&bytes);
+
+        assert_eq!(r.read_name().as_ref().map(|s| &s[..]), Ok("bücher.de."));
+        assert_eq!(
+            r.read_name().as_ref().map(|s| &s[..]),
+            Ok("ουτοπία.δπθ.gr.")
+        );
+    }
+
+    #[test]
+    fn test_idna_mapping() {
+        let mut buf = [0; 64];
+        let mut w = MsgWriter::new(&mut buf);
+
+        w.write_name("MÜNCHEN。de").unwrap();
+        w.write_name("xn--mnchen-3ya.de").unwrap();
+        assert_eq!(
+            w.write_name("bad\u{ffff}.de"),
+            Err(EncodeError::InvalidName)
+        );
+        assert_eq!(w.write_name("a。。de"), Err(EncodeError::InvalidName));
+
+        let bytes = w.into_bytes();
+
+        assert_eq!(
+            bytes,
+            &b"\
+            \x0exn--mnchen-3ya\x02de\x00\
+            \x0exn--mnchen-3ya\x02de\x00\
             "[..]
         );
+    }
 
-        let mut r = MsgReader::new(&bytes);
-
-        assert_eq!(r.read_name().as_ref().map(|s| &s[..]), Ok("bücher.de."));
-        assert_eq!(
-            r.read_name().as_ref().map(|s| &s[..]),
-            Ok("ουτοπία.δπθ.gr.")
+    #[test]
+    fn test_randomize_case() {
+        let mut question = Question::new(
+            "www.example-domain.com.".to_owned(),
+            RecordType::A,
+            Class::Internet,
         );
+        question.randomize_case();
+        assert!(question
+            .name
+            .eq_ignore_ascii_case("www.example-domain.com."));
+        question.randomize_case_with(&SeededRandom::new(20));
+        assert_eq!(question.name, "wWW.eXaMPlE-DoMAIN.Com.");
+
+        let mut query = Message::new();
+        query.question.push(Question::new(
+            "wWw.eXaMple-DoMAiN.cOm".to_owned(),
+            RecordType::A,
+            Class::Internet,
+        ));
+        let mut buf = [0; 64];
+        let bytes = query.encode(&mut buf).unwrap();
+        let echoed = Message::decode(bytes).unwrap();
+        assert!(echoed.echoes_question(&query));
+
+        let mut response = query.clone();
+        response.question[0].name = "www.example-domain.com.".to_owned();
+        let bytes = response.encode(&mut buf).unwrap();
+        let response = Message::decode(bytes).unwrap();
+        assert!(!response.echoes_question(&query));
+
+        let mut query = Message::new();
+        query.question.push(Question::new(
+            "xn--mnchen-3ya.de".to_owned(),
+            RecordType::A,
+            Class::Internet,
+        ));
+        for _ in 0..10 {
+            query.question[0].randomize_case();
+            assert_eq!(&query.question[0].name[..15], "xn--mnchen-3ya.");
+        }
+        let bytes = query.encode(&mut buf).unwrap();
+        let echoed = Message::decode(bytes).unwrap();
+        assert_eq!(echoed.question[0].name.to_lowercase(), "münchen.de.");
+        assert!(echoed.echoes_question(&query));
     }
 
     #[test]
@@ -1138,6 +2960,8 @@ mod test {
                 truncated: false,
                 recursion_desired: true,
                 recursion_available: true,
+                authentic_data: false,
+                checking_disabled: false,
                 rcode: RCode::NoError,
             },
             question: vec![Question::new(
@@ -1152,6 +2976,7 @@ mod test {
 
         let mut buf = [0; 64];
         let bytes = msg.encode(&mut buf).unwrap();
+        assert_eq!(msg.encoded_len(), bytes.len());
 
         assert_eq!(
             bytes,
@@ -1166,6 +2991,96 @@ mod test {
         assert_eq!(msg, msg2);
     }
 
+    #[test]
+    fn test_dnssec_flags() {
+        let mut msg = Message::with_id(0xabcd);
+        msg.header.authentic_data = true;
+        let mut buf = [0; 64];
+        assert_eq!(msg.encode(&mut buf).unwrap()[3], 0b00100000);
+        msg.header.authentic_data = false;
+        msg.header.checking_disabled = true;
+        assert_eq!(msg.encode(&mut buf).unwrap()[3], 0b00010000);
+
+        // a validated response with the reserved bit set, which is ignored
+        let data = [0xab, 0xcd, 0b10000001, 0b11100000, 0, 0, 0, 0, 0, 0, 0, 0];
+        let msg = Message::decode(&data).unwrap();
+        assert!(msg.header.recursion_available);
+        assert!(msg.header.authentic_data);
+        assert!(!msg.header.checking_disabled);
+        assert_eq!(msg.header.rcode, RCode::NoError);
+        let owned = OwnedMessage::decode(&data).unwrap();
+        assert!(owned.header.authentic_data);
+    }
+
+    #[test]
+    fn test_client_subnet() {
+        let subnet = ClientSubnet::new("192.0.2.123".parse().unwrap(), 24);
+        assert_eq!(subnet.address, "192.0.2.0".parse::<IpAddr>().unwrap());
+        assert_eq!(subnet.encode(), vec![0, 1, 24, 0, 192, 0, 2]);
+        assert_eq!(ClientSubnet::decode(&subnet.encode()), Ok(subnet));
+        let subnet6 = ClientSubnet::new("2001:db8:abcd::1".parse().unwrap(), 200);
+        assert_eq!(subnet6.source_prefix, 128);
+        assert_eq!(ClientSubnet::opt_out().encode(), vec![0, 1, 0, 0]);
+        // more address bytes than the prefix needs, or bits after it
+        assert_eq!(
+            ClientSubnet::decode(&[0, 1, 16, 0, 192, 0, 2]),
+            Err(DecodeError::InvalidMessage)
+        );
+        assert_eq!(
+            ClientSubnet::decode(&[0, 1, 15, 0, 192, 1]),
+            Err(DecodeError::InvalidMessage)
+        );
+
+        let mut msg = Message::with_id(0xabcd);
+        msg.set_dnssec_ok(true);
+        msg.set_client_subnet(&ClientSubnet::new("198.51.100.1".parse().unwrap(), 24));
+        msg.set_client_subnet(&subnet);
+        let mut buf = [0; 64];
+        let data = msg.encode(&mut buf).unwrap().to_vec();
+        let msg = Message::decode(&data).unwrap();
+        assert_eq!(msg.additional.len(), 1);
+        assert_eq!(msg.dnssec_ok(), Some(true));
+        assert_eq!(msg.client_subnet(), Some(subnet));
+
+        // the scope prefix of a response
+        let mut response = data.clone();
+        let len = response.len();
+        response[len - 4] = 16;
+        let owned = OwnedMessage::decode(&response).unwrap();
+        let scope = owned.client_subnet().unwrap();
+        assert_eq!(scope.scope_prefix, 16);
+        assert_eq!(
+            scope.scope(),
+            ClientSubnet::new("192.0.0.0".parse().unwrap(), 16)
+        );
+    }
+
+    #[test]
+    fn test_encoded_len() {
+        assert_eq!(encoded_name_len("."), 1);
+        assert_eq!(encoded_name_len("foo.bar.com."), 13);
+        assert_eq!(encoded_name_len("foo.bar.com"), 13);
+        assert_eq!(encoded_name_len("MÜNCHEN。de"), 19);
+
+        let mut msg = Message::new();
+        msg.question.push(Question::new(
+            "bücher.de".to_owned(),
+            RecordType::A,
+            Class::Internet,
+        ));
+        let mut rr = Resource::new("bücher.de.".to_owned(), RecordType::A, Class::Internet, 60);
+        rr.write_rdata(&A {
+            address: Ipv4Addr::new(192, 0, 2, 1),
+        })
+        .unwrap();
+        msg.answer.push(rr);
+
+        let mut buf = [0; MESSAGE_LIMIT];
+        let len = msg.encode(&mut buf).unwrap().len();
+        assert_eq!(msg.encoded_len(), len);
+        assert_eq!(len, 12 + (18 + 4) + (18 + 10 + 4));
+    }
+
     #[test]
     fn test_primitives() {
         let mut buf = [0; 64];
@@ -1268,6 +3183,69 @@ mod test {
         );
     }
 
+    #[test]
+    fn test_invalid_label() {
+        for &len in &[0x40, 0x41, 0x7f, 0x80, 0xbf] {
+            let data = [len, b'a', b'b', 0];
+            let mut r = MsgReader::new(&data);
+            assert_eq!(r.read_name(), Err(DecodeError::InvalidLabel));
+        }
+
+        // a query for "a." whose name continues with a 0x40 length byte
+        let mut data = b"\x12\x34\x01\x00\x00\x01\x00\x00\x00\x00\x00\x00".to_vec();
+        data.extend_from_slice(b"\x01a\x40");
+        data.extend_from_slice(&[b'x'; 64]);
+        data.extend_from_slice(b"\x00\x00\x01\x00\x01");
+        assert_eq!(Message::decode(&data), Err(DecodeError::InvalidLabel));
+
+        // the longest label and a compression pointer to it are valid
+        let mut data = vec![63];
+        data.extend_from_slice(&[b'a'; 63]);
+        data.extend_from_slice(b"\x00\xc0\x00");
+        let mut r = MsgReader::new(&data);
+        let name = r.read_name().unwrap();
+        assert_eq!(name.len(), 64);
+        assert_eq!(r.read_name(), Ok(name));
+    }
+
+    #[test]
+    fn test_name_too_long() {
+        // four names of one 63-byte label each, every one continuing with a
+        // pointer to the one before
+        let mut data = Vec::new();
+        let mut offsets = Vec::new();
+        for (i, &c) in b"abcd".iter().enumerate() {
+            offsets.push(data.len());
+            data.push(63);
+            data.extend_from_slice(&[c; 63]);
+            match i {
+                0 => data.push(0),
+                _ => data.extend_from_slice(&[0xc0, offsets[i - 1] as u8]),
+            }
+        }
+        let mut r = MsgReader::new(&data);
+        assert_eq!(r.read_name().unwrap().len(), 64);
+        assert_eq!(r.read_name().unwrap().len(), 128);
+        // 193 bytes in wire format
+        assert_eq!(r.read_name().unwrap().len(), 192);
+        // 257 bytes
+        assert_eq!(r.read_name(), Err(DecodeError::NameTooLong));
+
+        // the same in a message, as the name of an answer to the question
+        let mut msg = b"\x12\x34\x81\x80\x00\x01\x00\x01\x00\x00\x00\x00".to_vec();
+        let question = msg.len();
+        for &c in b"abc" {
+            msg.push(63);
+            msg.extend_from_slice(&[c; 63]);
+        }
+        msg.extend_from_slice(b"\x00\x00\x01\x00\x01");
+        msg.push(63);
+        msg.extend_from_slice(&[b'd'; 63]);
+        msg.extend_from_slice(&[0xc0, question as u8]);
+        msg.extend_from_slice(b"\x00\x01\x00\x01\x00\x00\x00\x3c\x00\x04\xc0\x00\x02\x01");
+        assert_eq!(Message::decode(&msg), Err(DecodeError::NameTooLong));
+    }
+
     #[test]
     fn test_valid_name() {
         assert!(is_valid_name("."));
@@ -1279,4 +3257,1151 @@ mod test {
         assert!(!is_valid_name(".foo.com"));
         assert!(!is_valid_name("foo..bar.com"));
     }
+
+    #[test]
+    fn test_owned_message() {
+        let mut msg = Message::with_id(0x1234);
+        msg.header.qr = Qr::Response;
+        msg.header.authoritative = true;
+        msg.header.recursion_desired = true;
+        msg.header.rcode = RCode::NameError;
+        msg.question.push(Question::new(
+            "foo.com.".to_owned(),
+            RecordType::A,
+            Class::Internet,
+        ));
+
+        let mut res = Resource::new("foo.com.".to_owned(), RecordType::A, Class::Internet, 60);
+        res.write_rdata(&A {
+            address: Ipv4Addr::new(192, 0, 2, 1),
+        })
+        .unwrap();
+        msg.answer.push(res);
+        let mut res = Resource::new("foo.com.".to_owned(), RecordType::AAAA, Class::Internet, 60);
+        res.write_rdata(&AAAA {
+            address: Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1),
+        })
+        .unwrap();
+        msg.answer.push(res);
+        let mut res = Resource::new("foo.com.".to_owned(), RecordType::Mx, Class::Internet, 60);
+        res.write_rdata(&Mx {
+            preference: 10,
+            exchange: "mail.foo.com.".to_owned(),
+        })
+        .unwrap();
+        msg.answer.push(res);
+        let mut res = Resource::new("foo.com.".to_owned(), RecordType::Txt, Class::Internet, 60);
+        res.write_rdata(&Txt {
+            data: b"v=spf1 -all".to_vec(),
+        })
+        .unwrap();
+        msg.authority.push(res);
+        let mut res = Resource::new(
+            "_x._udp.foo.com.".to_owned(),
+            RecordType::Srv,
+            Class::Any,
+            0,
+        );
+        res.write_rdata(&Srv {
+            priority: 1,
+            weight: 2,
+            port: 53,
+            target: "ns.foo.com.".to_owned(),
+        })
+        .unwrap();
+        msg.additional.push(res);
+        msg.additional.push(Resource::new(
+            ".".to_owned(),
+            RecordType::Opt,
+            Class::Other(1232),
+            0,
+        ));
+
+        let mut buf = [0; 512];
+        let bytes = msg.encode(&mut buf).unwrap().to_vec();
+        let owned = OwnedMessage::decode(&bytes).unwrap();
+
+        assert_eq!(owned.header, msg.header);
+        assert_eq!(
+            owned.answer[0].data,
+            RecordData::A(A {
+                address: Ipv4Addr::new(192, 0, 2, 1)
+            })
+        );
+        assert_eq!(
+            owned.additional[0].data,
+            RecordData::Srv(Srv {
+                priority: 1,
+                weight: 2,
+                port: 53,
+                target: "ns.foo.com.".to_owned(),
+            })
+        );
+        assert_eq!(owned.additional[1].r_type, RecordType::Opt);
+        assert_eq!(owned.additional[1].data, RecordData::Other(Vec::new()));
+
+        #[cfg(feature = "dns-serde")]
+        {
+            let json = serde_json::to_string(&owned).unwrap();
+            let owned2: OwnedMessage = serde_json::from_str(&json).unwrap();
+            assert_eq!(owned, owned2);
+        }
+
+        let mut buf2 = [0; 512];
+        assert_eq!(owned.encode(&mut buf2).unwrap(), &bytes[..]);
+    }
+
+    #[test]
+    fn test_into_owned() {
+        let mut buf = [0; 64];
+        let owned = {
+            let bytes = b"\
+                \x12\x34\x81\x80\x00\x01\x00\x01\x00\x00\x00\x00\
+                \x03foo\x03com\x00\x00\x05\x00\x01\
+                \xc0\x0c\x00\x05\x00\x01\x00\x00\x00\x3c\x00\x06\x03www\xc0\x0c";
+            buf[..bytes.len()].copy_from_slice(bytes);
+            let msg = Message::decode(&buf[..bytes.len()]).unwrap();
+            msg.into_owned()
+        };
+        buf = [0; 64]; // the owned message does not refer to the buffer
+
+        let cname = CName {
+            name: "www.foo.com.".to_owned(),
+        };
+        assert_eq!(owned.get_error(), Ok(()));
+        assert_eq!(owned.records().count(), 1);
+        assert_eq!(owned.answer[0].name, "foo.com.");
+        assert_eq!(owned.answer[0].data, RecordData::CName(cname.clone()));
+        assert_eq!(owned.answer[0].read_rdata::<CName>(), Ok(cname.clone()));
+
+        let bytes = owned.encode(&mut buf).unwrap();
+        let msg = Message::decode(bytes).unwrap();
+        assert_eq!(msg.answer[0].get_rdata(), &b"\x03www\x03foo\x03com\x00"[..]);
+        assert_eq!(msg.into_records().next().unwrap().read_rdata(), Ok(cname));
+    }
+
+    #[test]
+    fn test_message_buf() {
+        let bytes = b"\
+            \x12\x34\x81\x80\x00\x01\x00\x02\x00\x00\x00\x00\
+            \x03foo\x03com\x00\x00\x05\x00\x01\
+            \xc0\x0c\x00\x05\x00\x01\x00\x00\x00\x3c\x00\x06\x03www\xc0\x0c\
+            \xc0\x0c\x00\x10\x00\x01\x00\x00\x00\x3c\x00\x03\x02hi";
+        let mut buf = bytes.to_vec();
+        let msg = Message::decode(&buf).unwrap();
+        let owned: MessageBuf = msg.to_owned();
+        assert_eq!(owned, msg);
+        assert_eq!(Message::decode_owned(&buf).unwrap(), msg);
+        // the decompressed name and the copied data instead of the message
+        assert_eq!(
+            owned.answer[0].get_rdata(),
+            &b"\x03www\x03foo\x03com\x00"[..]
+        );
+        assert_eq!(owned.answer[1].get_rdata(), &b"\x02hi"[..]);
+        assert_eq!(owned.encode_vec(), msg.encode_vec());
+        assert_eq!(owned.encode_to_vec(), msg.encode_vec());
+        // a message without compression equals the compressed one
+        let uncompressed = owned.encode_vec().unwrap();
+        assert!(uncompressed.len() > bytes.len());
+        assert_eq!(Message::decode(&uncompressed).unwrap(), msg);
+        assert_eq!(msg, Message::decode(&uncompressed).unwrap());
+        drop(msg);
+        buf.clear();
+
+        // sent to another thread, as between the stack thread and resolver workers
+        let sent = std::thread::spawn(move || owned).join().unwrap();
+        let bytes = sent.encode_vec().unwrap();
+        assert_eq!(bytes.len(), sent.encoded_len());
+        let decoded = Message::decode(&bytes).unwrap();
+        assert_eq!(decoded, sent);
+        assert_eq!(decoded.to_owned(), sent);
+        assert_eq!(OwnedMessage::from(&decoded), OwnedMessage::from(&sent));
+        assert_eq!(OwnedMessage::from(&sent).to_message().unwrap(), sent);
+
+        // the borrowed and owned records differ in their data
+        let mut other = decoded.to_owned();
+        other.answer[0]
+            .write_rdata(&CName {
+                name: "ftp.foo.com.".to_owned(),
+            })
+            .unwrap();
+        assert!(other != decoded);
+        other.answer[0] = decoded.answer[0].to_owned();
+        assert_eq!(other, decoded);
+        other.answer[1].ttl = 1;
+        assert!(other != decoded);
+    }
+
+    #[test]
+    fn test_encode_vec() {
+        let mut msg = Message::with_id(0x1234);
+        msg.question.push(Question::new(
+            "example.com.".to_owned(),
+            RecordType::A,
+            Class::Internet,
+        ));
+        for i in 0..300 {
+            let mut res = Resource::new(
+                format!("host{}.example.com.", i),
+                RecordType::Txt,
+                Class::Internet,
+                60,
+            );
+            res.write_rdata(&Txt {
+                data: vec![b'x'; 250],
+            })
+            .unwrap();
+            msg.answer.push(res);
+        }
+        assert!(msg.encoded_len() > MESSAGE_LIMIT);
+        assert_eq!(msg.encode_vec(), Err(EncodeError::TooLong));
+
+        msg.answer.truncate(200);
+        let bytes = msg.encode_vec().unwrap();
+        assert_eq!(bytes.len(), msg.encoded_len());
+        assert_eq!(Message::decode(&bytes).unwrap(), msg);
+
+        msg.answer.truncate(1);
+        let mut buf = [0; MESSAGE_LIMIT];
+        assert_eq!(msg.encode_vec().unwrap(), msg.encode(&mut buf).unwrap());
+        msg.question[0].name = "bad..name".to_owned();
+        assert_eq!(msg.encode_vec(), Err(EncodeError::InvalidName));
+    }
+
+    #[test]
+    fn test_owned_record() {
+        let rec = OwnedRecord::new(
+            "foo.com.".to_owned(),
+            Class::Internet,
+            300,
+            RecordData::Mx(Mx {
+                preference: 5,
+                exchange: "mx.foo.com.".to_owned(),
+            }),
+        );
+        assert_eq!(rec.r_type, RecordType::Mx);
+
+        #[cfg(feature = "dns-serde")]
+        {
+            let json = serde_json::to_string(&rec).unwrap();
+            assert_eq!(serde_json::from_str::<OwnedRecord>(&json).unwrap(), rec);
+            for rcode in &[RCode::NoError, RCode::Refused, RCode::Other(11)] {
+                let json = serde_json::to_string(rcode).unwrap();
+                assert_eq!(serde_json::from_str::<RCode>(&json).unwrap(), *rcode);
+            }
+        }
+
+        let res = rec.to_resource().unwrap();
+        assert_eq!(OwnedRecord::from(&res), rec);
+    }
+
+    #[test]
+    fn test_dname_records() {
+        // www.sub.example.com. A: a DNAME from sub.example.com. to the compressed
+        // example.com., the synthesized CNAME, and the address of www.example.com.
+        let data = b"\
+            \x12\x34\x81\x80\x00\x01\x00\x03\x00\x00\x00\x00\
+            \x03www\x03sub\x07example\x03com\x00\x00\x01\x00\x01\
+            \xc0\x10\x00\x27\x00\x01\x00\x00\x0e\x10\x00\x02\xc0\x14\
+            \xc0\x0c\x00\x05\x00\x01\x00\x00\x0e\x10\x00\x06\x03www\xc0\x14\
+            \x03www\xc0\x14\x00\x01\x00\x01\x00\x00\x0e\x10\x00\x04\xc0\x00\x02\x01";
+        let mut msg = Message::decode(&data[..]).unwrap();
+        let dname = Dname {
+            target: "example.com.".to_owned(),
+        };
+        assert_eq!(msg.answer[0].r_type, RecordType::Dname);
+        assert_eq!(msg.dname_records(), vec![dname.clone()]);
+        assert_eq!(msg.answer_name(), Some("www.example.com.".to_owned()));
+        assert_eq!(msg.answer[2].name, "www.example.com.");
+
+        let owned = msg.clone().into_owned();
+        assert_eq!(owned.answer[0].data, RecordData::Dname(dname.clone()));
+        assert_eq!(owned.dname_records(), vec![dname]);
+        // the name is decompressed when the record is copied
+        assert_eq!(
+            msg.answer[0].to_owned().get_rdata(),
+            &b"\x07example\x03com\x00"[..]
+        );
+
+        // the DNAME alone redirects the name, without the synthesized CNAME
+        msg.answer.remove(1);
+        assert_eq!(msg.answer_name(), Some("www.example.com.".to_owned()));
+        msg.question[0].name = "WWW.Sub.Example.COM".to_owned();
+        assert_eq!(msg.answer_name(), Some("WWW.example.com.".to_owned()));
+        // but not the owner name itself
+        msg.question[0].name = "sub.example.com.".to_owned();
+        assert_eq!(msg.answer_name(), Some("sub.example.com.".to_owned()));
+        msg.question[0].q_type = RecordType::Dname;
+        msg.question[0].name = "www.sub.example.com.".to_owned();
+        assert_eq!(msg.answer_name(), Some("www.sub.example.com.".to_owned()));
+
+        assert_eq!(substitute_dname("a.b.", "b", "c."), Some("a.c.".to_owned()));
+        assert_eq!(substitute_dname("a.b", ".", "c"), Some("a.b.c.".to_owned()));
+        assert_eq!(substitute_dname("a.b.", "b.", "."), Some("a.".to_owned()));
+        assert_eq!(substitute_dname("a.xb.", "b.", "c."), None);
+        assert_eq!(substitute_dname("b.", "a.b.", "c."), None);
+    }
+
+    #[test]
+    fn test_naptr_records() {
+        // 4.3.2.1.5.5.5.0.0.8.1.e164.arpa. with two NAPTR answers,
+        // the second replacement is compressed to the query name
+        let data = b"\
+            \x12\x34\x81\x80\x00\x01\x00\x02\x00\x00\x00\x00\
+            \x011\x012\x013\x014\x015\x015\x015\x010\x010\x018\x011\x04e164\x04arpa\x00\
+            \x00\x23\x00\x01\
+            \xc0\x0c\x00\x23\x00\x01\x00\x00\x0e\x10\x00\x2b\
+            \x00\x0a\x00\x64\x01u\x07E2U+sip\
+            \x1b!^.*$!sip:info@example.com!\x00\
+            \xc0\x0c\x00\x23\x00\x01\x00\x00\x0e\x10\x00\x19\
+            \x00\x05\x00\x32\x01s\x07SIP+D2U\x03\xff!!\
+            \x04_sip\xc0\x0c";
+        let msg = Message::decode(&data[..]).unwrap();
+        let records = msg.naptr_records();
+        assert_eq!(records.len(), 2);
+
+        assert_eq!(records[0].order, 5);
+        assert_eq!(records[0].preference, 50);
+        assert_eq!(records[0].flags, b"s");
+        assert_eq!(records[0].services, b"SIP+D2U");
+        assert_eq!(records[0].regexp, b"\xff!!");
+        assert_eq!(records[0].regexp_lossy(), "\u{fffd}!!");
+        assert_eq!(
+            records[0].replacement,
+            "_sip.1.2.3.4.5.5.5.0.0.8.1.e164.arpa."
+        );
+
+        assert_eq!(records[1].order, 10);
+        assert_eq!(records[1].preference, 100);
+        assert_eq!(records[1].flags, b"u");
+        assert_eq!(records[1].services, b"E2U+sip");
+        assert_eq!(records[1].regexp_lossy(), "!^.*$!sip:info@example.com!");
+        assert_eq!(records[1].replacement, ".");
+
+        let owned = msg.into_owned();
+        assert_eq!(owned.naptr_records(), records);
+        let mut buf = [0; 512];
+        let data = owned.encode(&mut buf).unwrap();
+        assert_eq!(Message::decode(data).unwrap().naptr_records(), records);
+    }
+
+    #[test]
+    fn test_caa_record() {
+        // example.com. CAA 0 issue "letsencrypt.org"
+        let rdata = b"\x00\x05issueletsencrypt.org";
+        let mut res = Resource::new(
+            "example.com.".to_owned(),
+            RecordType::Caa,
+            Class::Internet,
+            300,
+        );
+        res.data = Borrowed(&rdata[..]);
+
+        let caa = res.read_rdata::<Caa>().unwrap();
+        assert_eq!(caa.flags, 0);
+        assert!(!caa.critical());
+        assert_eq!(caa.tag, "issue");
+        assert_eq!(caa.value, b"letsencrypt.org");
+
+        let mut msg = Message::new();
+        msg.answer.push(res);
+        assert_eq!(msg.caa_records(), vec![caa.clone()]);
+        let owned = msg.into_owned();
+        assert_eq!(owned.caa_records(), vec![caa.clone()]);
+        assert_eq!(
+            owned.answer[0].to_resource().unwrap().get_rdata(),
+            &rdata[..]
+        );
+
+        // a tag which is longer than the rdata is an error, not a panic
+        let rdata = b"\x80\x20issue";
+        assert_eq!(
+            Caa::decode(&mut MsgReader::new(&rdata[..])),
+            Err(DecodeError::ShortMessage)
+        );
+        let rdata = b"\x80\x00";
+        assert_eq!(
+            Caa::decode(&mut MsgReader::new(&rdata[..])),
+            Err(DecodeError::InvalidMessage)
+        );
+    }
+
+    #[test]
+    fn test_dnssec_records() {
+        // the signer name and the next domain are compressed
+        let data = b"\
+            \x00\x00\x81\x80\x00\x01\x00\x02\x00\x00\x00\x00\
+            \x07example\x03com\x00\x00\x01\x00\x01\
+            \xc0\x0c\x00\x2e\x00\x01\x00\x00\x01\x2c\x00\x17\
+            \x00\x01\x0d\x02\x00\x00\x01\x2c\x5f\x00\x00\x00\x5e\x00\x00\x00\x12\x34\
+            \xc0\x0c\xaa\xbb\xcc\
+            \xc0\x0c\x00\x2f\x00\x01\x00\x00\x01\x2c\x00\x11\
+            \x03www\xc0\x0c\x00\x06\x40\x00\x00\x00\x00\x03\x01\x01\x40";
+        let msg = Message::decode(&data[..]).unwrap();
+        let rrsig = Rrsig {
+            type_covered: RecordType::A,
+            algorithm: 13,
+            labels: 2,
+            original_ttl: 300,
+            expiration: 0x5f000000,
+            inception: 0x5e000000,
+            key_tag: 0x1234,
+            signer_name: "example.com.".to_owned(),
+            signature: vec![0xaa, 0xbb, 0xcc],
+        };
+        let nsec = Nsec {
+            next_domain: "www.example.com.".to_owned(),
+            types: vec![
+                RecordType::A,
+                RecordType::Rrsig,
+                RecordType::Nsec,
+                RecordType::Caa,
+            ],
+        };
+        assert_eq!(msg.rrsig_records(), vec![rrsig.clone()]);
+        assert_eq!(msg.nsec_records(), vec![nsec.clone()]);
+        assert!(nsec.has_type(RecordType::Caa));
+        assert!(!nsec.has_type(RecordType::AAAA));
+
+        let owned = msg.into_owned();
+        assert_eq!(owned.rrsig_records(), vec![rrsig]);
+        assert_eq!(owned.nsec_records(), vec![nsec]);
+        // names in the rdata are written without compression
+        assert_eq!(
+            owned.answer[1].to_resource().unwrap().get_rdata(),
+            &b"\x03www\x07example\x03com\x00\x00\x06\x40\x00\x00\x00\x00\x03\x01\x01\x40"[..]
+        );
+
+        // windows must be ascending and their bitmaps 1 to 32 bytes long
+        for rdata in &[
+            &b"\x00\x00\x00"[..],
+            &b"\x00\x00\x21"[..],
+            &b"\x00\x01\x01\x40\x00\x01\x40"[..],
+            &b"\x00\x00\x02\x40"[..],
+        ] {
+            assert!(Nsec::decode(&mut MsgReader::new(rdata)).is_err());
+        }
+    }
+
+    #[test]
+    fn test_dnskey_ds_records() {
+        // dskey.example.com. from section 5.4 of RFC 4034
+        let public_key = &b"\
+            \x01\x03\x9e\x8a\x24\x74\x18\xe3\x18\x90\x3b\x21\x5a\x84\x8a\xcf\
+            \xd5\xf3\x7f\x02\x6b\xd4\x06\x2d\xb2\x6c\x77\x4c\x69\x09\x68\xd5\
+            \xd5\x6d\xf8\xbf\xda\x91\xe6\xf3\x6d\x9a\x27\x98\x88\xf4\x13\x33\
+            \x35\x7c\x5e\x60\x29\x99\x0d\x10\xfd\xf5\x66\x30\x62\xa5\x12\x76\
+            \x33\x26\x98\x0a\x61\x5d\xdb\xf1\x7a\x05\xdd\xfc\xce\x7e\x5f\xb3\
+            \xab\xcc\xa0\x5a\x31\xb0\x95\x74\x52\xd4\x52\x1e\x83\x87\x07\x89\
+            \x06\x31\x15\xbf\x97\xf6\xc3\x08\xcc\xf5\x7c\xdc\x9c\xe7\xfe\x10\
+            \xf6\xed\x1b\xd0\xcc\x06\x60\x03\x8c\x50\xdc\xdb\x0f\xeb\x96\x3c\
+            \x2f\x17"[..];
+        let dnskey = Dnskey {
+            flags: 256,
+            protocol: 3,
+            algorithm: 5,
+            public_key: public_key.to_vec(),
+        };
+        assert!(dnskey.zone_key());
+        assert!(!dnskey.secure_entry_point());
+        assert_eq!(dnskey.key_tag(), 60485);
+
+        let mut res = Resource::new(
+            "dskey.example.com.".to_owned(),
+            RecordType::Dnskey,
+            Class::Internet,
+            86400,
+        );
+        res.write_rdata(&dnskey).unwrap();
+        assert_eq!(&res.get_rdata()[..4], &b"\x01\x00\x03\x05"[..]);
+        assert_eq!(res.read_rdata::<Dnskey>(), Ok(dnskey.clone()));
+
+        let rdata = b"\xec\x45\x05\x01\
+            \x2b\xb1\x83\xaf\x5f\x22\x58\x81\x79\xa5\x3b\x0a\x98\x63\x1f\xad\x1a\x29\x21\x18";
+        let mut ds_res = Resource::new(
+            "dskey.example.com.".to_owned(),
+            RecordType::Ds,
+            Class::Internet,
+            86400,
+        );
+        ds_res.data = Borrowed(&rdata[..]);
+        let ds = ds_res.read_rdata::<Ds>().unwrap();
+        assert_eq!(ds.key_tag, dnskey.key_tag());
+        assert_eq!(ds.algorithm, 5);
+        assert_eq!(ds.digest_type, 1);
+        assert_eq!(ds.digest.len(), 20);
+
+        let mut msg = Message::new();
+        msg.answer.push(res);
+        msg.answer.push(ds_res);
+        assert_eq!(msg.dnskey_records(), vec![dnskey.clone()]);
+        assert_eq!(msg.ds_records(), vec![ds.clone()]);
+        let owned = msg.into_owned();
+        assert_eq!(owned.dnskey_records(), vec![dnskey]);
+        assert_eq!(owned.ds_records(), vec![ds]);
+    }
+
+    #[test]
+    fn test_nsec3_record() {
+        let rdata = b"\x01\x01\x00\x0c\x04\xaa\xbb\xcc\xdd\x02\x12\x34\x00\x01\x62";
+        let mut res = Resource::new(
+            "0p9mhaveqvm6t7vbl5lop2u3t2rp3tom.example.".to_owned(),
+            RecordType::Nsec3,
+            Class::Internet,
+            3600,
+        );
+        res.data = Borrowed(&rdata[..]);
+
+        let nsec3 = res.read_rdata::<Nsec3>().unwrap();
+        assert_eq!(
+            nsec3,
+            Nsec3 {
+                hash_algorithm: 1,
+                flags: 1,
+                iterations: 12,
+                salt: vec![0xaa, 0xbb, 0xcc, 0xdd],
+                next_hashed_owner: vec![0x12, 0x34],
+                types: vec![RecordType::A, RecordType::Ns, RecordType::Soa],
+            }
+        );
+        assert!(nsec3.opt_out());
+        assert!(nsec3.has_type(RecordType::Ns));
+        assert!(!nsec3.has_type(RecordType::Ds));
+
+        let owned = OwnedRecord::from(&res);
+        assert_eq!(owned.data, RecordData::Nsec3(nsec3));
+        assert_eq!(owned.to_resource().unwrap().get_rdata(), &rdata[..]);
+
+        // the next hashed owner must not be empty
+        let rdata = b"\x01\x00\x00\x00\x00\x00\x00\x01\x62";
+        assert_eq!(
+            Nsec3::decode(&mut MsgReader::new(&rdata[..])),
+            Err(DecodeError::InvalidMessage)
+        );
+    }
+
+    #[test]
+    fn test_https_record() {
+        // cloudflare.com. HTTPS 1 . alpn="h3,h2" ipv4hint=104.16.132.229,104.16.133.229
+        let rdata = b"\x00\x01\x00\
+            \x00\x01\x00\x06\x02h3\x02h2\
+            \x00\x04\x00\x08\x68\x10\x84\xe5\x68\x10\x85\xe5";
+        let mut res = Resource::new(
+            "cloudflare.com.".to_owned(),
+            RecordType::Https,
+            Class::Internet,
+            300,
+        );
+        res.data = Borrowed(&rdata[..]);
+
+        let https = res.read_rdata::<Https>().unwrap();
+        assert_eq!(https.priority, 1);
+        assert_eq!(https.target, ".");
+        assert!(!https.is_alias());
+        assert_eq!(https.alpn(), Some(&[b"h3".to_vec(), b"h2".to_vec()][..]));
+        assert_eq!(
+            https.ipv4_hint(),
+            Some(
+                &[
+                    Ipv4Addr::new(104, 16, 132, 229),
+                    Ipv4Addr::new(104, 16, 133, 229)
+                ][..]
+            )
+        );
+        assert_eq!(https.ipv6_hint(), None);
+        assert_eq!(https.port(), None);
+
+        let rec = OwnedRecord::from(&res);
+        assert_eq!(rec.data, RecordData::Https(https.clone()));
+        assert_eq!(rec.to_resource().unwrap().get_rdata(), &rdata[..]);
+
+        // keys out of order, unknown keys kept raw
+        let rdata = b"\x00\x01\x00\x00\x04\x00\x04\x01\x02\x03\x04\x00\x01\x00\x03\x02h2";
+        assert_eq!(
+            Https::decode(&mut MsgReader::new(&rdata[..])),
+            Err(DecodeError::InvalidMessage)
+        );
+        let rdata = b"\x00\x01\x00\x00\x03\x00\x02\x01\xbb\x02\x9a\x00\x01x";
+        let https = Https::decode(&mut MsgReader::new(&rdata[..])).unwrap();
+        assert_eq!(https.port(), Some(443));
+        assert_eq!(
+            https.params[1],
+            SvcParam::Other {
+                key: 666,
+                value: b"x".to_vec()
+            }
+        );
+
+        // a mandatory key which is missing
+        let rdata = b"\x00\x01\x00\x00\x00\x00\x02\x00\x03";
+        assert_eq!(
+            Https::decode(&mut MsgReader::new(&rdata[..])),
+            Err(DecodeError::InvalidMessage)
+        );
+    }
+
+    #[test]
+    fn test_any_response() {
+        // ex.com. ANY with A, MX, and TXT answers, a record of the private
+        // type 65280, and an A record with a short address
+        let data = b"\
+            \x12\x34\x81\x80\x00\x01\x00\x05\x00\x00\x00\x00\
+            \x02ex\x03com\x00\x00\xff\x00\x01\
+            \xc0\x0c\x00\x01\x00\x01\x00\x00\x01\x2c\x00\x04\x0a\x00\x00\x01\
+            \xc0\x0c\x00\x0f\x00\x01\x00\x00\x01\x2c\x00\x07\x00\x0a\x02mx\xc0\x0c\
+            \xc0\x0c\x00\x10\x00\x01\x00\x00\x01\x2c\x00\x06\x05hello\
+            \xc0\x0c\xff\x00\x00\x01\x00\x00\x01\x2c\x00\x03\x01\x02\x03\
+            \xc0\x0c\x00\x01\x00\x01\x00\x00\x01\x2c\x00\x02\x0a\x00";
+        let msg = Message::decode(&data[..]).unwrap();
+        assert_eq!(msg.question[0].q_type, RecordType::Any);
+        let types: Vec<_> = msg.records().map(|r| r.r_type).collect();
+        assert_eq!(
+            types,
+            [
+                RecordType::A,
+                RecordType::Mx,
+                RecordType::Txt,
+                RecordType::Other(65280),
+                RecordType::A
+            ]
+        );
+
+        let owned = OwnedMessage::from(&msg);
+        let data: Vec<_> = owned.records().map(|r| r.data.clone()).collect();
+        assert_eq!(
+            data,
+            [
+                RecordData::A(A {
+                    address: Ipv4Addr::new(10, 0, 0, 1)
+                }),
+                RecordData::Mx(Mx {
+                    preference: 10,
+                    exchange: "mx.ex.com.".to_owned(),
+                }),
+                RecordData::Txt(Txt {
+                    data: b"hello".to_vec()
+                }),
+                RecordData::Other(vec![1, 2, 3]),
+                RecordData::Other(vec![10, 0]),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_unknown_records_verbatim() {
+        // TLSA whose data looks like a compressed name, LOC, and an empty record of
+        // a private type in the additional section
+        let data = b"\
+            \x12\x34\x81\x80\x00\x01\x00\x01\x00\x00\x00\x03\
+            \x02ex\x03com\x00\x00\x01\x00\x01\
+            \x02ex\x03com\x00\x00\x01\x00\x01\x00\x00\x01\x2c\x00\x04\xc0\x00\x02\x01\
+            \x04_443\x04_tcp\x02ex\x03com\x00\x00\x34\x00\x01\x00\x00\x01\x2c\x00\x07\
+            \x03\x01\x01\xc0\x0c\xaa\xbb\
+            \x02ex\x03com\x00\x00\x1d\x00\x01\x00\x00\x01\x2c\x00\x10\
+            \x00\x12\x16\x13\x89\x17\x2d\xd0\x70\xbe\x15\xf0\x00\x98\x8d\x20\
+            \x02ex\x03com\x00\xff\x00\x00\x01\x00\x00\x01\x2c\x00\x00";
+        let msg = Message::decode(&data[..]).unwrap();
+        let types: Vec<_> = msg.additional.iter().map(|r| r.r_type).collect();
+        assert_eq!(
+            types,
+            [
+                RecordType::Other(52),
+                RecordType::Other(29),
+                RecordType::Other(65280)
+            ]
+        );
+        assert_eq!(
+            msg.additional[0].get_rdata(),
+            &b"\x03\x01\x01\xc0\x0c\xaa\xbb"[..]
+        );
+        assert_eq!(msg.encode_vec().unwrap(), &data[..]);
+        assert_eq!(msg.to_owned().encode_vec().unwrap(), &data[..]);
+
+        let owned = OwnedMessage::decode(&data[..]).unwrap();
+        assert_eq!(
+            owned.additional[0].data,
+            RecordData::Other(b"\x03\x01\x01\xc0\x0c\xaa\xbb".to_vec())
+        );
+        assert_eq!(owned.additional[2].data, RecordData::Other(vec![]));
+        assert_eq!(owned.encode_to_vec().unwrap(), &data[..]);
+    }
+
+    #[test]
+    fn test_chaos_query() {
+        let mut msg = Message::with_id(0x1234);
+        msg.question.push(Question::new(
+            "version.bind.".to_owned(),
+            RecordType::Txt,
+            Class::Chaos,
+        ));
+        let mut buf = [0; 64];
+        let bytes = msg.encode(&mut buf).unwrap();
+        assert_eq!(&bytes[bytes.len() - 4..], &[0, 16, 0, 3]);
+        assert_eq!(Message::decode(bytes).unwrap(), msg);
+
+        let data = b"\
+            \x12\x34\x81\x80\x00\x01\x00\x01\x00\x00\x00\x00\
+            \x07version\x04bind\x00\x00\x10\x00\x03\
+            \xc0\x0c\x00\x10\x00\x03\x00\x00\x00\x00\x00\x07\x069.18.1";
+        let msg = Message::decode(&data[..]).unwrap();
+        assert_eq!(msg.answer[0].r_class, Class::Chaos);
+        let owned = OwnedMessage::from(&msg);
+        assert_eq!(
+            owned.answer[0].data,
+            RecordData::Txt(Txt {
+                data: b"9.18.1".to_vec()
+            })
+        );
+
+        assert_eq!(Class::from_u16(4), Class::Hesiod);
+        assert_eq!(Class::Hesiod.to_u16(), 4);
+    }
+
+    #[test]
+    fn test_record_type_round_trip() {
+        for n in 0..=u16::max_value() {
+            let r_type = RecordType::from_u16(n);
+            assert_eq!(r_type.to_u16(), n);
+            let class = Class::from_u16(n);
+            assert_eq!(class.to_u16(), n);
+            // unrecognized numbers are written as in RFC 3597
+            if let RecordType::Other(_) = r_type {
+                assert_eq!(r_type.to_string(), format!("TYPE{}", n));
+            }
+            if let Class::Other(_) = class {
+                assert_eq!(class.to_string(), format!("CLASS{}", n));
+            }
+        }
+        let known = [
+            (RecordType::A, 1, "A"),
+            (RecordType::Ns, 2, "NS"),
+            (RecordType::CName, 5, "CNAME"),
+            (RecordType::Soa, 6, "SOA"),
+            (RecordType::Ptr, 12, "PTR"),
+            (RecordType::Mx, 15, "MX"),
+            (RecordType::Txt, 16, "TXT"),
+            (RecordType::AAAA, 28, "AAAA"),
+            (RecordType::Srv, 33, "SRV"),
+            (RecordType::Naptr, 35, "NAPTR"),
+            (RecordType::Dname, 39, "DNAME"),
+            (RecordType::Opt, 41, "OPT"),
+            (RecordType::Ds, 43, "DS"),
+            (RecordType::Rrsig, 46, "RRSIG"),
+            (RecordType::Nsec, 47, "NSEC"),
+            (RecordType::Dnskey, 48, "DNSKEY"),
+            (RecordType::Nsec3, 50, "NSEC3"),
+            (RecordType::Svcb, 64, "SVCB"),
+            (RecordType::Https, 65, "HTTPS"),
+            (RecordType::Tsig, 250, "TSIG"),
+            (RecordType::Ixfr, 251, "IXFR"),
+            (RecordType::Axfr, 252, "AXFR"),
+            (RecordType::Any, 255, "ANY"),
+            (RecordType::Caa, 257, "CAA"),
+        ];
+        for &(r_type, n, mnemonic) in known.iter() {
+            assert_eq!(RecordType::from_u16(n), r_type);
+            assert_eq!(r_type.to_string(), mnemonic);
+        }
+        assert_eq!(RecordType::from_u16(65280).to_string(), "TYPE65280");
+
+        let classes = [
+            (Class::Internet, 1, "IN"),
+            (Class::Chaos, 3, "CH"),
+            (Class::Hesiod, 4, "HS"),
+            (Class::None, 254, "NONE"),
+            (Class::Any, 255, "ANY"),
+        ];
+        for &(class, n, mnemonic) in classes.iter() {
+            assert_eq!(Class::from_u16(n), class);
+            assert_eq!(class.to_string(), mnemonic);
+        }
+        assert_eq!(Class::from_u16(2).to_string(), "CLASS2");
+    }
+
+    #[test]
+    fn test_edns_accessors() {
+        // response with an OPT record: 1232 bytes, extended RCODE 1 (BADVERS), DO
+        let data = b"\x00\x05\x81\x80\x00\x00\x00\x00\x00\x00\x00\x01\
+                     \x00\x00\x29\x04\xd0\x01\x00\x80\x00\x00\x00";
+        let msg = Message::decode(data).unwrap();
+        assert_eq!(msg.edns_version(), Some(0));
+        assert_eq!(msg.dnssec_ok(), Some(true));
+        assert_eq!(msg.extended_rcode(), Some(16));
+        assert_eq!(msg.edns_udp_size(), Some(1232));
+
+        let owned = OwnedMessage::from(&msg);
+        assert_eq!(owned.dnssec_ok(), Some(true));
+        assert_eq!(owned.extended_rcode(), Some(16));
+
+        let mut msg = Message::new();
+        assert_eq!(msg.edns_version(), None);
+        assert_eq!(msg.dnssec_ok(), None);
+        assert_eq!(msg.extended_rcode(), None);
+        assert_eq!(msg.edns_udp_size(), None);
+
+        msg.header.rcode = RCode::ServerFailure;
+        msg.additional.push(Resource::new(
+            ".".to_owned(),
+            RecordType::Opt,
+            Class::Internet,
+            0x0100_0000,
+        ));
+        assert_eq!(msg.dnssec_ok(), Some(false));
+        assert_eq!(msg.extended_rcode(), Some(0x12));
+        assert_eq!(msg.edns_udp_size(), Some(1));
+    }
+
+    #[test]
+    fn test_nsid() {
+        // response with an OPT record holding the NSID option "ams1"
+        let data = b"\x00\x05\x81\x80\x00\x00\x00\x00\x00\x00\x00\x01\
+                     \x00\x00\x29\x04\xd0\x00\x00\x00\x00\x00\x08\
+                     \x00\x03\x00\x04ams1";
+        let msg = Message::decode(data).unwrap();
+        assert_eq!(msg.nsid(), Some(b"ams1".to_vec()));
+        assert_eq!(OwnedMessage::from(&msg).nsid(), Some(b"ams1".to_vec()));
+
+        let mut query = Message::with_id(5);
+        assert_eq!(query.nsid(), None);
+        query.request_nsid();
+        let mut buf = [0; 64];
+        let bytes = query.encode(&mut buf).unwrap();
+        assert_eq!(&bytes[bytes.len() - 6..], b"\x00\x04\x00\x03\x00\x00");
+        let decoded = Message::decode(bytes).unwrap();
+        assert_eq!(decoded.nsid(), Some(vec![]));
+        assert_eq!(decoded.edns_udp_size(), Some(1232));
+    }
+
+    #[test]
+    fn test_set_dnssec_ok() {
+        let mut msg = Message::with_id(0x1234);
+        msg.question.push(Question::new(
+            "example.com.".to_owned(),
+            RecordType::Dnskey,
+            Class::Internet,
+        ));
+        msg.set_dnssec_ok(false);
+        assert!(msg.additional.is_empty());
+        msg.additional.push(Resource::new(
+            "key.".to_owned(),
+            RecordType::Tsig,
+            Class::Any,
+            0,
+        ));
+        msg.set_dnssec_ok(true);
+        assert_eq!(msg.additional.len(), 2);
+        assert_eq!(msg.additional[1].r_type, RecordType::Tsig);
+
+        let bytes = msg.encode_to_vec().unwrap();
+        let decoded = Message::decode(&bytes).unwrap();
+        assert_eq!(decoded.dnssec_ok(), Some(true));
+        assert_eq!(decoded.edns_udp_size(), Some(1232));
+        assert_eq!(decoded.edns_version(), Some(0));
+        assert_eq!(OwnedMessage::decode(&bytes).unwrap().dnssec_ok(), Some(true));
+
+        // an existing OPT record keeps its payload size
+        msg.additional[0].r_class = Class::Other(4096);
+        msg.set_dnssec_ok(false);
+        msg.set_dnssec_ok(true);
+        assert_eq!(msg.additional.len(), 2);
+        let bytes = msg.encode_to_vec().unwrap();
+        let decoded = Message::decode(&bytes).unwrap();
+        assert_eq!(decoded.dnssec_ok(), Some(true));
+        assert_eq!(decoded.edns_udp_size(), Some(4096));
+        msg.set_dnssec_ok(false);
+        let bytes = msg.encode_to_vec().unwrap();
+        assert_eq!(Message::decode(&bytes).unwrap().dnssec_ok(), Some(false));
+    }
+
+    #[test]
+    fn test_dns_error_eq() {
+        let mut msg = Message::with_id(0x1234);
+        msg.header.qr = Qr::Response;
+        assert_eq!(msg.get_error(), Ok(()));
+        msg.header.rcode = RCode::NameError;
+        let err = msg.get_error().unwrap_err();
+        assert_eq!(err, RCode::NameError);
+        assert_eq!(err, DnsError::from(RCode::NameError));
+        assert!(err != RCode::ServerFailure);
+        let bogus = DnsError(RCode::ServerFailure, Some(ExtendedError::DNSSEC_BOGUS));
+        assert_eq!(bogus, RCode::ServerFailure);
+        assert!(bogus != DnsError::from(RCode::ServerFailure));
+    }
+
+    const CORPUS: &[&[u8]] = &[
+        // query for example.com. A with the RD bit
+        b"\xab\xcd\x01\x00\x00\x01\x00\x00\x00\x00\x00\x00\
+          \x07example\x03com\x00\x00\x01\x00\x01",
+        // www.ex.com. CNAME ex.com., ex.com. A, compressed owner and target
+        b"\x00\x02\x81\x80\x00\x01\x00\x02\x00\x00\x00\x00\
+          \x03www\x02ex\x03com\x00\x00\x01\x00\x01\
+          \xc0\x0c\x00\x05\x00\x01\x00\x00\x00\x3c\x00\x02\xc0\x10\
+          \xc0\x10\x00\x01\x00\x01\x00\x00\x00\x3c\x00\x04\xc0\x00\x02\x01",
+        // ex.com. MX with a compressed exchange and NXDOMAIN with a SOA
+        b"\x00\x03\x81\x80\x00\x01\x00\x01\x00\x00\x00\x00\
+          \x02ex\x03com\x00\x00\x0f\x00\x01\
+          \xc0\x0c\x00\x0f\x00\x01\x00\x00\x01\x2c\x00\x07\x00\x0a\x02mx\xc0\x0c",
+        b"\x00\x04\x81\x83\x00\x01\x00\x00\x00\x01\x00\x00\
+          \x02no\x02ex\x03com\x00\x00\x01\x00\x01\
+          \xc0\x0f\x00\x06\x00\x01\x00\x00\x0e\x10\x00\x21\
+          \x02ns\xc0\x0f\x05admin\xc0\x0f\
+          \x00\x00\x00\x01\x00\x00\x0e\x10\x00\x00\x03\x84\x00\x09\x3a\x80\x00\x00\x01\x2c",
+        // version.bind. TXT CH
+        b"\x12\x34\x81\x80\x00\x01\x00\x01\x00\x00\x00\x00\
+          \x07version\x04bind\x00\x00\x10\x00\x03\
+          \xc0\x0c\x00\x10\x00\x03\x00\x00\x00\x00\x00\x07\x069.18.1",
+    ];
+
+    #[test]
+    fn test_decode_encode_corpus() {
+        for &data in CORPUS {
+            let msg = Message::decode(data).unwrap();
+            let bytes = msg.encode_to_vec().unwrap();
+            assert_eq!(msg.encoded_len(), bytes.len());
+            assert!(msg.encoded_len() >= data.len());
+            let decoded = Message::decode(&bytes).unwrap();
+            assert_eq!(OwnedMessage::from(&decoded), OwnedMessage::from(&msg));
+            assert_eq!(decoded.encode_to_vec().unwrap(), bytes);
+            assert_eq!(OwnedMessage::from(&msg).encode_to_vec().unwrap(), bytes);
+        }
+        // without compression, the bytes are the same
+        assert_eq!(
+            Message::decode(CORPUS[0]).unwrap().encode_to_vec().unwrap(),
+            CORPUS[0]
+        );
+    }
+
+    #[test]
+    fn test_short_rdata() {
+        // an answer whose rdata length exceeds the message
+        let data = b"\x00\x01\x81\x80\x00\x00\x00\x01\x00\x00\x00\x00\
+                     \x00\x00\x01\x00\x01\x00\x00\x00\x00\x00\x04\x7f";
+        assert_eq!(Message::decode(data), Err(DecodeError::ShortMessage));
+        let mut answers = Message::answers_iter(data).unwrap();
+        assert_eq!(answers.next(), Some(Err(DecodeError::ShortMessage)));
+        assert_eq!(answers.next(), None);
+    }
+
+    #[test]
+    fn test_answers_iter() {
+        let data = CORPUS[1];
+        let answers = Message::answers_iter(data).unwrap();
+        assert_eq!(answers.size_hint(), (0, Some(2)));
+        let records: Vec<_> = answers.map(|r| r.unwrap()).collect();
+        assert_eq!(records[0].r_type, RecordType::CName);
+        assert_eq!(records[0].read_rdata::<CName>().unwrap().name, "ex.com.");
+        assert_eq!(records[0].name().unwrap(), "www.ex.com.");
+        let a = records.iter().find(|r| r.r_type == RecordType::A).unwrap();
+        assert_eq!(a.ttl, 60);
+        assert_eq!(a.get_rdata(), &[192, 0, 2, 1][..]);
+        assert_eq!(
+            a.read_rdata::<A>().unwrap().address,
+            Ipv4Addr::new(192, 0, 2, 1)
+        );
+        let msg = Message::decode(data).unwrap();
+        assert_eq!(records[1].to_resource().unwrap(), msg.answer[1]);
+
+        // the sections after the answers are not read
+        let mut data = data.to_vec();
+        data[11] = 1;
+        data.push(0);
+        assert!(Message::decode(&data).is_err());
+        assert_eq!(Message::answers_iter(&data).unwrap().count(), 2);
+        assert_eq!(
+            Message::answers_iter(&data[..11]).err(),
+            Some(DecodeError::ShortMessage)
+        );
+    }
+
+    #[test]
+    fn test_answers_iter_mutated() {
+        // the iterator yields the answers of every mutated message which
+        // decodes, and does not panic on the others
+        let rng = SeededRandom::new(100);
+        for _ in 0..20_000 {
+            let mut data = CORPUS[rng.between(0, CORPUS.len() as u64 - 1) as usize].to_vec();
+            for _ in 0..rng.between(1, 3) {
+                let i = rng.between(0, data.len() as u64 - 1) as usize;
+                data[i] = rng.next_u64() as u8;
+            }
+            if rng.coin() {
+                data.truncate(rng.between(0, data.len() as u64) as usize);
+            }
+            let answers = Message::answers_iter(&data);
+            let msg = match Message::decode(&data) {
+                Ok(msg) => msg,
+                Err(_) => {
+                    for res in answers.into_iter().flatten().flatten() {
+                        let _ = (res.name(), res.to_resource());
+                    }
+                    continue;
+                }
+            };
+            let records: Vec<_> = answers
+                .unwrap()
+                .map(|r| r.and_then(|r| r.to_resource()).unwrap())
+                .collect();
+            assert_eq!(records, msg.answer);
+        }
+    }
+
+    #[test]
+    fn test_answer_records() {
+        let msg = Message::decode(CORPUS[1]).unwrap();
+        assert_eq!(msg.a_records(), vec![Ipv4Addr::new(192, 0, 2, 1)]);
+        assert_eq!(msg.aaaa_records_iter().next(), None);
+        let cnames: Vec<CName> = msg.answer_records().collect();
+        assert_eq!(cnames[0].name, "ex.com.");
+        let msg = Message::decode(CORPUS[2]).unwrap();
+        assert_eq!(msg.mx_records()[0].exchange, "mx.ex.com.");
+        assert!(msg.txt_records().is_empty());
+
+        // records of other types and with data in another form are skipped,
+        // and the data is only decoded up to the record taken
+        let mut msg = Message::decode(CORPUS[1]).unwrap();
+        let mut short = Resource::new("ex.com.".to_owned(), RecordType::A, Class::Internet, 60);
+        short.write_rdata(&Txt { data: vec![1] }).unwrap();
+        msg.answer.insert(0, short);
+        let mut aaaa = Resource::new("ex.com.".to_owned(), RecordType::AAAA, Class::Internet, 60);
+        aaaa.write_rdata(&AAAA {
+            address: Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1),
+        })
+        .unwrap();
+        msg.answer.push(aaaa);
+        let mut a = msg.answer[2].clone();
+        a.write_rdata(&A {
+            address: Ipv4Addr::new(192, 0, 2, 2),
+        })
+        .unwrap();
+        msg.answer.push(a);
+        assert_eq!(
+            msg.a_records_iter().next(),
+            Some(Ipv4Addr::new(192, 0, 2, 1))
+        );
+        assert_eq!(msg.a_records_iter().count(), 2);
+        assert_eq!(
+            msg.aaaa_records(),
+            vec![Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1)]
+        );
+        // the authority and additional sections are not answers
+        msg.authority.push(msg.answer[2].clone());
+        assert_eq!(msg.a_records().len(), 2);
+    }
+
+    #[test]
+    fn test_update() {
+        let mut msg = Message::update("example.com.", Class::Internet);
+        msg.header.id = 0x1234;
+        msg.require_no_rrset("host.example.com.", RecordType::A);
+        msg.add_record(
+            "host.example.com.",
+            300,
+            &A {
+                address: Ipv4Addr::new(192, 0, 2, 1),
+            },
+        )
+        .unwrap();
+
+        let mut buf = [0; 512];
+        let data = msg.encode(&mut buf).unwrap().to_vec();
+        // opcode 5, ZOCOUNT 1, PRCOUNT 1, UPCOUNT 1, ADCOUNT 0
+        assert_eq!(
+            &data[..12],
+            b"\x12\x34\x28\x00\x00\x01\x00\x01\x00\x01\x00\x00"
+        );
+
+        let decoded = Message::decode(&data).unwrap();
+        assert_eq!(decoded.header.op, OpCode::Update);
+        let zone = decoded.zone().unwrap();
+        assert_eq!(zone.name, "example.com.");
+        assert_eq!(zone.q_type, RecordType::Soa);
+
+        let prerequisite = &decoded.prerequisites()[0];
+        assert_eq!(prerequisite.name, "host.example.com.");
+        assert_eq!(prerequisite.r_type, RecordType::A);
+        assert_eq!(prerequisite.r_class, Class::None);
+        assert_eq!(prerequisite.ttl, 0);
+        assert!(prerequisite.get_rdata().is_empty());
+
+        let update = &decoded.updates()[0];
+        assert_eq!(update.r_class, Class::Internet);
+        assert_eq!(update.ttl, 300);
+        assert_eq!(
+            update.read_rdata::<A>().unwrap().address,
+            Ipv4Addr::new(192, 0, 2, 1)
+        );
+        assert_eq!(decoded.encode(&mut buf).unwrap(), &data[..]);
+
+        let mut msg = Message::update("example.com.", Class::Internet);
+        msg.delete_rrset("host.example.com.", RecordType::AAAA);
+        msg.delete_name("old.example.com.");
+        assert_eq!(msg.updates()[0].r_class, Class::Any);
+        assert_eq!(msg.updates()[1].r_type, RecordType::Any);
+
+        let mut response = Message::decode(&data).unwrap();
+        response.header.qr = Qr::Response;
+        response.header.rcode = RCode::from_u8(10);
+        let data = response.encode(&mut buf).unwrap().to_vec();
+        let error = Message::decode(&data).unwrap().get_error().unwrap_err();
+        assert_eq!(error.0, RCode::NotZone);
+        assert_eq!(RCode::YxDomain.to_u8(), 6);
+    }
+
+    #[test]
+    fn test_response() {
+        let mut query = Message::with_id(0x1234);
+        query.set_recursion_desired(true);
+        query.question.push(Question::new(
+            "portal.example.".to_owned(),
+            RecordType::A,
+            Class::Internet,
+        ));
+        query.set_dnssec_ok(true);
+        query.additional[0].r_class = Class::Other(4096);
+
+        let mut response = Message::response(&query);
+        response.set_authoritative(true);
+        response
+            .add_answer(
+                "portal.example.",
+                60,
+                &A {
+                    address: Ipv4Addr::new(10, 0, 0, 1),
+                },
+            )
+            .unwrap();
+        let mut buf = [0; 512];
+        let data = response.encode(&mut buf).unwrap().to_vec();
+        // QR, AA, and RD, and one record of each section but the authority
+        assert_eq!(
+            &data[..12],
+            b"\x12\x34\x85\x00\x00\x01\x00\x01\x00\x00\x00\x01"
+        );
+        let decoded = Message::decode(&data).unwrap();
+        assert!(decoded.echoes_question(&query));
+        assert!(decoded.authoritative() && decoded.recursion_desired());
+        assert!(!decoded.recursion_available() && !decoded.truncated());
+        assert_eq!(decoded.edns_udp_size(), Some(1232));
+        assert_eq!(decoded.dnssec_ok(), Some(true));
+        assert_eq!(decoded.answer[0].r_class, Class::Internet);
+        assert_eq!(decoded.answer[0].ttl, 60);
+
+        let mut refused = Message::response(&Message::decode(&data).unwrap());
+        refused.set_rcode(RCode::Refused);
+        assert_eq!(refused.rcode(), RCode::Refused);
+        assert_eq!(refused.extended_rcode(), Some(5));
+        refused.set_extended_rcode(23);
+        let data = refused.encode(&mut buf).unwrap().to_vec();
+        let decoded = Message::decode(&data).unwrap();
+        assert_eq!(decoded.rcode(), RCode::YxRrSet);
+        assert_eq!(decoded.extended_rcode(), Some(23));
+        assert_eq!(decoded.dnssec_ok(), Some(true));
+
+        // no OPT record without one in the query
+        let mut nxdomain = Message::response(&Message::with_id(1));
+        nxdomain.set_rcode(RCode::NameError);
+        nxdomain.set_recursion_available(true);
+        assert!(nxdomain.additional.is_empty());
+        assert_eq!(nxdomain.get_error().unwrap_err().0, RCode::NameError);
+    }
 }
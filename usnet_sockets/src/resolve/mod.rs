@@ -3,20 +3,75 @@
 #![deny(missing_docs)]
 
 pub use self::address::address_name;
-pub use self::config::DnsConfig;
-pub use self::idna::{to_ascii, to_unicode};
-pub use self::message::{DecodeError, EncodeError, Message, Question, Resource, MESSAGE_LIMIT};
-pub use self::record::{Class, Record, RecordType};
-pub use self::resolver::{resolve_addr, resolve_host, DnsResolver};
-pub use self::socket::{DnsSocket, Error};
+pub use self::config::{
+    default_retry_decision, DnsConfig, Jitter, RetryDecision, RetryPolicy, RetryTimeouts, Transport,
+};
+pub use self::demux::Outstanding;
+#[cfg(feature = "doq")]
+pub use self::doq::{DoqConnection, DoqResolver};
+pub use self::idna::{to_ascii, to_unicode, to_unicode_lossy};
+pub use self::iterative::{Trace, TraceStep};
+#[cfg(feature = "dnssec")]
+pub use self::dnssec::{Security, TrustAnchor};
+#[cfg(feature = "tsig")]
+pub use self::tsig::{TsigError, TsigKey};
+pub use self::message::{
+    Answers, ClientSubnet, DecodeError, DnsError, EncodeError, ExtendedError, Message, MessageBuf,
+    OwnedMessage, OwnedRecord, Question, Resource, ResourceRef, MESSAGE_LIMIT,
+};
+pub use self::name::DnsName;
+pub use self::record::{Class, Record, RecordData, RecordType};
+pub use self::resolver::{
+    resolve_addr, resolve_host, resolve_https, resolve_server_id, resolve_version_bind,
+    DnsResolver, ResolverStats,
+};
+pub use self::rng::{OsRandom, RandSource, SeededRandom};
+pub use self::socket::{
+    DatagramSocket, DnsSocket, DnsSocketBuilder, Error, PacketDirection, PacketHook,
+};
+#[cfg(feature = "dot")]
+pub use self::stream::TlsAuth;
+pub use self::transfer::{axfr, ixfr, ZoneChange, ZoneDelta, ZoneTransfer};
+pub use self::update::UpdateBuilder;
+pub use crate::apimultithread::UdpBindOptions;
+pub use crate::pktinfo::PktInfo;
+
+// Query tracing of the resolver, compiled out without the dns-log feature
+#[cfg(feature = "dns-log")]
+macro_rules! query_log {
+    ($level:ident, $($arg:tt)+) => {
+        $level!($($arg)+)
+    };
+}
+
+#[cfg(not(feature = "dns-log"))]
+macro_rules! query_log {
+    ($level:ident, $($arg:tt)+) => {};
+}
 
 pub mod address;
 pub mod config;
+pub mod cookie;
+pub mod demux;
+#[cfg(feature = "dnssec")]
+pub mod dnssec;
+#[cfg(feature = "doq")]
+pub mod doq;
 pub mod hostname;
 pub mod hosts;
 pub mod idna;
+pub mod iterative;
+#[cfg(feature = "mdns")]
+pub mod mdns;
 pub mod message;
+pub mod name;
 pub mod record;
 pub mod resolv_conf;
 pub mod resolver;
+pub mod rng;
 pub mod socket;
+pub mod stream;
+pub mod transfer;
+pub mod update;
+#[cfg(feature = "tsig")]
+pub mod tsig;
@@ -0,0 +1,488 @@
+//! Domain names which compare as in the DNS
+//!
+//! The names of messages and records are strings like `www.example.com.`.
+//! `DnsName` wraps such a string so that names which only differ in the case
+//! of ASCII letters (RFC 4343), by the trailing dot, or in how a character is
+//! escaped compare and hash equal, e.g., as keys of a map:
+//!
+//! ```ignore
+//! let name = DnsName::new("WWW.Example.com");
+//! assert_eq!(name, DnsName::new("www.example.com."));
+//! assert!(name.is_subdomain_of(&DnsName::new("example.com")));
+//! assert_eq!(name.labels().collect::<Vec<_>>(), ["WWW", "Example", "com"]);
+//! assert_eq!(name.normalize().as_str(), "www.example.com.");
+//! ```
+//!
+//! The labels are separated by dots, and a backslash escapes the next
+//! character, e.g., `\.` for a dot within a label, or stands for the byte of
+//! three decimal digits, e.g., `\032` for a space (RFC 1035 section 5.1).
+//! Letters outside of ASCII are compared as they are, so a Unicode name only
+//! equals its A-label form after `idna::to_ascii`.
+
+use std::cmp::Ordering;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+
+/// A domain name with a trailing dot, which compares case-insensitively, see
+/// the module documentation
+///
+/// Names are ordered in the canonical order of RFC 4034 section 6.1, i.e., by
+/// their labels from the right.
+#[derive(Clone, Debug)]
+pub struct DnsName {
+    name: String,
+}
+
+impl DnsName {
+    /// Returns the name with a trailing dot. The case of the letters and the
+    /// escapes are kept, see `normalize`.
+    pub fn new(name: &str) -> DnsName {
+        let mut name = name.to_owned();
+        if !ends_with_separator(&name) {
+            name.push('.');
+        }
+        DnsName { name: name }
+    }
+
+    /// Returns the root name `.`.
+    pub fn root() -> DnsName {
+        DnsName::new(".")
+    }
+
+    /// Returns the name as a string with a trailing dot.
+    pub fn as_str(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns whether the name is the root name.
+    pub fn is_root(&self) -> bool {
+        self.labels().next().is_none()
+    }
+
+    /// Returns an iterator over the labels of the name from the left, without
+    /// the empty label of the root. The labels keep their escapes.
+    pub fn labels(&self) -> Labels {
+        Labels::new(&self.name)
+    }
+
+    /// Returns the number of labels of the name, 0 for the root name.
+    pub fn label_count(&self) -> usize {
+        self.labels().count()
+    }
+
+    /// Returns the name without its first label, or `None` for the root name.
+    pub fn parent(&self) -> Option<DnsName> {
+        let mut labels = self.labels();
+        labels.next()?;
+        Some(DnsName::new(labels.rest.unwrap_or("")))
+    }
+
+    /// Returns whether the name is the zone or a name below it.
+    pub fn is_subdomain_of(&self, zone: &DnsName) -> bool {
+        is_subdomain(&self.name, &zone.name)
+    }
+
+    /// Returns whether the names are the same and have the same case of the
+    /// letters, as required of the question of a response to a query with a
+    /// random case (0x20).
+    pub fn eq_case(&self, other: &DnsName) -> bool {
+        is_same_case(&self.name, &other.name)
+    }
+
+    /// Returns the name with the ASCII letters in lowercase and characters
+    /// only escaped where needed: dots and backslashes within labels with a
+    /// backslash, and spaces, control characters, and bytes which are not
+    /// UTF-8 in decimal.
+    pub fn normalize(&self) -> DnsName {
+        let mut name = String::with_capacity(self.name.len());
+        for label in self.labels() {
+            let bytes: Vec<u8> = LabelBytes::new(label)
+                .map(|b| b.to_ascii_lowercase())
+                .collect();
+            match String::from_utf8(bytes) {
+                Ok(label) => {
+                    for c in label.chars() {
+                        push_char(&mut name, c)
+                    }
+                }
+                Err(e) => {
+                    for &b in e.as_bytes() {
+                        if b.is_ascii() {
+                            push_char(&mut name, b as char)
+                        } else {
+                            name.push_str(&format!("\\{:03}", b))
+                        }
+                    }
+                }
+            }
+            name.push('.');
+        }
+        DnsName::new(&name)
+    }
+}
+
+impl PartialEq for DnsName {
+    fn eq(&self, other: &DnsName) -> bool {
+        is_same_name(&self.name, &other.name)
+    }
+}
+
+impl Eq for DnsName {}
+
+impl Hash for DnsName {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        for label in self.labels() {
+            for b in LabelBytes::new(label) {
+                state.write_u8(b.to_ascii_lowercase());
+            }
+            // the length keeps `a.bc` apart from `ab.c`
+            state.write_usize(LabelBytes::new(label).count());
+        }
+    }
+}
+
+impl PartialOrd for DnsName {
+    fn partial_cmp(&self, other: &DnsName) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for DnsName {
+    fn cmp(&self, other: &DnsName) -> Ordering {
+        canonical_cmp(&self.name, &other.name)
+    }
+}
+
+impl fmt::Display for DnsName {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.name)
+    }
+}
+
+impl<'a> From<&'a str> for DnsName {
+    fn from(name: &str) -> DnsName {
+        DnsName::new(name)
+    }
+}
+
+impl From<String> for DnsName {
+    fn from(mut name: String) -> DnsName {
+        if !ends_with_separator(&name) {
+            name.push('.');
+        }
+        DnsName { name: name }
+    }
+}
+
+/// Yields the labels of a name, see `DnsName::labels`
+#[derive(Clone, Debug)]
+pub struct Labels<'a> {
+    /// The labels not yet yielded, `None` after the last one
+    rest: Option<&'a str>,
+}
+
+impl<'a> Labels<'a> {
+    pub(crate) fn new(name: &'a str) -> Labels<'a> {
+        let name = if ends_with_separator(name) {
+            &name[..name.len() - 1]
+        } else {
+            name
+        };
+        Labels {
+            rest: if name.is_empty() { None } else { Some(name) },
+        }
+    }
+}
+
+impl<'a> Iterator for Labels<'a> {
+    type Item = &'a str;
+
+    fn next(&mut self) -> Option<&'a str> {
+        let rest = self.rest?;
+        let bytes = rest.as_bytes();
+        let mut i = 0;
+        while i < bytes.len() {
+            match bytes[i] {
+                b'\\' => i += 2,
+                b'.' => {
+                    self.rest = Some(&rest[i + 1..]);
+                    return Some(&rest[..i]);
+                }
+                _ => i += 1,
+            }
+        }
+        self.rest = None;
+        Some(rest)
+    }
+}
+
+impl<'a> DoubleEndedIterator for Labels<'a> {
+    fn next_back(&mut self) -> Option<&'a str> {
+        let rest = self.rest?;
+        match rest
+            .bytes()
+            .enumerate()
+            .rev()
+            .find(|&(i, b)| b == b'.' && !is_escaped(rest, i))
+        {
+            Some((i, _)) => {
+                self.rest = Some(&rest[..i]);
+                Some(&rest[i + 1..])
+            }
+            None => {
+                self.rest = None;
+                Some(rest)
+            }
+        }
+    }
+}
+
+/// Yields the bytes of a label with its escapes replaced
+struct LabelBytes<'a> {
+    label: &'a [u8],
+}
+
+impl<'a> LabelBytes<'a> {
+    fn new(label: &str) -> LabelBytes {
+        LabelBytes {
+            label: label.as_bytes(),
+        }
+    }
+}
+
+impl<'a> Iterator for LabelBytes<'a> {
+    type Item = u8;
+
+    fn next(&mut self) -> Option<u8> {
+        let label = self.label;
+        let (b, n) = match label.len() {
+            0 => return None,
+            1 => (label[0], 1),
+            _ if label[0] != b'\\' => (label[0], 1),
+            len => match decimal_escape(&label[1..len.min(4)]) {
+                Some(b) => (b, 4),
+                None => (label[1], 2),
+            },
+        };
+        self.label = &label[n..];
+        Some(b)
+    }
+}
+
+/// The byte of the three decimal digits after a backslash, if they are
+fn decimal_escape(digits: &[u8]) -> Option<u8> {
+    if digits.len() < 3 || !digits.iter().all(|d| d.is_ascii_digit()) {
+        return None;
+    }
+    let value = digits
+        .iter()
+        .fold(0u16, |value, &d| value * 10 + (d - b'0') as u16);
+    if value > 255 {
+        None
+    } else {
+        Some(value as u8)
+    }
+}
+
+/// Whether the byte at `i` follows an odd number of backslashes
+fn is_escaped(name: &str, i: usize) -> bool {
+    name.as_bytes()[..i]
+        .iter()
+        .rev()
+        .take_while(|&&b| b == b'\\')
+        .count()
+        % 2
+        == 1
+}
+
+fn ends_with_separator(name: &str) -> bool {
+    name.ends_with('.') && !is_escaped(name, name.len() - 1)
+}
+
+fn push_char(name: &mut String, c: char) {
+    match c {
+        '.' | '\\' => {
+            name.push('\\');
+            name.push(c)
+        }
+        c if c.is_ascii() && (c.is_ascii_control() || c == ' ') => {
+            name.push_str(&format!("\\{:03}", c as u8))
+        }
+        c => name.push(c),
+    }
+}
+
+fn label_cmp(a: &str, b: &str) -> Ordering {
+    let lower = |b: u8| b.to_ascii_lowercase();
+    LabelBytes::new(a)
+        .map(lower)
+        .cmp(LabelBytes::new(b).map(lower))
+}
+
+/// Whether the names have the same number of labels and `eq` holds for each
+/// pair of them
+fn labels_eq<F: Fn(&str, &str) -> bool>(a: &str, b: &str, eq: F) -> bool {
+    let (mut a, mut b) = (Labels::new(a), Labels::new(b));
+    loop {
+        match (a.next(), b.next()) {
+            (None, None) => return true,
+            (Some(a), Some(b)) if eq(a, b) => {}
+            _ => return false,
+        }
+    }
+}
+
+/// Returns whether the names are equal, ignoring the case of ASCII letters and
+/// the trailing dot, without allocating.
+pub(crate) fn is_same_name(a: &str, b: &str) -> bool {
+    labels_eq(a, b, |a, b| label_cmp(a, b) == Ordering::Equal)
+}
+
+/// Returns whether the names are equal with the same case of the letters.
+pub(crate) fn is_same_case(a: &str, b: &str) -> bool {
+    labels_eq(a, b, |a, b| LabelBytes::new(a).eq(LabelBytes::new(b)))
+}
+
+/// Returns whether the name is the zone or below it, ignoring the case of
+/// ASCII letters.
+pub(crate) fn is_subdomain(name: &str, zone: &str) -> bool {
+    let mut name = Labels::new(name).rev();
+    Labels::new(zone).rev().all(|zone| {
+        name.next()
+            .map_or(false, |label| label_cmp(label, zone) == Ordering::Equal)
+    })
+}
+
+/// Canonical order of names, see RFC 4034 section 6.1
+pub(crate) fn canonical_cmp(a: &str, b: &str) -> Ordering {
+    let (mut a, mut b) = (Labels::new(a).rev(), Labels::new(b).rev());
+    loop {
+        match (a.next(), b.next()) {
+            (None, None) => return Ordering::Equal,
+            (None, Some(_)) => return Ordering::Less,
+            (Some(_), None) => return Ordering::Greater,
+            (Some(a), Some(b)) => match label_cmp(a, b) {
+                Ordering::Equal => {}
+                order => return order,
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{canonical_cmp, is_same_name, is_subdomain, DnsName};
+    use std::cmp::Ordering;
+    use std::collections::hash_map::DefaultHasher;
+    use std::collections::BTreeMap;
+    use std::hash::{Hash, Hasher};
+
+    fn hash(name: &DnsName) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        name.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    #[test]
+    fn test_dns_name() {
+        let name = DnsName::new("WWW.Example.com");
+        assert_eq!(name.as_str(), "WWW.Example.com.");
+        assert_eq!(name, DnsName::new("www.example.COM."));
+        assert_eq!(hash(&name), hash(&DnsName::new("www.example.com.")));
+        assert!(name != DnsName::new("www.example.co.m"));
+        assert!(hash(&DnsName::new("a.bc")) != hash(&DnsName::new("ab.c")));
+        assert!(name.eq_case(&DnsName::new("WWW.Example.com.")));
+        assert!(!name.eq_case(&DnsName::new("www.example.com.")));
+        assert_eq!(name.normalize().as_str(), "www.example.com.");
+        assert_eq!(name.to_string(), "WWW.Example.com.");
+        assert_eq!(name.labels().collect::<Vec<_>>(), ["WWW", "Example", "com"]);
+        assert_eq!(name.labels().next_back(), Some("com"));
+        assert_eq!(name.label_count(), 3);
+        assert_eq!(name.parent(), Some(DnsName::new("example.com")));
+
+        let root = DnsName::root();
+        assert!(root.is_root() && DnsName::new("").is_root());
+        assert_eq!(root.label_count(), 0);
+        assert_eq!(root.parent(), None);
+        assert_eq!(DnsName::new("com.").parent(), Some(root.clone()));
+        assert!(name.is_subdomain_of(&root));
+        assert!(name.is_subdomain_of(&DnsName::new("EXAMPLE.com")));
+        assert!(name.is_subdomain_of(&name));
+        assert!(!name.is_subdomain_of(&DnsName::new("ample.com")));
+        assert!(!DnsName::new("example.com").is_subdomain_of(&name));
+
+        // München in Unicode and as A-label
+        assert_eq!(DnsName::new("MÜNCHEN.de"), DnsName::new("MÜNCHEN.DE."));
+        assert!(DnsName::new("münchen.de") != DnsName::new("xn--mnchen-3ya.de"));
+        assert_eq!(
+            DnsName::new("münchen.de").normalize().as_str(),
+            "münchen.de."
+        );
+    }
+
+    #[test]
+    fn test_escapes() {
+        // a dot within a label, and an escaped backslash before a separator
+        let name = DnsName::new(r"a\.b.c\\.d");
+        assert_eq!(name.labels().collect::<Vec<_>>(), [r"a\.b", r"c\\", "d"]);
+        assert_eq!(
+            name.labels().rev().collect::<Vec<_>>(),
+            ["d", r"c\\", r"a\.b"]
+        );
+        assert_eq!(DnsName::new(r"a\.").as_str(), r"a\..");
+        assert_eq!(DnsName::new(r"a\.").label_count(), 1);
+        assert_eq!(DnsName::new(r"a\\.").as_str(), r"a\\.");
+
+        // escaped characters equal the characters
+        assert_eq!(DnsName::new(r"\065\098c.com"), DnsName::new("abc.com."));
+        assert_eq!(DnsName::new(r"\a\B\c.com"), DnsName::new("ABC.com."));
+        assert_eq!(DnsName::new(r"a\046b.com"), DnsName::new(r"A\.B.com"));
+        assert!(DnsName::new(r"a\046b.com") != DnsName::new("a.b.com"));
+        assert_eq!(
+            hash(&DnsName::new(r"\065\098c.com")),
+            hash(&DnsName::new("abc.com"))
+        );
+        assert_eq!(
+            DnsName::new(r"\065\.\\\032\200x.com").normalize().as_str(),
+            r"a\.\\\032\200x.com."
+        );
+        assert!(DnsName::new(r"\065.com").eq_case(&DnsName::new("A.com")));
+    }
+
+    #[test]
+    fn test_canonical_order() {
+        // the example of RFC 4034 section 6.1
+        let names = [
+            "example.",
+            "a.example.",
+            "yljkjljk.a.example.",
+            "Z.a.example.",
+            r"zABC.a.EXAMPLE.",
+            "z.example.",
+            r"\001.z.example.",
+            "*.z.example.",
+            r"\200.z.example.",
+        ];
+        for (i, a) in names.iter().enumerate() {
+            for (j, b) in names.iter().enumerate() {
+                assert_eq!(canonical_cmp(a, b), i.cmp(&j), "{} {}", a, b);
+            }
+        }
+        assert_eq!(canonical_cmp(".", "example."), Ordering::Less);
+
+        let mut map = BTreeMap::new();
+        map.insert(DnsName::new("Example.com"), 1);
+        assert_eq!(map.get(&DnsName::new("example.COM.")), Some(&1));
+    }
+
+    #[test]
+    fn test_name_helpers() {
+        assert!(is_same_name("www.Example.com", "WWW.example.com."));
+        assert!(is_same_name(".", ""));
+        assert!(!is_same_name("www.example.com", "example.com"));
+        assert!(is_subdomain("www.Example.com", "example.COM."));
+        assert!(is_subdomain("example.com.", "."));
+        assert!(!is_subdomain("www.badexample.com.", "example.com."));
+        assert!(!is_subdomain(".", "com."));
+    }
+}
@@ -1,17 +1,26 @@
 //! DNS resource record types
 
+use std::borrow::Cow;
+use std::fmt;
 use std::mem::transmute;
-use std::net::{Ipv4Addr, Ipv6Addr};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 
 use super::message::{DecodeError, EncodeError, MsgReader, MsgWriter};
 
 /// Represents the class of data in a message.
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "dns-serde", derive(Serialize, Deserialize))]
 pub enum Class {
     /// Internet (`IN`)
     Internet,
+    /// Chaos (`CH`), used by servers for queries about themselves, e.g., `version.bind`
+    Chaos,
+    /// Hesiod (`HS`)
+    Hesiod,
     /// Any (`*`)
     Any,
+    /// None, for the prerequisites and deletions of a DNS UPDATE (RFC 2136)
+    None,
     /// An unrecognized class
     Other(u16),
 }
@@ -21,6 +30,9 @@ impl Class {
     pub fn from_u16(u: u16) -> Class {
         match u {
             1 => Class::Internet,
+            3 => Class::Chaos,
+            4 => Class::Hesiod,
+            254 => Class::None,
             255 => Class::Any,
             n => Class::Other(n),
         }
@@ -30,14 +42,33 @@ impl Class {
     pub fn to_u16(&self) -> u16 {
         match *self {
             Class::Internet => 1,
+            Class::Chaos => 3,
+            Class::Hesiod => 4,
+            Class::None => 254,
             Class::Any => 255,
             Class::Other(n) => n,
         }
     }
 }
 
+impl fmt::Display for Class {
+    /// Writes the mnemonic of the class as in zone files, or `CLASS` and the
+    /// number for an unrecognized class (RFC 3597).
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Class::Internet => f.write_str("IN"),
+            Class::Chaos => f.write_str("CH"),
+            Class::Hesiod => f.write_str("HS"),
+            Class::None => f.write_str("NONE"),
+            Class::Any => f.write_str("ANY"),
+            Class::Other(n) => write!(f, "CLASS{}", n),
+        }
+    }
+}
+
 /// Represents the type of data in a message.
-#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+#[cfg_attr(feature = "dns-serde", derive(Serialize, Deserialize))]
 pub enum RecordType {
     /// An IPv4 host address
     A,
@@ -45,6 +76,8 @@ pub enum RecordType {
     AAAA,
     /// Canonical name for an alias
     CName,
+    /// Redirection of the names below a domain
+    Dname,
     /// Mail exchange
     Mx,
     /// Authoritative name server
@@ -57,12 +90,40 @@ pub enum RecordType {
     Srv,
     /// Text string
     Txt,
+    /// General purpose service binding
+    Svcb,
+    /// Service binding for HTTPS origins
+    Https,
+    /// Naming authority pointer
+    Naptr,
+    /// Certification authority authorization
+    Caa,
+    /// DNSSEC signature of a record set
+    Rrsig,
+    /// DNSSEC public key of a zone
+    Dnskey,
+    /// Delegation signer, the digest of a DNSKEY of the child zone
+    Ds,
+    /// Next secure record, proves the nonexistence of names and types
+    Nsec,
+    /// Next secure record with hashed owner names
+    Nsec3,
+    /// EDNS pseudo-record (RFC 6891), only in the additional section
+    Opt,
+    /// Transaction signature, only as the last record of a message
+    Tsig,
+    /// Incremental zone transfer (RFC 1995), only in questions
+    Ixfr,
+    /// Whole zone transfer (RFC 5936), only in questions
+    Axfr,
+    /// All records (`*`), only in questions and in the updates of a DNS UPDATE
+    Any,
     /// Unrecognized record type
     Other(u16),
 }
 
 macro_rules! record_types {
-    ( $( $name:ident => $code:expr , )+ ) => {
+    ( $( $name:ident => $code:expr, $mnemonic:expr ; )+ ) => {
         impl RecordType {
             /// Converts a `u16` to a `RecordType`.
             pub fn from_u16(u: u16) -> RecordType {
@@ -80,19 +141,45 @@ macro_rules! record_types {
                 }
             }
         }
+
+        impl fmt::Display for RecordType {
+            /// Writes the mnemonic of the type as in zone files, or `TYPE` and
+            /// the number for an unrecognized type (RFC 3597).
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                match *self {
+                    $( RecordType::$name => f.write_str($mnemonic) , )+
+                    RecordType::Other(n) => write!(f, "TYPE{}", n),
+                }
+            }
+        }
     }
 }
 
 record_types! {
-    A => 1,
-    AAAA => 28,
-    CName => 5,
-    Mx => 15,
-    Ns => 2,
-    Ptr => 12,
-    Soa => 6,
-    Srv => 33,
-    Txt => 16,
+    A => 1, "A";
+    AAAA => 28, "AAAA";
+    CName => 5, "CNAME";
+    Dname => 39, "DNAME";
+    Mx => 15, "MX";
+    Ns => 2, "NS";
+    Ptr => 12, "PTR";
+    Soa => 6, "SOA";
+    Srv => 33, "SRV";
+    Txt => 16, "TXT";
+    Svcb => 64, "SVCB";
+    Https => 65, "HTTPS";
+    Naptr => 35, "NAPTR";
+    Caa => 257, "CAA";
+    Rrsig => 46, "RRSIG";
+    Dnskey => 48, "DNSKEY";
+    Ds => 43, "DS";
+    Nsec => 47, "NSEC";
+    Nsec3 => 50, "NSEC3";
+    Opt => 41, "OPT";
+    Tsig => 250, "TSIG";
+    Ixfr => 251, "IXFR";
+    Axfr => 252, "AXFR";
+    Any => 255, "ANY";
 }
 
 /// Represents resource record data.
@@ -109,6 +196,7 @@ pub trait Record: Sized {
 
 /// An IPv4 host address
 #[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "dns-serde", derive(Serialize, Deserialize))]
 pub struct A {
     /// The host address
     pub address: Ipv4Addr,
@@ -134,6 +222,7 @@ impl Record for A {
 
 /// An IPv6 host address
 #[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "dns-serde", derive(Serialize, Deserialize))]
 pub struct AAAA {
     /// The host address
     pub address: Ipv6Addr,
@@ -174,6 +263,7 @@ impl Record for AAAA {
 
 /// Canonical name for an alias
 #[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "dns-serde", derive(Serialize, Deserialize))]
 pub struct CName {
     /// Canonical host name
     pub name: String,
@@ -195,8 +285,37 @@ impl Record for CName {
     }
 }
 
+/// Redirection of the names below the owner name to the target (RFC 6672)
+///
+/// A name below the owner continues at the same labels below the target,
+/// e.g., with `example.com. DNAME example.net.`, `www.example.com.` becomes
+/// `www.example.net.`, for which servers synthesize a CNAME record.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "dns-serde", derive(Serialize, Deserialize))]
+pub struct Dname {
+    /// Domain name which replaces the owner name
+    pub target: String,
+}
+
+impl Record for Dname {
+    fn decode(data: &mut MsgReader) -> Result<Self, DecodeError> {
+        Ok(Dname {
+            target: data.read_name()?,
+        })
+    }
+
+    fn encode(&self, data: &mut MsgWriter) -> Result<(), EncodeError> {
+        data.write_name(&self.target)
+    }
+
+    fn record_type() -> RecordType {
+        RecordType::Dname
+    }
+}
+
 /// Mail exchange data
 #[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "dns-serde", derive(Serialize, Deserialize))]
 pub struct Mx {
     /// Represents the preference of this record among others.
     /// Lower values are preferred.
@@ -225,6 +344,7 @@ impl Record for Mx {
 
 /// Authoritative name server
 #[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "dns-serde", derive(Serialize, Deserialize))]
 pub struct Ns {
     /// Host which should be authoritative for the specified class and domain
     pub name: String,
@@ -248,6 +368,7 @@ impl Record for Ns {
 
 /// Domain name pointer
 #[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "dns-serde", derive(Serialize, Deserialize))]
 pub struct Ptr {
     /// The name of the host
     pub name: String,
@@ -271,6 +392,7 @@ impl Record for Ptr {
 
 /// Start of authority
 #[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "dns-serde", derive(Serialize, Deserialize))]
 pub struct Soa {
     /// Domain name of the name server that was the original or primary source
     /// of data for this zone.
@@ -323,6 +445,7 @@ impl Record for Soa {
 
 /// Service record
 #[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "dns-serde", derive(Serialize, Deserialize))]
 pub struct Srv {
     /// Record priority
     pub priority: u16,
@@ -359,6 +482,7 @@ impl Record for Srv {
 
 /// Text record
 #[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "dns-serde", derive(Serialize, Deserialize))]
 pub struct Txt {
     /// One or more character strings
     pub data: Vec<u8>,
@@ -379,3 +503,847 @@ impl Record for Txt {
         RecordType::Txt
     }
 }
+
+/// Naming authority pointer, used for SIP and ENUM lookups
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "dns-serde", derive(Serialize, Deserialize))]
+pub struct Naptr {
+    /// Order in which the records must be processed, lowest first
+    pub order: u16,
+    /// Order of records with the same `order`, lowest first
+    pub preference: u16,
+    /// Flags which control the rewriting, e.g., `U` for a terminal rule
+    pub flags: Vec<u8>,
+    /// Service parameters, e.g., `E2U+sip`
+    pub services: Vec<u8>,
+    /// Substitution expression applied to the original string, may be empty
+    pub regexp: Vec<u8>,
+    /// Next name to query, `.` if the regexp is used instead
+    pub replacement: String,
+}
+
+impl Naptr {
+    /// Returns the substitution expression, with invalid UTF-8 replaced.
+    pub fn regexp_lossy(&self) -> Cow<str> {
+        String::from_utf8_lossy(&self.regexp)
+    }
+}
+
+impl Record for Naptr {
+    fn decode(data: &mut MsgReader) -> Result<Self, DecodeError> {
+        Ok(Naptr {
+            order: data.read_u16()?,
+            preference: data.read_u16()?,
+            flags: data.read_character_string()?,
+            services: data.read_character_string()?,
+            regexp: data.read_character_string()?,
+            replacement: data.read_name()?,
+        })
+    }
+
+    fn encode(&self, data: &mut MsgWriter) -> Result<(), EncodeError> {
+        data.write_u16(self.order)?;
+        data.write_u16(self.preference)?;
+        data.write_character_string(&self.flags)?;
+        data.write_character_string(&self.services)?;
+        data.write_character_string(&self.regexp)?;
+        data.write_name(&self.replacement)?;
+        Ok(())
+    }
+
+    fn record_type() -> RecordType {
+        RecordType::Naptr
+    }
+}
+
+/// Certification authority authorization, the policy for issuing certificates
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "dns-serde", derive(Serialize, Deserialize))]
+pub struct Caa {
+    /// Flags, the highest bit marks the property as critical
+    pub flags: u8,
+    /// Property tag, e.g., `issue`, `issuewild`, or `iodef`
+    pub tag: String,
+    /// Property value
+    pub value: Vec<u8>,
+}
+
+impl Caa {
+    /// Whether a certification authority which does not understand the tag must not
+    /// issue a certificate
+    pub fn critical(&self) -> bool {
+        self.flags & 0x80 != 0
+    }
+}
+
+impl Record for Caa {
+    fn decode(data: &mut MsgReader) -> Result<Self, DecodeError> {
+        let flags = data.read_byte()?;
+        let tag = data.read_character_string()?;
+        // tags are 1 to 15 ASCII letters and digits
+        if tag.is_empty() || tag.len() > 15 || !tag.iter().all(u8::is_ascii_alphanumeric) {
+            return Err(DecodeError::InvalidMessage);
+        }
+        Ok(Caa {
+            flags: flags,
+            tag: String::from_utf8(tag).map_err(|_| DecodeError::InvalidMessage)?,
+            value: data.read_to_end()?,
+        })
+    }
+
+    fn encode(&self, data: &mut MsgWriter) -> Result<(), EncodeError> {
+        data.write_byte(self.flags)?;
+        data.write_character_string(self.tag.as_bytes())?;
+        data.write(&self.value)
+    }
+
+    fn record_type() -> RecordType {
+        RecordType::Caa
+    }
+}
+
+/// DNSSEC signature of a record set, as defined in RFC 4034
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "dns-serde", derive(Serialize, Deserialize))]
+pub struct Rrsig {
+    /// Type of the signed records
+    pub type_covered: RecordType,
+    /// Algorithm of the signature, e.g., 8 for RSA/SHA-256 or 13 for ECDSA P-256/SHA-256
+    pub algorithm: u8,
+    /// Number of labels of the owner name, without the root and a leading wildcard
+    pub labels: u8,
+    /// TTL of the records as it is in the zone
+    pub original_ttl: u32,
+    /// End of the validity period, in seconds since the epoch modulo 2^32
+    pub expiration: u32,
+    /// Start of the validity period, in seconds since the epoch modulo 2^32
+    pub inception: u32,
+    /// Key tag of the DNSKEY which validates the signature
+    pub key_tag: u16,
+    /// Zone of the records and the DNSKEY
+    pub signer_name: String,
+    /// Signature over the RRSIG data without the signature and the records
+    pub signature: Vec<u8>,
+}
+
+impl Record for Rrsig {
+    fn decode(data: &mut MsgReader) -> Result<Self, DecodeError> {
+        Ok(Rrsig {
+            type_covered: RecordType::from_u16(data.read_u16()?),
+            algorithm: data.read_byte()?,
+            labels: data.read_byte()?,
+            original_ttl: data.read_u32()?,
+            expiration: data.read_u32()?,
+            inception: data.read_u32()?,
+            key_tag: data.read_u16()?,
+            signer_name: data.read_name()?,
+            signature: data.read_to_end()?,
+        })
+    }
+
+    fn encode(&self, data: &mut MsgWriter) -> Result<(), EncodeError> {
+        data.write_u16(self.type_covered.to_u16())?;
+        data.write_byte(self.algorithm)?;
+        data.write_byte(self.labels)?;
+        data.write_u32(self.original_ttl)?;
+        data.write_u32(self.expiration)?;
+        data.write_u32(self.inception)?;
+        data.write_u16(self.key_tag)?;
+        data.write_name(&self.signer_name)?;
+        data.write(&self.signature)
+    }
+
+    fn record_type() -> RecordType {
+        RecordType::Rrsig
+    }
+}
+
+/// DNSSEC public key of a zone, as defined in RFC 4034
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "dns-serde", derive(Serialize, Deserialize))]
+pub struct Dnskey {
+    /// Flags, see `zone_key` and `secure_entry_point`
+    pub flags: u16,
+    /// Protocol, always 3
+    pub protocol: u8,
+    /// Algorithm of the key
+    pub algorithm: u8,
+    /// Public key in the format of the algorithm
+    pub public_key: Vec<u8>,
+}
+
+impl Dnskey {
+    /// Whether the key signs the records of the zone, only such keys validate RRSIGs
+    pub fn zone_key(&self) -> bool {
+        self.flags & 0x0100 != 0
+    }
+
+    /// Whether the key is a key signing key, i.e., referenced by a DS of the parent zone
+    pub fn secure_entry_point(&self) -> bool {
+        self.flags & 0x0001 != 0
+    }
+
+    /// Key tag which RRSIG and DS records use to refer to the key, as computed in
+    /// appendix B of RFC 4034
+    pub fn key_tag(&self) -> u16 {
+        let key = &self.public_key;
+        if self.algorithm == 1 && key.len() >= 3 {
+            // RSA/MD5 uses bits of the modulus
+            return u16::from_be_bytes([key[key.len() - 3], key[key.len() - 2]]);
+        }
+        let mut rdata = Vec::with_capacity(4 + self.public_key.len());
+        rdata.extend_from_slice(&self.flags.to_be_bytes());
+        rdata.push(self.protocol);
+        rdata.push(self.algorithm);
+        rdata.extend_from_slice(&self.public_key);
+        let mut sum: u32 = 0;
+        for (i, byte) in rdata.iter().enumerate() {
+            sum += if i % 2 == 0 {
+                (*byte as u32) << 8
+            } else {
+                *byte as u32
+            };
+        }
+        sum += (sum >> 16) & 0xffff;
+        (sum & 0xffff) as u16
+    }
+}
+
+impl Record for Dnskey {
+    fn decode(data: &mut MsgReader) -> Result<Self, DecodeError> {
+        Ok(Dnskey {
+            flags: data.read_u16()?,
+            protocol: data.read_byte()?,
+            algorithm: data.read_byte()?,
+            public_key: data.read_to_end()?,
+        })
+    }
+
+    fn encode(&self, data: &mut MsgWriter) -> Result<(), EncodeError> {
+        data.write_u16(self.flags)?;
+        data.write_byte(self.protocol)?;
+        data.write_byte(self.algorithm)?;
+        data.write(&self.public_key)
+    }
+
+    fn record_type() -> RecordType {
+        RecordType::Dnskey
+    }
+}
+
+/// Delegation signer, the digest of a DNSKEY of the child zone, as defined in RFC 4034
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "dns-serde", derive(Serialize, Deserialize))]
+pub struct Ds {
+    /// Key tag of the DNSKEY
+    pub key_tag: u16,
+    /// Algorithm of the DNSKEY
+    pub algorithm: u8,
+    /// Digest algorithm, e.g., 2 for SHA-256
+    pub digest_type: u8,
+    /// Digest over the owner name and the rdata of the DNSKEY
+    pub digest: Vec<u8>,
+}
+
+impl Record for Ds {
+    fn decode(data: &mut MsgReader) -> Result<Self, DecodeError> {
+        Ok(Ds {
+            key_tag: data.read_u16()?,
+            algorithm: data.read_byte()?,
+            digest_type: data.read_byte()?,
+            digest: data.read_to_end()?,
+        })
+    }
+
+    fn encode(&self, data: &mut MsgWriter) -> Result<(), EncodeError> {
+        data.write_u16(self.key_tag)?;
+        data.write_byte(self.algorithm)?;
+        data.write_byte(self.digest_type)?;
+        data.write(&self.digest)
+    }
+
+    fn record_type() -> RecordType {
+        RecordType::Ds
+    }
+}
+
+/// Next secure record, as defined in RFC 4034. No names exist between the owner
+/// and the next domain in the canonical order of the zone, and the owner has only
+/// the listed types.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "dns-serde", derive(Serialize, Deserialize))]
+pub struct Nsec {
+    /// Next owner name in the zone
+    pub next_domain: String,
+    /// Types of the records of the owner, in ascending order
+    pub types: Vec<RecordType>,
+}
+
+impl Nsec {
+    /// Whether the owner has records of the type
+    pub fn has_type(&self, r_type: RecordType) -> bool {
+        self.types.contains(&r_type)
+    }
+}
+
+impl Record for Nsec {
+    fn decode(data: &mut MsgReader) -> Result<Self, DecodeError> {
+        Ok(Nsec {
+            next_domain: data.read_name()?,
+            types: decode_type_bitmap(data)?,
+        })
+    }
+
+    fn encode(&self, data: &mut MsgWriter) -> Result<(), EncodeError> {
+        data.write_name(&self.next_domain)?;
+        encode_type_bitmap(&self.types, data)
+    }
+
+    fn record_type() -> RecordType {
+        RecordType::Nsec
+    }
+}
+
+/// Next secure record with hashed owner names, as defined in RFC 5155. No hashed
+/// names exist between the hash in the owner name and the next hashed owner.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "dns-serde", derive(Serialize, Deserialize))]
+pub struct Nsec3 {
+    /// Hash algorithm, 1 for SHA-1
+    pub hash_algorithm: u8,
+    /// Flags, see `opt_out`
+    pub flags: u8,
+    /// Number of additional hash iterations
+    pub iterations: u16,
+    /// Salt appended to the name in each iteration
+    pub salt: Vec<u8>,
+    /// Next hashed owner name in the zone, not encoded in base32
+    pub next_hashed_owner: Vec<u8>,
+    /// Types of the records of the unhashed owner, in ascending order
+    pub types: Vec<RecordType>,
+}
+
+impl Nsec3 {
+    /// Whether the span may contain unsigned delegations
+    pub fn opt_out(&self) -> bool {
+        self.flags & 0x01 != 0
+    }
+
+    /// Whether the unhashed owner has records of the type
+    pub fn has_type(&self, r_type: RecordType) -> bool {
+        self.types.contains(&r_type)
+    }
+}
+
+impl Record for Nsec3 {
+    fn decode(data: &mut MsgReader) -> Result<Self, DecodeError> {
+        let hash_algorithm = data.read_byte()?;
+        let flags = data.read_byte()?;
+        let iterations = data.read_u16()?;
+        let salt = data.read_character_string()?;
+        let next_hashed_owner = data.read_character_string()?;
+        if next_hashed_owner.is_empty() {
+            return Err(DecodeError::InvalidMessage);
+        }
+        Ok(Nsec3 {
+            hash_algorithm: hash_algorithm,
+            flags: flags,
+            iterations: iterations,
+            salt: salt,
+            next_hashed_owner: next_hashed_owner,
+            types: decode_type_bitmap(data)?,
+        })
+    }
+
+    fn encode(&self, data: &mut MsgWriter) -> Result<(), EncodeError> {
+        data.write_byte(self.hash_algorithm)?;
+        data.write_byte(self.flags)?;
+        data.write_u16(self.iterations)?;
+        data.write_character_string(&self.salt)?;
+        data.write_character_string(&self.next_hashed_owner)?;
+        encode_type_bitmap(&self.types, data)
+    }
+
+    fn record_type() -> RecordType {
+        RecordType::Nsec3
+    }
+}
+
+/// Transaction signature of a message, as defined in RFC 8945
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "dns-serde", derive(Serialize, Deserialize))]
+pub struct Tsig {
+    /// Name of the MAC algorithm, e.g., `hmac-sha256.`
+    pub algorithm: String,
+    /// Time of signing in seconds since the epoch, only 48 bits are encoded
+    pub time_signed: u64,
+    /// Seconds by which the clocks of the signer and the verifier may differ
+    pub fudge: u16,
+    /// MAC over the message and the other fields
+    pub mac: Vec<u8>,
+    /// Message ID before it was changed, e.g., by a forwarder
+    pub original_id: u16,
+    /// Extended response code of a TSIG error, e.g., 16 for BADSIG
+    pub error: u16,
+    /// Server time for a BADTIME error, empty otherwise
+    pub other: Vec<u8>,
+}
+
+impl Record for Tsig {
+    fn decode(data: &mut MsgReader) -> Result<Self, DecodeError> {
+        let algorithm = data.read_name()?;
+        let time_high = data.read_u16()? as u64;
+        let time_low = data.read_u32()? as u64;
+        let fudge = data.read_u16()?;
+        let mut mac = Vec::new();
+        let mac_len = data.read_u16()? as usize;
+        data.read_into(&mut mac, mac_len)?;
+        let original_id = data.read_u16()?;
+        let error = data.read_u16()?;
+        let mut other = Vec::new();
+        let other_len = data.read_u16()? as usize;
+        data.read_into(&mut other, other_len)?;
+        Ok(Tsig {
+            algorithm: algorithm,
+            time_signed: time_high << 32 | time_low,
+            fudge: fudge,
+            mac: mac,
+            original_id: original_id,
+            error: error,
+            other: other,
+        })
+    }
+
+    fn encode(&self, data: &mut MsgWriter) -> Result<(), EncodeError> {
+        if self.mac.len() > 0xffff || self.other.len() > 0xffff {
+            return Err(EncodeError::TooLong);
+        }
+        data.write_name(&self.algorithm)?;
+        data.write_u16((self.time_signed >> 32) as u16)?;
+        data.write_u32(self.time_signed as u32)?;
+        data.write_u16(self.fudge)?;
+        data.write_u16(self.mac.len() as u16)?;
+        data.write(&self.mac)?;
+        data.write_u16(self.original_id)?;
+        data.write_u16(self.error)?;
+        data.write_u16(self.other.len() as u16)?;
+        data.write(&self.other)
+    }
+
+    fn record_type() -> RecordType {
+        RecordType::Tsig
+    }
+}
+
+/// Reads the type bitmap of NSEC and NSEC3 records: blocks of a window number, the length
+/// of the bitmap, and the bitmap of the 256 types of the window, most
+/// significant bit first
+fn decode_type_bitmap(data: &mut MsgReader) -> Result<Vec<RecordType>, DecodeError> {
+    let mut types = Vec::new();
+    let mut last_window = None;
+    while data.remaining() > 0 {
+        let window = data.read_byte()?;
+        let len = data.read_byte()?;
+        if len == 0 || len > 32 || last_window.map_or(false, |last| window <= last) {
+            return Err(DecodeError::InvalidMessage);
+        }
+        last_window = Some(window);
+        let mut bitmap = [0; 32];
+        data.read(&mut bitmap[..len as usize])?;
+        for (i, byte) in bitmap[..len as usize].iter().enumerate() {
+            for bit in 0..8 {
+                if byte & (0x80 >> bit) != 0 {
+                    let code = ((window as u16) << 8) | (i * 8 + bit) as u16;
+                    types.push(RecordType::from_u16(code));
+                }
+            }
+        }
+    }
+    Ok(types)
+}
+
+fn encode_type_bitmap(types: &[RecordType], data: &mut MsgWriter) -> Result<(), EncodeError> {
+    let mut codes: Vec<u16> = types.iter().map(RecordType::to_u16).collect();
+    codes.sort();
+    codes.dedup();
+    let mut i = 0;
+    while i < codes.len() {
+        let window = (codes[i] >> 8) as u8;
+        let mut bitmap = [0u8; 32];
+        let mut len = 0;
+        while i < codes.len() && (codes[i] >> 8) as u8 == window {
+            let low = (codes[i] & 0xff) as usize;
+            bitmap[low / 8] |= 0x80 >> (low % 8);
+            len = low / 8 + 1;
+            i += 1;
+        }
+        data.write_byte(window)?;
+        data.write_byte(len as u8)?;
+        data.write(&bitmap[..len])?;
+    }
+    Ok(())
+}
+
+/// A parameter of a service binding, as defined in RFC 9460
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "dns-serde", derive(Serialize, Deserialize))]
+pub enum SvcParam {
+    /// Keys of the parameters which a client must support to use the record
+    Mandatory(Vec<u16>),
+    /// Protocol identifiers of the supported application protocols, e.g., `h2`
+    Alpn(Vec<Vec<u8>>),
+    /// The default protocol of the scheme is not supported
+    NoDefaultAlpn,
+    /// Port of the service
+    Port(u16),
+    /// IPv4 addresses of the target
+    Ipv4Hint(Vec<Ipv4Addr>),
+    /// Encrypted ClientHello configuration
+    Ech(Vec<u8>),
+    /// IPv6 addresses of the target
+    Ipv6Hint(Vec<Ipv6Addr>),
+    /// Parameter with an unrecognized key and its raw value
+    Other {
+        /// Parameter key
+        key: u16,
+        /// Raw parameter value
+        value: Vec<u8>,
+    },
+}
+
+impl SvcParam {
+    /// Returns the `SvcParamKey` of the parameter.
+    pub fn key(&self) -> u16 {
+        match *self {
+            SvcParam::Mandatory(_) => 0,
+            SvcParam::Alpn(_) => 1,
+            SvcParam::NoDefaultAlpn => 2,
+            SvcParam::Port(_) => 3,
+            SvcParam::Ipv4Hint(_) => 4,
+            SvcParam::Ech(_) => 5,
+            SvcParam::Ipv6Hint(_) => 6,
+            SvcParam::Other { key, .. } => key,
+        }
+    }
+
+    fn decode(key: u16, value: &[u8]) -> Result<SvcParam, DecodeError> {
+        let mut r = MsgReader::new(value);
+        let param = match key {
+            0 => {
+                let mut keys: Vec<u16> = Vec::new();
+                while r.remaining() > 0 {
+                    let k = r.read_u16()?;
+                    // the list is sorted, without duplicates and without mandatory itself
+                    if k == 0 || keys.last().map_or(false, |&last| last >= k) {
+                        return Err(DecodeError::InvalidMessage);
+                    }
+                    keys.push(k);
+                }
+                SvcParam::Mandatory(keys)
+            }
+            1 => {
+                let mut ids = Vec::new();
+                while r.remaining() > 0 {
+                    let id = r.read_character_string()?;
+                    if id.is_empty() {
+                        return Err(DecodeError::InvalidMessage);
+                    }
+                    ids.push(id);
+                }
+                SvcParam::Alpn(ids)
+            }
+            2 => SvcParam::NoDefaultAlpn,
+            3 => SvcParam::Port(r.read_u16()?),
+            4 => {
+                let mut addrs = Vec::new();
+                while r.remaining() > 0 {
+                    let mut buf = [0; 4];
+                    r.read(&mut buf)?;
+                    addrs.push(Ipv4Addr::from(buf));
+                }
+                SvcParam::Ipv4Hint(addrs)
+            }
+            5 => SvcParam::Ech(r.read_to_end()?),
+            6 => {
+                let mut addrs = Vec::new();
+                while r.remaining() > 0 {
+                    let mut buf = [0; 16];
+                    r.read(&mut buf)?;
+                    addrs.push(Ipv6Addr::from(buf));
+                }
+                SvcParam::Ipv6Hint(addrs)
+            }
+            key => SvcParam::Other {
+                key: key,
+                value: r.read_to_end()?,
+            },
+        };
+        let empty = match param {
+            SvcParam::Mandatory(ref v) => v.is_empty(),
+            SvcParam::Alpn(ref v) => v.is_empty(),
+            SvcParam::Ipv4Hint(ref v) => v.is_empty(),
+            SvcParam::Ipv6Hint(ref v) => v.is_empty(),
+            _ => false,
+        };
+        if empty {
+            return Err(DecodeError::InvalidMessage);
+        }
+        if r.remaining() != 0 {
+            return Err(DecodeError::ExtraneousData);
+        }
+        Ok(param)
+    }
+
+    fn encode_value(&self, data: &mut MsgWriter) -> Result<(), EncodeError> {
+        match *self {
+            SvcParam::Mandatory(ref keys) => {
+                for &k in keys {
+                    data.write_u16(k)?;
+                }
+            }
+            SvcParam::Alpn(ref ids) => {
+                for id in ids {
+                    data.write_character_string(id)?;
+                }
+            }
+            SvcParam::NoDefaultAlpn => (),
+            SvcParam::Port(port) => data.write_u16(port)?,
+            SvcParam::Ipv4Hint(ref addrs) => {
+                for addr in addrs {
+                    data.write(&addr.octets())?;
+                }
+            }
+            SvcParam::Ech(ref value) | SvcParam::Other { ref value, .. } => data.write(value)?,
+            SvcParam::Ipv6Hint(ref addrs) => {
+                for addr in addrs {
+                    data.write(&addr.octets())?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn decode_svc_params(data: &mut MsgReader) -> Result<Vec<SvcParam>, DecodeError> {
+    let mut params: Vec<SvcParam> = Vec::new();
+    while data.remaining() > 0 {
+        let key = data.read_u16()?;
+        if params.last().map_or(false, |last| last.key() >= key) {
+            // keys must be strictly increasing
+            return Err(DecodeError::InvalidMessage);
+        }
+        let len = data.read_u16()? as usize;
+        let mut value = Vec::with_capacity(len);
+        data.read_into(&mut value, len)?;
+        params.push(SvcParam::decode(key, &value)?);
+    }
+    let mandatory_present = params.iter().all(|p| match *p {
+        SvcParam::Mandatory(ref keys) => keys
+            .iter()
+            .all(|&k| params.iter().any(|other| other.key() == k)),
+        _ => true,
+    });
+    if !mandatory_present {
+        return Err(DecodeError::InvalidMessage);
+    }
+    Ok(params)
+}
+
+fn encode_svc_params(params: &[SvcParam], data: &mut MsgWriter) -> Result<(), EncodeError> {
+    let mut sorted: Vec<&SvcParam> = params.iter().collect();
+    sorted.sort_by_key(|p| p.key());
+    for param in sorted {
+        let mut buf = [0; 65535];
+        let mut w = MsgWriter::new(&mut buf[..]);
+        param.encode_value(&mut w)?;
+        let value = w.into_bytes();
+        data.write_u16(param.key())?;
+        data.write_u16(value.len() as u16)?;
+        data.write(value)?;
+    }
+    Ok(())
+}
+
+macro_rules! service_binding {
+    ( $( #[$attr:meta] )* $name:ident ) => {
+        $( #[$attr] )*
+        #[derive(Clone, Debug, PartialEq, Eq, Hash)]
+        #[cfg_attr(feature = "dns-serde", derive(Serialize, Deserialize))]
+        pub struct $name {
+            /// Priority of the record, 0 for an alias to the target name
+            pub priority: u16,
+            /// Target name, `.` for the owner name of the record
+            pub target: String,
+            /// Service parameters, sorted by key
+            pub params: Vec<SvcParam>,
+        }
+
+        impl $name {
+            /// Whether the record is an alias to the target name without parameters
+            pub fn is_alias(&self) -> bool {
+                self.priority == 0
+            }
+
+            /// Returns the supported application protocols.
+            pub fn alpn(&self) -> Option<&[Vec<u8>]> {
+                self.params.iter().filter_map(|p| match *p {
+                    SvcParam::Alpn(ref ids) => Some(&ids[..]),
+                    _ => None,
+                }).next()
+            }
+
+            /// Returns the port of the service.
+            pub fn port(&self) -> Option<u16> {
+                self.params.iter().filter_map(|p| match *p {
+                    SvcParam::Port(port) => Some(port),
+                    _ => None,
+                }).next()
+            }
+
+            /// Returns the IPv4 addresses of the target, which can be used without
+            /// resolving it.
+            pub fn ipv4_hint(&self) -> Option<&[Ipv4Addr]> {
+                self.params.iter().filter_map(|p| match *p {
+                    SvcParam::Ipv4Hint(ref addrs) => Some(&addrs[..]),
+                    _ => None,
+                }).next()
+            }
+
+            /// Returns the IPv6 addresses of the target, which can be used without
+            /// resolving it.
+            pub fn ipv6_hint(&self) -> Option<&[Ipv6Addr]> {
+                self.params.iter().filter_map(|p| match *p {
+                    SvcParam::Ipv6Hint(ref addrs) => Some(&addrs[..]),
+                    _ => None,
+                }).next()
+            }
+
+            /// Returns the socket addresses of the IP hints with the port of the
+            /// record or the default port, alternating between IPv6 and IPv4 as
+            /// Happy Eyeballs (RFC 8305) tries them.
+            pub fn hint_addrs(&self, default_port: u16) -> Vec<SocketAddr> {
+                let port = self.port().unwrap_or(default_port);
+                let v6 = self.ipv6_hint().unwrap_or(&[]);
+                let v4 = self.ipv4_hint().unwrap_or(&[]);
+                let mut addrs = Vec::with_capacity(v6.len() + v4.len());
+                for i in 0..v6.len().max(v4.len()) {
+                    if let Some(&ip) = v6.get(i) {
+                        addrs.push(SocketAddr::new(IpAddr::V6(ip), port));
+                    }
+                    if let Some(&ip) = v4.get(i) {
+                        addrs.push(SocketAddr::new(IpAddr::V4(ip), port));
+                    }
+                }
+                addrs
+            }
+        }
+
+        impl Record for $name {
+            fn decode(data: &mut MsgReader) -> Result<Self, DecodeError> {
+                Ok($name {
+                    priority: data.read_u16()?,
+                    target: data.read_name()?,
+                    params: decode_svc_params(data)?,
+                })
+            }
+
+            fn encode(&self, data: &mut MsgWriter) -> Result<(), EncodeError> {
+                data.write_u16(self.priority)?;
+                data.write_name(&self.target)?;
+                encode_svc_params(&self.params, data)
+            }
+
+            fn record_type() -> RecordType {
+                RecordType::$name
+            }
+        }
+    }
+}
+
+service_binding! {
+    /// General purpose service binding
+    Svcb
+}
+
+service_binding! {
+    /// Service binding for HTTPS origins, with the same format as `Svcb`
+    Https
+}
+
+/// Decoded data of a resource record of any supported type
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "dns-serde", derive(Serialize, Deserialize))]
+pub enum RecordData {
+    /// An IPv4 host address
+    A(A),
+    /// An IPv6 host address
+    AAAA(AAAA),
+    /// Canonical name for an alias
+    CName(CName),
+    /// Redirection of the names below a domain
+    Dname(Dname),
+    /// Mail exchange
+    Mx(Mx),
+    /// Authoritative name server
+    Ns(Ns),
+    /// Domain name pointer
+    Ptr(Ptr),
+    /// Start of authority
+    Soa(Soa),
+    /// Service record
+    Srv(Srv),
+    /// Text string
+    Txt(Txt),
+    /// General purpose service binding
+    Svcb(Svcb),
+    /// Service binding for HTTPS origins
+    Https(Https),
+    /// Naming authority pointer
+    Naptr(Naptr),
+    /// Certification authority authorization
+    Caa(Caa),
+    /// DNSSEC signature of a record set
+    Rrsig(Rrsig),
+    /// DNSSEC public key of a zone
+    Dnskey(Dnskey),
+    /// Delegation signer
+    Ds(Ds),
+    /// Next secure record
+    Nsec(Nsec),
+    /// Next secure record with hashed owner names
+    Nsec3(Nsec3),
+    /// Transaction signature
+    Tsig(Tsig),
+    /// Raw rdata of an unrecognized record type or of data which could not
+    /// be decoded as its record type
+    Other(Vec<u8>),
+}
+
+impl RecordData {
+    /// Returns the `RecordType` of the wrapped record, or `None` for raw data.
+    pub fn record_type(&self) -> Option<RecordType> {
+        match *self {
+            RecordData::A(_) => Some(RecordType::A),
+            RecordData::AAAA(_) => Some(RecordType::AAAA),
+            RecordData::CName(_) => Some(RecordType::CName),
+            RecordData::Dname(_) => Some(RecordType::Dname),
+            RecordData::Mx(_) => Some(RecordType::Mx),
+            RecordData::Ns(_) => Some(RecordType::Ns),
+            RecordData::Ptr(_) => Some(RecordType::Ptr),
+            RecordData::Soa(_) => Some(RecordType::Soa),
+            RecordData::Srv(_) => Some(RecordType::Srv),
+            RecordData::Txt(_) => Some(RecordType::Txt),
+            RecordData::Svcb(_) => Some(RecordType::Svcb),
+            RecordData::Https(_) => Some(RecordType::Https),
+            RecordData::Naptr(_) => Some(RecordType::Naptr),
+            RecordData::Caa(_) => Some(RecordType::Caa),
+            RecordData::Rrsig(_) => Some(RecordType::Rrsig),
+            RecordData::Dnskey(_) => Some(RecordType::Dnskey),
+            RecordData::Ds(_) => Some(RecordType::Ds),
+            RecordData::Nsec(_) => Some(RecordType::Nsec),
+            RecordData::Nsec3(_) => Some(RecordType::Nsec3),
+            RecordData::Tsig(_) => Some(RecordType::Tsig),
+            RecordData::Other(_) => None,
+        }
+    }
+}
@@ -1,13 +1,18 @@
 //! Partial Unix `resolv.conf(5)` parser
 
 use std::cmp::min;
+use std::collections::BTreeMap;
 use std::fs::File;
 use std::io::{self, BufRead, BufReader};
 use std::net::{IpAddr, SocketAddr};
 use std::time::Duration;
 
-use super::config::DnsConfig;
+use super::config::{
+    default_retry_decision, DnsConfig, RetryPolicy, DEFAULT_BATCH_LIMIT, DEFAULT_FAN_OUT,
+    DEFAULT_HANDSHAKE_TIMEOUT, DEFAULT_MDNS_WINDOW,
+};
 use super::hostname::get_hostname;
+use super::iterative::root_hints;
 
 /// port for DNS communication
 const DNS_PORT: u16 = 53;
@@ -43,9 +48,22 @@ fn default_config() -> DnsConfig {
 
         n_dots: DEFAULT_N_DOTS,
         attempts: DEFAULT_ATTEMPTS,
+        transports: BTreeMap::new(),
+        handshake_timeout: DEFAULT_HANDSHAKE_TIMEOUT,
         timeout: Duration::from_secs(DEFAULT_TIMEOUT),
+        retry: RetryPolicy::default(),
+        retry_decision: default_retry_decision,
+        fan_out: DEFAULT_FAN_OUT,
+        batch_limit: DEFAULT_BATCH_LIMIT,
+        root_hints: root_hints(),
+        client_subnet: None,
 
         rotate: false,
+        randomize_case: false,
+        cookies: false,
+        nsid: false,
+        mdns: cfg!(feature = "mdns"),
+        mdns_window: DEFAULT_MDNS_WINDOW,
         use_inet6: false,
     }
 }
@@ -1,25 +1,201 @@
 //! High-level resolver operations
 
-use crate::apimultithread::UsnetToSocketAddrs;
-use std::cell::Cell;
+use crate::apimultithread::{UdpSocket, UsnetToSocketAddrs};
+use std::cmp::min;
+use std::collections::BTreeMap;
 use std::io;
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex, PoisonError};
 use std::time::{Duration, Instant};
+#[cfg(feature = "dnssec")]
+use std::time::{SystemTime, UNIX_EPOCH};
 use std::vec::IntoIter;
 
-use super::address::address_name;
-use super::config::DnsConfig;
-use super::message::{Message, Qr, Question, MESSAGE_LIMIT};
-use super::record::{Class, Ptr, Record, RecordType, A, AAAA};
-use super::socket::{DnsSocket, Error};
+use super::address::{address_name, socket_address_equal};
+use super::config::{DnsConfig, RetryDecision, Transport};
+use super::cookie::{Cookies, BADCOOKIE};
+#[cfg(feature = "dnssec")]
+use super::dnssec::{Response, Security, TrustAnchor, Validator};
+use super::idna;
+use super::iterative::{self, Delegations, Trace, TraceStep, QUERY_LIMIT};
+#[cfg(feature = "mdns")]
+use super::mdns;
+use super::message::{
+    ClientSubnet, DnsError, EncodeError, Message, MessageBuf, OwnedMessage, OwnedRecord, Qr,
+    Question, RCode, ALIAS_LIMIT, EDNS_COOKIE, MESSAGE_LIMIT,
+};
+use super::name::{is_same_name, is_subdomain, DnsName};
+use super::record::{Class, Https, Ptr, Record, RecordType, Txt, A, AAAA};
+use super::rng::{OsRandom, RandSource};
+use super::socket::{DatagramSocket, DnsSocket, Error, PacketDirection};
+use super::stream::{Connections, UDP_QUERY_LIMIT};
+use super::transfer::{self, ZoneDelta, ZoneTransfer};
+#[cfg(feature = "tsig")]
+use super::tsig::{Clock, SystemClock, TsigError, TsigKey};
 
 /// Performs resolution operations
-pub struct DnsResolver {
-    sock: DnsSocket,
+///
+/// The queries go through a `UdpSocket` of the multithread API unless the
+/// resolver is created with `with_socket`.
+pub struct DnsResolver<S = UdpSocket> {
+    sock: DnsSocket<S>,
     config: DnsConfig,
     /// Index of `config.name_servers` to use in next DNS request;
     /// ignored if `config.rotate` is `false`.
-    next_ns: Cell<usize>,
+    next_ns: AtomicUsize,
+    /// Client cookies and the cookies of the name servers, used if
+    /// `config.cookies` is `true`
+    cookies: Cookies,
+    /// Open connections to the name servers of `config.transports`
+    streams: Connections,
+    /// Source of the IDs, the random case, and the jitter of the queries
+    rand: Arc<dyn RandSource>,
+    counters: Counters,
+    /// Queries sent by one caller which other callers with the same question wait for
+    in_flight: Mutex<BTreeMap<FlightKey, Arc<InFlight>>>,
+    /// Name servers of the zones learned by iterative resolution
+    delegations: Delegations,
+    /// Validates the responses if a trust anchor is set
+    #[cfg(feature = "dnssec")]
+    validator: Option<Validator>,
+    /// Signs the messages of `send_message` and verifies the responses
+    #[cfg(feature = "tsig")]
+    tsig_key: Option<TsigKey>,
+    /// Time of the transaction signatures
+    #[cfg(feature = "tsig")]
+    clock: Arc<dyn Clock>,
+}
+
+/// Name, type, class, and client subnet of a query in flight
+type FlightKey = (DnsName, u16, u16, Option<ClientSubnet>);
+
+/// Result of a query in flight, set once by the caller which sends the query
+#[derive(Default)]
+struct InFlight {
+    result: Mutex<Option<FlightResult>>,
+    done: Condvar,
+}
+
+enum FlightResult {
+    /// Response or error which all waiting callers get
+    Done(Result<OwnedMessage, Error>),
+    /// The caller which sent the query stopped at its own deadline, the waiting callers
+    /// send the query again
+    Abandoned,
+}
+
+/// Removes a query from `in_flight` and wakes up the waiting callers when the caller
+/// which sends the query is done, with an error if it panicked before setting the result
+struct FlightGuard<'a> {
+    in_flight: &'a Mutex<BTreeMap<FlightKey, Arc<InFlight>>>,
+    key: FlightKey,
+    flight: Arc<InFlight>,
+    result: Option<FlightResult>,
+}
+
+impl<'a> Drop for FlightGuard<'a> {
+    fn drop(&mut self) {
+        // the locks are not held during the query, but a panic must not become an abort
+        let _ = self
+            .in_flight
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(&self.key);
+        let result = self.result.take().unwrap_or_else(|| {
+            FlightResult::Done(Err(Error::IoError(io::Error::new(
+                io::ErrorKind::Other,
+                "the caller which sent the query panicked",
+            ))))
+        });
+        *self
+            .flight
+            .result
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) = Some(result);
+        self.flight.done.notify_all();
+    }
+}
+
+/// Snapshot of the counters of a `DnsResolver`.
+///
+/// The counters are cumulative over the lifetime of the resolver
+/// and are not reset when read. The cache counters cover the lookups in
+/// the delegations of iterative lookups and, with a trust anchor, in the
+/// NSEC and NSEC3 records of secure denials.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct ResolverStats {
+    /// Number of queries sent to name servers, including retries
+    pub queries_sent: u64,
+    /// Number of responses received which matched a sent query
+    pub responses_received: u64,
+    /// Number of attempts that timed out without a response
+    pub timeouts: u64,
+    /// Number of responses with a `ServerFailure` response code
+    pub servfails: u64,
+    /// Number of responses with a `NameError` (NXDOMAIN) response code
+    pub nxdomains: u64,
+    /// Number of packets discarded because they came from an unexpected
+    /// address, did not match the ID of the sent query, or did not echo the
+    /// randomized case of the question
+    pub mismatched: u64,
+    /// Number of lookups which waited for the same query of another caller
+    /// instead of sending their own
+    pub coalesced: u64,
+    /// Number of queries repeated without the OPT record after a FORMERR
+    /// or a timeout, for servers which do not support EDNS
+    pub edns_fallbacks: u64,
+    /// Number of queries repeated with the new server cookie of a BADCOOKIE
+    /// response
+    pub cookie_retries: u64,
+    /// Number of lookups answered with NXDOMAIN from the NSEC or NSEC3
+    /// records of earlier denials instead of a query
+    pub cached_denials: u64,
+    /// Number of lookups which found a cached denial of the name, or the
+    /// cached name servers of a zone above it for an iterative lookup
+    pub cache_hits: u64,
+    /// Number of lookups which found neither, and sent the query or started
+    /// an iterative lookup at the root servers
+    pub cache_misses: u64,
+}
+
+#[derive(Default)]
+struct Counters {
+    queries_sent: AtomicU64,
+    responses_received: AtomicU64,
+    timeouts: AtomicU64,
+    servfails: AtomicU64,
+    nxdomains: AtomicU64,
+    mismatched: AtomicU64,
+    coalesced: AtomicU64,
+    edns_fallbacks: AtomicU64,
+    cookie_retries: AtomicU64,
+    cached_denials: AtomicU64,
+    cache_hits: AtomicU64,
+    cache_misses: AtomicU64,
+}
+
+impl Counters {
+    fn snapshot(&self) -> ResolverStats {
+        ResolverStats {
+            queries_sent: self.queries_sent.load(Ordering::Relaxed),
+            responses_received: self.responses_received.load(Ordering::Relaxed),
+            timeouts: self.timeouts.load(Ordering::Relaxed),
+            servfails: self.servfails.load(Ordering::Relaxed),
+            nxdomains: self.nxdomains.load(Ordering::Relaxed),
+            mismatched: self.mismatched.load(Ordering::Relaxed),
+            coalesced: self.coalesced.load(Ordering::Relaxed),
+            edns_fallbacks: self.edns_fallbacks.load(Ordering::Relaxed),
+            cookie_retries: self.cookie_retries.load(Ordering::Relaxed),
+            cached_denials: self.cached_denials.load(Ordering::Relaxed),
+            cache_hits: self.cache_hits.load(Ordering::Relaxed),
+            cache_misses: self.cache_misses.load(Ordering::Relaxed),
+        }
+    }
+}
+
+fn inc(counter: &AtomicU64) {
+    counter.fetch_add(1, Ordering::Relaxed);
 }
 
 impl DnsResolver {
@@ -27,37 +203,167 @@ impl DnsResolver {
     pub fn new(config: DnsConfig) -> io::Result<DnsResolver> {
         let bind = bind_addr(&config.name_servers);
         let sock = DnsSocket::bind((bind, 0))?;
-        DnsResolver::with_sock(sock, config)
+        Ok(DnsResolver::with_socket(sock, config))
     }
 
     /// Constructs a `DnsResolver` using the given configuration and bound
     /// to the given address.
     pub fn bind<A: UsnetToSocketAddrs>(addr: A, config: DnsConfig) -> io::Result<DnsResolver> {
         let sock = DnsSocket::bind(addr)?;
-        DnsResolver::with_sock(sock, config)
+        Ok(DnsResolver::with_socket(sock, config))
     }
+}
 
-    fn with_sock(sock: DnsSocket, config: DnsConfig) -> io::Result<DnsResolver> {
-        Ok(DnsResolver {
+impl<S: DatagramSocket> DnsResolver<S> {
+    /// Constructs a `DnsResolver` using the given configuration which sends
+    /// the queries on the socket, e.g., one of the singlethread API from
+    /// `DnsSocket::from_socket`.
+    pub fn with_socket(sock: DnsSocket<S>, config: DnsConfig) -> DnsResolver<S> {
+        DnsResolver {
             sock: sock,
             config: config,
-            next_ns: Cell::new(0),
-        })
+            next_ns: AtomicUsize::new(0),
+            cookies: Cookies::default(),
+            streams: Connections::default(),
+            rand: Arc::new(OsRandom),
+            counters: Counters::default(),
+            in_flight: Mutex::new(BTreeMap::new()),
+            delegations: Delegations::default(),
+            #[cfg(feature = "dnssec")]
+            validator: None,
+            #[cfg(feature = "tsig")]
+            tsig_key: None,
+            #[cfg(feature = "tsig")]
+            clock: Arc::new(SystemClock),
+        }
+    }
+
+    /// Returns a snapshot of the cumulative query counters.
+    ///
+    /// The counters are updated atomically, so `stats` may be called
+    /// from another thread while queries are in progress.
+    pub fn stats(&self) -> ResolverStats {
+        self.counters.snapshot()
+    }
+
+    /// Installs a hook on the resolver socket which is called with the raw
+    /// bytes of each sent query and received packet.
+    /// See `DnsSocket::set_packet_hook`.
+    pub fn set_packet_hook<F>(&mut self, hook: F)
+    where
+        F: Fn(&[u8], &SocketAddr, PacketDirection) + Send + Sync + 'static,
+    {
+        self.sock.set_packet_hook(hook);
+    }
+
+    /// Replaces the source of the randomness of the queries, the transaction
+    /// IDs, the case of the names if `config.randomize_case` is set, and the
+    /// jitter of the retry timeouts, e.g., with a `SeededRandom` in tests. The
+    /// key of the client cookies is drawn again from the source.
+    ///
+    /// The source port is chosen by the network stack when the socket is bound.
+    pub fn set_rand_source<R: RandSource + 'static>(&mut self, source: R) {
+        self.cookies = Cookies::new(&source);
+        self.rand = Arc::new(source);
+    }
+
+    /// Enables DNSSEC validation of all responses, starting the chain of trust
+    /// at the given keys, e.g., `TrustAnchor::root()`.
+    ///
+    /// Queries then request signatures by setting the DO bit, and the records
+    /// to authenticate the zones of an answer are fetched through the same name
+    /// servers, which need to be validation-aware (i.e., not strip RRSIGs). A
+    /// response which fails validation results in `Error::DnsError` with the
+    /// response code `ServerFailure` and an extended error, see `DnsError::is_bogus`.
+    /// Answers from unsigned zones are returned as usual, `query_secure` tells
+    /// them apart from authenticated answers.
+    ///
+    /// The NSEC and NSEC3 records of secure denials are kept for their TTL, and
+    /// a lookup of a name in one of their spans fails with NXDOMAIN without a
+    /// query (RFC 8198), counted in `cached_denials` and `cache_hits` of
+    /// `stats()`.
+    #[cfg(feature = "dnssec")]
+    pub fn set_trust_anchor(&mut self, anchor: TrustAnchor) {
+        self.validator = Some(Validator::new(anchor));
+    }
+
+    /// Signs the messages of `send_message` and `send_message_deadline` with
+    /// the key shared with the server (RFC 8945), e.g., for dynamic updates
+    /// and zone transfers, and verifies the signatures of the responses.
+    ///
+    /// A response with an invalid signature fails with `Error::TsigError`, as
+    /// does one in which the server rejected the signature of the message,
+    /// e.g., with `TsigError::Rejected(16)` for BADSIG. Error responses without
+    /// a signature fail with their response code. Messages which are signed
+    /// already are sent as they are, and the caller verifies the response.
+    #[cfg(feature = "tsig")]
+    pub fn set_tsig_key(&mut self, key: TsigKey) {
+        self.tsig_key = Some(key);
+    }
+
+    /// Replaces the clock of the transaction signatures, e.g., with a
+    /// `FixedClock` in tests, see `set_tsig_key`.
+    #[cfg(feature = "tsig")]
+    pub fn set_clock<C: Clock + 'static>(&mut self, clock: C) {
+        self.clock = Arc::new(clock);
+    }
+
+    /// Sends a query and validates the response, see `set_trust_anchor`, which must
+    /// be called before.
+    ///
+    /// Unlike the other lookups, a response with the `NameError` (NXDOMAIN) response
+    /// code is returned so that the security of the denial is known.
+    #[cfg(feature = "dnssec")]
+    pub fn query_secure(
+        &self,
+        name: &str,
+        r_type: RecordType,
+    ) -> Result<(OwnedMessage, Security), Error> {
+        let validator = self.validator.as_ref().ok_or_else(|| {
+            Error::IoError(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "no trust anchor set",
+            ))
+        })?;
+        let mut out_msg = self.basic_message();
+        out_msg
+            .question
+            .push(Question::new(name.to_string(), r_type, Class::Internet));
+        if let Some(msg) = self.cached_denial(&out_msg.question[0]) {
+            return Ok((msg, Security::Secure));
+        }
+        let mut buf = [0; MESSAGE_LIMIT];
+        let msg = self.exchange_until(&out_msg, &mut buf, None)?;
+        let security = validator.validate(
+            name,
+            r_type,
+            &Response::new(&msg)?,
+            &|name, r_type| self.fetch(name, r_type, None),
+            unix_time(),
+        )?;
+        if msg.header.rcode != RCode::NameError {
+            msg.get_error()?;
+        }
+        Ok((OwnedMessage::from(&msg), security))
     }
 
     /// Resolves an IPv4 or IPv6 address to a hostname.
     pub fn resolve_addr(&self, addr: &IpAddr) -> io::Result<String> {
-        convert_error("failed to resolve address", || {
-            let mut out_msg = self.basic_message();
+        self.resolve_addr_until(addr, None)
+    }
 
-            out_msg.question.push(Question::new(
-                address_name(addr),
-                RecordType::Ptr,
-                Class::Internet,
-            ));
+    /// Resolves an IPv4 or IPv6 address to a hostname, failing with a
+    /// `TimedOut` error once the deadline passed.
+    ///
+    /// Every query, retry, and failover waits only for the time which is left,
+    /// instead of its full timeout.
+    pub fn resolve_addr_deadline(&self, addr: &IpAddr, deadline: Instant) -> io::Result<String> {
+        self.resolve_addr_until(addr, Some(deadline))
+    }
 
-            let mut buf = [0; MESSAGE_LIMIT];
-            let msg = self.send_message(&out_msg, &mut buf)?;
+    fn resolve_addr_until(&self, addr: &IpAddr, deadline: Option<Instant>) -> io::Result<String> {
+        convert_error("failed to resolve address", || {
+            let msg = self.query_until(&address_name(addr), RecordType::Ptr, deadline)?;
 
             for rr in msg.answer.into_iter() {
                 if rr.r_type == RecordType::Ptr {
@@ -79,8 +385,23 @@ impl DnsResolver {
 
     /// Resolves a hostname to a series of IPv4 or IPv6 addresses.
     pub fn resolve_host(&self, host: &str) -> io::Result<ResolveHost> {
+        self.resolve_host_until(host, None)
+    }
+
+    /// Resolves a hostname to a series of IPv4 or IPv6 addresses, failing with
+    /// a `TimedOut` error once the deadline passed.
+    ///
+    /// The deadline bounds the whole lookup: the names with the search domains,
+    /// both address types, and the retries and failovers of each query, which
+    /// wait only for the time which is left instead of their full timeout.
+    pub fn resolve_host_deadline(&self, host: &str, deadline: Instant) -> io::Result<ResolveHost> {
+        self.resolve_host_until(host, Some(deadline))
+    }
+
+    fn resolve_host_until(&self, host: &str, deadline: Option<Instant>) -> io::Result<ResolveHost> {
         convert_error("failed to resolve host", || {
             query_names(host, &self.config, |name| {
+                time_left(deadline)?;
                 let mut err;
                 let mut res = Vec::new();
 
@@ -88,20 +409,22 @@ impl DnsResolver {
 
                 if self.config.use_inet6 {
                     err = self
-                        .resolve_host_v6(&name, |ip| res.push(IpAddr::V6(ip)))
+                        .resolve_host_v6(&name, deadline, |ip| res.push(IpAddr::V6(ip)))
                         .err();
 
                     if res.is_empty() {
                         err = err.or(self
-                            .resolve_host_v4(&name, |ip| res.push(IpAddr::V6(ip.to_ipv6_mapped())))
+                            .resolve_host_v4(&name, deadline, |ip| {
+                                res.push(IpAddr::V6(ip.to_ipv6_mapped()))
+                            })
                             .err());
                     }
                 } else {
                     err = self
-                        .resolve_host_v4(&name, |ip| res.push(IpAddr::V4(ip)))
+                        .resolve_host_v4(&name, deadline, |ip| res.push(IpAddr::V4(ip)))
                         .err();
                     err = err.or(self
-                        .resolve_host_v6(&name, |ip| res.push(IpAddr::V6(ip)))
+                        .resolve_host_v6(&name, deadline, |ip| res.push(IpAddr::V6(ip)))
                         .err());
                 }
 
@@ -123,15 +446,41 @@ impl DnsResolver {
 
     /// Requests a type of record from the DNS server and returns the results.
     pub fn resolve_record<Rec: Record>(&self, name: &str) -> io::Result<Vec<Rec>> {
-        convert_error("failed to resolve record", || {
-            let r_ty = Rec::record_type();
-            let mut msg = self.basic_message();
+        self.resolve_record_class(name, Class::Internet)
+    }
 
-            msg.question
-                .push(Question::new(name.to_owned(), r_ty, Class::Internet));
+    /// Requests a type of record from the DNS server and returns the results,
+    /// failing with a `TimedOut` error once the deadline passed.
+    pub fn resolve_record_deadline<Rec: Record>(
+        &self,
+        name: &str,
+        deadline: Instant,
+    ) -> io::Result<Vec<Rec>> {
+        self.resolve_record_until(name, Class::Internet, Some(deadline))
+    }
 
-            let mut buf = [0; MESSAGE_LIMIT];
-            let reply = self.send_message(&msg, &mut buf)?;
+    /// Requests a type of record in the given class from the DNS server and returns
+    /// the results, e.g., `Txt` records of the `Chaos` class.
+    ///
+    /// Responses for other classes than `Internet` are not validated through DNSSEC.
+    pub fn resolve_record_class<Rec: Record>(
+        &self,
+        name: &str,
+        class: Class,
+    ) -> io::Result<Vec<Rec>> {
+        self.resolve_record_until(name, class, None)
+    }
+
+    fn resolve_record_until<Rec: Record>(
+        &self,
+        name: &str,
+        class: Class,
+        deadline: Option<Instant>,
+    ) -> io::Result<Vec<Rec>> {
+        convert_error("failed to resolve record", || {
+            let r_ty = Rec::record_type();
+            let subnet = self.config.client_subnet;
+            let reply = self.query_with_subnet(name, r_ty, class, subnet, deadline)?;
 
             let mut rec = Vec::new();
 
@@ -145,23 +494,142 @@ impl DnsResolver {
         })
     }
 
-    fn resolve_host_v4<F>(&self, host: &str, mut f: F) -> Result<(), Error>
+    /// Sends a query with the client subnet option for the network instead of
+    /// `DnsConfig::client_subnet` and returns the response, whose
+    /// `client_subnet()` holds the scope prefix length of the answer.
+    ///
+    /// A query in flight is only shared with lookups of the same name for the
+    /// same subnet, and the answers are not kept by the resolver. A cache of
+    /// the answers has to be keyed by `scope()` of the returned option as well,
+    /// so that the answer for one network is not used for another.
+    pub fn query_subnet(
+        &self,
+        name: &str,
+        r_type: RecordType,
+        subnet: ClientSubnet,
+    ) -> Result<MessageBuf, Error> {
+        self.query_with_subnet(name, r_type, Class::Internet, Some(subnet), None)
+    }
+
+    /// Sends an `ANY` query for diagnostics and returns the answers of all types.
+    ///
+    /// Records of unknown types, or whose data does not decode as their type,
+    /// are returned with the raw data in `RecordData::Other`. Many servers
+    /// refuse `ANY` queries, which results in an error with the response code
+    /// (e.g., `Refused` or `NotImplemented`), or answer with a single `HINFO`
+    /// record as described in RFC 8482. Use `send_message` to get the response
+    /// code as a `DnsError`.
+    pub fn resolve_any(&self, name: &str) -> io::Result<Vec<OwnedRecord>> {
+        convert_error("failed to resolve records", || {
+            let reply = self.query(name, RecordType::Any)?;
+            Ok(OwnedMessage::from(&reply).answer)
+        })
+    }
+
+    /// Requests the `HTTPS` records of a name, which carry the application
+    /// protocols, port, and IP hints of a web server, and returns the ones in
+    /// service mode in order of their priority.
+    ///
+    /// A record in alias mode is followed to its target name, and one with the
+    /// target `.` means that the service is not available. The IP hints can be
+    /// connected to before the target name is resolved, see `Https::hint_addrs`.
+    pub fn resolve_https(&self, name: &str) -> io::Result<Vec<Https>> {
+        self.resolve_https_until(name, None)
+    }
+
+    /// Requests the `HTTPS` records of a name like `resolve_https`, failing
+    /// with a `TimedOut` error once the deadline passed, also while following
+    /// aliases.
+    pub fn resolve_https_deadline(&self, name: &str, deadline: Instant) -> io::Result<Vec<Https>> {
+        self.resolve_https_until(name, Some(deadline))
+    }
+
+    fn resolve_https_until(&self, name: &str, deadline: Option<Instant>) -> io::Result<Vec<Https>> {
+        convert_error("failed to resolve HTTPS records", || {
+            let mut name = name.to_owned();
+            for _ in 0..ALIAS_LIMIT {
+                let reply = self.query_until(&name, RecordType::Https, deadline)?;
+                let answer_name = reply.answer_name();
+                let mut records = Vec::new();
+                for rr in reply.answer.iter() {
+                    if rr.r_type == RecordType::Https && is_answer_name(&rr.name, &answer_name) {
+                        records.push(rr.read_rdata::<Https>()?);
+                    }
+                }
+                let (aliases, mut services): (Vec<_>, Vec<_>) =
+                    records.into_iter().partition(Https::is_alias);
+                // service mode records next to an alias are ignored (RFC 9460, 2.4.2)
+                match aliases.into_iter().next() {
+                    Some(ref alias) if alias.target == "." => return Ok(Vec::new()),
+                    Some(alias) => name = alias.target,
+                    None => {
+                        services.sort_by_key(|rec| rec.priority);
+                        return Ok(services);
+                    }
+                }
+            }
+            Err(Error::IoError(io::Error::new(
+                io::ErrorKind::Other,
+                "failed to resolve HTTPS records: too many aliases",
+            )))
+        })
+    }
+
+    /// Queries the version of the name server software through `version.bind TXT CH`.
+    ///
+    /// Most servers answer with the software and version, e.g., `9.18.1` for BIND.
+    /// Others reply with a text of their choice or refuse the query, which results
+    /// in an error. The instance of an anycast service is identified by
+    /// `resolve_server_id`.
+    pub fn resolve_version_bind(&self) -> io::Result<String> {
+        let txt = self.resolve_record_class::<Txt>("version.bind.", Class::Chaos)?;
+        txt.into_iter()
+            .next()
+            .map(|txt| String::from_utf8_lossy(&txt.data).into_owned())
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::Other,
+                    "failed to resolve version: no TXT record",
+                )
+            })
+    }
+
+    /// Queries the identifier of the name server through `id.server TXT CH`
+    /// (RFC 4892), or `hostname.bind TXT CH` if the server does not answer
+    /// that, e.g., the instance of an anycast service which answered.
+    ///
+    /// Servers which support NSID send the identifier with any response, see
+    /// `DnsConfig::nsid`.
+    pub fn resolve_server_id(&self) -> io::Result<String> {
+        let txt = match self.resolve_record_class::<Txt>("id.server.", Class::Chaos) {
+            Ok(txt) if !txt.is_empty() => txt,
+            _ => self.resolve_record_class::<Txt>("hostname.bind.", Class::Chaos)?,
+        };
+        txt.into_iter()
+            .next()
+            .map(|txt| String::from_utf8_lossy(&txt.data).into_owned())
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::Other,
+                    "failed to resolve server identifier: no TXT record",
+                )
+            })
+    }
+
+    fn resolve_host_v4<F>(
+        &self,
+        host: &str,
+        deadline: Option<Instant>,
+        mut f: F,
+    ) -> Result<(), Error>
     where
         F: FnMut(Ipv4Addr),
     {
-        let mut out_msg = self.basic_message();
-
-        out_msg.question.push(Question::new(
-            host.to_owned(),
-            RecordType::A,
-            Class::Internet,
-        ));
-
-        let mut buf = [0; MESSAGE_LIMIT];
-        let msg = self.send_message(&out_msg, &mut buf)?;
+        let msg = self.query_until(host, RecordType::A, deadline)?;
+        let name = msg.answer_name();
 
         for rr in msg.answer.into_iter() {
-            if rr.r_type == RecordType::A {
+            if rr.r_type == RecordType::A && is_answer_name(&rr.name, &name) {
                 let a = rr.read_rdata::<A>()?;
                 f(a.address);
             }
@@ -170,23 +638,20 @@ impl DnsResolver {
         Ok(())
     }
 
-    fn resolve_host_v6<F>(&self, host: &str, mut f: F) -> Result<(), Error>
+    fn resolve_host_v6<F>(
+        &self,
+        host: &str,
+        deadline: Option<Instant>,
+        mut f: F,
+    ) -> Result<(), Error>
     where
         F: FnMut(Ipv6Addr),
     {
-        let mut out_msg = self.basic_message();
-
-        out_msg.question.push(Question::new(
-            host.to_owned(),
-            RecordType::AAAA,
-            Class::Internet,
-        ));
-
-        let mut buf = [0; MESSAGE_LIMIT];
-        let msg = self.send_message(&out_msg, &mut buf)?;
+        let msg = self.query_until(host, RecordType::AAAA, deadline)?;
+        let name = msg.answer_name();
 
         for rr in msg.answer.into_iter() {
-            if rr.r_type == RecordType::AAAA {
+            if rr.r_type == RecordType::AAAA && is_answer_name(&rr.name, &name) {
                 let aaaa = rr.read_rdata::<AAAA>()?;
                 f(aaaa.address);
             }
@@ -195,20 +660,828 @@ impl DnsResolver {
         Ok(())
     }
 
+    /// Sends a query for the name and type, or waits for the response if another caller
+    /// already sent the same query
+    ///
+    /// All callers which wait for a query get the same response or error. A lookup
+    /// which starts after the query finished sends a new query, and so do the waiting
+    /// callers if the caller which sent the query stopped at its deadline.
+    fn query(&self, name: &str, r_type: RecordType) -> Result<Message<'static>, Error> {
+        self.query_until(name, r_type, None)
+    }
+
+    fn query_until(
+        &self,
+        name: &str,
+        r_type: RecordType,
+        deadline: Option<Instant>,
+    ) -> Result<Message<'static>, Error> {
+        let subnet = self.config.client_subnet;
+        self.query_with_subnet(name, r_type, Class::Internet, subnet, deadline)
+    }
+
+    /// A caller with a deadline which waits for the query of another caller
+    /// stops waiting at its deadline.
+    fn query_with_subnet(
+        &self,
+        name: &str,
+        r_type: RecordType,
+        class: Class,
+        subnet: Option<ClientSubnet>,
+        deadline: Option<Instant>,
+    ) -> Result<Message<'static>, Error> {
+        // Unicode and A-label spellings of a name share the query
+        let name =
+            idna::to_ascii(name).map_err(|_| Error::EncodeError(EncodeError::InvalidName))?;
+        // as do lookups for the same client subnet only
+        let key = (DnsName::new(&name), r_type.to_u16(), class.to_u16(), subnet);
+        loop {
+            let (flight, owner) = {
+                let mut in_flight = self.in_flight.lock().unwrap();
+                match in_flight.get(&key) {
+                    Some(flight) => (flight.clone(), false),
+                    None => {
+                        let flight = Arc::new(InFlight::default());
+                        in_flight.insert(key.clone(), flight.clone());
+                        (flight, true)
+                    }
+                }
+            };
+
+            if owner {
+                let mut guard = FlightGuard {
+                    in_flight: &self.in_flight,
+                    key: key.clone(),
+                    flight: flight,
+                    result: None,
+                };
+                let mut out_msg = self.basic_message();
+
+                out_msg
+                    .question
+                    .push(Question::new(name.to_string(), r_type, class));
+                if let Some(ref subnet) = subnet {
+                    out_msg.set_client_subnet(subnet);
+                }
+
+                let mut buf = [0; MESSAGE_LIMIT];
+                let result = match self.query_mdns(&out_msg.question[0], deadline) {
+                    Some(result) => result,
+                    None if self.cached_denial(&out_msg.question[0]).is_some() => {
+                        Err(Error::from(DnsError::from(RCode::NameError)))
+                    }
+                    None => self
+                        .exchange_until(&out_msg, &mut buf, deadline)
+                        .and_then(|msg| {
+                            if class == Class::Internet {
+                                self.validate(&name, r_type, &msg, deadline)?;
+                            }
+                            msg.get_error()?;
+                            Ok(OwnedMessage::from(&msg))
+                        }),
+                };
+
+                // a failure at the own deadline is not the result for callers with a later one
+                let abandoned = result.is_err() && time_left(deadline).is_err();
+                guard.result = Some(match result {
+                    Err(_) if abandoned => FlightResult::Abandoned,
+                    Ok(ref msg) => FlightResult::Done(Ok(msg.clone())),
+                    Err(ref e) => FlightResult::Done(Err(duplicate_error(e))),
+                });
+                drop(guard);
+
+                return Ok(result?.to_message()?);
+            }
+
+            inc(&self.counters.coalesced);
+            let mut result = flight.result.lock().unwrap();
+            while result.is_none() {
+                result = match time_left(deadline)? {
+                    Some(left) => flight.done.wait_timeout(result, left).unwrap().0,
+                    None => flight.done.wait(result).unwrap(),
+                };
+            }
+            match *result {
+                Some(FlightResult::Done(Ok(ref msg))) => return Ok(msg.to_message()?),
+                Some(FlightResult::Done(Err(ref e))) => return Err(duplicate_error(e)),
+                Some(FlightResult::Abandoned) => {
+                    query_log!(debug, "resolver sending the abandoned query for {}", name);
+                }
+                None => unreachable!(),
+            }
+        }
+    }
+
+    /// Resolves the question through mDNS if its name is under `local.`, and
+    /// returns `None` for the other names
+    #[cfg(feature = "mdns")]
+    fn query_mdns(
+        &self,
+        question: &Question,
+        deadline: Option<Instant>,
+    ) -> Option<Result<OwnedMessage, Error>> {
+        if !uses_mdns(&self.config, &question.name) {
+            return None;
+        }
+        // the QU and the QM query may each take the window
+        let window = match remaining(deadline, self.config.mdns_window * 2) {
+            Ok(timeout) => timeout / 2,
+            Err(e) => return Some(Err(e)),
+        };
+        Some(
+            mdns::query(question, window)
+                .map(|responses| mdns::merge(question.clone(), &responses)),
+        )
+    }
+
+    /// Returns an NXDOMAIN response if the NSEC or NSEC3 records of earlier
+    /// secure denials prove that the name of the question does not exist, see
+    /// `set_trust_anchor`, and `None` otherwise
+    #[cfg(feature = "dnssec")]
+    fn cached_denial(&self, question: &Question) -> Option<OwnedMessage> {
+        let validator = self.validator.as_ref()?;
+        if question.q_class != Class::Internet {
+            return None;
+        }
+        if !validator.cached_nxdomain(&question.name, unix_time()) {
+            inc(&self.counters.cache_misses);
+            return None;
+        }
+        inc(&self.counters.cache_hits);
+        inc(&self.counters.cached_denials);
+        query_log!(
+            debug,
+            "resolver answering {} with NXDOMAIN from cached NSEC records",
+            question.name
+        );
+        let mut msg = OwnedMessage::default();
+        msg.header.qr = Qr::Response;
+        msg.header.authentic_data = true;
+        msg.header.rcode = RCode::NameError;
+        msg.question.push(question.clone());
+        Some(msg)
+    }
+
+    #[cfg(not(feature = "dnssec"))]
+    fn cached_denial(&self, _question: &Question) -> Option<OwnedMessage> {
+        None
+    }
+
+    #[cfg(not(feature = "mdns"))]
+    fn query_mdns(
+        &self,
+        _question: &Question,
+        _deadline: Option<Instant>,
+    ) -> Option<Result<OwnedMessage, Error>> {
+        None
+    }
+
     fn basic_message(&self) -> Message {
-        let mut msg = Message::new();
+        let mut msg = Message::with_id(self.rand.id());
 
         msg.header.recursion_desired = true;
+        if self.config.nsid {
+            msg.request_nsid();
+        }
+        #[cfg(feature = "dnssec")]
+        {
+            if self.validator.is_some() {
+                msg.set_dnssec_ok(true);
+            }
+        }
         msg
     }
 
+    #[cfg(feature = "dnssec")]
+    fn validate(
+        &self,
+        name: &str,
+        r_type: RecordType,
+        msg: &Message,
+        deadline: Option<Instant>,
+    ) -> Result<(), Error> {
+        match self.validator {
+            Some(ref validator) => validator
+                .validate(
+                    name,
+                    r_type,
+                    &Response::new(msg)?,
+                    &|name, r_type| self.fetch(name, r_type, deadline),
+                    unix_time(),
+                )
+                .map(|_| ()),
+            None => Ok(()),
+        }
+    }
+
+    #[cfg(not(feature = "dnssec"))]
+    fn validate(
+        &self,
+        _name: &str,
+        _r_type: RecordType,
+        _msg: &Message,
+        _deadline: Option<Instant>,
+    ) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Queries the records which the validator needs to build the chain of trust,
+    /// negative responses are records too
+    #[cfg(feature = "dnssec")]
+    fn fetch(
+        &self,
+        name: &str,
+        r_type: RecordType,
+        deadline: Option<Instant>,
+    ) -> Result<Response, Error> {
+        let mut out_msg = self.basic_message();
+        out_msg
+            .question
+            .push(Question::new(name.to_string(), r_type, Class::Internet));
+        let mut buf = [0; MESSAGE_LIMIT];
+        let msg = self.exchange_until(&out_msg, &mut buf, deadline)?;
+        if msg.header.rcode != RCode::NameError {
+            msg.get_error()?;
+        }
+        Ok(Response::new(&msg)?)
+    }
+
     /// Sends a message to the DNS server and attempts to read a response.
     pub fn send_message<'buf>(
         &self,
         out_msg: &Message,
         buf: &'buf mut [u8],
     ) -> Result<Message<'buf>, Error> {
+        let msg = self.exchange_signed(out_msg, buf, None)?;
+        msg.get_error()?;
+        Ok(msg)
+    }
+
+    /// Sends a message to the DNS server and attempts to read a response,
+    /// failing with a `TimedOut` error once the deadline passed.
+    pub fn send_message_deadline<'buf>(
+        &self,
+        out_msg: &Message,
+        buf: &'buf mut [u8],
+        deadline: Instant,
+    ) -> Result<Message<'buf>, Error> {
+        let msg = self.exchange_signed(out_msg, buf, Some(deadline))?;
+        msg.get_error()?;
+        Ok(msg)
+    }
+
+    /// Requests the changes of the zone since `from_serial` with an IXFR query
+    /// (RFC 1995) to the name servers, which have to be a primary or secondary
+    /// server of the zone that allows the transfer.
+    ///
+    /// The query goes over UDP, the changes of larger updates, for which the
+    /// server only sends its SOA record, are returned as `ZoneDelta::TooLarge`
+    /// and can be requested with `ixfr_tcp`.
+    pub fn ixfr(&self, zone: &str, from_serial: u32) -> Result<ZoneDelta, Error> {
+        let out_msg = transfer::ixfr_query(zone, from_serial)?;
+        let mut buf = [0; MESSAGE_LIMIT];
+        let msg = self.send_message(&out_msg, &mut buf)?;
+        Ok(ZoneDelta::from_response(from_serial, &msg)?)
+    }
+
+    /// Requests the changes of the zone since `from_serial` as `ixfr` does,
+    /// but over a TCP connection to a name server, so that larger changes are
+    /// transferred as well.
+    pub fn ixfr_tcp(&self, zone: &str, from_serial: u32) -> Result<ZoneDelta, Error> {
+        let query = transfer::ixfr_query(zone, from_serial)?;
+        ZoneDelta::from_transfer(from_serial, self.transfer(&query)?)
+    }
+
+    /// Transfers the whole zone over a TCP connection to a name server (AXFR,
+    /// RFC 5936), which has to be a primary or secondary server of the zone
+    /// that allows the transfer. The records are read as the iterator goes,
+    /// see `ZoneTransfer`.
+    ///
+    /// With a key of `set_tsig_key`, the query is signed and the transfer
+    /// fails with `Error::TsigError` at a message with an invalid signature.
+    pub fn axfr(&self, zone: &str) -> Result<ZoneTransfer, Error> {
+        self.transfer(&transfer::axfr_query(zone))
+    }
+
+    fn transfer(&self, query: &Message) -> Result<ZoneTransfer, Error> {
+        let server = self.next_nameserver();
+        let stream = transfer::connect(&server, self.config.timeout)?;
+        #[cfg(feature = "tsig")]
+        {
+            if let Some(ref key) = self.tsig_key {
+                return ZoneTransfer::signed(stream, query, key.clone(), self.clock.clone());
+            }
+        }
+        ZoneTransfer::new(stream, query)
+    }
+
+    /// Resolves the name without a recursive resolver and returns the queries
+    /// on the way, like `dig +trace`.
+    ///
+    /// Starting at `DnsConfig::root_hints`, or at the closest zone whose name
+    /// servers were learned before, the query goes to a name server of each zone
+    /// until one answers instead of referring to the name servers of a child
+    /// zone. Referrals are cached for their TTL. The addresses of name servers
+    /// without glue records are resolved the same way, and at most
+    /// `iterative::QUERY_LIMIT` queries are sent. CNAME records of the answer
+    /// are not followed.
+    pub fn trace(&self, name: &str, r_type: RecordType) -> Result<Trace, Error> {
+        let mut steps = Vec::new();
+        let mut budget = QUERY_LIMIT;
+        let answer = self.iterate(name, r_type, &mut budget, &mut steps)?;
+        Ok(Trace {
+            steps: steps,
+            answer: answer,
+        })
+    }
+
+    /// Resolves the name without a recursive resolver, see `trace`, and returns
+    /// the answer, or an error with its response code.
+    pub fn resolve_iterative(&self, name: &str, r_type: RecordType) -> Result<OwnedMessage, Error> {
+        let answer = self.trace(name, r_type)?.answer;
+        answer.get_error()?;
+        Ok(answer)
+    }
+
+    /// Sends the message to several of the servers at the same time and returns the
+    /// first response which `DnsConfig::retry_decision` accepts and which passes
+    /// validation, e.g., for lookups where latency matters more than load.
+    ///
+    /// At most `DnsConfig::fan_out` servers are queried at once. A server which
+    /// times out, gives a response to pass on (e.g., `ServerFailure`), or fails
+    /// validation leaves the race, and the next of the servers takes its place.
+    /// The later responses of the other servers are discarded when they arrive.
+    /// If no server answers well, the error of the last one is returned.
+    ///
+    /// The message is sent as it is, without the EDNS fallback and the random case.
+    pub fn query_fastest(
+        &self,
+        message: &Message,
+        servers: &[SocketAddr],
+    ) -> Result<OwnedMessage, Error> {
+        let mut pending = servers.iter();
+        // the servers in the race and until when they have to answer
+        let mut racing: Vec<(SocketAddr, Instant)> = Vec::new();
         let mut last_err = None;
+        let mut buf = [0; MESSAGE_LIMIT];
+        loop {
+            while racing.len() < self.config.fan_out.max(1) {
+                let ns_addr = match pending.next() {
+                    Some(ns_addr) => *ns_addr,
+                    None => break,
+                };
+                match self.sock.send_message(message, &ns_addr) {
+                    Ok(_) => {
+                        inc(&self.counters.queries_sent);
+                        racing.push((ns_addr, Instant::now() + self.config.timeout));
+                    }
+                    Err(e) if e.is_unreachable() => {
+                        query_log!(warn, "resolver cannot reach {}: {}", ns_addr, e);
+                        last_err = Some(e);
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
+            let now = Instant::now();
+            let racing_before = racing.len();
+            racing.retain(|&(_, deadline)| deadline > now);
+            if racing.len() < racing_before {
+                for _ in racing.len()..racing_before {
+                    inc(&self.counters.timeouts);
+                }
+                last_err = Some(Error::IoError(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "timed out waiting for response",
+                )));
+                continue;
+            }
+            let deadline = match racing.iter().map(|&(_, deadline)| deadline).min() {
+                Some(deadline) => deadline,
+                None => break,
+            };
+            self.sock.get().set_read_timeout(Some(deadline - now))?;
+
+            let (msg, addr) = match self.sock.recv_from(&mut buf) {
+                Ok(received) => received,
+                Err(ref e) if e.is_timeout() => continue,
+                // An ICMP error does not tell which server it was
+                Err(e) if e.is_unreachable() => {
+                    query_log!(warn, "resolver cannot reach a server: {}", e);
+                    last_err = Some(e);
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+            let racer = racing
+                .iter()
+                .position(|&(ns_addr, _)| socket_address_equal(&ns_addr, &addr));
+            let response = msg.header.id == message.header.id && msg.header.qr == Qr::Response;
+            let racer = match racer {
+                Some(racer) if response => racer,
+                _ => {
+                    inc(&self.counters.mismatched);
+                    continue;
+                }
+            };
+            racing.remove(racer);
+            inc(&self.counters.responses_received);
+            let rcode = msg.header.rcode;
+            match rcode {
+                RCode::ServerFailure => inc(&self.counters.servfails),
+                RCode::NameError => inc(&self.counters.nxdomains),
+                _ => {}
+            }
+            if (self.config.retry_decision)(&rcode) == RetryDecision::TryNext {
+                query_log!(
+                    info,
+                    "resolver dropping {} from {} out of the race",
+                    rcode.get_error(),
+                    addr
+                );
+                last_err = Some(Error::DnsError(DnsError(rcode, None)));
+                continue;
+            }
+            let validated = match message.question.first() {
+                Some(q) if q.q_class == Class::Internet => {
+                    self.validate(&q.name, q.q_type, &msg, None)
+                }
+                _ => Ok(()),
+            };
+            match validated {
+                Ok(()) => return Ok(OwnedMessage::from(&msg)),
+                Err(e) => last_err = Some(e),
+            }
+        }
+        Err(last_err.unwrap_or_else(|| {
+            Error::IoError(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "no name servers to query",
+            ))
+        }))
+    }
+
+    /// Resolves the IPv4 and IPv6 addresses of many names at once through the
+    /// server and returns them with each name, in order.
+    ///
+    /// The A and AAAA queries of the names are pipelined on the socket of the
+    /// resolver, at most `DnsConfig::batch_limit` at a time, and the responses
+    /// are told apart by their ID. An unanswered query is sent again after
+    /// `DnsConfig::timeout` until `DnsConfig::attempts` are used up, so a name
+    /// without a response only holds up its own place in the batch. Every name
+    /// gets its own result: the IPv4 addresses followed by the IPv6 ones, or an
+    /// error if there are none, e.g., the `NameError` of the server.
+    ///
+    /// The names are looked up as they are, without the search list.
+    pub fn resolve_batch(
+        &self,
+        names: &[&str],
+        server: SocketAddr,
+    ) -> Vec<(String, Result<Vec<IpAddr>, Error>)> {
+        let mut lookups: Vec<BatchLookup> = names.iter().map(|_| BatchLookup::default()).collect();
+        let mut pending = Vec::new();
+        for (i, name) in names.iter().enumerate() {
+            match idna::to_ascii(name) {
+                Ok(name) => {
+                    pending.push((i, name.clone().into_owned(), RecordType::A));
+                    pending.push((i, name.into_owned(), RecordType::AAAA));
+                }
+                Err(_) => lookups[i].fail(Error::EncodeError(EncodeError::InvalidName)),
+            }
+        }
+        let mut pending = pending.into_iter();
+        let mut in_flight: Vec<BatchQuery> = Vec::new();
+        // buffers for the responses which one call of the socket receives
+        let mut bufs = vec![vec![0; MESSAGE_LIMIT]; BATCH_RECV_LIMIT];
+        let limit = self.config.batch_limit.max(1);
+        loop {
+            while in_flight.len() < limit {
+                let (i, name, r_type) = match pending.next() {
+                    Some(query) => query,
+                    None => break,
+                };
+                let mut msg = self.basic_message();
+                // the ID tells the queries in flight apart
+                while in_flight.iter().any(|q| q.msg.header.id == msg.header.id) {
+                    msg.header.id = self.rand.id();
+                }
+                msg.question
+                    .push(Question::new(name, r_type, Class::Internet));
+                // sent below with the queries which are due
+                in_flight.push(BatchQuery {
+                    lookup: i,
+                    msg: msg,
+                    attempts: 0,
+                    deadline: Instant::now(),
+                });
+            }
+
+            let now = Instant::now();
+            let mut due = Vec::new();
+            let mut k = 0;
+            while k < in_flight.len() {
+                if in_flight[k].deadline > now {
+                    k += 1;
+                    continue;
+                }
+                if in_flight[k].attempts > 0 {
+                    inc(&self.counters.timeouts);
+                }
+                if in_flight[k].attempts < self.config.attempts {
+                    in_flight[k].attempts += 1;
+                    in_flight[k].deadline = now + self.config.timeout;
+                    due.push(k);
+                    k += 1;
+                } else {
+                    let query = in_flight.remove(k);
+                    lookups[query.lookup].fail(Error::IoError(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        "timed out waiting for response",
+                    )));
+                }
+            }
+            // as many queries per call of the socket as it takes
+            let mut failed = Vec::new();
+            let mut sent = 0;
+            while sent < due.len() {
+                let messages: Vec<(&Message, SocketAddr)> = due[sent..]
+                    .iter()
+                    .map(|&k| (&in_flight[k].msg, server))
+                    .collect();
+                match self.sock.send_messages(&messages) {
+                    Ok(n) => {
+                        for _ in 0..n {
+                            inc(&self.counters.queries_sent);
+                        }
+                        sent += n;
+                    }
+                    Err(e) => {
+                        query_log!(warn, "resolver cannot send to {}: {}", server, e);
+                        failed.push((due[sent], e));
+                        sent += 1;
+                    }
+                }
+            }
+            for (k, e) in failed.into_iter().rev() {
+                let query = in_flight.remove(k);
+                lookups[query.lookup].fail(e);
+            }
+
+            let deadline = match in_flight.iter().map(|q| q.deadline).min() {
+                Some(deadline) => deadline,
+                None if pending.len() == 0 => break,
+                None => continue,
+            };
+            let now = Instant::now();
+            if deadline <= now {
+                continue;
+            }
+            if let Err(e) = self.sock.get().set_read_timeout(Some(deadline - now)) {
+                let e = Error::from(e);
+                for query in in_flight.drain(..) {
+                    lookups[query.lookup].fail(duplicate_error(&e));
+                }
+                continue;
+            }
+
+            let mut slices: Vec<&mut [u8]> = bufs.iter_mut().map(|buf| &mut buf[..]).collect();
+            let received = match self.sock.recv_packets(&mut slices) {
+                Ok(received) => received,
+                Err(e) => {
+                    let e = Error::from(e);
+                    if e.is_timeout() {
+                        continue;
+                    }
+                    if e.is_unreachable() {
+                        query_log!(warn, "resolver cannot reach {}: {}", server, e);
+                        continue;
+                    }
+                    for query in in_flight.drain(..) {
+                        lookups[query.lookup].fail(duplicate_error(&e));
+                    }
+                    continue;
+                }
+            };
+            for (buf, (n, len, addr)) in slices.iter().zip(received.into_iter()) {
+                // e.g., a datagram too long for the buffer
+                let msg = match Message::decode(&buf[..n]) {
+                    Ok(msg) if len == n => msg,
+                    _ => {
+                        inc(&self.counters.mismatched);
+                        continue;
+                    }
+                };
+                let answered = in_flight.iter().position(|q| {
+                    q.msg.header.id == msg.header.id
+                        && msg.header.qr == Qr::Response
+                        && msg.echoes_question(&q.msg)
+                });
+                let query = match answered {
+                    Some(k) if socket_address_equal(&server, &addr) => in_flight.remove(k),
+                    _ => {
+                        inc(&self.counters.mismatched);
+                        continue;
+                    }
+                };
+                inc(&self.counters.responses_received);
+                let lookup = &mut lookups[query.lookup];
+                let q = &query.msg.question[0];
+                match msg.header.rcode {
+                    RCode::NoError => {}
+                    rcode => {
+                        match rcode {
+                            RCode::ServerFailure => inc(&self.counters.servfails),
+                            RCode::NameError => inc(&self.counters.nxdomains),
+                            _ => {}
+                        }
+                        lookup.fail(Error::DnsError(DnsError(rcode, None)));
+                        continue;
+                    }
+                }
+                if let Err(e) = self.validate(&q.name, q.q_type, &msg, None) {
+                    lookup.fail(e);
+                    continue;
+                }
+                let name = msg.answer_name();
+                for rr in msg.answer.iter() {
+                    if rr.r_type != q.q_type || !is_answer_name(&rr.name, &name) {
+                        continue;
+                    }
+                    let decoded = match rr.r_type {
+                        RecordType::A => rr
+                            .read_rdata::<A>()
+                            .map(|a| lookup.v4.push(IpAddr::V4(a.address))),
+                        _ => rr
+                            .read_rdata::<AAAA>()
+                            .map(|aaaa| lookup.v6.push(IpAddr::V6(aaaa.address))),
+                    };
+                    if let Err(e) = decoded {
+                        lookup.fail(Error::DecodeError(e));
+                    }
+                }
+            }
+        }
+
+        names
+            .iter()
+            .zip(lookups.into_iter())
+            .map(|(name, lookup)| (name.to_string(), lookup.result()))
+            .collect()
+    }
+
+    /// Follows the referrals for the name from the closest known zone
+    fn iterate(
+        &self,
+        name: &str,
+        r_type: RecordType,
+        budget: &mut usize,
+        steps: &mut Vec<TraceStep>,
+    ) -> Result<OwnedMessage, Error> {
+        let name = idna::to_ascii(name)
+            .map_err(|_| Error::EncodeError(EncodeError::InvalidName))?
+            .into_owned();
+        let (mut zone, mut servers) = match self.delegations.closest(&name) {
+            Some(closest) => {
+                inc(&self.counters.cache_hits);
+                closest
+            }
+            None => {
+                inc(&self.counters.cache_misses);
+                (".".to_owned(), self.config.root_hints.clone())
+            }
+        };
+        let mut msg = Message::with_id(self.rand.id());
+        msg.question
+            .push(Question::new(name.clone(), r_type, Class::Internet));
+        loop {
+            let (server, response) = self.query_servers(&msg, &servers, budget)?;
+            let referral = iterative::referral(&name, &zone, &response);
+            query_log!(
+                info,
+                "iterative lookup of \"{}\" asked {} for {}: {}",
+                name,
+                server,
+                zone,
+                referral.as_ref().map_or("answer", |r| &r.zone[..])
+            );
+            steps.push(TraceStep {
+                zone: zone,
+                server: server,
+                response: response.clone(),
+            });
+            let referral = match referral {
+                Some(referral) => referral,
+                None => return Ok(response),
+            };
+            let mut next = iterative::glue(&referral, &response);
+            for ns in referral.name_servers.iter() {
+                if !next.is_empty() {
+                    break;
+                }
+                // without glue, the servers of names below the zone are unreachable
+                if is_subdomain(ns, &referral.zone) {
+                    continue;
+                }
+                match self.iterate(ns, RecordType::A, budget, steps) {
+                    Ok(ns_response) => next = iterative::answer_addrs(&ns_response),
+                    Err(e) if *budget == 0 => return Err(e),
+                    Err(_) => {}
+                }
+            }
+            if next.is_empty() {
+                return Err(Error::IoError(io::Error::new(
+                    io::ErrorKind::Other,
+                    format!("no addresses of the name servers of {}", referral.zone),
+                )));
+            }
+            self.delegations
+                .insert(&referral.zone, next.clone(), referral.ttl);
+            zone = referral.zone;
+            servers = next;
+        }
+    }
+
+    /// Sends the message to the servers one after another until one answers
+    fn query_servers(
+        &self,
+        msg: &Message,
+        servers: &[SocketAddr],
+        budget: &mut usize,
+    ) -> Result<(SocketAddr, OwnedMessage), Error> {
+        let mut last_err = None;
+        for &server in servers {
+            if *budget == 0 {
+                return Err(Error::IoError(io::Error::new(
+                    io::ErrorKind::Other,
+                    "too many queries for an iterative lookup",
+                )));
+            }
+            *budget -= 1;
+            match self.query_fastest(msg, &[server]) {
+                Ok(response) => return Ok((server, response)),
+                Err(e) => last_err = Some(e),
+            }
+        }
+        Err(last_err.unwrap_or_else(|| {
+            Error::IoError(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "no name servers to query",
+            ))
+        }))
+    }
+
+    /// Signs the message if a TSIG key is set and it is not signed yet, and
+    /// verifies the signature of the response, see `set_tsig_key`.
+    #[cfg(feature = "tsig")]
+    fn exchange_signed<'buf>(
+        &self,
+        out_msg: &Message,
+        buf: &'buf mut [u8],
+        deadline: Option<Instant>,
+    ) -> Result<Message<'buf>, Error> {
+        let key = match self.tsig_key {
+            Some(ref key) if !is_signed(out_msg) => key,
+            _ => return self.exchange_until(out_msg, buf, deadline),
+        };
+        let mut signed = out_msg.clone();
+        let request_mac = key.sign(&mut signed, self.clock.unix_time())?;
+        let msg = self.exchange_until(&signed, buf, deadline)?;
+        match key.verify_message(&msg, &request_mac, self.clock.unix_time()) {
+            Ok(()) => Ok(msg),
+            Err(TsigError::Unsigned) if msg.header.rcode != RCode::NoError => Ok(msg),
+            Err(e) => {
+                query_log!(warn, "resolver rejecting response {}: {}", msg.header.id, e);
+                Err(Error::TsigError(e))
+            }
+        }
+    }
+
+    #[cfg(not(feature = "tsig"))]
+    fn exchange_signed<'buf>(
+        &self,
+        out_msg: &Message,
+        buf: &'buf mut [u8],
+        deadline: Option<Instant>,
+    ) -> Result<Message<'buf>, Error> {
+        self.exchange_until(out_msg, buf, deadline)
+    }
+
+    /// Sends a message to the DNS server with retries and returns the response,
+    /// whatever its response code is, unless `DnsConfig::retry_decision` passes
+    /// it on to the next server: then the error of the last such response is
+    /// returned if no other server accepts the query.
+    ///
+    /// With a deadline, each attempt waits at most for the time which is left,
+    /// and a `TimedOut` error is returned when the deadline passed.
+    fn exchange_until<'buf>(
+        &self,
+        out_msg: &Message,
+        buf: &'buf mut [u8],
+        deadline: Option<Instant>,
+    ) -> Result<Message<'buf>, Error> {
+        let mut last_err = None;
+        // servers whose response was passed on, which are not asked again
+        let mut passed_on = Vec::new();
+        let mut passed_on_err = None;
 
         // FIXME(rust-lang/rust#21906):
         // Workaround for mutable borrow interfering with itself.
@@ -216,50 +1489,302 @@ impl DnsResolver {
         let buf_ptr = buf as *mut _;
         drop(buf);
 
-        'retry: for retries in 0..self.config.attempts {
-            let ns_addr = if self.config.rotate {
-                self.next_nameserver()
+        let randomized;
+        // the case of a signed question is covered by the signature
+        let out_msg = if self.config.randomize_case && !is_signed(out_msg) {
+            let mut msg = out_msg.clone();
+            for q in msg.question.iter_mut() {
+                q.randomize_case_with(&*self.rand);
+            }
+            randomized = msg;
+            &randomized
+        } else {
+            out_msg
+        };
+
+        let timeouts = self
+            .config
+            .retry
+            .timeouts_with(self.config.timeout, self.rand.clone());
+        let mut attempts = (0..self.config.attempts).zip(timeouts);
+        // After a FORMERR or a timeout, the query is sent once more to the same
+        // server without the OPT record, in case the server does not support
+        // EDNS (RFC 6891 section 7), and the remaining attempts stay without it.
+        let mut plain = None;
+        let mut edns_failed = None;
+        // After a BADCOOKIE response with the cookie of the server, the query
+        // is sent once more to it with the new server cookie (RFC 7873 section 5.3)
+        let mut bad_cookie = None;
+        let mut cookie_retried = false;
+        'retry: loop {
+            let (ns_addr, mut timeout) = match (bad_cookie.take(), edns_failed.take()) {
+                (Some(retry), _) => retry,
+                (None, Some(failed)) => {
+                    let mut msg = out_msg.clone();
+                    msg.additional.retain(|r| r.r_type != RecordType::Opt);
+                    plain = Some(msg);
+                    inc(&self.counters.edns_fallbacks);
+                    query_log!(
+                        info,
+                        "resolver retrying query {} to {} without EDNS",
+                        out_msg.header.id,
+                        failed.0
+                    );
+                    failed
+                }
+                (None, None) => match attempts.next() {
+                    Some((retries, timeout)) => match self.pick_nameserver(retries, &passed_on) {
+                        Some(ns_addr) => (ns_addr, timeout),
+                        None => break,
+                    },
+                    None => break,
+                },
+            };
+            let attempt_timeout = timeout;
+            timeout = remaining(deadline, timeout)?;
+            let query = plain.as_ref().unwrap_or(out_msg);
+            let with_cookie;
+            let cookies = self.config.cookies && plain.is_none() && !is_signed(query);
+            let query = if cookies {
+                let mut msg = query.clone();
+                msg.set_edns_option(
+                    EDNS_COOKIE,
+                    &self.cookies.option(self.client_ip(), ns_addr.ip()),
+                );
+                with_cookie = msg;
+                &with_cookie
             } else {
-                let n = self.config.name_servers.len();
-                self.config.name_servers[retries as usize % n]
+                query
             };
+            let edns = uses_edns(query);
+            let transport = stream_transport(&self.config, &ns_addr, query);
 
-            let mut timeout = self.config.timeout;
+            query_log!(
+                debug,
+                "resolver sending query {} to {}: {} {:?}",
+                query.header.id,
+                ns_addr,
+                query.question.first().map_or("", |q| &q.name[..]),
+                query.question.first().map(|q| q.q_type)
+            );
 
-            info!("resolver sending message to {}", ns_addr);
-
-            self.sock.send_message(out_msg, &ns_addr)?;
+            // A TCP or TLS connection gives the response of the query right away
+            let mut streamed = None;
+            if let Some(transport) = transport {
+                inc(&self.counters.queries_sent);
+                let buf = unsafe { &mut *buf_ptr };
+                let handshake_timeout = remaining(deadline, self.config.handshake_timeout)?;
+                match self.streams.exchange(
+                    &ns_addr,
+                    transport,
+                    query,
+                    timeout,
+                    handshake_timeout,
+                    buf,
+                ) {
+                    Ok(n) => streamed = Some(n),
+                    // Try the next server without a connection or response
+                    Err(e) => {
+                        if e.is_timeout() {
+                            inc(&self.counters.timeouts);
+                        }
+                        query_log!(
+                            warn,
+                            "resolver got no response to {} from {} over {:?}: {}",
+                            out_msg.header.id,
+                            ns_addr,
+                            transport,
+                            e
+                        );
+                        last_err = Some(e);
+                        continue 'retry;
+                    }
+                }
+            } else {
+                match self.sock.send_message(query, &ns_addr) {
+                    Ok(_) => {}
+                    // Try the next server if this one is not reachable
+                    Err(e) if e.is_unreachable() => {
+                        query_log!(warn, "resolver cannot reach {}: {}", ns_addr, e);
+                        last_err = Some(e);
+                        continue 'retry;
+                    }
+                    Err(e) => return Err(e),
+                }
+                inc(&self.counters.queries_sent);
+            }
 
             loop {
-                self.sock.get().set_read_timeout(Some(timeout))?;
+                timeout = remaining(deadline, timeout)?;
 
                 // The other part of the aforementioned workaround.
                 let buf = unsafe { &mut *buf_ptr };
 
                 let start = Instant::now();
 
-                match self.sock.recv_message(&ns_addr, buf) {
+                let received = match streamed.take() {
+                    Some(n) => Message::decode(&buf[..n]).map(Some).map_err(Error::from),
+                    // The only response on the connection was discarded
+                    None if transport.is_some() => {
+                        last_err = Some(Error::IoError(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            "no matching response on the connection",
+                        )));
+                        continue 'retry;
+                    }
+                    None => {
+                        self.sock.get().set_read_timeout(Some(timeout))?;
+                        self.sock.recv_message(&ns_addr, buf)
+                    }
+                };
+
+                match received {
                     Ok(None) => {
+                        inc(&self.counters.mismatched);
+                        query_log!(
+                            warn,
+                            "resolver discarded packet from unexpected address while waiting for {} from {}",
+                            out_msg.header.id,
+                            ns_addr
+                        );
                         let passed = start.elapsed();
 
                         // Maintain the right total timeout if we're interrupted
-                        // by irrelevant messages.
-                        if timeout < passed {
-                            timeout = Duration::from_secs(0);
-                        } else {
-                            timeout = timeout - passed;
+                        // by irrelevant messages. The socket rejects a zero timeout.
+                        if timeout <= passed {
+                            inc(&self.counters.timeouts);
+                            query_log!(
+                                warn,
+                                "resolver timed out waiting for {} from {}",
+                                out_msg.header.id,
+                                ns_addr
+                            );
+                            last_err = Some(Error::IoError(io::Error::new(
+                                io::ErrorKind::TimedOut,
+                                "timed out waiting for response",
+                            )));
+                            if edns {
+                                edns_failed = Some((ns_addr, attempt_timeout));
+                            }
+                            continue 'retry;
                         }
+                        timeout = timeout - passed;
                     }
                     Ok(Some(msg)) => {
                         // Ignore irrelevant messages
+                        if msg.header.id == out_msg.header.id
+                            && msg.header.qr == Qr::Response
+                            && self.config.randomize_case
+                            && !msg.echoes_question(out_msg)
+                        {
+                            inc(&self.counters.mismatched);
+                            query_log!(
+                                warn,
+                                "resolver discarded response {} from {} with another case of the question",
+                                msg.header.id,
+                                ns_addr
+                            );
+                            continue;
+                        }
+                        let cookie = match msg.cookie() {
+                            Some(cookie) if cookies => Some(cookie),
+                            _ => None,
+                        };
+                        if msg.header.id == out_msg.header.id
+                            && msg.header.qr == Qr::Response
+                            && cookie.map_or(false, |cookie| {
+                                !self.cookies.update(self.client_ip(), ns_addr.ip(), cookie)
+                            })
+                        {
+                            inc(&self.counters.mismatched);
+                            query_log!(
+                                warn,
+                                "resolver discarded response {} from {} with another client cookie",
+                                msg.header.id,
+                                ns_addr
+                            );
+                            continue;
+                        }
                         if msg.header.id == out_msg.header.id && msg.header.qr == Qr::Response {
-                            msg.get_error()?;
+                            inc(&self.counters.responses_received);
+                            query_log!(
+                                debug,
+                                "resolver received response {} from {}: {}, {} answers",
+                                msg.header.id,
+                                ns_addr,
+                                msg.header.rcode.get_error(),
+                                msg.answer.len()
+                            );
+                            match msg.header.rcode {
+                                RCode::ServerFailure => inc(&self.counters.servfails),
+                                RCode::NameError => inc(&self.counters.nxdomains),
+                                _ => {}
+                            }
+                            // A server without EDNS support answers without an OPT record
+                            if msg.header.rcode == RCode::FormatError
+                                && msg.edns_version().is_none()
+                                && edns
+                            {
+                                edns_failed = Some((ns_addr, attempt_timeout));
+                                continue 'retry;
+                            }
+                            if msg.extended_rcode() == Some(BADCOOKIE)
+                                && cookie.is_some()
+                                && !cookie_retried
+                            {
+                                cookie_retried = true;
+                                inc(&self.counters.cookie_retries);
+                                query_log!(
+                                    info,
+                                    "resolver retrying query {} to {} with its new server cookie",
+                                    out_msg.header.id,
+                                    ns_addr
+                                );
+                                bad_cookie = Some((ns_addr, attempt_timeout));
+                                continue 'retry;
+                            }
+                            let rcode = msg.header.rcode;
+                            if (self.config.retry_decision)(&rcode) == RetryDecision::TryNext {
+                                query_log!(
+                                    info,
+                                    "resolver passing {} from {} on to the next server",
+                                    rcode.get_error(),
+                                    ns_addr
+                                );
+                                passed_on.push(ns_addr);
+                                passed_on_err = Some(Error::DnsError(DnsError(rcode, None)));
+                                continue 'retry;
+                            }
                             return Ok(msg);
                         }
+                        inc(&self.counters.mismatched);
+                        query_log!(
+                            warn,
+                            "resolver discarded message {} from {} while waiting for {}",
+                            msg.header.id,
+                            ns_addr,
+                            out_msg.header.id
+                        );
                     }
                     Err(e) => {
                         // Retry on timeout
                         if e.is_timeout() {
+                            inc(&self.counters.timeouts);
+                            query_log!(
+                                warn,
+                                "resolver timed out waiting for {} from {}",
+                                out_msg.header.id,
+                                ns_addr
+                            );
+                            last_err = Some(e);
+                            if edns {
+                                edns_failed = Some((ns_addr, attempt_timeout));
+                            }
+                            continue 'retry;
+                        }
+                        // Retry with the next server, e.g., for an ICMP port unreachable
+                        if e.is_unreachable() {
+                            query_log!(warn, "resolver cannot reach {}: {}", ns_addr, e);
                             last_err = Some(e);
                             continue 'retry;
                         }
@@ -270,16 +1795,133 @@ impl DnsResolver {
             }
         }
 
-        Err(last_err.unwrap())
+        Err(passed_on_err.or(last_err).unwrap())
+    }
+
+    /// Returns the server for the attempt, the next one in the rotation or the
+    /// list which is not skipped, None if all are
+    fn pick_nameserver(&self, retries: u32, skip: &[SocketAddr]) -> Option<SocketAddr> {
+        let n = self.config.name_servers.len();
+        (0..n)
+            .map(|i| {
+                if self.config.rotate {
+                    self.next_nameserver()
+                } else {
+                    self.config.name_servers[(retries as usize + i) % n]
+                }
+            })
+            .find(|ns_addr| !skip.contains(ns_addr))
+    }
+
+    /// Returns the address the resolver socket is bound to for the client
+    /// cookies, the unspecified address if it cannot be told
+    fn client_ip(&self) -> IpAddr {
+        self.sock
+            .get()
+            .local_addr()
+            .map(|addr| addr.ip())
+            .unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED))
     }
 
     fn next_nameserver(&self) -> SocketAddr {
-        let n = self.next_ns.get();
-        self.next_ns.set((n + 1) % self.config.name_servers.len());
+        let len = self.config.name_servers.len();
+        let n = self.next_ns.fetch_add(1, Ordering::Relaxed) % len;
         self.config.name_servers[n]
     }
 }
 
+#[cfg(feature = "dnssec")]
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Whether the message has a TSIG record, which covers the other records
+fn is_signed(msg: &Message) -> bool {
+    msg.additional.iter().any(|r| r.r_type == RecordType::Tsig)
+}
+
+/// Whether a server without EDNS support may reject the query because of its
+/// OPT record, a query signed with TSIG can't be sent without it
+fn uses_edns(msg: &Message) -> bool {
+    msg.additional.iter().any(|r| r.r_type == RecordType::Opt) && !is_signed(msg)
+}
+
+/// Maximum number of responses of `DnsResolver::resolve_batch` received with
+/// one call of the socket
+const BATCH_RECV_LIMIT: usize = 16;
+
+static TCP: Transport = Transport::Tcp;
+
+/// Returns the transport of the server unless it is UDP, or TCP for a query
+/// which is too long for UDP, e.g., a large UPDATE.
+fn stream_transport<'c>(
+    config: &'c DnsConfig,
+    server: &SocketAddr,
+    query: &Message,
+) -> Option<&'c Transport> {
+    match config.transports.get(server) {
+        Some(&Transport::Udp) | None if query.encoded_len() > UDP_QUERY_LIMIT => Some(&TCP),
+        Some(&Transport::Udp) | None => None,
+        Some(transport) => Some(transport),
+    }
+}
+
+/// A query of `DnsResolver::resolve_batch` waiting for its response
+struct BatchQuery<'a> {
+    /// Index of the name
+    lookup: usize,
+    msg: Message<'a>,
+    attempts: u32,
+    deadline: Instant,
+}
+
+/// The addresses of a name of `DnsResolver::resolve_batch` received so far
+#[derive(Default)]
+struct BatchLookup {
+    v4: Vec<IpAddr>,
+    v6: Vec<IpAddr>,
+    err: Option<Error>,
+}
+
+impl BatchLookup {
+    /// Records the error of a query, the first one is returned if the other
+    /// query has no addresses either
+    fn fail(&mut self, err: Error) {
+        if self.err.is_none() {
+            self.err = Some(err);
+        }
+    }
+
+    fn result(mut self) -> Result<Vec<IpAddr>, Error> {
+        if self.v4.is_empty() && self.v6.is_empty() {
+            return Err(self.err.unwrap_or_else(|| {
+                Error::IoError(io::Error::new(
+                    io::ErrorKind::Other,
+                    "failed to resolve host: name not found",
+                ))
+            }));
+        }
+        self.v4.append(&mut self.v6);
+        Ok(self.v4)
+    }
+}
+
+/// Returns an equal error for another caller, I/O errors keep their kind and message
+fn duplicate_error(err: &Error) -> Error {
+    match *err {
+        Error::DecodeError(e) => Error::DecodeError(e),
+        Error::EncodeError(ref e) => Error::EncodeError(e.clone()),
+        Error::DnsError(e) => Error::DnsError(e),
+        Error::IoError(ref e) => Error::IoError(io::Error::new(e.kind(), e.to_string())),
+        Error::BufferTooSmall(len) => Error::BufferTooSmall(len),
+        #[cfg(feature = "tsig")]
+        Error::TsigError(e) => Error::TsigError(e),
+    }
+}
+
 fn bind_addr(name_servers: &[SocketAddr]) -> IpAddr {
     match name_servers.first() {
         Some(&SocketAddr::V6(_)) => IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 0)),
@@ -301,12 +1943,42 @@ where
     }
 }
 
+/// Returns the time until the deadline, or a `TimedOut` error if it passed
+fn time_left(deadline: Option<Instant>) -> Result<Option<Duration>, Error> {
+    let deadline = match deadline {
+        Some(deadline) => deadline,
+        None => return Ok(None),
+    };
+    let now = Instant::now();
+    if now >= deadline {
+        return Err(Error::IoError(io::Error::new(
+            io::ErrorKind::TimedOut,
+            "deadline passed",
+        )));
+    }
+    Ok(Some(deadline - now))
+}
+
+/// Returns the timeout of a step, at most the time until the deadline
+fn remaining(deadline: Option<Instant>, timeout: Duration) -> Result<Duration, Error> {
+    Ok(time_left(deadline)?.map_or(timeout, |left| min(left, timeout)))
+}
+
+/// Whether the owner of an address record is the name the answer ends at after the
+/// CNAME and DNAME records, see `Message::answer_name`
+fn is_answer_name(owner: &str, answer_name: &Option<String>) -> bool {
+    answer_name
+        .as_ref()
+        .map_or(true, |name| is_same_name(owner, name))
+}
+
 fn query_names<F, T>(name: &str, config: &DnsConfig, mut f: F) -> Result<T, Error>
 where
     F: FnMut(String) -> Result<T, Error>,
 {
-    let use_search =
-        !name.ends_with('.') && name.chars().filter(|&c| c == '.').count() as u32 >= config.n_dots;
+    let use_search = !name.ends_with('.')
+        && name.chars().filter(|&c| c == '.').count() as u32 >= config.n_dots
+        && !uses_mdns(config, name);
 
     if use_search {
         let mut err = None;
@@ -331,6 +2003,17 @@ where
     }
 }
 
+/// Whether the name is resolved through mDNS, see `DnsConfig::mdns`
+#[cfg(feature = "mdns")]
+fn uses_mdns(config: &DnsConfig, name: &str) -> bool {
+    config.mdns && mdns::is_local(name)
+}
+
+#[cfg(not(feature = "mdns"))]
+fn uses_mdns(_config: &DnsConfig, _name: &str) -> bool {
+    false
+}
+
 fn with_suffixes(host: &str, suffixes: &[String]) -> Vec<String> {
     let mut v = suffixes
         .iter()
@@ -346,6 +2029,26 @@ pub fn resolve_addr(addr: &IpAddr) -> io::Result<String> {
     r.resolve_addr(addr)
 }
 
+/// Requests the `HTTPS` records of a name, see `DnsResolver::resolve_https`.
+pub fn resolve_https(name: &str) -> io::Result<Vec<Https>> {
+    let r = DnsResolver::new(DnsConfig::load_default()?)?;
+    r.resolve_https(name)
+}
+
+/// Queries the version of the name server software at the address through
+/// `version.bind TXT CH`, see `DnsResolver::resolve_version_bind`.
+pub fn resolve_version_bind(server: SocketAddr) -> io::Result<String> {
+    let r = DnsResolver::new(DnsConfig::with_name_servers(vec![server]))?;
+    r.resolve_version_bind()
+}
+
+/// Queries the identifier of the name server at the address through
+/// `id.server TXT CH`, see `DnsResolver::resolve_server_id`.
+pub fn resolve_server_id(server: SocketAddr) -> io::Result<String> {
+    let r = DnsResolver::new(DnsConfig::with_name_servers(vec![server]))?;
+    r.resolve_server_id()
+}
+
 /// Resolves a hostname to one or more IPv4 or IPv6 addresses.
 ///
 /// # Example
@@ -367,7 +2070,7 @@ pub fn resolve_host(host: &str) -> io::Result<ResolveHost> {
 }
 
 /// Yields a series of `IpAddr` values from `resolve_host`.
-pub struct ResolveHost(IntoIter<IpAddr>);
+pub struct ResolveHost(pub(crate) IntoIter<IpAddr>);
 
 impl Iterator for ResolveHost {
     type Item = IpAddr;
@@ -376,3 +2079,703 @@ impl Iterator for ResolveHost {
         self.0.next()
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::{stream_transport, DnsResolver};
+    use crate::resolve::config::{DnsConfig, Transport};
+    use crate::resolve::message::{
+        Message, OwnedRecord, Qr, Question, RCode, EDNS_COOKIE, MESSAGE_LIMIT,
+    };
+    use crate::resolve::record::{
+        CName, Class, Dname, Https, RecordData, RecordType, SvcParam, Txt, A, AAAA,
+    };
+    use crate::resolve::rng::SeededRandom;
+    use crate::resolve::socket::{DnsSocket, PacketDirection};
+    use crate::resolve::update::UpdateBuilder;
+    use std::io;
+    use std::net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    /// Answers every query with the response code, returns its address and the query count
+    fn server(rcode: RCode) -> (SocketAddr, Arc<AtomicUsize>) {
+        slow_server(rcode, Duration::from_secs(0))
+    }
+
+    /// Answers every query with the response code after the delay
+    fn slow_server(rcode: RCode, delay: Duration) -> (SocketAddr, Arc<AtomicUsize>) {
+        let sock = UdpSocket::bind("127.0.0.1:0").unwrap();
+        sock.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let addr = sock.local_addr().unwrap();
+        let queries = Arc::new(AtomicUsize::new(0));
+        let counter = queries.clone();
+        thread::spawn(move || {
+            let mut buf = [0; MESSAGE_LIMIT];
+            let mut out = [0; MESSAGE_LIMIT];
+            while let Ok((n, peer)) = sock.recv_from(&mut buf) {
+                counter.fetch_add(1, Ordering::SeqCst);
+                thread::sleep(delay);
+                let mut msg = Message::decode(&buf[..n]).unwrap();
+                msg.header.qr = Qr::Response;
+                msg.header.rcode = rcode;
+                sock.send_to(msg.encode(&mut out).unwrap(), peer).unwrap();
+            }
+        });
+        (addr, queries)
+    }
+
+    /// Answers every query with the records
+    fn answering(records: Vec<OwnedRecord>) -> SocketAddr {
+        let sock = UdpSocket::bind("127.0.0.1:0").unwrap();
+        sock.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let addr = sock.local_addr().unwrap();
+        thread::spawn(move || {
+            let mut buf = [0; MESSAGE_LIMIT];
+            let mut out = [0; MESSAGE_LIMIT];
+            while let Ok((n, peer)) = sock.recv_from(&mut buf) {
+                let mut msg = Message::decode(&buf[..n]).unwrap();
+                msg.header.qr = Qr::Response;
+                msg.answer = records.iter().map(|rr| rr.to_resource().unwrap()).collect();
+                sock.send_to(msg.encode(&mut out).unwrap(), peer).unwrap();
+            }
+        });
+        addr
+    }
+
+    fn resolver(servers: &[SocketAddr]) -> DnsResolver<UdpSocket> {
+        let mut config = DnsConfig::with_name_servers(servers.to_vec());
+        config.timeout = Duration::from_secs(2);
+        let sock = DnsSocket::from_socket(UdpSocket::bind("127.0.0.1:0").unwrap());
+        DnsResolver::with_socket(sock, config)
+    }
+
+    fn query() -> Message<'static> {
+        let mut msg = Message::with_id(0x1234);
+        msg.question.push(Question::new(
+            "example.com".to_string(),
+            RecordType::A,
+            Class::Internet,
+        ));
+        msg
+    }
+
+    /// Sends a query to the servers in order and returns the response code
+    fn resolve(servers: &[SocketAddr]) -> RCode {
+        let resolver = resolver(servers);
+        let msg = query();
+        let mut buf = [0; MESSAGE_LIMIT];
+        match resolver.send_message(&msg, &mut buf) {
+            Ok(msg) => msg.header.rcode,
+            Err(e) => e.dns_error().unwrap().0,
+        }
+    }
+
+    #[test]
+    fn test_servfail_tries_next() {
+        let (failing, failing_queries) = server(RCode::ServerFailure);
+        let (working, working_queries) = server(RCode::NoError);
+        assert_eq!(resolve(&[failing, working]), RCode::NoError);
+        assert_eq!(failing_queries.load(Ordering::SeqCst), 1);
+        assert_eq!(working_queries.load(Ordering::SeqCst), 1);
+        // the failure is returned when no other server answers
+        assert_eq!(resolve(&[failing]), RCode::ServerFailure);
+        assert_eq!(failing_queries.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn test_final_responses() {
+        for &rcode in &[RCode::NameError, RCode::NoError, RCode::NotImplemented] {
+            let (first, first_queries) = server(rcode);
+            let (second, second_queries) = server(RCode::NoError);
+            assert_eq!(resolve(&[first, second]), rcode);
+            assert_eq!(first_queries.load(Ordering::SeqCst), 1);
+            assert_eq!(second_queries.load(Ordering::SeqCst), 0);
+        }
+    }
+
+    #[test]
+    fn test_refused_by_all() {
+        let (first, first_queries) = server(RCode::Refused);
+        let (second, second_queries) = server(RCode::Refused);
+        // each server is asked once, not for all five attempts
+        assert_eq!(resolve(&[first, second]), RCode::Refused);
+        assert_eq!(first_queries.load(Ordering::SeqCst), 1);
+        assert_eq!(second_queries.load(Ordering::SeqCst), 1);
+
+        let (allowing, allowing_queries) = server(RCode::NoError);
+        assert_eq!(resolve(&[first, allowing]), RCode::NoError);
+        assert_eq!(allowing_queries.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_query_fastest() {
+        let silent = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let silent_addr = silent.local_addr().unwrap();
+        let (failing, failing_queries) = server(RCode::ServerFailure);
+        let (working, working_queries) = server(RCode::NoError);
+        // neither the silent server nor the failure end the race
+        let servers = [silent_addr, failing, working];
+        let resolver = resolver(&servers);
+        let start = Instant::now();
+        let msg = resolver.query_fastest(&query(), &servers).unwrap();
+        assert_eq!(msg.header.rcode, RCode::NoError);
+        assert!(start.elapsed() < Duration::from_secs(2));
+        assert_eq!(failing_queries.load(Ordering::SeqCst), 1);
+        assert_eq!(working_queries.load(Ordering::SeqCst), 1);
+
+        // one at a time, the next server only after the failure
+        let mut resolver = resolver;
+        resolver.config.fan_out = 1;
+        let (unused, unused_queries) = server(RCode::NoError);
+        let msg = resolver
+            .query_fastest(&query(), &[failing, working, unused])
+            .unwrap();
+        assert_eq!(msg.header.rcode, RCode::NoError);
+        assert_eq!(failing_queries.load(Ordering::SeqCst), 2);
+        assert_eq!(working_queries.load(Ordering::SeqCst), 2);
+        assert_eq!(unused_queries.load(Ordering::SeqCst), 0);
+
+        let err = resolver.query_fastest(&query(), &[failing]).unwrap_err();
+        assert_eq!(err.dns_error().unwrap().0, RCode::ServerFailure);
+    }
+
+    #[test]
+    fn test_coalesced_queries() {
+        // the callers start while the first query waits for the server
+        let concurrent = |resolver: &Arc<DnsResolver<UdpSocket>>, deadlines: Vec<Option<u64>>| {
+            let callers: Vec<_> = deadlines
+                .into_iter()
+                .enumerate()
+                .map(|(k, deadline)| {
+                    let resolver = resolver.clone();
+                    thread::spawn(move || {
+                        thread::sleep(Duration::from_millis(50 * k as u64));
+                        let deadline =
+                            deadline.map(|millis| Instant::now() + Duration::from_millis(millis));
+                        resolver
+                            .query_until("example.com", RecordType::A, deadline)
+                            .map(|msg| msg.header.rcode)
+                            .map_err(|e| e.dns_error().map(|e| e.0))
+                    })
+                })
+                .collect();
+            callers
+                .into_iter()
+                .map(|caller| caller.join().unwrap())
+                .collect::<Vec<_>>()
+        };
+        let delay = Duration::from_millis(400);
+
+        let (working, working_queries) = slow_server(RCode::NoError, delay);
+        let shared = Arc::new(resolver(&[working]));
+        let results = concurrent(&shared, vec![None; 4]);
+        assert_eq!(results, vec![Ok(RCode::NoError); 4]);
+        assert_eq!(working_queries.load(Ordering::SeqCst), 1);
+        assert_eq!(shared.stats().coalesced, 3);
+
+        // all waiting callers get the error, a later lookup sends a new query
+        let (failing, failing_queries) = slow_server(RCode::ServerFailure, delay);
+        let shared = Arc::new(resolver(&[failing]));
+        let results = concurrent(&shared, vec![None; 3]);
+        assert_eq!(results, vec![Err(Some(RCode::ServerFailure)); 3]);
+        assert_eq!(failing_queries.load(Ordering::SeqCst), 1);
+        let results = concurrent(&shared, vec![None]);
+        assert_eq!(results, vec![Err(Some(RCode::ServerFailure))]);
+        assert_eq!(failing_queries.load(Ordering::SeqCst), 2);
+        assert!(shared.in_flight.lock().unwrap().is_empty());
+
+        // the timeout of the first caller is not the result of the callers without one
+        let (working, working_queries) = slow_server(RCode::NoError, delay);
+        let shared = Arc::new(resolver(&[working]));
+        let results = concurrent(&shared, vec![Some(100), None, None]);
+        assert_eq!(results[0], Err(None));
+        assert_eq!(results[1..], [Ok(RCode::NoError), Ok(RCode::NoError)]);
+        assert_eq!(working_queries.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn test_flight_guard() {
+        use super::{FlightGuard, FlightResult, InFlight};
+        use crate::resolve::name::DnsName;
+
+        // the caller which sends the query panics before it sets the result
+        let resolver = resolver(&[]);
+        let key = (DnsName::new("example.com"), 1, 1, None);
+        let flight = Arc::new(InFlight::default());
+        let _ = resolver
+            .in_flight
+            .lock()
+            .unwrap()
+            .insert(key.clone(), flight.clone());
+        drop(FlightGuard {
+            in_flight: &resolver.in_flight,
+            key: key,
+            flight: flight.clone(),
+            result: None,
+        });
+        assert!(resolver.in_flight.lock().unwrap().is_empty());
+        let result = flight.result.lock().unwrap();
+        match *result {
+            Some(FlightResult::Done(Err(ref e))) => {
+                assert_eq!(e.to_string(), "the caller which sent the query panicked")
+            }
+            _ => panic!("no error for the waiting callers"),
+        }
+    }
+
+    #[test]
+    fn test_dname_answer() {
+        let record =
+            |name: &str, data| OwnedRecord::new(name.to_owned(), Class::Internet, 60, data);
+        let a = |last| {
+            RecordData::A(A {
+                address: Ipv4Addr::new(192, 0, 2, last),
+            })
+        };
+        let server = answering(vec![
+            record(
+                "sub.example.com.",
+                RecordData::Dname(Dname {
+                    target: "example.com.".to_owned(),
+                }),
+            ),
+            record(
+                "www.sub.example.com.",
+                RecordData::CName(CName {
+                    name: "www.example.com.".to_owned(),
+                }),
+            ),
+            record("www.example.com.", a(1)),
+            // not the name the answer ends at
+            record("www.sub.example.com.", a(2)),
+            record("mail.example.com.", a(3)),
+        ]);
+        let addrs: Vec<_> = resolver(&[server])
+            .resolve_host("www.sub.example.com")
+            .unwrap()
+            .collect();
+        assert_eq!(addrs, vec![IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1))]);
+    }
+
+    #[test]
+    fn test_trace() {
+        let a = |last| {
+            OwnedRecord::new(
+                "www.example.com.".to_owned(),
+                Class::Internet,
+                60,
+                RecordData::A(A {
+                    address: Ipv4Addr::new(192, 0, 2, last),
+                }),
+            )
+        };
+        let root = answering(vec![a(1)]);
+        let mut config = DnsConfig::with_name_servers(Vec::new());
+        config.timeout = Duration::from_secs(2);
+        config.root_hints = vec![root];
+        let sock = DnsSocket::from_socket(UdpSocket::bind("127.0.0.1:0").unwrap());
+        let resolver = DnsResolver::with_socket(sock, config);
+
+        let trace = resolver.trace("www.example.com", RecordType::A).unwrap();
+        assert_eq!(trace.servers(), vec![root]);
+        assert_eq!(trace.steps[0].zone, ".");
+        assert_eq!(trace.answer.answer, vec![a(1)]);
+        assert!(!trace.answer.header.recursion_desired);
+        assert_eq!(resolver.stats().cache_misses, 1);
+
+        // the name servers of a zone learned before are asked first
+        let zone = answering(vec![a(2)]);
+        resolver.delegations.insert("Example.com.", vec![zone], 60);
+        let trace = resolver.trace("www.example.com", RecordType::A).unwrap();
+        assert_eq!(trace.servers(), vec![zone]);
+        assert_eq!(trace.steps[0].zone, "example.com.");
+        assert_eq!(resolver.stats().cache_hits, 1);
+        let answer = resolver
+            .resolve_iterative("www.example.org", RecordType::A)
+            .unwrap();
+        assert_eq!(answer.answer, vec![a(1)]);
+        let stats = resolver.stats();
+        assert_eq!((stats.cache_hits, stats.cache_misses), (1, 2));
+    }
+
+    #[cfg(feature = "dnssec")]
+    #[test]
+    fn test_cached_denial_miss() {
+        use crate::resolve::dnssec::{TrustAnchor, Validator};
+        use crate::resolve::message::Question;
+
+        let mut resolver = resolver(&[]);
+        let question = Question::new("nx.example.".to_owned(), RecordType::A, Class::Internet);
+        // without a trust anchor there is no cache to look up
+        assert!(resolver.cached_denial(&question).is_none());
+        assert_eq!(resolver.stats().cache_misses, 0);
+        resolver.validator = Some(Validator::new(TrustAnchor::root()));
+        assert!(resolver.cached_denial(&question).is_none());
+        // the denials are only kept for the Internet class
+        let chaos = Question::new("nx.example.".to_owned(), RecordType::A, Class::Chaos);
+        assert!(resolver.cached_denial(&chaos).is_none());
+        let stats = resolver.stats();
+        assert_eq!((stats.cache_hits, stats.cache_misses), (0, 1));
+        assert_eq!(stats.cached_denials, 0);
+    }
+
+    #[test]
+    fn test_resolve_https() {
+        let https = |name: &str, priority, target: &str, params| {
+            OwnedRecord::new(
+                name.to_owned(),
+                Class::Internet,
+                60,
+                RecordData::Https(Https {
+                    priority: priority,
+                    target: target.to_owned(),
+                    params: params,
+                }),
+            )
+        };
+        let server = answering(vec![
+            https("example.com.", 0, "svc.example.net.", vec![]),
+            // ignored next to the alias
+            https("example.com.", 1, ".", vec![SvcParam::Port(8000)]),
+            https("svc.example.net.", 2, "backup.example.net.", vec![]),
+            https(
+                "svc.example.net.",
+                1,
+                ".",
+                vec![
+                    SvcParam::Alpn(vec![b"h2".to_vec()]),
+                    SvcParam::Port(8443),
+                    SvcParam::Ipv4Hint(vec![
+                        Ipv4Addr::new(192, 0, 2, 1),
+                        Ipv4Addr::new(192, 0, 2, 2),
+                    ]),
+                    SvcParam::Ipv6Hint(vec!["2001:db8::1".parse().unwrap()]),
+                ],
+            ),
+        ]);
+        let records = resolver(&[server]).resolve_https("example.com").unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].priority, 1);
+        assert_eq!(records[1].target, "backup.example.net.");
+        assert_eq!(records[0].alpn(), Some(&[b"h2".to_vec()][..]));
+        assert_eq!(
+            records[0].hint_addrs(443),
+            vec![
+                "[2001:db8::1]:8443".parse::<SocketAddr>().unwrap(),
+                "192.0.2.1:8443".parse().unwrap(),
+                "192.0.2.2:8443".parse().unwrap(),
+            ]
+        );
+        assert!(records[1].hint_addrs(443).is_empty());
+
+        // an alias to the root name means that there is no service
+        let server = answering(vec![https("example.com.", 0, ".", vec![])]);
+        assert!(resolver(&[server])
+            .resolve_https("example.com")
+            .unwrap()
+            .is_empty());
+    }
+    #[test]
+    fn test_resolve_batch() {
+        let record =
+            |name: &str, data| OwnedRecord::new(name.to_owned(), Class::Internet, 60, data);
+        let server = answering(vec![
+            record(
+                "a.example.",
+                RecordData::A(A {
+                    address: Ipv4Addr::new(192, 0, 2, 1),
+                }),
+            ),
+            record(
+                "a.example.",
+                RecordData::AAAA(AAAA {
+                    address: "2001:db8::1".parse().unwrap(),
+                }),
+            ),
+            record(
+                "b.example.",
+                RecordData::A(A {
+                    address: Ipv4Addr::new(192, 0, 2, 2),
+                }),
+            ),
+        ]);
+        let mut resolver = resolver(&[server]);
+        resolver.config.batch_limit = 3;
+        let names = ["a.example.", "b.example.", "c.example.", "a.example."];
+        let results = resolver.resolve_batch(&names, server);
+        assert_eq!(results.len(), 4);
+        assert_eq!(results[0].0, "a.example.");
+        assert_eq!(
+            results[0].1.as_ref().unwrap(),
+            &vec![
+                "192.0.2.1".parse::<IpAddr>().unwrap(),
+                "2001:db8::1".parse().unwrap(),
+            ]
+        );
+        assert_eq!(
+            results[1].1.as_ref().unwrap(),
+            &vec!["192.0.2.2".parse::<IpAddr>().unwrap()]
+        );
+        // a name without addresses does not fail the others
+        assert!(results[2].1.is_err());
+        assert_eq!(results[3].1.as_ref().unwrap().len(), 2);
+        assert_eq!(resolver.stats().queries_sent, 8);
+        assert_eq!(resolver.stats().responses_received, 8);
+    }
+    #[test]
+    fn test_cookies() {
+        let sock = UdpSocket::bind("127.0.0.1:0").unwrap();
+        sock.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let addr = sock.local_addr().unwrap();
+        thread::spawn(move || {
+            let mut buf = [0; MESSAGE_LIMIT];
+            let mut out = [0; MESSAGE_LIMIT];
+            while let Ok((n, peer)) = sock.recv_from(&mut buf) {
+                let mut msg = Message::decode(&buf[..n]).unwrap();
+                let mut cookie = msg.cookie().unwrap().to_vec();
+                msg.header.qr = Qr::Response;
+                // BADCOOKIE without the server cookie
+                if cookie.len() == 8 {
+                    cookie.extend_from_slice(&[0x5e; 16]);
+                    msg.header.rcode = RCode::YxRrSet;
+                    msg.additional[0].ttl |= 1 << 24;
+                }
+                msg.set_edns_option(EDNS_COOKIE, &cookie);
+                sock.send_to(msg.encode(&mut out).unwrap(), peer).unwrap();
+            }
+        });
+        let mut resolver = resolver(&[addr]);
+        resolver.config.cookies = true;
+        let mut buf = [0; MESSAGE_LIMIT];
+        let response = resolver.send_message(&query(), &mut buf).unwrap();
+        assert_eq!(response.cookie().unwrap()[8..], [0x5e; 16]);
+        assert_eq!(resolver.stats().cookie_retries, 1);
+        // the next query has the server cookie from the start
+        resolver.send_message(&query(), &mut buf).unwrap();
+        assert_eq!(resolver.stats().queries_sent, 3);
+        assert_eq!(resolver.stats().cookie_retries, 1);
+    }
+
+    #[test]
+    fn test_stats_failed_lookup() {
+        let (failing, _) = server(RCode::ServerFailure);
+        let (missing, _) = server(RCode::NameError);
+        let failed = resolver(&[failing, missing]);
+        let mut buf = [0; MESSAGE_LIMIT];
+        let err = failed.send_message(&query(), &mut buf).unwrap_err();
+        assert_eq!(err.dns_error().unwrap().0, RCode::NameError);
+        let stats = failed.stats();
+        assert_eq!(stats.queries_sent, 2);
+        assert_eq!(stats.responses_received, 2);
+        assert_eq!(stats.servfails, 1);
+        assert_eq!(stats.nxdomains, 1);
+        assert_eq!(stats.timeouts, 0);
+
+        let silent = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let mut timed_out = resolver(&[silent.local_addr().unwrap()]);
+        timed_out.config.timeout = Duration::from_millis(50);
+        timed_out.config.attempts = 1;
+        assert!(timed_out.send_message(&query(), &mut buf).unwrap_err().is_timeout());
+        let stats = timed_out.stats();
+        assert!(stats.queries_sent >= 1);
+        assert!(stats.timeouts >= 1);
+        assert_eq!(stats.responses_received, 0);
+    }
+
+    #[test]
+    fn test_deadline() {
+        let silent = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let mut resolver = resolver(&[silent.local_addr().unwrap()]);
+        resolver.config.search = vec!["a.example".to_owned(), "b.example".to_owned()];
+        // three names, two types, and five attempts of 2 seconds each
+        let start = Instant::now();
+        match resolver.resolve_host_deadline("host.test", start + Duration::from_millis(300)) {
+            Err(e) => assert_eq!(e.kind(), io::ErrorKind::TimedOut),
+            Ok(_) => panic!("resolved without a response"),
+        }
+        assert!(start.elapsed() < Duration::from_secs(1));
+
+        // a passed deadline sends nothing
+        let queries_sent = resolver.stats().queries_sent;
+        let mut buf = [0; MESSAGE_LIMIT];
+        let err = resolver
+            .send_message_deadline(&query(), &mut buf, Instant::now())
+            .unwrap_err();
+        assert!(err.is_timeout());
+        assert_eq!(resolver.stats().queries_sent, queries_sent);
+    }
+
+    #[test]
+    fn test_stream_transport() {
+        let server: SocketAddr = "192.0.2.1:53".parse().unwrap();
+        let mut config = DnsConfig::with_name_servers(vec![server]);
+        let mut update = UpdateBuilder::new("internal.example.");
+        assert_eq!(stream_transport(&config, &server, &query()), None);
+        for i in 0..20 {
+            let name = format!("host-{}.internal.example.", i);
+            let address = Ipv4Addr::new(192, 0, 2, i);
+            update = update.add(&name, 300, &A { address: address });
+        }
+        let update = update.build().unwrap();
+        assert!(update.encoded_len() > 512);
+        assert_eq!(
+            stream_transport(&config, &server, &update),
+            Some(&Transport::Tcp)
+        );
+        config.transports.insert(server, Transport::Udp);
+        assert_eq!(
+            stream_transport(&config, &server, &update),
+            Some(&Transport::Tcp)
+        );
+        assert_eq!(stream_transport(&config, &server, &query()), None);
+    }
+
+    #[test]
+    fn test_rand_source() {
+        let addr = answering(vec![OwnedRecord::new(
+            "example.com.".to_owned(),
+            Class::Internet,
+            60,
+            RecordData::A(A {
+                address: Ipv4Addr::new(192, 0, 2, 1),
+            }),
+        )]);
+        let sent = Arc::new(Mutex::new(Vec::new()));
+        for _ in 0..2 {
+            let mut resolver = resolver(&[addr]);
+            resolver.config.randomize_case = true;
+            resolver.set_rand_source(SeededRandom::new(96));
+            let queries = sent.clone();
+            resolver.set_packet_hook(move |data, _, direction| {
+                if direction == PacketDirection::Outgoing {
+                    queries.lock().unwrap().push(data.to_vec());
+                }
+            });
+            assert_eq!(
+                resolver.resolve_record::<A>("example.com").unwrap().len(),
+                1
+            );
+        }
+        let sent = sent.lock().unwrap();
+        assert_eq!(sent.len(), 2);
+        assert_eq!(sent[0], sent[1]);
+        let query = Message::decode(&sent[0]).unwrap();
+        assert_eq!(query.id(), 59341);
+        assert_eq!(query.question[0].name, "eXAMpLe.com.");
+    }
+
+    #[test]
+    fn test_nsid() {
+        let addr = answering(vec![OwnedRecord::new(
+            "id.server.".to_owned(),
+            Class::Chaos,
+            0,
+            RecordData::Txt(Txt {
+                data: b"fra-3".to_vec(),
+            }),
+        )]);
+        let mut resolver = resolver(&[addr]);
+        resolver.config.nsid = true;
+        let sent = Arc::new(Mutex::new(Vec::new()));
+        let queries = sent.clone();
+        resolver.set_packet_hook(move |data, _, direction| {
+            if direction == PacketDirection::Outgoing {
+                queries.lock().unwrap().push(data.to_vec());
+            }
+        });
+        assert_eq!(resolver.resolve_server_id().unwrap(), "fra-3");
+        let sent = sent.lock().unwrap();
+        let query = Message::decode(&sent[0]).unwrap();
+        assert_eq!(query.question[0].name, "id.server.");
+        assert_eq!(query.question[0].q_class, Class::Chaos);
+        assert_eq!(query.nsid(), Some(vec![]));
+    }
+
+    /// Answers every query with a response signed with the key at the time,
+    /// or with a BADSIG error if the query is not signed with the key; the
+    /// time of the query is not checked
+    #[cfg(feature = "tsig")]
+    fn signing_server(key: crate::resolve::tsig::TsigKey, now: u64) -> SocketAddr {
+        use crate::resolve::message::Resource;
+        use crate::resolve::record::Tsig;
+
+        let sock = UdpSocket::bind("127.0.0.1:0").unwrap();
+        sock.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let addr = sock.local_addr().unwrap();
+        thread::spawn(move || {
+            let mut buf = [0; MESSAGE_LIMIT];
+            let mut out = [0; MESSAGE_LIMIT];
+            while let Ok((n, peer)) = sock.recv_from(&mut buf) {
+                let query = Message::decode(&buf[..n]).unwrap();
+                let mut response = Message::response(&query);
+                let tsig: Tsig = query.additional[0].read_rdata().unwrap();
+                if key.verify(&buf[..n], &[], tsig.time_signed).is_ok() {
+                    key.sign_response(&mut response, &tsig.mac, now).unwrap();
+                } else {
+                    response.set_rcode(RCode::NotAuth);
+                    let mut res =
+                        Resource::new(key.name().to_owned(), RecordType::Tsig, Class::Any, 0);
+                    res.write_rdata(&Tsig {
+                        algorithm: key.algorithm().name().to_owned(),
+                        time_signed: now,
+                        fudge: 300,
+                        mac: vec![],
+                        original_id: query.header.id,
+                        error: 16,
+                        other: vec![],
+                    })
+                    .unwrap();
+                    response.additional.push(res);
+                }
+                let data = response.encode(&mut out).unwrap();
+                sock.send_to(data, peer).unwrap();
+            }
+        });
+        addr
+    }
+
+    #[cfg(feature = "tsig")]
+    #[test]
+    fn test_tsig_key() {
+        use crate::resolve::socket::Error;
+        use crate::resolve::tsig::{Algorithm, FixedClock, TsigError, TsigKey};
+
+        const NOW: u64 = 1_700_000_000;
+        let key = || TsigKey::new("update-key.", Algorithm::HmacSha256, &[7; 32]);
+        let tsig_error = |server: SocketAddr, key: TsigKey| {
+            let mut resolver = resolver(&[server]);
+            resolver.set_tsig_key(key);
+            resolver.set_clock(FixedClock(NOW));
+            let mut buf = [0; MESSAGE_LIMIT];
+            match resolver.send_message(&query(), &mut buf) {
+                Err(Error::TsigError(e)) => Some(e),
+                Err(e) => panic!("unexpected error {}", e),
+                Ok(_) => None,
+            }
+        };
+
+        let mut resolver = resolver(&[signing_server(key(), NOW + 1)]);
+        resolver.config.randomize_case = true;
+        resolver.set_tsig_key(key());
+        resolver.set_clock(FixedClock(NOW));
+        let mut buf = [0; MESSAGE_LIMIT];
+        let msg = resolver.send_message(&query(), &mut buf).unwrap();
+        assert_eq!(msg.header.rcode, RCode::NoError);
+        assert_eq!(msg.additional[0].r_type, RecordType::Tsig);
+
+        // the clock of the server is off by more than the fudge
+        let server = signing_server(key(), NOW + 301);
+        assert_eq!(tsig_error(server, key()), Some(TsigError::BadTime));
+        // the server does not know the secret
+        let other = TsigKey::new("update-key.", Algorithm::HmacSha256, &[8; 32]);
+        let server = signing_server(other, NOW);
+        assert_eq!(tsig_error(server, key()), Some(TsigError::Rejected(16)));
+        // or the response is signed with another key
+        let other_name = TsigKey::new("other-key.", Algorithm::HmacSha256, &[7; 32]);
+        let server = signing_server(other_name, NOW);
+        assert_eq!(tsig_error(server, key()), Some(TsigError::BadKey));
+    }
+}
@@ -0,0 +1,99 @@
+//! Sources of the randomness of the resolver
+//!
+//! The transaction IDs, the case of the queried names (0x20), the jitter of
+//! the retry timeouts, and the keys of the client cookies come from a
+//! `RandSource`. `OsRandom` is the default, and a `SeededRandom` makes them
+//! reproducible in tests, see `DnsResolver::set_rand_source`.
+
+use std::fmt::Debug;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use rand::random;
+
+/// A source of random numbers
+pub trait RandSource: Debug + Send + Sync {
+    /// Returns 64 random bits.
+    fn next_u64(&self) -> u64;
+
+    /// Returns a random transaction ID.
+    fn id(&self) -> u16 {
+        self.next_u64() as u16
+    }
+
+    /// Returns `true` or `false` with the same probability.
+    fn coin(&self) -> bool {
+        self.next_u64() & 1 == 1
+    }
+
+    /// Returns a number between `low` and `high`, both included.
+    fn between(&self, low: u64, high: u64) -> u64 {
+        if high <= low {
+            return low;
+        }
+        match (high - low).checked_add(1) {
+            Some(range) => low + self.next_u64() % range,
+            None => self.next_u64(),
+        }
+    }
+}
+
+/// The thread-local generator of `rand`, which is seeded by the operating
+/// system
+#[derive(Clone, Copy, Debug, Default)]
+pub struct OsRandom;
+
+impl RandSource for OsRandom {
+    fn next_u64(&self) -> u64 {
+        random()
+    }
+}
+
+/// A generator with a fixed seed (SplitMix64), for tests which check the
+/// generated IDs and cases; it is predictable and must not be used otherwise
+#[derive(Debug)]
+pub struct SeededRandom {
+    state: AtomicU64,
+}
+
+impl SeededRandom {
+    /// Returns a generator which yields the same numbers for the same seed.
+    pub fn new(seed: u64) -> SeededRandom {
+        SeededRandom {
+            state: AtomicU64::new(seed),
+        }
+    }
+}
+
+impl RandSource for SeededRandom {
+    fn next_u64(&self) -> u64 {
+        const GAMMA: u64 = 0x9e37_79b9_7f4a_7c15;
+        let mut z = self
+            .state
+            .fetch_add(GAMMA, Ordering::Relaxed)
+            .wrapping_add(GAMMA);
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{RandSource, SeededRandom};
+
+    #[test]
+    fn test_seeded() {
+        // the first outputs of SplitMix64 for the seed 0
+        let rand = SeededRandom::new(0);
+        assert_eq!(rand.next_u64(), 0xe220_a839_7b1d_cdaf);
+        assert_eq!(rand.next_u64(), 0x6e78_9e6a_a1b9_65f4);
+        let (a, b) = (SeededRandom::new(7), SeededRandom::new(7));
+        for _ in 0..100 {
+            let x = a.between(10, 20);
+            assert_eq!(x, b.between(10, 20));
+            assert!(x >= 10 && x <= 20);
+        }
+        assert_eq!(a.between(5, 5), 5);
+        a.between(0, u64::max_value());
+    }
+}
@@ -1,16 +1,502 @@
 //! Low-level UDP socket operations
 
-use crate::apimultithread::{UdpSocket, UsnetToSocketAddrs};
+use crate::apimultithread::{UdpBindOptions, UdpSocket, UdpSocketBuilder, UsnetToSocketAddrs};
+use crate::apisinglethread::UdpSocket as SinglethreadUdpSocket;
+use crate::pktinfo::PktInfo;
+use libc;
 use std::fmt;
 use std::io;
-use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+#[cfg(target_os = "linux")]
+use std::mem;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr, UdpSocket as SystemUdpSocket};
+#[cfg(target_os = "linux")]
+use std::net::{Ipv4Addr, SocketAddrV4, SocketAddrV6};
+use std::os::unix::io::AsRawFd;
+#[cfg(target_os = "linux")]
+use std::ptr;
+use std::time::Duration;
 
 use super::address::socket_address_equal;
-use super::message::{DecodeError, DnsError, EncodeError, Message, MESSAGE_LIMIT};
+use super::message::{DecodeError, DnsError, EncodeError, Message, Qr, MESSAGE_LIMIT};
+use super::record::RecordType;
+#[cfg(feature = "tsig")]
+use super::tsig::TsigError;
 
 /// Represents a socket transmitting DNS messages.
-pub struct DnsSocket {
-    sock: UdpSocket,
+///
+/// The messages go through a `UdpSocket` of the multithread API unless the
+/// `DnsSocket` is created with `from_socket` on another `DatagramSocket`.
+pub struct DnsSocket<S = UdpSocket> {
+    sock: S,
+    hook: Option<PacketHook>,
+    edns_payload_size: Option<u16>,
+}
+
+/// Collects the options of a `DnsSocket` and binds sockets with them, so that
+/// many sockets can be configured alike.
+///
+/// The reuse flags, the buffer size, and the device are set before the socket
+/// is bound, the other options afterwards.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DnsSocketBuilder {
+    udp: UdpSocketBuilder,
+    read_timeout: Option<Duration>,
+    write_timeout: Option<Duration>,
+    nonblocking: bool,
+    ttl: Option<u32>,
+    hop_limit: Option<u32>,
+    dscp: Option<u8>,
+    edns_payload_size: Option<u16>,
+}
+
+impl DnsSocketBuilder {
+    /// Returns a builder with the defaults of `DnsSocket::bind`.
+    pub fn new() -> DnsSocketBuilder {
+        DnsSocketBuilder::default()
+    }
+
+    /// Sets `SO_REUSEADDR`, see `UdpBindOptions`.
+    pub fn reuse_address(mut self, reuse_address: bool) -> DnsSocketBuilder {
+        self.udp = self.udp.reuse_address(reuse_address);
+        self
+    }
+
+    /// Sets `SO_REUSEPORT`, see `UdpBindOptions`.
+    pub fn reuse_port(mut self, reuse_port: bool) -> DnsSocketBuilder {
+        self.udp = self.udp.reuse_port(reuse_port);
+        self
+    }
+
+    /// Sets the size of the receive and send buffers of the socket on the stack.
+    pub fn buffer_size(mut self, buffer_size: usize) -> DnsSocketBuilder {
+        self.udp = self.udp.buffer_size(buffer_size);
+        self
+    }
+
+    /// Sets `IPV6_V6ONLY`, a socket bound to `[::]` is dual-stack without it,
+    /// see `UdpBindOptions`.
+    pub fn only_v6(mut self, only_v6: bool) -> DnsSocketBuilder {
+        self.udp = self.udp.only_v6(only_v6);
+        self
+    }
+
+    /// Only sends and receives through the network interface, see
+    /// `UdpSocketBuilder::device`.
+    pub fn device(mut self, device: &str) -> DnsSocketBuilder {
+        self.udp = self.udp.device(device);
+        self
+    }
+
+    /// Sets the read timeout, `None` blocks indefinitely.
+    pub fn read_timeout(mut self, dur: Option<Duration>) -> DnsSocketBuilder {
+        self.read_timeout = dur;
+        self
+    }
+
+    /// Sets the write timeout, `None` blocks indefinitely.
+    pub fn write_timeout(mut self, dur: Option<Duration>) -> DnsSocketBuilder {
+        self.write_timeout = dur;
+        self
+    }
+
+    /// Puts the socket into nonblocking mode, see `DnsSocket::set_nonblocking`.
+    pub fn nonblocking(mut self, nonblocking: bool) -> DnsSocketBuilder {
+        self.nonblocking = nonblocking;
+        self
+    }
+
+    /// Sets the IPv4 time-to-live of outgoing packets.
+    pub fn ttl(mut self, ttl: u32) -> DnsSocketBuilder {
+        self.ttl = Some(ttl);
+        self
+    }
+
+    /// Sets the IPv6 hop limit of outgoing packets.
+    pub fn hop_limit(mut self, hop_limit: u32) -> DnsSocketBuilder {
+        self.hop_limit = Some(hop_limit);
+        self
+    }
+
+    /// Marks outgoing packets with the DSCP value, see `DnsSocket::set_dscp`.
+    pub fn dscp(mut self, dscp: u8) -> DnsSocketBuilder {
+        self.dscp = Some(dscp);
+        self
+    }
+
+    /// Advertises the UDP payload size in queries, see
+    /// `DnsSocket::set_edns_payload_size`.
+    pub fn edns_payload_size(mut self, size: u16) -> DnsSocketBuilder {
+        self.edns_payload_size = Some(size);
+        self
+    }
+
+    /// Returns the options which are set before the bind.
+    pub fn bind_options(&self) -> UdpBindOptions {
+        self.udp.bind_options()
+    }
+
+    /// Returns a `DnsSocket` bound to the given address with the options.
+    ///
+    /// Returns an `InvalidInput` error before binding if an option is out of
+    /// range.
+    pub fn build<A: UsnetToSocketAddrs>(&self, addr: A) -> io::Result<DnsSocket> {
+        self.validate()?;
+        let mut socket = DnsSocket {
+            sock: self.udp.bind(addr)?,
+            hook: None,
+            edns_payload_size: None,
+        };
+        socket.set_read_timeout(self.read_timeout)?;
+        socket.set_write_timeout(self.write_timeout)?;
+        socket.set_nonblocking(self.nonblocking)?;
+        if let Some(ttl) = self.ttl {
+            socket.set_ttl(ttl)?;
+        }
+        if let Some(hop_limit) = self.hop_limit {
+            socket.set_hop_limit(hop_limit)?;
+        }
+        if let Some(dscp) = self.dscp {
+            socket.set_dscp(dscp)?;
+        }
+        if let Some(size) = self.edns_payload_size {
+            socket.set_edns_payload_size(Some(size))?;
+        }
+        Ok(socket)
+    }
+
+    fn validate(&self) -> io::Result<()> {
+        let invalid = |msg| Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+        if self.read_timeout == Some(Duration::from_secs(0))
+            || self.write_timeout == Some(Duration::from_secs(0))
+        {
+            return invalid("zero timeout");
+        }
+        if self.udp.bind_options().buffer_size == Some(0) {
+            return invalid("zero-sized socket buffer");
+        }
+        if self.ttl.map_or(false, |ttl| ttl == 0 || ttl > 255) {
+            return invalid("TTL must be in the range 1..=255");
+        }
+        if self.hop_limit.map_or(false, |hops| hops > 255) {
+            return invalid("hop limit must be in the range 0..=255");
+        }
+        if self.dscp.map_or(false, |dscp| dscp > 63) {
+            return invalid("DSCP must be in the range 0..=63");
+        }
+        check_edns_payload_size(self.edns_payload_size)
+    }
+}
+
+/// Direction of a packet passed to a `PacketHook`
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum PacketDirection {
+    /// Packet sent to the peer
+    Outgoing,
+    /// Packet received from the peer
+    Incoming,
+}
+
+/// Callback invoked with the raw bytes of each sent or received packet
+/// and the address of the peer
+pub type PacketHook = Box<dyn Fn(&[u8], &SocketAddr, PacketDirection) + Send + Sync>;
+
+/// Datagram socket on which a `DnsSocket` transmits the messages, implemented
+/// by the `UdpSocket` types of the multithread and the singlethread API and by
+/// `std::net::UdpSocket`, e.g., to resolve names before the stack is usable.
+///
+/// The singlethread socket has no background thread: each call polls the stack
+/// of the current thread, a blocking receive until a datagram arrives or the
+/// read timeout passes, and a nonblocking one only once.
+pub trait DatagramSocket {
+    /// Sends the datagram to the address and returns the number of bytes sent.
+    fn send_datagram(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize>;
+
+    /// Receives a datagram and returns the received length, the full length
+    /// of the datagram, which is larger if the buffer was too small, and the
+    /// address of the sender.
+    fn recv_datagram(&self, buf: &mut [u8]) -> io::Result<(usize, usize, SocketAddr)>;
+
+    /// Sets the read timeout, `None` blocks indefinitely.
+    fn set_read_timeout(&self, dur: Option<Duration>) -> io::Result<()>;
+
+    /// Returns the read timeout.
+    fn read_timeout(&self) -> io::Result<Option<Duration>>;
+
+    /// Moves the socket into or out of nonblocking mode.
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()>;
+
+    /// Returns the local address of the socket.
+    fn local_addr(&self) -> io::Result<SocketAddr>;
+
+    /// Sends the datagrams, each to its address, and returns the number of
+    /// datagrams sent, at least one: an error is only returned for the first.
+    ///
+    /// The default sends them one after another with `send_datagram`, the
+    /// kernel socket with `sendmmsg` on Linux.
+    fn send_datagrams(&self, datagrams: &[(&[u8], SocketAddr)]) -> io::Result<usize> {
+        for (i, &(buf, addr)) in datagrams.iter().enumerate() {
+            if let Err(e) = self.send_datagram(buf, addr) {
+                if i == 0 {
+                    return Err(e);
+                }
+                return Ok(i);
+            }
+        }
+        Ok(datagrams.len())
+    }
+
+    /// Receives one datagram or more into the buffers, waiting for the first
+    /// like `recv_datagram`, and returns the lengths and the sender of each as
+    /// `recv_datagram` does.
+    ///
+    /// The default receives one datagram with `recv_datagram`, the kernel
+    /// socket all that are available with `recvmmsg` on Linux.
+    fn recv_datagrams(
+        &self,
+        bufs: &mut [&mut [u8]],
+    ) -> io::Result<Vec<(usize, usize, SocketAddr)>> {
+        match bufs.first_mut() {
+            Some(buf) => Ok(vec![self.recv_datagram(buf)?]),
+            None => Ok(Vec::new()),
+        }
+    }
+}
+
+impl DatagramSocket for UdpSocket {
+    fn send_datagram(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        self.send_to(buf, addr)
+    }
+
+    fn recv_datagram(&self, buf: &mut [u8]) -> io::Result<(usize, usize, SocketAddr)> {
+        self.recv_from_trunc(buf)
+    }
+
+    fn set_read_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
+        UdpSocket::set_read_timeout(self, dur)
+    }
+
+    fn read_timeout(&self) -> io::Result<Option<Duration>> {
+        UdpSocket::read_timeout(self)
+    }
+
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        UdpSocket::set_nonblocking(self, nonblocking)
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        UdpSocket::local_addr(self)
+    }
+}
+
+impl DatagramSocket for SinglethreadUdpSocket {
+    fn send_datagram(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        self.send_to(buf, addr)
+    }
+
+    fn recv_datagram(&self, buf: &mut [u8]) -> io::Result<(usize, usize, SocketAddr)> {
+        self.recv_from_trunc(buf)
+    }
+
+    fn set_read_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
+        SinglethreadUdpSocket::set_read_timeout(self, dur)
+    }
+
+    fn read_timeout(&self) -> io::Result<Option<Duration>> {
+        SinglethreadUdpSocket::read_timeout(self)
+    }
+
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        SinglethreadUdpSocket::set_nonblocking(self, nonblocking)
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        SinglethreadUdpSocket::local_addr(self)
+    }
+}
+
+impl DatagramSocket for SystemUdpSocket {
+    fn send_datagram(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        self.send_to(buf, addr)
+    }
+
+    /// Peeks with `MSG_TRUNC` for the full length before receiving the datagram,
+    /// the length is the received one where the flag is not supported.
+    fn recv_datagram(&self, buf: &mut [u8]) -> io::Result<(usize, usize, SocketAddr)> {
+        let len = unsafe {
+            libc::recv(
+                self.as_raw_fd(),
+                buf.as_mut_ptr() as *mut libc::c_void,
+                0,
+                libc::MSG_PEEK | libc::MSG_TRUNC,
+            )
+        };
+        if len < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let (n, addr) = self.recv_from(buf)?;
+        Ok((n, (len as usize).max(n), addr))
+    }
+
+    fn set_read_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
+        SystemUdpSocket::set_read_timeout(self, dur)
+    }
+
+    fn read_timeout(&self) -> io::Result<Option<Duration>> {
+        SystemUdpSocket::read_timeout(self)
+    }
+
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        SystemUdpSocket::set_nonblocking(self, nonblocking)
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        SystemUdpSocket::local_addr(self)
+    }
+
+    #[cfg(target_os = "linux")]
+    fn send_datagrams(&self, datagrams: &[(&[u8], SocketAddr)]) -> io::Result<usize> {
+        if datagrams.is_empty() {
+            return Ok(0);
+        }
+        let mut addrs: Vec<_> = datagrams
+            .iter()
+            .map(|&(_, ref addr)| to_sockaddr(addr))
+            .collect();
+        let mut iovecs: Vec<libc::iovec> = datagrams
+            .iter()
+            .map(|&(buf, _)| libc::iovec {
+                iov_base: buf.as_ptr() as *mut libc::c_void,
+                iov_len: buf.len(),
+            })
+            .collect();
+        let mut msgs: Vec<libc::mmsghdr> = addrs
+            .iter_mut()
+            .zip(iovecs.iter_mut())
+            .map(|(&mut (ref mut addr, len), iovec)| {
+                let mut msg: libc::mmsghdr = unsafe { mem::zeroed() };
+                msg.msg_hdr.msg_name = addr as *mut libc::sockaddr_storage as *mut libc::c_void;
+                msg.msg_hdr.msg_namelen = len;
+                msg.msg_hdr.msg_iov = iovec;
+                msg.msg_hdr.msg_iovlen = 1;
+                msg
+            })
+            .collect();
+        let n = unsafe {
+            libc::sendmmsg(
+                self.as_raw_fd(),
+                msgs.as_mut_ptr(),
+                msgs.len() as libc::c_uint,
+                0,
+            )
+        };
+        if n < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(n as usize)
+    }
+
+    /// Receives with `MSG_TRUNC` for the full lengths and `MSG_WAITFORONE`,
+    /// which only waits for the first datagram.
+    #[cfg(target_os = "linux")]
+    fn recv_datagrams(
+        &self,
+        bufs: &mut [&mut [u8]],
+    ) -> io::Result<Vec<(usize, usize, SocketAddr)>> {
+        if bufs.is_empty() {
+            return Ok(Vec::new());
+        }
+        let mut addrs: Vec<libc::sockaddr_storage> = vec![unsafe { mem::zeroed() }; bufs.len()];
+        let mut iovecs: Vec<libc::iovec> = bufs
+            .iter_mut()
+            .map(|buf| libc::iovec {
+                iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+                iov_len: buf.len(),
+            })
+            .collect();
+        let mut msgs: Vec<libc::mmsghdr> = addrs
+            .iter_mut()
+            .zip(iovecs.iter_mut())
+            .map(|(addr, iovec)| {
+                let mut msg: libc::mmsghdr = unsafe { mem::zeroed() };
+                msg.msg_hdr.msg_name = addr as *mut libc::sockaddr_storage as *mut libc::c_void;
+                msg.msg_hdr.msg_namelen =
+                    mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+                msg.msg_hdr.msg_iov = iovec;
+                msg.msg_hdr.msg_iovlen = 1;
+                msg
+            })
+            .collect();
+        let n = unsafe {
+            libc::recvmmsg(
+                self.as_raw_fd(),
+                msgs.as_mut_ptr(),
+                msgs.len() as libc::c_uint,
+                libc::MSG_WAITFORONE | libc::MSG_TRUNC,
+                ptr::null_mut(),
+            )
+        };
+        if n < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut received = Vec::with_capacity(n as usize);
+        for i in 0..n as usize {
+            let len = msgs[i].msg_len as usize;
+            received.push((len.min(bufs[i].len()), len, from_sockaddr(&addrs[i])?));
+        }
+        Ok(received)
+    }
+}
+
+/// Returns the socket address for the system calls and its length
+#[cfg(target_os = "linux")]
+fn to_sockaddr(addr: &SocketAddr) -> (libc::sockaddr_storage, libc::socklen_t) {
+    let mut storage: libc::sockaddr_storage = unsafe { mem::zeroed() };
+    let len = match *addr {
+        SocketAddr::V4(ref a) => {
+            let sin = unsafe { &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in) };
+            sin.sin_family = libc::AF_INET as libc::sa_family_t;
+            sin.sin_port = a.port().to_be();
+            sin.sin_addr.s_addr = u32::from(*a.ip()).to_be();
+            mem::size_of::<libc::sockaddr_in>()
+        }
+        SocketAddr::V6(ref a) => {
+            let sin6 = unsafe { &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in6) };
+            sin6.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+            sin6.sin6_port = a.port().to_be();
+            sin6.sin6_flowinfo = a.flowinfo();
+            sin6.sin6_addr.s6_addr = a.ip().octets();
+            sin6.sin6_scope_id = a.scope_id();
+            mem::size_of::<libc::sockaddr_in6>()
+        }
+    };
+    (storage, len as libc::socklen_t)
+}
+
+/// Returns the socket address which a system call stored
+#[cfg(target_os = "linux")]
+fn from_sockaddr(storage: &libc::sockaddr_storage) -> io::Result<SocketAddr> {
+    match storage.ss_family as libc::c_int {
+        libc::AF_INET => {
+            let sin = unsafe { &*(storage as *const _ as *const libc::sockaddr_in) };
+            Ok(SocketAddr::V4(SocketAddrV4::new(
+                Ipv4Addr::from(u32::from_be(sin.sin_addr.s_addr)),
+                u16::from_be(sin.sin_port),
+            )))
+        }
+        libc::AF_INET6 => {
+            let sin6 = unsafe { &*(storage as *const _ as *const libc::sockaddr_in6) };
+            Ok(SocketAddr::V6(SocketAddrV6::new(
+                Ipv6Addr::from(sin6.sin6_addr.s6_addr),
+                u16::from_be(sin6.sin6_port),
+                sin6.sin6_flowinfo,
+                sin6.sin6_scope_id,
+            )))
+        }
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "unknown address family of the sender",
+        )),
+    }
 }
 
 impl DnsSocket {
@@ -26,59 +512,513 @@ impl DnsSocket {
     pub fn bind<A: UsnetToSocketAddrs>(addr: A) -> io::Result<DnsSocket> {
         Ok(DnsSocket {
             sock: UdpSocket::bind(addr)?,
+            hook: None,
+            edns_payload_size: None,
         })
     }
 
-    /// Returns a reference to the wrapped `UdpSocket`.
-    pub fn get(&self) -> &UdpSocket {
+    /// Returns a `DnsSocket`, bound to the given address with the options
+    /// that are set before the bind.
+    pub fn bind_with<A: UsnetToSocketAddrs>(
+        addr: A,
+        options: UdpBindOptions,
+    ) -> io::Result<DnsSocket> {
+        Ok(DnsSocket {
+            sock: UdpSocket::bind_with(addr, options)?,
+            hook: None,
+            edns_payload_size: None,
+        })
+    }
+
+    /// Returns a `DnsSocket`, bound to the given address with
+    /// `SO_REUSEADDR` and `SO_REUSEPORT`, so that several responders, e.g.,
+    /// one per process, can listen on the same port.
+    ///
+    /// `SO_REUSEPORT` is only available on Linux 3.9 and newer and on the
+    /// BSDs, elsewhere an error of kind `Other` is returned. On Linux, the
+    /// sockets sharing the port must belong to the same user.
+    pub fn bind_reuse<A: UsnetToSocketAddrs>(addr: A) -> io::Result<DnsSocket> {
+        DnsSocket::bind_with(
+            addr,
+            UdpBindOptions {
+                reuse_address: true,
+                reuse_port: true,
+                buffer_size: None,
+                only_v6: false,
+            },
+        )
+    }
+
+    /// Returns a `DnsSocket`, bound to the given address on the named
+    /// network interface, so that queries egress through it regardless of
+    /// the default route and only responses arriving on it are received, see
+    /// `UdpSocketBuilder::device`.
+    ///
+    /// Returns an error if the device does not exist or is not used by the
+    /// network stack.
+    pub fn bind_device<A: UsnetToSocketAddrs>(addr: A, device: &str) -> io::Result<DnsSocket> {
+        Ok(DnsSocket {
+            sock: UdpSocket::bind_device(addr, device)?,
+            hook: None,
+            edns_payload_size: None,
+        })
+    }
+
+    /// Sets the write timeout of the socket, `None` blocks indefinitely.
+    ///
+    /// Returns an error if the duration is zero, as `std::net::UdpSocket` does.
+    pub fn set_write_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
+        self.sock.set_write_timeout(dur)
+    }
+
+    /// Returns the write timeout of the socket.
+    pub fn write_timeout(&self) -> io::Result<Option<Duration>> {
+        self.sock.write_timeout()
+    }
+
+    /// Sets the IPv4 time-to-live of outgoing packets.
+    /// This affects all subsequent sends on the socket.
+    pub fn set_ttl(&self, ttl: u32) -> io::Result<()> {
+        self.sock.set_ttl(ttl)
+    }
+
+    /// Returns the IPv4 time-to-live of outgoing packets.
+    pub fn ttl(&self) -> io::Result<u32> {
+        self.sock.ttl()
+    }
+
+    /// Sets the IPv6 hop limit of outgoing packets.
+    /// This affects all subsequent sends on the socket.
+    ///
+    /// Returns an error if the socket is not an IPv6 socket or the backend
+    /// does not support IPv6.
+    pub fn set_hop_limit(&self, hop_limit: u32) -> io::Result<()> {
+        self.sock.set_hop_limit(hop_limit)
+    }
+
+    /// Returns the IPv6 hop limit of outgoing packets.
+    pub fn hop_limit(&self) -> io::Result<u32> {
+        self.sock.hop_limit()
+    }
+
+    /// Marks outgoing packets with the DSCP value, e.g., 46 for expedited
+    /// forwarding, through `IP_TOS` or `IPV6_TCLASS`.
+    /// This affects all subsequent sends on the socket.
+    ///
+    /// Returns an `InvalidInput` error if the value does not fit the 6 DSCP
+    /// bits.
+    pub fn set_dscp(&self, dscp: u8) -> io::Result<()> {
+        if dscp > 63 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "DSCP must be in the range 0..=63",
+            ));
+        }
+        self.sock.set_tos(u32::from(dscp) << 2)
+    }
+
+    /// Returns the DSCP value of outgoing packets.
+    pub fn dscp(&self) -> io::Result<u8> {
+        self.sock.tos().map(|tos| (tos >> 2) as u8)
+    }
+
+    /// Sets the whole TOS byte (or IPv6 traffic class) of outgoing packets,
+    /// which is the DSCP value shifted left by 2.
+    ///
+    /// Returns an `InvalidInput` error if the value is out of range or sets
+    /// the two ECN bits, which belong to the transport.
+    pub fn set_tos(&self, tos: u32) -> io::Result<()> {
+        self.sock.set_tos(tos)
+    }
+
+    /// Returns the TOS byte of outgoing packets.
+    pub fn tos(&self) -> io::Result<u32> {
+        self.sock.tos()
+    }
+
+    /// Sends a message to the given address from the local address of the
+    /// `PktInfo`, e.g., a response from the address which `recv_msg` returned
+    /// for the query, see `UdpSocket::send_msg`.
+    pub fn send_msg<A: UsnetToSocketAddrs>(
+        &self,
+        message: &Message,
+        addr: A,
+        info: Option<PktInfo>,
+    ) -> Result<(), Error> {
+        let mut buf = [0; MESSAGE_LIMIT];
+        let len = self.encode_query(message, &mut buf)?;
+        let addr = self.outgoing(&buf[..len], addr)?;
+        let sent = self.sock.send_msg(&buf[..len], addr, info)?;
+        check_sent(sent, len)
+    }
+
+    /// Receives a message like `recv_from` and also returns the local address
+    /// which it was sent to, see `UdpSocket::recv_msg`.
+    ///
+    /// The buffer should be exactly `MESSAGE_LIMIT` bytes in length.
+    pub fn recv_msg<'buf>(
+        &self,
+        buf: &'buf mut [u8],
+    ) -> Result<(Message<'buf>, SocketAddr, PktInfo), Error> {
+        let (n, addr, info) = self.sock.recv_msg(buf)?;
+        if let Some(ref hook) = self.hook {
+            hook(&buf[..n], &addr, PacketDirection::Incoming);
+        }
+
+        let msg = Message::decode(&buf[..n])?;
+        Ok((msg, addr, info))
+    }
+
+    /// Receives a message and calls the function with it and the address of the sender.
+    /// The message is decoded in place from the receive buffer of the stack instead of
+    /// being copied into a caller buffer first.
+    ///
+    /// The function and the packet hook run while the stack is locked, see
+    /// `UdpSocket::recv_with`. They should only look at the message or convert it into an
+    /// `OwnedMessage` and must not send on any socket.
+    pub fn recv_with<F, R>(&self, f: F) -> Result<R, Error>
+    where
+        F: FnOnce(Message, SocketAddr) -> R,
+    {
+        let r = self.sock.recv_with(|data, addr| {
+            if let Some(ref hook) = self.hook {
+                hook(data, &addr, PacketDirection::Incoming);
+            }
+            Message::decode(data).map(|msg| f(msg, addr))
+        })?;
+        Ok(r?)
+    }
+}
+
+impl<S: DatagramSocket> DnsSocket<S> {
+    /// Returns a `DnsSocket` which transmits the messages on the given socket,
+    /// e.g., a `UdpSocket` of the singlethread API.
+    pub fn from_socket(sock: S) -> DnsSocket<S> {
+        DnsSocket {
+            sock: sock,
+            hook: None,
+            edns_payload_size: None,
+        }
+    }
+
+    /// Installs a hook which is called with the raw outgoing buffer before
+    /// it is sent and with the raw incoming buffer after it is received.
+    /// Replaces any previously installed hook.
+    pub fn set_packet_hook<F>(&mut self, hook: F)
+    where
+        F: Fn(&[u8], &SocketAddr, PacketDirection) + Send + Sync + 'static,
+    {
+        self.hook = Some(Box::new(hook));
+    }
+
+    /// Removes the packet hook, if any.
+    pub fn clear_packet_hook(&mut self) {
+        self.hook = None;
+    }
+
+    /// Moves the socket into or out of nonblocking mode.
+    ///
+    /// In nonblocking mode, `recv_from` and `recv_message` return an `IoError`
+    /// of kind `WouldBlock` if no packet is ready, which `Error::is_timeout`
+    /// reports as a timeout.
+    pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        self.sock.set_nonblocking(nonblocking)
+    }
+
+    /// Sets the read timeout of the socket, `None` blocks indefinitely.
+    ///
+    /// Returns an error if the duration is zero, as `std::net::UdpSocket` does.
+    pub fn set_read_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
+        self.sock.set_read_timeout(dur)
+    }
+
+    /// Returns the read timeout of the socket.
+    pub fn read_timeout(&self) -> io::Result<Option<Duration>> {
+        self.sock.read_timeout()
+    }
+
+    /// Advertises the UDP payload size with an EDNS OPT record (RFC 6891) in
+    /// the queries sent with `send_message` and `send_msg` which do not have
+    /// one yet, `None` sends the queries as they are.
+    ///
+    /// Returns an `InvalidInput` error if the size is below 512 bytes.
+    pub fn set_edns_payload_size(&mut self, size: Option<u16>) -> io::Result<()> {
+        check_edns_payload_size(size)?;
+        self.edns_payload_size = size;
+        Ok(())
+    }
+
+    /// Returns the UDP payload size which is advertised in queries.
+    pub fn edns_payload_size(&self) -> Option<u16> {
+        self.edns_payload_size
+    }
+
+    /// Returns a reference to the wrapped socket.
+    pub fn get(&self) -> &S {
         &self.sock
     }
 
     /// Sends a message to the given address.
+    ///
+    /// A response which is longer than the UDP payload size of its OPT record,
+    /// or 512 bytes without one, is sent without its additional records but
+    /// the OPT record, and if that is still too long, also without its answer
+    /// and authority records and with the TC bit, so that the client repeats
+    /// the query over TCP. Signed responses have to fit, since truncating them
+    /// drops the TSIG record.
     pub fn send_message<A: UsnetToSocketAddrs>(
         &self,
         message: &Message,
         addr: A,
     ) -> Result<(), Error> {
         let mut buf = [0; MESSAGE_LIMIT];
-        let data = message.encode(&mut buf)?;
-        self.sock.send_to(data, addr)?;
+        self.send_message_buf(message, addr, &mut buf)?;
         Ok(())
     }
 
+    /// Sends a message like `send_message`, encoding it into the given buffer,
+    /// which can be reused for the next message, and returns the number of
+    /// bytes sent.
+    ///
+    /// Returns `EncodeError::TooLong` if the encoded message does not fit into
+    /// the buffer.
+    pub fn send_message_buf<A: UsnetToSocketAddrs>(
+        &self,
+        message: &Message,
+        addr: A,
+        buf: &mut [u8],
+    ) -> Result<usize, Error> {
+        let len = self.encode_query(message, buf)?;
+        self.send_packet(&buf[..len], addr)?;
+        Ok(len)
+    }
+
+    /// Sends the bytes as they are to the given address, e.g., to replay a
+    /// captured query or to forward one without encoding it again.
+    ///
+    /// Returns `EncodeError::TooLong` if there are more than `MESSAGE_LIMIT` bytes.
+    pub fn send_raw<A: UsnetToSocketAddrs>(&self, bytes: &[u8], addr: A) -> Result<(), Error> {
+        if bytes.len() > MESSAGE_LIMIT {
+            return Err(Error::EncodeError(EncodeError::TooLong));
+        }
+        self.send_packet(bytes, addr)
+    }
+
+    /// Encodes the message and returns its length, with the OPT record of
+    /// `set_edns_payload_size` appended to a query which has none, and a
+    /// response truncated as `send_message` describes.
+    fn encode_query(&self, message: &Message, buf: &mut [u8]) -> Result<usize, Error> {
+        let mut len = message.encode(buf)?.len();
+        if message.header.qr == Qr::Response {
+            let limit = message.edns_udp_size().map_or(512, |size| size.max(512));
+            if len > limit as usize {
+                len = encode_truncated(message, buf, limit as usize)?;
+            }
+        } else if let Some(size) = self.edns_payload_size {
+            let has_opt = message
+                .additional
+                .iter()
+                .any(|r| r.r_type == RecordType::Opt || r.r_type == RecordType::Tsig);
+            if !has_opt {
+                len = append_opt(buf, len, size)?;
+            }
+        }
+        Ok(len)
+    }
+
+    /// Sends the messages, each to its address, with as few calls of the socket
+    /// as it allows (see `DatagramSocket::send_datagrams`) and returns the
+    /// number of messages sent, at least one.
+    pub(crate) fn send_messages(
+        &self,
+        messages: &[(&Message, SocketAddr)],
+    ) -> Result<usize, Error> {
+        let mut buf = [0; MESSAGE_LIMIT];
+        let mut packets = Vec::with_capacity(messages.len());
+        for &(message, addr) in messages {
+            let len = self.encode_query(message, &mut buf)?;
+            packets.push((buf[..len].to_vec(), addr));
+        }
+        let datagrams: Vec<(&[u8], SocketAddr)> = packets
+            .iter()
+            .map(|&(ref data, addr)| (&data[..], addr))
+            .collect();
+        let sent = self.sock.send_datagrams(&datagrams)?;
+        if let Some(ref hook) = self.hook {
+            for &(data, ref addr) in datagrams[..sent].iter() {
+                hook(data, addr, PacketDirection::Outgoing);
+            }
+        }
+        Ok(sent)
+    }
+
+    /// Receives the datagrams which are available into the buffers, waiting for
+    /// the first one (see `DatagramSocket::recv_datagrams`), and returns their
+    /// received and full lengths and their senders without decoding them.
+    pub(crate) fn recv_packets(
+        &self,
+        bufs: &mut [&mut [u8]],
+    ) -> io::Result<Vec<(usize, usize, SocketAddr)>> {
+        let received = self.sock.recv_datagrams(bufs)?;
+        if let Some(ref hook) = self.hook {
+            for (buf, &(n, _, ref addr)) in bufs.iter().zip(received.iter()) {
+                hook(&buf[..n], addr, PacketDirection::Incoming);
+            }
+        }
+        Ok(received)
+    }
+
+    fn send_packet<A: UsnetToSocketAddrs>(&self, data: &[u8], addr: A) -> Result<(), Error> {
+        let addr = self.outgoing(data, addr)?;
+        let sent = self.sock.send_datagram(data, addr)?;
+        check_sent(sent, data.len())
+    }
+
+    /// Returns the first address to send to, after passing the packet to the hook
+    fn outgoing<A: UsnetToSocketAddrs>(&self, data: &[u8], addr: A) -> io::Result<SocketAddr> {
+        let addr = addr
+            .usnet_to_socket_addrs()?
+            .next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no address to send to"))?;
+        if let Some(ref hook) = self.hook {
+            hook(data, &addr, PacketDirection::Outgoing);
+        }
+        Ok(addr)
+    }
+
     /// Receives a message, returning the address of the sender.
     /// The given buffer is used to store and parse message data.
     ///
-    /// The buffer should be exactly `MESSAGE_LIMIT` bytes in length.
+    /// The buffer should be exactly `MESSAGE_LIMIT` bytes in length. A longer
+    /// datagram is discarded with `Error::BufferTooSmall` instead of being
+    /// decoded in parts.
     pub fn recv_from<'buf>(
         &self,
         buf: &'buf mut [u8],
     ) -> Result<(Message<'buf>, SocketAddr), Error> {
-        let (n, addr) = self.sock.recv_from(buf)?;
+        let (n, len, addr) = self.recv_packet(buf)?;
+        check_truncated(n, len)?;
 
         let msg = Message::decode(&buf[..n])?;
         Ok((msg, addr))
     }
 
+    /// Receives a packet without decoding it, returning its bytes in the buffer
+    /// and the address of the sender.
+    ///
+    /// The buffer should be exactly `MESSAGE_LIMIT` bytes in length, longer
+    /// packets are discarded with `Error::BufferTooSmall`.
+    pub fn recv_raw<'buf>(&self, buf: &'buf mut [u8]) -> Result<(&'buf [u8], SocketAddr), Error> {
+        let (n, len, addr) = self.recv_packet(buf)?;
+        check_truncated(n, len)?;
+        Ok((&buf[..n], addr))
+    }
+
     /// Attempts to read a DNS message. The message will only be decoded if the
     /// remote address matches `addr`. If a packet is received from a non-matching
     /// address, the message is not decoded and `Ok(None)` is returned.
     ///
-    /// The buffer should be exactly `MESSAGE_LIMIT` bytes in length.
+    /// Each call receives at most one packet. In nonblocking mode, a packet from
+    /// a non-matching address consumes the available datagram and `Ok(None)` is
+    /// returned, the caller is expected to poll again.
+    ///
+    /// The buffer should be exactly `MESSAGE_LIMIT` bytes in length, a longer
+    /// message from `addr` is discarded with `Error::BufferTooSmall`.
     pub fn recv_message<'buf>(
         &self,
         addr: &SocketAddr,
         buf: &'buf mut [u8],
     ) -> Result<Option<Message<'buf>>, Error> {
-        let (n, recv_addr) = self.sock.recv_from(buf)?;
+        let (n, len, recv_addr) = self.recv_packet(buf)?;
 
         if !socket_address_equal(&recv_addr, addr) {
             Ok(None)
         } else {
+            check_truncated(n, len)?;
             let msg = Message::decode(&buf[..n])?;
             Ok(Some(msg))
         }
     }
+
+    /// Receives the next message from any sender, for callers which match the
+    /// responses to their queries themselves, e.g., with `demux::Outstanding`.
+    ///
+    /// Nothing is discarded as with `recv_message`, so the caller is
+    /// responsible for the defenses against spoofed responses: the sender, the
+    /// ID, and the question have to be those of an outstanding query.
+    pub fn recv_any<'buf>(
+        &self,
+        buf: &'buf mut [u8],
+    ) -> Result<(Message<'buf>, SocketAddr), Error> {
+        self.recv_from(buf)
+    }
+
+    /// Returns the received length, the full length of the datagram, and the sender
+    fn recv_packet(&self, buf: &mut [u8]) -> io::Result<(usize, usize, SocketAddr)> {
+        let (n, len, addr) = self.sock.recv_datagram(buf)?;
+        if let Some(ref hook) = self.hook {
+            hook(&buf[..n], &addr, PacketDirection::Incoming);
+        }
+        Ok((n, len, addr))
+    }
+}
+
+/// A datagram which was sent in part would arrive as a truncated message
+fn check_sent(sent: usize, len: usize) -> Result<(), Error> {
+    if sent != len {
+        return Err(Error::IoError(io::Error::new(
+            io::ErrorKind::WriteZero,
+            "datagram sent in part",
+        )));
+    }
+    Ok(())
+}
+
+fn check_truncated(n: usize, len: usize) -> Result<(), Error> {
+    if len > n {
+        return Err(Error::BufferTooSmall(len));
+    }
+    Ok(())
+}
+
+fn check_edns_payload_size(size: Option<u16>) -> io::Result<()> {
+    if size.map_or(false, |size| size < 512) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "EDNS payload size must be at least 512 bytes",
+        ));
+    }
+    Ok(())
+}
+
+/// Encodes a response without the records which do not fit into the limit,
+/// see `DnsSocket::send_message`.
+fn encode_truncated(message: &Message, buf: &mut [u8], limit: usize) -> Result<usize, Error> {
+    let mut short = message.clone();
+    short.additional.retain(|r| r.r_type == RecordType::Opt);
+    let len = short.encode(buf)?.len();
+    if len <= limit {
+        return Ok(len);
+    }
+    short.answer.clear();
+    short.authority.clear();
+    short.header.truncated = true;
+    Ok(short.encode(buf)?.len())
+}
+
+/// Appends an OPT record with the UDP payload size to an encoded message
+/// and returns the new length; a TSIG record has to stay last, so messages
+/// with one are not passed here.
+fn append_opt(buf: &mut [u8], len: usize, size: u16) -> Result<usize, EncodeError> {
+    let arcount = u16::from_be_bytes([buf[10], buf[11]]);
+    if len + 11 > buf.len() || arcount == u16::max_value() {
+        return Err(EncodeError::TooLong);
+    }
+    // root name, type OPT, the size as class, TTL 0, and no data
+    let opt = [0, 0, 41, (size >> 8) as u8, size as u8, 0, 0, 0, 0, 0, 0];
+    buf[len..len + 11].copy_from_slice(&opt);
+    buf[10..12].copy_from_slice(&(arcount + 1).to_be_bytes());
+    Ok(len + 11)
 }
 
 /// Represents an error in sending or receiving a DNS message.
@@ -92,10 +1032,20 @@ pub enum Error {
     DnsError(DnsError),
     /// Error generated by network operation
     IoError(io::Error),
+    /// Received datagram of the given size did not fit into the buffer and was
+    /// discarded, it can be received again with a larger buffer or over TCP
+    BufferTooSmall(usize),
+    /// The transaction signature of the response is invalid, or the server
+    /// rejected the one of the query, see `DnsResolver::set_tsig_key`
+    #[cfg(feature = "tsig")]
+    TsigError(TsigError),
 }
 
 impl Error {
     /// Returns `true` if the error is the result of an operation having timed out.
+    ///
+    /// A refused query or a server without a route is not a timeout but fails
+    /// early, see `is_unreachable`.
     pub fn is_timeout(&self) -> bool {
         match *self {
             Error::IoError(ref e) => {
@@ -105,6 +1055,30 @@ impl Error {
             _ => false,
         }
     }
+
+    /// Returns `true` if the server could not be reached, i.e., the connection
+    /// or datagram was refused or reset, or there is no route to the server's
+    /// host or network, so that another server may still answer.
+    pub fn is_unreachable(&self) -> bool {
+        match *self {
+            Error::IoError(ref e) => match e.kind() {
+                io::ErrorKind::ConnectionRefused | io::ErrorKind::ConnectionReset => true,
+                _ => e.raw_os_error().map_or(false, |code| {
+                    code == libc::EHOSTUNREACH || code == libc::ENETUNREACH
+                }),
+            },
+            _ => false,
+        }
+    }
+
+    /// Returns the error response of the server, if it was one, for comparisons
+    /// such as `err.dns_error() == Some(RCode::NameError.into())`.
+    pub fn dns_error(&self) -> Option<DnsError> {
+        match *self {
+            Error::DnsError(e) => Some(e),
+            _ => None,
+        }
+    }
 }
 
 impl fmt::Display for Error {
@@ -114,6 +1088,11 @@ impl fmt::Display for Error {
             Error::EncodeError(ref e) => write!(f, "error encoding message: {}", e),
             Error::DnsError(e) => write!(f, "server responded with error: {}", e),
             Error::IoError(ref e) => fmt::Display::fmt(e, f),
+            Error::BufferTooSmall(len) => {
+                write!(f, "received message of {} bytes exceeds the buffer", len)
+            }
+            #[cfg(feature = "tsig")]
+            Error::TsigError(e) => fmt::Display::fmt(&e, f),
         }
     }
 }
@@ -136,8 +1115,317 @@ impl From<DnsError> for Error {
     }
 }
 
+#[cfg(feature = "tsig")]
+impl From<TsigError> for Error {
+    fn from(err: TsigError) -> Error {
+        Error::TsigError(err)
+    }
+}
+
 impl From<io::Error> for Error {
     fn from(err: io::Error) -> Error {
         Error::IoError(err)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::{
+        append_opt, check_truncated, DatagramSocket, DnsSocket, DnsSocketBuilder, Error,
+        PacketDirection,
+    };
+    use crate::apimultithread::UdpBindOptions;
+    use crate::resolve::message::{Message, Qr, Question, MESSAGE_LIMIT};
+    use crate::resolve::record::{Class, RecordType, Txt, A};
+    use libc;
+    use std::io;
+    use std::net::{Ipv4Addr, SocketAddr, UdpSocket};
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    /// Socket which sends all but the last byte of each datagram
+    struct ShortWrites(UdpSocket);
+
+    impl DatagramSocket for ShortWrites {
+        fn send_datagram(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize> {
+            self.0.send_to(&buf[..buf.len() - 1], addr)
+        }
+
+        fn recv_datagram(&self, buf: &mut [u8]) -> io::Result<(usize, usize, SocketAddr)> {
+            self.0.recv_datagram(buf)
+        }
+
+        fn set_read_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
+            self.0.set_read_timeout(dur)
+        }
+
+        fn read_timeout(&self) -> io::Result<Option<Duration>> {
+            self.0.read_timeout()
+        }
+
+        fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+            self.0.set_nonblocking(nonblocking)
+        }
+
+        fn local_addr(&self) -> io::Result<SocketAddr> {
+            self.0.local_addr()
+        }
+    }
+
+    #[test]
+    fn test_builder() {
+        let builder = DnsSocketBuilder::new()
+            .reuse_port(true)
+            .buffer_size(1 << 20)
+            .read_timeout(Some(Duration::from_secs(2)))
+            .ttl(32)
+            .dscp(46)
+            .edns_payload_size(1232);
+        assert_eq!(
+            builder.bind_options(),
+            UdpBindOptions {
+                reuse_address: false,
+                reuse_port: true,
+                buffer_size: Some(1 << 20),
+                only_v6: false,
+            }
+        );
+        let other = builder.clone().write_timeout(Some(Duration::from_secs(1)));
+        assert_eq!(builder.clone(), builder);
+        assert!(other != builder);
+        assert!(builder.clone().device("eth0") != builder);
+        assert_eq!(
+            builder.clone().device("eth0").bind_options(),
+            builder.bind_options()
+        );
+
+        // invalid options are reported before the bind
+        for invalid in &[
+            builder.clone().dscp(64),
+            builder.clone().ttl(0),
+            builder.clone().read_timeout(Some(Duration::from_secs(0))),
+            builder.clone().edns_payload_size(511),
+            builder.clone().buffer_size(0),
+        ] {
+            let err = invalid.build("0.0.0.0:0").err().unwrap();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        }
+    }
+
+    #[test]
+    fn test_from_socket() {
+        let mut sock = DnsSocket::from_socket(UdpSocket::bind("127.0.0.1:0").unwrap());
+        sock.set_edns_payload_size(Some(1232)).unwrap();
+        sock.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let addr = sock.get().local_addr().unwrap();
+        sock.send_message(&Message::with_id(0x1234), addr).unwrap();
+        let mut buf = [0; MESSAGE_LIMIT];
+        let msg = sock.recv_message(&addr, &mut buf).unwrap().unwrap();
+        assert_eq!(msg.header.id, 0x1234);
+        assert_eq!(msg.additional[0].r_class, Class::Other(1232));
+
+        // the full length of a datagram which does not fit
+        sock.send_message(&Message::with_id(0x1234), addr).unwrap();
+        match sock.recv_from(&mut buf[..20]) {
+            Err(Error::BufferTooSmall(23)) => {}
+            r => panic!("unexpected {:?}", r.map(|_| ())),
+        }
+
+        sock.set_nonblocking(true).unwrap();
+        assert!(sock.recv_from(&mut buf).err().unwrap().is_timeout());
+    }
+
+    #[test]
+    fn test_packet_hook() {
+        let mut sock = DnsSocket::from_socket(UdpSocket::bind("127.0.0.1:0").unwrap());
+        sock.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let packets = Arc::new(Mutex::new(Vec::new()));
+        let seen = packets.clone();
+        sock.set_packet_hook(move |data, addr, direction| {
+            seen.lock().unwrap().push((data.to_vec(), *addr, direction));
+        });
+        let addr = sock.get().local_addr().unwrap();
+        let mut msg = Message::with_id(0x4321);
+        msg.question.push(Question::new(
+            "example.com".to_string(),
+            RecordType::A,
+            Class::Internet,
+        ));
+        sock.send_message(&msg, addr).unwrap();
+        let mut buf = [0; MESSAGE_LIMIT];
+        sock.recv_message(&addr, &mut buf).unwrap().unwrap();
+
+        let packets = packets.lock().unwrap();
+        assert_eq!(packets.len(), 2);
+        assert_eq!(packets[0].2, PacketDirection::Outgoing);
+        assert_eq!(packets[1].2, PacketDirection::Incoming);
+        for &(ref data, ref from, _) in packets.iter() {
+            assert_eq!(*from, addr);
+            let query = Message::decode(data).unwrap();
+            assert_eq!(query.header.id, 0x4321);
+            assert_eq!(query.header.qr, Qr::Query);
+            assert_eq!(query.question.len(), 1);
+            assert_eq!(query.question[0].name, "example.com.");
+            assert_eq!(query.question[0].q_type, RecordType::A);
+        }
+    }
+
+    #[test]
+    fn test_send_message_buf() {
+        let sock = DnsSocket::from_socket(UdpSocket::bind("127.0.0.1:0").unwrap());
+        let addr = sock.get().local_addr().unwrap();
+        let msg = Message::with_id(0x1234);
+        let mut out = [0; 512];
+        assert_eq!(sock.send_message_buf(&msg, addr, &mut out).unwrap(), 12);
+        match sock.send_message_buf(&msg, addr, &mut out[..8]) {
+            Err(Error::EncodeError(_)) => {}
+            r => panic!("unexpected {:?}", r),
+        }
+
+        let short = DnsSocket::from_socket(ShortWrites(UdpSocket::bind("127.0.0.1:0").unwrap()));
+        match short.send_message_buf(&msg, addr, &mut out) {
+            Err(Error::IoError(ref e)) if e.kind() == io::ErrorKind::WriteZero => {}
+            r => panic!("unexpected {:?}", r),
+        }
+        assert!(short.send_message(&msg, addr).is_err());
+    }
+
+    #[test]
+    fn test_truncated_response() {
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        receiver
+            .set_read_timeout(Some(Duration::from_secs(2)))
+            .unwrap();
+        let to = receiver.local_addr().unwrap();
+        let sock = DnsSocket::from_socket(UdpSocket::bind("127.0.0.1:0").unwrap());
+        let mut query = Message::with_id(0x1234);
+        query.question.push(Question::new(
+            "many.example.".to_owned(),
+            RecordType::A,
+            Class::Internet,
+        ));
+        let mut buf = [0; MESSAGE_LIMIT];
+        // 16 bytes per answer, 40 of them fit into 1232 bytes but not 512
+        for &(edns, truncated) in [(false, true), (true, false)].iter() {
+            if edns {
+                query.set_dnssec_ok(true);
+            }
+            let mut response = Message::response(&query);
+            for i in 0..40 {
+                let address = Ipv4Addr::new(192, 0, 2, i);
+                response
+                    .add_answer("many.example.", 60, &A { address: address })
+                    .unwrap();
+            }
+            response
+                .add_additional(
+                    "ns.example.",
+                    60,
+                    &A {
+                        address: Ipv4Addr::new(192, 0, 2, 53),
+                    },
+                )
+                .unwrap();
+            sock.send_message(&response, to).unwrap();
+            let n = receiver.recv(&mut buf).unwrap();
+            let received = Message::decode(&buf[..n]).unwrap();
+            assert_eq!(received.truncated(), truncated);
+            assert_eq!(received.answer.len(), if truncated { 0 } else { 40 });
+            assert_eq!(received.question, query.question);
+        }
+
+        // only the additional records are dropped if the answer fits
+        let mut response = Message::response(&query);
+        response.set_edns_option(10, &[0; 8]);
+        for _ in 0..8 {
+            let txt = Txt { data: vec![0; 200] };
+            response.add_additional("ns.example.", 60, &txt).unwrap();
+        }
+        sock.send_message(&response, to).unwrap();
+        let n = receiver.recv(&mut buf).unwrap();
+        let received = Message::decode(&buf[..n]).unwrap();
+        assert!(!received.truncated());
+        assert_eq!(received.additional.len(), 1);
+        assert_eq!(received.edns_option(10), Some(&[0; 8][..]));
+    }
+
+    #[test]
+    fn test_datagram_batches() {
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        receiver
+            .set_read_timeout(Some(Duration::from_secs(2)))
+            .unwrap();
+        let to = receiver.local_addr().unwrap();
+        // the default batches of a socket which cuts the last byte off, and
+        // those of the kernel socket
+        let sockets: Vec<(Box<dyn DatagramSocket>, usize)> = vec![
+            (
+                Box::new(ShortWrites(UdpSocket::bind("127.0.0.1:0").unwrap())),
+                1,
+            ),
+            (Box::new(UdpSocket::bind("127.0.0.1:0").unwrap()), 0),
+        ];
+        for &(ref sock, cut) in sockets.iter() {
+            let datagrams: Vec<(&[u8], SocketAddr)> =
+                vec![(b"first!", to), (b"second!", to), (b"third, long!", to)];
+            assert_eq!(sock.send_datagrams(&datagrams).unwrap(), 3);
+            let mut bufs = vec![[0; 8]; 4];
+            let mut received = Vec::new();
+            while received.len() < 3 {
+                let mut slices: Vec<&mut [u8]> = bufs.iter_mut().map(|buf| &mut buf[..]).collect();
+                let batch = receiver.recv_datagrams(&mut slices).unwrap();
+                for (buf, &(n, len, addr)) in slices.iter().zip(batch.iter()) {
+                    assert_eq!(addr, sock.local_addr().unwrap());
+                    received.push((buf[..n].to_vec(), len));
+                }
+            }
+            assert_eq!(received[0].0, &b"first!"[..6 - cut]);
+            assert_eq!(received[1].0, &b"second!"[..7 - cut]);
+            // the full length of a datagram too long for the buffer
+            assert_eq!(received[2], (b"third, l".to_vec(), 12 - cut));
+        }
+    }
+
+    #[test]
+    fn test_append_opt() {
+        let msg = Message::with_id(0x1234);
+        let mut buf = [0; MESSAGE_LIMIT];
+        let len = msg.encode(&mut buf).unwrap().len();
+        let len = append_opt(&mut buf, len, 1232).unwrap();
+        let msg = Message::decode(&buf[..len]).unwrap();
+        assert_eq!(msg.additional.len(), 1);
+        assert_eq!(msg.additional[0].name, ".");
+        assert_eq!(msg.additional[0].r_type, RecordType::Opt);
+        assert_eq!(msg.additional[0].r_class, Class::Other(1232));
+        assert_eq!(msg.additional[0].ttl, 0);
+
+        let mut buf = [0; 20];
+        buf[..12].copy_from_slice(&[0; 12]);
+        assert!(append_opt(&mut buf, 12, 1232).is_err());
+    }
+
+    #[test]
+    fn test_check_truncated() {
+        assert!(check_truncated(512, 512).is_ok());
+        match check_truncated(512, 1400) {
+            Err(Error::BufferTooSmall(1400)) => {}
+            r => panic!("unexpected {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_error_kinds() {
+        let timed_out = Error::IoError(io::Error::new(io::ErrorKind::TimedOut, "timed out"));
+        assert!(timed_out.is_timeout());
+        assert!(!timed_out.is_unreachable());
+        let refused = Error::IoError(io::Error::from_raw_os_error(libc::ECONNREFUSED));
+        assert!(!refused.is_timeout());
+        assert!(refused.is_unreachable());
+        for &code in &[libc::EHOSTUNREACH, libc::ENETUNREACH] {
+            let unreachable = Error::IoError(io::Error::from_raw_os_error(code));
+            assert!(!unreachable.is_timeout());
+            assert!(unreachable.is_unreachable());
+        }
+        assert!(!Error::BufferTooSmall(1400).is_unreachable());
+    }
+}
@@ -0,0 +1,435 @@
+//! DNS over TCP (RFC 7766) and over TLS (DoT, RFC 7858)
+//!
+//! Both transports carry each message with a 2-byte length prefix. The
+//! resolver keeps the connection to a name server open after a response, so
+//! that the next query does not wait for the TCP and TLS handshakes, and
+//! closes it after `IDLE_TIMEOUT` without queries. A server may close an idle
+//! connection earlier, then the query is sent once more on a new connection.
+//!
+//! DoT needs the `dot` build feature, which pulls in `rustls`. The certificate
+//! of the server is validated for the authentication name of its `TlsAuth`
+//! against the Mozilla root certificates of `webpki-roots`, or, with SPKI
+//! pins, only matched against them (RFC 7858 section 4.2).
+
+use std::collections::BTreeMap;
+use std::io::{self, Read, Write};
+use std::net::SocketAddr;
+#[cfg(feature = "dot")]
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+#[cfg(feature = "dot")]
+use ring::digest;
+#[cfg(feature = "dot")]
+use rustls::{self, Session};
+#[cfg(feature = "dot")]
+use webpki;
+#[cfg(feature = "dot")]
+use webpki_roots;
+
+use crate::apimultithread::TcpStream;
+
+use super::config::Transport;
+use super::message::Message;
+use super::socket::Error;
+
+/// TCP port of DoT servers
+pub const DOT_PORT: u16 = 853;
+
+/// ALPN protocol of DoT
+pub const DOT_ALPN: &[u8] = b"dot";
+
+/// Duration after which an unused connection to a name server is closed
+pub const IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Maximum length of a query sent over UDP, a longer one is sent over TCP
+/// (RFC 1035 section 4.2.1)
+pub const UDP_QUERY_LIMIT: usize = 512;
+
+/// How the certificate of a DoT server is authenticated
+#[cfg(feature = "dot")]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TlsAuth {
+    /// Authentication domain name of the server, which is sent in the SNI
+    /// extension and which the certificate has to be valid for
+    pub name: String,
+    /// SHA-256 digests of the SubjectPublicKeyInfo of the server's key; if
+    /// there are any, the certificate is accepted if its key matches one of
+    /// them, without validating its chain and name
+    pub spki_pins: Vec<[u8; 32]>,
+}
+
+#[cfg(feature = "dot")]
+impl TlsAuth {
+    /// Authenticates the server by a certificate for the name.
+    pub fn name(name: &str) -> TlsAuth {
+        TlsAuth {
+            name: name.to_owned(),
+            spki_pins: Vec::new(),
+        }
+    }
+
+    /// Authenticates the server by the SHA-256 digest of its key, sending the
+    /// name in the SNI extension.
+    pub fn spki_pin(name: &str, pin: [u8; 32]) -> TlsAuth {
+        TlsAuth {
+            name: name.to_owned(),
+            spki_pins: vec![pin],
+        }
+    }
+}
+
+/// Returns the client configuration for DoT connections, which trusts the
+/// Mozilla root certificates of `webpki-roots` or only the SPKI pins of the
+/// authentication, and offers the `dot` ALPN protocol.
+///
+/// Connections that share the configuration share the session cache, so that
+/// a reconnect resumes the TLS session.
+#[cfg(feature = "dot")]
+pub fn client_config(auth: &TlsAuth) -> rustls::ClientConfig {
+    let mut config = rustls::ClientConfig::new();
+    config.alpn_protocols = vec![DOT_ALPN.to_vec()];
+    config
+        .root_store
+        .add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS);
+    if !auth.spki_pins.is_empty() {
+        config
+            .dangerous()
+            .set_certificate_verifier(Arc::new(SpkiPins(auth.spki_pins.clone())));
+    }
+    config
+}
+
+/// Accepts a certificate whose key matches one of the SPKI pins
+#[cfg(feature = "dot")]
+struct SpkiPins(Vec<[u8; 32]>);
+
+#[cfg(feature = "dot")]
+impl rustls::ServerCertVerifier for SpkiPins {
+    fn verify_server_cert(
+        &self,
+        _roots: &rustls::RootCertStore,
+        presented_certs: &[rustls::Certificate],
+        _dns_name: webpki::DNSNameRef,
+        _ocsp_response: &[u8],
+    ) -> Result<rustls::ServerCertVerified, rustls::TLSError> {
+        let cert = presented_certs
+            .first()
+            .ok_or(rustls::TLSError::NoCertificatesPresented)?;
+        let spki = subject_public_key_info(&cert.0)
+            .ok_or(rustls::TLSError::WebPKIError(webpki::Error::BadDER))?;
+        let pin = digest::digest(&digest::SHA256, spki);
+        if self.0.iter().any(|p| &p[..] == pin.as_ref()) {
+            Ok(rustls::ServerCertVerified::assertion())
+        } else {
+            Err(rustls::TLSError::General(
+                "no SPKI pin matches the certificate".to_owned(),
+            ))
+        }
+    }
+}
+
+/// Returns the tag, the encoding, and the contents of the first DER element of
+/// the data, and the data after it.
+#[cfg_attr(not(feature = "dot"), allow(dead_code))]
+fn der_element(data: &[u8]) -> Option<(u8, &[u8], &[u8], &[u8])> {
+    let tag = *data.get(0)?;
+    let first = *data.get(1)? as usize;
+    let (len, header) = if first < 0x80 {
+        (first, 2)
+    } else {
+        let n = first & 0x7f;
+        if n == 0 || n > 4 || data.len() < 2 + n {
+            return None;
+        }
+        let len = data[2..2 + n]
+            .iter()
+            .fold(0, |len, &b| len << 8 | b as usize);
+        (len, 2 + n)
+    };
+    if data.len() - header < len {
+        return None;
+    }
+    let (element, rest) = data.split_at(header + len);
+    Some((tag, element, &element[header..], rest))
+}
+
+/// Returns the DER encoding of the SubjectPublicKeyInfo of the certificate,
+/// which SPKI pins are digests of.
+#[cfg_attr(not(feature = "dot"), allow(dead_code))]
+fn subject_public_key_info(cert: &[u8]) -> Option<&[u8]> {
+    let (_, _, cert, _) = der_element(cert)?;
+    let (_, _, mut tbs, _) = der_element(cert)?;
+    // the optional version, the serial number, the signature algorithm, the
+    // issuer, the validity, and the subject come first
+    if tbs.first() == Some(&0xa0) {
+        tbs = der_element(tbs)?.3;
+    }
+    for _ in 0..5 {
+        tbs = der_element(tbs)?.3;
+    }
+    match der_element(tbs)? {
+        (0x30, spki, _, _) => Some(spki),
+        _ => None,
+    }
+}
+
+/// Writes the message with its length prefix in one piece, so that it does
+/// not go out in two segments.
+pub(crate) fn write_message<W: Write>(w: &mut W, msg: &[u8]) -> io::Result<()> {
+    if msg.len() > u16::max_value() as usize {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "message too long for the length prefix",
+        ));
+    }
+    let mut data = Vec::with_capacity(2 + msg.len());
+    data.extend_from_slice(&(msg.len() as u16).to_be_bytes());
+    data.extend_from_slice(msg);
+    w.write_all(&data)?;
+    w.flush()
+}
+
+/// Reads a message with its length prefix into the buffer and returns its
+/// length.
+pub(crate) fn read_message<R: Read>(r: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut prefix = [0; 2];
+    r.read_exact(&mut prefix)?;
+    let len = u16::from_be_bytes(prefix) as usize;
+    if len > buf.len() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "message too long for the buffer",
+        ));
+    }
+    r.read_exact(&mut buf[..len])?;
+    Ok(len)
+}
+
+/// A connection to a name server
+enum Connection {
+    Tcp(TcpStream),
+    #[cfg(feature = "dot")]
+    Tls(Box<rustls::StreamOwned<rustls::ClientSession, TcpStream>>),
+}
+
+impl Connection {
+    fn stream(&self) -> &TcpStream {
+        match *self {
+            Connection::Tcp(ref stream) => stream,
+            #[cfg(feature = "dot")]
+            Connection::Tls(ref tls) => &tls.sock,
+        }
+    }
+
+    /// Sends the query and returns the length of the response with its ID,
+    /// skipping late responses to earlier queries.
+    fn exchange(
+        &mut self,
+        query: &[u8],
+        id: u16,
+        timeout: Duration,
+        buf: &mut [u8],
+    ) -> io::Result<usize> {
+        self.stream().set_read_timeout(Some(timeout))?;
+        self.stream().set_write_timeout(Some(timeout))?;
+        write_message(self, query)?;
+        loop {
+            let n = read_message(self, buf)?;
+            if n >= 2 && u16::from_be_bytes([buf[0], buf[1]]) == id {
+                return Ok(n);
+            }
+        }
+    }
+}
+
+impl Read for Connection {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match *self {
+            Connection::Tcp(ref mut stream) => stream.read(buf),
+            #[cfg(feature = "dot")]
+            Connection::Tls(ref mut tls) => tls.read(buf),
+        }
+    }
+}
+
+impl Write for Connection {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match *self {
+            Connection::Tcp(ref mut stream) => stream.write(buf),
+            #[cfg(feature = "dot")]
+            Connection::Tls(ref mut tls) => tls.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match *self {
+            Connection::Tcp(ref mut stream) => stream.flush(),
+            #[cfg(feature = "dot")]
+            Connection::Tls(ref mut tls) => tls.flush(),
+        }
+    }
+}
+
+/// The open connections of a resolver to the name servers which are queried
+/// over TCP or TLS, at most one idle connection to each
+#[derive(Default)]
+pub(crate) struct Connections {
+    idle: Mutex<BTreeMap<SocketAddr, (Connection, Instant)>>,
+    /// TLS configurations with the session caches of the servers
+    #[cfg(feature = "dot")]
+    tls_configs: Mutex<BTreeMap<SocketAddr, (TlsAuth, Arc<rustls::ClientConfig>)>>,
+}
+
+impl Connections {
+    /// Sends the query over a connection to the server and returns the length
+    /// of the response in the buffer. A new connection is established within
+    /// the handshake timeout, and the response has to arrive within the
+    /// timeout.
+    pub fn exchange(
+        &self,
+        server: &SocketAddr,
+        transport: &Transport,
+        query: &Message,
+        timeout: Duration,
+        handshake_timeout: Duration,
+        buf: &mut [u8],
+    ) -> Result<usize, Error> {
+        let data = query.encode_vec()?;
+        let id = query.header.id;
+        let (mut conn, reused) = match self.take(server) {
+            Some(conn) => (conn, true),
+            None => (self.connect(server, transport, handshake_timeout)?, false),
+        };
+        let n = match conn.exchange(&data, id, timeout, buf) {
+            // the server may have closed the idle connection
+            Err(ref e) if reused && !is_timeout(e) => {
+                conn = self.connect(server, transport, handshake_timeout)?;
+                conn.exchange(&data, id, timeout, buf)?
+            }
+            result => result?,
+        };
+        self.idle
+            .lock()
+            .unwrap()
+            .insert(*server, (conn, Instant::now()));
+        Ok(n)
+    }
+
+    /// Returns the idle connection to the server if it is not too old.
+    fn take(&self, server: &SocketAddr) -> Option<Connection> {
+        let now = Instant::now();
+        let mut idle = self.idle.lock().unwrap();
+        idle.retain(|_, &mut (_, used)| now.duration_since(used) < IDLE_TIMEOUT);
+        idle.remove(server).map(|(conn, _)| conn)
+    }
+
+    fn connect(
+        &self,
+        server: &SocketAddr,
+        transport: &Transport,
+        handshake_timeout: Duration,
+    ) -> io::Result<Connection> {
+        let deadline = Instant::now() + handshake_timeout;
+        let stream = TcpStream::connect_timeout(server, handshake_timeout)?;
+        stream.set_nodelay(true)?;
+        match *transport {
+            Transport::Tcp => Ok(Connection::Tcp(stream)),
+            #[cfg(feature = "dot")]
+            Transport::Tls(ref auth) => {
+                let name = webpki::DNSNameRef::try_from_ascii_str(auth.name.trim_end_matches('.'))
+                    .map_err(|_| {
+                        io::Error::new(io::ErrorKind::InvalidInput, "invalid authentication name")
+                    })?;
+                let session = rustls::ClientSession::new(&self.tls_config(server, auth), name);
+                let mut tls = rustls::StreamOwned::new(session, stream);
+                // the handshake is completed before the query is sent,
+                // within the handshake timeout
+                while tls.sess.is_handshaking() {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return Err(io::Error::new(
+                            io::ErrorKind::TimedOut,
+                            "timed out in the TLS handshake",
+                        ));
+                    }
+                    tls.sock.set_read_timeout(Some(deadline - now))?;
+                    tls.sock.set_write_timeout(Some(deadline - now))?;
+                    tls.sess.complete_io(&mut tls.sock)?;
+                }
+                Ok(Connection::Tls(Box::new(tls)))
+            }
+            Transport::Udp => {
+                let _ = deadline;
+                Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "no connection for UDP",
+                ))
+            }
+        }
+    }
+
+    #[cfg(feature = "dot")]
+    fn tls_config(&self, server: &SocketAddr, auth: &TlsAuth) -> Arc<rustls::ClientConfig> {
+        let mut configs = self.tls_configs.lock().unwrap();
+        if let Some(&(ref cached, ref config)) = configs.get(server) {
+            if cached == auth {
+                return config.clone();
+            }
+        }
+        let config = Arc::new(client_config(auth));
+        configs.insert(*server, (auth.clone(), config.clone()));
+        config
+    }
+}
+
+fn is_timeout(e: &io::Error) -> bool {
+    e.kind() == io::ErrorKind::TimedOut || e.kind() == io::ErrorKind::WouldBlock
+}
+
+#[cfg(test)]
+mod test {
+    use super::{read_message, subject_public_key_info, write_message};
+    use std::io::{self, Cursor};
+
+    #[test]
+    fn test_framing() {
+        let mut data = Vec::new();
+        write_message(&mut data, b"\x12\x34query").unwrap();
+        write_message(&mut data, b"").unwrap();
+        assert_eq!(data, b"\x00\x07\x12\x34query\x00\x00");
+
+        let mut r = Cursor::new(data);
+        let mut buf = [0; 16];
+        assert_eq!(read_message(&mut r, &mut buf).unwrap(), 7);
+        assert_eq!(&buf[..7], b"\x12\x34query");
+        assert_eq!(read_message(&mut r, &mut buf).unwrap(), 0);
+        // the connection closed before the next message
+        let err = read_message(&mut r, &mut buf).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+
+        let mut r = Cursor::new(b"\x00\x20short".to_vec());
+        let err = read_message(&mut r, &mut buf).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(write_message(&mut Vec::new(), &[0; 0x10000]).is_err());
+    }
+
+    #[test]
+    fn test_spki() {
+        let spki = b"\x30\x0a\x30\x05\x06\x03\x2b\x65\x70\x03\x01\x00";
+        let mut tbs = b"\xa0\x03\x02\x01\x02\x02\x01\x01".to_vec();
+        for _ in 0..4 {
+            tbs.extend_from_slice(b"\x30\x00");
+        }
+        tbs.extend_from_slice(spki);
+        tbs.extend_from_slice(b"\xa3\x00");
+        // the TBSCertificate with a long-form length
+        let mut cert = vec![0x30, 0x81, tbs.len() as u8 + 2, 0x30, tbs.len() as u8];
+        cert.extend_from_slice(&tbs);
+        cert[2] = (cert.len() - 3) as u8;
+        assert_eq!(subject_public_key_info(&cert), Some(&spki[..]));
+        assert_eq!(subject_public_key_info(&cert[..cert.len() - 4]), None);
+        assert_eq!(subject_public_key_info(b"\x30\x84\xff\xff\xff\xff"), None);
+    }
+}
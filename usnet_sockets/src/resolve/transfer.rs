@@ -0,0 +1,892 @@
+//! Implements zone transfers, incremental ones (IXFR, RFC 1995) over UDP and
+//! TCP, and whole zones (AXFR, RFC 5936) over TCP
+//!
+//! Over TCP, the response comes in several messages. `ZoneTransfer` reads them
+//! one at a time and yields their records, so that a large zone is not held in
+//! memory as a whole.
+
+use std::io::{self, Read, Write};
+use std::net::SocketAddr;
+#[cfg(feature = "tsig")]
+use std::sync::Arc;
+use std::time::Duration;
+use std::vec::IntoIter;
+
+use crate::apimultithread::TcpStream;
+
+use super::config::DnsConfig;
+#[cfg(feature = "tsig")]
+use super::message::RCode;
+use super::message::{DecodeError, EncodeError, Message, OwnedRecord, Qr, Question, Resource};
+use super::name::is_same_name;
+#[cfg(feature = "tsig")]
+use super::record::Tsig;
+use super::record::{Class, RecordData, RecordType, Soa};
+use super::resolver::DnsResolver;
+use super::socket::Error;
+use super::stream::{read_message, write_message};
+#[cfg(feature = "tsig")]
+use super::tsig::{Clock, TsigError, TsigKey};
+
+/// Record type of an incremental zone transfer query
+pub const IXFR: RecordType = RecordType::Ixfr;
+
+/// Maximum number of unsigned messages between two signed ones of a transfer
+/// signed with TSIG (RFC 8945 section 5.3.1)
+pub const UNSIGNED_LIMIT: usize = 99;
+
+/// Represents the changes of a zone since a serial, as sent by the primary
+/// name server in response to an IXFR query.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum ZoneDelta {
+    /// The zone did not change since the serial, or the given serial is newer
+    /// than the server's; holds the SOA record of the server's version.
+    UpToDate(Soa),
+    /// The sequence of changes from the given serial to the server's version.
+    Incremental(Vec<ZoneChange>),
+    /// The server sent the whole zone instead of the changes (AXFR fallback).
+    Full {
+        /// SOA record of the server's version
+        soa: Soa,
+        /// The other records of the zone
+        records: Vec<OwnedRecord>,
+    },
+    /// Only the SOA record of the newer version fit into the UDP response, the
+    /// changes have to be requested over TCP, see `DnsResolver::ixfr_tcp`.
+    TooLarge(Soa),
+}
+
+/// Represents one version step of an incremental zone transfer.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ZoneChange {
+    /// SOA record of the version the change applies to
+    pub from: Soa,
+    /// SOA record of the version after the change
+    pub to: Soa,
+    /// Records removed from the zone
+    pub removed: Vec<OwnedRecord>,
+    /// Records added to the zone
+    pub added: Vec<OwnedRecord>,
+}
+
+impl ZoneDelta {
+    /// Returns the SOA record of the server's version of the zone.
+    pub fn soa(&self) -> Option<&Soa> {
+        match *self {
+            ZoneDelta::UpToDate(ref soa) | ZoneDelta::TooLarge(ref soa) => Some(soa),
+            ZoneDelta::Full { ref soa, .. } => Some(soa),
+            ZoneDelta::Incremental(ref changes) => changes.last().map(|c| &c.to),
+        }
+    }
+
+    /// Parses the answer records of an IXFR response to a query for changes
+    /// since `from_serial`.
+    ///
+    /// The records begin and end with the SOA record of the server's version.
+    /// In between are the difference sequences, each the SOA record of the old
+    /// version, the removed records, the SOA record of the new version, and the
+    /// added records, or, if the server falls back to AXFR, the records of the
+    /// whole zone. A single SOA record means that there is nothing to transfer,
+    /// or that the changes did not fit into the response if its serial is newer.
+    pub fn from_records(
+        from_serial: u32,
+        records: &[OwnedRecord],
+    ) -> Result<ZoneDelta, DecodeError> {
+        let (first, rest) = records.split_first().ok_or(DecodeError::InvalidMessage)?;
+        let current = soa(first).ok_or(DecodeError::InvalidMessage)?;
+        let (last, body) = match rest.split_last() {
+            Some(split) => split,
+            None if serial_gt(current.serial, from_serial) => {
+                return Ok(ZoneDelta::TooLarge(current.clone()))
+            }
+            None => return Ok(ZoneDelta::UpToDate(current.clone())),
+        };
+        if soa(last).map_or(true, |last| last.serial != current.serial) {
+            return Err(DecodeError::InvalidMessage);
+        }
+
+        if body.first().and_then(soa).is_none() {
+            if body.iter().any(|rr| soa(rr).is_some()) {
+                return Err(DecodeError::InvalidMessage);
+            }
+            return Ok(ZoneDelta::Full {
+                soa: current.clone(),
+                records: body.to_vec(),
+            });
+        }
+
+        let mut changes = Vec::new();
+        let mut serial = from_serial;
+        let mut rest = body;
+        while let Some((first, tail)) = rest.split_first() {
+            let from = soa(first).ok_or(DecodeError::InvalidMessage)?;
+            if from.serial != serial {
+                return Err(DecodeError::InvalidMessage);
+            }
+            let (removed, tail) = until_soa(tail);
+            let (next, tail) = tail.split_first().ok_or(DecodeError::InvalidMessage)?;
+            let to = soa(next).ok_or(DecodeError::InvalidMessage)?;
+            let (added, tail) = until_soa(tail);
+            changes.push(ZoneChange {
+                from: from.clone(),
+                to: to.clone(),
+                removed: removed.to_vec(),
+                added: added.to_vec(),
+            });
+            serial = to.serial;
+            rest = tail;
+        }
+        if serial != current.serial {
+            return Err(DecodeError::InvalidMessage);
+        }
+        Ok(ZoneDelta::Incremental(changes))
+    }
+
+    /// Parses the response to an IXFR query for changes since `from_serial`.
+    ///
+    /// A truncated response which starts with the SOA record of a newer version
+    /// is `TooLarge`, as sent by servers whose changes do not fit.
+    pub fn from_response(from_serial: u32, msg: &Message) -> Result<ZoneDelta, DecodeError> {
+        let records: Vec<OwnedRecord> = msg.answer.iter().map(OwnedRecord::from).collect();
+        if msg.header.truncated {
+            if let Some(current) = records.first().and_then(soa) {
+                if serial_gt(current.serial, from_serial) {
+                    return Ok(ZoneDelta::TooLarge(current.clone()));
+                }
+            }
+        }
+        ZoneDelta::from_records(from_serial, &records)
+    }
+
+    /// Collects the records of an IXFR transfer over TCP for changes since
+    /// `from_serial`, which the server may answer with the whole zone.
+    pub fn from_transfer<S: Read + Write>(
+        from_serial: u32,
+        mut transfer: ZoneTransfer<S>,
+    ) -> Result<ZoneDelta, Error> {
+        let mut records = Vec::new();
+        for rr in transfer.by_ref() {
+            records.push(rr?);
+        }
+        // the closing SOA record is not yielded
+        if transfer.closed {
+            records.push(records[0].clone());
+        }
+        Ok(ZoneDelta::from_records(from_serial, &records)?)
+    }
+}
+
+/// Constructs an IXFR query for the changes of the zone since `from_serial`.
+///
+/// The authority section holds an SOA record with the serial, its other fields
+/// are not used by the server.
+pub fn ixfr_query(zone: &str, from_serial: u32) -> Result<Message<'static>, EncodeError> {
+    let mut msg = Message::new();
+    msg.question
+        .push(Question::new(zone.to_owned(), IXFR, Class::Internet));
+    let mut res = Resource::new(zone.to_owned(), RecordType::Soa, Class::Internet, 0);
+    res.write_rdata(&Soa {
+        mname: ".".to_owned(),
+        rname: ".".to_owned(),
+        serial: from_serial,
+        refresh: 0,
+        retry: 0,
+        expire: 0,
+        minimum: 0,
+    })?;
+    msg.authority.push(res);
+    Ok(msg)
+}
+
+/// Requests the changes of the zone since `from_serial` from the server, see
+/// `DnsResolver::ixfr`.
+pub fn ixfr(zone: &str, server: SocketAddr, from_serial: u32) -> Result<ZoneDelta, Error> {
+    let r = DnsResolver::new(DnsConfig::with_name_servers(vec![server]))?;
+    r.ixfr(zone, from_serial)
+}
+
+/// Constructs an AXFR query for the whole zone.
+pub fn axfr_query(zone: &str) -> Message<'static> {
+    let mut msg = Message::new();
+    msg.question.push(Question::new(
+        zone.to_owned(),
+        RecordType::Axfr,
+        Class::Internet,
+    ));
+    msg
+}
+
+/// Transfers the whole zone from the server over TCP, see `DnsResolver::axfr`.
+pub fn axfr(zone: &str, server: SocketAddr) -> Result<ZoneTransfer, Error> {
+    let r = DnsResolver::new(DnsConfig::with_name_servers(vec![server]))?;
+    r.axfr(zone)
+}
+
+/// Connects to the server for a zone transfer, the connection and each read
+/// time out after the timeout.
+pub(crate) fn connect(server: &SocketAddr, timeout: Duration) -> Result<TcpStream, Error> {
+    let stream = TcpStream::connect_timeout(server, timeout)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+    Ok(stream)
+}
+
+/// Yields the records of the response to an AXFR or IXFR query over a TCP
+/// connection, reading one message at a time.
+///
+/// The SOA record of the zone comes first. The transfer ends with the copy of
+/// it which closes the response, which is not yielded, and an error ends it
+/// early, e.g., an `UnexpectedEof` I/O error if the server closes the
+/// connection before, or the `DnsError` of a refused transfer.
+pub struct ZoneTransfer<S = TcpStream> {
+    stream: S,
+    id: u16,
+    zone: String,
+    q_type: RecordType,
+    /// Serial in the authority section of an IXFR query
+    from_serial: Option<u32>,
+    /// One message of the response, up to the limit of the length prefix
+    buf: Vec<u8>,
+    /// Records of the message which are not yielded yet
+    records: IntoIter<OwnedRecord>,
+    /// Serial of the first SOA record
+    serial: Option<u32>,
+    /// Number of records which were yielded
+    yielded: usize,
+    /// Number of SOA records of an incremental transfer after the first one
+    soas: Option<usize>,
+    /// Whether the closing SOA record was read
+    closed: bool,
+    done: bool,
+    #[cfg(feature = "tsig")]
+    tsig: Option<Signed>,
+}
+
+impl<S: Read + Write> ZoneTransfer<S> {
+    /// Sends the AXFR or IXFR query on the stream and returns the transfer of
+    /// the response.
+    pub fn new(mut stream: S, query: &Message) -> Result<ZoneTransfer<S>, Error> {
+        let question = query.question.first().ok_or_else(|| {
+            Error::IoError(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "no question in the query",
+            ))
+        })?;
+        write_message(&mut stream, &query.encode_vec()?)?;
+        Ok(ZoneTransfer {
+            stream: stream,
+            id: query.header.id,
+            zone: question.name.clone(),
+            q_type: question.q_type,
+            from_serial: query
+                .authority
+                .first()
+                .and_then(|res| res.read_rdata::<Soa>().ok())
+                .map(|soa| soa.serial),
+            buf: vec![0; u16::max_value() as usize],
+            records: Vec::new().into_iter(),
+            serial: None,
+            yielded: 0,
+            soas: None,
+            closed: false,
+            done: false,
+            #[cfg(feature = "tsig")]
+            tsig: None,
+        })
+    }
+
+    /// Signs the query with the key and verifies the signatures of the
+    /// response at the time of the clock. Signed messages may be followed by
+    /// up to `UNSIGNED_LIMIT` unsigned ones, and the last one must be signed.
+    #[cfg(feature = "tsig")]
+    pub fn signed(
+        stream: S,
+        query: &Message,
+        key: TsigKey,
+        clock: Arc<dyn Clock>,
+    ) -> Result<ZoneTransfer<S>, Error> {
+        let mut query = query.clone();
+        let mac = key.sign(&mut query, clock.unix_time())?;
+        let mut transfer = ZoneTransfer::new(stream, &query)?;
+        transfer.tsig = Some(Signed {
+            key: key,
+            clock: clock,
+            mac: mac,
+            first: true,
+            unsigned: Vec::new(),
+            unsigned_count: 0,
+        });
+        Ok(transfer)
+    }
+
+    /// Reads the next message of the response.
+    fn read(&mut self) -> Result<(), Error> {
+        let n = read_message(&mut self.stream, &mut self.buf)?;
+        let data = &self.buf[..n];
+        let msg = Message::decode(data)?;
+        if msg.header.id != self.id || msg.header.qr != Qr::Response {
+            return Err(Error::DecodeError(DecodeError::InvalidMessage));
+        }
+        #[cfg(feature = "tsig")]
+        {
+            if let Some(ref mut signed) = self.tsig {
+                signed.verify(data, &msg)?;
+            }
+        }
+        msg.get_error()?;
+        let records: Vec<OwnedRecord> = msg.answer.iter().map(OwnedRecord::from).collect();
+        if records.is_empty() && self.serial.is_none() {
+            return Err(Error::DecodeError(DecodeError::InvalidMessage));
+        }
+        self.records = records.into_iter();
+        Ok(())
+    }
+
+    /// Whether the record closes the response, at the second SOA record of
+    /// an AXFR, or at the SOA record of the current version in place of the
+    /// old version of a difference sequence of an IXFR.
+    fn closes(&mut self, rr: &OwnedRecord) -> Result<bool, Error> {
+        let serial = match (self.serial, soa(rr)) {
+            (None, Some(soa)) if is_same_name(&rr.name, &self.zone) => {
+                self.serial = Some(soa.serial);
+                return Ok(false);
+            }
+            (None, _) => return Err(Error::DecodeError(DecodeError::InvalidMessage)),
+            (Some(serial), _) => serial,
+        };
+        if self.q_type == RecordType::Ixfr && self.yielded == 1 && soa(rr).is_some() {
+            self.soas = Some(0);
+        }
+        match (self.soas, soa(rr)) {
+            (None, Some(_)) => Ok(true),
+            (Some(n), Some(soa)) => {
+                self.soas = Some(n + 1);
+                Ok(n % 2 == 0 && soa.serial == serial)
+            }
+            (_, None) => Ok(false),
+        }
+    }
+
+    /// Whether the transfer is complete after the records read so far: the
+    /// response to an IXFR of an up-to-date zone is its SOA record only, one
+    /// with a newer serial is followed by more in the next messages.
+    fn is_up_to_date(&self) -> bool {
+        match (self.serial, self.from_serial) {
+            (Some(serial), Some(from_serial)) => {
+                self.q_type == RecordType::Ixfr
+                    && self.yielded == 1
+                    && !serial_gt(serial, from_serial)
+            }
+            _ => false,
+        }
+    }
+
+    /// Checks that the message with the closing SOA record is signed if the
+    /// transfer is.
+    fn finish(&self) -> Result<(), Error> {
+        #[cfg(feature = "tsig")]
+        {
+            if let Some(ref signed) = self.tsig {
+                if signed.unsigned_count > 0 {
+                    return Err(Error::TsigError(TsigError::Unsigned));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<S: Read + Write> Iterator for ZoneTransfer<S> {
+    type Item = Result<OwnedRecord, Error>;
+
+    fn next(&mut self) -> Option<Result<OwnedRecord, Error>> {
+        loop {
+            if self.done {
+                return None;
+            }
+            if let Some(rr) = self.records.next() {
+                match self.closes(&rr) {
+                    Ok(false) => {
+                        self.yielded += 1;
+                        return Some(Ok(rr));
+                    }
+                    Ok(true) => {
+                        self.done = true;
+                        self.closed = true;
+                        return self.finish().err().map(Err);
+                    }
+                    Err(e) => {
+                        self.done = true;
+                        return Some(Err(e));
+                    }
+                }
+            }
+            if self.is_up_to_date() {
+                self.done = true;
+                return None;
+            }
+            if let Err(e) = self.read() {
+                self.done = true;
+                return Some(Err(e));
+            }
+        }
+    }
+}
+
+/// The state of verifying the messages of a transfer signed with TSIG
+#[cfg(feature = "tsig")]
+struct Signed {
+    key: TsigKey,
+    clock: Arc<dyn Clock>,
+    /// MAC of the query, then of the last signed message
+    mac: Vec<u8>,
+    /// Whether no message was verified yet
+    first: bool,
+    /// Messages after the last signed one, which the next MAC covers
+    unsigned: Vec<u8>,
+    unsigned_count: usize,
+}
+
+#[cfg(feature = "tsig")]
+impl Signed {
+    fn verify(&mut self, data: &[u8], msg: &Message) -> Result<(), TsigError> {
+        let tsig = match msg.additional.last() {
+            Some(res) if res.r_type == RecordType::Tsig => res,
+            // errors such as REFUSED may come unsigned
+            _ if self.first && msg.header.rcode != RCode::NoError => return Ok(()),
+            _ if self.first || self.unsigned_count == UNSIGNED_LIMIT => {
+                return Err(TsigError::Unsigned)
+            }
+            _ => {
+                self.unsigned.extend_from_slice(data);
+                self.unsigned_count += 1;
+                return Ok(());
+            }
+        };
+        let now = self.clock.unix_time();
+        if self.first {
+            self.key.verify(data, &self.mac, now)?;
+        } else {
+            self.key
+                .verify_continued(data, &self.mac, &self.unsigned, now)?;
+        }
+        let tsig: Tsig = tsig.read_rdata()?;
+        self.mac = tsig.mac;
+        self.first = false;
+        self.unsigned.clear();
+        self.unsigned_count = 0;
+        Ok(())
+    }
+}
+
+fn soa(rr: &OwnedRecord) -> Option<&Soa> {
+    match rr.data {
+        RecordData::Soa(ref soa) => Some(soa),
+        _ => None,
+    }
+}
+
+/// Splits the records before the next SOA record from the rest
+fn until_soa(records: &[OwnedRecord]) -> (&[OwnedRecord], &[OwnedRecord]) {
+    let end = records
+        .iter()
+        .position(|rr| soa(rr).is_some())
+        .unwrap_or(records.len());
+    records.split_at(end)
+}
+
+/// Whether a > b in the serial number arithmetic of RFC 1982
+fn serial_gt(a: u32, b: u32) -> bool {
+    a != b && (a.wrapping_sub(b) as i32) > 0
+}
+
+#[cfg(test)]
+mod test {
+    use super::{axfr_query, ixfr_query, serial_gt, ZoneChange, ZoneDelta, ZoneTransfer, IXFR};
+    use crate::resolve::message::{DecodeError, Message, OwnedRecord, Qr, RCode, MESSAGE_LIMIT};
+    use crate::resolve::record::{Class, RecordData, RecordType, Soa, A};
+    use crate::resolve::socket::Error;
+    use crate::resolve::stream::{read_message, write_message};
+    use std::io::{self, Cursor, Read, Write};
+    use std::net::Ipv4Addr;
+
+    /// Reads the response in pieces of at most `chunk` bytes and keeps the
+    /// written query
+    struct Stream {
+        response: Cursor<Vec<u8>>,
+        chunk: usize,
+        written: Vec<u8>,
+    }
+
+    impl Read for Stream {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let n = buf.len().min(self.chunk);
+            self.response.read(&mut buf[..n])
+        }
+    }
+
+    impl Write for Stream {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.written.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn stream(messages: &[Vec<u8>], chunk: usize) -> Stream {
+        let mut response = Vec::new();
+        for data in messages.iter() {
+            write_message(&mut response, data).unwrap();
+        }
+        Stream {
+            response: Cursor::new(response),
+            chunk: chunk,
+            written: Vec::new(),
+        }
+    }
+
+    /// Returns a response to the query with the records
+    fn message(query: &Message, records: &[OwnedRecord]) -> Message<'static> {
+        let mut msg = Message::response(query);
+        msg.answer = records.iter().map(|rr| rr.to_resource().unwrap()).collect();
+        msg
+    }
+
+    /// Returns the responses to the query, each with a slice of the records
+    fn messages(query: &Message, records: &[OwnedRecord], per_message: usize) -> Vec<Vec<u8>> {
+        records
+            .chunks(per_message)
+            .map(|records| message(query, records).encode_vec().unwrap())
+            .collect()
+    }
+
+    fn soa(serial: u32) -> Soa {
+        Soa {
+            mname: "ns.example.".to_owned(),
+            rname: "hostmaster.example.".to_owned(),
+            serial: serial,
+            refresh: 3600,
+            retry: 600,
+            expire: 86400,
+            minimum: 300,
+        }
+    }
+
+    fn soa_rr(serial: u32) -> OwnedRecord {
+        OwnedRecord::new(
+            "example.".to_owned(),
+            Class::Internet,
+            3600,
+            RecordData::Soa(soa(serial)),
+        )
+    }
+
+    fn a_rr(name: &str, last: u8) -> OwnedRecord {
+        OwnedRecord::new(
+            name.to_owned(),
+            Class::Internet,
+            300,
+            RecordData::A(A {
+                address: Ipv4Addr::new(192, 0, 2, last),
+            }),
+        )
+    }
+
+    #[test]
+    fn test_incremental() {
+        // RFC 1995 section 7: serial 1 to 2 to 3
+        let records = vec![
+            soa_rr(3),
+            soa_rr(1),
+            a_rr("nezu.example.", 1),
+            soa_rr(2),
+            a_rr("jain-bb.example.", 4),
+            a_rr("jain-bb.example.", 5),
+            soa_rr(2),
+            a_rr("jain-bb.example.", 4),
+            soa_rr(3),
+            a_rr("jain-bb.example.", 6),
+            soa_rr(3),
+        ];
+        let delta = ZoneDelta::from_records(1, &records).unwrap();
+        assert_eq!(
+            delta,
+            ZoneDelta::Incremental(vec![
+                ZoneChange {
+                    from: soa(1),
+                    to: soa(2),
+                    removed: vec![a_rr("nezu.example.", 1)],
+                    added: vec![a_rr("jain-bb.example.", 4), a_rr("jain-bb.example.", 5)],
+                },
+                ZoneChange {
+                    from: soa(2),
+                    to: soa(3),
+                    removed: vec![a_rr("jain-bb.example.", 4)],
+                    added: vec![a_rr("jain-bb.example.", 6)],
+                },
+            ])
+        );
+        assert_eq!(delta.soa(), Some(&soa(3)));
+    }
+
+    #[test]
+    fn test_axfr_fallback() {
+        let records = vec![
+            soa_rr(3),
+            a_rr("nezu.example.", 1),
+            a_rr("jain-bb.example.", 6),
+            soa_rr(3),
+        ];
+        assert_eq!(
+            ZoneDelta::from_records(1, &records).unwrap(),
+            ZoneDelta::Full {
+                soa: soa(3),
+                records: vec![a_rr("nezu.example.", 1), a_rr("jain-bb.example.", 6)],
+            }
+        );
+        // a zone which only has the SOA record
+        assert_eq!(
+            ZoneDelta::from_records(1, &[soa_rr(3), soa_rr(3)]).unwrap(),
+            ZoneDelta::Full {
+                soa: soa(3),
+                records: vec![],
+            }
+        );
+    }
+
+    #[test]
+    fn test_single_soa() {
+        assert_eq!(
+            ZoneDelta::from_records(3, &[soa_rr(3)]).unwrap(),
+            ZoneDelta::UpToDate(soa(3))
+        );
+        assert_eq!(
+            ZoneDelta::from_records(4, &[soa_rr(3)]).unwrap(),
+            ZoneDelta::UpToDate(soa(3))
+        );
+        assert_eq!(
+            ZoneDelta::from_records(1, &[soa_rr(3)]).unwrap(),
+            ZoneDelta::TooLarge(soa(3))
+        );
+        // the serial wraps around
+        assert_eq!(
+            ZoneDelta::from_records(0xffff_fffe, &[soa_rr(3)]).unwrap(),
+            ZoneDelta::TooLarge(soa(3))
+        );
+        assert!(serial_gt(1, 0xffff_ffff));
+        assert!(!serial_gt(0xffff_ffff, 1));
+    }
+
+    #[test]
+    fn test_invalid_sequences() {
+        let invalid = |from_serial, records: Vec<OwnedRecord>| {
+            ZoneDelta::from_records(from_serial, &records) == Err(DecodeError::InvalidMessage)
+        };
+        assert!(invalid(1, vec![]));
+        assert!(invalid(1, vec![a_rr("nezu.example.", 1)]));
+        // the stream does not end with the current SOA record
+        assert!(invalid(1, vec![soa_rr(3), a_rr("nezu.example.", 1)]));
+        assert!(invalid(1, vec![soa_rr(3), soa_rr(1), soa_rr(2)]));
+        // a difference sequence without the new version
+        assert!(invalid(
+            1,
+            vec![soa_rr(3), soa_rr(1), a_rr("nezu.example.", 1), soa_rr(3)]
+        ));
+        // the changes do not start at the requested serial
+        assert!(invalid(2, vec![soa_rr(3), soa_rr(1), soa_rr(3), soa_rr(3)]));
+        // or do not lead to the current version
+        assert!(invalid(1, vec![soa_rr(3), soa_rr(1), soa_rr(2), soa_rr(3)]));
+        // an SOA record within the full zone
+        assert!(invalid(
+            1,
+            vec![soa_rr(3), a_rr("nezu.example.", 1), soa_rr(2), soa_rr(3)]
+        ));
+    }
+
+    #[test]
+    fn test_response() {
+        let query = ixfr_query("example.", 1).unwrap();
+        assert_eq!(query.question[0].q_type, IXFR);
+        let serial = query.authority[0].read_rdata::<Soa>().unwrap().serial;
+        assert_eq!(serial, 1);
+
+        let mut response = Message::with_id(query.header.id);
+        response.header.qr = Qr::Response;
+        response.header.truncated = true;
+        response.question = query.question.clone();
+        response.answer.push(soa_rr(3).to_resource().unwrap());
+        response.answer.push(soa_rr(1).to_resource().unwrap());
+        let mut buf = [0; MESSAGE_LIMIT];
+        let data = response.encode(&mut buf).unwrap();
+        let msg = Message::decode(data).unwrap();
+        assert_eq!(
+            ZoneDelta::from_response(1, &msg).unwrap(),
+            ZoneDelta::TooLarge(soa(3))
+        );
+        assert_eq!(msg.answer[0].r_type, RecordType::Soa);
+    }
+
+    #[test]
+    fn test_axfr() {
+        let query = axfr_query("example.");
+        let records = vec![
+            soa_rr(3),
+            a_rr("nezu.example.", 1),
+            a_rr("jain-bb.example.", 4),
+            a_rr("jain-bb.example.", 5),
+            a_rr("jain-bb.example.", 6),
+            soa_rr(3),
+        ];
+        let responses = messages(&query, &records, 2);
+        // messages split across reads, and several in one read
+        for &chunk in [1, 3, 0x10000].iter() {
+            let transfer = ZoneTransfer::new(stream(&responses, chunk), &query).unwrap();
+            let received = transfer.collect::<Result<Vec<_>, _>>().unwrap();
+            assert_eq!(received, &records[..5]);
+        }
+
+        let mut transfer = ZoneTransfer::new(stream(&responses, 7), &query).unwrap();
+        assert_eq!(transfer.next().unwrap().unwrap(), soa_rr(3));
+        let mut buf = [0; 512];
+        let n = read_message(&mut Cursor::new(&transfer.stream.written), &mut buf).unwrap();
+        let sent = Message::decode(&buf[..n]).unwrap();
+        assert_eq!(sent.question[0].q_type, RecordType::Axfr);
+        assert_eq!(sent.question[0].name, "example.");
+
+        // the connection closes before the closing SOA record
+        let transfer = ZoneTransfer::new(stream(&responses[..2], 7), &query).unwrap();
+        let received = transfer.collect::<Vec<_>>();
+        assert_eq!(received.len(), 5);
+        match received[4] {
+            Err(Error::IoError(ref e)) => assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof),
+            _ => panic!("transfer did not fail"),
+        }
+
+        let mut refused = Message::response(&query);
+        refused.set_rcode(RCode::NotAuth);
+        let data = refused.encode_vec().unwrap();
+        let mut transfer = ZoneTransfer::new(stream(&[data], 7), &query).unwrap();
+        let err = transfer.next().unwrap().unwrap_err();
+        assert_eq!(err.dns_error(), Some(RCode::NotAuth.into()));
+        assert!(transfer.next().is_none());
+
+        // a response to another query, and one which does not start with the SOA record
+        let other = messages(&axfr_query("example."), &records, 6);
+        let mut transfer = ZoneTransfer::new(stream(&other, 7), &query).unwrap();
+        assert!(transfer.next().unwrap().is_err());
+        let without_soa = messages(&query, &records[1..], 6);
+        let mut transfer = ZoneTransfer::new(stream(&without_soa, 7), &query).unwrap();
+        assert!(transfer.next().unwrap().is_err());
+    }
+
+    #[test]
+    fn test_ixfr_transfer() {
+        let query = ixfr_query("example.", 1).unwrap();
+        let records = vec![
+            soa_rr(3),
+            soa_rr(1),
+            a_rr("nezu.example.", 1),
+            soa_rr(2),
+            a_rr("jain-bb.example.", 4),
+            soa_rr(2),
+            soa_rr(3),
+            a_rr("jain-bb.example.", 6),
+            soa_rr(3),
+        ];
+        let incremental = ZoneDelta::from_records(1, &records).unwrap();
+        for &per_message in [1, 4, 9].iter() {
+            let responses = messages(&query, &records, per_message);
+            let transfer = ZoneTransfer::new(stream(&responses, 5), &query).unwrap();
+            assert_eq!(ZoneDelta::from_transfer(1, transfer).unwrap(), incremental);
+        }
+
+        // the server falls back to the whole zone
+        let full = [soa_rr(3), a_rr("jain-bb.example.", 6), soa_rr(3)];
+        let transfer = ZoneTransfer::new(stream(&messages(&query, &full, 2), 5), &query).unwrap();
+        assert_eq!(
+            ZoneDelta::from_transfer(1, transfer).unwrap(),
+            ZoneDelta::Full {
+                soa: soa(3),
+                records: vec![a_rr("jain-bb.example.", 6)],
+            }
+        );
+        let only_soa = messages(&query, &[soa_rr(3), soa_rr(3)], 2);
+        let transfer = ZoneTransfer::new(stream(&only_soa, 5), &query).unwrap();
+        assert_eq!(
+            ZoneDelta::from_transfer(1, transfer).unwrap(),
+            ZoneDelta::Full {
+                soa: soa(3),
+                records: vec![],
+            }
+        );
+
+        let query = ixfr_query("example.", 3).unwrap();
+        let up_to_date = messages(&query, &[soa_rr(3)], 1);
+        let transfer = ZoneTransfer::new(stream(&up_to_date, 5), &query).unwrap();
+        assert_eq!(
+            ZoneDelta::from_transfer(3, transfer).unwrap(),
+            ZoneDelta::UpToDate(soa(3))
+        );
+    }
+
+    #[cfg(feature = "tsig")]
+    #[test]
+    fn test_signed_transfer() {
+        use crate::resolve::tsig::{Algorithm, FixedClock, TsigError, TsigKey};
+        use std::sync::Arc;
+
+        const NOW: u64 = 1_700_000_000;
+        let key = TsigKey::new("transfer-key.", Algorithm::HmacSha256, &[5; 32]);
+        let query = axfr_query("example.");
+        let request_mac = key.sign(&mut query.clone(), NOW).unwrap();
+        let transfer = |responses: &[Vec<u8>]| -> Vec<Result<OwnedRecord, Error>> {
+            let clock = Arc::new(FixedClock(NOW));
+            let stream = stream(responses, 0x10000);
+            ZoneTransfer::signed(stream, &query, key.clone(), clock)
+                .unwrap()
+                .collect()
+        };
+        let tsig_error = |results: &[Result<OwnedRecord, Error>]| match results.last() {
+            Some(&Err(Error::TsigError(e))) => Some(e),
+            _ => None,
+        };
+
+        // the first and the last message are signed, the second is not
+        let mut first = message(&query, &[soa_rr(3), a_rr("nezu.example.", 1)]);
+        let first_mac = key.sign_response(&mut first, &request_mac, NOW).unwrap();
+        let second = message(&query, &[a_rr("jain-bb.example.", 4)])
+            .encode_vec()
+            .unwrap();
+        let mut third = message(&query, &[a_rr("jain-bb.example.", 6), soa_rr(3)]);
+        key.sign_continued(&mut third, &first_mac, &second, NOW)
+            .unwrap();
+        let responses = vec![
+            first.encode_vec().unwrap(),
+            second.clone(),
+            third.encode_vec().unwrap(),
+        ];
+        let results = transfer(&responses);
+        assert_eq!(results.len(), 4);
+        assert!(results.iter().all(|r| r.is_ok()));
+
+        // the last message is unsigned
+        let mut unsigned_end = responses.clone();
+        unsigned_end[2] = message(&query, &[soa_rr(3)]).encode_vec().unwrap();
+        assert_eq!(
+            tsig_error(&transfer(&unsigned_end)),
+            Some(TsigError::Unsigned)
+        );
+        // the MAC does not cover the unsigned message
+        let mut skipped = responses.clone();
+        skipped.remove(1);
+        assert_eq!(tsig_error(&transfer(&skipped)), Some(TsigError::BadSig));
+        // the first message is unsigned
+        let unsigned_first = messages(&query, &[soa_rr(3), soa_rr(3)], 2);
+        assert_eq!(
+            tsig_error(&transfer(&unsigned_first)),
+            Some(TsigError::Unsigned)
+        );
+    }
+}
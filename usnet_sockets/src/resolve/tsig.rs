@@ -0,0 +1,659 @@
+//! Transaction signatures (TSIG) of DNS messages, as defined by RFC 8945.
+//!
+//! A message is signed with a secret shared with the server, e.g., for dynamic
+//! updates. The MAC covers the message as it is before the TSIG record is
+//! appended, the name of the key, the algorithm, and the time of signing, which
+//! the verifier checks against its clock. The MAC of a response also covers the
+//! MAC of the request, which binds the response to the request. In a response
+//! of several messages, e.g., a zone transfer over TCP, the MAC of each signed
+//! message covers the MAC of the previous one and the unsigned messages since.
+//!
+//! With `DnsResolver::set_tsig_key`, the messages of `send_message` are signed
+//! when they are sent and the responses verified, at the time of a `Clock`.
+
+use std::fmt::{self, Debug};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use ring::hmac;
+
+use super::message::{
+    last_additional_offset, DecodeError, EncodeError, Message, MsgWriter, Resource, MESSAGE_LIMIT,
+};
+use super::name::is_same_name;
+use super::record::{Class, RecordType, Tsig};
+
+/// Seconds by which the clocks may differ by default, as recommended by RFC 8945
+pub const DEFAULT_FUDGE: u16 = 300;
+
+/// The time of signing and verifying messages
+pub trait Clock: Debug + Send + Sync {
+    /// Returns the seconds since the epoch.
+    fn unix_time(&self) -> u64;
+}
+
+/// The clock of the system, the default
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn unix_time(&self) -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0)
+    }
+}
+
+/// A clock which stands still at the given seconds since the epoch, for tests
+/// which check the signatures
+#[derive(Clone, Copy, Debug)]
+pub struct FixedClock(pub u64);
+
+impl Clock for FixedClock {
+    fn unix_time(&self) -> u64 {
+        self.0
+    }
+}
+
+/// MAC algorithm of a TSIG key
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Algorithm {
+    /// HMAC with SHA-1, only for servers without SHA-256
+    HmacSha1,
+    /// HMAC with SHA-256, which every implementation supports
+    HmacSha256,
+    /// HMAC with SHA-384
+    HmacSha384,
+    /// HMAC with SHA-512
+    HmacSha512,
+}
+
+impl Algorithm {
+    /// Returns the name of the algorithm in TSIG records.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Algorithm::HmacSha1 => "hmac-sha1.",
+            Algorithm::HmacSha256 => "hmac-sha256.",
+            Algorithm::HmacSha384 => "hmac-sha384.",
+            Algorithm::HmacSha512 => "hmac-sha512.",
+        }
+    }
+
+    /// Returns the algorithm of a TSIG record, the name is case insensitive.
+    pub fn from_name(name: &str) -> Option<Algorithm> {
+        [
+            Algorithm::HmacSha1,
+            Algorithm::HmacSha256,
+            Algorithm::HmacSha384,
+            Algorithm::HmacSha512,
+        ]
+        .iter()
+        .cloned()
+        .find(|algorithm| is_same_name(algorithm.name(), name))
+    }
+
+    /// Returns the length of the untruncated MAC.
+    pub fn mac_len(&self) -> usize {
+        match *self {
+            Algorithm::HmacSha1 => 20,
+            Algorithm::HmacSha256 => 32,
+            Algorithm::HmacSha384 => 48,
+            Algorithm::HmacSha512 => 64,
+        }
+    }
+
+    fn hmac(&self) -> hmac::Algorithm {
+        match *self {
+            Algorithm::HmacSha1 => hmac::HMAC_SHA1_FOR_LEGACY_USE_ONLY,
+            Algorithm::HmacSha256 => hmac::HMAC_SHA256,
+            Algorithm::HmacSha384 => hmac::HMAC_SHA384,
+            Algorithm::HmacSha512 => hmac::HMAC_SHA512,
+        }
+    }
+}
+
+/// Represents an error in verifying the transaction signature of a message.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum TsigError {
+    /// The last record of the message is not a TSIG record
+    Unsigned,
+    /// The message is signed with another key or algorithm
+    BadKey,
+    /// The MAC does not match the message
+    BadSig,
+    /// The MAC is truncated, which is not accepted
+    BadTrunc,
+    /// The time of signing is not within the fudge of the local clock
+    BadTime,
+    /// The server rejected the signature of the request with the TSIG error
+    /// code, e.g., 16 for BADSIG or 18 for BADTIME
+    Rejected(u16),
+    /// Error decoding the message
+    DecodeError(DecodeError),
+}
+
+impl fmt::Display for TsigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            TsigError::Unsigned => f.write_str("message is not signed"),
+            TsigError::BadKey => f.write_str("message is signed with another key"),
+            TsigError::BadSig => f.write_str("invalid transaction signature"),
+            TsigError::BadTrunc => f.write_str("truncated transaction signature"),
+            TsigError::BadTime => f.write_str("time of signing outside of the fudge"),
+            TsigError::Rejected(16) => f.write_str("server rejected the signature (BADSIG)"),
+            TsigError::Rejected(17) => f.write_str("server does not know the key (BADKEY)"),
+            TsigError::Rejected(18) => f.write_str("server rejected the time (BADTIME)"),
+            TsigError::Rejected(22) => f.write_str("server rejected the MAC size (BADTRUNC)"),
+            TsigError::Rejected(error) => write!(f, "server rejected the signature ({})", error),
+            TsigError::DecodeError(e) => write!(f, "error decoding message: {}", e),
+        }
+    }
+}
+
+impl From<DecodeError> for TsigError {
+    fn from(e: DecodeError) -> TsigError {
+        TsigError::DecodeError(e)
+    }
+}
+
+/// Secret shared with a server to sign and verify messages
+#[derive(Clone, Debug)]
+pub struct TsigKey {
+    name: String,
+    algorithm: Algorithm,
+    key: hmac::Key,
+    fudge: u16,
+}
+
+impl TsigKey {
+    /// Constructs a key with the name and the secret of the server
+    /// configuration, where the secret is usually given in base64.
+    pub fn new(name: &str, algorithm: Algorithm, secret: &[u8]) -> TsigKey {
+        TsigKey {
+            name: name.to_owned(),
+            algorithm: algorithm,
+            key: hmac::Key::new(algorithm.hmac(), secret),
+            fudge: DEFAULT_FUDGE,
+        }
+    }
+
+    /// Sets the seconds by which the clocks may differ, `DEFAULT_FUDGE` by
+    /// default.
+    pub fn with_fudge(mut self, fudge: u16) -> TsigKey {
+        self.fudge = fudge;
+        self
+    }
+
+    /// Returns the name of the key.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns the MAC algorithm of the key.
+    pub fn algorithm(&self) -> Algorithm {
+        self.algorithm
+    }
+
+    /// Signs a request by appending a TSIG record to the additional section,
+    /// so the message must not be changed afterwards; `now` is the time in
+    /// seconds since the epoch. Returns the MAC, which is needed to verify the
+    /// response.
+    pub fn sign(&self, msg: &mut Message, now: u64) -> Result<Vec<u8>, EncodeError> {
+        self.sign_response(msg, &[], now)
+    }
+
+    /// Signs a response to the request with the given MAC, as `sign` does for
+    /// a request.
+    pub fn sign_response(
+        &self,
+        msg: &mut Message,
+        request_mac: &[u8],
+        now: u64,
+    ) -> Result<Vec<u8>, EncodeError> {
+        self.sign_with(msg, request_mac, None, now)
+    }
+
+    /// Signs a later message of a response of several messages, whose MAC
+    /// covers the MAC of the previous signed message and the unsigned messages
+    /// sent after it as they went on the wire (RFC 8945 section 5.3.1).
+    pub fn sign_continued(
+        &self,
+        msg: &mut Message,
+        prior_mac: &[u8],
+        unsigned: &[u8],
+        now: u64,
+    ) -> Result<Vec<u8>, EncodeError> {
+        self.sign_with(msg, prior_mac, Some(unsigned), now)
+    }
+
+    fn sign_with(
+        &self,
+        msg: &mut Message,
+        prior_mac: &[u8],
+        continued: Option<&[u8]>,
+        now: u64,
+    ) -> Result<Vec<u8>, EncodeError> {
+        let mut tsig = Tsig {
+            algorithm: self.algorithm.name().to_owned(),
+            time_signed: now & 0xffff_ffff_ffff,
+            fudge: self.fudge,
+            mac: Vec::new(),
+            original_id: msg.header.id,
+            error: 0,
+            other: Vec::new(),
+        };
+        let mut buf = [0; MESSAGE_LIMIT];
+        let data = msg.encode(&mut buf)?;
+        let input = self.mac_input(prior_mac, continued, data, &tsig)?;
+        tsig.mac = hmac::sign(&self.key, &input).as_ref().to_vec();
+        let mut res = Resource::new(self.name.clone(), RecordType::Tsig, Class::Any, 0);
+        res.write_rdata(&tsig)?;
+        msg.additional.push(res);
+        Ok(tsig.mac)
+    }
+
+    /// Verifies the TSIG record of a received message, which is the response
+    /// to a request signed with the given MAC, or a request if it is empty;
+    /// `now` is the time in seconds since the epoch.
+    pub fn verify(&self, data: &[u8], request_mac: &[u8], now: u64) -> Result<(), TsigError> {
+        self.verify_with(data, request_mac, None, now)
+    }
+
+    /// Verifies the TSIG record of a later message of a response of several
+    /// messages, given the MAC of the previous signed message and the unsigned
+    /// messages after it, see `sign_continued`.
+    pub fn verify_continued(
+        &self,
+        data: &[u8],
+        prior_mac: &[u8],
+        unsigned: &[u8],
+        now: u64,
+    ) -> Result<(), TsigError> {
+        self.verify_with(data, prior_mac, Some(unsigned), now)
+    }
+
+    fn verify_with(
+        &self,
+        data: &[u8],
+        prior_mac: &[u8],
+        continued: Option<&[u8]>,
+        now: u64,
+    ) -> Result<(), TsigError> {
+        let msg = Message::decode(data)?;
+        let res = match msg.additional.last() {
+            Some(res) if res.r_type == RecordType::Tsig => res,
+            _ => return Err(TsigError::Unsigned),
+        };
+        let tsig: Tsig = res.read_rdata()?;
+        if !is_same_name(&res.name, &self.name)
+            || Algorithm::from_name(&tsig.algorithm) != Some(self.algorithm)
+        {
+            return Err(TsigError::BadKey);
+        }
+        // BADSIG and BADKEY errors are not signed by the server
+        if tsig.error != 0 {
+            return Err(TsigError::Rejected(tsig.error));
+        }
+        if tsig.mac.len() < self.algorithm.mac_len() {
+            return Err(TsigError::BadTrunc);
+        }
+        let offset = match last_additional_offset(data)? {
+            Some(offset) => offset,
+            None => return Err(TsigError::Unsigned),
+        };
+        // the message as it was signed, with the original ID and without the
+        // TSIG record
+        let mut unsigned = data[..offset].to_vec();
+        unsigned[0..2].copy_from_slice(&tsig.original_id.to_be_bytes());
+        let ar_count = msg.additional.len() as u16 - 1;
+        unsigned[10..12].copy_from_slice(&ar_count.to_be_bytes());
+        let input = self
+            .mac_input(prior_mac, continued, &unsigned, &tsig)
+            .map_err(|_| TsigError::DecodeError(DecodeError::InvalidMessage))?;
+        if hmac::verify(&self.key, &input, &tsig.mac).is_err() {
+            return Err(TsigError::BadSig);
+        }
+        let difference = now.max(tsig.time_signed) - now.min(tsig.time_signed);
+        if difference > tsig.fudge as u64 {
+            return Err(TsigError::BadTime);
+        }
+        Ok(())
+    }
+
+    /// Verifies the TSIG record of a decoded message, e.g., the response of
+    /// `DnsResolver::send_message`, as `verify` does for the received data.
+    pub fn verify_message(
+        &self,
+        msg: &Message,
+        request_mac: &[u8],
+        now: u64,
+    ) -> Result<(), TsigError> {
+        let res = match msg.additional.last() {
+            Some(res) if res.r_type == RecordType::Tsig => res,
+            _ => return Err(TsigError::Unsigned),
+        };
+        match res.wire_message() {
+            Some(data) => self.verify(data, request_mac, now),
+            None => {
+                // constructed instead of received, the encoding is the same
+                let mut buf = [0; MESSAGE_LIMIT];
+                let data = msg
+                    .encode(&mut buf)
+                    .map_err(|_| TsigError::DecodeError(DecodeError::InvalidMessage))?;
+                self.verify(data, request_mac, now)
+            }
+        }
+    }
+
+    /// Returns the data covered by the MAC of the first message, or of a later
+    /// one of a response of several messages, which only covers the timers of
+    /// the TSIG variables
+    fn mac_input(
+        &self,
+        prior_mac: &[u8],
+        continued: Option<&[u8]>,
+        message: &[u8],
+        tsig: &Tsig,
+    ) -> Result<Vec<u8>, EncodeError> {
+        let unsigned = match continued {
+            Some(unsigned) => unsigned,
+            None => return self.digest_input(prior_mac, message, tsig),
+        };
+        let mut input = Vec::with_capacity(prior_mac.len() + unsigned.len() + message.len() + 10);
+        input.extend_from_slice(&(prior_mac.len() as u16).to_be_bytes());
+        input.extend_from_slice(prior_mac);
+        input.extend_from_slice(unsigned);
+        input.extend_from_slice(message);
+        input.extend_from_slice(&((tsig.time_signed >> 32) as u16).to_be_bytes());
+        input.extend_from_slice(&(tsig.time_signed as u32).to_be_bytes());
+        input.extend_from_slice(&tsig.fudge.to_be_bytes());
+        Ok(input)
+    }
+
+    /// Returns the data covered by the MAC: the MAC of the request, the
+    /// message, and the TSIG variables with the names in canonical form
+    fn digest_input(
+        &self,
+        request_mac: &[u8],
+        message: &[u8],
+        tsig: &Tsig,
+    ) -> Result<Vec<u8>, EncodeError> {
+        let mut input = Vec::with_capacity(request_mac.len() + message.len() + 512);
+        if !request_mac.is_empty() {
+            input.extend_from_slice(&(request_mac.len() as u16).to_be_bytes());
+            input.extend_from_slice(request_mac);
+        }
+        input.extend_from_slice(message);
+
+        let mut buf = [0; MESSAGE_LIMIT];
+        let mut w = MsgWriter::new(&mut buf);
+        w.write_name(&self.name.to_ascii_lowercase())?;
+        w.write_u16(Class::Any.to_u16())?;
+        w.write_u32(0)?;
+        w.write_name(&tsig.algorithm.to_ascii_lowercase())?;
+        w.write_u16((tsig.time_signed >> 32) as u16)?;
+        w.write_u32(tsig.time_signed as u32)?;
+        w.write_u16(tsig.fudge)?;
+        w.write_u16(tsig.error)?;
+        w.write_u16(tsig.other.len() as u16)?;
+        w.write(&tsig.other)?;
+        input.extend_from_slice(w.into_bytes());
+        Ok(input)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Algorithm, TsigError, TsigKey};
+    use crate::resolve::message::{Message, OpCode, Qr, Question};
+    use crate::resolve::record::{Class, RecordType, Tsig, A};
+    use std::net::Ipv4Addr;
+
+    const NOW: u64 = 1_700_000_000;
+
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    fn key() -> TsigKey {
+        let secret = (0..32).collect::<Vec<u8>>();
+        TsigKey::new("update-key", Algorithm::HmacSha256, &secret)
+    }
+
+    fn update() -> Message<'static> {
+        let mut msg = Message::update("example.com", Class::Internet);
+        msg.header.id = 0x2a2a;
+        let a = A {
+            address: Ipv4Addr::new(192, 0, 2, 1),
+        };
+        msg.add_record("host.example.com", 300, &a).unwrap();
+        msg
+    }
+
+    // MAC computed independently over the wire message and the TSIG variables
+    const REQUEST_MAC: &str = "57a3d0d08051a34798b66e2d7dd3b8130e8f0c5418a81675cb95ace5f91bb8b9";
+    // Response to the update above, signed one second later
+    const RESPONSE: &str = "2a2aa8000001000000000001076578616d706c6503636f6d00000600010a75706461\
+                            74652d6b65790000fa00ff00000000003d0b686d61632d7368613235360000006553\
+                            f101012c00204f65d6a90b26d59715034fea895e7965a8a9d40a00ef14b5ebe04a2f\
+                            25ce13ee2a2a00000000";
+
+    #[test]
+    fn test_sign_hmac_sha256() {
+        let mut signed = update();
+        let mac = key().sign(&mut signed, NOW).unwrap();
+        assert_eq!(mac, hex(REQUEST_MAC));
+
+        let mut buf = [0; 512];
+        let data = signed.encode(&mut buf).unwrap();
+        let msg = Message::decode(data).unwrap();
+        assert_eq!(msg.additional.len(), 1);
+        let res = &msg.additional[0];
+        assert_eq!(res.name, "update-key.");
+        assert_eq!(res.r_type, RecordType::Tsig);
+        assert_eq!(res.r_class, Class::Any);
+        assert_eq!(res.ttl, 0);
+        let tsig: Tsig = res.read_rdata().unwrap();
+        assert_eq!(
+            tsig,
+            Tsig {
+                algorithm: "hmac-sha256.".to_owned(),
+                time_signed: NOW,
+                fudge: 300,
+                mac: hex(REQUEST_MAC),
+                original_id: 0x2a2a,
+                error: 0,
+                other: vec![],
+            }
+        );
+        assert_eq!(key().verify(data, &[], NOW), Ok(()));
+        assert_eq!(key().verify_message(&signed, &[], NOW), Ok(()));
+    }
+
+    #[test]
+    fn test_verify_hmac_sha256() {
+        let response = hex(RESPONSE);
+        let request_mac = hex(REQUEST_MAC);
+        assert_eq!(key().verify(&response, &request_mac, NOW), Ok(()));
+        let msg = Message::decode(&response).unwrap();
+        assert_eq!(key().verify_message(&msg, &request_mac, NOW), Ok(()));
+        // the MAC of the response covers the one of the request
+        assert_eq!(key().verify(&response, &[], NOW), Err(TsigError::BadSig));
+        let mut other_request = request_mac.clone();
+        other_request[0] ^= 1;
+        assert_eq!(
+            key().verify(&response, &other_request, NOW),
+            Err(TsigError::BadSig)
+        );
+
+        let mut changed = response.clone();
+        changed[3] |= 0x01; // NXDOMAIN instead of NOERROR
+        assert_eq!(
+            key().verify(&changed, &request_mac, NOW),
+            Err(TsigError::BadSig)
+        );
+        let other_secret = TsigKey::new("update-key", Algorithm::HmacSha256, b"secret");
+        assert_eq!(
+            other_secret.verify(&response, &request_mac, NOW),
+            Err(TsigError::BadSig)
+        );
+        let other_name = TsigKey::new("other-key", Algorithm::HmacSha256, &[0; 32]);
+        assert_eq!(
+            other_name.verify(&response, &request_mac, NOW),
+            Err(TsigError::BadKey)
+        );
+        let other_algorithm = TsigKey::new("update-key", Algorithm::HmacSha512, &[0; 32]);
+        assert_eq!(
+            other_algorithm.verify(&response, &request_mac, NOW),
+            Err(TsigError::BadKey)
+        );
+    }
+
+    #[test]
+    fn test_verify_time() {
+        let response = hex(RESPONSE);
+        let request_mac = hex(REQUEST_MAC);
+        // signed at NOW + 1 with a fudge of 300 seconds
+        assert_eq!(key().verify(&response, &request_mac, NOW - 299), Ok(()));
+        assert_eq!(key().verify(&response, &request_mac, NOW + 301), Ok(()));
+        assert_eq!(
+            key().verify(&response, &request_mac, NOW - 300),
+            Err(TsigError::BadTime)
+        );
+        assert_eq!(
+            key().verify(&response, &request_mac, NOW + 302),
+            Err(TsigError::BadTime)
+        );
+
+        let mut msg = update();
+        key().with_fudge(10).sign(&mut msg, NOW).unwrap();
+        let mut buf = [0; 512];
+        let data = msg.encode(&mut buf).unwrap();
+        assert_eq!(key().verify(data, &[], NOW + 10), Ok(()));
+        assert_eq!(key().verify(data, &[], NOW + 11), Err(TsigError::BadTime));
+    }
+
+    #[test]
+    fn test_sign_response() {
+        let mut request = update();
+        let request_mac = key().sign(&mut request, NOW).unwrap();
+
+        let mut response = Message::with_id(0x2a2a);
+        response.header.qr = Qr::Response;
+        response.header.op = OpCode::Update;
+        response.question.push(Question::new(
+            "example.com".to_owned(),
+            RecordType::Soa,
+            Class::Internet,
+        ));
+        let mac = key()
+            .sign_response(&mut response, &request_mac, NOW + 1)
+            .unwrap();
+        let mut buf = [0; 512];
+        let data = response.encode(&mut buf).unwrap();
+        assert_eq!(data, &hex(RESPONSE)[..]);
+        assert_eq!(mac.len(), Algorithm::HmacSha256.mac_len());
+    }
+
+    #[test]
+    fn test_continued() {
+        let mut request = update();
+        let request_mac = key().sign(&mut request, NOW).unwrap();
+        let mut first = update();
+        first.header.qr = Qr::Response;
+        let first_mac = key().sign_response(&mut first, &request_mac, NOW).unwrap();
+        let mut buf = [0; 512];
+        let data = first.encode(&mut buf).unwrap().to_vec();
+        assert_eq!(key().verify(&data, &request_mac, NOW), Ok(()));
+
+        // the second message is unsigned, the third covers it
+        let unsigned = update().encode_vec().unwrap();
+        let mut third = update();
+        key()
+            .sign_continued(&mut third, &first_mac, &unsigned, NOW + 1)
+            .unwrap();
+        let data = third.encode(&mut buf).unwrap();
+        assert_eq!(
+            key().verify_continued(data, &first_mac, &unsigned, NOW),
+            Ok(())
+        );
+        assert_eq!(
+            key().verify_continued(data, &first_mac, &[], NOW),
+            Err(TsigError::BadSig)
+        );
+        assert_eq!(
+            key().verify_continued(data, &request_mac, &unsigned, NOW),
+            Err(TsigError::BadSig)
+        );
+        // only the first message covers all TSIG variables
+        assert_eq!(key().verify(data, &first_mac, NOW), Err(TsigError::BadSig));
+    }
+
+    #[test]
+    fn test_verify_errors() {
+        let mut msg = update();
+        let mut buf = [0; 512];
+        let unsigned = msg.encode(&mut buf).unwrap().to_vec();
+        assert_eq!(key().verify(&unsigned, &[], NOW), Err(TsigError::Unsigned));
+
+        // a BADSIG error of the server is not signed
+        let mut res = crate::resolve::message::Resource::new(
+            "update-key".to_owned(),
+            RecordType::Tsig,
+            Class::Any,
+            0,
+        );
+        res.write_rdata(&Tsig {
+            algorithm: "hmac-sha256.".to_owned(),
+            time_signed: NOW,
+            fudge: 300,
+            mac: vec![],
+            original_id: 0x2a2a,
+            error: 16,
+            other: vec![],
+        })
+        .unwrap();
+        msg.additional.push(res);
+        let data = msg.encode(&mut buf).unwrap();
+        let err = key().verify(data, &[], NOW).unwrap_err();
+        assert_eq!(err, TsigError::Rejected(16));
+        assert_eq!(err.to_string(), "server rejected the signature (BADSIG)");
+
+        let mut msg = update();
+        key().sign(&mut msg, NOW).unwrap();
+        let data = msg.encode(&mut buf).unwrap().to_vec();
+        // MAC truncated to 16 bytes, the length fields are adjusted
+        let mac_start = data.len() - 6 - 32;
+        let mut truncated = data[..mac_start - 2].to_vec();
+        truncated.extend_from_slice(&[0, 16]);
+        truncated.extend_from_slice(&data[mac_start..mac_start + 16]);
+        truncated.extend_from_slice(&data[data.len() - 6..]);
+        // before the MAC size, the fudge, the time, and the algorithm name
+        let rdlen_at = mac_start - 2 - 2 - 6 - 13 - 2;
+        let rdlen = u16::from_be_bytes([truncated[rdlen_at], truncated[rdlen_at + 1]]) - 16;
+        truncated[rdlen_at..rdlen_at + 2].copy_from_slice(&rdlen.to_be_bytes());
+        assert_eq!(key().verify(&truncated, &[], NOW), Err(TsigError::BadTrunc));
+    }
+
+    #[test]
+    fn test_algorithm_names() {
+        for algorithm in [
+            Algorithm::HmacSha1,
+            Algorithm::HmacSha256,
+            Algorithm::HmacSha384,
+            Algorithm::HmacSha512,
+        ]
+        .iter()
+        {
+            assert_eq!(Algorithm::from_name(algorithm.name()), Some(*algorithm));
+        }
+        assert_eq!(
+            Algorithm::from_name("HMAC-SHA256"),
+            Some(Algorithm::HmacSha256)
+        );
+        assert_eq!(Algorithm::from_name("hmac-md5.sig-alg.reg.int."), None);
+    }
+}
@@ -0,0 +1,193 @@
+//! Builder of dynamic updates (RFC 2136)
+//!
+//! `UpdateBuilder` chains the prerequisites and updates of an UPDATE message
+//! like the commands of `nsupdate`, and returns the first error of a record
+//! from `build`:
+//!
+//! ```ignore
+//! let msg = UpdateBuilder::new("internal.example.")
+//!     .require_no_rrset("host.internal.example.", RecordType::A)
+//!     .add_address("host.internal.example.", 300, ip)
+//!     .delete("host.internal.example.", RecordType::Txt)
+//!     .build()?;
+//! let response = resolver.send_message(&msg, &mut buf)?;
+//! response.get_error()?;
+//! ```
+//!
+//! The update is sent as any other message, over TCP if it is longer than
+//! 512 bytes, see `DnsResolver::send_message`.
+
+use std::net::IpAddr;
+
+use super::message::{EncodeError, MessageBuf};
+use super::record::{Class, Record, RecordType, A, AAAA};
+
+/// Builds an UPDATE message for a zone, see `Message::update`
+#[derive(Clone, Debug)]
+pub struct UpdateBuilder {
+    msg: MessageBuf,
+    error: Option<EncodeError>,
+}
+
+impl UpdateBuilder {
+    /// Returns a builder of an update of the zone of class IN.
+    pub fn new(zone: &str) -> UpdateBuilder {
+        UpdateBuilder::with_class(zone, Class::Internet)
+    }
+
+    /// Returns a builder of an update of the zone of the class.
+    pub fn with_class(zone: &str, class: Class) -> UpdateBuilder {
+        UpdateBuilder {
+            msg: MessageBuf::update(zone, class),
+            error: None,
+        }
+    }
+
+    fn check(mut self, result: Result<(), EncodeError>) -> UpdateBuilder {
+        if let Err(e) = result {
+            self.error = self.error.or(Some(e));
+        }
+        self
+    }
+
+    /// Requires that a record set of the type exists at the name.
+    pub fn require_rrset(mut self, name: &str, r_type: RecordType) -> UpdateBuilder {
+        self.msg.require_rrset(name, r_type);
+        self
+    }
+
+    /// Requires that the record set at the name contains the record.
+    pub fn require_record<R: Record>(mut self, name: &str, record: &R) -> UpdateBuilder {
+        let result = self.msg.require_record(name, record);
+        self.check(result)
+    }
+
+    /// Requires that no record set of the type exists at the name.
+    pub fn require_no_rrset(mut self, name: &str, r_type: RecordType) -> UpdateBuilder {
+        self.msg.require_no_rrset(name, r_type);
+        self
+    }
+
+    /// Requires that the name has a record of any type.
+    pub fn require_name(mut self, name: &str) -> UpdateBuilder {
+        self.msg.require_name(name);
+        self
+    }
+
+    /// Requires that the name has no records.
+    pub fn require_no_name(mut self, name: &str) -> UpdateBuilder {
+        self.msg.require_no_name(name);
+        self
+    }
+
+    /// Adds the record to the record set at the name.
+    pub fn add<R: Record>(mut self, name: &str, ttl: u32, record: &R) -> UpdateBuilder {
+        let result = self.msg.add_record(name, ttl, record);
+        self.check(result)
+    }
+
+    /// Adds an A or AAAA record with the address.
+    pub fn add_address(self, name: &str, ttl: u32, address: IpAddr) -> UpdateBuilder {
+        match address {
+            IpAddr::V4(address) => self.add(name, ttl, &A { address: address }),
+            IpAddr::V6(address) => self.add(name, ttl, &AAAA { address: address }),
+        }
+    }
+
+    /// Deletes the record set of the type at the name.
+    pub fn delete(mut self, name: &str, r_type: RecordType) -> UpdateBuilder {
+        self.msg.delete_rrset(name, r_type);
+        self
+    }
+
+    /// Deletes the record from the record set at the name.
+    pub fn delete_record<R: Record>(mut self, name: &str, record: &R) -> UpdateBuilder {
+        let result = self.msg.delete_record(name, record);
+        self.check(result)
+    }
+
+    /// Deletes all record sets at the name.
+    pub fn delete_name(mut self, name: &str) -> UpdateBuilder {
+        self.msg.delete_name(name);
+        self
+    }
+
+    /// Adds a record to the additional section, e.g., the address of a name
+    /// server whose NS record is added.
+    pub fn additional<R: Record>(mut self, name: &str, ttl: u32, record: &R) -> UpdateBuilder {
+        let result = self.msg.add_additional(name, ttl, record);
+        self.check(result)
+    }
+
+    /// Returns the UPDATE message, or the error of the first record which did
+    /// not encode.
+    pub fn build(self) -> Result<MessageBuf, EncodeError> {
+        match self.error {
+            Some(e) => Err(e),
+            None => Ok(self.msg),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::UpdateBuilder;
+    use crate::resolve::message::{DnsError, EncodeError, Message, OpCode, Qr, RCode};
+    use crate::resolve::record::{Class, RecordType, Txt, AAAA};
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+    #[test]
+    fn test_update_builder() {
+        let ip = IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1));
+        let msg = UpdateBuilder::new("internal.example.")
+            .require_name("host.internal.example.")
+            .add_address("host.internal.example.", 300, ip)
+            .add_address(
+                "host.internal.example.",
+                300,
+                Ipv4Addr::new(192, 0, 2, 1).into(),
+            )
+            .delete("host.internal.example.", RecordType::Txt)
+            .delete_record(
+                "host.internal.example.",
+                &Txt {
+                    data: b"old".to_vec(),
+                },
+            )
+            .build()
+            .unwrap();
+        let mut buf = [0; 512];
+        let data = msg.encode(&mut buf).unwrap().to_vec();
+        let decoded = Message::decode(&data).unwrap();
+        assert_eq!(decoded.header.op, OpCode::Update);
+        assert_eq!(decoded.zone().unwrap().name, "internal.example.");
+        assert_eq!(decoded.prerequisites()[0].r_class, Class::Any);
+        let updates = decoded.updates();
+        assert_eq!(updates.len(), 4);
+        assert_eq!(
+            IpAddr::V6(updates[0].read_rdata::<AAAA>().unwrap().address),
+            ip
+        );
+        assert_eq!(updates[1].r_type, RecordType::A);
+        // a record set is deleted with class ANY, a record with class NONE
+        assert_eq!((updates[2].r_class, updates[2].ttl), (Class::Any, 0));
+        assert_eq!((updates[3].r_class, updates[3].ttl), (Class::None, 0));
+
+        // a character string of more than 255 bytes
+        let long = Txt {
+            data: vec![b'x'; 300],
+        };
+        let err = UpdateBuilder::new("internal.example.")
+            .add("host.internal.example.", 300, &long)
+            .delete_name("old.internal.example.")
+            .build();
+        assert_eq!(err.unwrap_err(), EncodeError::TooLong);
+
+        let mut response = Message::decode(&data).unwrap();
+        response.header.qr = Qr::Response;
+        response.set_rcode(RCode::YxRrSet);
+        let error = response.get_error().unwrap_err();
+        assert!(error.is_prerequisite_failure());
+        assert!(!DnsError::from(RCode::NotAuth).is_prerequisite_failure());
+    }
+}
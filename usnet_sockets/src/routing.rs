@@ -0,0 +1,707 @@
+/// Additional IP addresses and static routes of the interface
+/// smoltcp uses the route with the longest matching prefix for every outbound packet to a
+/// destination outside of the interface's networks, the static routes are installed there
+/// next to the default routes from the backend configuration, DHCP, or SLAAC.
+/// When a connection is made without a local address, the source address is selected
+/// among the interface addresses as described in RFC 6724.
+use std::cmp::Ordering;
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
+
+use smoltcp;
+use smoltcp::iface::Routes;
+use smoltcp::wire::{IpAddress, IpCidr, Ipv4Address};
+
+use crate::device::StcpBackendInterface;
+
+/// Route to a destination network through a next hop on the local network
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Route {
+    pub destination: IpAddr,
+    pub prefix_len: u8,
+    pub via: IpAddr,
+}
+
+impl Route {
+    /// Checks that all addresses are of the same family, the host bits of the destination are cleared
+    pub fn new(destination: IpAddr, prefix_len: u8, via: IpAddr) -> io::Result<Route> {
+        if destination.is_ipv4() != via.is_ipv4() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("route to {} via {} mixes IPv4 and IPv6", destination, via),
+            ));
+        }
+        check_prefix_len(destination, prefix_len)?;
+        if via.is_unspecified() || via.is_multicast() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid next hop {}", via),
+            ));
+        }
+        Ok(Route {
+            destination: network(destination, prefix_len),
+            prefix_len: prefix_len,
+            via: via,
+        })
+    }
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        in_network(self.destination, self.prefix_len, addr)
+    }
+    fn cidr(&self) -> IpCidr {
+        IpCidr::new(IpAddress::from(self.destination), self.prefix_len)
+    }
+}
+
+impl fmt::Display for Route {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}/{} via {}",
+            self.destination, self.prefix_len, self.via
+        )
+    }
+}
+
+/// Parses the format "10.1.0.0/16 via 10.0.0.1" as used for USNET_ROUTES
+impl FromStr for Route {
+    type Err = io::Error;
+    fn from_str(s: &str) -> io::Result<Route> {
+        let parts: Vec<&str> = s.split_whitespace().collect();
+        match parts[..] {
+            [destination, "via", via] => {
+                let (destination, prefix_len) = parse_cidr(destination)?;
+                let via = IpAddr::from_str(via).map_err(|_| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("invalid next hop in route {}", s),
+                    )
+                })?;
+                Route::new(destination, prefix_len, via)
+            }
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "route {} is not in the format ADDRESS/PREFIX via ADDRESS",
+                    s
+                ),
+            )),
+        }
+    }
+}
+
+/// Parses the format "10.0.0.5/24" as used for USNET_ADDRESSES
+pub fn parse_cidr(s: &str) -> io::Result<(IpAddr, u8)> {
+    let invalid = || {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} is not in the format ADDRESS/PREFIX", s),
+        )
+    };
+    let mut parts = s.trim().splitn(2, '/');
+    let addr = parts
+        .next()
+        .and_then(|a| IpAddr::from_str(a).ok())
+        .ok_or_else(invalid)?;
+    let prefix_len = parts
+        .next()
+        .and_then(|p| u8::from_str(p).ok())
+        .ok_or_else(invalid)?;
+    check_prefix_len(addr, prefix_len)?;
+    Ok((addr, prefix_len))
+}
+
+pub(crate) fn check_prefix_len(addr: IpAddr, prefix_len: u8) -> io::Result<()> {
+    let max = if addr.is_ipv4() { 32 } else { 128 };
+    if prefix_len > max {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("invalid prefix length /{} for {}", prefix_len, addr),
+        ));
+    }
+    Ok(())
+}
+
+pub(crate) fn check_address(address: IpAddr, prefix_len: u8) -> io::Result<()> {
+    check_prefix_len(address, prefix_len)?;
+    if address.is_unspecified() || address.is_multicast() || address.is_loopback() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} cannot be an interface address", address),
+        ));
+    }
+    Ok(())
+}
+
+fn to_bits(addr: IpAddr) -> (u128, u8) {
+    match addr {
+        IpAddr::V4(a) => ((u32::from(a) as u128) << 96, 32),
+        IpAddr::V6(a) => (u128::from(a), 128),
+    }
+}
+
+/// Number of leading bits the addresses have in common, both must be of the same family
+fn common_prefix_len(a: IpAddr, b: IpAddr) -> u8 {
+    let ((a, len), (b, _)) = (to_bits(a), to_bits(b));
+    ((a ^ b).leading_zeros() as u8).min(len)
+}
+
+pub(crate) fn in_network(network: IpAddr, prefix_len: u8, addr: IpAddr) -> bool {
+    addr.is_ipv4() == network.is_ipv4() && common_prefix_len(network, addr) >= prefix_len
+}
+
+pub(crate) fn network(addr: IpAddr, prefix_len: u8) -> IpAddr {
+    let (bits, len) = to_bits(addr);
+    let mask = if prefix_len == 0 {
+        0
+    } else {
+        !0u128 << (128 - prefix_len as u32)
+    };
+    let bits = bits & mask;
+    if len == 32 {
+        IpAddr::V4(Ipv4Addr::from((bits >> 96) as u32))
+    } else {
+        IpAddr::V6(Ipv6Addr::from(bits))
+    }
+}
+
+pub(crate) fn to_std(addr: IpAddress) -> Option<IpAddr> {
+    match addr {
+        IpAddress::Ipv4(a) => Some(IpAddr::V4(Ipv4Addr::from(a))),
+        IpAddress::Ipv6(a) => Some(IpAddr::V6(Ipv6Addr::from(a))),
+        _ => None,
+    }
+}
+
+/// The unspecified address matches all interface addresses when used for a socket
+pub(crate) fn endpoint_address(addr: IpAddr) -> IpAddress {
+    if addr.is_unspecified() {
+        IpAddress::Unspecified
+    } else {
+        IpAddress::from(addr)
+    }
+}
+
+/// Sockets can only be bound to addresses of the interface, as with the kernel
+pub(crate) fn check_local_address(iface: &StcpBackendInterface, addr: IpAddr) -> io::Result<()> {
+    if addr.is_unspecified() || addr.is_loopback() || iface.has_ip(addr) {
+        Ok(())
+    } else {
+        Err(io::Error::new(
+            io::ErrorKind::AddrNotAvailable,
+            format!("cannot bind to {}, not an address of the interface", addr),
+        ))
+    }
+}
+
+/// Addresses of the interface without the placeholder used while DHCP has no lease
+/// and without 127.0.0.1 of the loopback
+pub(crate) fn interface_addresses(ips: &[IpCidr]) -> Vec<(IpAddr, u8)> {
+    ips.iter()
+        .filter_map(|cidr| to_std(cidr.address()).map(|a| (a, cidr.prefix_len())))
+        .filter(|&(a, _)| !a.is_unspecified() && !a.is_loopback())
+        .collect()
+}
+
+/// Adds the addresses after the primary address and installs the routes
+pub(crate) fn configure(
+    iface: &mut StcpBackendInterface,
+    addresses: &[(IpAddr, u8)],
+    routes: &[Route],
+) -> io::Result<()> {
+    for &(address, prefix_len) in addresses {
+        add_address(iface, address, prefix_len)?;
+    }
+    for route in routes {
+        add_route(iface, route);
+    }
+    Ok(())
+}
+
+pub(crate) fn add_address(
+    iface: &mut StcpBackendInterface,
+    address: IpAddr,
+    prefix_len: u8,
+) -> io::Result<()> {
+    check_address(address, prefix_len)?;
+    if iface.has_ip(address) {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("address {} is already configured", address),
+        ));
+    }
+    iface.update_ip_addrs(|addrs| addrs.push(IpCidr::new(address.into(), prefix_len)));
+    info!("added address {}/{}", address, prefix_len);
+    Ok(())
+}
+
+/// The primary address at the first position can't be removed because DHCP and
+/// the usnetd port matches rely on it
+pub(crate) fn remove_address(iface: &mut StcpBackendInterface, address: IpAddr) -> io::Result<()> {
+    let pos = iface
+        .ips()
+        .iter()
+        .position(|cidr| to_std(cidr.address()) == Some(address) && !address.is_loopback());
+    match pos {
+        None => Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("address {} is not configured", address),
+        )),
+        Some(0) => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("the primary address {} cannot be removed", address),
+        )),
+        Some(pos) => {
+            iface.update_ip_addrs(|addrs| {
+                addrs.remove(pos);
+            });
+            info!("removed address {}", address);
+            Ok(())
+        }
+    }
+}
+
+/// Replaces an existing route to the same destination network
+pub(crate) fn add_route(iface: &mut StcpBackendInterface, route: &Route) {
+    let via = match route.via {
+        IpAddr::V4(via) => smoltcp::iface::Route::new_ipv4_gateway(via.into()),
+        IpAddr::V6(via) => smoltcp::iface::Route::new_ipv6_gateway(via.into()),
+    };
+    iface.update_routes(|routes: &mut Routes<'static>| {
+        routes.update(|storage| {
+            let _ = storage.insert(route.cidr(), via);
+        })
+    });
+    info!("added route {}", route);
+}
+
+pub(crate) fn remove_route(iface: &mut StcpBackendInterface, route: &Route) {
+    iface.update_routes(|routes: &mut Routes<'static>| {
+        routes.update(|storage| {
+            let _ = storage.remove(&route.cidr());
+        })
+    });
+    info!("removed route {}", route);
+}
+
+/// The IPv4 default route of the interface, also when set by the backend configuration or DHCP
+pub(crate) fn default_ipv4_gateway(iface: &mut StcpBackendInterface) -> Option<Ipv4Addr> {
+    let default = IpCidr::new(IpAddress::Ipv4(Ipv4Address::UNSPECIFIED), 0);
+    let mut gateway = None;
+    iface.update_routes(|routes: &mut Routes<'static>| {
+        routes.update(|storage| {
+            gateway = storage.get(&default).map(|route| route.via_router);
+        })
+    });
+    match gateway {
+        Some(IpAddress::Ipv4(gateway)) => Some(Ipv4Addr::from(gateway)),
+        _ => None,
+    }
+}
+
+/// Longest prefix match, the same as done by smoltcp for outbound packets
+pub(crate) fn lookup(routes: &[Route], dst: IpAddr) -> Option<&Route> {
+    routes
+        .iter()
+        .filter(|r| r.contains(dst))
+        .max_by_key(|r| r.prefix_len)
+}
+
+/// The neighbor to which packets to dst are sent, dst itself in the networks of the interface,
+/// None if there is no route, which includes the static and default routes
+pub(crate) fn next_hop(iface: &mut StcpBackendInterface, dst: IpAddr) -> Option<IpAddr> {
+    let addresses = interface_addresses(iface.ips());
+    if on_link(&addresses, dst) {
+        return Some(dst);
+    }
+    lookup(&installed_routes(iface), dst).map(|r| r.via)
+}
+
+fn on_link(addresses: &[(IpAddr, u8)], dst: IpAddr) -> bool {
+    addresses.iter().any(|&(a, len)| in_network(a, len, dst))
+}
+
+/// The routes of the interface, i.e., the static routes and the default routes
+fn installed_routes(iface: &mut StcpBackendInterface) -> Vec<Route> {
+    let mut installed = vec![];
+    iface.update_routes(|routes: &mut Routes<'static>| {
+        routes.update(|storage| {
+            installed = storage
+                .iter()
+                .filter_map(|(cidr, route)| {
+                    Some(Route {
+                        destination: to_std(cidr.address())?,
+                        prefix_len: cidr.prefix_len(),
+                        via: to_std(route.via_router)?,
+                    })
+                })
+                .collect();
+        })
+    });
+    installed
+}
+
+/// Selects the source address for connections to dst from an unspecified local address,
+/// None if the interface has no address of the destination's family
+/// The next hop for rule 5.5 is the gateway of the route to dst, including the default route.
+pub(crate) fn source_address(iface: &mut StcpBackendInterface, dst: IpAddr) -> Option<IpAddr> {
+    let next_hop = next_hop(iface, dst);
+    select_source(&interface_addresses(iface.ips()), dst, next_hop)
+}
+
+fn select_source(
+    addresses: &[(IpAddr, u8)],
+    dst: IpAddr,
+    next_hop: Option<IpAddr>,
+) -> Option<IpAddr> {
+    let candidates = addresses
+        .iter()
+        .cloned()
+        .filter(|&(a, _)| a.is_ipv4() == dst.is_ipv4());
+    let mut best: Option<(IpAddr, u8)> = None;
+    for candidate in candidates {
+        best = match best {
+            Some(b) if compare_sources(candidate, b, dst, next_hop) != Ordering::Greater => Some(b),
+            _ => Some(candidate),
+        };
+    }
+    best.map(|(a, _)| a)
+}
+
+/// Applies the rules of RFC 6724 section 5 that are relevant for a single interface without
+/// deprecated, home, or temporary addresses, Greater means that a is preferred
+/// Rule 8 is not listed because rule 2 already decides when the scopes differ.
+fn compare_sources(
+    a: (IpAddr, u8),
+    b: (IpAddr, u8),
+    dst: IpAddr,
+    next_hop: Option<IpAddr>,
+) -> Ordering {
+    // Rule 1: prefer same address
+    if a.0 == dst {
+        return Ordering::Greater;
+    }
+    if b.0 == dst {
+        return Ordering::Less;
+    }
+    // Rule 2: prefer appropriate scope
+    let (scope_a, scope_b, scope_dst) = (scope(a.0), scope(b.0), scope(dst));
+    if scope_a < scope_b {
+        return if scope_a < scope_dst {
+            Ordering::Less
+        } else {
+            Ordering::Greater
+        };
+    }
+    if scope_b < scope_a {
+        return if scope_b < scope_dst {
+            Ordering::Greater
+        } else {
+            Ordering::Less
+        };
+    }
+    // Rule 5.5: prefer addresses in a prefix of the next hop
+    if let Some(next_hop) = next_hop {
+        let in_a = common_prefix_len(a.0, next_hop) >= a.1;
+        let in_b = common_prefix_len(b.0, next_hop) >= b.1;
+        if in_a != in_b {
+            return in_a.cmp(&in_b);
+        }
+    }
+    // Rule 6: prefer matching label
+    let label_dst = label(dst);
+    let (match_a, match_b) = (label(a.0) == label_dst, label(b.0) == label_dst);
+    if match_a != match_b {
+        return match_a.cmp(&match_b);
+    }
+    // Rule 9: use longest matching prefix, up to the prefix length of the source
+    let common_a = common_prefix_len(a.0, dst).min(a.1);
+    let common_b = common_prefix_len(b.0, dst).min(b.1);
+    common_a.cmp(&common_b)
+}
+
+/// Scope values of RFC 6724 section 3.1 and 3.2
+fn scope(addr: IpAddr) -> u8 {
+    match addr {
+        IpAddr::V4(a) => {
+            if a.is_loopback() || a.is_link_local() {
+                0x2
+            } else {
+                0xe
+            }
+        }
+        IpAddr::V6(a) => {
+            let segments = a.segments();
+            if a.is_multicast() {
+                (segments[0] & 0xf) as u8
+            } else if a.is_loopback() || segments[0] & 0xffc0 == 0xfe80 {
+                0x2
+            } else {
+                0xe
+            }
+        }
+    }
+}
+
+/// Labels of the default policy table of RFC 6724 section 2.1, IPv4 addresses are compared
+/// as IPv4-mapped IPv6 addresses
+fn label(addr: IpAddr) -> u8 {
+    match addr {
+        IpAddr::V4(_) => 4,
+        IpAddr::V6(a) => {
+            let s = a.segments();
+            if a.is_loopback() {
+                0
+            } else if s[..5] == [0; 5] && s[5] == 0xffff {
+                4
+            } else if s[..6] == [0; 6] {
+                3
+            } else if s[0] == 0x2002 {
+                2
+            } else if s[0] == 0x2001 && s[1] == 0 {
+                5
+            } else if s[0] & 0xfe00 == 0xfc00 {
+                13
+            } else if s[0] & 0xffc0 == 0xfec0 {
+                11
+            } else if s[0] == 0x3ffe {
+                12
+            } else {
+                1
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        add_address, add_route, compare_sources, label, lookup, next_hop, parse_cidr,
+        remove_address, remove_route, scope, select_source, source_address, Route,
+    };
+    use crate::device::StcpBackendInterface;
+    use crate::usnetconfig::StackConfigBuilder;
+    use std::cmp::Ordering;
+    use std::io;
+    use std::net::{IpAddr, Ipv4Addr};
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    fn cidr(s: &str) -> (IpAddr, u8) {
+        parse_cidr(s).unwrap()
+    }
+
+    fn route(s: &str) -> Route {
+        s.parse().unwrap()
+    }
+
+    /// Interface of an in-memory link with 10.0.0.2/24 and the default gateway 10.0.0.1
+    fn interface(link: &str) -> StcpBackendInterface {
+        let config = StackConfigBuilder::new()
+            .in_memory(link)
+            .ipv4(Ipv4Addr::new(10, 0, 0, 2), 24)
+            .gateway(Ipv4Addr::new(10, 0, 0, 1))
+            .build()
+            .unwrap();
+        config.backend.to_interface(false, None).unwrap().1
+    }
+
+    #[test]
+    fn test_parse_cidr() {
+        assert_eq!(parse_cidr("10.0.0.5/24").unwrap(), (ip("10.0.0.5"), 24));
+        assert_eq!(
+            parse_cidr(" 2001:db8::1/64 ").unwrap(),
+            (ip("2001:db8::1"), 64)
+        );
+        assert_eq!(parse_cidr("0.0.0.0/0").unwrap(), (ip("0.0.0.0"), 0));
+        for invalid in &[
+            "10.0.0.5",
+            "10.0.0.5/",
+            "10.0.0.5/33",
+            "2001:db8::1/129",
+            "10.0.0/24",
+            "/24",
+        ] {
+            let err = parse_cidr(invalid).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput, "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_parse_route() {
+        let r = route("10.1.2.3/16 via 10.0.0.1");
+        assert_eq!(r.destination, ip("10.1.0.0"));
+        assert_eq!(r.prefix_len, 16);
+        assert_eq!(r.via, ip("10.0.0.1"));
+        assert_eq!(r.to_string(), "10.1.0.0/16 via 10.0.0.1");
+        assert!(r.contains(ip("10.1.255.1")));
+        assert!(!r.contains(ip("10.2.0.1")));
+        assert!(!r.contains(ip("::a01:1")));
+        for invalid in &[
+            "10.1.0.0/16",
+            "10.1.0.0/16 via",
+            "10.1.0.0/16 through 10.0.0.1",
+            "10.1.0.0/16 via 2001:db8::1",
+            "10.1.0.0/16 via 0.0.0.0",
+            "10.1.0.0/16 via 224.0.0.1",
+        ] {
+            let err = invalid.parse::<Route>().unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput, "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_lookup() {
+        let routes = vec![
+            route("0.0.0.0/0 via 10.0.0.1"),
+            route("10.1.0.0/16 via 10.0.0.2"),
+            route("10.1.2.0/24 via 10.0.0.3"),
+            route("2001:db8::/32 via fe80::1"),
+        ];
+        let via = |dst| lookup(&routes, ip(dst)).map(|r| r.via);
+        assert_eq!(via("10.1.2.5"), Some(ip("10.0.0.3")));
+        assert_eq!(via("10.1.9.9"), Some(ip("10.0.0.2")));
+        assert_eq!(via("8.8.8.8"), Some(ip("10.0.0.1")));
+        assert_eq!(via("2001:db8:1::1"), Some(ip("fe80::1")));
+        // the IPv4 default route does not match IPv6 destinations
+        assert_eq!(via("2001:db9::1"), None);
+    }
+
+    #[test]
+    fn test_scope_and_label() {
+        assert_eq!(scope(ip("127.0.0.1")), 0x2);
+        assert_eq!(scope(ip("169.254.1.1")), 0x2);
+        assert_eq!(scope(ip("192.168.1.1")), 0xe);
+        assert_eq!(scope(ip("fe80::1")), 0x2);
+        assert_eq!(scope(ip("::1")), 0x2);
+        assert_eq!(scope(ip("ff02::1")), 0x2);
+        assert_eq!(scope(ip("ff05::1")), 0x5);
+        assert_eq!(scope(ip("2001:db8::1")), 0xe);
+        assert_eq!(label(ip("::1")), 0);
+        assert_eq!(label(ip("2001:db8::1")), 1);
+        assert_eq!(label(ip("2002:c000:201::1")), 2);
+        assert_eq!(label(ip("::192.0.2.1")), 3);
+        assert_eq!(label(ip("::ffff:192.0.2.1")), 4);
+        assert_eq!(label(ip("192.0.2.1")), 4);
+        assert_eq!(label(ip("2001:0:1::1")), 5);
+        assert_eq!(label(ip("fd00::1")), 13);
+        assert_eq!(label(ip("fec0::1")), 11);
+        assert_eq!(label(ip("3ffe::1")), 12);
+    }
+
+    #[test]
+    fn test_compare_sources() {
+        let (global, link_local) = (cidr("2001:db8:1::2/64"), cidr("fe80::2/64"));
+        let dst = ip("2001:db8:1::9");
+        // rule 1: the destination itself
+        assert_eq!(
+            compare_sources(global, cidr("2001:db8:1::9/64"), dst, None),
+            Ordering::Less
+        );
+        // rule 2: the scope of the destination
+        assert_eq!(
+            compare_sources(global, link_local, dst, None),
+            Ordering::Greater
+        );
+        assert_eq!(
+            compare_sources(global, link_local, ip("fe80::9"), None),
+            Ordering::Less
+        );
+        // rule 5.5: the prefix of the next hop decides before the longest match
+        let (a, b) = (cidr("2001:db8:1::2/64"), cidr("2001:db8:2::2/64"));
+        let off_link = ip("2001:db8:1:1::1");
+        assert_eq!(compare_sources(a, b, off_link, None), Ordering::Greater);
+        assert_eq!(
+            compare_sources(a, b, off_link, Some(ip("2001:db8:2::1"))),
+            Ordering::Less
+        );
+        // rule 6: the label of the destination
+        let six_to_four = cidr("2002:c000:201::1/48");
+        assert_eq!(
+            compare_sources(six_to_four, global, ip("2002:c000:202::1"), None),
+            Ordering::Greater
+        );
+        // rule 9: the longest matching prefix, up to the prefix length of the source
+        let (near, far) = (cidr("10.0.0.5/24"), cidr("192.168.1.5/24"));
+        assert_eq!(
+            compare_sources(near, far, ip("10.0.1.1"), None),
+            Ordering::Greater
+        );
+        assert_eq!(
+            compare_sources(
+                cidr("10.0.0.5/8"),
+                cidr("10.0.1.5/24"),
+                ip("10.0.1.1"),
+                None
+            ),
+            Ordering::Less
+        );
+    }
+
+    #[test]
+    fn test_select_source() {
+        let addresses = vec![
+            cidr("10.0.0.2/24"),
+            cidr("2001:db8:1::2/64"),
+            cidr("2001:db8:2::2/64"),
+            cidr("fe80::2/64"),
+        ];
+        let dst = ip("2001:db8:ffff::1");
+        assert_eq!(
+            select_source(&addresses, dst, Some(ip("2001:db8:2::1"))),
+            Some(ip("2001:db8:2::2"))
+        );
+        assert_eq!(
+            select_source(&addresses, ip("8.8.8.8"), Some(ip("10.0.0.1"))),
+            Some(ip("10.0.0.2"))
+        );
+        assert_eq!(select_source(&addresses[1..], ip("8.8.8.8"), None), None);
+    }
+
+    #[test]
+    fn test_addresses_and_routes() {
+        let mut iface = interface("routing-test");
+        let primary = ip("10.0.0.2");
+        assert_eq!(next_hop(&mut iface, ip("10.0.0.9")), Some(ip("10.0.0.9")));
+        assert_eq!(next_hop(&mut iface, ip("8.8.8.8")), Some(ip("10.0.0.1")));
+
+        add_address(&mut iface, ip("192.168.7.2"), 24).unwrap();
+        assert!(iface.has_ip(ip("192.168.7.2")));
+        let err = add_address(&mut iface, ip("192.168.7.2"), 24).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+        let err = add_address(&mut iface, ip("224.0.0.1"), 24).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(
+            source_address(&mut iface, ip("192.168.7.9")),
+            Some(ip("192.168.7.2"))
+        );
+
+        // the route through a gateway of the added network selects its address (rule 5.5)
+        let r = route("172.16.0.0/12 via 192.168.7.1");
+        add_route(&mut iface, &r);
+        assert_eq!(
+            next_hop(&mut iface, ip("172.16.5.5")),
+            Some(ip("192.168.7.1"))
+        );
+        assert_eq!(
+            source_address(&mut iface, ip("172.16.5.5")),
+            Some(ip("192.168.7.2"))
+        );
+        // the default gateway is in the network of the primary address
+        assert_eq!(source_address(&mut iface, ip("172.32.0.1")), Some(primary));
+        remove_route(&mut iface, &r);
+        assert_eq!(next_hop(&mut iface, ip("172.16.5.5")), Some(ip("10.0.0.1")));
+        assert_eq!(source_address(&mut iface, ip("172.16.5.5")), Some(primary));
+
+        let err = remove_address(&mut iface, primary).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        remove_address(&mut iface, ip("192.168.7.2")).unwrap();
+        assert!(!iface.has_ip(ip("192.168.7.2")));
+        let err = remove_address(&mut iface, ip("192.168.7.2")).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        assert_eq!(source_address(&mut iface, ip("2001:db8::1")), None);
+    }
+}
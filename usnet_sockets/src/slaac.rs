@@ -0,0 +1,681 @@
+/// IPv6 stateless address autoconfiguration (RFC 4862) with router discovery (RFC 4861)
+/// A link-local address and one address per autonomous prefix of the router advertisements
+/// are formed with the EUI-64 interface identifier of the MAC address. Each address is
+/// only added to the interface after duplicate address detection found no other user.
+/// The default router, the link MTU and the RDNSS option (RFC 8106) are taken from the
+/// router advertisements. Addresses are removed when the valid lifetime of their prefix ends
+/// and the default router when its router lifetime ends, unless a new advertisement extends them.
+/// The ICMPv6 packets are sent and received through a raw socket
+/// because smoltcp ignores router advertisements.
+use parking_lot::RwLock;
+use std::collections::HashMap;
+use std::net::Ipv6Addr;
+
+use smoltcp;
+use smoltcp::socket::{RawPacketMetadata, RawSocket, RawSocketBuffer, SocketHandle, SocketSet};
+use smoltcp::time::{Duration, Instant};
+use smoltcp::wire::{EthernetAddress, IpAddress, IpCidr, IpProtocol, IpVersion, Ipv6Address};
+
+use crate::device::StcpBackendInterface;
+
+const IPV6_HEADER_LEN: usize = 40;
+const ICMPV6_ROUTER_SOLICIT: u8 = 133;
+const ICMPV6_ROUTER_ADVERT: u8 = 134;
+const ICMPV6_NEIGHBOR_SOLICIT: u8 = 135;
+const ICMPV6_NEIGHBOR_ADVERT: u8 = 136;
+const NDISC_OPTION_SOURCE_LLADDR: u8 = 1;
+const NDISC_OPTION_PREFIX_INFO: u8 = 3;
+const NDISC_OPTION_MTU: u8 = 5;
+const NDISC_OPTION_RDNSS: u8 = 25;
+const PREFIX_FLAG_AUTONOMOUS: u8 = 0x40;
+const RETRANS_TIMER_MS: u64 = 1000; // wait time for a reply to the DAD neighbor solicitation
+const RTR_SOLICITATION_INTERVAL_SECS: u64 = 4;
+const MAX_RTR_SOLICITATIONS: u8 = 3;
+const INFINITE_LIFETIME: u32 = 0xffff_ffff;
+const MIN_VALID_LIFETIME_SECS: u64 = 2 * 60 * 60; // RFC 4862 5.5.3 e)
+
+/// Addresses and router information learned through SLAAC
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SlaacStatus {
+    /// Addresses which passed duplicate address detection and were added to the interface
+    pub addresses: Vec<Ipv6Addr>,
+    /// Addresses which are already used by another node on the link
+    pub duplicates: Vec<Ipv6Addr>,
+    /// Addresses whose preferred lifetime ended, they stay on the interface until the
+    /// valid lifetime ends
+    pub deprecated: Vec<Ipv6Addr>,
+    pub default_router: Option<Ipv6Addr>,
+    pub mtu: Option<u32>,
+    pub dns_servers: Vec<Ipv6Addr>,
+}
+
+lazy_static! {
+    static ref STATUS: RwLock<SlaacStatus> = RwLock::new(SlaacStatus::default());
+}
+
+/// Returns the state of the IPv6 address autoconfiguration
+pub fn slaac_status() -> SlaacStatus {
+    STATUS.read().clone()
+}
+
+pub(crate) struct Slaac {
+    handle: SocketHandle,
+    ethernet_addr: EthernetAddress,
+    mtu_limit: Option<usize>,
+    link_local: Option<Ipv6Addr>,           // once DAD passed
+    tentative: Option<(Ipv6Addr, Instant)>, // address under DAD and the end of the DAD
+    queue: Vec<Ipv6Addr>,                   // addresses waiting for DAD
+    solicitations: u8,
+    next_solicitation: Instant,
+    got_advert: bool,
+    lifetimes: HashMap<Ipv6Addr, PrefixLifetimes>, // of the addresses formed from prefixes
+    router_expiry: Option<Instant>,
+    status: SlaacStatus,
+}
+
+/// End of the lifetimes of an address, None for the infinite lifetime
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct PrefixLifetimes {
+    valid_until: Option<Instant>,
+    preferred_until: Option<Instant>,
+}
+
+impl PrefixLifetimes {
+    fn new(timestamp: Instant, valid_lifetime: u32, preferred_lifetime: u32) -> PrefixLifetimes {
+        PrefixLifetimes {
+            valid_until: lifetime_end(timestamp, valid_lifetime),
+            preferred_until: lifetime_end(timestamp, preferred_lifetime),
+        }
+    }
+
+    /// Applies the lifetimes of a new advertisement of the prefix, a valid lifetime of less than
+    /// two hours only shortens the remaining one down to two hours (RFC 4862 5.5.3 e), so that
+    /// an unauthenticated advertisement cannot remove the address
+    fn update(&mut self, timestamp: Instant, valid_lifetime: u32, preferred_lifetime: u32) {
+        self.preferred_until = lifetime_end(timestamp, preferred_lifetime);
+        let received = lifetime_end(timestamp, valid_lifetime);
+        let two_hours = timestamp + Duration::from_secs(MIN_VALID_LIFETIME_SECS);
+        let extends = match (received, self.valid_until) {
+            (None, _) => true,
+            (Some(_), None) => false,
+            (Some(received), Some(remaining)) => received > remaining,
+        };
+        if valid_lifetime as u64 > MIN_VALID_LIFETIME_SECS || extends {
+            self.valid_until = received;
+        } else if self.valid_until.map_or(true, |end| end > two_hours) {
+            self.valid_until = Some(two_hours);
+        }
+    }
+
+    fn is_valid(&self, timestamp: Instant) -> bool {
+        self.valid_until.map_or(true, |end| timestamp < end)
+    }
+
+    fn is_preferred(&self, timestamp: Instant) -> bool {
+        self.preferred_until.map_or(true, |end| timestamp < end)
+    }
+}
+
+fn lifetime_end(timestamp: Instant, lifetime: u32) -> Option<Instant> {
+    if lifetime == INFINITE_LIFETIME {
+        None
+    } else {
+        Some(timestamp + Duration::from_secs(lifetime as u64))
+    }
+}
+
+impl Slaac {
+    pub fn new(
+        sockets: &mut SocketSet<'static>,
+        ethernet_addr: EthernetAddress,
+        mtu_limit: Option<usize>,
+        timestamp: Instant,
+    ) -> Slaac {
+        let rx_buffer = RawSocketBuffer::new(vec![RawPacketMetadata::EMPTY; 8], vec![0; 8192]);
+        let tx_buffer = RawSocketBuffer::new(vec![RawPacketMetadata::EMPTY; 4], vec![0; 1024]);
+        let handle = sockets.add(RawSocket::new(
+            IpVersion::Ipv6,
+            IpProtocol::Icmpv6,
+            rx_buffer,
+            tx_buffer,
+        ));
+        let link_local =
+            address_from_prefix(&Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, 0), &ethernet_addr);
+        Slaac {
+            handle: handle,
+            ethernet_addr: ethernet_addr,
+            mtu_limit: mtu_limit,
+            link_local: None,
+            tentative: None,
+            queue: vec![link_local],
+            solicitations: 0,
+            next_solicitation: timestamp,
+            got_advert: false,
+            lifetimes: HashMap::new(),
+            router_expiry: None,
+            status: SlaacStatus::default(),
+        }
+    }
+
+    /// Processes router advertisements and timers, returns the new status if it changed
+    pub fn poll(
+        &mut self,
+        iface: &mut StcpBackendInterface,
+        sockets: &mut SocketSet,
+        timestamp: Instant,
+    ) -> Option<SlaacStatus> {
+        let before = self.status.clone();
+        let mut packets = vec![];
+        {
+            let mut socket = sockets.get::<RawSocket>(self.handle);
+            while let Ok(packet) = socket.recv() {
+                packets.push(packet.to_vec());
+            }
+        }
+        for packet in packets.iter() {
+            self.process(iface, packet, timestamp);
+        }
+        self.expire(iface, timestamp);
+        if let Some((address, dad_end)) = self.tentative {
+            if timestamp >= dad_end {
+                self.tentative = None;
+                iface.update_ip_addrs(|addrs| {
+                    addrs.push(IpCidr::new(Ipv6Address::from(address).into(), 64))
+                });
+                info!("SLAAC address: {}", address);
+                self.status.addresses.push(address);
+                if self.link_local.is_none() {
+                    self.link_local = Some(address);
+                    self.next_solicitation = timestamp;
+                }
+            }
+        }
+        if self.tentative.is_none() && !self.queue.is_empty() {
+            let address = self.queue.remove(0);
+            let packet = neighbor_solicitation(&address);
+            if self.send(sockets, packet) {
+                self.tentative =
+                    Some((address, timestamp + Duration::from_millis(RETRANS_TIMER_MS)));
+            } else {
+                self.queue.insert(0, address);
+            }
+        }
+        if let Some(link_local) = self.link_local {
+            if !self.got_advert
+                && self.solicitations < MAX_RTR_SOLICITATIONS
+                && timestamp >= self.next_solicitation
+            {
+                let packet = router_solicitation(&link_local, &self.ethernet_addr);
+                if self.send(sockets, packet) {
+                    self.solicitations += 1;
+                    self.next_solicitation =
+                        timestamp + Duration::from_secs(RTR_SOLICITATION_INTERVAL_SECS);
+                }
+            }
+        }
+        if self.status == before {
+            return None;
+        }
+        *STATUS.write() = self.status.clone();
+        Some(self.status.clone())
+    }
+
+    /// Changes the configured MTU limit, the MTU option of the router advertisements still applies
+    pub fn set_mtu_limit(&mut self, iface: &mut StcpBackendInterface, mtu_limit: Option<usize>) {
+        self.mtu_limit = mtu_limit;
+        let limit = match (mtu_limit, self.status.mtu) {
+            (Some(limit), Some(mtu)) => Some(limit.min(mtu as usize)),
+            (limit, mtu) => limit.or(mtu.map(|mtu| mtu as usize)),
+        };
+        iface.set_mtu_limit(limit);
+    }
+
+    /// Time until the next timer of the DAD, router solicitation, or the end of a lifetime,
+    /// None if nothing is pending
+    pub fn next_poll(&self, timestamp: Instant) -> Option<Duration> {
+        let mut next = None;
+        {
+            let mut at = |instant: Instant| {
+                next = Some(next.map_or(instant, |n: Instant| n.min(instant)));
+            };
+            if let Some((_, dad_end)) = self.tentative {
+                at(dad_end);
+            } else if !self.queue.is_empty() {
+                at(timestamp);
+            }
+            if self.link_local.is_some()
+                && !self.got_advert
+                && self.solicitations < MAX_RTR_SOLICITATIONS
+            {
+                at(self.next_solicitation);
+            }
+            for lifetimes in self.lifetimes.values() {
+                if let Some(end) = lifetimes.valid_until {
+                    at(end);
+                }
+                // ended preferred lifetimes are already reflected in the status
+                if let Some(end) = lifetimes.preferred_until.filter(|end| *end > timestamp) {
+                    at(end);
+                }
+            }
+            if let Some(end) = self.router_expiry {
+                at(end);
+            }
+        }
+        next.map(|n| {
+            if n > timestamp {
+                n - timestamp
+            } else {
+                Duration::from_millis(0)
+            }
+        })
+    }
+
+    fn send(&self, sockets: &mut SocketSet, packet: Vec<u8>) -> bool {
+        let mut socket = sockets.get::<RawSocket>(self.handle);
+        match socket.send_slice(&packet) {
+            Ok(()) => true,
+            Err(err) => {
+                debug!("SLAAC send result: {}", err);
+                false
+            }
+        }
+    }
+
+    /// Removes the addresses and the default router whose lifetime ended
+    fn expire(&mut self, iface: &mut StcpBackendInterface, timestamp: Instant) {
+        let expired: Vec<Ipv6Addr> = self
+            .lifetimes
+            .iter()
+            .filter(|&(_, lifetimes)| !lifetimes.is_valid(timestamp))
+            .map(|(address, _)| *address)
+            .collect();
+        for address in expired {
+            let _ = self.lifetimes.remove(&address);
+            self.queue.retain(|a| *a != address);
+            if self.tentative.map(|(t, _)| t) == Some(address) {
+                self.tentative = None;
+            }
+            if let Some(pos) = self.status.addresses.iter().position(|a| *a == address) {
+                let _ = self.status.addresses.remove(pos);
+                let ip = IpAddress::from(Ipv6Address::from(address));
+                iface.update_ip_addrs(|addrs| addrs.retain(|cidr| cidr.address() != ip));
+                info!("SLAAC address expired: {}", address);
+            }
+        }
+        let lifetimes = &self.lifetimes;
+        self.status.deprecated = self
+            .status
+            .addresses
+            .iter()
+            .filter(|a| {
+                lifetimes
+                    .get(a)
+                    .map_or(false, |l| !l.is_preferred(timestamp))
+            })
+            .cloned()
+            .collect();
+        if self.router_expiry.map_or(false, |end| timestamp >= end) {
+            self.router_expiry = None;
+            if let Some(router) = self.status.default_router.take() {
+                info!("SLAAC default router expired: {}", router);
+                iface.set_default_ipv6_route(None);
+            }
+        }
+    }
+
+    fn process(&mut self, iface: &mut StcpBackendInterface, packet: &[u8], timestamp: Instant) {
+        let (src, icmp) = match parse_ndisc(packet) {
+            Some(p) => p,
+            None => return,
+        };
+        match icmp[0] {
+            ICMPV6_NEIGHBOR_ADVERT if icmp.len() >= 24 => {
+                let target = ipv6_from_slice(&icmp[8..24]);
+                if self.tentative.map(|(t, _)| t) == Some(target) {
+                    warn!("SLAAC address {} is used by another node", target);
+                    self.tentative = None;
+                    let _ = self.lifetimes.remove(&target);
+                    self.status.duplicates.push(target);
+                }
+            }
+            ICMPV6_ROUTER_ADVERT if icmp.len() >= 16 => {
+                self.got_advert = true;
+                let router_lifetime = u16::from_be_bytes([icmp[6], icmp[7]]);
+                let router = if router_lifetime > 0 { Some(src) } else { None };
+                self.router_expiry =
+                    router.map(|_| timestamp + Duration::from_secs(router_lifetime as u64));
+                if router != self.status.default_router {
+                    iface.set_default_ipv6_route(router.map(Ipv6Address::from));
+                    self.status.default_router = router;
+                }
+                for (option_type, data) in ndisc_options(&icmp[16..]) {
+                    self.process_option(iface, option_type, data, timestamp);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn process_option(
+        &mut self,
+        iface: &mut StcpBackendInterface,
+        option_type: u8,
+        data: &[u8],
+        timestamp: Instant,
+    ) {
+        match option_type {
+            NDISC_OPTION_PREFIX_INFO if data.len() >= 30 => {
+                let prefix_len = data[0];
+                let flags = data[1];
+                let valid_lifetime = u32::from_be_bytes([data[2], data[3], data[4], data[5]]);
+                let preferred_lifetime = u32::from_be_bytes([data[6], data[7], data[8], data[9]]);
+                let prefix = ipv6_from_slice(&data[14..30]);
+                if flags & PREFIX_FLAG_AUTONOMOUS == 0
+                    || prefix_len != 64
+                    || preferred_lifetime > valid_lifetime
+                {
+                    return;
+                }
+                let address = address_from_prefix(&prefix, &self.ethernet_addr);
+                if let Some(lifetimes) = self.lifetimes.get_mut(&address) {
+                    lifetimes.update(timestamp, valid_lifetime, preferred_lifetime);
+                    return;
+                }
+                let known = self.status.addresses.contains(&address)
+                    || self.status.duplicates.contains(&address)
+                    || self.queue.contains(&address)
+                    || self.tentative.map(|(t, _)| t) == Some(address);
+                if !known && valid_lifetime > 0 {
+                    self.queue.push(address);
+                    let lifetimes =
+                        PrefixLifetimes::new(timestamp, valid_lifetime, preferred_lifetime);
+                    let _ = self.lifetimes.insert(address, lifetimes);
+                }
+            }
+            NDISC_OPTION_MTU if data.len() >= 6 => {
+                let mtu = u32::from_be_bytes([data[2], data[3], data[4], data[5]]);
+                if self.status.mtu != Some(mtu) {
+                    let limit = self
+                        .mtu_limit
+                        .map_or(mtu as usize, |limit| limit.min(mtu as usize));
+                    iface.set_mtu_limit(Some(limit));
+                    self.status.mtu = Some(mtu);
+                }
+            }
+            NDISC_OPTION_RDNSS if data.len() >= 22 => {
+                let lifetime = u32::from_be_bytes([data[2], data[3], data[4], data[5]]);
+                let servers: Vec<Ipv6Addr> = data[6..]
+                    .chunks(16)
+                    .filter(|c| c.len() == 16)
+                    .map(ipv6_from_slice)
+                    .collect();
+                for server in servers {
+                    let pos = self.status.dns_servers.iter().position(|s| *s == server);
+                    match (pos, lifetime) {
+                        (Some(pos), 0) => {
+                            let _ = self.status.dns_servers.remove(pos);
+                        }
+                        (None, lifetime) if lifetime > 0 => self.status.dns_servers.push(server),
+                        _ => {}
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Forms the address from a /64 prefix and the EUI-64 interface identifier
+fn address_from_prefix(prefix: &Ipv6Addr, ethernet_addr: &EthernetAddress) -> Ipv6Addr {
+    let mut a = prefix.octets();
+    let m = ethernet_addr.as_bytes();
+    a[8] = m[0] ^ 0x02;
+    a[9] = m[1];
+    a[10] = m[2];
+    a[11] = 0xff;
+    a[12] = 0xfe;
+    a[13] = m[3];
+    a[14] = m[4];
+    a[15] = m[5];
+    Ipv6Addr::from(a)
+}
+
+pub(crate) fn solicited_node(address: &Ipv6Addr) -> Ipv6Addr {
+    let a = address.octets();
+    Ipv6Addr::from([
+        0xff, 0x02, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x01, 0xff, a[13], a[14], a[15],
+    ])
+}
+
+fn ipv6_from_slice(s: &[u8]) -> Ipv6Addr {
+    let mut a = [0u8; 16];
+    a.copy_from_slice(&s[..16]);
+    Ipv6Addr::from(a)
+}
+
+/// Returns the source address and the ICMPv6 message of a valid neighbor discovery packet
+fn parse_ndisc(packet: &[u8]) -> Option<(Ipv6Addr, &[u8])> {
+    if packet.len() < IPV6_HEADER_LEN + 4 || packet[0] >> 4 != 6 {
+        return None;
+    }
+    let payload_len = u16::from_be_bytes([packet[4], packet[5]]) as usize;
+    // only packets from the link without extension headers, hop limit 255 as in RFC 4861
+    if packet[6] != 58 || packet[7] != 255 || packet.len() < IPV6_HEADER_LEN + payload_len {
+        return None;
+    }
+    let src = ipv6_from_slice(&packet[8..24]);
+    let dst = ipv6_from_slice(&packet[24..40]);
+    let icmp = &packet[IPV6_HEADER_LEN..IPV6_HEADER_LEN + payload_len];
+    if icmp.len() < 4 || icmp[1] != 0 || icmpv6_checksum(&src, &dst, icmp) != 0 {
+        return None;
+    }
+    Some((src, icmp))
+}
+
+fn ndisc_options(mut options: &[u8]) -> Vec<(u8, &[u8])> {
+    let mut r = vec![];
+    while options.len() >= 8 {
+        let len = options[1] as usize * 8;
+        if len == 0 || len > options.len() {
+            break;
+        }
+        r.push((options[0], &options[2..len]));
+        options = &options[len..];
+    }
+    r
+}
+
+fn icmpv6_checksum(src: &Ipv6Addr, dst: &Ipv6Addr, icmp: &[u8]) -> u16 {
+    let mut sum: u32 = 0;
+    let mut add = |data: &[u8]| {
+        for chunk in data.chunks(2) {
+            let word = if chunk.len() == 2 {
+                u16::from_be_bytes([chunk[0], chunk[1]])
+            } else {
+                u16::from_be_bytes([chunk[0], 0])
+            };
+            sum += word as u32;
+        }
+    };
+    add(&src.octets());
+    add(&dst.octets());
+    add(&(icmp.len() as u32).to_be_bytes());
+    add(&[0, 0, 0, 58]);
+    add(icmp);
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+pub(crate) fn ipv6_packet(src: &Ipv6Addr, dst: &Ipv6Addr, mut icmp: Vec<u8>) -> Vec<u8> {
+    let checksum = icmpv6_checksum(src, dst, &icmp);
+    icmp[2..4].copy_from_slice(&checksum.to_be_bytes());
+    let mut packet = vec![0x60, 0, 0, 0];
+    packet.extend_from_slice(&(icmp.len() as u16).to_be_bytes());
+    packet.push(58); // next header ICMPv6
+    packet.push(255); // hop limit
+    packet.extend_from_slice(&src.octets());
+    packet.extend_from_slice(&dst.octets());
+    packet.extend_from_slice(&icmp);
+    packet
+}
+
+/// Neighbor solicitation for the duplicate address detection, sent from the unspecified address
+fn neighbor_solicitation(target: &Ipv6Addr) -> Vec<u8> {
+    let mut icmp = vec![ICMPV6_NEIGHBOR_SOLICIT, 0, 0, 0, 0, 0, 0, 0];
+    icmp.extend_from_slice(&target.octets());
+    ipv6_packet(&Ipv6Addr::UNSPECIFIED, &solicited_node(target), icmp)
+}
+
+fn router_solicitation(src: &Ipv6Addr, ethernet_addr: &EthernetAddress) -> Vec<u8> {
+    let mut icmp = vec![ICMPV6_ROUTER_SOLICIT, 0, 0, 0, 0, 0, 0, 0];
+    icmp.extend_from_slice(&[NDISC_OPTION_SOURCE_LLADDR, 1]);
+    icmp.extend_from_slice(ethernet_addr.as_bytes());
+    let all_routers = Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 2);
+    ipv6_packet(src, &all_routers, icmp)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        address_from_prefix, icmpv6_checksum, ndisc_options, neighbor_solicitation, parse_ndisc,
+        PrefixLifetimes, INFINITE_LIFETIME, NDISC_OPTION_MTU, NDISC_OPTION_PREFIX_INFO,
+        NDISC_OPTION_SOURCE_LLADDR,
+    };
+    use smoltcp::time::{Duration, Instant};
+    use smoltcp::wire::EthernetAddress;
+    use std::net::Ipv6Addr;
+
+    /// Router advertisement from fe80::1 with a router lifetime of 1800 s, the source link-layer
+    /// address 52:54:00:00:00:01, an MTU of 1480, and the autonomous prefix 2001:db8:1::/64
+    /// with a valid lifetime of 86400 s and a preferred lifetime of 14400 s
+    const ROUTER_ADVERT: [u8; 104] = [
+        0x60, 0x00, 0x00, 0x00, 0x00, 0x40, 0x3a, 0xff, 0xfe, 0x80, 0x00, 0x00, //
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, //
+        0xff, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, //
+        0x00, 0x00, 0x00, 0x01, 0x86, 0x00, 0xdb, 0x97, 0x40, 0x00, 0x07, 0x08, //
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x01, 0x52, 0x54, //
+        0x00, 0x00, 0x00, 0x01, 0x05, 0x01, 0x00, 0x00, 0x00, 0x00, 0x05, 0xc8, //
+        0x03, 0x04, 0x40, 0xc0, 0x00, 0x01, 0x51, 0x80, 0x00, 0x00, 0x38, 0x40, //
+        0x00, 0x00, 0x00, 0x00, 0x20, 0x01, 0x0d, 0xb8, 0x00, 0x01, 0x00, 0x00, //
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    ];
+
+    #[test]
+    fn test_address_from_prefix() {
+        let mac = EthernetAddress([0x52, 0x54, 0x00, 0x12, 0x34, 0x56]);
+        let prefix: Ipv6Addr = "2001:db8:1::".parse().unwrap();
+        let expected: Ipv6Addr = "2001:db8:1::5054:ff:fe12:3456".parse().unwrap();
+        assert_eq!(address_from_prefix(&prefix, &mac), expected);
+        // the universal/local bit is inverted, not set
+        let local = EthernetAddress([0x02, 0, 0, 0, 0, 0x01]);
+        let link_local: Ipv6Addr = "fe80::ff:fe00:1".parse().unwrap();
+        assert_eq!(
+            address_from_prefix(&"fe80::".parse().unwrap(), &local),
+            link_local
+        );
+    }
+
+    #[test]
+    fn test_icmpv6_checksum() {
+        let src: Ipv6Addr = "fe80::1".parse().unwrap();
+        let dst: Ipv6Addr = "ff02::1".parse().unwrap();
+        let mut icmp = ROUTER_ADVERT[40..].to_vec();
+        // the checksum over a message with a correct checksum field is zero
+        assert_eq!(icmpv6_checksum(&src, &dst, &icmp), 0);
+        icmp[2] = 0;
+        icmp[3] = 0;
+        assert_eq!(icmpv6_checksum(&src, &dst, &icmp), 0xdb97);
+
+        let dad = neighbor_solicitation(&"fe80::5054:ff:fe12:3456".parse().unwrap());
+        assert_eq!(&dad[40..44], &[135, 0, 0xc4, 0x02]);
+        assert_eq!(
+            &dad[24..40],
+            &"ff02::1:ff12:3456".parse::<Ipv6Addr>().unwrap().octets()
+        );
+    }
+
+    #[test]
+    fn test_parse_ndisc() {
+        let (src, icmp) = parse_ndisc(&ROUTER_ADVERT).unwrap();
+        assert_eq!(src, "fe80::1".parse::<Ipv6Addr>().unwrap());
+        assert_eq!(icmp, &ROUTER_ADVERT[40..]);
+        assert_eq!(u16::from_be_bytes([icmp[6], icmp[7]]), 1800);
+
+        let mut corrupted = ROUTER_ADVERT;
+        corrupted[60] ^= 1;
+        assert_eq!(parse_ndisc(&corrupted), None);
+        // forwarded by a router
+        let mut forwarded = ROUTER_ADVERT;
+        forwarded[7] = 64;
+        assert_eq!(parse_ndisc(&forwarded), None);
+        // not ICMPv6
+        let mut udp = ROUTER_ADVERT;
+        udp[6] = 17;
+        assert_eq!(parse_ndisc(&udp), None);
+        // the payload length exceeds the packet
+        assert_eq!(parse_ndisc(&ROUTER_ADVERT[..100]), None);
+        // a non-zero code
+        let mut code = ROUTER_ADVERT;
+        code[41] = 1;
+        assert_eq!(parse_ndisc(&code), None);
+    }
+
+    #[test]
+    fn test_ndisc_options() {
+        let options = ndisc_options(&ROUTER_ADVERT[56..]);
+        let types: Vec<u8> = options.iter().map(|&(t, _)| t).collect();
+        assert_eq!(
+            types,
+            vec![
+                NDISC_OPTION_SOURCE_LLADDR,
+                NDISC_OPTION_MTU,
+                NDISC_OPTION_PREFIX_INFO
+            ]
+        );
+        assert_eq!(options[0].1, &[0x52, 0x54, 0, 0, 0, 0x01]);
+        assert_eq!(options[1].1, &[0, 0, 0, 0, 0x05, 0xc8]);
+        let prefix = options[2].1;
+        assert_eq!(prefix.len(), 30);
+        assert_eq!((prefix[0], prefix[1]), (64, 0xc0));
+        assert_eq!(&prefix[14..18], &[0x20, 0x01, 0x0d, 0xb8]);
+
+        // a zero length ends the options, as does a length beyond the end
+        assert!(ndisc_options(&[1, 0, 0, 0, 0, 0, 0, 0]).is_empty());
+        let mut truncated = ROUTER_ADVERT[56..].to_vec();
+        truncated.truncate(truncated.len() - 8);
+        assert_eq!(ndisc_options(&truncated).len(), 2);
+    }
+
+    #[test]
+    fn test_prefix_lifetimes() {
+        let start = Instant::from_secs(1000);
+        let after = |secs| start + Duration::from_secs(secs);
+        let mut lifetimes = PrefixLifetimes::new(start, 86400, 14400);
+        assert!(lifetimes.is_preferred(after(14399)));
+        assert!(!lifetimes.is_preferred(after(14400)));
+        assert!(lifetimes.is_valid(after(86399)));
+        assert!(!lifetimes.is_valid(after(86400)));
+
+        // a short valid lifetime only reduces the remaining one to two hours
+        lifetimes.update(start, 0, 0);
+        assert!(!lifetimes.is_preferred(start));
+        assert!(lifetimes.is_valid(after(7199)));
+        assert!(!lifetimes.is_valid(after(7200)));
+        lifetimes.update(after(3600), 60, 0);
+        assert!(!lifetimes.is_valid(after(7200)));
+        // a longer one extends it
+        lifetimes.update(after(3600), 7000, 3600);
+        assert!(lifetimes.is_valid(after(10599)));
+        assert!(lifetimes.is_preferred(after(7199)));
+
+        let mut infinite = PrefixLifetimes::new(start, INFINITE_LIFETIME, INFINITE_LIFETIME);
+        assert!(infinite.is_valid(after(1 << 40)));
+        infinite.update(start, 10, 10);
+        assert!(infinite.is_valid(after(7199)));
+        assert!(!infinite.is_valid(after(7200)));
+        assert!(!infinite.is_preferred(after(10)));
+    }
+}
@@ -0,0 +1,306 @@
+//! Stack-wide counters of the userspace network stack
+//! The counters are global for the process and maintained with relaxed atomics
+//! by the device layer and the interface polling, so that they do not slow down
+//! the datapath. Use stats() to get a snapshot. The values are cumulative and
+//! never reset, the difference of two snapshots can be calculated with delta().
+//! They are the sum over all stacks and interfaces of the process. The device of
+//! each stack also counts its own frames, which interface_stats() returns with the
+//! occupancy of the netmap rings or of the io_uring of tap and macvtap.
+use std::ops::Sub;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use smoltcp;
+
+use crate::congestion::Congestion;
+
+extern crate usnet_devices;
+pub use self::usnet_devices::RingOccupancy;
+
+pub(crate) struct Counters {
+    pub rx_packets: AtomicU64,
+    pub rx_bytes: AtomicU64,
+    pub tx_packets: AtomicU64,
+    pub tx_bytes: AtomicU64,
+    pub rx_no_socket: AtomicU64,
+    pub rx_buffer_full: AtomicU64,
+    pub rx_errors: AtomicU64,
+    pub tx_tcp_resets: AtomicU64,
+    pub tx_arp_requests: AtomicU64,
+    pub unresolved_neighbors: AtomicU64,
+    pub loopback_frames: AtomicU64,
+    pub capture_dropped: AtomicU64,
+    pub spin_time_us: AtomicU64,
+    pub blocked_time_us: AtomicU64,
+}
+
+pub(crate) static COUNTERS: Counters = Counters {
+    rx_packets: AtomicU64::new(0),
+    rx_bytes: AtomicU64::new(0),
+    tx_packets: AtomicU64::new(0),
+    tx_bytes: AtomicU64::new(0),
+    rx_no_socket: AtomicU64::new(0),
+    rx_buffer_full: AtomicU64::new(0),
+    rx_errors: AtomicU64::new(0),
+    tx_tcp_resets: AtomicU64::new(0),
+    tx_arp_requests: AtomicU64::new(0),
+    unresolved_neighbors: AtomicU64::new(0),
+    loopback_frames: AtomicU64::new(0),
+    capture_dropped: AtomicU64::new(0),
+    spin_time_us: AtomicU64::new(0),
+    blocked_time_us: AtomicU64::new(0),
+};
+
+pub(crate) fn inc(counter: &AtomicU64) {
+    counter.fetch_add(1, Ordering::Relaxed);
+}
+
+pub(crate) fn add(counter: &AtomicU64, value: u64) {
+    counter.fetch_add(value, Ordering::Relaxed);
+}
+
+/// Snapshot of the stack-wide counters
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct StackStats {
+    /// Frames received on the interface
+    pub rx_packets: u64,
+    /// Bytes received on the interface (including the Ethernet header)
+    pub rx_bytes: u64,
+    /// Frames transmitted on the interface
+    pub tx_packets: u64,
+    /// Bytes transmitted on the interface (including the Ethernet header)
+    pub tx_bytes: u64,
+    /// Received UDP datagrams and TCP segments for which no socket matched, counted when the
+    /// stack answers them with an ICMPv4 or ICMPv6 port unreachable error or a TCP reset;
+    /// datagrams to broadcast or multicast addresses and resets get no answer and are missing
+    pub rx_no_socket: u64,
+    /// Ingress packets dropped because the socket buffer was full
+    pub rx_buffer_full: u64,
+    /// Ingress packets that could not be processed for other reasons
+    pub rx_errors: u64,
+    /// TCP segments sent with the RST flag, including those for unmatched segments
+    pub tx_tcp_resets: u64,
+    /// ARP requests sent, each one is caused by a neighbor cache miss
+    pub tx_arp_requests: u64,
+    /// ARP requests and neighbor solicitations which were not answered in time, i.e., the
+    /// packets to the neighbor could not be sent
+    pub unresolved_neighbors: u64,
+    /// Frames to the own addresses which were handed back to the stack instead of sent
+    pub loopback_frames: u64,
+    /// Frames not written to the pcap capture because its queue was full
+    pub capture_dropped: u64,
+    /// Microseconds the background threads busy-polled within the spin budget after traffic
+    pub spin_time_us: u64,
+    /// Microseconds the background threads were blocked waiting for the backend or a timeout
+    pub blocked_time_us: u64,
+}
+
+impl StackStats {
+    /// Counter increase since an earlier snapshot
+    pub fn delta(&self, earlier: &StackStats) -> StackStats {
+        *self - *earlier
+    }
+}
+
+impl Sub for StackStats {
+    type Output = StackStats;
+    fn sub(self, other: StackStats) -> StackStats {
+        StackStats {
+            rx_packets: self.rx_packets.wrapping_sub(other.rx_packets),
+            rx_bytes: self.rx_bytes.wrapping_sub(other.rx_bytes),
+            tx_packets: self.tx_packets.wrapping_sub(other.tx_packets),
+            tx_bytes: self.tx_bytes.wrapping_sub(other.tx_bytes),
+            rx_no_socket: self.rx_no_socket.wrapping_sub(other.rx_no_socket),
+            rx_buffer_full: self.rx_buffer_full.wrapping_sub(other.rx_buffer_full),
+            rx_errors: self.rx_errors.wrapping_sub(other.rx_errors),
+            tx_tcp_resets: self.tx_tcp_resets.wrapping_sub(other.tx_tcp_resets),
+            tx_arp_requests: self.tx_arp_requests.wrapping_sub(other.tx_arp_requests),
+            unresolved_neighbors: self
+                .unresolved_neighbors
+                .wrapping_sub(other.unresolved_neighbors),
+            loopback_frames: self.loopback_frames.wrapping_sub(other.loopback_frames),
+            capture_dropped: self.capture_dropped.wrapping_sub(other.capture_dropped),
+            spin_time_us: self.spin_time_us.wrapping_sub(other.spin_time_us),
+            blocked_time_us: self.blocked_time_us.wrapping_sub(other.blocked_time_us),
+        }
+    }
+}
+
+/// Frames which passed the device of one stack, counted next to the stack-wide counters
+#[derive(Debug, Default)]
+pub(crate) struct InterfaceCounters {
+    rx_packets: AtomicU64,
+    rx_bytes: AtomicU64,
+    tx_packets: AtomicU64,
+    tx_bytes: AtomicU64,
+}
+
+impl InterfaceCounters {
+    /// Counts a frame received on the interface
+    pub fn count_rx(&self, len: usize) {
+        inc(&self.rx_packets);
+        add(&self.rx_bytes, len as u64);
+        inc(&COUNTERS.rx_packets);
+        add(&COUNTERS.rx_bytes, len as u64);
+    }
+
+    /// Counts a frame transmitted on the interface
+    pub fn count_tx(&self, len: usize) {
+        inc(&self.tx_packets);
+        add(&self.tx_bytes, len as u64);
+        inc(&COUNTERS.tx_packets);
+        add(&COUNTERS.tx_bytes, len as u64);
+    }
+
+    /// Returns a snapshot with the occupancy of the RX and TX rings of the device
+    pub fn snapshot(&self, rings: Option<(RingOccupancy, RingOccupancy)>) -> InterfaceStats {
+        InterfaceStats {
+            rx_packets: self.rx_packets.load(Ordering::Relaxed),
+            rx_bytes: self.rx_bytes.load(Ordering::Relaxed),
+            tx_packets: self.tx_packets.load(Ordering::Relaxed),
+            tx_bytes: self.tx_bytes.load(Ordering::Relaxed),
+            rx_ring: rings.map(|(rx, _)| rx),
+            tx_ring: rings.map(|(_, tx)| tx),
+        }
+    }
+}
+
+/// Snapshot of the counters of the interface of one stack, see StcpNetRef::interface_stats
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct InterfaceStats {
+    /// Frames received on the interface
+    pub rx_packets: u64,
+    /// Bytes received on the interface (including the Ethernet header)
+    pub rx_bytes: u64,
+    /// Frames transmitted on the interface
+    pub tx_packets: u64,
+    /// Bytes transmitted on the interface (including the Ethernet header)
+    pub tx_bytes: u64,
+    /// Received frames in the RX rings of netmap or in the RX buffers of the io_uring of tap
+    /// and macvtap, None for the other backends and without io_uring
+    pub rx_ring: Option<RingOccupancy>,
+    /// Frames in the TX rings or buffers which were not transmitted yet
+    pub tx_ring: Option<RingOccupancy>,
+}
+
+/// Congestion state of a TCP connection on the stack, see TcpStream::stats
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TcpStats {
+    /// Congestion control algorithm of the connection
+    pub congestion: Congestion,
+    /// Congestion window in bytes, None without congestion control
+    pub cwnd: Option<usize>,
+    /// Slow start threshold in bytes, None before the first loss
+    pub ssthresh: Option<usize>,
+    /// Smoothed round-trip time, None before the first sample
+    pub srtt: Option<Duration>,
+    /// Bytes in flight or waiting in the smoltcp socket
+    pub in_flight: usize,
+    /// Losses which reduced the congestion window
+    pub losses: u64,
+}
+
+/// Returns a snapshot of the stack-wide counters
+pub fn stats() -> StackStats {
+    let c = &COUNTERS;
+    StackStats {
+        rx_packets: c.rx_packets.load(Ordering::Relaxed),
+        rx_bytes: c.rx_bytes.load(Ordering::Relaxed),
+        tx_packets: c.tx_packets.load(Ordering::Relaxed),
+        tx_bytes: c.tx_bytes.load(Ordering::Relaxed),
+        rx_no_socket: c.rx_no_socket.load(Ordering::Relaxed),
+        rx_buffer_full: c.rx_buffer_full.load(Ordering::Relaxed),
+        rx_errors: c.rx_errors.load(Ordering::Relaxed),
+        tx_tcp_resets: c.tx_tcp_resets.load(Ordering::Relaxed),
+        tx_arp_requests: c.tx_arp_requests.load(Ordering::Relaxed),
+        unresolved_neighbors: c.unresolved_neighbors.load(Ordering::Relaxed),
+        loopback_frames: c.loopback_frames.load(Ordering::Relaxed),
+        capture_dropped: c.capture_dropped.load(Ordering::Relaxed),
+        spin_time_us: c.spin_time_us.load(Ordering::Relaxed),
+        blocked_time_us: c.blocked_time_us.load(Ordering::Relaxed),
+    }
+}
+
+/// Accounts an error returned by the interface poll which dropped an ingress packet
+pub(crate) fn record_poll_error(err: &smoltcp::Error) {
+    match err {
+        smoltcp::Error::Exhausted => inc(&COUNTERS.rx_buffer_full),
+        _ => inc(&COUNTERS.rx_errors),
+    }
+}
+
+/// Starts a background thread which logs the counter increase every interval
+pub fn spawn_stats_logger(interval: Duration) -> JoinHandle<()> {
+    thread::spawn(move || {
+        let mut last = stats();
+        loop {
+            thread::sleep(interval);
+            let current = stats();
+            info!("stats delta: {:?}", current.delta(&last));
+            last = current;
+        }
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::{stats, InterfaceCounters, InterfaceStats, RingOccupancy, StackStats};
+
+    #[test]
+    fn test_delta() {
+        let earlier = StackStats {
+            rx_packets: 10,
+            tx_bytes: 1500,
+            rx_no_socket: 1,
+            blocked_time_us: u64::max_value(),
+            ..Default::default()
+        };
+        let current = StackStats {
+            rx_packets: 25,
+            tx_bytes: 4500,
+            rx_no_socket: 1,
+            blocked_time_us: 4,
+            ..Default::default()
+        };
+        let delta = current.delta(&earlier);
+        assert_eq!(delta.rx_packets, 15);
+        assert_eq!(delta.tx_bytes, 3000);
+        assert_eq!(delta.rx_no_socket, 0);
+        // a counter which wrapped around still gives the increase
+        assert_eq!(delta.blocked_time_us, 5);
+        assert_eq!(delta, current - earlier);
+        assert_eq!(current.delta(&current), StackStats::default());
+    }
+
+    #[test]
+    fn test_interface_counters() {
+        let before = stats();
+        let counters = InterfaceCounters::default();
+        let other = InterfaceCounters::default();
+        counters.count_rx(60);
+        counters.count_rx(1514);
+        counters.count_tx(42);
+        other.count_tx(100);
+        let rx = RingOccupancy { used: 3, size: 16 };
+        let tx = RingOccupancy { used: 0, size: 64 };
+        assert_eq!(
+            counters.snapshot(Some((rx, tx))),
+            InterfaceStats {
+                rx_packets: 2,
+                rx_bytes: 1574,
+                tx_packets: 1,
+                tx_bytes: 42,
+                rx_ring: Some(rx),
+                tx_ring: Some(tx),
+            }
+        );
+        assert_eq!(other.snapshot(None).tx_packets, 1);
+        assert_eq!(other.snapshot(None).rx_ring, None);
+        // the frames of all interfaces are also in the stack-wide counters
+        let delta = stats().delta(&before);
+        assert!(delta.rx_packets >= 2 && delta.rx_bytes >= 1574);
+        assert!(delta.tx_packets >= 2 && delta.tx_bytes >= 142);
+    }
+}
@@ -1,6 +1,8 @@
+use libc;
 use rand;
 use smoltcp::wire::{EthernetAddress, IpAddress, IpCidr, Ipv4Address};
 use std::env;
+use std::ffi::CString;
 use std::fs;
 use std::io::prelude::*;
 use std::process::Command;
@@ -19,6 +21,14 @@ pub fn get_default_interface() -> String {
     origifname.replace("usnetd", "")
 }
 
+/// Returns the index of the kernel interface, 0 if there is none with the name
+pub fn interface_index(interface: &str) -> u32 {
+    match CString::new(interface) {
+        Ok(name) => unsafe { libc::if_nametoindex(name.as_ptr()) },
+        Err(_) => 0,
+    }
+}
+
 pub fn get_gateway(interface: &str) -> Option<String> {
     let coutput = Command::new("ip")
         .args(&["-4", "route", "get", "1", "oif", interface])
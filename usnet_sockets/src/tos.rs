@@ -0,0 +1,169 @@
+/// TOS marking of outgoing UDP packets for UdpSocket::set_tos
+/// smoltcp sends all IPv4 packets with a TOS byte of 0. Therefore the device layer sets the
+/// TOS byte of the UDP packets from the local ports of sockets with a TOS and updates the
+/// IPv4 header checksum. The UDP checksum does not cover the TOS. Each stack keeps the marks
+/// of its own ports.
+use parking_lot::Mutex;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use smoltcp::wire::{IpProtocol, Ipv4Packet, UdpPacket};
+
+use crate::frame::{Headers, ETHERNET_HEADER_LEN};
+
+/// TOS bytes of the marked UDP ports of one stack
+#[derive(Debug, Default)]
+pub(crate) struct Marks {
+    // number of marked ports, the frames are not inspected if there are none
+    marked: AtomicUsize,
+    marks: Mutex<BTreeMap<u16, u8>>,
+}
+
+impl Marks {
+    /// Sets the TOS byte of the packets from the local UDP port, 0 removes the marking
+    pub fn set(&self, port: u16, tos: u8) {
+        let mut marks = self.marks.lock();
+        let previous = if tos == 0 {
+            marks.remove(&port)
+        } else {
+            marks.insert(port, tos)
+        };
+        match (previous.is_some(), tos != 0) {
+            (false, true) => {
+                let _ = self.marked.fetch_add(1, Ordering::Relaxed);
+            }
+            (true, false) => {
+                let _ = self.marked.fetch_sub(1, Ordering::Relaxed);
+            }
+            _ => {}
+        }
+    }
+
+    #[cfg(test)]
+    pub fn get(&self, port: u16) -> Option<u8> {
+        self.marks.lock().get(&port).cloned()
+    }
+
+    /// Sets the TOS byte of an outgoing IPv4 UDP packet from a marked port
+    /// (called for every transmitted frame)
+    pub fn mark_tx_frame(&self, buffer: &mut [u8], headers: &Headers) {
+        if self.marked.load(Ordering::Relaxed) == 0 {
+            return;
+        }
+        let ip = match headers.ip(IpProtocol::Udp) {
+            Some(ip) if ip.src.is_ipv4() && ip.first_fragment() => ip,
+            _ => return,
+        };
+        let port = match UdpPacket::new_checked(ip.payload(buffer)) {
+            Ok(udp) => udp.src_port(),
+            Err(_) => return,
+        };
+        let tos = match self.marks.lock().get(&port) {
+            Some(tos) => *tos,
+            None => return,
+        };
+        let mut packet = Ipv4Packet::new_unchecked(&mut buffer[ETHERNET_HEADER_LEN..ip.end]);
+        packet.set_dscp(tos >> 2);
+        packet.fill_checksum();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Marks;
+    use crate::frame;
+    use smoltcp::wire::Ipv4Packet;
+
+    /// UDP datagram from 10.0.0.2:47110 to 10.0.0.3:53 with the payload "dns!" and a TOS of 0
+    const DATAGRAM: [u8; 46] = [
+        0x52, 0x54, 0x00, 0x00, 0x00, 0x03, 0x52, 0x54, 0x00, 0x00, 0x00, 0x02, //
+        0x08, 0x00, 0x45, 0x00, 0x00, 0x20, 0x12, 0x34, 0x40, 0x00, 0x40, 0x11, //
+        0x14, 0x95, 0x0a, 0x00, 0x00, 0x02, 0x0a, 0x00, 0x00, 0x03, 0xb8, 0x06, //
+        0x00, 0x35, 0x00, 0x0c, 0x5c, 0x06, 0x64, 0x6e, 0x73, 0x21,
+    ];
+
+    /// Returns the DSCP value and whether the IPv4 header checksum is valid
+    fn dscp(frame: &[u8]) -> (u8, bool) {
+        let ip = Ipv4Packet::new_checked(&frame[14..]).unwrap();
+        (ip.dscp(), ip.verify_checksum())
+    }
+
+    fn mark_tx_frame(marks: &Marks, frame: &mut [u8]) {
+        let headers = frame::parse(frame);
+        marks.mark_tx_frame(frame, &headers);
+    }
+
+    #[test]
+    fn test_mark_tx_frame() {
+        let marks = Marks::default();
+        let mut frame = DATAGRAM;
+        mark_tx_frame(&marks, &mut frame);
+        assert_eq!(frame, DATAGRAM);
+        marks.set(47110, 46 << 2);
+        mark_tx_frame(&marks, &mut frame);
+        assert_eq!(dscp(&frame), (46, true));
+        assert_eq!(frame[15], 0xb8);
+        // only the TOS byte and the header checksum change
+        assert_eq!(frame[..15], DATAGRAM[..15]);
+        assert_eq!(frame[16..24], DATAGRAM[16..24]);
+        assert_eq!(frame[26..], DATAGRAM[26..]);
+        marks.set(47110, 10 << 2);
+        mark_tx_frame(&marks, &mut frame);
+        assert_eq!(dscp(&frame), (10, true));
+
+        // other ports, a TCP segment, and a later fragment
+        let mut other_port = DATAGRAM;
+        other_port[35] = 0x08;
+        let mut tcp = DATAGRAM;
+        tcp[23] = 6;
+        let mut fragment = DATAGRAM;
+        fragment[21] = 0x01;
+        for unmarked in &mut [other_port, tcp, fragment] {
+            let before = *unmarked;
+            mark_tx_frame(&marks, unmarked);
+            assert_eq!(*unmarked, before);
+        }
+        // the port of another stack
+        let mut frame = DATAGRAM;
+        mark_tx_frame(&Marks::default(), &mut frame);
+        assert_eq!(frame, DATAGRAM);
+
+        marks.set(47110, 0);
+        assert_eq!(marks.get(47110), None);
+        mark_tx_frame(&marks, &mut frame);
+        assert_eq!(frame, DATAGRAM);
+    }
+
+    #[cfg(feature = "multi")]
+    #[test]
+    fn test_drop_removes_mark() {
+        use crate::apimultithread::StcpNetRef;
+        use crate::usnetconfig::StackConfigBuilder;
+        use std::net::Ipv4Addr;
+
+        let stack = |link: &str| {
+            let config = StackConfigBuilder::new()
+                .in_memory(link)
+                .ipv4(Ipv4Addr::new(10, 0, 0, 2), 24)
+                .gateway(Ipv4Addr::new(10, 0, 0, 1))
+                .build()
+                .unwrap();
+            StcpNetRef::new(config)
+        };
+        let mark = |stack: &StcpNetRef| stack.r.0.lock().inspection().tos.get(47113);
+        let marked = stack("tos-drop");
+        let other = stack("tos-drop-other");
+        let socket = marked
+            .udp_bind((Ipv4Addr::new(10, 0, 0, 2), 47113))
+            .unwrap();
+        socket.set_tos(46 << 2).unwrap();
+        assert_eq!(mark(&marked), Some(46 << 2));
+        assert_eq!(mark(&other), None);
+        // the clones share the smoltcp socket and its mark
+        let clone = socket.try_clone().unwrap();
+        drop(socket);
+        assert_eq!(mark(&marked), Some(46 << 2));
+        drop(clone);
+        assert_eq!(mark(&marked), None);
+    }
+}
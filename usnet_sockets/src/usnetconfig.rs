@@ -2,6 +2,7 @@ use std::collections::BTreeMap;
 use std::fmt;
 use std::os::unix::io::{AsRawFd, RawFd};
 use std::str::{self, FromStr};
+use std::time::Duration;
 
 use std::fs::remove_file;
 use std::process;
@@ -12,21 +13,27 @@ extern crate usnet_devices;
 #[cfg(feature = "netmap")]
 use self::usnet_devices::{nmreq, Netmap};
 
-use self::usnet_devices::{RawSocket, TapInterface, UnixDomainSocket};
+pub use self::usnet_devices::{link_conditions, set_link_conditions, LinkConditions};
+use self::usnet_devices::{InMemory, RawSocket, TapInterface, UnixDomainSocket, Xdp};
 
 use smoltcp::iface::{EthernetInterfaceBuilder, NeighborCache, Routes};
 use smoltcp::wire::{EthernetAddress, IpAddress, IpCidr, IpProtocol, Ipv4Address};
 
+use std::env;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use std::os::unix::net::UnixDatagram;
 
 use libc;
 use nix::cmsg_space;
-use nix::sys::socket::{recvmsg, ControlMessageOwned, MsgFlags, SockAddr};
+use nix::sys::socket::{recvmsg, ControlMessageOwned, MsgFlags, RecvMsg, SockAddr};
 use nix::sys::uio::IoVec;
 use nix::unistd::{gettid, getuid};
 use std::os::unix::io::FromRawFd;
 
+use crate::congestion;
 use crate::device::*;
+use crate::routing::{check_address, check_prefix_len, parse_cidr, Route};
 use std::fs;
 use std::io::prelude::*;
 use std::process::Command;
@@ -52,20 +59,20 @@ pub enum MacVtapDevice {
     }, // copies MAC from device, must have been set after creation
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum SystemInterface {
     DiscoverFromRoute,
     Interface(String),
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum Mac {
     Passthru, // copy from parent, takes ownership of device, kernel does not process traffic anymore
     Static(String),
     Random,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum IpV4 {
     Dhcp,
     Static {
@@ -105,8 +112,36 @@ pub enum TapMac {
     Random,
 }
 
-#[cfg(feature = "netmap")]
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum XdpPoll {
+    Wakeup,   // the driver is only woken up when it waits for new fill or TX entries
+    BusyPoll, // prefers busy polling, every receive and transmit drives the driver
+}
+
 #[derive(Debug, Serialize, Deserialize)]
+pub enum XdpDevice {
+    Interface {
+        interface: SystemInterface,
+        queue_id: u32,
+        poll: XdpPoll,
+        program: Option<String>, // pinned XDP program to attach, None uses the attached one
+        mac: Mac,
+        ipv4: IpV4,
+    },
+}
+
+/// Connects two stacks of the same process, e.g., a client and a server in a test
+#[derive(Debug, Serialize, Deserialize)]
+pub enum InMemoryDevice {
+    Link {
+        link: String, // the first two stacks with the same link name are connected
+        mac: TapMac,
+        ipv4: Option<TapIpV4>, // None for IPv6 only, see StackConfig::addresses
+    },
+}
+
+#[cfg(feature = "netmap")]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum NetmapInterface {
     DiscoverFromRoute,
     Interface { netmap_name: String, parent: String },
@@ -147,6 +182,8 @@ pub enum StcpBackend {
     #[cfg(feature = "netmap")]
     NetmapConfig(NetmapDevice),
     UsnetConfig(UsnetDevice),
+    InMemoryConfig(InMemoryDevice),
+    XdpConfig(XdpDevice),
 }
 
 impl fmt::Display for StcpBackend {
@@ -160,54 +197,176 @@ impl StcpBackend {
         serde_json::to_string(self).unwrap()
     }
 
+    /// Whether the IP address and the default route are configured by the DHCP client
+    pub fn uses_dhcp(&self) -> bool {
+        match self {
+            StcpBackend::MacVtapConfig(MacVtapDevice::Create {
+                ipv4: IpV4::Dhcp, ..
+            })
+            | StcpBackend::MacVtapConfig(MacVtapDevice::Interface {
+                ipv4: IpV4::Dhcp, ..
+            }) => true,
+            #[cfg(feature = "netmap")]
+            StcpBackend::NetmapConfig(NetmapDevice::Interface {
+                ipv4: IpV4::Dhcp, ..
+            }) => true,
+            StcpBackend::XdpConfig(XdpDevice::Interface {
+                ipv4: IpV4::Dhcp, ..
+            }) => true,
+            _ => false,
+        }
+    }
+
+    /// Names of the kernel network interfaces which the backend sends through,
+    /// the parent NIC for macvtap and usnetd
+    /// A tap device created by the backend is named after its IPv4 address with the dots
+    /// replaced by "o", e.g., 10o0o0o2, and for an in-memory link the name of the link is used.
+    pub fn device_names(&self) -> Vec<String> {
+        let system_interface = |interface: &SystemInterface| match interface {
+            SystemInterface::DiscoverFromRoute => get_default_interface(),
+            SystemInterface::Interface(name) => name.clone(),
+        };
+        match self {
+            StcpBackend::RawConfig => vec![get_default_interface()],
+            StcpBackend::TapConfig(TapDevice::Create { ipv4, .. }) => match ipv4 {
+                // the name of the created device, see to_interface
+                TapIpV4::Static { ipv4, .. } => vec![ipv4.replace(".", "o")],
+            },
+            StcpBackend::TapConfig(TapDevice::Interface { interface, .. }) => {
+                vec![interface.clone()]
+            }
+            StcpBackend::MacVtapConfig(MacVtapDevice::Create { parent, .. }) => {
+                vec![system_interface(parent)]
+            }
+            StcpBackend::MacVtapConfig(MacVtapDevice::Interface { interface, .. }) => {
+                vec![interface.clone(), get_macvtap_parent(interface)]
+            }
+            #[cfg(feature = "netmap")]
+            StcpBackend::NetmapConfig(NetmapDevice::Interface { interface, .. }) => match interface
+            {
+                NetmapInterface::DiscoverFromRoute => vec![get_default_interface()],
+                NetmapInterface::Interface { parent, .. } => vec![parent.clone()],
+            },
+            StcpBackend::UsnetConfig(UsnetDevice::Interface { interface, .. }) => {
+                vec![system_interface(interface)]
+            }
+            StcpBackend::InMemoryConfig(InMemoryDevice::Link { link, .. }) => vec![link.clone()],
+            StcpBackend::XdpConfig(XdpDevice::Interface { interface, .. }) => {
+                vec![system_interface(interface)]
+            }
+        }
+    }
+
+    /// Backend for the stack on the queue with the index after the first queue, only the first
+    /// stack attaches the XDP program, netmap opens the hardware ring with the index
+    pub fn for_queue(&self, index: usize) -> io::Result<StcpBackend> {
+        match self {
+            #[cfg(feature = "netmap")]
+            StcpBackend::NetmapConfig(NetmapDevice::Interface {
+                interface:
+                    NetmapInterface::Interface {
+                        netmap_name,
+                        parent,
+                    },
+                mac,
+                ipv4,
+            }) => Ok(StcpBackend::NetmapConfig(NetmapDevice::Interface {
+                interface: NetmapInterface::Interface {
+                    netmap_name: format!("{}-{}", netmap_name, index),
+                    parent: parent.clone(),
+                },
+                mac: mac.clone(),
+                ipv4: ipv4.clone(),
+            })),
+            StcpBackend::XdpConfig(XdpDevice::Interface {
+                interface,
+                queue_id,
+                poll,
+                program,
+                mac,
+                ipv4,
+            }) => Ok(StcpBackend::XdpConfig(XdpDevice::Interface {
+                interface: interface.clone(),
+                queue_id: queue_id + index as u32,
+                poll: poll.clone(),
+                program: if index == 0 { program.clone() } else { None },
+                mac: mac.clone(),
+                ipv4: ipv4.clone(),
+            })),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "the backend does not run on multiple queues",
+            )),
+        }
+    }
+
+    /// The NIC queue of the stack, 0 if the backend does not select one
+    pub fn queue_id(&self) -> u32 {
+        match self {
+            StcpBackend::XdpConfig(XdpDevice::Interface { queue_id, .. }) => *queue_id,
+            _ => 0,
+        }
+    }
+
+    /// Sets up the backend and the smoltcp interface on it, failures to open or create the
+    /// device and to get it from usnetd are returned as errors
     // this function needs refactoring for code sharing
     pub fn to_interface(
         self,
         waiting_poll: bool,
         reduce_mtu_by: Option<usize>,
-    ) -> (RawFd, StcpBackendInterface) {
+    ) -> io::Result<(RawFd, StcpBackendInterface)> {
         let _ = waiting_poll;
         let neighbor_cache = NeighborCache::new(BTreeMap::new());
         match self {
             StcpBackend::RawConfig => {
-                let coutput = Command::new("ip")
-                    .args(&["route", "get", "1"])
-                    .output()
-                    .expect("command ip route get 1 failed");
-                let sout1 = String::from_utf8(coutput.stdout).unwrap();
+                let unexpected = |what: &str| {
+                    io::Error::new(io::ErrorKind::InvalidData, format!("unexpected {}", what))
+                };
+                let coutput = Command::new("ip").args(&["route", "get", "1"]).output()?;
+                let sout1 = String::from_utf8_lossy(&coutput.stdout).into_owned();
                 let sout = sout1.split(' ').collect::<Vec<_>>();
+                if sout.len() < 7 {
+                    return Err(unexpected("output of ip route get 1"));
+                }
                 let ifname = sout[4];
                 let gateway = sout[2];
                 let ip = sout[6];
                 debug!("ifname {}", ifname);
-                let device = RawSocket::new(ifname, reduce_mtu_by).unwrap();
+                let device = Monitored::new(RawSocket::new(ifname, reduce_mtu_by)?);
                 let fd = device.as_raw_fd();
                 let mut macaddr = String::new();
                 {
-                    let mut f = fs::File::open("/sys/class/net/".to_owned() + ifname + "/address")
-                        .expect("invalid interface");
-                    f.read_to_string(&mut macaddr)
-                        .expect("can not read mac addr");
+                    let mut f = fs::File::open("/sys/class/net/".to_owned() + ifname + "/address")?;
+                    f.read_to_string(&mut macaddr)?;
                 }
                 let m = macaddr
                     .trim()
                     .split(':')
-                    .map(|s| u8::from_str_radix(s, 16).unwrap())
-                    .collect::<Vec<_>>();
+                    .map(|s| u8::from_str_radix(s, 16))
+                    .collect::<Result<Vec<_>, _>>()
+                    .map_err(|_| unexpected("MAC address"))?;
+                if m.len() != 6 {
+                    return Err(unexpected("MAC address"));
+                }
                 let ethernet_addr = EthernetAddress([m[0], m[1], m[2], m[3], m[4], m[5]]);
 
                 let ioutput = Command::new("ip")
                     .args(&["a", "show", "dev", ifname])
-                    .output()
-                    .expect("command ip show failed");
-                let iout1 = String::from_utf8(ioutput.stdout).unwrap();
-                let iout2 = iout1.split('/').collect::<Vec<_>>()[2];
-                let iout = iout2.split(' ').collect::<Vec<_>>()[0];
-                let sub = u8::from_str(iout).expect("net size not found");
+                    .output()?;
+                let iout1 = String::from_utf8_lossy(&ioutput.stdout).into_owned();
+                let sub = iout1
+                    .split('/')
+                    .nth(2)
+                    .and_then(|iout2| iout2.split(' ').next())
+                    .and_then(|iout| u8::from_str(iout).ok())
+                    .ok_or_else(|| unexpected("output of ip a show"))?;
                 debug!("/{}", sub);
 
-                let ip_addrs = [IpCidr::new(IpAddress::from_str(ip).expect("ip conv"), sub)];
-                let default_v4_gw = Ipv4Address::from_str(gateway).expect("gateway conv");
+                let ip = IpAddress::from_str(ip).map_err(|_| unexpected("IP address"))?;
+                let ip_addrs = [IpCidr::new(ip, sub)];
+                let default_v4_gw =
+                    Ipv4Address::from_str(gateway).map_err(|_| unexpected("gateway"))?;
                 let mut routes = Routes::new(BTreeMap::new());
                 routes.add_default_ipv4_route(default_v4_gw).unwrap();
                 let iface = EthernetInterfaceBuilder::new(device)
@@ -216,7 +375,7 @@ impl StcpBackend {
                     .ip_addrs(ip_addrs)
                     .routes(routes)
                     .finalize();
-                (fd, StcpBackendInterface::Raw(iface))
+                Ok((fd, StcpBackendInterface::Raw(iface)))
             }
             StcpBackend::MacVtapConfig(device) => {
                 let (iface, fd, destroy) = match device {
@@ -242,16 +401,24 @@ impl StcpBackend {
                         };
                         let ethernet_addr = mac_str_to_eth_addr(&macaddr);
                         create_macvtap(&ifname, &origifname, &mode, &macaddr); // invokes pkexec
-                        let device = TapInterface::new_macvtap(&ifname, reduce_mtu_by).unwrap();
+                        let device =
+                            Monitored::new(TapInterface::new_macvtap(&ifname, reduce_mtu_by)?);
                         let fd = device.as_raw_fd();
+                        log_tap_mode(device.get_ref(), &ifname);
 
-                        let iface: EthernetInterfaceBuilder<TapInterface> =
+                        let iface: EthernetInterfaceBuilder<Monitored<TapInterface>> =
                             EthernetInterfaceBuilder::new(device)
                                 .ethernet_addr(ethernet_addr)
                                 .neighbor_cache(neighbor_cache);
 
                         let iface = match ipv4 {
-                            IpV4::Dhcp => panic!("DHCP not implemented yet in smoltcp"),
+                            IpV4::Dhcp => {
+                                // address and default route are set by the DHCP client
+                                let ip_addrs = [IpCidr::new(Ipv4Address::UNSPECIFIED.into(), 0)];
+                                iface
+                                    .ip_addrs(ip_addrs)
+                                    .routes(Routes::new(BTreeMap::new()))
+                            }
                             IpV4::Passthru => {
                                 let mut routes = Routes::new(BTreeMap::new());
                                 if let Some(gw) = get_gateway(&origifname) {
@@ -278,15 +445,23 @@ impl StcpBackend {
                         let macaddr = get_macaddr(&interface);
                         let ethernet_addr = mac_str_to_eth_addr(&macaddr);
 
-                        let device = TapInterface::new_macvtap(&interface, reduce_mtu_by).unwrap();
+                        let device =
+                            Monitored::new(TapInterface::new_macvtap(&interface, reduce_mtu_by)?);
                         let fd = device.as_raw_fd();
+                        log_tap_mode(device.get_ref(), &interface);
 
                         let iface = EthernetInterfaceBuilder::new(device)
                             .ethernet_addr(ethernet_addr)
                             .neighbor_cache(neighbor_cache);
 
                         let iface = match ipv4 {
-                            IpV4::Dhcp => panic!("DHCP not implemented yet in smoltcp"),
+                            IpV4::Dhcp => {
+                                // address and default route are set by the DHCP client
+                                let ip_addrs = [IpCidr::new(Ipv4Address::UNSPECIFIED.into(), 0)];
+                                iface
+                                    .ip_addrs(ip_addrs)
+                                    .routes(Routes::new(BTreeMap::new()))
+                            }
                             IpV4::Passthru => {
                                 let origifname = get_macvtap_parent(&interface);
                                 let mut routes = Routes::new(BTreeMap::new());
@@ -311,13 +486,13 @@ impl StcpBackend {
                     }
                 };
 
-                (
+                Ok((
                     fd,
                     StcpBackendInterface::MacVtap {
                         interface: iface,
                         destroy: destroy,
                     },
-                )
+                ))
             }
             StcpBackend::TapConfig(device) => {
                 let (iface, fd, destroy) = match device {
@@ -332,8 +507,10 @@ impl StcpBackend {
                             TapIpV4::Static { ipv4, sub, gateway } => {
                                 let ifname = ipv4.replace(".", "o"); // make interface name easy to recognize
                                 create_tap(&ifname, &host_ip, sub); // invokes pkexec
-                                let device = TapInterface::new(&ifname, reduce_mtu_by).unwrap();
+                                let device =
+                                    Monitored::new(TapInterface::new(&ifname, reduce_mtu_by)?);
                                 let fd = device.as_raw_fd();
+                                log_tap_mode(device.get_ref(), &ifname);
 
                                 let iface = EthernetInterfaceBuilder::new(device)
                                     .ethernet_addr(ethernet_addr)
@@ -360,8 +537,9 @@ impl StcpBackend {
                         };
                         let ethernet_addr = mac_str_to_eth_addr(&macaddr);
 
-                        let device = TapInterface::new(&interface, reduce_mtu_by).unwrap();
+                        let device = Monitored::new(TapInterface::new(&interface, reduce_mtu_by)?);
                         let fd = device.as_raw_fd();
+                        log_tap_mode(device.get_ref(), &interface);
 
                         let iface = EthernetInterfaceBuilder::new(device)
                             .ethernet_addr(ethernet_addr)
@@ -381,13 +559,13 @@ impl StcpBackend {
                     }
                 };
 
-                (
+                Ok((
                     fd,
                     StcpBackendInterface::Tap {
                         interface: iface,
                         destroy: destroy,
                     },
-                )
+                ))
             }
             #[cfg(feature = "netmap")]
             StcpBackend::NetmapConfig(NetmapDevice::Interface {
@@ -413,8 +591,12 @@ impl StcpBackend {
                     };
                     let ethernet_addr = mac_str_to_eth_addr(&macaddr);
 
-                    let device =
-                        Netmap::new(&interface, &parent, waiting_poll, reduce_mtu_by).unwrap();
+                    let device = Monitored::new(Netmap::new(
+                        &interface,
+                        &parent,
+                        waiting_poll,
+                        reduce_mtu_by,
+                    )?);
                     let fd = device.as_raw_fd();
 
                     let mut iface = EthernetInterfaceBuilder::new(device)
@@ -422,7 +604,13 @@ impl StcpBackend {
                         .neighbor_cache(neighbor_cache);
 
                     let iface = match ipv4 {
-                        IpV4::Dhcp => panic!("not implemented yet in smoltcp"),
+                        IpV4::Dhcp => {
+                            // address and default route are set by the DHCP client
+                            let ip_addrs = [IpCidr::new(Ipv4Address::UNSPECIFIED.into(), 0)];
+                            iface
+                                .ip_addrs(ip_addrs)
+                                .routes(Routes::new(BTreeMap::new()))
+                        }
                         IpV4::Passthru => {
                             let mut routes = Routes::new(BTreeMap::new());
                             if let Some(gw) = get_gateway(&parent) {
@@ -445,7 +633,7 @@ impl StcpBackend {
                     (iface.finalize(), fd)
                 };
 
-                (fd, StcpBackendInterface::Netmap { interface: iface })
+                Ok((fd, StcpBackendInterface::Netmap { interface: iface }))
             }
             StcpBackend::UsnetConfig(UsnetDevice::Interface {
                 interface,
@@ -472,17 +660,14 @@ impl StcpBackend {
                         rand::random::<u64>()
                     );
                     let _ = remove_file(&tmpsckt);
-                    let control_uds =
-                        UnixDatagram::bind(tmpsckt).expect("binding tmp socket failed");
+                    let control_uds = UnixDatagram::bind(tmpsckt)?;
                     let payl = serde_json::to_string(&ClientMessage::RequestUDS(
                         parent.to_owned(),
                         libc::pid_t::from(gettid()) as u64,
                     ))
-                    .unwrap();
-                    let sent_bytes = control_uds
-                        .send_to(payl.as_bytes(), SOCKET_PATH)
-                        .expect("cannot send to service unix domain socket");
-                    assert_eq!(sent_bytes, payl.len());
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+                    let sent_bytes = control_uds.send_to(payl.as_bytes(), SOCKET_PATH)?;
+                    check_sent_request(sent_bytes, payl.len())?;
                     // receive response
                     let mut buf = [0u8; 1];
                     let iov = [IoVec::from_mut_slice(&mut buf[..])];
@@ -493,45 +678,25 @@ impl StcpBackend {
                         Some(&mut cmsgspace),
                         MsgFlags::empty(),
                     )
-                    .unwrap();
-                    let mut received_fd: Option<RawFd> = None;
-                    for cmsg in msg.cmsgs() {
-                        if let ControlMessageOwned::ScmRights(fd) = cmsg {
-                            assert_eq!(received_fd, None);
-                            assert_eq!(fd.len(), 1);
-                            received_fd = Some(fd[0]);
-                        } else {
-                            panic!("unexpected cmsg");
-                        }
-                    }
-                    assert_eq!(
-                        match msg.address {
-                            Some(SockAddr::Unix(ua)) => {
-                                ua.path().map(|p| p.to_str().map(|i| i == SOCKET_PATH))
-                            }
-                            _ => None,
-                        },
-                        Some(Some(true))
-                    );
-                    assert!(!msg
-                        .flags
-                        .intersects(MsgFlags::MSG_TRUNC | MsgFlags::MSG_CTRUNC));
-                    assert!(received_fd.is_some());
-
-                    let fd = received_fd.unwrap();
-                    let device = UnixDomainSocket::new_from_unix_datagram(
+                    .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+                    let fd = received_fd(&msg)?;
+                    let device = Monitored::new(UnixDomainSocket::new_from_unix_datagram(
                         unsafe { UnixDatagram::from_raw_fd(fd) },
                         &parent,
                         reduce_mtu_by,
-                    )
-                    .unwrap();
+                    )?);
 
                     let iface = EthernetInterfaceBuilder::new(device)
                         .ethernet_addr(ethernet_addr)
                         .neighbor_cache(neighbor_cache);
 
                     let iface = match ipv4 {
-                        IpV4::Dhcp => panic!("not implemented yet in smoltcp"),
+                        IpV4::Dhcp => {
+                            return Err(io::Error::new(
+                                io::ErrorKind::InvalidInput,
+                                "DHCP not supported with usnetd, port matches need the IP",
+                            ))
+                        }
                         IpV4::Passthru => {
                             let mut routes = Routes::new(BTreeMap::new());
                             if let Some(gw) = get_gateway(&parent) {
@@ -554,13 +719,13 @@ impl StcpBackend {
                     (iface.finalize(), fd, control_uds)
                 };
 
-                (
+                Ok((
                     fd,
                     StcpBackendInterface::UsnetUds {
                         interface: iface,
                         control: control_uds,
                     },
-                )
+                ))
             }
             #[cfg(feature = "netmap")]
             StcpBackend::UsnetConfig(UsnetDevice::Interface {
@@ -588,17 +753,14 @@ impl StcpBackend {
                         rand::random::<u64>()
                     );
                     let _ = remove_file(&tmpsckt);
-                    let mut control_uds =
-                        UnixDatagram::bind(tmpsckt).expect("binding tmp socket failed");
+                    let mut control_uds = UnixDatagram::bind(tmpsckt)?;
                     let payl = serde_json::to_string(&ClientMessage::RequestNetmapPipe(
                         parent.to_owned(),
                         libc::pid_t::from(gettid()) as u64,
                     ))
-                    .unwrap();
-                    let sent_bytes = control_uds
-                        .send_to(payl.as_bytes(), SOCKET_PATH)
-                        .expect("cannot send to service unix domain socket");
-                    assert_eq!(sent_bytes, payl.len());
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+                    let sent_bytes = control_uds.send_to(payl.as_bytes(), SOCKET_PATH)?;
+                    check_sent_request(sent_bytes, payl.len())?;
                     // receive response
                     let mut buf = [0u8; mem::size_of::<nmreq>()];
                     let iov = [IoVec::from_mut_slice(&mut buf[..])];
@@ -609,45 +771,30 @@ impl StcpBackend {
                         Some(&mut cmsgspace),
                         MsgFlags::empty(),
                     )
-                    .unwrap();
+                    .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
                     let req: nmreq = unsafe {
                         mem::transmute_copy(&(*(iov[0].as_slice().as_ptr() as *const nmreq)))
                     };
-                    let mut received_fd: Option<RawFd> = None;
-                    for cmsg in msg.cmsgs() {
-                        if let ControlMessageOwned::ScmRights(fd) = cmsg {
-                            assert_eq!(received_fd, None);
-                            assert_eq!(fd.len(), 1);
-                            received_fd = Some(fd[0]);
-                        } else {
-                            panic!("unexpected cmsg");
-                        }
-                    }
-                    assert_eq!(
-                        match msg.address {
-                            Some(SockAddr::Unix(ua)) => {
-                                ua.path().map(|p| p.to_str().map(|i| i == SOCKET_PATH))
-                            }
-                            _ => None,
-                        },
-                        Some(Some(true))
-                    );
-                    assert!(!msg
-                        .flags
-                        .intersects(MsgFlags::MSG_TRUNC | MsgFlags::MSG_CTRUNC));
-                    assert!(received_fd.is_some());
-
-                    let fd = received_fd.unwrap();
-                    let device =
-                        Netmap::new_from_shared_fd(fd, req, &parent, waiting_poll, reduce_mtu_by)
-                            .expect("Netmap mmap failed");
+                    let fd = received_fd(&msg)?;
+                    let device = Monitored::new(Netmap::new_from_shared_fd(
+                        fd,
+                        req,
+                        &parent,
+                        waiting_poll,
+                        reduce_mtu_by,
+                    )?);
 
                     let mut iface = EthernetInterfaceBuilder::new(device)
                         .ethernet_addr(ethernet_addr)
                         .neighbor_cache(neighbor_cache);
 
                     let iface = match ipv4 {
-                        IpV4::Dhcp => panic!("not implemented yet in smoltcp"),
+                        IpV4::Dhcp => {
+                            return Err(io::Error::new(
+                                io::ErrorKind::InvalidInput,
+                                "DHCP not supported with usnetd, port matches need the IP",
+                            ))
+                        }
                         IpV4::Passthru => {
                             let mut routes = Routes::new(BTreeMap::new());
                             if let Some(gw) = get_gateway(&parent) {
@@ -670,16 +817,200 @@ impl StcpBackend {
                     (iface.finalize(), fd, control_uds)
                 };
 
-                (
+                Ok((
                     fd,
                     StcpBackendInterface::UsnetNetmap {
                         interface: iface,
                         control: control_uds,
                     },
-                )
+                ))
+            }
+            StcpBackend::InMemoryConfig(InMemoryDevice::Link { link, mac, ipv4 }) => {
+                let macaddr: String = match mac {
+                    TapMac::Static(mac_str) => mac_str,
+                    TapMac::Random => gen_random_mac(),
+                };
+                let ethernet_addr = mac_str_to_eth_addr(&macaddr);
+
+                let device = Monitored::new(InMemory::new(&link, reduce_mtu_by)?);
+                let fd = device.as_raw_fd();
+
+                let iface = EthernetInterfaceBuilder::new(device)
+                    .ethernet_addr(ethernet_addr)
+                    .neighbor_cache(neighbor_cache);
+
+                let iface = match ipv4 {
+                    Some(TapIpV4::Static { ipv4, sub, gateway }) => {
+                        let gateway = ip_addr_from_str(&gateway);
+                        let mut routes = Routes::new(BTreeMap::new());
+                        routes.add_default_ipv4_route(gateway).unwrap();
+                        let ip_addrs = [ip_cidr_from_str(&ipv4, sub)];
+                        iface.ip_addrs(ip_addrs).routes(routes)
+                    }
+                    None => {
+                        let ip_addrs = [IpCidr::new(Ipv4Address::UNSPECIFIED.into(), 0)];
+                        iface
+                            .ip_addrs(ip_addrs)
+                            .routes(Routes::new(BTreeMap::new()))
+                    }
+                };
+
+                Ok((
+                    fd,
+                    StcpBackendInterface::InMemory {
+                        interface: iface.finalize(),
+                    },
+                ))
+            }
+            StcpBackend::XdpConfig(XdpDevice::Interface {
+                interface,
+                queue_id,
+                poll,
+                program,
+                mac,
+                ipv4,
+            }) => {
+                let interface = match interface {
+                    SystemInterface::DiscoverFromRoute => get_default_interface(),
+                    SystemInterface::Interface(interface) => interface,
+                };
+                let macaddr = match mac {
+                    Mac::Passthru => get_macaddr(&interface),
+                    Mac::Static(mac_str) => mac_str,
+                    Mac::Random => gen_random_mac(),
+                };
+                let ethernet_addr = mac_str_to_eth_addr(&macaddr);
+
+                let busy_poll = match poll {
+                    XdpPoll::Wakeup => false,
+                    XdpPoll::BusyPoll => true,
+                };
+                let device = Monitored::new(Xdp::new(
+                    &interface,
+                    queue_id,
+                    busy_poll,
+                    program.as_ref().map(|p| p.as_str()),
+                    reduce_mtu_by,
+                )?);
+                info!(
+                    "AF_XDP socket on {} queue {} in {} mode",
+                    interface,
+                    queue_id,
+                    if device.get_ref().zero_copy() {
+                        "zero-copy"
+                    } else {
+                        "copy"
+                    }
+                );
+                let fd = device.as_raw_fd();
+
+                let iface = EthernetInterfaceBuilder::new(device)
+                    .ethernet_addr(ethernet_addr)
+                    .neighbor_cache(neighbor_cache);
+
+                let iface = match ipv4 {
+                    IpV4::Dhcp => {
+                        // address and default route are set by the DHCP client
+                        let ip_addrs = [IpCidr::new(Ipv4Address::UNSPECIFIED.into(), 0)];
+                        iface
+                            .ip_addrs(ip_addrs)
+                            .routes(Routes::new(BTreeMap::new()))
+                    }
+                    IpV4::Passthru => {
+                        let mut routes = Routes::new(BTreeMap::new());
+                        if let Some(gw) = get_gateway(&interface) {
+                            let gateway = ip_addr_from_str(&gw);
+                            routes.add_default_ipv4_route(gateway).unwrap();
+                        }
+                        let (ipv4, sub) = get_ipv4(&interface);
+                        let ip_addrs = [ip_cidr_from_str(&ipv4, sub)];
+                        iface.ip_addrs(ip_addrs).routes(routes)
+                    }
+                    IpV4::Static { ipv4, sub, gateway } => {
+                        let gateway = ip_addr_from_str(&gateway);
+                        let mut routes = Routes::new(BTreeMap::new());
+                        routes.add_default_ipv4_route(gateway).unwrap();
+                        let ip_addrs = [ip_cidr_from_str(&ipv4, sub)];
+                        iface.ip_addrs(ip_addrs).routes(routes)
+                    }
+                };
+
+                Ok((
+                    fd,
+                    StcpBackendInterface::Xdp {
+                        interface: iface.finalize(),
+                    },
+                ))
+            }
+        }
+    }
+}
+
+/// Checks that the request to usnetd was sent as one datagram
+fn check_sent_request(sent: usize, len: usize) -> io::Result<()> {
+    if sent != len {
+        return Err(io::Error::new(
+            io::ErrorKind::WriteZero,
+            "request to usnetd was not sent completely",
+        ));
+    }
+    Ok(())
+}
+
+/// Returns the file descriptor which usnetd passed in its response
+fn received_fd(msg: &RecvMsg) -> io::Result<RawFd> {
+    let invalid = |msg: &str| Err(io::Error::new(io::ErrorKind::InvalidData, msg));
+    let mut received_fd: Option<RawFd> = None;
+    for cmsg in msg.cmsgs() {
+        match cmsg {
+            ControlMessageOwned::ScmRights(ref fd) if received_fd.is_none() && fd.len() == 1 => {
+                received_fd = Some(fd[0]);
             }
+            _ => return invalid("unexpected control message from usnetd"),
         }
     }
+    let from_usnetd = match msg.address {
+        Some(SockAddr::Unix(ua)) => ua.path().and_then(|p| p.to_str()) == Some(SOCKET_PATH),
+        _ => false,
+    };
+    if !from_usnetd {
+        return invalid("response not sent by usnetd");
+    }
+    if msg
+        .flags
+        .intersects(MsgFlags::MSG_TRUNC | MsgFlags::MSG_CTRUNC)
+    {
+        return invalid("truncated response from usnetd");
+    }
+    match received_fd {
+        Some(fd) => Ok(fd),
+        None => invalid("usnetd passed no file descriptor"),
+    }
+}
+
+fn log_tap_mode(device: &TapInterface, interface: &str) {
+    if device.uses_io_uring() {
+        info!(
+            "Frames of {} are read and written in batches over io_uring",
+            interface
+        );
+    } else {
+        info!(
+            "io_uring is not available, frames of {} use one syscall each",
+            interface
+        );
+    }
+    if device.uses_offload() {
+        info!(
+            "Checksums and TCP segmentation of {} are offloaded through virtio-net headers",
+            interface
+        );
+    } else {
+        info!(
+            "The kernel does not support virtio-net headers for {}, checksums and TCP segmentation are done in software",
+            interface
+        );
+    }
 }
 
 // can be used for avoiding connect port clashes for same endpoint or debugging and visualizing usnetd rules
@@ -763,3 +1094,721 @@ pub fn find_free_connecting_or_listening_port_for(
     let (_, _, port) = triple;
     port
 }
+
+pub const DEFAULT_NAME_SERVERS: [&str; 2] = ["1.1.1.1:53", "1.0.0.1:53"];
+
+/// Configuration of the userspace network stack, either read from the environment
+/// variables or created with StackConfigBuilder and passed to init_with
+#[derive(Debug)]
+pub struct StackConfig {
+    pub backend: StcpBackend,
+    pub waiting_poll: bool,
+    /// Time the background thread keeps polling without blocking after the last traffic
+    /// before it waits for the backend again, zero to always block when idle
+    pub spin_budget: Duration,
+    pub socket_buffer_size: usize,
+    pub bg_thread_pin_cpu_id: Option<usize>,
+    pub socket_backlog: usize,
+    pub reduce_mtu_by: Option<usize>,
+    pub mtu: Option<usize>,
+    /// Limits the MSS announced in TCP SYN segments in both directions, e.g., for PPPoE
+    pub mss_clamp: Option<u16>,
+    /// Lowers the path MTU when full-size TCP segments are retransmitted without an ICMP
+    /// error, which detects black holes on paths that filter ICMP, see the pmtu module
+    pub pmtu_probing: bool,
+    /// Negotiates selective acknowledgments for new TCP connections
+    pub tcp_sack: bool,
+    /// Negotiates window scaling for new TCP connections, without it the advertised receive
+    /// window is limited to 65535 bytes
+    pub tcp_window_scaling: bool,
+    /// Receive buffer of a TCP connection, which is the largest receive window it advertises,
+    /// None for the socket buffer size
+    pub tcp_receive_window: Option<usize>,
+    /// Congestion window of new TCP connections in segments before the first acknowledgment
+    pub tcp_initial_window: usize,
+    pub name_servers: Vec<SocketAddr>,
+    pub slaac: bool,
+    /// Allows RawSocket, which receives copies of all packets of its IP protocol
+    pub raw_sockets: bool,
+    /// Loops TCP, UDP, and ICMP traffic to 127.0.0.1 and the own IPv4 addresses back inside
+    /// the stack when a socket of the stack is bound to the destination port
+    pub loopback: bool,
+    /// Networks reached through the userspace network stack, the other remote destinations
+    /// are reached through kernel sockets, empty to use the stack for all destinations
+    pub usnet_prefixes: Vec<(IpAddr, u8)>,
+    /// Added after the address of the backend configuration
+    pub addresses: Vec<(IpAddr, u8)>,
+    pub routes: Vec<Route>,
+    /// Number of stacks on consecutive NIC queues, starting at the queue of the backend, each
+    /// with its own background thread, see the queues module
+    pub queues: usize,
+}
+
+impl StackConfig {
+    pub fn from_env() -> StackConfig {
+        let confvar = env::var("USNET_SOCKETS").unwrap();
+        let backend: StcpBackend = serde_json::from_str(&confvar).unwrap();
+        let waiting_poll = env::var("USNET_SOCKETS_WAIT").unwrap_or("true".to_string()) == "true";
+        // Not touched in the evaluation, should normally stay "true" and was only used with "false" for testing busy polling with netmap
+        info!("USNET_SOCKETS_WAIT: {}", waiting_poll);
+        let spin_budget = Duration::from_micros(
+            u64::from_str(&env::var("USNET_SOCKETS_SPIN_US").unwrap_or("0".to_string()))
+                .expect("USNET_SOCKETS_SPIN_US not an u64"),
+        );
+        info!("USNET_SOCKETS_SPIN_US: {:?}", spin_budget);
+        let socket_buffer_size =
+            usize::from_str(&env::var("SOCKET_BUFFER").unwrap_or("500000".to_string()))
+                .expect("SOCKET_BUFFER not an usize");
+        assert!(socket_buffer_size > 0);
+        info!("SOCKET_BUFFER: {}", socket_buffer_size);
+        let bg_thread_pin_cpu_id_nr =
+            i16::from_str(&env::var("BG_THREAD_PIN_CPU_ID").unwrap_or("-1".to_string()))
+                .expect("BG_THREAD_PIN_CPU_ID not a number");
+        let bg_thread_pin_cpu_id = if bg_thread_pin_cpu_id_nr < 0 {
+            None
+        } else {
+            Some(bg_thread_pin_cpu_id_nr as usize)
+        };
+        info!("BG_THREAD_PIN_CPU_ID: {:?}", bg_thread_pin_cpu_id);
+        let socket_backlog =
+            usize::from_str(&env::var("SOCKET_BACKLOG").unwrap_or("32".to_string()))
+                .expect("SOCKET_BACKLOG not an usize");
+        assert!(socket_backlog > 0);
+        info!("SOCKET_BACKLOG: {}", socket_backlog);
+        let reduce_mtu_by_nr =
+            usize::from_str(&env::var("REDUCE_MTU_BY").unwrap_or("0".to_string()))
+                .expect("REDUCE_MTU_BY not a number");
+        let reduce_mtu_by = if reduce_mtu_by_nr == 0 {
+            None
+        } else {
+            Some(reduce_mtu_by_nr)
+        };
+        info!("REDUCE_MTU_BY: {:?}", reduce_mtu_by);
+        let mss_clamp_nr = u16::from_str(&env::var("USNET_MSS_CLAMP").unwrap_or("0".to_string()))
+            .expect("USNET_MSS_CLAMP not an u16");
+        let mss_clamp = if mss_clamp_nr == 0 {
+            None
+        } else {
+            Some(mss_clamp_nr)
+        };
+        info!("USNET_MSS_CLAMP: {:?}", mss_clamp);
+        let pmtu_probing = env::var("USNET_PMTU_PROBING").unwrap_or("false".to_string()) == "true";
+        info!("USNET_PMTU_PROBING: {}", pmtu_probing);
+        let tcp_sack = env::var("USNET_TCP_SACK").unwrap_or("true".to_string()) == "true";
+        info!("USNET_TCP_SACK: {}", tcp_sack);
+        let tcp_window_scaling =
+            env::var("USNET_TCP_WINDOW_SCALING").unwrap_or("true".to_string()) == "true";
+        info!("USNET_TCP_WINDOW_SCALING: {}", tcp_window_scaling);
+        let tcp_receive_window_nr =
+            usize::from_str(&env::var("USNET_TCP_RECEIVE_WINDOW").unwrap_or("0".to_string()))
+                .expect("USNET_TCP_RECEIVE_WINDOW not an usize");
+        let tcp_receive_window = if tcp_receive_window_nr == 0 {
+            None
+        } else {
+            Some(tcp_receive_window_nr)
+        };
+        info!("USNET_TCP_RECEIVE_WINDOW: {:?}", tcp_receive_window);
+        let tcp_initial_window = usize::from_str(
+            &env::var("USNET_TCP_INITIAL_WINDOW")
+                .unwrap_or(congestion::DEFAULT_INITIAL_WINDOW.to_string()),
+        )
+        .expect("USNET_TCP_INITIAL_WINDOW not an usize");
+        assert!(tcp_initial_window > 0);
+        info!("USNET_TCP_INITIAL_WINDOW: {}", tcp_initial_window);
+        let name_servers = env::var("USNET_NAME_SERVERS")
+            .unwrap_or(DEFAULT_NAME_SERVERS.join(","))
+            .split(',')
+            .map(|ns| {
+                SocketAddr::from_str(ns.trim()).expect("USNET_NAME_SERVERS not a list of IP:port")
+            })
+            .collect::<Vec<_>>();
+        assert!(!name_servers.is_empty());
+        info!("USNET_NAME_SERVERS: {:?}", name_servers);
+        let slaac = env::var("USNET_SLAAC").unwrap_or("false".to_string()) == "true";
+        info!("USNET_SLAAC: {}", slaac);
+        let raw_sockets = env::var("USNET_RAW_SOCKETS").unwrap_or("false".to_string()) == "true";
+        info!("USNET_RAW_SOCKETS: {}", raw_sockets);
+        let loopback = env::var("USNET_LOOPBACK").unwrap_or("false".to_string()) == "true";
+        info!("USNET_LOOPBACK: {}", loopback);
+        let addresses = env::var("USNET_ADDRESSES")
+            .map(|a| {
+                a.split(',')
+                    .map(|cidr| parse_cidr(cidr).expect("USNET_ADDRESSES not a list of IP/prefix"))
+                    .collect::<Vec<_>>()
+            })
+            .unwrap_or(vec![]);
+        info!("USNET_ADDRESSES: {:?}", addresses);
+        let usnet_prefixes = env::var("USNET_PREFIXES")
+            .map(|a| {
+                a.split(',')
+                    .map(|cidr| parse_cidr(cidr).expect("USNET_PREFIXES not a list of IP/prefix"))
+                    .collect::<Vec<_>>()
+            })
+            .unwrap_or(vec![]);
+        info!("USNET_PREFIXES: {:?}", usnet_prefixes);
+        let routes = env::var("USNET_ROUTES")
+            .map(|r| {
+                r.split(',')
+                    .map(|route| {
+                        Route::from_str(route).expect("USNET_ROUTES not a list of IP/prefix via IP")
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .unwrap_or(vec![]);
+        info!("USNET_ROUTES: {:?}", routes);
+        let queues = usize::from_str(&env::var("USNET_QUEUES").unwrap_or("1".to_string()))
+            .expect("USNET_QUEUES not an usize");
+        check_queues(&backend, queues).expect("invalid USNET_QUEUES");
+        info!("USNET_QUEUES: {}", queues);
+        StackConfig {
+            backend: backend,
+            waiting_poll: waiting_poll,
+            spin_budget: spin_budget,
+            socket_buffer_size: socket_buffer_size,
+            bg_thread_pin_cpu_id: bg_thread_pin_cpu_id,
+            socket_backlog: socket_backlog,
+            reduce_mtu_by: reduce_mtu_by,
+            mtu: None,
+            mss_clamp: mss_clamp,
+            pmtu_probing: pmtu_probing,
+            tcp_sack: tcp_sack,
+            tcp_window_scaling: tcp_window_scaling,
+            tcp_receive_window: tcp_receive_window,
+            tcp_initial_window: tcp_initial_window,
+            name_servers: name_servers,
+            slaac: slaac,
+            raw_sockets: raw_sockets,
+            loopback: loopback,
+            usnet_prefixes: usnet_prefixes,
+            addresses: addresses,
+            routes: routes,
+            queues: queues,
+        }
+    }
+
+    /// Size of the receive buffer of TCP sockets, which without window scaling can only be
+    /// advertised up to 65535 bytes
+    pub fn tcp_receive_buffer_size(&self) -> usize {
+        let size = self.tcp_receive_window.unwrap_or(self.socket_buffer_size);
+        if self.tcp_window_scaling {
+            size
+        } else {
+            size.min(u16::max_value() as usize)
+        }
+    }
+
+    /// Configuration of the stack on the queue with the index after the first queue
+    pub fn for_queue(&self, index: usize) -> io::Result<StackConfig> {
+        Ok(StackConfig {
+            backend: self.backend.for_queue(index)?,
+            waiting_poll: self.waiting_poll,
+            spin_budget: self.spin_budget,
+            socket_buffer_size: self.socket_buffer_size,
+            bg_thread_pin_cpu_id: self.bg_thread_pin_cpu_id.map(|cpu_id| cpu_id + index),
+            socket_backlog: self.socket_backlog,
+            reduce_mtu_by: self.reduce_mtu_by,
+            mtu: self.mtu,
+            mss_clamp: self.mss_clamp,
+            pmtu_probing: self.pmtu_probing,
+            tcp_sack: self.tcp_sack,
+            tcp_window_scaling: self.tcp_window_scaling,
+            tcp_receive_window: self.tcp_receive_window,
+            tcp_initial_window: self.tcp_initial_window,
+            name_servers: self.name_servers.clone(),
+            slaac: self.slaac,
+            raw_sockets: false, // only on the first queue
+            loopback: false,
+            usnet_prefixes: self.usnet_prefixes.clone(),
+            addresses: self.addresses.clone(),
+            routes: self.routes.clone(),
+            queues: 1,
+        })
+    }
+}
+
+/// Builds a validated StackConfig in code instead of through the environment variables
+#[derive(Debug, Default)]
+pub struct StackConfigBuilder {
+    backend: Option<StcpBackend>,
+    ipv4: Option<(Ipv4Addr, u8)>,
+    gateway: Option<Ipv4Addr>,
+    mtu: Option<usize>,
+    mss_clamp: Option<u16>,
+    pmtu_probing: bool,
+    tcp_sack: Option<bool>,
+    tcp_window_scaling: Option<bool>,
+    tcp_receive_window: Option<usize>,
+    tcp_initial_window: Option<usize>,
+    name_servers: Option<Vec<SocketAddr>>,
+    socket_buffer_size: Option<usize>,
+    socket_backlog: Option<usize>,
+    waiting_poll: Option<bool>,
+    spin_budget: Option<Duration>,
+    bg_thread_pin_cpu_id: Option<usize>,
+    slaac: bool,
+    raw_sockets: bool,
+    loopback: bool,
+    usnet_prefixes: Vec<(IpAddr, u8)>,
+    addresses: Vec<(IpAddr, u8)>,
+    routes: Vec<(IpAddr, u8, IpAddr)>,
+    queues: Option<usize>,
+}
+
+pub const MIN_MTU: usize = 576;
+pub const MAX_MTU: usize = 9000;
+/// Default MSS of IPv4 (RFC 879)
+pub const MIN_MSS: usize = 536;
+
+impl StackConfigBuilder {
+    pub fn new() -> StackConfigBuilder {
+        StackConfigBuilder::default()
+    }
+    /// Selects the backend, same as the USNET_SOCKETS JSON configuration
+    pub fn backend(mut self, backend: StcpBackend) -> StackConfigBuilder {
+        self.backend = Some(backend);
+        self
+    }
+    /// Selects usnetd on the given interface with Unix domain socket IPC and the kernel's MAC and IP
+    pub fn usnetd(self, interface: &str) -> StackConfigBuilder {
+        self.backend(StcpBackend::UsnetConfig(UsnetDevice::Interface {
+            interface: SystemInterface::Interface(interface.to_string()),
+            ipc: UsnetType::UnixDomainSocket,
+            mac: Mac::Passthru,
+            ipv4: IpV4::Passthru,
+        }))
+    }
+    /// Selects a passthru macvtap device on the given interface, taking over the NIC
+    pub fn macvtap(self, parent: &str) -> StackConfigBuilder {
+        self.backend(StcpBackend::MacVtapConfig(MacVtapDevice::Create {
+            mac: Mac::Passthru,
+            parent: SystemInterface::Interface(parent.to_string()),
+            ipv4: IpV4::Passthru,
+        }))
+    }
+    /// Selects an AF_XDP socket on the queue of the interface with the kernel's MAC and IP,
+    /// the frames are steered to it by the XDP program which is already attached
+    pub fn xdp(self, interface: &str, queue_id: u32) -> StackConfigBuilder {
+        self.backend(StcpBackend::XdpConfig(XdpDevice::Interface {
+            interface: SystemInterface::Interface(interface.to_string()),
+            queue_id: queue_id,
+            poll: XdpPoll::Wakeup,
+            program: None,
+            mac: Mac::Passthru,
+            ipv4: IpV4::Passthru,
+        }))
+    }
+    /// Selects one end of the named in-memory link, the other end is the next stack created
+    /// with the same link name in this process, set the addresses with ipv4 and address
+    pub fn in_memory(self, link: &str) -> StackConfigBuilder {
+        self.backend(StcpBackend::InMemoryConfig(InMemoryDevice::Link {
+            link: link.to_string(),
+            mac: TapMac::Random,
+            ipv4: None,
+        }))
+    }
+    /// Sets a static IP address and prefix length, requires a gateway
+    pub fn ipv4(mut self, address: Ipv4Addr, prefix_len: u8) -> StackConfigBuilder {
+        self.ipv4 = Some((address, prefix_len));
+        self
+    }
+    /// Sets the IPv4 default route of the static IP address, must be inside its prefix
+    pub fn gateway(mut self, gateway: Ipv4Addr) -> StackConfigBuilder {
+        self.gateway = Some(gateway);
+        self
+    }
+    /// Limits the IP MTU, the interface MTU is used if it is smaller
+    pub fn mtu(mut self, mtu: usize) -> StackConfigBuilder {
+        self.mtu = Some(mtu);
+        self
+    }
+    /// Limits the MSS of new TCP connections in both directions, e.g., to 1452 for PPPoE
+    pub fn mss_clamp(mut self, mss: u16) -> StackConfigBuilder {
+        self.mss_clamp = Some(mss);
+        self
+    }
+    /// Detects path MTU black holes through retransmitted full-size TCP segments
+    pub fn pmtu_probing(mut self, pmtu_probing: bool) -> StackConfigBuilder {
+        self.pmtu_probing = pmtu_probing;
+        self
+    }
+    /// Negotiates selective acknowledgments for new TCP connections, enabled by default
+    pub fn tcp_sack(mut self, sack: bool) -> StackConfigBuilder {
+        self.tcp_sack = Some(sack);
+        self
+    }
+    /// Negotiates window scaling for new TCP connections, enabled by default, without it
+    /// the receive window is limited to 65535 bytes
+    pub fn tcp_window_scaling(mut self, window_scaling: bool) -> StackConfigBuilder {
+        self.tcp_window_scaling = Some(window_scaling);
+        self
+    }
+    /// Limits the receive window of TCP connections instead of the socket buffer size
+    pub fn tcp_receive_window(mut self, size: usize) -> StackConfigBuilder {
+        self.tcp_receive_window = Some(size);
+        self
+    }
+    /// Congestion window of new TCP connections in segments, 10 by default (RFC 6928)
+    pub fn tcp_initial_window(mut self, segments: usize) -> StackConfigBuilder {
+        self.tcp_initial_window = Some(segments);
+        self
+    }
+    /// Name servers for the resolver used by usnet_to_socket_addrs
+    pub fn name_servers(mut self, name_servers: Vec<SocketAddr>) -> StackConfigBuilder {
+        self.name_servers = Some(name_servers);
+        self
+    }
+    /// Size in bytes of the send and receive buffers of each socket, 500000 by default
+    pub fn socket_buffer_size(mut self, size: usize) -> StackConfigBuilder {
+        self.socket_buffer_size = Some(size);
+        self
+    }
+    /// Number of connections a TcpListener accepts in parallel before they are taken with
+    /// accept, 32 by default
+    pub fn socket_backlog(mut self, backlog: usize) -> StackConfigBuilder {
+        self.socket_backlog = Some(backlog);
+        self
+    }
+    pub fn waiting_poll(mut self, waiting_poll: bool) -> StackConfigBuilder {
+        self.waiting_poll = Some(waiting_poll);
+        self
+    }
+    /// Keeps the background thread busy-polling for this time after the last traffic,
+    /// which trades CPU time for latency, see spin_time_us and blocked_time_us in StackStats
+    pub fn spin_budget(mut self, budget: Duration) -> StackConfigBuilder {
+        self.spin_budget = Some(budget);
+        self
+    }
+    pub fn bg_thread_pin_cpu_id(mut self, cpu_id: usize) -> StackConfigBuilder {
+        self.bg_thread_pin_cpu_id = Some(cpu_id);
+        self
+    }
+    /// Configures IPv6 addresses and the IPv6 default route from router advertisements
+    pub fn slaac(mut self, slaac: bool) -> StackConfigBuilder {
+        self.slaac = slaac;
+        self
+    }
+    /// Allows creating a RawSocket for IP protocols other than TCP and UDP
+    pub fn raw_sockets(mut self, raw_sockets: bool) -> StackConfigBuilder {
+        self.raw_sockets = raw_sockets;
+        self
+    }
+    /// Connects sockets to 127.0.0.1 and the own addresses inside the stack instead of
+    /// through the kernel when a socket of the stack is bound to the destination port
+    pub fn loopback(mut self, loopback: bool) -> StackConfigBuilder {
+        self.loopback = loopback;
+        self
+    }
+    /// Adds another address to the interface, e.g., a service address next to the primary address
+    pub fn address(mut self, address: IpAddr, prefix_len: u8) -> StackConfigBuilder {
+        self.addresses.push((address, prefix_len));
+        self
+    }
+    /// Reaches the network through the userspace network stack, once a network is given,
+    /// the other remote destinations are reached through kernel sockets
+    pub fn usnet_prefix(mut self, network: IpAddr, prefix_len: u8) -> StackConfigBuilder {
+        self.usnet_prefixes.push((network, prefix_len));
+        self
+    }
+    /// Adds a route to the destination network through a next hop on the local network
+    pub fn route(mut self, destination: IpAddr, prefix_len: u8, via: IpAddr) -> StackConfigBuilder {
+        self.routes.push((destination, prefix_len, via));
+        self
+    }
+    /// Runs a stack on each of the given number of NIC queues for the AF_XDP and netmap
+    /// backends, the NIC's RSS decides on which stack a TCP connection is
+    pub fn queues(mut self, queues: usize) -> StackConfigBuilder {
+        self.queues = Some(queues);
+        self
+    }
+    pub fn build(self) -> io::Result<StackConfig> {
+        let invalid = |msg: String| io::Error::new(io::ErrorKind::InvalidInput, msg);
+        let mut backend = self
+            .backend
+            .ok_or_else(|| invalid("no backend selected".to_string()))?;
+        match (self.ipv4, self.gateway) {
+            (Some((address, prefix_len)), Some(gateway)) => {
+                check_ipv4(address, prefix_len, Some(gateway))?;
+                set_static_ipv4(&mut backend, address, prefix_len, gateway)?;
+            }
+            (Some(_), None) => {
+                return Err(invalid("static IP address without gateway".to_string()))
+            }
+            (None, Some(_)) => {
+                return Err(invalid("gateway without static IP address".to_string()))
+            }
+            (None, None) => {}
+        }
+        check_mtu(self.mtu)?;
+        if let Some(mss) = self.mss_clamp {
+            if (mss as usize) < MIN_MSS {
+                return Err(invalid(format!("MSS clamp {} below {}", mss, MIN_MSS)));
+            }
+        }
+        let name_servers = match self.name_servers {
+            Some(name_servers) => name_servers,
+            None => DEFAULT_NAME_SERVERS
+                .iter()
+                .map(|ns| SocketAddr::from_str(ns).unwrap())
+                .collect(),
+        };
+        if name_servers.is_empty() {
+            return Err(invalid("empty list of name servers".to_string()));
+        }
+        let socket_buffer_size = self.socket_buffer_size.unwrap_or(500000);
+        if socket_buffer_size == 0 {
+            return Err(invalid("socket buffer size must not be 0".to_string()));
+        }
+        let socket_backlog = self.socket_backlog.unwrap_or(32);
+        if socket_backlog == 0 {
+            return Err(invalid("socket backlog must not be 0".to_string()));
+        }
+        if self.tcp_receive_window == Some(0) {
+            return Err(invalid("TCP receive window must not be 0".to_string()));
+        }
+        let tcp_initial_window = self
+            .tcp_initial_window
+            .unwrap_or(congestion::DEFAULT_INITIAL_WINDOW);
+        if tcp_initial_window == 0 {
+            return Err(invalid("TCP initial window must not be 0".to_string()));
+        }
+        for &(address, prefix_len) in self.addresses.iter() {
+            check_address(address, prefix_len)?;
+        }
+        let routes = self
+            .routes
+            .iter()
+            .map(|&(destination, prefix_len, via)| Route::new(destination, prefix_len, via))
+            .collect::<io::Result<Vec<_>>>()?;
+        for &(network, prefix_len) in self.usnet_prefixes.iter() {
+            check_prefix_len(network, prefix_len)?;
+        }
+        let queues = self.queues.unwrap_or(1);
+        check_queues(&backend, queues)?;
+        Ok(StackConfig {
+            backend: backend,
+            waiting_poll: self.waiting_poll.unwrap_or(true),
+            spin_budget: self.spin_budget.unwrap_or(Duration::from_secs(0)),
+            socket_buffer_size: socket_buffer_size,
+            bg_thread_pin_cpu_id: self.bg_thread_pin_cpu_id,
+            socket_backlog: socket_backlog,
+            reduce_mtu_by: None,
+            mtu: self.mtu,
+            mss_clamp: self.mss_clamp,
+            pmtu_probing: self.pmtu_probing,
+            tcp_sack: self.tcp_sack.unwrap_or(true),
+            tcp_window_scaling: self.tcp_window_scaling.unwrap_or(true),
+            tcp_receive_window: self.tcp_receive_window,
+            tcp_initial_window: tcp_initial_window,
+            name_servers: name_servers,
+            slaac: self.slaac,
+            raw_sockets: self.raw_sockets,
+            loopback: self.loopback,
+            usnet_prefixes: self.usnet_prefixes,
+            addresses: self.addresses,
+            routes: routes,
+            queues: queues,
+        })
+    }
+}
+
+/// Settings of the running stack that can be changed with reconfigure
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RuntimeConfig {
+    /// Primary IPv4 address and prefix length, None if the interface has no IPv4 address
+    pub ipv4: Option<(Ipv4Addr, u8)>,
+    /// IPv4 default route
+    pub gateway: Option<Ipv4Addr>,
+    /// Limits the IP MTU, None for the interface MTU
+    pub mtu: Option<usize>,
+    pub name_servers: Vec<SocketAddr>,
+}
+
+impl RuntimeConfig {
+    pub fn validate(&self) -> io::Result<()> {
+        match self.ipv4 {
+            Some((address, prefix_len)) => check_ipv4(address, prefix_len, self.gateway)?,
+            None if self.gateway.is_some() => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "gateway without IP address",
+                ));
+            }
+            None => {}
+        }
+        check_mtu(self.mtu)?;
+        if self.name_servers.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "empty list of name servers",
+            ));
+        }
+        Ok(())
+    }
+}
+
+fn check_ipv4(address: Ipv4Addr, prefix_len: u8, gateway: Option<Ipv4Addr>) -> io::Result<()> {
+    if prefix_len == 0 || prefix_len > 32 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("invalid prefix length /{}", prefix_len),
+        ));
+    }
+    let mask = !0u32 << (32 - prefix_len as u32);
+    if let Some(gateway) = gateway {
+        if u32::from(address) & mask != u32::from(gateway) & mask {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "gateway {} is not inside {}/{}",
+                    gateway, address, prefix_len
+                ),
+            ));
+        }
+    }
+    Ok(())
+}
+
+fn check_mtu(mtu: Option<usize>) -> io::Result<()> {
+    if let Some(mtu) = mtu {
+        if mtu < MIN_MTU || mtu > MAX_MTU {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("MTU {} not in range {}..={}", mtu, MIN_MTU, MAX_MTU),
+            ));
+        }
+    }
+    Ok(())
+}
+
+fn check_queues(backend: &StcpBackend, queues: usize) -> io::Result<()> {
+    let invalid = |msg: &str| Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+    if queues == 0 {
+        return invalid("the number of queues must not be 0");
+    }
+    if queues == 1 {
+        return Ok(());
+    }
+    if backend.uses_dhcp() {
+        return invalid("DHCP is not supported on multiple queues");
+    }
+    match backend {
+        #[cfg(feature = "netmap")]
+        StcpBackend::NetmapConfig(NetmapDevice::Interface {
+            interface: NetmapInterface::Interface { .. },
+            ..
+        }) => Ok(()),
+        StcpBackend::XdpConfig(_) => Ok(()),
+        _ => invalid(
+            "only the AF_XDP and netmap backends with a named interface run on multiple queues",
+        ),
+    }
+}
+
+fn set_static_ipv4(
+    backend: &mut StcpBackend,
+    address: Ipv4Addr,
+    prefix_len: u8,
+    gateway: Ipv4Addr,
+) -> io::Result<()> {
+    let static_ipv4 = IpV4::Static {
+        ipv4: address.to_string(),
+        sub: prefix_len,
+        gateway: gateway.to_string(),
+    };
+    match backend {
+        StcpBackend::RawConfig => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "raw socket backend always uses the IP address of the interface",
+            ));
+        }
+        StcpBackend::TapConfig(TapDevice::Create { ref mut ipv4, .. })
+        | StcpBackend::TapConfig(TapDevice::Interface { ref mut ipv4, .. }) => {
+            *ipv4 = TapIpV4::Static {
+                ipv4: address.to_string(),
+                sub: prefix_len,
+                gateway: gateway.to_string(),
+            };
+        }
+        StcpBackend::MacVtapConfig(MacVtapDevice::Create { ref mut ipv4, .. })
+        | StcpBackend::MacVtapConfig(MacVtapDevice::Interface { ref mut ipv4, .. }) => {
+            *ipv4 = static_ipv4;
+        }
+        #[cfg(feature = "netmap")]
+        StcpBackend::NetmapConfig(NetmapDevice::Interface { ref mut ipv4, .. }) => {
+            *ipv4 = static_ipv4;
+        }
+        StcpBackend::UsnetConfig(UsnetDevice::Interface { ref mut ipv4, .. }) => {
+            *ipv4 = static_ipv4;
+        }
+        StcpBackend::XdpConfig(XdpDevice::Interface { ref mut ipv4, .. }) => {
+            *ipv4 = static_ipv4;
+        }
+        StcpBackend::InMemoryConfig(InMemoryDevice::Link { ref mut ipv4, .. }) => {
+            *ipv4 = Some(TapIpV4::Static {
+                ipv4: address.to_string(),
+                sub: prefix_len,
+                gateway: gateway.to_string(),
+            });
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{StackConfigBuilder, MAX_MTU, MIN_MTU};
+    use std::io;
+    use std::net::Ipv4Addr;
+
+    fn builder() -> StackConfigBuilder {
+        StackConfigBuilder::new()
+            .in_memory("config-test")
+            .ipv4(Ipv4Addr::new(10, 0, 0, 2), 24)
+            .gateway(Ipv4Addr::new(10, 0, 0, 1))
+    }
+
+    fn rejected(builder: StackConfigBuilder) -> bool {
+        builder.build().err().map(|err| err.kind()) == Some(io::ErrorKind::InvalidInput)
+    }
+
+    #[test]
+    fn test_build_validation() {
+        let config = builder().build().unwrap();
+        assert_eq!(config.socket_buffer_size, 500000);
+        assert_eq!(config.socket_backlog, 32);
+
+        assert!(rejected(
+            StackConfigBuilder::new()
+                .ipv4(Ipv4Addr::new(10, 0, 0, 2), 24)
+                .gateway(Ipv4Addr::new(10, 0, 0, 1))
+        ));
+        assert!(rejected(builder().gateway(Ipv4Addr::new(10, 0, 1, 1))));
+        assert!(!rejected(
+            builder()
+                .ipv4(Ipv4Addr::new(10, 0, 0, 2), 16)
+                .gateway(Ipv4Addr::new(10, 0, 1, 1))
+        ));
+        assert!(rejected(builder().ipv4(Ipv4Addr::new(10, 0, 0, 2), 33)));
+        assert!(rejected(
+            StackConfigBuilder::new()
+                .in_memory("config-test")
+                .ipv4(Ipv4Addr::new(10, 0, 0, 2), 24)
+        ));
+        assert!(rejected(
+            StackConfigBuilder::new()
+                .in_memory("config-test")
+                .gateway(Ipv4Addr::new(10, 0, 0, 1))
+        ));
+
+        assert!(builder().mtu(MIN_MTU).build().is_ok());
+        assert!(builder().mtu(MAX_MTU).build().is_ok());
+        assert!(rejected(builder().mtu(MIN_MTU - 1)));
+        assert!(rejected(builder().mtu(MAX_MTU + 1)));
+
+        assert!(rejected(builder().socket_buffer_size(0)));
+        assert!(rejected(builder().socket_backlog(0)));
+        assert!(rejected(builder().name_servers(vec![])));
+        assert!(rejected(builder().queues(2)));
+    }
+}
@@ -0,0 +1,113 @@
+//! The io::ErrorKind of connection and send errors, tested on stacks of in-memory links
+//! Each test uses its own link and ports, the kernel loopback also gets the listening ports.
+#![cfg(feature = "multi")]
+
+extern crate usnet_sockets;
+
+use std::io::{self, Read, Write};
+use std::net::{Ipv4Addr, Shutdown, SocketAddr};
+use std::time::Duration;
+
+use usnet_sockets::{StackConfigBuilder, StcpNetRef, TcpStream, UdpSocket};
+
+const CLIENT: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 2);
+const SERVER: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 3);
+
+fn stack(link: &str, ip: Ipv4Addr) -> StcpNetRef {
+    StcpNetRef::new(
+        StackConfigBuilder::new()
+            .in_memory(link)
+            .ipv4(ip, 24)
+            .gateway(Ipv4Addr::new(10, 0, 0, 1))
+            .build()
+            .unwrap(),
+    )
+}
+
+/// Returns the client and server side of an established connection and the server stack
+fn connection(link: &str, port: u16) -> (TcpStream, TcpStream, StcpNetRef) {
+    let client = stack(link, CLIENT);
+    let server = stack(link, SERVER);
+    let listener = server.tcp_bind((SERVER, port)).unwrap();
+    let stream = client.tcp_connect((SERVER, port)).unwrap();
+    let (accepted, _) = listener.accept().unwrap();
+    (stream, accepted, server)
+}
+
+#[test]
+fn test_connection_refused() {
+    let client = stack("refused", CLIENT);
+    let _server = stack("refused", SERVER);
+    let addr = SocketAddr::from((SERVER, 47001));
+    let err = client
+        .tcp_connect_timeout(&addr, Duration::from_secs(10))
+        .unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused);
+}
+
+#[test]
+fn test_connection_reset() {
+    let (mut stream, accepted, _server) = connection("reset", 47002);
+    stream
+        .set_read_timeout(Some(Duration::from_secs(10)))
+        .unwrap();
+    accepted.shutdown(Shutdown::Both).unwrap();
+    let err = stream.read(&mut [0; 16]).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::ConnectionReset);
+    let err = stream.write(b"after the reset").unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::ConnectionReset);
+}
+
+#[test]
+fn test_host_unreachable() {
+    let client = stack("unresolved", CLIENT);
+    let _server = stack("unresolved", SERVER);
+    // no stack answers the ARP requests for the address
+    let addr = SocketAddr::from((Ipv4Addr::new(10, 0, 0, 9), 47003));
+    let err = client
+        .tcp_connect_timeout(&addr, Duration::from_secs(10))
+        .unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::HostUnreachable);
+}
+
+#[test]
+fn test_network_unreachable() {
+    // the global stack, the only one in this test binary, to remove its default route
+    usnet_sockets::init_with(
+        StackConfigBuilder::new()
+            .in_memory("unrouted")
+            .ipv4(CLIENT, 24)
+            .gateway(Ipv4Addr::new(10, 0, 0, 1))
+            .build()
+            .unwrap(),
+    )
+    .unwrap();
+    usnet_sockets::reconfigure(|config| config.gateway = None).unwrap();
+    let addr = SocketAddr::from((Ipv4Addr::new(192, 0, 2, 1), 47004));
+    let err = TcpStream::connect(addr).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::NetworkUnreachable);
+    let socket = UdpSocket::bind((CLIENT, 0)).unwrap();
+    let err = socket.send_to(b"no route", addr).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::NetworkUnreachable);
+}
+
+#[test]
+fn test_not_connected() {
+    let (mut stream, _accepted, _server) = connection("closed", 47005);
+    stream.shutdown(Shutdown::Write).unwrap();
+    let err = stream.write(b"after the close").unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::NotConnected);
+}
+
+#[test]
+fn test_would_block() {
+    // the server does not read, so that the windows and the send ring fill up
+    let (mut stream, _accepted, _server) = connection("full", 47006);
+    stream.set_nonblocking(true).unwrap();
+    let buf = [0; 65536];
+    let err = (0..10_000)
+        .filter_map(|_| stream.write(&buf).err())
+        .next()
+        .expect("send ring never full");
+    assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+}
@@ -0,0 +1,271 @@
+//! Connections between two stacks of in-memory links, also under loss and latency
+//! Each test uses its own link and ports, the kernel loopback also gets the listening ports.
+#![cfg(feature = "multi")]
+
+extern crate usnet_sockets;
+
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use usnet_sockets::resolve::message::{OwnedRecord, Qr, MESSAGE_LIMIT};
+use usnet_sockets::resolve::record::{Class, RecordData, A};
+use usnet_sockets::resolve::{DnsConfig, DnsResolver, DnsSocket};
+use usnet_sockets::{
+    link_conditions, set_link_conditions, stats, Congestion, LinkConditions, StackConfigBuilder,
+    StcpNetRef,
+};
+
+const CLIENT: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 2);
+const SERVER: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 3);
+
+fn stack(link: &str, ip: Ipv4Addr) -> StcpNetRef {
+    StcpNetRef::new(
+        StackConfigBuilder::new()
+            .in_memory(link)
+            .ipv4(ip, 24)
+            .gateway(Ipv4Addr::new(10, 0, 0, 1))
+            .build()
+            .unwrap(),
+    )
+}
+
+/// Returns the time until the datagram comes back from the echo socket
+fn udp_rtt(socket: &usnet_sockets::UdpSocket, echo: SocketAddr) -> io::Result<Duration> {
+    let start = Instant::now();
+    socket.send_to(b"ping", echo)?;
+    let mut buf = [0; 16];
+    let (n, from) = socket.recv_from(&mut buf)?;
+    assert_eq!((&buf[..n], from), (&b"ping"[..], echo));
+    Ok(start.elapsed())
+}
+
+#[test]
+fn test_tcp_handshake() {
+    let client = stack("mem-handshake", CLIENT);
+    let server = stack("mem-handshake", SERVER);
+    let listener = server.tcp_bind((SERVER, 47301)).unwrap();
+    let mut stream = client.tcp_connect((SERVER, 47301)).unwrap();
+    let (mut accepted, peer) = listener.accept().unwrap();
+    assert_eq!(peer, stream.local_addr().unwrap());
+    assert_eq!(peer.ip(), IpAddr::V4(CLIENT));
+    assert_eq!(
+        stream.peer_addr().unwrap(),
+        SocketAddr::from((SERVER, 47301))
+    );
+
+    stream.write_all(b"hello server").unwrap();
+    let mut buf = [0; 12];
+    accepted.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf, b"hello server");
+    accepted.write_all(b"hello client").unwrap();
+    stream.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf, b"hello client");
+
+    // the close of the server ends the stream of the client
+    drop(accepted);
+    assert_eq!(stream.read(&mut buf).unwrap(), 0);
+}
+
+#[test]
+fn test_retransmission() {
+    // the link drops frames from the start, also of the handshake
+    let conditions = LinkConditions {
+        loss: 0.1,
+        seed: 7,
+        ..LinkConditions::default()
+    };
+    set_link_conditions("mem-loss", conditions).unwrap();
+    let client = stack("mem-loss", CLIENT);
+    let server = stack("mem-loss", SERVER);
+    let listener = server.tcp_bind((SERVER, 47302)).unwrap();
+    let mut stream = client.tcp_connect((SERVER, 47302)).unwrap();
+    let (mut accepted, _) = listener.accept().unwrap();
+    accepted
+        .set_read_timeout(Some(Duration::from_secs(30)))
+        .unwrap();
+
+    let data: Vec<u8> = (0..64 * 1024).map(|n| (n % 251) as u8).collect();
+    let sent = data.clone();
+    let writer = thread::spawn(move || {
+        stream.write_all(&sent).unwrap();
+        stream
+    });
+    let mut received = vec![0; data.len()];
+    accepted.read_exact(&mut received).unwrap();
+    assert!(received == data, "the data arrived corrupted");
+    let _stream = writer.join().unwrap();
+    assert_eq!(link_conditions("mem-loss"), Some(conditions));
+}
+
+#[test]
+fn test_congestion_on_loss() {
+    let conditions = LinkConditions {
+        loss: 0.05,
+        latency: Duration::from_millis(10),
+        seed: 11,
+        ..LinkConditions::default()
+    };
+    set_link_conditions("mem-congestion", conditions).unwrap();
+    let client = stack("mem-congestion", CLIENT);
+    let server = stack("mem-congestion", SERVER);
+    let listener = server.tcp_bind((SERVER, 47306)).unwrap();
+    let mut stream = client.tcp_connect((SERVER, 47306)).unwrap();
+    stream.set_congestion(Congestion::Reno).unwrap();
+    let (mut accepted, _) = listener.accept().unwrap();
+    accepted
+        .set_read_timeout(Some(Duration::from_secs(30)))
+        .unwrap();
+
+    let data: Vec<u8> = (0..256 * 1024).map(|n| (n % 251) as u8).collect();
+    let sent = data.clone();
+    let writer = thread::spawn(move || {
+        stream.write_all(&sent).unwrap();
+        stream
+    });
+    let mut received = vec![0; data.len()];
+    accepted.read_exact(&mut received).unwrap();
+    assert!(received == data, "the data arrived corrupted");
+    let stream = writer.join().unwrap();
+
+    // the lost segments reduced the window and set the slow start threshold
+    let stats = stream.stats().unwrap();
+    assert_eq!(stats.congestion, Congestion::Reno);
+    assert!(stats.losses > 0, "no losses in {:?}", stats);
+    assert!(stats.ssthresh.is_some() && stats.cwnd.is_some());
+    assert!(stats.srtt.unwrap() >= Duration::from_millis(20));
+}
+
+#[test]
+fn test_dns_socket() {
+    let client = stack("mem-dns", CLIENT);
+    let server = stack("mem-dns", SERVER);
+    // answers every query with an A record, also the one for AAAA records
+    let sock = DnsSocket::from_socket(server.udp_bind((SERVER, 47303)).unwrap());
+    sock.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    thread::spawn(move || {
+        let record = OwnedRecord::new(
+            "www.example.com.".to_owned(),
+            Class::Internet,
+            60,
+            RecordData::A(A {
+                address: Ipv4Addr::new(192, 0, 2, 1),
+            }),
+        );
+        let mut buf = [0; MESSAGE_LIMIT];
+        while let Ok((mut msg, from)) = sock.recv_from(&mut buf) {
+            msg.header.qr = Qr::Response;
+            msg.answer = vec![record.to_resource().unwrap()];
+            sock.send_message(&msg, from).unwrap();
+        }
+    });
+
+    let sock = DnsSocket::from_socket(client.udp_bind((CLIENT, 0)).unwrap());
+    let mut config = DnsConfig::with_name_servers(vec![SocketAddr::from((SERVER, 47303))]);
+    config.timeout = Duration::from_secs(2);
+    let resolver = DnsResolver::with_socket(sock, config);
+    let addrs: Vec<_> = resolver.resolve_host("www.example.com.").unwrap().collect();
+    assert_eq!(addrs, vec![IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1))]);
+    let stats = resolver.stats();
+    assert_eq!(stats.queries_sent, 2);
+    assert_eq!(stats.responses_received, 2);
+}
+
+#[test]
+fn test_changed_conditions() {
+    let client = stack("mem-changed", CLIENT);
+    let server = stack("mem-changed", SERVER);
+    let echo = server.udp_bind((SERVER, 47304)).unwrap();
+    let echo_thread = thread::spawn(move || {
+        let mut buf = [0; 16];
+        while let Ok((n, from)) = echo.recv_from(&mut buf) {
+            if &buf[..n] == b"stop" {
+                break;
+            }
+            echo.send_to(&buf[..n], from).unwrap();
+        }
+    });
+    let socket = client.udp_bind((CLIENT, 47305)).unwrap();
+    socket
+        .set_read_timeout(Some(Duration::from_secs(2)))
+        .unwrap();
+    let echo_addr = SocketAddr::from((SERVER, 47304));
+    // the first datagram waits for the ARP reply
+    udp_rtt(&socket, echo_addr).unwrap();
+    assert!(udp_rtt(&socket, echo_addr).unwrap() < Duration::from_millis(100));
+
+    // the latency applies to each direction
+    let delayed = LinkConditions {
+        latency: Duration::from_millis(100),
+        ..LinkConditions::default()
+    };
+    set_link_conditions("mem-changed", delayed).unwrap();
+    assert_eq!(link_conditions("mem-changed"), Some(delayed));
+    assert!(udp_rtt(&socket, echo_addr).unwrap() >= Duration::from_millis(200));
+
+    // all datagrams are lost until the link is lossless again
+    let lossy = LinkConditions {
+        loss: 1.0,
+        ..LinkConditions::default()
+    };
+    set_link_conditions("mem-changed", lossy).unwrap();
+    socket
+        .set_read_timeout(Some(Duration::from_millis(300)))
+        .unwrap();
+    let err = udp_rtt(&socket, echo_addr).unwrap_err();
+    assert!(
+        err.kind() == io::ErrorKind::WouldBlock || err.kind() == io::ErrorKind::TimedOut,
+        "unexpected error {}",
+        err
+    );
+    set_link_conditions("mem-changed", LinkConditions::default()).unwrap();
+    assert!(udp_rtt(&socket, echo_addr).unwrap() < Duration::from_millis(100));
+
+    let invalid = LinkConditions {
+        loss: 1.5,
+        ..LinkConditions::default()
+    };
+    let err = set_link_conditions("mem-changed", invalid).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    assert_eq!(
+        link_conditions("mem-changed"),
+        Some(LinkConditions::default())
+    );
+    socket.send_to(b"stop", echo_addr).unwrap();
+    echo_thread.join().unwrap();
+    assert_eq!(link_conditions("mem-unused"), None);
+}
+
+#[test]
+fn test_interface_stats() {
+    let client = stack("mem-stats", CLIENT);
+    let server = stack("mem-stats", SERVER);
+    let before = stats();
+    let server_before = server.interface_stats();
+
+    // a datagram and a connection to a port without a socket on the server
+    let socket = client.udp_bind((CLIENT, 47307)).unwrap();
+    socket
+        .send_to(b"nobody", SocketAddr::from((SERVER, 47308)))
+        .unwrap();
+    let err = client
+        .tcp_connect_timeout(&SocketAddr::from((SERVER, 47308)), Duration::from_secs(10))
+        .unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused);
+    // the port unreachable error and the reset, other tests may add to the process counters
+    assert!(stats().delta(&before).rx_no_socket >= 2);
+
+    // the ARP request, the datagram, and the SYN, answered by the ARP reply, the error, and
+    // the reset
+    let server_stats = server.interface_stats();
+    assert!(server_stats.rx_packets >= server_before.rx_packets + 3);
+    assert!(server_stats.tx_packets >= server_before.tx_packets + 3);
+    assert!(server_stats.rx_bytes > server_before.rx_bytes);
+    assert!(server_stats.tx_bytes > server_before.tx_bytes);
+    // in-memory links have no rings
+    assert_eq!((server_stats.rx_ring, server_stats.tx_ring), (None, None));
+    let client_stats = client.interface_stats();
+    assert!(client_stats.tx_packets >= 3);
+    assert!(client_stats.rx_packets >= 3);
+}
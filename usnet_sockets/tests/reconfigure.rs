@@ -0,0 +1,102 @@
+//! Runtime reconfiguration, tested on stacks of in-memory links
+//! Each test uses its own link and ports, the kernel loopback also gets the listening ports.
+#![cfg(feature = "multi")]
+
+extern crate usnet_sockets;
+
+use std::io::{self, Read, Write};
+use std::net::{Ipv4Addr, SocketAddr};
+use std::thread;
+use std::time::Duration;
+
+use usnet_sockets::{StackConfigBuilder, StcpNetRef};
+
+const CLIENT: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 2);
+const SERVER: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 3);
+const MOVED: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 4);
+
+fn stack(link: &str, ip: Ipv4Addr) -> StcpNetRef {
+    StcpNetRef::new(
+        StackConfigBuilder::new()
+            .in_memory(link)
+            .ipv4(ip, 24)
+            .gateway(Ipv4Addr::new(10, 0, 0, 1))
+            .build()
+            .unwrap(),
+    )
+}
+
+#[test]
+fn test_blocked_read_reset() {
+    let client = stack("reconf-reset", CLIENT);
+    let server = stack("reconf-reset", SERVER);
+    let listener = server.tcp_bind((SERVER, 47201)).unwrap();
+    let mut stream = client.tcp_connect((SERVER, 47201)).unwrap();
+    let (_accepted, _) = listener.accept().unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(10)))
+        .unwrap();
+    let reader = thread::spawn(move || stream.read(&mut [0; 16]).map_err(|err| err.kind()));
+    thread::sleep(Duration::from_millis(200)); // until the read blocks
+    client
+        .reconfigure(|config| config.ipv4 = Some((MOVED, 24)))
+        .unwrap();
+    assert_eq!(reader.join().unwrap(), Err(io::ErrorKind::ConnectionReset));
+    assert_eq!(client.runtime_config().ipv4, Some((MOVED, 24)));
+}
+
+#[test]
+fn test_unspecified_listener() {
+    let client = stack("reconf-listen", CLIENT);
+    let server = stack("reconf-listen", SERVER);
+    let listener = server.tcp_bind((Ipv4Addr::UNSPECIFIED, 47202)).unwrap();
+    server
+        .reconfigure(|config| config.ipv4 = Some((MOVED, 24)))
+        .unwrap();
+    let mut stream = client.tcp_connect((MOVED, 47202)).unwrap();
+    let (mut accepted, _) = listener.accept().unwrap();
+    stream.write_all(b"to the new address").unwrap();
+    let mut buf = [0; 18];
+    accepted.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf, b"to the new address");
+}
+
+#[test]
+fn test_invalid_config() {
+    let client = stack("reconf-invalid", CLIENT);
+    let before = client.runtime_config();
+    let err = client
+        .reconfigure(|config| {
+            config.mtu = Some(1400);
+            config.gateway = Some(Ipv4Addr::new(192, 0, 2, 1));
+        })
+        .unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    assert_eq!(client.runtime_config(), before);
+}
+
+#[test]
+fn test_concurrent_changes() {
+    // each closure changes another setting, none of the changes may get lost
+    let client = stack("reconf-concurrent", CLIENT);
+    let mtu_changes = {
+        let client = client.clone();
+        thread::spawn(move || {
+            for n in 0..100 {
+                client
+                    .reconfigure(|config| config.mtu = Some(1280 + n))
+                    .unwrap();
+            }
+        })
+    };
+    for n in 1..=100 {
+        let name_server = SocketAddr::from((Ipv4Addr::new(10, 0, 1, n), 53));
+        client
+            .reconfigure(|config| config.name_servers = vec![name_server])
+            .unwrap();
+    }
+    mtu_changes.join().unwrap();
+    let config = client.runtime_config();
+    assert_eq!(config.mtu, Some(1379));
+    assert_eq!(config.name_servers, vec!["10.0.1.100:53".parse().unwrap()]);
+}
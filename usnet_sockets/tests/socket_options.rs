@@ -0,0 +1,114 @@
+//! Setters and getters of the socket options, tested on stacks of in-memory links
+//! Each test uses its own link, the kernel loopback also gets the bound ports.
+#![cfg(feature = "multi")]
+
+extern crate usnet_sockets;
+
+use std::fs;
+use std::io;
+use std::net::Ipv4Addr;
+
+use usnet_sockets::resolve::DnsSocket;
+use usnet_sockets::{StackConfigBuilder, StcpNetRef, TcpListenerBuilder, UdpSocketBuilder};
+
+const CLIENT: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 2);
+
+fn stack(link: &str, ip: Ipv4Addr) -> StcpNetRef {
+    StcpNetRef::new(
+        StackConfigBuilder::new()
+            .in_memory(link)
+            .ipv4(ip, 24)
+            .gateway(Ipv4Addr::new(10, 0, 0, 1))
+            .build()
+            .unwrap(),
+    )
+}
+
+#[test]
+fn test_ttl() {
+    let client = stack("ttl", CLIENT);
+    let socket = client.udp_bind((CLIENT, 0)).unwrap();
+    assert_eq!(socket.ttl().unwrap(), 64);
+    socket.set_ttl(7).unwrap();
+    assert_eq!(socket.ttl().unwrap(), 7);
+    assert_eq!(socket.try_clone().unwrap().ttl().unwrap(), 7);
+    for &invalid in &[0, 256] {
+        let err = socket.set_ttl(invalid).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+    assert_eq!(socket.ttl().unwrap(), 7);
+
+    let dns = DnsSocket::from_socket(client.udp_bind((CLIENT, 0)).unwrap());
+    dns.set_ttl(1).unwrap();
+    assert_eq!(dns.ttl().unwrap(), 1);
+    dns.set_ttl(255).unwrap();
+    assert_eq!(dns.ttl().unwrap(), 255);
+    // the stack has no IPv6, and an IPv4 socket has no hop limit
+    assert!(dns.set_hop_limit(32).is_err());
+    assert!(dns.hop_limit().is_err());
+    assert_eq!(dns.ttl().unwrap(), 255);
+}
+
+#[test]
+fn test_bind_device() {
+    let client = stack("bound", CLIENT);
+    // the name of the in-memory link is the device of the stack
+    TcpListenerBuilder::new()
+        .device("bound")
+        .bind_on(&client, (CLIENT, 47101))
+        .unwrap();
+    UdpSocketBuilder::new()
+        .device("bound")
+        .bind_on(&client, (CLIENT, 47101))
+        .unwrap();
+    UdpSocketBuilder::new()
+        .device("lo")
+        .bind_on(&client, "0.0.0.0:47102")
+        .unwrap();
+
+    let err = TcpListenerBuilder::new()
+        .device("nosuchdev0")
+        .bind_on(&client, (CLIENT, 47103))
+        .unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::NotFound);
+    let err = UdpSocketBuilder::new()
+        .device("nosuchdev0")
+        .bind_on(&client, (CLIENT, 47103))
+        .unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::NotFound);
+
+    // an existing interface which the stack does not use
+    let other = fs::read_dir("/sys/class/net")
+        .unwrap()
+        .filter_map(|entry| entry.ok()?.file_name().into_string().ok())
+        .find(|name| name != "lo");
+    if let Some(other) = other {
+        let err = UdpSocketBuilder::new()
+            .device(&other)
+            .bind_on(&client, (CLIENT, 47104))
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+}
+
+#[test]
+fn test_dscp() {
+    let client = stack("dscp", CLIENT);
+    let dns = DnsSocket::from_socket(client.udp_bind((CLIENT, 47105)).unwrap());
+    assert_eq!(dns.dscp().unwrap(), 0);
+    dns.set_dscp(46).unwrap();
+    assert_eq!(dns.dscp().unwrap(), 46);
+    assert_eq!(dns.tos().unwrap(), 46 << 2);
+    // only the six DSCP bits
+    let err = dns.set_dscp(64).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    for &invalid in &[(46 << 2) | 1, (46 << 2) | 2, 256] {
+        let err = dns.set_tos(invalid).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+    assert_eq!(dns.dscp().unwrap(), 46);
+    dns.set_tos(10 << 2).unwrap();
+    assert_eq!(dns.dscp().unwrap(), 10);
+    dns.set_dscp(0).unwrap();
+    assert_eq!(dns.tos().unwrap(), 0);
+}